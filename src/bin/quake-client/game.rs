@@ -19,16 +19,26 @@
 // SOFTWARE.
 
 use std::cell::{Cell, RefCell};
+use std::io::Write;
+use std::ops::DerefMut;
 use std::rc::Rc;
 
+use richter::client::demo::DemoServer;
+use richter::client::input::game::Action;
 use richter::client::input::{Input, InputFocus};
 use richter::client::menu::Menu;
+use richter::client::render::brush;
 use richter::client::render::hud::HudRenderer;
+use richter::client::render::lightmap;
+use richter::client::render::loading::LoadingRenderer;
 use richter::client::render::menu::MenuRenderer;
-use richter::client::render::{self, pipe, GraphicsPackage, SceneRenderer};
+use richter::client::render::screenshot::{self, HiresScreenshot, PendingScreenshot};
+use richter::client::render::{self, pipe, DummyTexturePattern, GraphicsPackage, SceneRenderer};
 use richter::client::Client;
-use richter::common::console::{CmdRegistry, CvarRegistry};
+use richter::common::console::{CmdGuard, CmdRegistry, CvarRegistry};
+use richter::common::engine;
 use richter::common::math;
+use richter::common::model::ModelKind;
 use richter::common::net::SignOnStage;
 use richter::common::vfs::Vfs;
 
@@ -39,7 +49,23 @@ use gfx::{CommandBuffer, Encoder};
 use gfx_device_gl::Resources;
 use glutin::Event;
 
-#[derive(Clone, Copy)]
+// bounds for host_timescale
+const MIN_TIMESCALE: f32 = 0.1;
+const MAX_TIMESCALE: f32 = 10.0;
+
+// the fraction of the screen covered by the console when it's fully open
+const CONSOLE_HEIGHT: f32 = 0.5;
+
+// scr_conspeed is measured in lines per second on the original 200-line screen
+const CONSOLE_SPEED_SCALE: f32 = 1.0 / 200.0;
+
+// where cfg_save writes bindings and archived cvars
+const CONFIG_FILE: &str = "config.cfg";
+
+/// The title of the window when no level is loaded.
+pub const WINDOW_TITLE: &str = "Richter client";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum InGameFocus {
     // active in game
     Game,
@@ -52,10 +78,25 @@ enum InGameFocus {
 }
 
 struct InGameState {
-    cmds: Rc<RefCell<CmdRegistry>>,
+    // the commands registered by this state, removed again when it's dropped
+    _focus_cmds: CmdGuard,
+
     renderer: SceneRenderer,
     hud_renderer: HudRenderer,
     focus: Rc<Cell<InGameFocus>>,
+
+    // the player shown in the right half of a split screen, if any. the scene target is sized for
+    // the layout, so this is fixed for the level
+    split_player: Option<usize>,
+
+    // the fraction of the screen currently covered by the console
+    console_height: f32,
+
+    // how far the view has zoomed from fov to zoom_fov, from 0 to 1
+    zoom: f32,
+
+    // a high-resolution screenshot rendered last frame, read back once the encoder is flushed
+    screenshot: Option<PendingScreenshot>,
 }
 
 impl InGameState {
@@ -64,60 +105,120 @@ impl InGameState {
         scene_renderer: SceneRenderer,
         hud_renderer: HudRenderer,
         focus: InGameFocus,
+        split_player: Option<usize>,
     ) -> InGameState {
         let focus_rc = Rc::new(Cell::new(focus));
-        let toggleconsole_focus = focus_rc.clone();
+        let focus_cmds = register_focus_cmds(cmds, focus_rc.clone());
 
-        cmds.borrow_mut()
-            .insert(
-                "toggleconsole",
-                Box::new(move |_| match toggleconsole_focus.get() {
-                    InGameFocus::Game => {
-                        println!("toggleconsole: ON");
-                        toggleconsole_focus.set(InGameFocus::Console);
-                    }
+        InGameState {
+            _focus_cmds: focus_cmds,
+            renderer: scene_renderer,
+            hud_renderer,
+            focus: focus_rc,
+            split_player,
+            console_height: 0.0,
+            zoom: 0.0,
+            screenshot: None,
+        }
+    }
 
-                    InGameFocus::Console => {
-                        println!("toggleconsole: OFF");
-                        toggleconsole_focus.set(InGameFocus::Game);
-                    }
+    /// Slides the console towards its open or closed position.
+    ///
+    /// The console moves `scr_conspeed` lines per second, or snaps into place if `scr_conspeed`
+    /// is 0.
+    fn update_console_height(&mut self, frame_duration: Duration, speed: f32) {
+        let target = match self.focus.get() {
+            InGameFocus::Console => CONSOLE_HEIGHT,
+            _ => 0.0,
+        };
+
+        if !(speed > 0.0) {
+            self.console_height = target;
+            return;
+        }
 
-                    InGameFocus::Menu => (),
-                }),
-            )
-            .unwrap();
+        let step = engine::duration_to_f32(frame_duration) * speed * CONSOLE_SPEED_SCALE;
+        self.console_height = if self.console_height < target {
+            (self.console_height + step).min(target)
+        } else {
+            (self.console_height - step).max(target)
+        };
+    }
 
-        let togglemenu_focus = focus_rc.clone();
+    /// Moves the zoom towards fully zoomed in while `zooming`, or back out otherwise.
+    ///
+    /// A full transition takes `zoom_time` seconds, or is instant if `zoom_time` is 0.
+    fn update_zoom(&mut self, frame_duration: Duration, zooming: bool, zoom_time: f32) {
+        let target = if zooming { 1.0 } else { 0.0 };
 
-        cmds.borrow_mut()
-            .insert(
-                "togglemenu",
-                Box::new(move |_| match togglemenu_focus.get() {
-                    InGameFocus::Game => {
-                        println!("togglemenu: ON");
-                        togglemenu_focus.set(InGameFocus::Menu);
-                    }
+        if !(zoom_time > 0.0) {
+            self.zoom = target;
+            return;
+        }
 
-                    InGameFocus::Menu | InGameFocus::Console => {
-                        println!("togglemenu: OFF");
-                        togglemenu_focus.set(InGameFocus::Game);
-                    }
-                }),
-            )
-            .unwrap();
+        let step = engine::duration_to_f32(frame_duration) / zoom_time;
+        self.zoom = if self.zoom < target {
+            (self.zoom + step).min(target)
+        } else {
+            (self.zoom - step).max(target)
+        };
+    }
 
-        InGameState {
-            cmds,
-            renderer: scene_renderer,
-            hud_renderer,
-            focus: focus_rc,
-        }
+    /// Returns the horizontal field of view, blended from `fov` towards `zoom_fov` by the zoom.
+    fn fov(&self, fov: f32, zoom_fov: f32) -> f32 {
+        fov + (zoom_fov - fov) * self.zoom
     }
 }
 
-impl ::std::ops::Drop for InGameState {
-    fn drop(&mut self) {
-        // TODO: delete toggleconsole from cmds
+// registers the commands that switch between the game, console and menu
+fn register_focus_cmds(cmds: Rc<RefCell<CmdRegistry>>, focus: Rc<Cell<InGameFocus>>) -> CmdGuard {
+    let mut guard = CmdGuard::new(cmds);
+
+    let toggleconsole_focus = focus.clone();
+    guard.insert(
+        "toggleconsole",
+        Box::new(move |_| match toggleconsole_focus.get() {
+            InGameFocus::Game => {
+                println!("toggleconsole: ON");
+                toggleconsole_focus.set(InGameFocus::Console);
+            }
+
+            InGameFocus::Console => {
+                println!("toggleconsole: OFF");
+                toggleconsole_focus.set(InGameFocus::Game);
+            }
+
+            InGameFocus::Menu => (),
+        }),
+    )
+    .unwrap();
+
+    let togglemenu_focus = focus.clone();
+    guard.insert(
+        "togglemenu",
+        Box::new(move |_| match togglemenu_focus.get() {
+            InGameFocus::Game => {
+                println!("togglemenu: ON");
+                togglemenu_focus.set(InGameFocus::Menu);
+            }
+
+            InGameFocus::Menu | InGameFocus::Console => {
+                println!("togglemenu: OFF");
+                togglemenu_focus.set(InGameFocus::Game);
+            }
+        }),
+    )
+    .unwrap();
+
+    guard
+}
+
+// the local player's view fills the display, or its left half when the screen is split
+fn split_viewports(display_w: u32, display_h: u32, split: bool) -> Vec<render::Viewport> {
+    if split {
+        render::Viewport::side_by_side(display_w, display_h, 2)
+    } else {
+        vec![render::Viewport::full(display_w, display_h)]
     }
 }
 
@@ -127,6 +228,21 @@ enum GameState {
 
     // in game
     InGame(InGameState),
+
+    // no longer connected to a server
+    Disconnected,
+}
+
+/// Saves key bindings and archived cvars to `config.cfg`.
+///
+/// Bindings are preceded by `unbindall` so that defaults the player removed stay removed.
+fn write_config(vfs: &Vfs, cvars: &CvarRegistry, input: &Input) -> Result<(), Error> {
+    let mut cfg = vfs.create(CONFIG_FILE)?;
+    writeln!(cfg, "unbindall")?;
+    input.write_bindings(&mut cfg)?;
+    cvars.write_archived(&mut cfg)?;
+
+    Ok(())
 }
 
 pub struct Game {
@@ -135,10 +251,23 @@ pub struct Game {
     cmds: Rc<RefCell<CmdRegistry>>,
     menu: Rc<RefCell<Menu>>,
     menu_renderer: MenuRenderer,
+    loading_renderer: LoadingRenderer,
     gfx_pkg: Rc<RefCell<GraphicsPackage>>,
     state: GameState,
     input: Rc<RefCell<Input>>,
     client: Client,
+
+    // signon stage changes reported by the client since the last frame
+    signon_events: Rc<RefCell<Vec<SignOnStage>>>,
+
+    // set by the disconnect command, handled at the start of the next frame
+    disconnect_requested: Rc<Cell<bool>>,
+
+    // set by the playdemo command, handled at the start of the next frame
+    demo_requested: Rc<RefCell<Option<String>>>,
+
+    // the multiplier given to screenshot_hires, handled when the next frame is rendered
+    screenshot_requested: Rc<Cell<Option<u32>>>,
 }
 
 impl Game {
@@ -149,57 +278,295 @@ impl Game {
         menu: Rc<RefCell<Menu>>,
         gfx_pkg: Rc<RefCell<GraphicsPackage>>,
         input: Rc<RefCell<Input>>,
-        client: Client,
+        mut client: Client,
     ) -> Result<Game, Error> {
         input.borrow().register_cmds(&mut cmds.borrow_mut());
 
+        let disconnect_requested = Rc::new(Cell::new(false));
+        let cmd_disconnect_requested = disconnect_requested.clone();
+        cmds.borrow_mut()
+            .insert_or_replace(
+                "disconnect",
+                Box::new(move |_| cmd_disconnect_requested.set(true)),
+            )
+            .unwrap();
+
+        let demo_requested = Rc::new(RefCell::new(None));
+        let cmd_demo_requested = demo_requested.clone();
+        cmds.borrow_mut()
+            .insert_or_replace(
+                "playdemo",
+                Box::new(move |args| {
+                    if args.len() != 1 {
+                        println!("playdemo <demoname>");
+                        return;
+                    }
+
+                    cmd_demo_requested.replace(Some(args[0].to_owned()));
+                }),
+            )
+            .unwrap();
+
+        let screenshot_requested = Rc::new(Cell::new(None));
+        let cmd_screenshot_requested = screenshot_requested.clone();
+        cmds.borrow_mut()
+            .insert_or_replace(
+                "screenshot_hires",
+                Box::new(move |args| {
+                    let multiplier = match args.len() {
+                        1 => args[0].parse::<u32>().ok(),
+                        _ => None,
+                    };
+
+                    match multiplier {
+                        Some(m) if m >= 1 && m <= screenshot::MAX_MULTIPLIER => {
+                            cmd_screenshot_requested.set(Some(m))
+                        }
+                        _ => println!("screenshot_hires <1-{}>", screenshot::MAX_MULTIPLIER),
+                    }
+                }),
+            )
+            .unwrap();
+
+        let cfg_vfs = vfs.clone();
+        let cfg_cvars = cvars.clone();
+        let cfg_input = input.clone();
+        cmds.borrow_mut()
+            .insert_or_replace(
+                "cfg_save",
+                Box::new(move |_| {
+                    match write_config(&cfg_vfs, &cfg_cvars.borrow(), &cfg_input.borrow()) {
+                        Ok(_) => println!("Wrote {}", CONFIG_FILE),
+                        Err(e) => println!("Couldn't write {}: {}", CONFIG_FILE, e),
+                    }
+                }),
+            )
+            .unwrap();
+
+        let signon_events = Rc::new(RefCell::new(Vec::new()));
+        let callback_events = signon_events.clone();
+        client.add_signon_callback(Box::new(move |stage| {
+            callback_events.borrow_mut().push(stage)
+        }));
+
         println!("Building menu renderer...");
         let menu_renderer = MenuRenderer::new(vfs.clone(), menu.clone(), gfx_pkg.clone()).unwrap();
+        let loading_renderer = LoadingRenderer::new(&vfs, &gfx_pkg.borrow())?;
         Ok(Game {
             vfs,
             cvars,
             cmds,
             menu,
             menu_renderer,
+            loading_renderer,
             gfx_pkg,
             state: GameState::Loading,
             input,
             client,
+            signon_events,
+            disconnect_requested,
+            demo_requested,
+            screenshot_requested,
         })
     }
 
     // advance the simulation
     pub fn frame(&mut self, frame_duration: Duration) {
-        self.client.frame(frame_duration).unwrap();
+        if self.disconnect_requested.replace(false) {
+            if let Err(e) = self.client.disconnect() {
+                println!("Error while disconnecting: {}", e);
+            }
+            self.handle_signon_events();
+        }
 
-        if let Some(ref mut game_input) = self.input.borrow_mut().game_input_mut() {
-            self.client
-                .handle_input(game_input, frame_duration)
-                .unwrap();
+        let demo_requested = self.demo_requested.borrow_mut().take();
+        if let Some(name) = demo_requested {
+            self.start_demo(&name);
+        }
+
+        if let GameState::Disconnected = self.state {
+            return;
         }
 
-        if let GameState::Loading = self.state {
-            println!("loading...");
-            // check if we've finished getting server info yet
-            if self.client.signon_stage() == SignOnStage::Done {
-                println!("finished loading");
-                // if we have, build renderers
-                let renderer = SceneRenderer::new(
-                    self.client.models().unwrap(),
-                    1,
-                    &mut self.gfx_pkg.borrow_mut(),
+        // in a demo, this reads recorded messages up to the new client time instead of polling
+        // the network
+        self.client
+            .frame(self.scale_frame_duration(frame_duration))
+            .unwrap();
+
+        if !self.client.is_demo() {
+            if let Some(ref mut game_input) = self.input.borrow_mut().game_input_mut() {
+                self.client
+                    .handle_input(game_input, frame_duration)
+                    .unwrap();
+            }
+        }
+
+        if let GameState::InGame(ref mut state) = self.state {
+            let conspeed = self.cvars.borrow().get_value("scr_conspeed").unwrap();
+            state.update_console_height(frame_duration, conspeed);
+
+            let zooming = self
+                .input
+                .borrow()
+                .game_input()
+                .map(|i| i.action_state(Action::Zoom))
+                .unwrap_or(false);
+            let zoom_time = self.cvars.borrow().get_value("zoom_time").unwrap();
+            state.update_zoom(frame_duration, zooming, zoom_time);
+        }
+
+        self.handle_signon_events();
+    }
+
+    // drop the current level and start playing back the demo `name`
+    fn start_demo(&mut self, name: &str) {
+        let demo = match DemoServer::load(&self.vfs, name) {
+            Ok(d) => d,
+            Err(e) => {
+                println!("Couldn't play demo: {}", e);
+                return;
+            }
+        };
+
+        if let Err(e) = self.client.start_demo(demo) {
+            println!("Couldn't play demo: {}", e);
+            return;
+        }
+
+        // tear down the old level before loading the demo's
+        self.handle_signon_events();
+        self.state = GameState::Loading;
+        self.input
+            .borrow_mut()
+            .set_focus(InputFocus::Game)
+            .unwrap();
+    }
+
+    // react to signon stage changes reported by the client
+    fn handle_signon_events(&mut self) {
+        let signon_events: Vec<SignOnStage> = self.signon_events.borrow_mut().drain(..).collect();
+        for stage in signon_events {
+            println!("signon stage: {:?}", stage);
+
+            match stage {
+                // once we've finished getting server info, build renderers
+                SignOnStage::Done => {
+                    if let GameState::Loading = self.state {
+                        println!("finished loading");
+                        self.enter_game();
+                    }
+                }
+
+                // dropping the in-game state frees the renderers and their GPU resources
+                SignOnStage::Not => {
+                    println!("disconnected");
+                    self.state = GameState::Disconnected;
+                    self.input
+                        .borrow_mut()
+                        .set_focus(InputFocus::Console)
+                        .unwrap();
+                    return;
+                }
+
+                _ => (),
+            }
+        }
+    }
+
+    // build renderers for the newly loaded level and start the game
+    fn enter_game(&mut self) {
+        let (display_width, display_height, _, _) =
+            self.gfx_pkg.borrow().color_target().get_dimensions();
+        let split_player = match self.cvars.borrow().get_value("cl_splitscreen").unwrap() {
+            p if p >= 1.0 => Some(p as usize),
+            _ => None,
+        };
+        let viewports =
+            split_viewports(display_width as u32, display_height as u32, split_player.is_some());
+
+        let renderer = SceneRenderer::new(
+            self.client.models().unwrap(),
+            1,
+            &mut self.gfx_pkg.borrow_mut(),
+            self.cvars.borrow().get_value("r_reversez").unwrap() != 0.0,
+            self.cvars.borrow().get_value("r_texturegamma").unwrap(),
+            DummyTexturePattern::from_cvar(
+                self.cvars.borrow().get_value("r_missingtexture").unwrap(),
+            ),
+            brush::subdivide_size(self.cvars.borrow().get_value("gl_subdivide_size").unwrap()),
+            self.cvars.borrow().get_value("r_scale").unwrap(),
+            viewports[0],
+        )
+        .unwrap();
+
+        let hud_renderer = HudRenderer::new(self.gfx_pkg.clone()).unwrap();
+
+        if let ModelKind::Brush(ref bmodel) = *self.client.models().unwrap()[1].kind() {
+            let bsp_data = bmodel.bsp_data();
+            let vfs = self.vfs.clone();
+            self.cmds
+                .borrow_mut()
+                .insert_or_replace(
+                    "lightmap_export",
+                    Box::new(move |args| {
+                        let dir = match args.len() {
+                            0 => "lightmaps",
+                            1 => args[0],
+                            _ => {
+                                println!("lightmap_export [directory]");
+                                return;
+                            }
+                        };
+
+                        match brush::export_lightmaps(&bsp_data, &vfs, dir) {
+                            Ok(n) => println!("Exported {} lightmaps to {}", n, dir),
+                            Err(e) => println!("Couldn't export lightmaps: {}", e),
+                        }
+                    }),
                 )
                 .unwrap();
+        }
+
+        self.state = GameState::InGame(InGameState::new(
+            self.cmds.clone(),
+            renderer,
+            hud_renderer,
+            InGameFocus::Game,
+            split_player,
+        ));
+    }
+
+    /// Scales `frame_duration` by `host_timescale` for the simulation.
+    ///
+    /// The timescale is clamped to a sane range and only applies in single-player games, where it
+    /// can't desynchronize us from other players.
+    fn scale_frame_duration(&self, frame_duration: Duration) -> Duration {
+        if self.client.max_players() > 1 {
+            return frame_duration;
+        }
+
+        let timescale = match self.cvars.borrow().get_value("host_timescale").unwrap() {
+            t if t.is_nan() || t <= 0.0 => return frame_duration,
+            t => t.max(MIN_TIMESCALE).min(MAX_TIMESCALE),
+        };
 
-                let hud_renderer = HudRenderer::new(self.gfx_pkg.clone()).unwrap();
+        engine::duration_from_f32(engine::duration_to_f32(frame_duration) * timescale)
+    }
 
-                self.state = GameState::InGame(InGameState::new(
-                    self.cmds.clone(),
-                    renderer,
-                    hud_renderer,
-                    InGameFocus::Game,
-                ));
+    /// Returns the window title, naming the current level once it's loaded.
+    pub fn window_title(&self) -> String {
+        match self.state {
+            GameState::InGame(_) => {
+                let level = self.client.level();
+                if level.title.is_empty() {
+                    format!("{} - {}", WINDOW_TITLE, level.name)
+                } else {
+                    format!("{} - {} ({})", WINDOW_TITLE, level.title, level.name)
+                }
             }
+
+            _ => WINDOW_TITLE.to_owned(),
         }
     }
 
@@ -208,11 +575,20 @@ impl Game {
             // ignore inputs during loading
             GameState::Loading => return,
 
+            // only the console is available once disconnected
+            GameState::Disconnected => (),
+
             GameState::InGame(ref state) => {
                 // set the proper focus
                 match state.focus.get() {
+                    // typing a message takes over the keys until it's sent or cancelled
                     InGameFocus::Game => {
-                        self.input.borrow_mut().set_focus(InputFocus::Game).unwrap()
+                        let focus = if self.input.borrow().chatting() {
+                            InputFocus::Chat
+                        } else {
+                            InputFocus::Game
+                        };
+                        self.input.borrow_mut().set_focus(focus).unwrap()
                     }
                     InGameFocus::Menu => {
                         self.input.borrow_mut().set_focus(InputFocus::Menu).unwrap()
@@ -239,65 +615,249 @@ impl Game {
         C: CommandBuffer<Resources>,
     {
         match self.state {
-            // TODO: loading screen
-            GameState::Loading => (),
+            GameState::Loading => {
+                let gfx_pkg = self.gfx_pkg.borrow();
+                let mut data = gfx_pkg.gen_user_data_2d();
+                self.loading_renderer
+                    .render(
+                        encoder,
+                        &gfx_pkg,
+                        &mut data,
+                        display_width,
+                        display_height,
+                        self.client.signon_stage(),
+                    )
+                    .unwrap();
+            }
 
             GameState::InGame(ref mut state) => {
-                let aspect = display_width as f32 / display_height as f32;
-                let fov_x = self.cvars.borrow().get_value("fov").unwrap();
-                let fov_y = math::fov_x_to_fov_y(cgmath::Deg(fov_x), aspect).unwrap();
+                // the tiles of last frame's screenshot have been flushed, so they can be read
+                if let Some(pending) = state.screenshot.take() {
+                    let gfx_pkg = self.gfx_pkg.borrow();
+                    let mut factory = gfx_pkg.factory_mut();
+                    let result = match screenshot::next_screenshot_path(&self.vfs) {
+                        Ok(path) => pending
+                            .finish(factory.deref_mut(), &self.vfs, &path)
+                            .map(|_| path),
+                        Err(e) => Err(e),
+                    };
+
+                    match result {
+                        Ok(path) => println!("Wrote {}", path),
+                        Err(e) => println!("Couldn't write screenshot: {}", e),
+                    }
+                }
 
-                let perspective = cgmath::perspective(fov_y, aspect, 4.0, 4096.0);
+                let viewports =
+                    split_viewports(display_width, display_height, state.split_player.is_some());
+                let fov_x = state.fov(
+                    self.cvars.borrow().get_value("fov").unwrap(),
+                    self.cvars.borrow().get_value("zoom_fov").unwrap(),
+                );
+                let far_clip =
+                    render::far_clip(self.cvars.borrow().get_value("r_farclip").unwrap());
+                let reverse_z = state.renderer.reverse_z();
+
+                // each view keeps the horizontal field of view across its own width
+                let projection = |viewport: &render::Viewport| {
+                    let aspect = viewport.width as f32 / viewport.height as f32;
+                    let fov_y = math::fov_x_to_fov_y(cgmath::Deg(fov_x), aspect).unwrap();
+                    let perspective =
+                        cgmath::perspective(fov_y, aspect, render::NEAR_CLIP, far_clip);
+                    if reverse_z {
+                        render::reverse_z_projection(perspective)
+                    } else {
+                        perspective
+                    }
+                };
 
+                let perspective = projection(&viewports[0]);
                 let camera = render::Camera::new(
                     self.client.view_origin(),
                     self.client.view_angles(),
                     perspective,
                 );
 
-                // render world
+                let lightmap_bilinear =
+                    self.cvars.borrow().get_value("gl_lightmap_bilinear").unwrap() != 0.0;
+                state.renderer.set_lightmap_bilinear(
+                    self.gfx_pkg.borrow().factory_mut().deref_mut(),
+                    lightmap_bilinear,
+                );
+
+                // gl_texturemode overrides the r_texturequality preset when set. unrecognized
+                // values leave the current filtering in place
+                let texture_mode = self.cvars.borrow().get("gl_texturemode").unwrap();
+                let texture_filter = if texture_mode.trim().is_empty() {
+                    let texture_quality = self.cvars.borrow().get("r_texturequality").unwrap();
+                    brush::TextureQuality::parse(&texture_quality).map(|q| q.filter_method())
+                } else {
+                    brush::parse_texture_mode(&texture_mode)
+                };
+
+                if let Some(filter) = texture_filter {
+                    state.renderer.set_texture_filter(
+                        self.gfx_pkg.borrow().factory_mut().deref_mut(),
+                        filter,
+                    );
+                }
+
+                let dynamic_intensity = self.cvars.borrow().get_value("r_dynamic").unwrap();
+                let max_updates = lightmap::max_updates(
+                    self.cvars.borrow().get_value("r_lightmap_maxupdates").unwrap(),
+                );
                 state
                     .renderer
-                    .render(
+                    .update_dynamic_lights(
                         encoder,
-                        user_data,
+                        self.client.dynamic_lights().iter(),
                         self.client.entities().unwrap(),
-                        self.client.view_ent(),
-                        self.client.weapon() as usize,
-                        self.client.time(),
-                        &camera,
-                        self.client.lightstyle_values().unwrap().as_slice(),
+                        dynamic_intensity,
+                        max_updates,
                     )
                     .unwrap();
 
-                state
-                    .hud_renderer
-                    .render(encoder, &self.client, display_width, display_height)
-                    .unwrap();
-
-                match state.focus.get() {
-                    // don't need to render anything else
-                    InGameFocus::Game => (),
-
-                    // render the console
-                    InGameFocus::Console => {
-                        let mut data = self.gfx_pkg.borrow().gen_user_data_2d();
+                // a requested screenshot renders each of its tiles before the normal view
+                let mut views = Vec::new();
+                let mut pending = None;
+                if let Some(multiplier) = self.screenshot_requested.take() {
+                    let (tile_width, tile_height) = state.renderer.scene_size();
+                    match HiresScreenshot::new(tile_width, tile_height, multiplier) {
+                        Ok(shot) => {
+                            for tile_y in 0..multiplier {
+                                for tile_x in 0..multiplier {
+                                    let projection = screenshot::tile_projection(
+                                        perspective,
+                                        multiplier,
+                                        tile_x,
+                                        tile_y,
+                                    );
+                                    let tile_camera = render::Camera::new(
+                                        self.client.view_origin(),
+                                        self.client.view_angles(),
+                                        projection,
+                                    );
+                                    views.push((
+                                        Some((tile_x, tile_y)),
+                                        viewports[0],
+                                        self.client.view_ent(),
+                                        tile_camera,
+                                    ));
+                                }
+                            }
+                            pending = Some(PendingScreenshot::new(shot));
+                        }
+
+                        Err(e) => println!("Couldn't take screenshot: {}", e),
+                    }
+                }
+                views.push((None, viewports[0], self.client.view_ent(), camera));
+
+                // the other half of a split screen looks out of the chosen player's eyes, or ours
+                // while that player isn't in the game
+                if let Some(player) = state.split_player {
+                    let (view_ent, origin, angles) = match self.client.player_view(player) {
+                        Some((origin, angles)) => (player, origin, angles),
+                        None => (
+                            self.client.view_ent(),
+                            self.client.view_origin(),
+                            self.client.view_angles(),
+                        ),
+                    };
+                    let split_camera =
+                        render::Camera::new(origin, angles, projection(&viewports[1]));
+                    views.push((None, viewports[1], view_ent, split_camera));
+                }
 
-                        self.gfx_pkg
-                            .borrow()
-                            .console_renderer()
-                            .render(
-                                encoder,
-                                self.gfx_pkg.borrow().pipeline_2d(),
-                                &mut data,
-                                display_width,
-                                display_height,
-                                0.5,
-                                1.0,
+                // render world
+                for &(tile, viewport, view_ent, ref camera) in views.iter() {
+                    // only our own weapon is known, so other players' views go without one
+                    let (view_model_id, view_model_frame, view_model_prev_frame, view_model_drop) =
+                        if view_ent == self.client.view_ent() {
+                            (
+                                self.client.weapon() as usize,
+                                self.client.weapon_frame(),
+                                self.client.prev_weapon_frame(),
+                                self.client.view_model_drop(),
                             )
+                        } else {
+                            (0, 0, 0, 0.0)
+                        };
+
+                    state
+                        .renderer
+                        .render(
+                            encoder,
+                            user_data,
+                            &viewport,
+                            self.client.entities().unwrap(),
+                            self.client.particles(),
+                            view_ent,
+                            view_model_id,
+                            view_model_frame,
+                            view_model_prev_frame,
+                            view_model_drop,
+                            self.client.time(),
+                            camera,
+                            self.client.lightstyle_values().unwrap().as_slice(),
+                            &self.client.fog(),
+                            &self.cvars.borrow(),
+                        )
+                        .unwrap();
+
+                    if let (Some((tile_x, tile_y)), Some(pending)) = (tile, pending.as_mut()) {
+                        let gfx_pkg = self.gfx_pkg.borrow();
+                        let download = state
+                            .renderer
+                            .download_scene(gfx_pkg.factory_mut().deref_mut(), encoder)
                             .unwrap();
+                        pending.add_tile(tile_x, tile_y, download);
+                    }
+                }
+                state.screenshot = pending;
+
+                let muzzle_flash_origin = state.renderer.muzzle_flash_origin();
+                self.client.set_muzzle_flash_origin(muzzle_flash_origin);
+
+                for viewport in viewports.iter() {
+                    state
+                        .hud_renderer
+                        .render(
+                            encoder,
+                            &self.client,
+                            &self.cvars.borrow(),
+                            viewport,
+                            display_width,
+                            display_height,
+                        )
+                        .unwrap();
+                }
+
+                match state.focus.get() {
+                    // draw the chat message being typed, if any
+                    InGameFocus::Game => {
+                        let input = self.input.borrow();
+                        if input.chatting() {
+                            let mut data = self.gfx_pkg.borrow().gen_user_data_2d();
+                            self.gfx_pkg
+                                .borrow()
+                                .console_renderer()
+                                .render_chat(
+                                    encoder,
+                                    self.gfx_pkg.borrow().pipeline_2d(),
+                                    &mut data,
+                                    display_width,
+                                    display_height,
+                                    &input.chat().borrow(),
+                                )
+                                .unwrap();
+                        }
                     }
 
+                    // don't need to render anything else, the console is drawn below if it's
+                    // still sliding closed
+                    InGameFocus::Console => (),
+
                     // render the menu
                     InGameFocus::Menu => {
                         let mut data = self.gfx_pkg.borrow().gen_user_data_2d();
@@ -314,6 +874,49 @@ impl Game {
                             .unwrap();
                     }
                 }
+
+                // render the console
+                if state.console_height > 0.0 {
+                    let mut data = self.gfx_pkg.borrow().gen_user_data_2d();
+                    let conalpha = self
+                        .cvars
+                        .borrow()
+                        .get_value("scr_conalpha")
+                        .unwrap()
+                        .max(0.0)
+                        .min(1.0);
+
+                    self.gfx_pkg
+                        .borrow()
+                        .console_renderer()
+                        .render(
+                            encoder,
+                            self.gfx_pkg.borrow().pipeline_2d(),
+                            &mut data,
+                            display_width,
+                            display_height,
+                            state.console_height,
+                            conalpha,
+                        )
+                        .unwrap();
+                }
+            }
+
+            GameState::Disconnected => {
+                let mut data = self.gfx_pkg.borrow().gen_user_data_2d();
+                self.gfx_pkg
+                    .borrow()
+                    .console_renderer()
+                    .render(
+                        encoder,
+                        self.gfx_pkg.borrow().pipeline_2d(),
+                        &mut data,
+                        display_width,
+                        display_height,
+                        1.0,
+                        1.0,
+                    )
+                    .unwrap();
             }
         }
     }
@@ -41,6 +41,7 @@ use std::path::Path;
 use std::process::exit;
 use std::rc::Rc;
 
+use richter::client::demo::DemoServer;
 use richter::client::input::game::MouseWheel;
 use richter::client::input::{Input, InputFocus};
 use richter::client::menu::Menu;
@@ -50,6 +51,7 @@ use richter::common;
 use richter::common::console::{CmdRegistry, Console, CvarRegistry};
 use richter::common::host::{Host, Program};
 use richter::common::vfs::Vfs;
+use richter::server;
 
 use game::Game;
 
@@ -57,6 +59,7 @@ use cgmath::{Matrix4, SquareMatrix};
 use chrono::Duration;
 use gfx::Encoder;
 use gfx_device_gl::{CommandBuffer, Device, Resources};
+use glutin::dpi::LogicalPosition;
 use glutin::{Event, EventsLoop, MouseCursor, WindowEvent, WindowedContext};
 use rodio::Endpoint;
 
@@ -79,6 +82,7 @@ struct ClientProgram {
 
     events_loop: RefCell<EventsLoop>,
     windowed_context: RefCell<WindowedContext>,
+    window_title: RefCell<String>,
 
     gfx_pkg: Rc<RefCell<GraphicsPackage>>,
     device: RefCell<Device>,
@@ -113,21 +117,41 @@ impl ClientProgram {
             vfs.add_pakfile(path).unwrap();
         }
 
+        // then PK3 archives, in alphabetical order so later ones override earlier ones
+        if let Ok(dir) = std::fs::read_dir(common::DEFAULT_BASEDIR) {
+            let mut pk3_paths: Vec<_> = dir
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| match p.extension().and_then(|e| e.to_str()) {
+                    Some(ext) => ext.eq_ignore_ascii_case("pk3") || ext.eq_ignore_ascii_case("zip"),
+                    None => false,
+                })
+                .collect();
+            pk3_paths.sort();
+
+            for path in pk3_paths {
+                if let Err(e) = vfs.add_pk3file(&path) {
+                    println!("Couldn't mount {}: {}", path.display(), e);
+                }
+            }
+        }
+
         let cvars = Rc::new(RefCell::new(CvarRegistry::new()));
         client::register_cvars(&cvars.borrow_mut());
+        server::register_cvars(&cvars.borrow_mut());
 
         let cmds = Rc::new(RefCell::new(CmdRegistry::new()));
         // TODO: register commands as other subsystems come online
 
         let console = Rc::new(RefCell::new(Console::new(cmds.clone(), cvars.clone())));
-        let menu = Rc::new(RefCell::new(menu::build_main_menu().unwrap()));
+        let menu = Rc::new(RefCell::new(menu::build_main_menu(cvars.clone()).unwrap()));
 
         let input = Rc::new(RefCell::new(Input::new(InputFocus::Game, console.clone(), menu.clone())));
         input.borrow_mut().bind_defaults();
 
         let events_loop = glutin::EventsLoop::new();
         let window_builder = glutin::WindowBuilder::new()
-            .with_title("Richter client")
+            .with_title(game::WINDOW_TITLE)
             .with_dimensions((1600, 900).into());
         let context_builder = glutin::ContextBuilder::new()
             .with_gl(glutin::GlRequest::Specific(glutin::Api::OpenGl, (3, 3)))
@@ -158,10 +182,18 @@ impl ClientProgram {
 
         let data = render::pipe::Data {
             vertex_buffer: factory.create_vertex_buffer(&[]),
+            normal_buffer: factory.create_vertex_buffer(&[]),
             transform: Matrix4::identity().into(),
+            shade_vector: [0.0; 3],
             sampler: (dummy_texture.clone(), sampler.clone()),
+            light: 1.0,
+            alpha: 1.0,
+            fog_color: [0.0; 4],
+            fog_density: 0.0,
             out_color: color.clone(),
-            out_depth: depth.clone(),
+            out_depth: factory
+                .create_depth_stencil_view_only::<render::SceneDepthFormat>(1, 1)
+                .expect("dummy depth target creation failed"),
         };
 
         let encoder = factory.create_command_buffer().into();
@@ -177,7 +209,11 @@ impl ClientProgram {
         )));
 
         // this will also execute config.cfg and autoexec.cfg (assuming an unmodified quake.rc)
-        console.borrow().stuff_text("exec quake.rc\n");
+        if vfs.open("quake.rc").is_ok() {
+            console.borrow().stuff_text("exec quake.rc\n");
+        } else if vfs.open("config.cfg").is_ok() {
+            console.borrow().stuff_text("exec config.cfg\n");
+        }
 
         ClientProgram {
             vfs: Rc::new(vfs),
@@ -187,6 +223,7 @@ impl ClientProgram {
             menu,
             events_loop: RefCell::new(events_loop),
             windowed_context: RefCell::new(windowed_context),
+            window_title: RefCell::new(game::WINDOW_TITLE.to_owned()),
             gfx_pkg,
             device: RefCell::new(device),
             encoder: RefCell::new(encoder),
@@ -207,10 +244,29 @@ impl ClientProgram {
             self.cvars.clone(),
             self.cmds.clone(),
             self.console.clone(),
-            self.endpoint.clone(),
+            Some(self.endpoint.clone()),
+        )
+        .unwrap();
+
+        self.start_game(cl);
+    }
+
+    fn play_demo(&mut self, name: &str) {
+        let demo = DemoServer::load(&self.vfs, name).unwrap();
+        let cl = Client::play_demo(
+            demo,
+            self.vfs.clone(),
+            self.cvars.clone(),
+            self.cmds.clone(),
+            self.console.clone(),
+            Some(self.endpoint.clone()),
         )
         .unwrap();
 
+        self.start_game(cl);
+    }
+
+    fn start_game(&mut self, cl: Client) {
         cl.register_cmds(&mut self.cmds.borrow_mut());
 
         self.state.replace(ProgramState::Game(
@@ -231,9 +287,11 @@ impl ClientProgram {
         self.encoder
             .borrow_mut()
             .clear(&self.gfx_pkg.borrow().color_target(), [0.0, 0.0, 0.0, 1.0]);
-        self.encoder
-            .borrow_mut()
-            .clear_depth(&self.gfx_pkg.borrow().depth_stencil(), 1.0);
+        // the scene has its own depth target, so the display always uses the standard convention
+        self.encoder.borrow_mut().clear_depth(
+            &self.gfx_pkg.borrow().depth_stencil(),
+            render::depth_clear_value(false),
+        );
         let (win_w, win_h) = self
             .windowed_context
             .borrow()
@@ -279,6 +337,13 @@ impl Program for ClientProgram {
 
             ProgramState::Game(ref mut game) => {
                 game.frame(frame_duration);
+
+                // name the level in the window title once it's loaded
+                let title = game.window_title();
+                if *self.window_title.borrow() != title {
+                    self.windowed_context.borrow().set_title(&title);
+                    self.window_title.replace(title);
+                }
             }
         }
 
@@ -302,13 +367,30 @@ impl Program for ClientProgram {
             });
         flame::end("EventsLoop::poll_events");
 
-        match self.input.borrow().current_focus() {
+        let focus = self.input.borrow().current_focus();
+        match focus {
             InputFocus::Game => {
                 self.windowed_context
                     .borrow_mut()
                     .grab_cursor(true)
                     .unwrap();
                 self.windowed_context.borrow_mut().hide_cursor(true);
+
+                // the grabbed cursor stops at the window edge, so without raw input it has to be
+                // put back in the middle every frame
+                let mut input = self.input.borrow_mut();
+                if let Some(game_input) = input.game_input_mut() {
+                    if !game_input.raw_mouse() {
+                        let size = self.windowed_context.borrow().get_inner_size();
+                        if let Some(size) = size {
+                            let center = LogicalPosition::new(size.width / 2.0, size.height / 2.0);
+                            match self.windowed_context.borrow().set_cursor_position(center) {
+                                Ok(()) => game_input.recenter_cursor(center),
+                                Err(e) => println!("Couldn't recenter cursor: {}", e),
+                            }
+                        }
+                    }
+                }
             }
 
             _ => {
@@ -332,13 +414,25 @@ fn main() {
 
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 2 {
-        println!("Usage: {} <server_address>", args[0]);
-        exit(1);
-    }
+    let client_program = match args.len() {
+        2 => {
+            let mut program = ClientProgram::new();
+            program.connect(&args[1]);
+            program
+        }
 
-    let mut client_program = ClientProgram::new();
-    client_program.connect(&args[1]);
+        3 if args[1] == "-playdemo" => {
+            let mut program = ClientProgram::new();
+            program.play_demo(&args[2]);
+            program
+        }
+
+        _ => {
+            println!("Usage: {} <server_address>", args[0]);
+            println!("       {} -playdemo <demoname>", args[0]);
+            exit(1);
+        }
+    };
     let mut host = Host::new(client_program);
 
     loop {
@@ -18,14 +18,21 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-use richter::client::menu::{Menu, MenuBuilder};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use richter::client::menu::{EnumItem, Menu, MenuBuilder};
+use richter::common::console::CvarRegistry;
+use richter::server;
 
 use failure::Error;
 
-pub fn build_main_menu() -> Result<Menu, Error> {
+const SKILL_NAMES: [&str; 4] = ["Easy", "Normal", "Hard", "Nightmare"];
+
+pub fn build_main_menu(cvars: Rc<RefCell<CvarRegistry>>) -> Result<Menu, Error> {
     Ok(MenuBuilder::new()
         .with_gfx("gfx/mainmenu.lmp")
-        .add_submenu("Single Player", build_menu_sp()?)
+        .add_submenu("Single Player", build_menu_sp(cvars)?)
         .add_submenu("Multiplayer", build_menu_mp()?)
         .add_submenu("Options", build_menu_options()?)
         .add_action("Help/Ordering", Box::new(|| ()))
@@ -33,8 +40,24 @@ pub fn build_main_menu() -> Result<Menu, Error> {
         .build())
 }
 
-fn build_menu_sp() -> Result<Menu, Error> {
+fn build_menu_sp(cvars: Rc<RefCell<CvarRegistry>>) -> Result<Menu, Error> {
+    let mut skill_items = Vec::new();
+    for (skill, name) in SKILL_NAMES.iter().enumerate() {
+        let cvars = cvars.clone();
+        skill_items.push(EnumItem::new(
+            *name,
+            Box::new(move || {
+                cvars
+                    .borrow()
+                    .set("skill", &skill.to_string())
+                    .unwrap()
+            }),
+        )?);
+    }
+    let init_skill = server::skill_level(&cvars.borrow()) as usize;
+
     Ok(MenuBuilder::new()
+        .add_enum("Skill", skill_items, init_skill)?
         .add_action("New Game", Box::new(|| ()))
         // .add_submenu("Load", unimplemented!())
         // .add_submenu("Save", unimplemented!())
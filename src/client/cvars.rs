@@ -18,9 +18,14 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-use common::console::CvarRegistry;
+use common::console::{CvarKind, CvarRegistry};
 
 pub fn register_cvars(cvars: &CvarRegistry) {
+    cvars.register_archive("bgm_duckrelease", "1").unwrap();
+    cvars.register_archive("bgm_duckthreshold", "0.75").unwrap();
+    cvars.register_archive("bgm_ducking", "0.5").unwrap();
+    cvars.register_archive("bgmvolume", "1").unwrap();
+    cvars.register_archive("cl_allowdownload", "0").unwrap();
     cvars.register("cl_anglespeedkey", "1.5").unwrap();
     cvars.register_archive("cl_backspeed", "200").unwrap();
     cvars.register("cl_bob", "0.02").unwrap();
@@ -29,18 +34,85 @@ pub fn register_cvars(cvars: &CvarRegistry) {
     cvars.register_archive("_cl_color", "0").unwrap();
     cvars.register("cl_crossx", "0").unwrap();
     cvars.register("cl_crossy", "0").unwrap();
+    cvars.register_user_only("cl_downloadurl", "").unwrap();
     cvars.register_archive("cl_forwardspeed", "400").unwrap();
+    cvars.register_archive("cl_hitmarker", "0").unwrap();
+    cvars.register_archive("cl_hud_warn", "1").unwrap();
+    cvars.register_archive("cl_hud_warn_ammo", "10").unwrap();
+    cvars.register_archive("cl_hud_warn_health", "25").unwrap();
+    cvars.register_archive("cl_instantswitch", "0").unwrap();
+    cvars.register_archive("cl_maxcorpses", "0").unwrap();
+    cvars.register_archive("cl_maxgibs", "0").unwrap();
     cvars.register("cl_movespeedkey", "2.0").unwrap();
+    cvars.register_archive("cl_particlelimit", "4096").unwrap();
     cvars.register_archive("_cl_name", "player").unwrap();
     cvars.register("cl_nolerp", "0").unwrap();
+    cvars.register_archive("cl_nolerp_models", "").unwrap();
     cvars.register("cl_pitchspeed", "150").unwrap();
+    cvars.register_archive("cl_port", "0").unwrap();
+    cvars.register_archive("cl_precache_tolerant", "0").unwrap();
+    cvars.register_archive("cl_predict", "0").unwrap();
     cvars.register("cl_rollangle", "2.0").unwrap();
     cvars.register("cl_rollspeed", "200").unwrap();
+    cvars.register("cl_seed", "0").unwrap();
     cvars.register("cl_shownet", "0").unwrap();
+    cvars.register("cl_showpos", "0").unwrap();
     cvars.register("cl_sidespeed", "350").unwrap();
+    cvars.register_archive("cl_splitscreen", "0").unwrap();
     cvars.register("cl_upspeed", "200").unwrap();
+    cvars.register_archive("cl_weaponswitchtime", "0.3").unwrap();
     cvars.register("cl_yawspeed", "140").unwrap();
-    cvars.register("fov", "90").unwrap();
+    cvars.register_archive("crosshair", "0").unwrap();
+    cvars
+        .register_typed("fov", "90", CvarKind::Float { min: 10.0, max: 170.0 })
+        .unwrap();
+    cvars.register_archive("gl_lightmap_bilinear", "1").unwrap();
+    cvars.register_archive("gl_subdivide_size", "128").unwrap();
+    cvars.register_archive("gl_texturemode", "").unwrap();
+    cvars.register("host_timescale", "1").unwrap();
     cvars.register_archive("m_pitch", "0.022").unwrap();
+    cvars.register_archive("m_rawinput", "1").unwrap();
+    cvars.register_archive("m_smooth", "1").unwrap();
     cvars.register_archive("m_yaw", "0.022").unwrap();
+    cvars.register_archive("max_edicts", "600").unwrap();
+    cvars.register("r_drawentities", "1").unwrap();
+    cvars.register("r_dynamic", "1").unwrap();
+    cvars.register_archive("r_farclip", "4096").unwrap();
+    cvars.register_archive("r_lavaalpha", "1").unwrap();
+    cvars.register_archive("r_lavatint", "1 0.3 0.1 0.6").unwrap();
+    cvars.register_archive("r_lerpmodels", "1").unwrap();
+    cvars.register_archive("r_lightcache_dist", "1").unwrap();
+    cvars.register_archive("r_lightmap_maxupdates", "0").unwrap();
+    cvars.register_archive("r_maxdrawdist", "0").unwrap();
+    cvars.register("r_missingtexture", "1").unwrap();
+    cvars.register_archive("r_particle_size", "2").unwrap();
+    cvars.register_archive("r_particles", "1").unwrap();
+    cvars.register_archive("r_reversez", "0").unwrap();
+    cvars.register_archive("r_scale", "1").unwrap();
+    cvars.register_archive("r_scalefilter", "0").unwrap();
+    cvars.register_archive("r_shadows", "0").unwrap();
+    cvars.register("r_showbboxes", "0").unwrap();
+    cvars.register_archive("r_slimealpha", "1").unwrap();
+    cvars.register_archive("r_slimetint", "0.3 1 0.3 0.6").unwrap();
+    cvars.register_archive("r_telealpha", "1").unwrap();
+    cvars.register_archive("r_texturegamma", "1").unwrap();
+    cvars.register_archive("r_texturequality", "low").unwrap();
+    cvars.register_archive("r_translucent_prepass", "1").unwrap();
+    cvars.register_archive("r_viewmodel_lefthand", "0").unwrap();
+    cvars.register_archive("r_viewmodel_offset_x", "0").unwrap();
+    cvars.register_archive("r_viewmodel_offset_y", "0").unwrap();
+    cvars.register_archive("r_viewmodel_offset_z", "0").unwrap();
+    cvars.register_archive("r_wateralpha", "1").unwrap();
+    cvars.register_archive("r_watertint", "0.4 0.6 1 0.5").unwrap();
+    cvars.register_archive("r_waterwarp", "1").unwrap();
+    cvars.register_archive("rate", "2500").unwrap();
+    cvars.register_archive("s_channel_overflow", "oldest").unwrap();
+    cvars.register_archive("s_channels", "128").unwrap();
+    cvars.register_archive("s_doppler", "0").unwrap();
+    cvars.register_archive("s_pitch_variation", "0").unwrap();
+    cvars.register_archive("scr_conalpha", "1").unwrap();
+    cvars.register("scr_conspeed", "300").unwrap();
+    cvars.register_archive("volume", "0.7").unwrap();
+    cvars.register_archive("zoom_fov", "30").unwrap();
+    cvars.register_archive("zoom_time", "0.2").unwrap();
 }
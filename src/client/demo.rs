@@ -0,0 +1,425 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Demo (`.dem`) playback.
+//!
+//! A demo is the CD track to play, written as text and terminated by a newline, followed by the
+//! server messages the recording client received. Each message is prefixed by its length and the
+//! client's view angles at the time it was received.
+
+use std::cell::RefCell;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::rc::Rc;
+
+use client::Client;
+use common::console::{CmdRegistry, Console, CvarRegistry};
+use common::net::ServerCmd;
+use common::vfs::Vfs;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use cgmath::{Deg, Vector3};
+use chrono::Duration;
+use failure::Error;
+
+// the largest message the original engine will record
+const MAX_DEMO_MESSAGE: usize = 8192;
+
+/// Returns the path of the demo `name`, adding the `.dem` extension if it has none.
+pub fn demo_path<S>(name: S) -> String
+where
+    S: AsRef<str>,
+{
+    let name = name.as_ref();
+    let file_name = name.rsplit('/').next().unwrap_or(name);
+    if file_name.contains('.') {
+        name.to_owned()
+    } else {
+        format!("{}.dem", name)
+    }
+}
+
+/// A single server message recorded in a demo.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DemoMessage {
+    view_angles: Vector3<Deg<f32>>,
+    message: Vec<u8>,
+}
+
+impl DemoMessage {
+    pub fn new(view_angles: Vector3<Deg<f32>>, message: Vec<u8>) -> DemoMessage {
+        DemoMessage {
+            view_angles,
+            message,
+        }
+    }
+
+    /// The recording client's view angles when this message was received.
+    pub fn view_angles(&self) -> Vector3<Deg<f32>> {
+        self.view_angles
+    }
+
+    pub fn message(&self) -> &[u8] {
+        &self.message
+    }
+}
+
+/// Plays back the messages in a demo file in place of a server.
+#[derive(Debug)]
+pub struct DemoServer {
+    track_override: Option<u32>,
+    messages: Vec<DemoMessage>,
+    next: usize,
+}
+
+impl DemoServer {
+    /// Reads a demo file in its entirety.
+    pub fn new<R>(file: R) -> Result<DemoServer, Error>
+    where
+        R: Read,
+    {
+        let mut reader = BufReader::new(file);
+
+        let mut track = String::new();
+        reader.read_line(&mut track)?;
+        ensure!(track.ends_with('\n'), "Demo has no CD track line");
+        let track_override = match track.trim().parse::<i32>()? {
+            t if t < 0 => None,
+            t => Some(t as u32),
+        };
+
+        let mut messages = Vec::new();
+        loop {
+            let len = match reader.read_i32::<LittleEndian>() {
+                Ok(l) => l,
+
+                // a clean end of file between messages is the end of the demo
+                Err(ref e) if e.kind() == ::std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            };
+            ensure!(
+                len >= 0 && len as usize <= MAX_DEMO_MESSAGE,
+                "Bad demo message length {}",
+                len
+            );
+
+            let mut view_angles = Vector3::new(Deg(0.0), Deg(0.0), Deg(0.0));
+            for i in 0..3 {
+                view_angles[i] = Deg(reader.read_f32::<LittleEndian>()?);
+            }
+
+            let mut message = vec![0; len as usize];
+            reader.read_exact(&mut message)?;
+
+            messages.push(DemoMessage {
+                view_angles,
+                message,
+            });
+        }
+
+        Ok(DemoServer {
+            track_override,
+            messages,
+            next: 0,
+        })
+    }
+
+    /// Reads the demo `name` from the virtual filesystem.
+    ///
+    /// As with the original `playdemo` command, the `.dem` extension may be omitted.
+    pub fn load<S>(vfs: &Vfs, name: S) -> Result<DemoServer, Error>
+    where
+        S: AsRef<str>,
+    {
+        let path = demo_path(name);
+        let file = vfs
+            .open(&path)
+            .map_err(|e| format_err!("Couldn't open {}: {}", path, e))?;
+        DemoServer::new(file)
+    }
+
+    /// The CD track the demo plays instead of the map's, if any.
+    pub fn track_override(&self) -> Option<u32> {
+        self.track_override
+    }
+
+    /// Returns the next message in the demo, or `None` once playback is finished.
+    pub fn next(&mut self) -> Option<&DemoMessage> {
+        let msg = self.messages.get(self.next)?;
+        self.next += 1;
+        Some(msg)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.messages.len()
+    }
+}
+
+/// Writes the server messages received by the client to a demo file.
+pub struct DemoRecorder<W>
+where
+    W: Write,
+{
+    writer: W,
+}
+
+impl DemoRecorder<::std::fs::File> {
+    /// Creates the demo `name` in the virtual filesystem and starts recording to it.
+    ///
+    /// As with `DemoServer::load`, the `.dem` extension may be omitted.
+    pub fn create<S>(
+        vfs: &Vfs,
+        name: S,
+        track_override: Option<u32>,
+    ) -> Result<DemoRecorder<::std::fs::File>, Error>
+    where
+        S: AsRef<str>,
+    {
+        let path = demo_path(name);
+        let file = vfs
+            .create(&path)
+            .map_err(|e| format_err!("Couldn't create {}: {}", path, e))?;
+        DemoRecorder::new(file, track_override)
+    }
+}
+
+impl<W> DemoRecorder<W>
+where
+    W: Write,
+{
+    /// Starts a demo by writing the CD track header to `writer`.
+    pub fn new(mut writer: W, track_override: Option<u32>) -> Result<DemoRecorder<W>, Error> {
+        match track_override {
+            Some(t) => writeln!(writer, "{}", t)?,
+            None => writeln!(writer, "-1")?,
+        }
+
+        Ok(DemoRecorder { writer })
+    }
+
+    /// Appends a server message, along with the view angles it was received with.
+    pub fn write_message(
+        &mut self,
+        view_angles: Vector3<Deg<f32>>,
+        message: &[u8],
+    ) -> Result<(), Error> {
+        ensure!(
+            message.len() <= MAX_DEMO_MESSAGE,
+            "Message too long to record ({} bytes)",
+            message.len()
+        );
+
+        self.writer.write_i32::<LittleEndian>(message.len() as i32)?;
+        for i in 0..3 {
+            self.writer.write_f32::<LittleEndian>(view_angles[i].0)?;
+        }
+        self.writer.write_all(message)?;
+
+        Ok(())
+    }
+
+    /// Ends the demo with a disconnect so playback stops cleanly, returning the writer.
+    pub fn finish(mut self, view_angles: Vector3<Deg<f32>>) -> Result<W, Error> {
+        let mut msg = Vec::new();
+        ServerCmd::Disconnect.serialize(&mut msg)?;
+        self.write_message(view_angles, &msg)?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+/// Plays a demo without rendering, stepping the client at a fixed frame rate.
+///
+/// Since the client's time only advances in fixed steps, playback is deterministic, which makes
+/// this suitable for regression tests of message parsing and interpolation.
+pub struct DemoHarness {
+    client: Client,
+    frame_time: Duration,
+}
+
+impl DemoHarness {
+    /// The frame time used unless another is set with `set_frame_time`.
+    pub fn default_frame_time() -> Duration {
+        Duration::milliseconds(10)
+    }
+
+    /// Starts playing `demo`, loading precached assets from `vfs`.
+    pub fn new(vfs: Rc<Vfs>, demo: DemoServer) -> Result<DemoHarness, Error> {
+        let cvars = Rc::new(RefCell::new(CvarRegistry::new()));
+        ::client::register_cvars(&cvars.borrow());
+
+        // reproducible particle effects
+        cvars.borrow_mut().set("cl_seed", "1").unwrap();
+
+        let cmds = Rc::new(RefCell::new(CmdRegistry::new()));
+        let console = Rc::new(RefCell::new(Console::new(cmds.clone(), cvars.clone())));
+
+        // sounds are still started and tracked, but without an endpoint nothing is played, so the
+        // harness runs on machines without a sound device
+        let client = Client::play_demo(demo, vfs, cvars, cmds, console, None)?;
+
+        Ok(DemoHarness {
+            client,
+            frame_time: DemoHarness::default_frame_time(),
+        })
+    }
+
+    pub fn set_frame_time(&mut self, frame_time: Duration) {
+        self.frame_time = frame_time;
+    }
+
+    /// Runs client frames until its time reaches `time`.
+    ///
+    /// Once the demo runs out of messages, the client keeps running without updates.
+    pub fn run_until(&mut self, time: Duration) -> Result<(), Error> {
+        while self.client.time() < time {
+            self.client.frame(self.frame_time)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    use common::net::ClientStat;
+
+    fn write_message(demo: &mut Vec<u8>, angles: [f32; 3], message: &[u8]) {
+        demo.write_i32::<LittleEndian>(message.len() as i32).unwrap();
+        for a in angles.iter() {
+            demo.write_f32::<LittleEndian>(*a).unwrap();
+        }
+        demo.extend_from_slice(message);
+    }
+
+    #[test]
+    fn test_demo_server_read() {
+        let mut demo = b"-1\n".to_vec();
+        write_message(&mut demo, [10.0, 90.0, 0.0], &[1, 2, 3]);
+        write_message(&mut demo, [0.0, 0.0, 0.0], &[]);
+
+        let mut server = DemoServer::new(Cursor::new(demo)).unwrap();
+        assert_eq!(server.track_override(), None);
+        {
+            let first = server.next().unwrap();
+            assert_eq!(first.view_angles(), Vector3::new(Deg(10.0), Deg(90.0), Deg(0.0)));
+            assert_eq!(first.message(), &[1, 2, 3]);
+        }
+        assert_eq!(server.next().unwrap().message(), &[] as &[u8]);
+        assert!(server.is_finished());
+        assert!(server.next().is_none());
+    }
+
+    #[test]
+    fn test_demo_recorder_round_trip() {
+        let angles = Vector3::new(Deg(-5.0), Deg(270.0), Deg(1.5));
+        let mut recorder = DemoRecorder::new(Vec::new(), Some(4)).unwrap();
+        recorder.write_message(angles, &[1, 2, 3]).unwrap();
+        recorder.write_message(angles, &[]).unwrap();
+        let demo = recorder.finish(angles).unwrap();
+        assert!(demo.starts_with(b"4\n"));
+
+        let mut server = DemoServer::new(Cursor::new(demo)).unwrap();
+        assert_eq!(server.track_override(), Some(4));
+        assert_eq!(server.next().unwrap(), &DemoMessage::new(angles, vec![1, 2, 3]));
+        assert_eq!(server.next().unwrap(), &DemoMessage::new(angles, Vec::new()));
+
+        let mut disconnect = Vec::new();
+        ServerCmd::Disconnect.serialize(&mut disconnect).unwrap();
+        assert_eq!(server.next().unwrap().message(), &disconnect[..]);
+        assert!(server.is_finished());
+    }
+
+    #[test]
+    fn test_demo_recorder_rejects_long_message() {
+        let mut recorder = DemoRecorder::new(Vec::new(), None).unwrap();
+        let angles = Vector3::new(Deg(0.0), Deg(0.0), Deg(0.0));
+        assert!(recorder
+            .write_message(angles, &vec![0; MAX_DEMO_MESSAGE + 1])
+            .is_err());
+    }
+
+    #[test]
+    fn test_demo_path() {
+        assert_eq!(demo_path("demo1"), "demo1.dem");
+        assert_eq!(demo_path("demo1.dem"), "demo1.dem");
+        assert_eq!(demo_path("demos.old/demo1"), "demos.old/demo1.dem");
+    }
+
+    #[test]
+    fn test_demo_server_bad_input() {
+        assert!(DemoServer::new(Cursor::new(b"2".to_vec())).is_err());
+
+        let mut truncated = b"2\n".to_vec();
+        write_message(&mut truncated, [0.0; 3], &[1, 2, 3]);
+        truncated.pop();
+        assert!(DemoServer::new(Cursor::new(truncated)).is_err());
+
+        let mut too_long = b"2\n".to_vec();
+        too_long.write_i32::<LittleEndian>(MAX_DEMO_MESSAGE as i32 + 1).unwrap();
+        assert!(DemoServer::new(Cursor::new(too_long)).is_err());
+    }
+
+    // plays testdata/harness/harness.dem, in which a sprite (entity 1) moves from the origin
+    // along the x axis at 100 units per second, updated every 100ms, while the recording
+    // player turns 90 degrees per second and loses 10 health per second.
+    //
+    // the client computes its lerp factor before advancing its time, so interpolated values
+    // trail the demo by one 10ms frame, and stats are applied as soon as their message is read.
+    #[test]
+    fn test_demo_harness() {
+        let data = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata/harness");
+        let mut vfs = Vfs::new();
+        vfs.add_directory(&data).unwrap();
+        let vfs = Rc::new(vfs);
+
+        let demo = DemoServer::new(vfs.open("harness.dem").unwrap()).unwrap();
+        let mut harness = DemoHarness::new(vfs, demo).unwrap();
+
+        harness.run_until(Duration::milliseconds(250)).unwrap();
+        {
+            let client = harness.client();
+            let origin = client.entities().unwrap()[1].get_origin();
+            assert!((origin.x - 24.0).abs() < 0.01, "origin.x = {}", origin.x);
+            assert_eq!(origin.y, 0.0);
+
+            let yaw = client.view_angles().y;
+            assert!((yaw.0 - 21.6).abs() < 0.01, "yaw = {:?}", yaw);
+
+            // the message at 300ms has already been read
+            assert_eq!(client.stats()[ClientStat::Health as usize], 97);
+        }
+
+        harness.run_until(Duration::milliseconds(1000)).unwrap();
+        {
+            let client = harness.client();
+            let origin = client.entities().unwrap()[1].get_origin();
+            assert!((origin.x - 99.0).abs() < 0.01, "origin.x = {}", origin.x);
+            assert_eq!(client.stats()[ClientStat::Health as usize], 90);
+            assert!(client.demo_finished());
+        }
+    }
+}
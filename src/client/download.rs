@@ -0,0 +1,312 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Downloading missing content over HTTP.
+//!
+//! The user points `cl_downloadurl` at a server's content, and missing files are fetched from
+//! `<base>/<path>` on a background thread and written to the `Vfs` write directory.
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::Duration as StdDuration;
+
+use common::vfs::Vfs;
+
+use failure::Error;
+
+const DOWNLOAD_TIMEOUT_SECS: u64 = 10;
+
+// no single game asset comes close to this
+const MAX_DOWNLOAD_SIZE: usize = 64 * 1024 * 1024;
+
+// only these kinds of content may be downloaded
+const DOWNLOAD_DIRS: &[&str] = &["gfx/", "maps/", "progs/", "sound/"];
+const DOWNLOAD_EXTENSIONS: &[&str] = &[".bsp", ".ent", ".lit", ".lmp", ".mdl", ".spr", ".wav"];
+
+/// Checks that `path` is safe to write downloaded content to.
+///
+/// Paths must be relative, stay inside one of the content directories and name a known asset
+/// type, so that a server can't overwrite configs or escape the game directory. The case of the
+/// path is kept, since that's the name the file is requested and later looked up by.
+pub fn sanitize_download_path(path: &str) -> Result<String, Error> {
+    // directory and extension names are matched regardless of case
+    let lower = path.to_lowercase();
+
+    ensure!(
+        !path.starts_with('/') && !path.contains('\\') && !path.contains(':'),
+        "\"{}\" isn't a relative path",
+        path
+    );
+    ensure!(
+        path.split('/').all(|c| !c.is_empty() && c != "." && c != ".."),
+        "\"{}\" has an invalid path component",
+        path
+    );
+    ensure!(
+        DOWNLOAD_DIRS.iter().any(|d| lower.starts_with(d)),
+        "\"{}\" isn't in a content directory",
+        path
+    );
+    ensure!(
+        DOWNLOAD_EXTENSIONS.iter().any(|e| lower.ends_with(e)),
+        "\"{}\" isn't a downloadable file type",
+        path
+    );
+
+    Ok(path.to_owned())
+}
+
+/// Splits an `http://host[:port]/path` URL into its host, port and path.
+fn parse_http_url(url: &str) -> Result<(String, u16, String), Error> {
+    ensure!(url.starts_with("http://"), "Only http:// URLs are supported");
+    let rest = &url["http://".len()..];
+
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+
+    let (host, port) = match authority.rfind(':') {
+        Some(i) => (&authority[..i], authority[i + 1..].parse()?),
+        None => (authority, 80),
+    };
+    ensure!(!host.is_empty(), "No host in URL \"{}\"", url);
+
+    Ok((host.to_owned(), port, path.to_owned()))
+}
+
+/// Extracts the body of an HTTP response, failing if the status isn't 200.
+fn parse_http_response(response: &[u8]) -> Result<Vec<u8>, Error> {
+    let header_end = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(i) => i,
+        None => bail!("Incomplete HTTP response"),
+    };
+
+    let header = String::from_utf8_lossy(&response[..header_end]);
+    let mut lines = header.split("\r\n");
+    let status = lines.next().unwrap_or("");
+    let code = status.split_whitespace().nth(1).unwrap_or("");
+    ensure!(code == "200", "HTTP request failed ({})", status);
+
+    let body = &response[header_end + 4..];
+    for line in lines {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            let len: usize = parts.next().unwrap_or("").trim().parse()?;
+            ensure!(body.len() >= len, "HTTP response truncated");
+            return Ok(body[..len].to_vec());
+        }
+    }
+
+    Ok(body.to_vec())
+}
+
+/// Fetches `url` with a blocking HTTP/1.0 GET request.
+pub fn http_get(url: &str) -> Result<Vec<u8>, Error> {
+    let (host, port, path) = parse_http_url(url)?;
+
+    let mut stream = TcpStream::connect((host.as_str(), port))?;
+    let timeout = Some(StdDuration::from_secs(DOWNLOAD_TIMEOUT_SECS));
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+
+    // send the request in one write so the server sees it all at once
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: Richter\r\n\r\n",
+        path, host
+    );
+    stream.write_all(request.as_bytes())?;
+
+    let mut response = Vec::new();
+    stream
+        .take(MAX_DOWNLOAD_SIZE as u64 + 1)
+        .read_to_end(&mut response)?;
+    ensure!(response.len() <= MAX_DOWNLOAD_SIZE, "Download from {} is too large", url);
+
+    parse_http_response(&response)
+}
+
+fn download_url(base_url: &str, path: &str) -> String {
+    format!("{}/{}", base_url.trim_end_matches('/'), path)
+}
+
+/// A set of files being downloaded on a background thread.
+///
+/// Files are fetched one at a time, so that a server isn't flooded with requests. The results
+/// are written to the `Vfs` by `poll`, on the thread that owns it.
+pub struct DownloadQueue {
+    remaining: usize,
+    results: Receiver<(String, Result<Vec<u8>, Error>)>,
+}
+
+impl DownloadQueue {
+    /// Starts downloading each of `paths` from the server's content URL `base_url`.
+    ///
+    /// Paths that aren't safe to download are skipped with a warning.
+    pub fn start(base_url: &str, paths: &[String]) -> DownloadQueue {
+        let mut paths: Vec<String> = paths
+            .iter()
+            .filter_map(|p| match sanitize_download_path(p) {
+                Ok(p) => Some(p),
+                Err(e) => {
+                    warn!("Not downloading {}: {}", p, e);
+                    None
+                }
+            })
+            .collect();
+        paths.sort();
+        paths.dedup();
+
+        let remaining = paths.len();
+        let base_url = base_url.to_owned();
+        let (sender, results) = mpsc::channel();
+        thread::spawn(move || {
+            for path in paths {
+                let url = download_url(&base_url, &path);
+                debug!("Downloading {}", url);
+                let result = http_get(&url);
+
+                // the client stopped waiting, so the rest isn't needed
+                if sender.send((path, result)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        DownloadQueue { remaining, results }
+    }
+
+    /// Writes finished downloads to the `Vfs` write directory and returns whether all of them are
+    /// done.
+    ///
+    /// A file that fails to download is only reported, so that loading can go on without it.
+    pub fn poll(&mut self, vfs: &Vfs) -> bool {
+        while self.remaining > 0 {
+            let (path, result) = match self.results.try_recv() {
+                Ok(r) => r,
+                Err(TryRecvError::Empty) => return false,
+                Err(TryRecvError::Disconnected) => {
+                    warn!("Download thread stopped with {} files left", self.remaining);
+                    self.remaining = 0;
+                    break;
+                }
+            };
+            self.remaining -= 1;
+
+            let written = result.and_then(|data| {
+                vfs.create(&path)?.write_all(&data)?;
+                Ok(())
+            });
+            match written {
+                Ok(()) => println!("Downloaded {}", path),
+                Err(e) => println!("Downloading {} failed: {}", path, e),
+            }
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_sanitize_download_path() {
+        assert_eq!(sanitize_download_path("maps/E1M1.bsp").unwrap(), "maps/E1M1.bsp");
+        assert_eq!(sanitize_download_path("MAPS/dm1.BSP").unwrap(), "MAPS/dm1.BSP");
+        assert!(sanitize_download_path("progs/player.mdl").is_ok());
+
+        assert!(sanitize_download_path("../id1/config.cfg").is_err());
+        assert!(sanitize_download_path("maps/../../autoexec.bsp").is_err());
+        assert!(sanitize_download_path("/etc/maps/passwd.bsp").is_err());
+        assert!(sanitize_download_path("maps\\..\\x.bsp").is_err());
+        assert!(sanitize_download_path("c:maps/x.bsp").is_err());
+        assert!(sanitize_download_path("maps//x.bsp").is_err());
+        assert!(sanitize_download_path("config.cfg").is_err());
+        assert!(sanitize_download_path("maps/autoexec.cfg").is_err());
+    }
+
+    #[test]
+    fn test_parse_http_url() {
+        assert_eq!(
+            parse_http_url("http://example.com:8080/quake/id1").unwrap(),
+            ("example.com".to_owned(), 8080, "/quake/id1".to_owned())
+        );
+        assert_eq!(
+            parse_http_url("http://example.com").unwrap(),
+            ("example.com".to_owned(), 80, "/".to_owned())
+        );
+        assert!(parse_http_url("https://example.com/").is_err());
+    }
+
+    #[test]
+    fn test_parse_http_response() {
+        let ok = b"HTTP/1.0 200 OK\r\nContent-Length: 4\r\n\r\nmap!extra";
+        assert_eq!(parse_http_response(ok).unwrap(), b"map!".to_vec());
+
+        let not_found = b"HTTP/1.0 404 Not Found\r\n\r\n";
+        assert!(parse_http_response(not_found).is_err());
+    }
+
+    #[test]
+    fn test_download_into_vfs() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let len = stream.read(&mut buf).unwrap();
+                assert!(len > 0);
+                request.extend_from_slice(&buf[..len]);
+            }
+            let request = String::from_utf8_lossy(&request).into_owned();
+            stream
+                .write_all(b"HTTP/1.0 200 OK\r\nContent-Length: 8\r\n\r\nBSP DATA")
+                .unwrap();
+            request
+        });
+
+        let dir = ::std::env::temp_dir().join(format!("richter-dl-test-{}", ::std::process::id()));
+        let mut vfs = Vfs::new();
+        vfs.add_directory(&dir).unwrap();
+
+        let base_url = format!("http://127.0.0.1:{}/id1/", port);
+        let paths = vec!["maps/DL.bsp".to_owned(), "../config.cfg".to_owned()];
+        let mut queue = DownloadQueue::start(&base_url, &paths);
+        while !queue.poll(&vfs) {
+            thread::sleep(StdDuration::from_millis(10));
+        }
+        assert!(server.join().unwrap().starts_with("GET /id1/maps/DL.bsp HTTP/1.0"));
+
+        // the downloaded map is now visible through the Vfs under the name it was requested by
+        let mut contents = Vec::new();
+        vfs.open("maps/DL.bsp").unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"BSP DATA".to_vec());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
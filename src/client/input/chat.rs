@@ -0,0 +1,190 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use common::console::Console;
+
+use failure::Error;
+use winit::{ElementState, Event, KeyboardInput, VirtualKeyCode as Key, WindowEvent};
+
+// the original engine's chat buffer holds 32 bytes including the terminator
+const MAX_CHAT_LENGTH: usize = 31;
+
+/// A chat message being typed, started with `messagemode` or `messagemode2`.
+#[derive(Debug, Default)]
+pub struct Chat {
+    text: String,
+    team: bool,
+    active: bool,
+}
+
+impl Chat {
+    pub fn new() -> Chat {
+        Chat::default()
+    }
+
+    /// Starts a new message, to everyone or only to the player's team.
+    pub fn start(&mut self, team: bool) {
+        self.text.clear();
+        self.team = team;
+        self.active = true;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn is_team(&self) -> bool {
+        self.team
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Types a character into the message. Backspace deletes the last character and other
+    /// control characters are ignored.
+    pub fn send_char(&mut self, c: char) {
+        match c {
+            '\x08' => {
+                self.text.pop();
+            }
+
+            // quotes would end the argument to `say` early
+            '"' => (),
+
+            c if c.is_control() => (),
+
+            c => {
+                if self.text.chars().count() < MAX_CHAT_LENGTH {
+                    self.text.push(c);
+                }
+            }
+        }
+    }
+
+    /// Ends the message and returns the console command that sends it, if it isn't empty.
+    pub fn submit(&mut self) -> Option<String> {
+        self.active = false;
+        if self.text.is_empty() {
+            return None;
+        }
+
+        let cmd = if self.team { "say_team" } else { "say" };
+        Some(format!("{} \"{}\"\n", cmd, self.text))
+    }
+
+    /// Ends the message without sending it.
+    pub fn cancel(&mut self) {
+        self.active = false;
+        self.text.clear();
+    }
+}
+
+/// Routes typing to the chat message instead of the player's binds.
+///
+/// Only Enter (send) and Escape (cancel) act as keys; everything else is text.
+pub struct ChatInput {
+    chat: Rc<RefCell<Chat>>,
+    console: Rc<RefCell<Console>>,
+}
+
+impl ChatInput {
+    pub fn new(chat: Rc<RefCell<Chat>>, console: Rc<RefCell<Console>>) -> ChatInput {
+        ChatInput { chat, console }
+    }
+
+    pub fn handle_event(&self, event: Event) -> Result<(), Error> {
+        match event {
+            Event::WindowEvent { event, .. } => match event {
+                WindowEvent::ReceivedCharacter(c) => self.chat.borrow_mut().send_char(c),
+
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            virtual_keycode: Some(key),
+                            state: ElementState::Pressed,
+                            ..
+                        },
+                    ..
+                } => match key {
+                    Key::Return => {
+                        if let Some(cmd) = self.chat.borrow_mut().submit() {
+                            self.console.borrow().stuff_text(cmd);
+                        }
+                    }
+                    Key::Escape => self.chat.borrow_mut().cancel(),
+                    _ => (),
+                },
+
+                _ => (),
+            },
+
+            _ => (),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn type_str(chat: &mut Chat, s: &str) {
+        for c in s.chars() {
+            chat.send_char(c);
+        }
+    }
+
+    #[test]
+    fn test_chat_submit() {
+        let mut chat = Chat::new();
+        chat.start(false);
+        type_str(&mut chat, "gg\"  wp\x08\x08no");
+        assert_eq!(chat.text(), "gg  no");
+        assert_eq!(chat.submit(), Some(String::from("say \"gg  no\"\n")));
+        assert!(!chat.is_active());
+
+        chat.start(true);
+        type_str(&mut chat, "rl\r");
+        assert_eq!(chat.submit(), Some(String::from("say_team \"rl\"\n")));
+    }
+
+    #[test]
+    fn test_chat_cancel_and_empty() {
+        let mut chat = Chat::new();
+        chat.start(false);
+        type_str(&mut chat, "oops");
+        chat.cancel();
+        assert!(!chat.is_active());
+        assert_eq!(chat.text(), "");
+
+        chat.start(false);
+        assert_eq!(chat.submit(), None);
+    }
+
+    #[test]
+    fn test_chat_max_length() {
+        let mut chat = Chat::new();
+        chat.start(false);
+        type_str(&mut chat, &"a".repeat(40));
+        assert_eq!(chat.text().len(), MAX_CHAT_LENGTH);
+    }
+}
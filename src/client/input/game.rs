@@ -16,7 +16,8 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 use std::cell::{Cell, RefCell};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
 use std::rc::Rc;
 use std::str::FromStr;
 use std::string::ToString;
@@ -32,10 +33,15 @@ use winit::{
     VirtualKeyCode as Key, WindowEvent,
 };
 
-const ACTION_COUNT: usize = 19;
+const ACTION_COUNT: usize = 20;
 
-static INPUT_NAMES: [&'static str; 79] = [
+/// The most frames of mouse movement `m_smooth` can average over.
+pub const MAX_MOUSE_SMOOTH_FRAMES: usize = 8;
+
+static INPUT_NAMES: [&'static str; 102] = [
+    "'",
     ",",
+    "-",
     ".",
     "/",
     "0",
@@ -48,11 +54,13 @@ static INPUT_NAMES: [&'static str; 79] = [
     "7",
     "8",
     "9",
+    "=",
     "A",
     "ALT",
     "B",
     "BACKSPACE",
     "C",
+    "CAPSLOCK",
     "CTRL",
     "D",
     "DEL",
@@ -81,6 +89,23 @@ static INPUT_NAMES: [&'static str; 79] = [
     "INS",
     "J",
     "K",
+    "KP_5",
+    "KP_DEL",
+    "KP_DOWNARROW",
+    "KP_END",
+    "KP_ENTER",
+    "KP_HOME",
+    "KP_INS",
+    "KP_LEFTARROW",
+    "KP_MINUS",
+    "KP_NUMLOCK",
+    "KP_PGDN",
+    "KP_PGUP",
+    "KP_PLUS",
+    "KP_RIGHTARROW",
+    "KP_SLASH",
+    "KP_STAR",
+    "KP_UPARROW",
     "L",
     "LEFTARROW",
     "M",
@@ -92,12 +117,14 @@ static INPUT_NAMES: [&'static str; 79] = [
     "N",
     "O",
     "P",
+    "PAUSE",
     "PGDN",
     "PGUP",
     "Q",
     "R",
     "RIGHTARROW",
     "S",
+    "SCROLLLOCK",
     "SEMICOLON",
     "SHIFT",
     "SPACE",
@@ -116,8 +143,10 @@ static INPUT_NAMES: [&'static str; 79] = [
     "`",
 ];
 
-static INPUT_VALUES: [BindInput; 79] = [
+static INPUT_VALUES: [BindInput; 102] = [
+    BindInput::Key(Key::Apostrophe),
     BindInput::Key(Key::Comma),
+    BindInput::Key(Key::Minus),
     BindInput::Key(Key::Period),
     BindInput::Key(Key::Slash),
     BindInput::Key(Key::Key0),
@@ -130,11 +159,13 @@ static INPUT_VALUES: [BindInput; 79] = [
     BindInput::Key(Key::Key7),
     BindInput::Key(Key::Key8),
     BindInput::Key(Key::Key9),
+    BindInput::Key(Key::Equals),
     BindInput::Key(Key::A),
     BindInput::Key(Key::LAlt),
     BindInput::Key(Key::B),
     BindInput::Key(Key::Back),
     BindInput::Key(Key::C),
+    BindInput::Key(Key::Capital),
     BindInput::Key(Key::LControl),
     BindInput::Key(Key::D),
     BindInput::Key(Key::Delete),
@@ -163,6 +194,23 @@ static INPUT_VALUES: [BindInput; 79] = [
     BindInput::Key(Key::Insert),
     BindInput::Key(Key::J),
     BindInput::Key(Key::K),
+    BindInput::Key(Key::Numpad5),
+    BindInput::Key(Key::Decimal),
+    BindInput::Key(Key::Numpad2),
+    BindInput::Key(Key::Numpad1),
+    BindInput::Key(Key::NumpadEnter),
+    BindInput::Key(Key::Numpad7),
+    BindInput::Key(Key::Numpad0),
+    BindInput::Key(Key::Numpad4),
+    BindInput::Key(Key::Subtract),
+    BindInput::Key(Key::Numlock),
+    BindInput::Key(Key::Numpad3),
+    BindInput::Key(Key::Numpad9),
+    BindInput::Key(Key::Add),
+    BindInput::Key(Key::Numpad6),
+    BindInput::Key(Key::Divide),
+    BindInput::Key(Key::Multiply),
+    BindInput::Key(Key::Numpad8),
     BindInput::Key(Key::L),
     BindInput::Key(Key::Left),
     BindInput::Key(Key::M),
@@ -174,12 +222,14 @@ static INPUT_VALUES: [BindInput; 79] = [
     BindInput::Key(Key::N),
     BindInput::Key(Key::O),
     BindInput::Key(Key::P),
+    BindInput::Key(Key::Pause),
     BindInput::Key(Key::PageDown),
     BindInput::Key(Key::PageUp),
     BindInput::Key(Key::Q),
     BindInput::Key(Key::R),
     BindInput::Key(Key::Right),
     BindInput::Key(Key::S),
+    BindInput::Key(Key::Scroll),
     BindInput::Key(Key::Semicolon),
     BindInput::Key(Key::LShift),
     BindInput::Key(Key::Space),
@@ -198,6 +248,34 @@ static INPUT_VALUES: [BindInput; 79] = [
     BindInput::Key(Key::Grave),
 ];
 
+// alternative key names used by other Quake engines, mapped to the names above
+static INPUT_ALIASES: [(&'static str, &'static str); 24] = [
+    ("BACKQUOTE", "`"),
+    ("CONTROL", "CTRL"),
+    ("DELETE", "DEL"),
+    ("DOWN", "DOWNARROW"),
+    ("ESC", "ESCAPE"),
+    ("INSERT", "INS"),
+    ("KP_0", "KP_INS"),
+    ("KP_1", "KP_END"),
+    ("KP_2", "KP_DOWNARROW"),
+    ("KP_3", "KP_PGDN"),
+    ("KP_4", "KP_LEFTARROW"),
+    ("KP_6", "KP_RIGHTARROW"),
+    ("KP_7", "KP_HOME"),
+    ("KP_8", "KP_UPARROW"),
+    ("KP_9", "KP_PGUP"),
+    ("KP_DIVIDE", "KP_SLASH"),
+    ("KP_MULTIPLY", "KP_STAR"),
+    ("KP_SUBTRACT", "KP_MINUS"),
+    ("LEFT", "LEFTARROW"),
+    ("PAGEDOWN", "PGDN"),
+    ("PAGEUP", "PGUP"),
+    ("RETURN", "ENTER"),
+    ("RIGHT", "RIGHTARROW"),
+    ("UP", "UPARROW"),
+];
+
 /// A unique identifier for an in-game action.
 #[derive(Clone, Copy, Debug, Eq, FromPrimitive, PartialEq)]
 pub enum Action {
@@ -257,6 +335,9 @@ pub enum Action {
 
     /// Show the team scoreboard.
     ShowTeamScores = 18,
+
+    /// Narrow the field of view to `zoom_fov`.
+    Zoom = 19,
 }
 
 impl FromStr for Action {
@@ -283,6 +364,7 @@ impl FromStr for Action {
             "mlook" => Action::MLook,
             "showscores" => Action::ShowScores,
             "showteamscores" => Action::ShowTeamScores,
+            "zoom" => Action::Zoom,
             _ => bail!("Invalid action name: {}", s),
         };
 
@@ -312,6 +394,7 @@ impl ToString for Action {
             Action::MLook => "mlook",
             Action::ShowScores => "showscores",
             Action::ShowTeamScores => "showteamscores",
+            Action::Zoom => "zoom",
         })
     }
 }
@@ -389,7 +472,10 @@ impl FromStr for BindInput {
     type Err = Error;
 
     fn from_str(src: &str) -> Result<BindInput, Error> {
-        let upper = src.to_uppercase();
+        let mut upper = src.to_uppercase();
+        if let Some(&(_, name)) = INPUT_ALIASES.iter().find(|&&(alias, _)| alias == upper) {
+            upper = name.to_owned();
+        }
 
         for (i, name) in INPUT_NAMES.iter().enumerate() {
             if upper == *name {
@@ -467,12 +553,50 @@ impl ToString for BindTarget {
     }
 }
 
+// mouse movement from previous frames, most recent first
+#[derive(Clone, Debug, Default)]
+struct MouseHistory {
+    deltas: VecDeque<(f64, f64)>,
+}
+
+impl MouseHistory {
+    fn push(&mut self, delta: (f64, f64)) {
+        self.deltas.push_front(delta);
+        self.deltas.truncate(MAX_MOUSE_SMOOTH_FRAMES - 1);
+    }
+
+    // average `current` with the movement of up to `frames - 1` previous frames
+    fn smooth(&self, current: (f64, f64), frames: usize) -> (f64, f64) {
+        let frames = frames.max(1).min(MAX_MOUSE_SMOOTH_FRAMES);
+        let (mut x, mut y) = current;
+        let mut count = 1;
+        for &(dx, dy) in self.deltas.iter().take(frames - 1) {
+            x += dx;
+            y += dy;
+            count += 1;
+        }
+
+        (x / count as f64, y / count as f64)
+    }
+}
+
 #[derive(Clone)]
 pub struct GameInput {
     console: Rc<RefCell<Console>>,
     bindings: Rc<RefCell<HashMap<BindInput, BindTarget>>>,
     action_states: Rc<RefCell<[bool; ACTION_COUNT]>>,
     mouse_delta: (f64, f64),
+    mouse_history: MouseHistory,
+
+    // if false, mouse movement is taken from the (OS-accelerated) cursor position instead of the
+    // raw device motion. the cursor is warped back to the window center every frame, so movement
+    // is measured from there.
+    raw_mouse: bool,
+    last_cursor_pos: Option<LogicalPosition>,
+
+    // keys currently held down, used to recognize OS key repeats
+    held_keys: HashSet<Key>,
+
     impulse: Rc<Cell<u8>>,
 }
 
@@ -483,6 +607,10 @@ impl GameInput {
             bindings: Rc::new(RefCell::new(HashMap::new())),
             action_states: Rc::new(RefCell::new([false; ACTION_COUNT])),
             mouse_delta: (0.0, 0.0),
+            mouse_history: MouseHistory::default(),
+            raw_mouse: true,
+            last_cursor_pos: None,
+            held_keys: HashSet::new(),
             impulse: Rc::new(Cell::new(0)),
         }
     }
@@ -491,6 +619,37 @@ impl GameInput {
         self.mouse_delta
     }
 
+    /// Returns this frame's mouse movement averaged with that of up to `frames - 1` previous
+    /// frames.
+    ///
+    /// `frames` is clamped to `[1, MAX_MOUSE_SMOOTH_FRAMES]`; 1 disables smoothing.
+    pub fn smoothed_mouse_delta(&self, frames: usize) -> (f64, f64) {
+        self.mouse_history.smooth(self.mouse_delta, frames)
+    }
+
+    /// Selects whether mouse movement is read from raw device motion or from the cursor position.
+    ///
+    /// Raw motion bypasses the operating system's pointer acceleration.
+    pub fn set_raw_mouse(&mut self, raw: bool) {
+        if raw != self.raw_mouse {
+            self.raw_mouse = raw;
+            self.last_cursor_pos = None;
+        }
+    }
+
+    /// Returns whether mouse movement is read from raw device motion.
+    ///
+    /// Otherwise, the cursor must be warped back into the window each frame with
+    /// `recenter_cursor` so that it never stops at the window edge.
+    pub fn raw_mouse(&self) -> bool {
+        self.raw_mouse
+    }
+
+    /// Records that the cursor was warped to `center`, so the warp isn't read as movement.
+    pub fn recenter_cursor(&mut self, center: LogicalPosition) {
+        self.last_cursor_pos = Some(center);
+    }
+
     pub fn impulse(&self) -> u8 {
         self.impulse.get()
     }
@@ -509,6 +668,7 @@ impl GameInput {
         self.bind(Key::LControl, BindTarget::from_str("+attack").unwrap());
         self.bind(Key::E, BindTarget::from_str("+use").unwrap());
         self.bind(Key::Grave, BindTarget::from_str("toggleconsole").unwrap());
+        self.bind(Key::T, BindTarget::from_str("messagemode").unwrap());
         self.bind(Key::Key1, BindTarget::from_str("impulse 1").unwrap());
         self.bind(Key::Key2, BindTarget::from_str("impulse 2").unwrap());
         self.bind(Key::Key3, BindTarget::from_str("impulse 3").unwrap());
@@ -539,6 +699,18 @@ impl GameInput {
         self.bindings.borrow().get(&input.into()).map(|t| t.clone())
     }
 
+    /// Writes a `bind` line for each binding, sorted by input name.
+    pub fn write_bindings<W>(&self, writer: &mut W) -> Result<(), Error>
+    where
+        W: Write,
+    {
+        for line in bind_lines(&self.bindings.borrow()) {
+            writeln!(writer, "{}", line)?;
+        }
+
+        Ok(())
+    }
+
     pub fn handle_event(&mut self, outer_event: Event) -> Result<(), Error> {
         let (input, state): (BindInput, _) = match outer_event {
             Event::WindowEvent { event, .. } => match event {
@@ -555,11 +727,16 @@ impl GameInput {
                 WindowEvent::MouseInput { state, button, .. } => (button.into(), state),
                 WindowEvent::MouseWheel { delta, .. } => (delta.into(), ElementState::Pressed),
 
+                WindowEvent::CursorMoved { position, .. } => {
+                    self.cursor_moved(position);
+                    return Ok(());
+                }
+
                 _ => return Ok(()),
             },
 
             Event::DeviceEvent { event, .. } => match event {
-                DeviceEvent::MouseMotion { delta } => {
+                DeviceEvent::MouseMotion { delta } if self.raw_mouse => {
                     self.mouse_delta.0 += delta.0;
                     self.mouse_delta.1 += delta.1;
                     return Ok(());
@@ -578,15 +755,30 @@ impl GameInput {
         Ok(())
     }
 
+    // accumulates cursor movement when raw input is off
+    fn cursor_moved(&mut self, position: LogicalPosition) {
+        if !self.raw_mouse {
+            if let Some(last) = self.last_cursor_pos {
+                self.mouse_delta.0 += position.x - last.x;
+                self.mouse_delta.1 += position.y - last.y;
+            }
+            self.last_cursor_pos = Some(position);
+        }
+    }
+
     pub fn handle_input<I>(&mut self, input: I, state: ElementState) -> Result<(), Error>
     where
         I: Into<BindInput>,
     {
         let bind_input = input.into();
+        let repeat = self.track_repeat(bind_input, state);
 
         // debug!("handle input {:?}: {:?}", &bind_input, state);
         if let Some(target) = self.bindings.borrow().get(&bind_input) {
             match *target {
+                // only the initial press and the release change an action's state
+                BindTarget::Action { .. } if repeat => (),
+
                 BindTarget::Action { trigger, action } => {
                     self.action_states.borrow_mut()[action as usize] = state == trigger;
                     debug!(
@@ -607,6 +799,29 @@ impl GameInput {
         Ok(())
     }
 
+    /// Records the state of a key and returns whether a press is an OS key repeat, i.e. the key
+    /// was already held down.
+    fn track_repeat(&mut self, input: BindInput, state: ElementState) -> bool {
+        let key = match input {
+            BindInput::Key(k) => k,
+            _ => return false,
+        };
+
+        match state {
+            ElementState::Pressed => !self.held_keys.insert(key),
+            ElementState::Released => {
+                self.held_keys.remove(&key);
+                false
+            }
+        }
+    }
+
+    /// Forgets which keys are held down, so that the next press of each key isn't treated as a
+    /// repeat.
+    pub fn clear_held_keys(&mut self) {
+        self.held_keys.clear();
+    }
+
     pub fn action_state(&self, action: Action) -> bool {
         self.action_states.borrow()[action as usize]
     }
@@ -885,6 +1100,22 @@ impl GameInput {
             }),
         )
         .unwrap();
+        let states = self.action_states.clone();
+        cmds.insert_or_replace(
+            "+zoom",
+            Box::new(move |_| {
+                states.borrow_mut()[Action::Zoom as usize] = true;
+            }),
+        )
+        .unwrap();
+        let states = self.action_states.clone();
+        cmds.insert_or_replace(
+            "-zoom",
+            Box::new(move |_| {
+                states.borrow_mut()[Action::Zoom as usize] = false;
+            }),
+        )
+        .unwrap();
 
         // "bind"
         let bindings = self.bindings.clone();
@@ -923,6 +1154,20 @@ impl GameInput {
         )
         .unwrap();
 
+        // "bindlist"
+        let bindings = self.bindings.clone();
+        cmds.insert_or_replace(
+            "bindlist",
+            Box::new(move |_| {
+                let lines = bind_list(&bindings.borrow());
+                for line in lines.iter() {
+                    println!("{}", line);
+                }
+                println!("{} binding(s)", lines.len());
+            }),
+        )
+        .unwrap();
+
         // "unbindall"
         let bindings = self.bindings.clone();
         cmds.insert_or_replace(
@@ -966,6 +1211,7 @@ impl GameInput {
     fn clear_mouse(&mut self) -> Result<(), Error> {
         self.handle_input(MouseWheel::Up, ElementState::Released)?;
         self.handle_input(MouseWheel::Down, ElementState::Released)?;
+        self.mouse_history.push(self.mouse_delta);
         self.mouse_delta = (0.0, 0.0);
 
         Ok(())
@@ -978,9 +1224,134 @@ impl GameInput {
     }
 }
 
+/// Returns a description of each binding in `bindings`, sorted by input name.
+fn bind_list(bindings: &HashMap<BindInput, BindTarget>) -> Vec<String> {
+    let mut binds: Vec<(String, String)> = bindings
+        .iter()
+        .map(|(input, target)| (input.to_string(), target.to_string()))
+        .collect();
+    binds.sort();
+
+    binds
+        .into_iter()
+        .map(|(input, target)| format!("\"{}\" = {}", input, target))
+        .collect()
+}
+
+/// Returns the `bind` commands that recreate `bindings`, sorted by input name.
+fn bind_lines(bindings: &HashMap<BindInput, BindTarget>) -> Vec<String> {
+    let mut binds: Vec<(String, String)> = bindings
+        .iter()
+        .map(|(input, target)| {
+            let target = match *target {
+                BindTarget::Action { .. } => target.to_string(),
+                BindTarget::ConsoleInput { ref text } => text.replace('"', ""),
+            };
+            (input.to_string(), target)
+        })
+        .collect();
+    binds.sort();
+
+    binds
+        .into_iter()
+        .map(|(input, target)| format!("bind \"{}\" \"{}\"", input, target))
+        .collect()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use common::console::CvarRegistry;
+
+    #[test]
+    fn test_input_names_sorted() {
+        for pair in INPUT_NAMES.windows(2) {
+            assert!(pair[0] < pair[1], "{} should come after {}", pair[0], pair[1]);
+        }
+
+        for &(alias, name) in INPUT_ALIASES.iter() {
+            assert!(INPUT_NAMES.contains(&name), "alias {} names unknown input {}", alias, name);
+        }
+    }
+
+    #[test]
+    fn test_bind_input_from_str() {
+        assert_eq!(BindInput::from_str("mwheelup").unwrap(), MouseWheel::Up.into());
+        assert_eq!(BindInput::from_str("semicolon").unwrap(), Key::Semicolon.into());
+        assert_eq!(BindInput::from_str("kp_enter").unwrap(), Key::NumpadEnter.into());
+
+        // aliases resolve to the same input and print by their canonical name
+        let kp_home = BindInput::from_str("KP_7").unwrap();
+        assert_eq!(kp_home, Key::Numpad7.into());
+        assert_eq!(kp_home.to_string(), "KP_HOME");
+        assert_eq!(BindInput::from_str("return").unwrap().to_string(), "ENTER");
+
+        assert!(BindInput::from_str("notakey").is_err());
+    }
+
+    #[test]
+    fn test_key_repeat_ignored_for_actions() {
+        let cmds = Rc::new(RefCell::new(CmdRegistry::new()));
+        let cvars = Rc::new(RefCell::new(CvarRegistry::new()));
+        let mut input = GameInput::new(Rc::new(RefCell::new(Console::new(cmds, cvars))));
+
+        // a held key sends one press, then repeats, then one release
+        let events = [
+            ElementState::Pressed,
+            ElementState::Pressed,
+            ElementState::Pressed,
+            ElementState::Released,
+        ];
+        let repeats: Vec<bool> = events
+            .iter()
+            .map(|state| input.track_repeat(Key::W.into(), *state))
+            .collect();
+        assert_eq!(repeats, vec![false, true, true, false]);
+
+        // mouse wheel "presses" are never repeats
+        assert!(!input.track_repeat(MouseWheel::Up.into(), ElementState::Pressed));
+        assert!(!input.track_repeat(MouseWheel::Up.into(), ElementState::Pressed));
+
+        // a repeat of the +forward key while the action was cleared by a command elsewhere
+        // doesn't set it again
+        input.bind(Key::W, BindTarget::from_str("+forward").unwrap());
+        input.handle_input(Key::W, ElementState::Pressed).unwrap();
+        assert!(input.action_state(Action::Forward));
+        input.action_states.borrow_mut()[Action::Forward as usize] = false;
+        input.handle_input(Key::W, ElementState::Pressed).unwrap();
+        assert!(!input.action_state(Action::Forward));
+        input.handle_input(Key::W, ElementState::Released).unwrap();
+        assert!(!input.action_state(Action::Forward));
+    }
+
+    #[test]
+    fn test_bind_list() {
+        let mut bindings = HashMap::new();
+        bindings.insert(BindInput::from(Key::W), BindTarget::from_str("+forward").unwrap());
+        bindings.insert(BindInput::from(Key::F1), BindTarget::from_str("help").unwrap());
+
+        assert_eq!(
+            bind_list(&bindings),
+            vec!["\"F1\" = \"help\"".to_owned(), "\"W\" = +forward".to_owned()]
+        );
+    }
+
+    #[test]
+    fn test_bind_lines() {
+        let mut bindings = HashMap::new();
+        bindings.insert(BindInput::from(Key::W), BindTarget::from_str("+forward").unwrap());
+        bindings.insert(BindInput::from(Key::F1), BindTarget::from_str("help").unwrap());
+        bindings.insert(BindInput::from(Key::T), BindTarget::from_str("say hi; say bye").unwrap());
+
+        assert_eq!(
+            bind_lines(&bindings),
+            vec![
+                "bind \"F1\" \"help\"".to_owned(),
+                "bind \"T\" \"say hi; say bye\"".to_owned(),
+                "bind \"W\" \"+forward\"".to_owned(),
+            ]
+        );
+    }
 
     #[test]
     fn test_action_to_string() {
@@ -997,4 +1368,44 @@ mod test {
 
         assert_eq!(target.to_string(), "+forward");
     }
+
+    #[test]
+    fn test_cursor_recentered() {
+        let cmds = Rc::new(RefCell::new(CmdRegistry::new()));
+        let cvars = Rc::new(RefCell::new(CvarRegistry::new()));
+        let mut input = GameInput::new(Rc::new(RefCell::new(Console::new(cmds, cvars))));
+        input.set_raw_mouse(false);
+
+        // movement is measured from the center, so warping back there isn't movement
+        input.recenter_cursor(LogicalPosition::new(320.0, 240.0));
+        input.cursor_moved(LogicalPosition::new(330.0, 235.0));
+        input.recenter_cursor(LogicalPosition::new(320.0, 240.0));
+        input.cursor_moved(LogicalPosition::new(320.0, 240.0));
+        input.cursor_moved(LogicalPosition::new(325.0, 240.0));
+        assert_eq!(input.mouse_delta(), (15.0, -5.0));
+    }
+
+    #[test]
+    fn test_mouse_history_smooth() {
+        let mut history = MouseHistory::default();
+
+        // with no history, the current movement is used as-is
+        assert_eq!(history.smooth((4.0, -2.0), 4), (4.0, -2.0));
+
+        history.push((2.0, 0.0));
+        history.push((6.0, 6.0));
+        assert_eq!(history.smooth((4.0, 0.0), 1), (4.0, 0.0));
+        assert_eq!(history.smooth((4.0, 0.0), 0), (4.0, 0.0));
+        assert_eq!(history.smooth((4.0, 0.0), 2), (5.0, 3.0));
+        assert_eq!(history.smooth((4.0, 0.0), 3), (4.0, 2.0));
+
+        // only the history that exists is averaged
+        assert_eq!(history.smooth((4.0, 0.0), 8), (4.0, 2.0));
+
+        // old movement is forgotten
+        for _ in 0..MAX_MOUSE_SMOOTH_FRAMES {
+            history.push((0.0, 0.0));
+        }
+        assert_eq!(history.smooth((8.0, 8.0), 100), (1.0, 1.0));
+    }
 }
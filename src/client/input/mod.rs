@@ -15,11 +15,13 @@
 // DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+pub mod chat;
 pub mod console;
 pub mod game;
 pub mod menu;
 
 use std::cell::RefCell;
+use std::io::Write;
 use std::rc::Rc;
 
 use client::menu::Menu;
@@ -28,15 +30,19 @@ use common::console::{CmdRegistry, Console};
 use failure::Error;
 use winit::{Event, WindowEvent};
 
+use self::chat::{Chat, ChatInput};
 use self::console::ConsoleInput;
 use self::game::{BindInput, BindTarget, GameInput};
 use self::menu::MenuInput;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum InputFocus {
     Game,
     Console,
     Menu,
+
+    /// Typing a chat message. Keys go to the message instead of triggering binds.
+    Chat,
 }
 
 pub struct Input {
@@ -46,6 +52,9 @@ pub struct Input {
     game_input: GameInput,
     console_input: ConsoleInput,
     menu_input: MenuInput,
+
+    chat: Rc<RefCell<Chat>>,
+    chat_input: ChatInput,
 }
 
 impl Input {
@@ -54,6 +63,8 @@ impl Input {
         console: Rc<RefCell<Console>>,
         menu: Rc<RefCell<Menu>>,
     ) -> Input {
+        let chat = Rc::new(RefCell::new(Chat::new()));
+
         Input {
             window_focused: true,
             current_focus: init_focus,
@@ -61,6 +72,9 @@ impl Input {
             game_input: GameInput::new(console.clone()),
             console_input: ConsoleInput::new(console.clone()),
             menu_input: MenuInput::new(menu.clone(), console.clone()),
+
+            chat: chat.clone(),
+            chat_input: ChatInput::new(chat, console.clone()),
         }
     }
 
@@ -70,7 +84,12 @@ impl Input {
             Event::WindowEvent {
                 event: WindowEvent::Focused(focused),
                 ..
-            } => self.window_focused = focused,
+            } => {
+                self.window_focused = focused;
+                if !focused {
+                    self.game_input.clear_held_keys();
+                }
+            }
 
             _ => {
                 if self.window_focused {
@@ -78,6 +97,7 @@ impl Input {
                         InputFocus::Game => self.game_input.handle_event(event)?,
                         InputFocus::Console => self.console_input.handle_event(event)?,
                         InputFocus::Menu => self.menu_input.handle_event(event)?,
+                        InputFocus::Chat => self.chat_input.handle_event(event)?,
                     }
                 }
             }
@@ -86,11 +106,25 @@ impl Input {
         Ok(())
     }
 
+    /// Returns whether a chat message is being typed.
+    pub fn chatting(&self) -> bool {
+        self.chat.borrow().is_active()
+    }
+
+    pub fn chat(&self) -> Rc<RefCell<Chat>> {
+        self.chat.clone()
+    }
+
     pub fn current_focus(&self) -> InputFocus {
         self.current_focus
     }
 
     pub fn set_focus(&mut self, new_focus: InputFocus) -> Result<(), Error> {
+        // releases sent to another focus never reach the game input
+        if new_focus != self.current_focus {
+            self.game_input.clear_held_keys();
+        }
+
         self.current_focus = new_focus;
 
         Ok(())
@@ -109,6 +143,14 @@ impl Input {
         self.game_input.bind_defaults();
     }
 
+    /// Writes a `bind` line for each binding, so they can be restored by running the output.
+    pub fn write_bindings<W>(&self, writer: &mut W) -> Result<(), Error>
+    where
+        W: Write,
+    {
+        self.game_input.write_bindings(writer)
+    }
+
     pub fn game_input(&self) -> Option<&GameInput> {
         if let InputFocus::Game = self.current_focus {
             Some(&self.game_input)
@@ -127,5 +169,19 @@ impl Input {
 
     pub fn register_cmds(&self, cmds: &mut CmdRegistry) {
         self.game_input.register_cmds(cmds);
+
+        let chat = self.chat.clone();
+        cmds.insert_or_replace(
+            "messagemode",
+            Box::new(move |_| chat.borrow_mut().start(false)),
+        )
+        .unwrap();
+
+        let chat = self.chat.clone();
+        cmds.insert_or_replace(
+            "messagemode2",
+            Box::new(move |_| chat.borrow_mut().start(true)),
+        )
+        .unwrap();
     }
 }
@@ -0,0 +1,187 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Dynamic lights from muzzle flashes, glowing entities and explosions.
+//!
+//! Each light only lives for a moment and may shrink while it does. The renderer adds the lights
+//! alive in a frame to the lightmaps of the surfaces they reach.
+
+use cgmath::Vector3;
+use chrono::Duration;
+
+use common::engine;
+
+/// The maximum number of dynamic lights alive at once.
+pub const MAX_DYNAMIC_LIGHTS: usize = 32;
+
+/// Identifies a light owned by an entity, so that respawning it each frame replaces it.
+///
+/// An entity can own one light of each kind at a time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LightKey {
+    /// The flash of a weapon being fired.
+    MuzzleFlash(usize),
+
+    /// The glow of an entity with the bright or dim light effect.
+    Glow(usize),
+
+    /// The light carried by a rocket.
+    Rocket(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DynamicLight {
+    /// The position of the light in world space.
+    pub origin: Vector3<f32>,
+
+    /// The distance the light reaches, in world units.
+    pub radius: f32,
+
+    /// The time at which the light disappears.
+    pub die: Duration,
+
+    /// How quickly the radius shrinks, in world units per second.
+    pub decay: f32,
+
+    /// The entity light this is, if any.
+    pub key: Option<LightKey>,
+}
+
+impl DynamicLight {
+    /// Returns whether the light still lights anything at time `time`.
+    pub fn is_alive(&self, time: Duration) -> bool {
+        self.die >= time && self.radius > 0.0
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DynamicLights {
+    lights: Vec<DynamicLight>,
+}
+
+impl DynamicLights {
+    pub fn new() -> DynamicLights {
+        DynamicLights::default()
+    }
+
+    /// Adds a light, replacing any light with the same key.
+    ///
+    /// If all `MAX_DYNAMIC_LIGHTS` slots are taken, the light closest to dying is replaced.
+    pub fn spawn(&mut self, light: DynamicLight) {
+        if let Some(key) = light.key {
+            if let Some(l) = self.lights.iter_mut().find(|l| l.key == Some(key)) {
+                *l = light;
+                return;
+            }
+        }
+
+        if self.lights.len() < MAX_DYNAMIC_LIGHTS {
+            self.lights.push(light);
+            return;
+        }
+
+        if let Some(l) = self.lights.iter_mut().min_by_key(|l| l.die) {
+            *l = light;
+        }
+    }
+
+    /// Shrinks each light by its decay over `frame_time` and removes dead lights.
+    pub fn update(&mut self, time: Duration, frame_time: Duration) {
+        let frame_time = engine::duration_to_f32(frame_time);
+        for light in self.lights.iter_mut() {
+            light.radius = (light.radius - light.decay * frame_time).max(0.0);
+        }
+
+        self.lights.retain(|l| l.is_alive(time));
+    }
+
+    /// Removes all lights.
+    pub fn clear(&mut self) {
+        self.lights.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &DynamicLight> {
+        self.lights.iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn light(radius: f32, die_ms: i64, decay: f32, key: Option<LightKey>) -> DynamicLight {
+        DynamicLight {
+            origin: Vector3::new(0.0, 0.0, 0.0),
+            radius,
+            die: Duration::milliseconds(die_ms),
+            decay,
+            key,
+        }
+    }
+
+    #[test]
+    fn test_spawn_replaces_same_key() {
+        let mut lights = DynamicLights::new();
+        lights.spawn(light(200.0, 100, 0.0, Some(LightKey::Glow(3))));
+        lights.spawn(light(350.0, 500, 300.0, None));
+        lights.spawn(light(400.0, 100, 0.0, Some(LightKey::Glow(3))));
+
+        let radii: Vec<f32> = lights.iter().map(|l| l.radius).collect();
+        assert_eq!(radii, vec![400.0, 350.0]);
+    }
+
+    #[test]
+    fn test_spawn_keeps_other_kinds() {
+        // a glowing entity firing its weapon keeps both lights
+        let mut lights = DynamicLights::new();
+        lights.spawn(light(200.0, 100, 0.0, Some(LightKey::MuzzleFlash(3))));
+        lights.spawn(light(400.0, 1, 0.0, Some(LightKey::Glow(3))));
+
+        let radii: Vec<f32> = lights.iter().map(|l| l.radius).collect();
+        assert_eq!(radii, vec![200.0, 400.0]);
+    }
+
+    #[test]
+    fn test_spawn_full_replaces_soonest_to_die() {
+        let mut lights = DynamicLights::new();
+        for i in 0..MAX_DYNAMIC_LIGHTS {
+            lights.spawn(light(100.0, 1000 + i as i64, 0.0, None));
+        }
+        lights.spawn(light(200.0, 5000, 0.0, None));
+
+        assert_eq!(lights.iter().count(), MAX_DYNAMIC_LIGHTS);
+        assert!(lights.iter().all(|l| l.die != Duration::milliseconds(1000)));
+    }
+
+    #[test]
+    fn test_update_decays_and_expires() {
+        let mut lights = DynamicLights::new();
+        lights.spawn(light(350.0, 500, 300.0, None));
+        lights.spawn(light(200.0, 100, 0.0, None));
+
+        lights.update(Duration::milliseconds(250), Duration::milliseconds(250));
+        let radii: Vec<f32> = lights.iter().map(|l| l.radius).collect();
+        assert_eq!(radii, vec![275.0]);
+
+        // shrinks to nothing before it dies
+        lights.update(Duration::milliseconds(400), Duration::milliseconds(1000));
+        assert_eq!(lights.iter().count(), 0);
+    }
+}
@@ -24,7 +24,9 @@ use std::cell::RefCell;
 
 use failure::Error;
 
-use self::item::{Enum, EnumItem, Item, Slider, TextField, Toggle};
+pub use self::item::EnumItem;
+
+use self::item::{Enum, Item, Slider, TextField, Toggle};
 
 #[derive(Clone)]
 pub enum MenuState {
@@ -18,24 +18,40 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+pub mod demo;
+pub mod download;
 pub mod input;
+pub mod light;
 pub mod menu;
+pub mod particle;
+pub mod predict;
 pub mod render;
 pub mod sound;
+pub mod weapon;
 
 mod cvars;
 pub use self::cvars::register_cvars;
 
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::io::{BufReader, Read};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::Cursor;
 use std::net::ToSocketAddrs;
 use std::rc::Rc;
 
+use client::demo::{DemoMessage, DemoRecorder, DemoServer};
+use client::download::DownloadQueue;
 use client::input::game::{Action, GameInput};
-use client::sound::{AudioSource, Channel, StaticSound};
-use common::bsp;
-use common::console::{CmdRegistry, Console, CvarRegistry};
+use client::light::{DynamicLight, DynamicLights, LightKey};
+use client::particle::{Particles, TrailKind};
+use client::predict::{PlayerMove, PredictCmd, Predictor};
+use client::sound::{
+    AudioSource, Channel, ChannelOverflow, ChannelSlot, Listener, MusicDucking, MusicPlayer,
+    PitchControl, StaticSound, StereoGain,
+};
+use client::weapon::WeaponSwitch;
+use common::bsp::{self, BspData, HullKind};
+use common::console::{CmdRegistry, Console, ConsoleColor, CvarRegistry};
 use common::engine;
 use common::model::{Model, ModelFlags, ModelKind, SyncType};
 use common::net::connect::{ConnectSocket, Request, Response, CONNECT_PROTOCOL_VERSION};
@@ -53,12 +69,17 @@ use cgmath::Zero;
 use chrono::Duration;
 use failure::Error;
 use flame;
+use rand::{self, Rng, XorShiftRng};
 use rodio::Endpoint;
 
 // connections are tried 3 times, see
 // https://github.com/id-Software/Quake/blob/master/WinQuake/net_dgrm.c#L1248
 const MAX_CONNECT_ATTEMPTS: usize = 3;
 
+// the disconnect message is sent 3 times, see
+// https://github.com/id-Software/Quake/blob/master/WinQuake/cl_main.c#L97
+const DISCONNECT_SEND_COUNT: usize = 3;
+
 const MAX_STATS: usize = 32;
 
 const DEFAULT_SOUND_PACKET_VOLUME: u8 = 255;
@@ -66,6 +87,85 @@ const DEFAULT_SOUND_PACKET_ATTENUATION: f32 = 1.0;
 
 const MAX_CHANNELS: usize = 128;
 
+// serverinfo keys that are mirrored into cvars of the same name, if they exist
+const SERVERINFO_CVARS: [&str; 6] = [
+    "coop",
+    "deathmatch",
+    "fraglimit",
+    "skill",
+    "teamplay",
+    "timelimit",
+];
+
+// model name prefixes of gibs and severed heads
+const GIB_MODEL_PREFIXES: [&str; 2] = ["progs/gib", "progs/h_"];
+
+/// Returns whether `name` matches any of the space- or comma-separated `patterns`.
+///
+/// A pattern ending in `*` matches any name starting with the rest of the pattern; other patterns
+/// must match exactly.
+fn model_name_matches(name: &str, patterns: &str) -> bool {
+    patterns
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|p| !p.is_empty())
+        .any(|p| {
+            if p.ends_with('*') {
+                name.starts_with(&p[..p.len() - 1])
+            } else {
+                name == p
+            }
+        })
+}
+
+// how long the hitmarker stays lit after a hit
+const HITMARKER_DURATION_MS: i64 = 200;
+
+// the range of the traceline in the shotgun and lightning gun QuakeC
+const HIT_TRACE_RANGE: f32 = 2048.0;
+
+// the client isn't sent bounding boxes, so shots are checked against a player-sized box
+const HIT_BOX_MIN: [f32; 3] = [-16.0, -16.0, -24.0];
+const HIT_BOX_MAX: [f32; 3] = [16.0, 16.0, 32.0];
+
+// the server pitches player models by a third of the view pitch, in the opposite direction
+fn model_to_view_angles(angles: Vector3<Deg<f32>>) -> Vector3<Deg<f32>> {
+    Vector3::new(angles.x * -3.0, angles.y, angles.z)
+}
+
+/// Returns how far along the segment from `start` to `end` it enters the box from `min` to `max`,
+/// from 0 at `start` to 1 at `end`, or `None` if it misses.
+fn segment_box_fraction(
+    start: Vector3<f32>,
+    end: Vector3<f32>,
+    min: Vector3<f32>,
+    max: Vector3<f32>,
+) -> Option<f32> {
+    let delta = end - start;
+    let mut enter: f32 = 0.0;
+    let mut exit: f32 = 1.0;
+
+    for i in 0..3 {
+        if delta[i] == 0.0 {
+            // parallel to this pair of faces, so it has to start between them
+            if start[i] < min[i] || start[i] > max[i] {
+                return None;
+            }
+
+            continue;
+        }
+
+        let t0 = (min[i] - start[i]) / delta[i];
+        let t1 = (max[i] - start[i]) / delta[i];
+        enter = enter.max(t0.min(t1));
+        exit = exit.min(t0.max(t1));
+        if enter > exit {
+            return None;
+        }
+    }
+
+    Some(enter)
+}
+
 #[derive(Debug, FromPrimitive)]
 enum ColorShiftCode {
     Contents = 0,
@@ -74,9 +174,141 @@ enum ColorShiftCode {
     Powerup = 3,
 }
 
-struct ServerInfo {
-    max_clients: u8,
-    game_type: GameType,
+/// Distance fog parameters.
+///
+/// These follow the conventions of the `fog` command found in other Quake engines: density is
+/// typically between 0 and 1, and a density of 0 disables fog entirely.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fog {
+    pub density: f32,
+    pub color: [f32; 3],
+}
+
+impl Fog {
+    /// Parse fog parameters in the form `"density r g b"`, as used by the worldspawn `fog` key.
+    pub fn from_str_components<S>(src: S) -> Option<Fog>
+    where
+        S: AsRef<str>,
+    {
+        let components: Vec<f32> = match src
+            .as_ref()
+            .split_whitespace()
+            .map(|c| c.parse())
+            .collect()
+        {
+            Ok(c) => c,
+            Err(_) => return None,
+        };
+
+        match components.len() {
+            1 => Some(Fog {
+                density: components[0],
+                ..Fog::default()
+            }),
+            4 => Some(Fog {
+                density: components[0],
+                color: [components[1], components[2], components[3]],
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns the fog color as a shader uniform value.
+    pub fn shader_color(&self) -> [f32; 4] {
+        [self.color[0], self.color[1], self.color[2], 1.0]
+    }
+
+    /// Returns the fog density scaled to world units for use in a shader.
+    pub fn shader_density(&self) -> f32 {
+        // fog densities are specified per 64 units
+        self.density / 64.0
+    }
+}
+
+impl ::std::default::Default for Fog {
+    fn default() -> Fog {
+        Fog {
+            density: 0.0,
+            color: [0.3, 0.3, 0.3],
+        }
+    }
+}
+
+/// The name and title of the current level.
+#[derive(Clone, Debug, Default)]
+pub struct LevelInfo {
+    /// The base name of the map file, e.g. `e1m1`.
+    pub name: String,
+
+    /// The title of the map from the worldspawn `message` key, e.g. `the Slipgate Complex`.
+    pub title: String,
+}
+
+/// Server settings as key/value pairs.
+///
+/// NetQuake servers don't send a serverinfo string, so the basic values (`maxclients`,
+/// `deathmatch` and `map`) are filled in from the server info message. Servers that know better can
+/// send a QuakeWorld-style `\key\value` string with the `fullserverinfo` command.
+#[derive(Clone, Debug, Default)]
+pub struct ServerInfo {
+    values: BTreeMap<String, String>,
+}
+
+impl ServerInfo {
+    /// Parses a serverinfo string of the form `\key1\value1\key2\value2`.
+    ///
+    /// A trailing key without a value is ignored.
+    pub fn parse<S>(info: S) -> ServerInfo
+    where
+        S: AsRef<str>,
+    {
+        let mut values = BTreeMap::new();
+        let mut components = info.as_ref().trim_start_matches('\\').split('\\');
+        while let (Some(key), Some(value)) = (components.next(), components.next()) {
+            if !key.is_empty() {
+                values.insert(key.to_owned(), value.to_owned());
+            }
+        }
+
+        ServerInfo { values }
+    }
+
+    pub fn get<S>(&self, key: S) -> Option<&str>
+    where
+        S: AsRef<str>,
+    {
+        self.values.get(key.as_ref()).map(|v| v.as_str())
+    }
+
+    pub fn set<S, T>(&mut self, key: S, value: T)
+    where
+        S: AsRef<str>,
+        T: AsRef<str>,
+    {
+        self.values
+            .insert(key.as_ref().to_owned(), value.as_ref().to_owned());
+    }
+
+    /// Merges the values of `other` into this serverinfo, replacing any existing values.
+    pub fn merge(&mut self, other: ServerInfo) {
+        self.values.extend(other.values);
+    }
+
+    /// Returns an iterator over the key/value pairs in alphabetical order by key.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Copies the server's game rules into the matching cvars, if they're registered.
+    pub fn sync_cvars(&self, cvars: &CvarRegistry) {
+        for key in SERVERINFO_CVARS.iter() {
+            if let Some(value) = self.get(key) {
+                if cvars.contains(key) {
+                    let _ = cvars.set(*key, value);
+                }
+            }
+        }
+    }
 }
 
 struct ClientView {
@@ -125,10 +357,16 @@ pub struct ClientEntity {
     angles: Vector3<Deg<f32>>,
     model_id: usize,
     frame_id: usize,
+    // the frame before the last update, blended into `frame_id` by `frame_lerp`
+    prev_frame_id: usize,
+    frame_lerp: f32,
     skin_id: usize,
     sync_base: Duration,
     effects: EntityEffects,
     // vis_frame: usize,
+
+    // hidden to stay within cl_maxcorpses or cl_maxgibs
+    pruned: bool,
 }
 
 impl ClientEntity {
@@ -146,9 +384,12 @@ impl ClientEntity {
             angles: baseline.angles,
             model_id: baseline.model_id,
             frame_id: baseline.frame_id,
+            prev_frame_id: baseline.frame_id,
+            frame_lerp: 1.0,
             skin_id: baseline.skin_id,
             sync_base: Duration::zero(),
             effects: baseline.effects,
+            pruned: false,
         }
     }
 
@@ -166,9 +407,12 @@ impl ClientEntity {
             angles: Vector3::new(Deg(0.0), Deg(0.0), Deg(0.0)),
             model_id: 0,
             frame_id: 0,
+            prev_frame_id: 0,
+            frame_lerp: 1.0,
             skin_id: 0,
             sync_base: Duration::zero(),
             effects: EntityEffects::empty(),
+            pruned: false,
         }
     }
 
@@ -188,26 +432,135 @@ impl ClientEntity {
         self.frame_id
     }
 
+    /// Returns the frame this entity had before its last update.
+    pub fn get_prev_frame_id(&self) -> usize {
+        self.prev_frame_id
+    }
+
+    /// Returns how far to blend from the previous frame to the current one, between 0 and 1.
+    pub fn get_frame_lerp(&self) -> f32 {
+        self.frame_lerp
+    }
+
     pub fn get_skin_id(&self) -> usize {
         self.skin_id
     }
+
+    /// Returns whether this entity has been hidden to keep the number of corpses or gibs within
+    /// `cl_maxcorpses` or `cl_maxgibs`.
+    pub fn is_pruned(&self) -> bool {
+        self.pruned
+    }
+
+    pub fn get_effects(&self) -> EntityEffects {
+        self.effects
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum DebrisKind {
+    Corpse,
+    Gib,
+}
+
+impl DebrisKind {
+    /// Determines whether an entity with the given model and frame is a corpse or gib.
+    fn classify(model: &Model, frame_id: usize) -> Option<DebrisKind> {
+        if GIB_MODEL_PREFIXES.iter().any(|p| model.name().starts_with(p)) {
+            return Some(DebrisKind::Gib);
+        }
+
+        if let ModelKind::Alias(ref amodel) = *model.kind() {
+            let keyframes = amodel.keyframes();
+            if let Some(keyframe) = keyframes.get(frame_id) {
+                let next = keyframes.get(frame_id + 1).map(|k| k.name());
+                if is_final_death_frame(keyframe.name(), next) {
+                    return Some(DebrisKind::Corpse);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Returns whether the keyframe `name` is the last frame of a death animation, given the name of
+/// the keyframe that follows it.
+///
+/// Death animations are named e.g. `death1` through `death9` or `deathb1` through `deathb8`, so
+/// the animation ends where the alphabetic prefix changes.
+fn is_final_death_frame(name: &str, next: Option<&str>) -> bool {
+    fn prefix(name: &str) -> &str {
+        name.trim_end_matches(|c: char| c.is_ascii_digit())
+    }
+
+    if !name.starts_with("death") {
+        return false;
+    }
+
+    match next {
+        Some(n) => prefix(n) != prefix(name),
+        None => true,
+    }
+}
+
+/// Tracks a kind of entity in the order they appeared so the oldest can be pruned.
+#[derive(Default)]
+struct EntityLimit {
+    ent_ids: VecDeque<usize>,
+}
+
+impl EntityLimit {
+    /// Starts tracking `ent_id`, if it isn't already being tracked.
+    fn track(&mut self, ent_id: usize) {
+        if !self.ent_ids.contains(&ent_id) {
+            self.ent_ids.push_back(ent_id);
+        }
+    }
+
+    /// Stops tracking `ent_id`.
+    fn untrack(&mut self, ent_id: usize) {
+        self.ent_ids.retain(|id| *id != ent_id);
+    }
+
+    /// Returns the IDs of the oldest entities that exceed `limit`. A limit of 0 means no limit.
+    fn excess(&self, limit: usize) -> impl Iterator<Item = &usize> {
+        let count = if limit == 0 {
+            0
+        } else {
+            self.ent_ids.len().saturating_sub(limit)
+        };
+
+        self.ent_ids.iter().take(count)
+    }
 }
 
 struct ClientChannel {
     start_time: Duration,
     ent_id: usize,
     ent_channel: i8,
+    volume: u8,
+    origin: Vector3<f32>,
+    attenuation: f32,
+    // random pitch offset chosen when the sound started
+    detune: f32,
+    pitch: PitchControl,
+    gain: StereoGain,
     channel: Channel,
 }
 
 struct Mixer {
-    endpoint: Rc<Endpoint>,
-    // TODO: replace with an array once const type parameters are implemented
-    channels: Box<[Option<ClientChannel>]>,
+    endpoint: Option<Rc<Endpoint>>,
+    channels: Vec<Option<ClientChannel>>,
+
+    // where sounds are heard from, as of the last call to update_listener
+    listener: Listener,
+    listener_ent_id: usize,
+    master_volume: f32,
 }
 
 impl Mixer {
-    pub fn new(endpoint: Rc<Endpoint>) -> Mixer {
+    pub fn new(endpoint: Option<Rc<Endpoint>>) -> Mixer {
         let mut channel_vec = Vec::new();
 
         for _ in 0..MAX_CHANNELS {
@@ -216,48 +569,56 @@ impl Mixer {
 
         Mixer {
             endpoint,
-            channels: channel_vec.into_boxed_slice(),
+            channels: channel_vec,
+            listener: Listener::default(),
+            listener_ent_id: 0,
+            master_volume: 1.0,
         }
     }
 
-    fn find_free_channel(&self, ent_id: usize, ent_channel: i8) -> usize {
-        let mut oldest = 0;
-
-        for (i, channel) in self.channels.iter().enumerate() {
-            match *channel {
-                Some(ref chan) => {
-                    // if this channel is free, return it right away
-                    if !chan.channel.in_use() {
-                        return i;
-                    }
-
-                    // replace sounds on the same entity channel
-                    if ent_channel != 0
-                        && chan.ent_id == ent_id
-                        && (chan.ent_channel == ent_channel || ent_channel == -1)
-                    {
-                        return i;
-                    }
-
-                    // TODO: don't clobber player sounds with monster sounds
+    // the gain in each ear of a sound on `ent_id`
+    fn channel_gain(
+        &self,
+        ent_id: usize,
+        origin: Vector3<f32>,
+        volume: u8,
+        attenuation: f32,
+    ) -> (f32, f32) {
+        // the player's own sounds play at full volume in both ears
+        let (left, right) = if ent_id == self.listener_ent_id {
+            let v = volume as f32 / 255.0;
+            (v, v)
+        } else {
+            sound::spatialize(&self.listener, origin, volume, attenuation)
+        };
 
-                    // keep track of which sound started the earliest
-                    if chan.start_time
-                        < match self.channels[oldest] {
-                            Some(ref o) => o.start_time,
-                            None => Duration::zero(),
-                        }
-                    {
-                        oldest = i;
-                    }
-                }
+        (left * self.master_volume, right * self.master_volume)
+    }
 
-                None => return i,
-            }
+    /// Moves the listener, adjusting the volume of every playing sound for its new position.
+    ///
+    /// Sounds on `ent_id` aren't spatialized, and `master_volume` scales everything.
+    pub fn update_listener(&mut self, listener: Listener, ent_id: usize, master_volume: f32) {
+        self.listener = listener;
+        self.listener_ent_id = ent_id;
+        self.master_volume = master_volume;
+
+        for chan in self.channels.iter().filter_map(|c| c.as_ref()) {
+            let (left, right) =
+                self.channel_gain(chan.ent_id, chan.origin, chan.volume, chan.attenuation);
+            chan.gain.set(left, right);
         }
+    }
 
-        // if there are no good channels, just replace the one that's been running the longest
-        oldest
+    /// Sets the number of channels, clamped to `[1, MAX_CHANNELS]`.
+    ///
+    /// Sounds playing on removed channels are stopped.
+    pub fn set_channel_count(&mut self, count: usize) {
+        let count = count.max(1).min(MAX_CHANNELS);
+        self.channels.truncate(count);
+        while self.channels.len() < count {
+            self.channels.push(None);
+        }
     }
 
     pub fn start_sound(
@@ -266,17 +627,73 @@ impl Mixer {
         time: Duration,
         ent_id: usize,
         ent_channel: i8,
+        origin: Vector3<f32>,
+        volume: u8,
+        attenuation: f32,
+        overflow: ChannelOverflow,
+        detune: f32,
     ) {
-        let chan_id = self.find_free_channel(ent_id, ent_channel);
+        let slots: Vec<Option<ChannelSlot>> = self
+            .channels
+            .iter()
+            .map(|c| {
+                c.as_ref().map(|c| ChannelSlot {
+                    in_use: c.channel.in_use(),
+                    ent_id: c.ent_id,
+                    ent_channel: c.ent_channel,
+                    start_time: c.start_time,
+                    volume: c.volume,
+                })
+            })
+            .collect();
+
+        let chan_id = match sound::choose_channel(&slots, ent_id, ent_channel, volume, overflow) {
+            Some(c) => c,
+            None => {
+                debug!("All channels busy, dropping sound on entity {}", ent_id);
+                return;
+            }
+        };
+
+        let pitch = PitchControl::new(detune);
+        let (left, right) = self.channel_gain(ent_id, origin, volume, attenuation);
+        let gain = StereoGain::new(left, right);
         let new_channel = Channel::new(self.endpoint.clone());
-        new_channel.play(src.clone());
+        new_channel.play(src.clone(), pitch.clone(), gain.clone());
         self.channels[chan_id] = Some(ClientChannel {
             start_time: time,
             ent_id,
             ent_channel,
+            volume,
+            origin,
+            attenuation,
+            detune,
+            pitch,
+            gain,
             channel: new_channel,
         })
     }
+
+    /// Returns the combined volume of the sounds playing, where 1 is one sound at full volume.
+    pub fn level(&self) -> f32 {
+        self.channels
+            .iter()
+            .filter_map(|c| c.as_ref())
+            .filter(|c| c.channel.in_use())
+            .map(|c| c.volume as f32 / 255.0)
+            .sum()
+    }
+
+    /// Sets the pitch of each playing sound to its detune times the doppler factor `doppler`
+    /// returns for its entity.
+    pub fn update_pitch<F>(&self, doppler: F)
+    where
+        F: Fn(usize) -> f32,
+    {
+        for chan in self.channels.iter().filter_map(|c| c.as_ref()) {
+            chan.pitch.set(chan.detune * doppler(chan.ent_id));
+        }
+    }
 }
 
 // client information regarding the current level
@@ -298,8 +715,11 @@ struct ClientState {
 
     // various values relevant to the player and level (see common::net::ClientStat)
     stats: [i32; MAX_STATS],
+    // the view model frame before the last update, blended like entity frames
+    prev_weapon_frame: usize,
 
     max_players: usize,
+    max_edicts: usize,
     player_info: [Option<PlayerInfo>; net::MAX_CLIENTS],
 
     // the last two timestamps sent by the server (for lerping)
@@ -312,13 +732,26 @@ struct ClientState {
     // cmd: MoveCmd,
     items: ItemFlags,
     item_get_time: [Duration; net::MAX_ITEMS],
+    hit_time: Option<Duration>,
+    particles: Particles,
+    dynamic_lights: DynamicLights,
+
+    // drives light flicker and sound detuning, seeded by cl_seed like the particles
+    rng: XorShiftRng,
+
+    // where the renderer last drew the view model's muzzle, if it was flashing
+    muzzle_flash_origin: Option<Vector3<f32>>,
+    corpses: EntityLimit,
+    gibs: EntityLimit,
     // face_anim_time: f32,
     color_shifts: [Rc<RefCell<ColorShift>>; 4],
     // prev_color_shifts: [ColorShift; 4],
     view: ClientView,
+    weapon_switch: WeaponSwitch,
 
     msg_velocity: [Vector3<f32>; 2],
     velocity: Vector3<f32>,
+    predictor: Predictor,
 
     // ideal_pitch: Deg<f32>,
     // pitch_velocity: f32,
@@ -341,11 +774,12 @@ struct ClientState {
 
     // worldmodel: Model,
     mixer: Mixer,
+    music_ducking: MusicDucking,
 }
 
 impl ClientState {
     // TODO: add parameter for number of player slots and reserve them in entity list
-    pub fn new(vfs: Rc<Vfs>, endpoint: Rc<Endpoint>) -> ClientState {
+    pub fn new(vfs: Rc<Vfs>, endpoint: Option<Rc<Endpoint>>) -> ClientState {
         ClientState {
             vfs: vfs.clone(),
             models: vec![Model::none()],
@@ -354,7 +788,9 @@ impl ClientState {
             entities: Vec::new(),
             light_styles: HashMap::new(),
             stats: [0; MAX_STATS],
+            prev_weapon_frame: 0,
             max_players: 0,
+            max_edicts: net::DEFAULT_MAX_EDICTS,
             // TODO: for the love of god can the lang team hurry up (https://github.com/rust-lang/rfcs/pull/2203)
             // this might make more sense as a different data structure anyway who knows
             player_info: [
@@ -365,6 +801,13 @@ impl ClientState {
             time: Duration::zero(),
             lerp_factor: 0.0,
             items: ItemFlags::empty(),
+            hit_time: None,
+            particles: Particles::default(),
+            dynamic_lights: DynamicLights::new(),
+            rng: rand::weak_rng(),
+            muzzle_flash_origin: None,
+            corpses: EntityLimit::default(),
+            gibs: EntityLimit::default(),
             // TODO: make this less horrific once const fn array initializers are available
             item_get_time: [
                 Duration::zero(),
@@ -419,26 +862,74 @@ impl ClientState {
                 })),
             ],
             view: ClientView::new(),
+            weapon_switch: WeaponSwitch::new(),
             msg_velocity: [Vector3::zero(), Vector3::zero()],
             velocity: Vector3::zero(),
+            predictor: Predictor::new(),
             on_ground: false,
             in_water: false,
             mixer: Mixer::new(endpoint.clone()),
+            music_ducking: MusicDucking::new(),
         }
     }
 }
 
+// where server messages come from
+enum ConnectionKind {
+    Server(QSocket),
+    Demo(DemoServer),
+}
+
+// a level whose missing content is being downloaded
+struct PendingDownloads {
+    queue: DownloadQueue,
+
+    // server messages that arrived meanwhile, starting with the server info, handled once the
+    // downloads finish
+    deferred: Vec<Vec<u8>>,
+}
+
 pub struct Client {
     vfs: Rc<Vfs>,
     cvars: Rc<RefCell<CvarRegistry>>,
     cmds: Rc<RefCell<CmdRegistry>>,
     console: Rc<RefCell<Console>>,
-    endpoint: Rc<Endpoint>,
+    endpoint: Option<Rc<Endpoint>>,
 
-    qsock: QSocket,
+    conn: ConnectionKind,
     compose: Vec<u8>,
     signon: SignOnStage,
 
+    // string commands from console commands like `say`, sent along with the next message
+    forward: Rc<RefCell<Vec<String>>>,
+
+    // the demo being recorded, if any
+    recording: Option<DemoRecorder<File>>,
+
+    // set by the record and stop commands, handled at the start of the next frame
+    record_requested: Rc<RefCell<Option<(String, Option<u32>)>>>,
+    stop_requested: Rc<Cell<bool>>,
+
+    // the messages that signed on to the current level, replayed into recordings started later
+    signon_msgs: Vec<DemoMessage>,
+
+    // missing content being downloaded before the level loads
+    downloads: Option<PendingDownloads>,
+
+    // files already downloaded or tried, so a failed download isn't retried
+    download_attempts: HashSet<String>,
+
+    // replaces CD audio, shared with the cd command
+    music: Rc<RefCell<MusicPlayer>>,
+
+    // invoked with the new stage whenever the signon stage changes
+    signon_callbacks: Vec<Box<dyn Fn(SignOnStage)>>,
+
+    // fog and level outlive the ClientState so their commands stay valid across map changes
+    fog: Rc<Cell<Fog>>,
+    level: Rc<RefCell<LevelInfo>>,
+    server_info: Rc<RefCell<ServerInfo>>,
+
     state: ClientState,
 }
 
@@ -449,12 +940,19 @@ impl Client {
         cvars: Rc<RefCell<CvarRegistry>>,
         cmds: Rc<RefCell<CmdRegistry>>,
         console: Rc<RefCell<Console>>,
-        endpoint: Rc<Endpoint>,
+        endpoint: Option<Rc<Endpoint>>,
     ) -> Result<Client, Error>
     where
         A: ToSocketAddrs,
     {
-        let mut con_sock = ConnectSocket::bind("0.0.0.0:0")?;
+        // cl_port 0 lets the OS choose the source port
+        let port = cvars.borrow().get_value("cl_port").unwrap_or(0.0);
+        ensure!(
+            port >= 0.0 && port <= u16::max_value() as f32,
+            "Invalid cl_port {}",
+            port
+        );
+        let mut con_sock = ConnectSocket::bind(("0.0.0.0", port as u16))?;
         let server_addr = server_addrs.to_socket_addrs().unwrap().next().unwrap();
 
         let mut response = None;
@@ -532,13 +1030,84 @@ impl Client {
             cmds,
             console,
             endpoint: endpoint.clone(),
-            qsock,
+            conn: ConnectionKind::Server(qsock),
+            compose: Vec::new(),
+            signon: SignOnStage::Not,
+            forward: Rc::new(RefCell::new(Vec::new())),
+            recording: None,
+            record_requested: Rc::new(RefCell::new(None)),
+            stop_requested: Rc::new(Cell::new(false)),
+            signon_msgs: Vec::new(),
+            downloads: None,
+            download_attempts: HashSet::new(),
+            music: Rc::new(RefCell::new(MusicPlayer::new(vfs.clone(), endpoint.clone()))),
+            signon_callbacks: Vec::new(),
+            fog: Rc::new(Cell::new(Fog::default())),
+            level: Rc::new(RefCell::new(LevelInfo::default())),
+            server_info: Rc::new(RefCell::new(ServerInfo::default())),
+            state: ClientState::new(vfs.clone(), endpoint.clone()),
+        })
+    }
+
+    /// Plays back a demo as if it were a connection to a server.
+    ///
+    /// Without an `endpoint`, sounds and music are tracked but not played.
+    pub fn play_demo(
+        demo: DemoServer,
+        vfs: Rc<Vfs>,
+        cvars: Rc<RefCell<CvarRegistry>>,
+        cmds: Rc<RefCell<CmdRegistry>>,
+        console: Rc<RefCell<Console>>,
+        endpoint: Option<Rc<Endpoint>>,
+    ) -> Result<Client, Error> {
+        Ok(Client {
+            vfs: vfs.clone(),
+            cvars,
+            cmds,
+            console,
+            endpoint: endpoint.clone(),
+            conn: ConnectionKind::Demo(demo),
             compose: Vec::new(),
             signon: SignOnStage::Not,
+            forward: Rc::new(RefCell::new(Vec::new())),
+            recording: None,
+            record_requested: Rc::new(RefCell::new(None)),
+            stop_requested: Rc::new(Cell::new(false)),
+            signon_msgs: Vec::new(),
+            downloads: None,
+            download_attempts: HashSet::new(),
+            music: Rc::new(RefCell::new(MusicPlayer::new(vfs.clone(), endpoint.clone()))),
+            signon_callbacks: Vec::new(),
+            fog: Rc::new(Cell::new(Fog::default())),
+            level: Rc::new(RefCell::new(LevelInfo::default())),
+            server_info: Rc::new(RefCell::new(ServerInfo::default())),
             state: ClientState::new(vfs.clone(), endpoint.clone()),
         })
     }
 
+    /// Disconnects from the current server or demo and starts playing back `demo` instead.
+    pub fn start_demo(&mut self, demo: DemoServer) -> Result<(), Error> {
+        self.disconnect()?;
+        self.conn = ConnectionKind::Demo(demo);
+        Ok(())
+    }
+
+    /// Returns whether this client is playing back a demo rather than connected to a server.
+    pub fn is_demo(&self) -> bool {
+        match self.conn {
+            ConnectionKind::Demo(_) => true,
+            ConnectionKind::Server(_) => false,
+        }
+    }
+
+    /// Returns whether this client is playing a demo that has no messages left.
+    pub fn demo_finished(&self) -> bool {
+        match self.conn {
+            ConnectionKind::Demo(ref demo) => demo.is_finished(),
+            ConnectionKind::Server(_) => false,
+        }
+    }
+
     pub fn add_cmd(&mut self, cmd: ClientCmd) -> Result<(), Error> {
         cmd.serialize(&mut self.compose)?;
 
@@ -642,7 +1211,10 @@ impl Client {
 
         let mut button_flags = ButtonFlags::empty();
 
-        if game_input.action_state(Action::Attack) {
+        // the new weapon can't fire until it's been raised
+        if game_input.action_state(Action::Attack)
+            && !self.state.weapon_switch.blocks_fire(self.state.time)
+        {
             button_flags |= ButtonFlags::ATTACK;
         }
 
@@ -651,12 +1223,16 @@ impl Client {
         }
 
         // TODO: IN_Move (mouse / joystick / gamepad)
+        game_input.set_raw_mouse(self.cvars.borrow().get_value("m_rawinput").unwrap() != 0.0);
+        let m_smooth = self.cvars.borrow().get_value("m_smooth").unwrap().max(1.0);
+        let mouse_delta = game_input.smoothed_mouse_delta(m_smooth as usize);
+
         if game_input.action_state(Action::MLook) {
             let m_pitch = self.cvars.borrow().get_value("m_pitch").unwrap();
-            self.state.view.view_angles.x += Deg(game_input.mouse_delta().1 as f32 * m_pitch);
+            self.state.view.view_angles.x += Deg(mouse_delta.1 as f32 * m_pitch);
 
             let m_yaw = self.cvars.borrow().get_value("m_yaw").unwrap();
-            self.state.view.view_angles.y -= Deg(game_input.mouse_delta().0 as f32 * m_yaw);
+            self.state.view.view_angles.y -= Deg(mouse_delta.0 as f32 * m_yaw);
         } else {
             // TODO: mouse movement controls player movement
         }
@@ -674,9 +1250,37 @@ impl Client {
         };
         // debug!("Sending move command: {:?}", move_cmd);
 
-        let mut msg = Vec::new();
-        move_cmd.serialize(&mut msg)?;
-        self.qsock.send_msg_unreliable(&msg)?;
+        if self.predicting() {
+            if let Some(world) = self.world_bsp() {
+                let trace = |hull: HullKind, start: Vector3<f32>, end: Vector3<f32>| {
+                    world.hull_trace(hull.index(), start, end).map_err(Error::from)
+                };
+                let predicted = self.state.predictor.predict(
+                    PredictCmd {
+                        send_time,
+                        duration: frame_time,
+                        angles,
+                        fwd_move: forwardmove,
+                        side_move: sidemove,
+                        jump: button_flags.contains(ButtonFlags::JUMP),
+                    },
+                    &trace,
+                );
+
+                // show the player where the server last put them until the next update
+                if let Err(e) = predicted {
+                    warn!("Prediction failed: {}", e);
+                    self.state.predictor.reset();
+                }
+            }
+        }
+
+        // demos can't be controlled
+        if let ConnectionKind::Server(ref mut qsock) = self.conn {
+            let mut msg = Vec::new();
+            move_cmd.serialize(&mut msg)?;
+            qsock.send_msg_unreliable(&msg)?;
+        }
 
         // clear mouse and impulse
         game_input.refresh()?;
@@ -686,9 +1290,21 @@ impl Client {
 
     pub fn send(&mut self) -> Result<(), Error> {
         let _guard = flame::start_guard("Client::send");
-        if self.qsock.can_send() && !self.compose.is_empty() {
-            self.qsock.begin_send_msg(&self.compose)?;
-            self.compose.clear();
+        let forward: Vec<String> = self.forward.borrow_mut().drain(..).collect();
+        for cmd in forward {
+            self.add_cmd(ClientCmd::StringCmd { cmd })?;
+        }
+
+        match self.conn {
+            ConnectionKind::Server(ref mut qsock) => {
+                if qsock.can_send() && !self.compose.is_empty() {
+                    qsock.begin_send_msg(&self.compose)?;
+                    self.compose.clear();
+                }
+            }
+
+            // there's nobody to respond to string commands
+            ConnectionKind::Demo(_) => self.compose.clear(),
         }
 
         Ok(())
@@ -734,6 +1350,13 @@ impl Client {
     ) -> Result<(), Error> {
         let id = ent_id as usize;
 
+        ensure!(
+            id < self.state.max_edicts,
+            "Entity {} exceeds the entity limit ({}), raise max_edicts to load this map",
+            id,
+            self.state.max_edicts
+        );
+
         // don't clobber existing entities
         ensure!(
             id >= self.state.entities.len(),
@@ -781,70 +1404,298 @@ impl Client {
 
     pub fn parse_server_msg(&mut self) -> Result<(), Error> {
         let _guard = flame::start_guard("Client::parse_server_msg");
-        let msg = self.qsock.recv_msg(match self.signon {
+        self.update_downloads()?;
+
+        let block = match self.signon {
             // if we're in the game, don't block waiting for messages
             SignOnStage::Done => BlockingMode::NonBlocking,
 
+            // the server is waiting for us while content downloads
+            _ if self.downloads.is_some() => BlockingMode::NonBlocking,
+
             // otherwise, give the server some time to respond
             // TODO: might make sense to make this a future or something
             _ => BlockingMode::Timeout(Duration::seconds(5)),
-        })?;
+        };
+
+        let msg = match self.conn {
+            ConnectionKind::Server(ref mut qsock) => qsock.recv_msg(block)?,
+
+            ConnectionKind::Demo(_) => {
+                // read messages until the demo catches up with the client's time
+                while let Some(msg) = self.next_demo_msg() {
+                    self.handle_msg(&msg)?;
+                }
+
+                return Ok(());
+            }
+        };
 
         // no data available at this time
         if msg.is_empty() {
             return Ok(());
         }
 
-        let mut reader = BufReader::new(msg.as_slice());
+        self.handle_msg(&msg)
+    }
 
-        while let Some(cmd) = ServerCmd::deserialize(&mut reader)? {
-            match cmd {
-                ServerCmd::Bad => panic!("Invalid command from server"),
-                ServerCmd::NoOp => (),
+    // record and parse a message from the server
+    fn handle_msg(&mut self, msg: &[u8]) -> Result<(), Error> {
+        let view_angles = self.state.view.view_angles;
+        let write_result = match self.recording {
+            Some(ref mut recording) => recording.write_message(view_angles, msg),
+            None => Ok(()),
+        };
 
-                ServerCmd::CdTrack { .. } => {
-                    // TODO: play CD track
-                    warn!("CD tracks not yet implemented");
-                }
+        if let Err(e) = write_result {
+            self.abort_recording(e);
+        }
 
-                ServerCmd::CenterPrint { text } => {
-                    // TODO: print to center of screen
-                    warn!("Center print not yet implemented!");
-                    println!("{}", text);
-                }
+        // nothing can be parsed until the level's content is here
+        if let Some(ref mut downloads) = self.downloads {
+            downloads.deferred.push(msg.to_vec());
+            return Ok(());
+        }
 
-                ServerCmd::ClientData {
-                    view_height,
-                    ideal_pitch,
-                    punch_pitch,
-                    velocity_x,
-                    punch_yaw,
-                    velocity_y,
-                    punch_roll,
-                    velocity_z,
-                    items,
-                    on_ground,
-                    in_water,
-                    weapon_frame,
-                    armor,
-                    weapon,
-                    health,
-                    ammo,
-                    ammo_shells,
-                    ammo_nails,
-                    ammo_rockets,
-                    ammo_cells,
-                    active_weapon,
-                } => {
-                    self.state.view.view_height = view_height.unwrap_or(net::DEFAULT_VIEWHEIGHT);
-                    self.state.view.ideal_pitch = ideal_pitch.unwrap_or(Deg(0.0));
+        self.process_msg(msg)
+    }
 
-                    self.state.view.punch_angle[0] = punch_pitch.unwrap_or(Deg(0.0));
-                    self.state.view.punch_angle[1] = punch_yaw.unwrap_or(Deg(0.0));
-                    self.state.view.punch_angle[2] = punch_roll.unwrap_or(Deg(0.0));
+    // parse a message and keep it if it's part of the signon
+    fn process_msg(&mut self, msg: &[u8]) -> Result<(), Error> {
+        let view_angles = self.state.view.view_angles;
+        let signon = self.signon;
+        self.parse_msg(msg)?;
 
-                    // store old velocity
-                    self.state.msg_velocity[1] = self.state.msg_velocity[0];
+        // the rest of the message was deferred until downloads finish, and is kept then
+        if self.downloads.is_some() {
+            return Ok(());
+        }
+
+        // a disconnect ends the level, so there's nothing left to sign on to
+        let connected = self.signon != SignOnStage::Not;
+        if connected && (signon != SignOnStage::Done || self.signon != SignOnStage::Done) {
+            self.signon_msgs.push(DemoMessage::new(view_angles, msg.to_vec()));
+        }
+
+        Ok(())
+    }
+
+    // returns the precached files that are missing and may be downloaded
+    fn missing_content(
+        &self,
+        model_precache: &[String],
+        sound_precache: &[String],
+    ) -> Vec<String> {
+        let models = model_precache.iter().filter(|m| !m.starts_with("*")).cloned();
+        let sounds = sound_precache.iter().map(|s| format!("sound/{}", s));
+
+        models
+            .chain(sounds)
+            .filter(|path| !self.download_attempts.contains(path) && self.vfs.open(path).is_err())
+            .collect()
+    }
+
+    // with cl_allowdownload set, starts fetching missing content from cl_downloadurl
+    fn start_downloads(
+        &mut self,
+        model_precache: &[String],
+        sound_precache: &[String],
+    ) -> Option<DownloadQueue> {
+        // demos can't wait for downloads
+        if let ConnectionKind::Demo(_) = self.conn {
+            return None;
+        }
+
+        if self.cvars.borrow().get_value("cl_allowdownload").unwrap() == 0.0 {
+            return None;
+        }
+
+        let url = self.cvars.borrow().get("cl_downloadurl").unwrap();
+        if url.is_empty() {
+            return None;
+        }
+
+        let missing = self.missing_content(model_precache, sound_precache);
+        if missing.is_empty() {
+            return None;
+        }
+
+        println!("Downloading {} missing files...", missing.len());
+        self.download_attempts.extend(missing.iter().cloned());
+        Some(DownloadQueue::start(&url, &missing))
+    }
+
+    // once downloads finish, handles the messages that waited for them
+    fn update_downloads(&mut self) -> Result<(), Error> {
+        let done = match self.downloads {
+            Some(ref mut downloads) => downloads.queue.poll(&self.vfs),
+            None => return Ok(()),
+        };
+
+        if !done {
+            return Ok(());
+        }
+
+        let mut deferred = self.downloads.take().unwrap().deferred.into_iter();
+        while let Some(msg) = deferred.next() {
+            self.process_msg(&msg)?;
+
+            // a message may start another round of downloads, which the rest has to wait for
+            if let Some(ref mut downloads) = self.downloads {
+                downloads.deferred.extend(deferred);
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    // a failed write leaves the demo truncated, but it shouldn't take the game down with it
+    fn abort_recording(&mut self, error: Error) {
+        println!("Demo recording failed: {}", error);
+        self.recording = None;
+    }
+
+    // start or stop recording as requested by the record and stop commands
+    fn update_recording(&mut self) -> Result<(), Error> {
+        if self.stop_requested.replace(false) {
+            match self.recording.take() {
+                Some(recording) => {
+                    recording.finish(self.state.view.view_angles)?;
+                    println!("Completed demo");
+                }
+                None => println!("Not recording a demo."),
+            }
+        }
+
+        let (name, track_override) = match self.record_requested.borrow_mut().take() {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+
+        if self.recording.is_some() {
+            println!("Already recording a demo; use stop first");
+            return Ok(());
+        }
+
+        let mut recording = DemoRecorder::create(&self.vfs, &name, track_override)?;
+
+        // when starting mid-level, the demo needs the signon to set up the level, and the light
+        // styles may have changed since
+        let view_angles = self.state.view.view_angles;
+        for msg in self.signon_msgs.iter() {
+            recording.write_message(msg.view_angles(), msg.message())?;
+        }
+
+        if self.signon == SignOnStage::Done {
+            let mut msg = Vec::new();
+            for (&id, value) in self.state.light_styles.iter() {
+                ServerCmd::LightStyle {
+                    id,
+                    value: value.clone(),
+                }
+                .serialize(&mut msg)?;
+            }
+
+            if !msg.is_empty() {
+                recording.write_message(view_angles, &msg)?;
+            }
+        }
+
+        println!("Recording to {}", demo::demo_path(&name));
+        self.recording = Some(recording);
+
+        Ok(())
+    }
+
+    // returns the next demo message if the client's time has passed the last one
+    fn next_demo_msg(&mut self) -> Option<Vec<u8>> {
+        if self.signon == SignOnStage::Done && self.state.time <= self.state.msg_times[0] {
+            return None;
+        }
+
+        let demo = match self.conn {
+            ConnectionKind::Demo(ref mut demo) => demo,
+            ConnectionKind::Server(_) => return None,
+        };
+
+        let msg = demo.next()?;
+        self.state.view.msg_view_angles[1] = self.state.view.msg_view_angles[0];
+        self.state.view.msg_view_angles[0] = msg.view_angles();
+        Some(msg.message().to_vec())
+    }
+
+    fn parse_msg(&mut self, msg: &[u8]) -> Result<(), Error> {
+        let mut reader = Cursor::new(msg);
+
+        // whether our entity's update shows that we fired this frame
+        let mut fired = false;
+
+        loop {
+            let cmd_start = reader.position() as usize;
+            let cmd = match ServerCmd::deserialize(&mut reader)? {
+                Some(c) => c,
+                None => break,
+            };
+
+            match cmd {
+                ServerCmd::Bad => panic!("Invalid command from server"),
+                ServerCmd::NoOp => (),
+
+                ServerCmd::CdTrack { track, .. } => {
+                    // demos can be recorded with a different track than the level's
+                    let track = match self.conn {
+                        ConnectionKind::Demo(ref demo) => {
+                            demo.track_override().unwrap_or(track as u32)
+                        }
+                        ConnectionKind::Server(_) => track as u32,
+                    };
+
+                    // the original client always loops the level's track. A broken music file
+                    // shouldn't keep the level from loading
+                    if let Err(e) = self.music.borrow_mut().play(track, true) {
+                        warn!("Couldn't play CD track {}: {}", track, e);
+                    }
+                }
+
+                ServerCmd::CenterPrint { text } => {
+                    // TODO: print to center of screen
+                    warn!("Center print not yet implemented!");
+                    println!("{}", text);
+                }
+
+                ServerCmd::ClientData {
+                    view_height,
+                    ideal_pitch,
+                    punch_pitch,
+                    velocity_x,
+                    punch_yaw,
+                    velocity_y,
+                    punch_roll,
+                    velocity_z,
+                    items,
+                    on_ground,
+                    in_water,
+                    weapon_frame,
+                    armor,
+                    weapon,
+                    health,
+                    ammo,
+                    ammo_shells,
+                    ammo_nails,
+                    ammo_rockets,
+                    ammo_cells,
+                    active_weapon,
+                } => {
+                    self.state.view.view_height = view_height.unwrap_or(net::DEFAULT_VIEWHEIGHT);
+                    self.state.view.ideal_pitch = ideal_pitch.unwrap_or(Deg(0.0));
+
+                    self.state.view.punch_angle[0] = punch_pitch.unwrap_or(Deg(0.0));
+                    self.state.view.punch_angle[1] = punch_yaw.unwrap_or(Deg(0.0));
+                    self.state.view.punch_angle[2] = punch_roll.unwrap_or(Deg(0.0));
+
+                    // store old velocity
+                    self.state.msg_velocity[1] = self.state.msg_velocity[0];
                     self.state.msg_velocity[0].x = velocity_x.unwrap_or(0.0);
                     self.state.msg_velocity[0].y = velocity_y.unwrap_or(0.0);
                     self.state.msg_velocity[0].z = velocity_z.unwrap_or(0.0);
@@ -867,6 +1718,7 @@ impl Client {
                     self.state.on_ground = on_ground;
                     self.state.in_water = in_water;
 
+                    self.state.prev_weapon_frame = self.weapon_frame();
                     self.state.stats[ClientStat::WeaponFrame as usize] =
                         weapon_frame.unwrap_or(0) as i32;
 
@@ -880,7 +1732,15 @@ impl Client {
 
                     let weapon = weapon.unwrap_or(0);
                     if self.state.stats[ClientStat::Weapon as usize] != weapon as i32 {
+                        // the first weapon after spawning is ready immediately
+                        if self.state.stats[ClientStat::Weapon as usize] != 0 {
+                            let duration = weapon::switch_duration(&self.cvars.borrow());
+                            self.state.weapon_switch.begin(self.state.time, duration);
+                        }
+
                         self.state.stats[ClientStat::Weapon as usize] = weapon as i32;
+                        // don't blend in a frame of the previous weapon's model
+                        self.state.prev_weapon_frame = self.weapon_frame();
                         // TODO: update status bar
                     }
 
@@ -922,7 +1782,7 @@ impl Client {
                     }
                 }
 
-                ServerCmd::Disconnect => self.disconnect(),
+                ServerCmd::Disconnect => self.disconnect()?,
 
                 ServerCmd::FastUpdate {
                     ent_id,
@@ -941,9 +1801,7 @@ impl Client {
                 } => {
                     // first update signals the last sign-on stage
                     if self.signon == SignOnStage::Begin {
-                        self.signon = SignOnStage::Done;
-                        let signon = self.signon;
-                        self.handle_signon(signon)?;
+                        self.handle_signon(SignOnStage::Done)?;
                     }
 
                     let mut force_link = false;
@@ -990,6 +1848,8 @@ impl Client {
                         }
                     }
 
+                    let prev_frame_id = self.state.entities[ent_id].frame_id;
+                    self.state.entities[ent_id].prev_frame_id = prev_frame_id;
                     self.state.entities[ent_id].frame_id = frame_id
                         .map(|x| x as usize)
                         .unwrap_or(self.state.entities[ent_id].baseline.frame_id);
@@ -1016,6 +1876,16 @@ impl Client {
                     self.state.entities[ent_id].effects =
                         effects.unwrap_or(self.state.entities[ent_id].baseline.effects);
 
+                    // the server clears the flash after each frame, so every update that carries
+                    // it is a new shot
+                    if ent_id == self.state.view.ent_id
+                        && self.state.entities[ent_id]
+                            .effects
+                            .contains(EntityEffects::MUZZLE_FLASH)
+                    {
+                        fired = true;
+                    }
+
                     // save previous origin and angles
                     self.state.entities[ent_id].msg_origins[1] =
                         self.state.entities[ent_id].msg_origins[0];
@@ -1055,6 +1925,21 @@ impl Client {
                     }
                 }
 
+                ServerCmd::Damage { .. } => {
+                    // TODO: damage color shift and view kick
+                }
+
+                ServerCmd::Particle {
+                    origin,
+                    direction,
+                    count,
+                    color,
+                } => {
+                    self.state
+                        .particles
+                        .create_effect(self.state.time, origin, direction, color, count);
+                }
+
                 ServerCmd::FoundSecret => self.state.stats[ClientStat::FoundSecrets as usize] += 1,
                 ServerCmd::KilledMonster => {
                     self.state.stats[ClientStat::KilledMonsters as usize] += 1
@@ -1078,6 +1963,16 @@ impl Client {
                     model_precache,
                     sound_precache,
                 } => {
+                    // the level can't load until its missing content has downloaded, so the
+                    // rest of the message waits for it too
+                    if let Some(queue) = self.start_downloads(&model_precache, &sound_precache) {
+                        self.downloads = Some(PendingDownloads {
+                            queue,
+                            deferred: vec![msg[cmd_start..].to_vec()],
+                        });
+                        return Ok(());
+                    }
+
                     self.update_server_info(
                         protocol_version,
                         max_clients,
@@ -1118,20 +2013,41 @@ impl Client {
                     entity_id,
                     channel,
                     sound_id,
-                    position: _,
+                    position,
                 } => {
-                    println!(
+                    debug!(
                         "starting sound with id {} on entity {} channel {}",
                         sound_id, entity_id, channel
                     );
-                    let _volume = volume.unwrap_or(DEFAULT_SOUND_PACKET_VOLUME);
-                    let _attenuation = attenuation.unwrap_or(DEFAULT_SOUND_PACKET_ATTENUATION);
-                    // TODO: apply volume, attenuation, spatialization
+                    let volume = volume.unwrap_or(DEFAULT_SOUND_PACKET_VOLUME);
+                    let attenuation = attenuation.unwrap_or(DEFAULT_SOUND_PACKET_ATTENUATION);
+
+                    let (channel_count, overflow) = {
+                        let cvars = self.cvars.borrow();
+                        (
+                            cvars.get_value("s_channels").unwrap_or(MAX_CHANNELS as f32),
+                            cvars
+                                .get("s_channel_overflow")
+                                .ok()
+                                .and_then(ChannelOverflow::parse)
+                                .unwrap_or(ChannelOverflow::StealOldest),
+                        )
+                    };
+                    self.state.mixer.set_channel_count(channel_count.max(0.0) as usize);
+
+                    let variation = self.cvars.borrow().get_value("s_pitch_variation").unwrap();
+                    let detune = sound::detune(&mut self.state.rng, variation);
+
                     self.state.mixer.start_sound(
                         self.state.sounds[sound_id as usize].clone(),
                         self.state.msg_times[0],
                         entity_id as usize,
                         channel,
+                        position,
+                        volume,
+                        attenuation,
+                        overflow,
+                        detune,
                     );
                 }
 
@@ -1156,7 +2072,7 @@ impl Client {
                     attenuation,
                 } => {
                     self.state.static_sounds.push(StaticSound::new(
-                        &self.endpoint,
+                        self.endpoint.as_ref().map(|e| &**e),
                         origin,
                         self.state.sounds[sound_id as usize].clone(),
                         volume,
@@ -1166,7 +2082,7 @@ impl Client {
 
                 ServerCmd::TempEntity { temp_entity } => self.spawn_temp_entity(&temp_entity),
 
-                ServerCmd::StuffText { text } => self.console.borrow_mut().stuff_text(text),
+                ServerCmd::StuffText { text } => self.console.borrow().stuff_server_text(text),
 
                 ServerCmd::Time { time } => {
                     self.state.msg_times[1] = self.state.msg_times[0];
@@ -1265,9 +2181,75 @@ impl Client {
             }
         }
 
+        // targets are only where the server says they are once the whole message is read
+        if fired && self.shot_hit() {
+            self.state.hit_time = Some(self.state.time);
+        }
+
         Ok(())
     }
 
+    // whether the shot just fired from the view would hit another entity before the world.
+    //
+    // the protocol doesn't tell attackers what they hit, so this traces the shot the way the
+    // weapon code's traceline does, along the view direction from the player's eyes.
+    fn shot_hit(&self) -> bool {
+        let view_ent_id = self.state.view.ent_id;
+        if view_ent_id == 0 || view_ent_id >= self.state.entities.len() {
+            return false;
+        }
+
+        let start = self.state.entities[view_ent_id].msg_origins[0]
+            + Vector3::new(0.0, 0.0, self.state.view.view_height);
+
+        let (pitch, yaw) = (self.state.view.view_angles.x, self.state.view.view_angles.y);
+        let forward = Vector3::new(
+            pitch.cos() * yaw.cos(),
+            pitch.cos() * yaw.sin(),
+            -pitch.sin(),
+        );
+        let end = start + forward * HIT_TRACE_RANGE;
+
+        // the shot stops at the first wall
+        let world_fraction = match self.world_bsp() {
+            Some(world) => match world.hull_trace(HullKind::Point.index(), start, end) {
+                Ok(trace) => trace.fraction,
+                Err(e) => {
+                    warn!("Couldn't trace shot: {}", e);
+                    return false;
+                }
+            },
+            None => 1.0,
+        };
+
+        let msg_time = self.state.msg_times[0];
+        self.state
+            .entities
+            .iter()
+            .enumerate()
+            .filter(|&(id, ent)| {
+                // only monsters and players can be hurt, and only those still being updated
+                // are where we think they are
+                id != 0
+                    && id != view_ent_id
+                    && ent.msg_time == msg_time
+                    && match self.state.models.get(ent.model_id).map(|m| m.kind()) {
+                        Some(&ModelKind::Alias(_)) => true,
+                        _ => false,
+                    }
+            })
+            .filter_map(|(_, ent)| {
+                let origin = ent.msg_origins[0];
+                segment_box_fraction(
+                    start,
+                    end,
+                    origin + Vector3::from(HIT_BOX_MIN),
+                    origin + Vector3::from(HIT_BOX_MAX),
+                )
+            })
+            .any(|f| f < world_fraction)
+    }
+
     fn handle_signon(&mut self, stage: SignOnStage) -> Result<(), Error> {
         match stage {
             SignOnStage::Not => (), // TODO this is an error (invalid value)
@@ -1284,6 +2266,10 @@ impl Client {
                 self.add_cmd(ClientCmd::StringCmd {
                     cmd: format!("color {} {}", 0, 0),
                 })?;
+                let rate = self.cvars.borrow().get_value("rate").unwrap_or(0.0);
+                self.add_cmd(ClientCmd::StringCmd {
+                    cmd: format!("rate {}", rate.max(0.0) as u32),
+                })?;
                 // TODO: need default spawn parameters?
                 self.add_cmd(ClientCmd::StringCmd {
                     cmd: format!("spawn {}", ""),
@@ -1300,7 +2286,12 @@ impl Client {
             }
         }
 
-        self.signon = stage;
+        if stage != self.signon {
+            self.signon = stage;
+            for callback in self.signon_callbacks.iter() {
+                callback(stage);
+            }
+        }
 
         Ok(())
     }
@@ -1315,6 +2306,18 @@ impl Client {
         sound_precache: Vec<String>,
     ) -> Result<(), Error> {
         let mut new_client_state = ClientState::new(self.vfs.clone(), self.endpoint.clone());
+        self.signon_msgs.clear();
+
+        // a nonzero cl_seed makes effects reproducible, e.g. for timedemo comparisons
+        let seed = self.cvars.borrow().get_value("cl_seed").unwrap();
+        if seed != 0.0 {
+            new_client_state.particles.seed(seed as u32);
+            new_client_state.rng = particle::seeded_rng(seed as u32);
+        }
+
+        let max_edicts = net::max_edicts(self.cvars.borrow().get_value("max_edicts").unwrap());
+        new_client_state.max_edicts = max_edicts;
+        new_client_state.entities.reserve(max_edicts);
 
         // check protocol version
         ensure!(
@@ -1324,21 +2327,57 @@ impl Client {
             net::PROTOCOL_VERSION,
         );
 
-        // TODO: print sign-on message to in-game console
-        println!("{}", message);
+        // the server sends the worldspawn message, which is used if the map doesn't have one
+        let mut level = LevelInfo {
+            name: String::new(),
+            title: message,
+        };
+
+        // with cl_precache_tolerant set, missing models are replaced by empty ones instead of
+        // failing the connection
+        let tolerant = self.cvars.borrow().get_value("cl_precache_tolerant").unwrap() != 0.0;
+        let mut missing = Vec::new();
 
         // parse model precache
         // TODO: validate submodel names
         for mod_name in model_precache {
             if mod_name.ends_with(".bsp") {
-                let bsp_data = self.vfs.open(&mod_name)?;
-                let (mut brush_models, _) = bsp::load(bsp_data).unwrap();
+                let (mut brush_models, _) = bsp::load_map(&self.vfs, &mod_name)?;
+
+                level.name = mod_name
+                    .trim_start_matches("maps/")
+                    .trim_end_matches(".bsp")
+                    .to_owned();
+
+                // apply the map's default fog, if any
+                let mut fog = None;
+                if let ModelKind::Brush(ref bmodel) = *brush_models[0].kind() {
+                    if let Some(worldspawn) = bmodel.bsp_data().worldspawn() {
+                        fog = worldspawn.get("fog").and_then(|f| Fog::from_str_components(f));
+
+                        if let Some(title) = worldspawn.get("message") {
+                            level.title = title.to_owned();
+                        }
+                    }
+                }
+                self.fog.set(fog.unwrap_or_default());
+
                 new_client_state.models.append(&mut brush_models);
             } else if !mod_name.starts_with("*") {
                 debug!("Loading model {}", mod_name);
-                new_client_state
-                    .models
-                    .push(Model::load(&self.vfs, mod_name)?);
+                let model = match Model::load(&self.vfs, &mod_name) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        if !tolerant {
+                            return Err(e);
+                        }
+
+                        warn!("Loading {} failed: {}", mod_name, e);
+                        missing.push(mod_name);
+                        Model::none()
+                    }
+                };
+                new_client_state.models.push(model);
             }
 
             // TODO: send keepalive message?
@@ -1355,6 +2394,7 @@ impl Client {
                     Ok(a) => a,
                     Err(e) => {
                         warn!("Loading {} failed: {}", snd_name, e);
+                        missing.push(snd_name.to_string());
                         AudioSource::load(&self.vfs, "misc/null.wav").unwrap()
                     }
                 });
@@ -1362,12 +2402,28 @@ impl Client {
             // TODO: send keepalive message?
         }
 
-        let server_info = ServerInfo {
-            max_clients: max_clients,
-            game_type: game_type,
-        };
+        new_client_state.max_players = max_clients as usize;
 
-        new_client_state.max_players = server_info.max_clients as usize;
+        let mut server_info = ServerInfo::default();
+        server_info.set("maxclients", max_clients.to_string());
+        server_info.set(
+            "deathmatch",
+            match game_type {
+                GameType::CoOp => "0",
+                GameType::Deathmatch => "1",
+            },
+        );
+        server_info.set("map", &level.name);
+        server_info.sync_cvars(&self.cvars.borrow());
+        self.server_info.replace(server_info);
+
+        // TODO: print sign-on message to in-game console
+        println!("\n{}\n", level.title);
+
+        for line in missing_assets_summary(&missing) {
+            self.console.borrow().println(line);
+        }
+        self.level.replace(level);
 
         // TODO: set up rest of client state (R_NewMap)
 
@@ -1382,6 +2438,11 @@ impl Client {
         self.signon
     }
 
+    /// Registers a callback to be invoked with the new stage whenever the signon stage changes.
+    pub fn add_signon_callback(&mut self, callback: Box<dyn Fn(SignOnStage)>) {
+        self.signon_callbacks.push(callback);
+    }
+
     pub fn entities(&self) -> Option<&[ClientEntity]> {
         match self.signon {
             SignOnStage::Done => Some(&self.state.entities),
@@ -1397,18 +2458,117 @@ impl Client {
     }
 
     pub fn view_origin(&self) -> Vector3<f32> {
-        self.state.entities[self.state.view.ent_id].origin
-            + Vector3::new(0.0, 0.0, self.state.view.view_height)
+        let origin = match self.state.predictor.origin() {
+            Some(o) => o,
+            None => self.state.entities[self.state.view.ent_id].origin,
+        };
+
+        origin + Vector3::new(0.0, 0.0, self.state.view.view_height)
+    }
+
+    /// Returns the eye position and view angles of player `ent_id`, or `None` if that player
+    /// isn't in the game.
+    ///
+    /// Only the player's model is sent, so the eyes are placed at the default height and the
+    /// view pitch is recovered from the model's.
+    pub fn player_view(&self, ent_id: usize) -> Option<(Vector3<f32>, Vector3<Deg<f32>>)> {
+        if ent_id == 0 || ent_id > self.state.max_players {
+            return None;
+        }
+
+        let ent = self.state.entities.get(ent_id)?;
+        if ent.is_pruned() {
+            return None;
+        }
+
+        Some((
+            ent.get_origin() + Vector3::new(0.0, 0.0, net::DEFAULT_VIEWHEIGHT),
+            model_to_view_angles(ent.get_angles()),
+        ))
+    }
+
+    // whether the player's movement is predicted rather than taken straight from the server
+    fn predicting(&self) -> bool {
+        if self.cvars.borrow().get_value("cl_predict").unwrap_or(0.0) == 0.0 {
+            return false;
+        }
+
+        // there's no input to predict in a demo, and swimming isn't simulated
+        match self.conn {
+            ConnectionKind::Server(_) => {
+                self.signon == SignOnStage::Done
+                    && !self.state.in_water
+                    && self.state.view.ent_id != 0
+                    && self.state.view.ent_id < self.state.entities.len()
+            }
+            ConnectionKind::Demo(_) => false,
+        }
+    }
+
+    // the level's collision hulls
+    fn world_bsp(&self) -> Option<Rc<BspData>> {
+        match *self.state.models.get(1)?.kind() {
+            ModelKind::Brush(ref bmodel) => Some(bmodel.bsp_data()),
+            _ => None,
+        }
+    }
+
+    // start the prediction over from the player's state in the latest server update
+    fn reconcile_prediction(&mut self) {
+        if !self.predicting() {
+            self.state.predictor.reset();
+            return;
+        }
+
+        let world = match self.world_bsp() {
+            Some(w) => w,
+            None => return,
+        };
+
+        let latency = match self.conn {
+            ConnectionKind::Server(ref qsock) => qsock.rtt().unwrap_or_else(Duration::zero),
+            ConnectionKind::Demo(_) => Duration::zero(),
+        };
+
+        let server = PlayerMove::new(
+            self.state.entities[self.state.view.ent_id].msg_origins[0],
+            self.state.msg_velocity[0],
+            self.state.on_ground,
+        );
+
+        let trace = |hull: HullKind, start: Vector3<f32>, end: Vector3<f32>| {
+            world.hull_trace(hull.index(), start, end).map_err(Error::from)
+        };
+        let reconciled = self
+            .state
+            .predictor
+            .reconcile(server, self.state.msg_times[0], latency, &trace);
+
+        // fall back to the server's position until the next update
+        if let Err(e) = reconciled {
+            warn!("Prediction failed: {}", e);
+            self.state.predictor.reset();
+        }
     }
 
     pub fn view_angles(&self) -> Vector3<Deg<f32>> {
         self.state.view.view_angles
     }
 
+    /// Returns the player's velocity, interpolated between the last two server messages.
+    pub fn velocity(&self) -> Vector3<f32> {
+        self.state.velocity
+    }
+
     pub fn view_ent(&self) -> usize {
         self.state.view.ent_id
     }
 
+    /// Returns the maximum number of players on the server.
+    pub fn max_players(&self) -> usize {
+        self.state.max_players
+    }
+
     pub fn time(&self) -> Duration {
         self.state.time
     }
@@ -1480,10 +2640,24 @@ impl Client {
     pub fn relink_entities(&mut self) {
         let _guard = flame::start_guard("Client::relink_entities");
         let lerp_factor = self.get_lerp_factor();
+        let nolerp_models = self.cvars.borrow().get("cl_nolerp_models").unwrap_or_default();
 
         self.state.velocity = self.state.msg_velocity[1] + lerp_factor * self.state.msg_velocity[0];
 
-        // TODO: if we're in demo playback, interpolate the view angles
+        // demos record the player's view angles, so interpolate them like entity angles
+        if let ConnectionKind::Demo(_) = self.conn {
+            let view = &mut self.state.view;
+            for i in 0..3 {
+                let mut delta = view.msg_view_angles[0][i] - view.msg_view_angles[1][i];
+                if delta > Deg(180.0) {
+                    delta = delta - Deg(360.0);
+                } else if delta < Deg(-180.0) {
+                    delta = delta + Deg(360.0);
+                }
+
+                view.view_angles[i] = view.msg_view_angles[1][i] + delta * lerp_factor;
+            }
+        }
 
         use cgmath::Angle;
         let obj_rotate = Deg(100.0 * engine::duration_to_f32(self.state.time)).normalize();
@@ -1508,12 +2682,20 @@ impl Client {
                 continue;
             }
 
-            let _old_origin = ent.origin;
+            let old_origin = ent.origin;
+
+            // some models (instant movers, teleporting objects) look wrong when interpolated
+            if !ent.force_link
+                && model_name_matches(self.state.models[ent.model_id].name(), &nolerp_models)
+            {
+                ent.force_link = true;
+            }
 
             if ent.force_link {
                 debug!("force link on entity {}", ent_id);
                 ent.origin = ent.msg_origins[0];
                 ent.angles = ent.msg_angles[0];
+                ent.frame_lerp = 1.0;
             } else {
                 let origin_delta = ent.msg_origins[0] - ent.msg_origins[1];
                 let ent_lerp_factor = if origin_delta.magnitude2() > 10_000.0 {
@@ -1530,6 +2712,9 @@ impl Client {
 
                 ent.origin = ent.msg_origins[1] + ent_lerp_factor * origin_delta;
 
+                // frames change with server messages, so they blend along with the origin
+                ent.frame_lerp = lerp_factor;
+
                 for i in 0..3 {
                     let angle_delta = ent.msg_angles[0][i] - ent.msg_angles[1][i];
                     ent.angles[i] =
@@ -1541,25 +2726,249 @@ impl Client {
                 ent.angles[1] = obj_rotate;
             }
 
-            // TODO: apply various effects (lights, particles, trails...)
+            let time = self.state.time;
+
+            // teleported entities would leave a trail across the level
+            if !ent.force_link {
+                let flags = self.state.models[ent.model_id].flags();
+                if let Some(kind) = TrailKind::from_model_flags(flags) {
+                    self.state.particles.create_trail(time, old_origin, ent.origin, kind);
+                }
+            }
+
+            let flicker = |rng: &mut XorShiftRng| (rng.gen::<u8>() & 31) as f32;
+            if ent.effects.contains(EntityEffects::MUZZLE_FLASH) {
+                // our own flash comes from the barrel of the view model
+                let origin = match self.state.muzzle_flash_origin {
+                    Some(origin) if ent_id == self.state.view.ent_id => origin,
+                    _ => {
+                        let (pitch, yaw) = (ent.angles[0], ent.angles[1]);
+                        let forward = Vector3::new(
+                            pitch.cos() * yaw.cos(),
+                            pitch.cos() * yaw.sin(),
+                            -pitch.sin(),
+                        );
+                        ent.origin + Vector3::new(0.0, 0.0, 16.0) + forward * 18.0
+                    }
+                };
+
+                self.state.dynamic_lights.spawn(DynamicLight {
+                    origin,
+                    radius: 200.0 + flicker(&mut self.state.rng),
+                    die: time + Duration::milliseconds(100),
+                    decay: 0.0,
+                    key: Some(LightKey::MuzzleFlash(ent_id)),
+                });
+            }
+
+            // glowing entities only keep their light while they keep the effect
+            let glow = if ent.effects.contains(EntityEffects::BRIGHT_LIGHT) {
+                Some(400.0)
+            } else if ent.effects.contains(EntityEffects::DIM_LIGHT) {
+                Some(200.0)
+            } else {
+                None
+            };
+            if let Some(radius) = glow {
+                self.state.dynamic_lights.spawn(DynamicLight {
+                    origin: ent.origin + Vector3::new(0.0, 0.0, 16.0),
+                    radius: radius + flicker(&mut self.state.rng),
+                    die: time + Duration::milliseconds(1),
+                    decay: 0.0,
+                    key: Some(LightKey::Glow(ent_id)),
+                });
+            }
+
+            if self.state.models[ent.model_id].has_flag(ModelFlags::ROCKET) {
+                self.state.dynamic_lights.spawn(DynamicLight {
+                    origin: ent.origin,
+                    radius: 200.0,
+                    die: time + Duration::milliseconds(10),
+                    decay: 0.0,
+                    key: Some(LightKey::Rocket(ent_id)),
+                });
+            }
+
             // TODO: update visedicts
 
             ent.force_link = false;
         }
+
+        self.prune_debris();
+    }
+
+    /// Lowers the music gain while the mixer's output is louder than `bgm_duckthreshold`, and
+    /// lets it recover over `bgm_duckrelease` seconds once gameplay quiets down.
+    fn update_music_ducking(&mut self, frame_time: Duration) {
+        let (amount, threshold, release) = {
+            let cvars = self.cvars.borrow();
+            (
+                cvars.get_value("bgm_ducking").unwrap(),
+                cvars.get_value("bgm_duckthreshold").unwrap(),
+                cvars.get_value("bgm_duckrelease").unwrap(),
+            )
+        };
+
+        let level = self.state.mixer.level();
+        self.state
+            .music_ducking
+            .update(level, frame_time, amount, threshold, release);
+    }
+
+    /// Returns the gain the music should be played at while gameplay sounds are ducking it.
+    pub fn music_gain(&self) -> f32 {
+        self.state.music_ducking.gain()
+    }
+
+    fn update_music_volume(&mut self) {
+        let bgmvolume = self.cvars.borrow().get_value("bgmvolume").unwrap().max(0.0).min(1.0);
+        let gain = self.music_gain();
+        self.music.borrow_mut().set_volume(bgmvolume * gain);
+    }
+
+    /// Spatializes sounds for the current view.
+    fn update_listener(&mut self) {
+        let master_volume = self.cvars.borrow().get_value("volume").unwrap().max(0.0).min(1.0);
+        let listener = if self.state.view.ent_id < self.state.entities.len() {
+            Listener::from_angles(self.view_origin(), self.view_angles())
+        } else {
+            Listener::default()
+        };
+
+        self.state
+            .mixer
+            .update_listener(listener, self.state.view.ent_id, master_volume);
+        for static_sound in self.state.static_sounds.iter() {
+            static_sound.update(&listener, master_volume);
+        }
+    }
+
+    /// Applies the doppler effect to sounds playing on moving entities.
+    fn update_sound_pitch(&mut self) {
+        let scale = self.cvars.borrow().get_value("s_doppler").unwrap();
+
+        // entity velocities are estimated from their last two updates
+        let interval = engine::duration_to_f32(self.state.msg_times[0] - self.state.msg_times[1]);
+        let listener_origin = self.view_origin();
+        let listener_velocity = self.state.velocity;
+        let view_ent_id = self.state.view.ent_id;
+        let entities = &self.state.entities;
+
+        self.state.mixer.update_pitch(|ent_id| {
+            // the world doesn't move, and the player moves with the listener
+            if scale <= 0.0 || interval <= 0.0 || ent_id == 0 || ent_id == view_ent_id {
+                return 1.0;
+            }
+
+            let ent = match entities.get(ent_id) {
+                Some(e) if e.model_id != 0 => e,
+                _ => return 1.0,
+            };
+
+            let velocity = (ent.msg_origins[0] - ent.msg_origins[1]) / interval;
+            sound::doppler_factor(listener_origin, listener_velocity, ent.origin, velocity, scale)
+        });
+    }
+
+    /// Hides the oldest corpses and gibs beyond `cl_maxcorpses` and `cl_maxgibs`.
+    fn prune_debris(&mut self) {
+        let max_corpses = self.cvars.borrow().get_value("cl_maxcorpses").unwrap();
+        let max_gibs = self.cvars.borrow().get_value("cl_maxgibs").unwrap();
+
+        let models = &self.state.models;
+        for (ent_id, ent) in self.state.entities.iter_mut().enumerate().skip(1) {
+            let kind = if ent.model_id == 0 {
+                None
+            } else {
+                DebrisKind::classify(&models[ent.model_id], ent.frame_id)
+            };
+
+            match kind {
+                Some(DebrisKind::Corpse) => self.state.corpses.track(ent_id),
+                _ => self.state.corpses.untrack(ent_id),
+            }
+
+            match kind {
+                Some(DebrisKind::Gib) => self.state.gibs.track(ent_id),
+                _ => self.state.gibs.untrack(ent_id),
+            }
+
+            ent.pruned = false;
+        }
+
+        let corpses = self.state.corpses.excess(max_corpses.max(0.0) as usize);
+        let gibs = self.state.gibs.excess(max_gibs.max(0.0) as usize);
+        for ent_id in corpses.chain(gibs) {
+            self.state.entities[*ent_id].pruned = true;
+        }
     }
 
     pub fn frame(&mut self, frame_time: Duration) -> Result<(), Error> {
         self.update_time();
         self.state.time = self.state.time + frame_time;
+        if let Err(e) = self.update_recording() {
+            self.abort_recording(e);
+        }
+
         self.send()?;
         self.parse_server_msg()?;
+        self.reconcile_prediction();
         self.relink_entities();
+        self.update_sound_pitch();
+        self.update_listener();
+        self.update_music_ducking(frame_time);
+        self.update_music_volume();
         // TODO: CL_UpdateTEnts
 
+        let particle_limit = self.cvars.borrow().get_value("cl_particlelimit").unwrap();
+        self.state.particles.set_limit(particle_limit.max(0.0) as usize);
+        self.state.particles.update(self.state.time, frame_time);
+        self.state.dynamic_lights.update(self.state.time, frame_time);
+
         Ok(())
     }
 
+    pub fn fog(&self) -> Fog {
+        self.fog.get()
+    }
+
+    /// Returns the name and title of the current level.
+    pub fn level(&self) -> LevelInfo {
+        self.level.borrow().clone()
+    }
+
+    /// Returns the settings of the server we're connected to.
+    pub fn server_info(&self) -> ServerInfo {
+        self.server_info.borrow().clone()
+    }
+
     pub fn register_cmds(&self, cmds: &mut CmdRegistry) {
+        let music = self.music.clone();
+        cmds.insert_or_replace(
+            "cd",
+            Box::new(move |args| {
+                let usage = "usage: cd <play | loop> <track> | stop | pause | resume";
+                let looping = match args.get(0) {
+                    Some(&"play") => false,
+                    Some(&"loop") => true,
+                    Some(&"stop") => return music.borrow_mut().stop(),
+                    Some(&"pause") => return music.borrow().pause(),
+                    Some(&"resume") => return music.borrow().resume(),
+                    _ => return println!("{}", usage),
+                };
+
+                let track = match args.get(1).map(|t| t.parse::<u32>()) {
+                    Some(Ok(t)) => t,
+                    _ => return println!("{}", usage),
+                };
+
+                if let Err(e) = music.borrow_mut().play(track, looping) {
+                    println!("Couldn't play CD track {}: {}", track, e);
+                }
+            }),
+        )
+        .unwrap();
+
         let bonus_cshift = self.state.color_shifts[ColorShiftCode::Bonus as usize].clone();
         cmds.insert_or_replace(
             "bf",
@@ -1580,18 +2989,9 @@ impl Client {
                 match args.len() {
                     // exec (filename): execute a script file
                     1 => {
-                        let mut script_file = match vfs.open(args[0]) {
-                            Ok(s) => s,
-                            Err(e) => {
-                                println!("Couldn't exec {}: {:?}", args[0], e);
-                                return;
-                            }
-                        };
-
-                        let mut script = String::new();
-                        script_file.read_to_string(&mut script).unwrap();
-
-                        console.borrow().stuff_text(script);
+                        if let Err(e) = console.borrow().exec(&vfs, args[0]) {
+                            println!("Couldn't exec {}: {}", args[0], e);
+                        }
                     }
 
                     _ => println!("exec (filename): execute a script file"),
@@ -1599,10 +2999,183 @@ impl Client {
             }),
         )
         .unwrap();
+
+        let vfs = self.vfs.clone();
+        let console = self.console.clone();
+        cmds.insert_or_replace(
+            "condump",
+            Box::new(move |args| {
+                let path = match args.len() {
+                    0 => "condump.txt",
+                    _ => args[0],
+                };
+
+                let console = console.borrow();
+                match console.dump(&vfs, path) {
+                    Ok(_) => console.println(format!("Dumped console text to {}", path)),
+                    Err(e) => console.println(format!(
+                        "{}Couldn't dump console text to {}: {}",
+                        ConsoleColor::Red.code(),
+                        path,
+                        e
+                    )),
+                }
+            }),
+        )
+        .unwrap();
+
+        let fog = self.fog.clone();
+        cmds.insert_or_replace(
+            "fog",
+            Box::new(move |args| match args.len() {
+                0 => {
+                    let f = fog.get();
+                    println!(
+                        "\"fog\" is \"{} {} {} {}\"",
+                        f.density, f.color[0], f.color[1], f.color[2]
+                    );
+                }
+
+                1 | 4 => match Fog::from_str_components(args.join(" ")) {
+                    Some(f) => fog.set(f),
+                    None => println!("fog: invalid value"),
+                },
+
+                _ => println!("fog (density) (red green blue): set distance fog"),
+            }),
+        )
+        .unwrap();
+
+        let server_info = self.server_info.clone();
+        let cvars = self.cvars.clone();
+        cmds.insert_or_replace(
+            "fullserverinfo",
+            Box::new(move |args| match args.len() {
+                1 => {
+                    let mut server_info = server_info.borrow_mut();
+                    server_info.merge(ServerInfo::parse(args[0]));
+                    server_info.sync_cvars(&cvars.borrow());
+                }
+                _ => println!("fullserverinfo <info>: set server settings (sent by the server)"),
+            }),
+        )
+        .unwrap();
+
+        let server_info = self.server_info.clone();
+        cmds.insert_or_replace(
+            "serverinfo",
+            Box::new(move |_| {
+                println!("Server info settings:");
+                for (key, value) in server_info.borrow().iter() {
+                    println!("{:<20}{}", key, value);
+                }
+            }),
+        )
+        .unwrap();
+
+        let record_requested = self.record_requested.clone();
+        cmds.insert_or_replace(
+            "record",
+            Box::new(move |args| {
+                let track_override = match args.len() {
+                    1 => None,
+                    2 => match args[1].parse::<u32>() {
+                        Ok(t) => Some(t),
+                        Err(_) => {
+                            println!("Invalid CD track: {}", args[1]);
+                            return;
+                        }
+                    },
+                    _ => {
+                        println!("record <demoname> [cd track]");
+                        return;
+                    }
+                };
+
+                record_requested.replace(Some((args[0].to_owned(), track_override)));
+            }),
+        )
+        .unwrap();
+
+        let stop_requested = self.stop_requested.clone();
+        cmds.insert_or_replace("stop", Box::new(move |_| stop_requested.set(true)))
+            .unwrap();
+
+        for &name in ["say", "say_team"].iter() {
+            let forward = self.forward.clone();
+            cmds.insert_or_replace(
+                name,
+                Box::new(move |args| match args.len() {
+                    0 => println!("{} <message>: send a chat message", name),
+                    _ => forward
+                        .borrow_mut()
+                        .push(format!("{} \"{}\"", name, args.join(" "))),
+                }),
+            )
+            .unwrap();
+        }
+
+        let level = self.level.clone();
+        cmds.insert_or_replace(
+            "status",
+            Box::new(move |_| {
+                let level = level.borrow();
+                println!("map:     {}", level.name);
+                println!("title:   {}", level.title);
+            }),
+        )
+        .unwrap();
+    }
+
+    pub fn spawn_temp_entity(&mut self, temp_entity: &TempEntity) {
+        let time = self.state.time;
+        let particles = &mut self.state.particles;
+        match *temp_entity {
+            TempEntity::Spike(ref p) => {
+                particles.create_effect(time, p.origin(), Vector3::zero(), 0, 10)
+            }
+            TempEntity::SuperSpike(ref p) | TempEntity::Gunshot(ref p) => {
+                particles.create_effect(time, p.origin(), Vector3::zero(), 0, 20)
+            }
+            TempEntity::WizSpike(ref p) => {
+                particles.create_effect(time, p.origin(), Vector3::zero(), 20, 30)
+            }
+            TempEntity::KnightSpike(ref p) => {
+                particles.create_effect(time, p.origin(), Vector3::zero(), 226, 20)
+            }
+            TempEntity::Explosion(ref p) => {
+                particles.create_explosion(time, p.origin());
+                self.state.dynamic_lights.spawn(DynamicLight {
+                    origin: p.origin(),
+                    radius: 350.0,
+                    die: time + Duration::milliseconds(500),
+                    decay: 300.0,
+                    key: None,
+                });
+            }
+
+            TempEntity::LavaSplash(ref p) => particles.create_lava_splash(time, p.origin()),
+            TempEntity::Teleport(ref p) => particles.create_teleport_splash(time, p.origin()),
+
+            // TODO: beams, tarbaby explosions
+            _ => warn!("Temporary entity not yet implemented: {:?}", temp_entity),
+        }
     }
 
-    pub fn spawn_temp_entity(&self, _temp_entity: &TempEntity) {
-        warn!("Temporary entities not yet implemented!");
+    /// Returns the particles currently alive in the level.
+    pub fn particles(&self) -> &Particles {
+        &self.state.particles
+    }
+
+    /// Returns the dynamic lights currently alive in the level.
+    pub fn dynamic_lights(&self) -> &DynamicLights {
+        &self.state.dynamic_lights
+    }
+
+    /// Sets where the view model's muzzle flash was drawn, so that the flash's light comes from
+    /// the barrel instead of the player's origin.
+    pub fn set_muzzle_flash_origin(&mut self, origin: Option<Vector3<f32>>) {
+        self.state.muzzle_flash_origin = origin;
     }
 
     pub fn items(&self) -> ItemFlags {
@@ -1613,10 +3186,42 @@ impl Client {
         &self.state.item_get_time
     }
 
+    /// Returns the strength of the hitmarker flash, from 1 immediately after a hit to 0 once the
+    /// flash has decayed.
+    pub fn hitmarker_intensity(&self) -> f32 {
+        match self.state.hit_time {
+            Some(t) => {
+                let elapsed_ms = (self.state.time - t).num_milliseconds();
+                if elapsed_ms < 0 || elapsed_ms >= HITMARKER_DURATION_MS {
+                    0.0
+                } else {
+                    1.0 - elapsed_ms as f32 / HITMARKER_DURATION_MS as f32
+                }
+            }
+
+            None => 0.0,
+        }
+    }
+
     pub fn weapon(&self) -> i32 {
         self.state.stats[ClientStat::Weapon as usize]
     }
 
+    /// Returns the frame of the view model's animation to draw.
+    pub fn weapon_frame(&self) -> usize {
+        self.state.stats[ClientStat::WeaponFrame as usize].max(0) as usize
+    }
+
+    /// Returns the view model frame before the last update, to blend from with `r_lerpmodels`.
+    pub fn prev_weapon_frame(&self) -> usize {
+        self.state.prev_weapon_frame
+    }
+
+    /// Returns how far to lower the view model while a newly selected weapon is raised.
+    pub fn view_model_drop(&self) -> f32 {
+        self.state.weapon_switch.view_model_drop(self.state.time)
+    }
+
     pub fn active_weapon(&self) -> i32 {
         self.state.stats[ClientStat::ActiveWeapon as usize]
     }
@@ -1653,7 +3258,182 @@ impl Client {
         Ok(values)
     }
 
-    pub fn disconnect(&self) {
-        unimplemented!();
+    /// Tells the server we're leaving and resets the signon stage.
+    ///
+    /// Like the original client, the disconnect message is sent unreliably several times in case
+    /// some are lost; the server frees our slot as soon as one arrives.
+    pub fn disconnect(&mut self) -> Result<(), Error> {
+        self.music.borrow_mut().stop();
+        self.downloads = None;
+
+        if self.signon == SignOnStage::Not {
+            return Ok(());
+        }
+
+        if let ConnectionKind::Server(ref mut qsock) = self.conn {
+            let mut msg = Vec::new();
+            ClientCmd::Disconnect.serialize(&mut msg)?;
+            for _ in 0..DISCONNECT_SEND_COUNT {
+                qsock.send_msg_unreliable(&msg)?;
+            }
+        }
+
+        self.compose.clear();
+        self.signon_msgs.clear();
+
+        // the recording would be useless without the next level's signon
+        if let Some(recording) = self.recording.take() {
+            match recording.finish(self.state.view.view_angles) {
+                Ok(_) => println!("Completed demo"),
+                Err(e) => println!("Demo recording failed: {}", e),
+            }
+        }
+
+        self.handle_signon(SignOnStage::Not)?;
+
+        Ok(())
+    }
+}
+
+/// Returns the console lines reporting precached assets that couldn't be loaded.
+fn missing_assets_summary(missing: &[String]) -> Vec<String> {
+    if missing.is_empty() {
+        return Vec::new();
+    }
+
+    let mut lines = vec![format!(
+        "{}{} precached asset(s) missing:",
+        ConsoleColor::Yellow.code(),
+        missing.len()
+    )];
+    lines.extend(missing.iter().map(|name| format!("    {}", name)));
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_model_to_view_angles() {
+        let view = model_to_view_angles(Vector3::new(Deg(-10.0), Deg(90.0), Deg(0.0)));
+        assert_eq!(view, Vector3::new(Deg(30.0), Deg(90.0), Deg(0.0)));
+    }
+
+    #[test]
+    fn test_segment_box_fraction() {
+        let min = Vector3::new(-16.0, -16.0, -24.0);
+        let max = Vector3::new(16.0, 16.0, 32.0);
+
+        // straight through the middle, entering at x = -16
+        let f = segment_box_fraction(
+            Vector3::new(-100.0, 0.0, 0.0),
+            Vector3::new(100.0, 0.0, 0.0),
+            min,
+            max,
+        )
+        .unwrap();
+        assert!((f - 0.42).abs() < 0.001, "f = {}", f);
+
+        // passing overhead
+        assert!(segment_box_fraction(
+            Vector3::new(-100.0, 0.0, 40.0),
+            Vector3::new(100.0, 0.0, 40.0),
+            min,
+            max,
+        )
+        .is_none());
+
+        // stopping short
+        assert!(segment_box_fraction(
+            Vector3::new(-100.0, 0.0, 0.0),
+            Vector3::new(-50.0, 0.0, 0.0),
+            min,
+            max,
+        )
+        .is_none());
+
+        // starting inside counts as an immediate hit
+        assert_eq!(
+            segment_box_fraction(Vector3::zero(), Vector3::new(100.0, 0.0, 0.0), min, max),
+            Some(0.0)
+        );
+    }
+
+    #[test]
+    fn test_missing_assets_summary() {
+        assert!(missing_assets_summary(&[]).is_empty());
+
+        let missing = vec!["progs/foo.mdl".to_owned(), "foo/bar.wav".to_owned()];
+        assert_eq!(
+            missing_assets_summary(&missing),
+            vec![
+                "^32 precached asset(s) missing:".to_owned(),
+                "    progs/foo.mdl".to_owned(),
+                "    foo/bar.wav".to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_model_name_matches() {
+        let patterns = "progs/teleport.mdl, maps/b_*  progs/s_*";
+        assert!(model_name_matches("progs/teleport.mdl", patterns));
+        assert!(model_name_matches("maps/b_bh10.bsp", patterns));
+        assert!(model_name_matches("progs/s_light.spr", patterns));
+        assert!(!model_name_matches("progs/teleport.mdl2", patterns));
+        assert!(!model_name_matches("progs/player.mdl", patterns));
+
+        // an empty list matches nothing
+        assert!(!model_name_matches("progs/player.mdl", ""));
+        assert!(!model_name_matches("", " , "));
+    }
+
+    #[test]
+    fn test_server_info_parse() {
+        let info =
+            ServerInfo::parse("\\deathmatch\\1\\timelimit\\20\\hostname\\my server\\dangling");
+        assert_eq!(info.get("deathmatch"), Some("1"));
+        assert_eq!(info.get("timelimit"), Some("20"));
+        assert_eq!(info.get("hostname"), Some("my server"));
+        assert_eq!(info.get("dangling"), None);
+        assert_eq!(info.iter().count(), 3);
+    }
+
+    #[test]
+    fn test_server_info_merge() {
+        let mut info = ServerInfo::parse("\\deathmatch\\0\\maxclients\\1");
+        info.merge(ServerInfo::parse("\\deathmatch\\1\\teamplay\\2"));
+        assert_eq!(info.get("deathmatch"), Some("1"));
+        assert_eq!(info.get("maxclients"), Some("1"));
+        assert_eq!(info.get("teamplay"), Some("2"));
+    }
+
+    #[test]
+    fn test_is_final_death_frame() {
+        assert!(!is_final_death_frame("stand1", Some("stand2")));
+        assert!(!is_final_death_frame("death1", Some("death2")));
+        assert!(is_final_death_frame("death9", Some("deathb1")));
+        assert!(is_final_death_frame("deathb8", Some("pain1")));
+        assert!(is_final_death_frame("death4", None));
+    }
+
+    #[test]
+    fn test_entity_limit_prunes_oldest() {
+        let mut limit = EntityLimit::default();
+        limit.track(5);
+        limit.track(2);
+        limit.track(9);
+
+        // tracking an entity twice doesn't change its age
+        limit.track(5);
+
+        assert_eq!(limit.excess(2).cloned().collect::<Vec<_>>(), vec![5]);
+        assert_eq!(limit.excess(1).cloned().collect::<Vec<_>>(), vec![5, 2]);
+        assert_eq!(limit.excess(3).count(), 0);
+        assert_eq!(limit.excess(0).count(), 0);
+
+        limit.untrack(5);
+        assert_eq!(limit.excess(1).cloned().collect::<Vec<_>>(), vec![2]);
     }
 }
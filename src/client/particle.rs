@@ -0,0 +1,667 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::collections::VecDeque;
+
+use common::engine;
+use common::model::ModelFlags;
+
+use cgmath::{InnerSpace, Vector3};
+use chrono::Duration;
+use rand::{self, Rng, SeedableRng, XorShiftRng};
+
+/// The absolute maximum number of live particles, regardless of `cl_particlelimit`.
+pub const MAX_PARTICLES: usize = 16384;
+
+/// The default value of `cl_particlelimit`.
+pub const DEFAULT_PARTICLE_LIMIT: usize = 4096;
+
+// acceleration due to gravity for falling particles, in units per second squared
+// (sv_gravity * 0.05 in the original client)
+const PARTICLE_GRAVITY: f32 = 40.0;
+
+// color ramps for explosion particles, see
+// https://github.com/id-Software/Quake/blob/master/WinQuake/r_part.c#L27
+const EXPLOSION_RAMP_1: [u8; 8] = [0x6f, 0x6d, 0x6b, 0x69, 0x67, 0x65, 0x63, 0x61];
+const EXPLOSION_RAMP_2: [u8; 8] = [0x6f, 0x6e, 0x6d, 0x6c, 0x6b, 0x6a, 0x68, 0x66];
+
+// color ramp for the fire and smoke of rocket and grenade trails
+const FIRE_RAMP: [u8; 6] = [0x6d, 0x6b, 0x06, 0x05, 0x04, 0x03];
+
+// ramp steps per second
+const EXPLOSION_RAMP_1_SPEED: f32 = 10.0;
+const EXPLOSION_RAMP_2_SPEED: f32 = 15.0;
+const FIRE_RAMP_SPEED: f32 = 5.0;
+
+// palette colors of blood trails, lava and teleporter sparks, see
+// https://github.com/id-Software/Quake/blob/master/WinQuake/r_part.c
+const BLOOD_TRAIL_COLOR: u8 = 67;
+const LAVA_COLOR: u8 = 224;
+const TELEPORT_COLOR: u8 = 7;
+
+// the server sends a particle count of 255 to request an explosion
+const EXPLOSION_PARTICLE_COUNT: u8 = 255;
+
+// mixed into fixed seeds so that no seed produces the all-zero state XorShiftRng rejects
+const SEED_SALT: [u32; 4] = [0x9E37_79B9, 0x243F_6A88, 0x85A3_08D3, 0x1319_8A2E];
+
+/// Returns a random number generator that always produces the same sequence for `seed`.
+pub fn seeded_rng(seed: u32) -> XorShiftRng {
+    XorShiftRng::from_seed([
+        seed ^ SEED_SALT[0],
+        seed ^ SEED_SALT[1],
+        seed ^ SEED_SALT[2],
+        seed ^ SEED_SALT[3],
+    ])
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ParticleKind {
+    /// The particle stays in place.
+    Static,
+
+    /// The particle falls under gravity.
+    Grav,
+
+    /// The particle accelerates outward and cycles through the first explosion color ramp.
+    Explode,
+
+    /// The particle slows down and cycles through the second explosion color ramp.
+    Explode2,
+
+    /// The particle rises and cycles through the fire color ramp, fading to smoke.
+    Fire,
+}
+
+/// The trails of particles left behind by moving models.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TrailKind {
+    /// Fire and smoke behind rockets.
+    Rocket,
+
+    /// Smoke behind grenades.
+    Smoke,
+
+    /// Blood falling from gibs.
+    Blood,
+
+    /// A thinner trail of blood from zombie gibs.
+    SlightBlood,
+
+    /// The green tracer behind scrag spit.
+    Tracer,
+
+    /// The orange tracer behind death knight spikes.
+    Tracer2,
+
+    /// The purple trail behind vore balls.
+    Voor,
+}
+
+impl TrailKind {
+    /// Returns the trail left by a model with `flags`, if it leaves one.
+    pub fn from_model_flags(flags: ModelFlags) -> Option<TrailKind> {
+        if flags.contains(ModelFlags::ROCKET) {
+            Some(TrailKind::Rocket)
+        } else if flags.contains(ModelFlags::GRENADE) {
+            Some(TrailKind::Smoke)
+        } else if flags.contains(ModelFlags::GIB) {
+            Some(TrailKind::Blood)
+        } else if flags.contains(ModelFlags::ZOMGIB) {
+            Some(TrailKind::SlightBlood)
+        } else if flags.contains(ModelFlags::TRACER) {
+            Some(TrailKind::Tracer)
+        } else if flags.contains(ModelFlags::TRACER2) {
+            Some(TrailKind::Tracer2)
+        } else if flags.contains(ModelFlags::TRACER3) {
+            Some(TrailKind::Voor)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Particle {
+    kind: ParticleKind,
+    origin: Vector3<f32>,
+    velocity: Vector3<f32>,
+    color: u8,
+    ramp: f32,
+    die_time: Duration,
+}
+
+impl Particle {
+    pub fn new(
+        kind: ParticleKind,
+        origin: Vector3<f32>,
+        velocity: Vector3<f32>,
+        color: u8,
+        die_time: Duration,
+    ) -> Particle {
+        Particle {
+            kind,
+            origin,
+            velocity,
+            color,
+            ramp: 0.0,
+            die_time,
+        }
+    }
+
+    pub fn origin(&self) -> Vector3<f32> {
+        self.origin
+    }
+
+    /// Returns the palette index of this particle's current color.
+    pub fn color(&self) -> u8 {
+        self.color
+    }
+
+    fn update(&mut self, frame_time: f32) -> bool {
+        self.origin += self.velocity * frame_time;
+
+        match self.kind {
+            ParticleKind::Static => (),
+
+            ParticleKind::Grav => self.velocity.z -= PARTICLE_GRAVITY * frame_time,
+
+            ParticleKind::Explode => {
+                self.ramp += EXPLOSION_RAMP_1_SPEED * frame_time;
+                match EXPLOSION_RAMP_1.get(self.ramp as usize) {
+                    Some(c) => self.color = *c,
+                    None => return false,
+                }
+                self.velocity += self.velocity * 4.0 * frame_time;
+                self.velocity.z -= PARTICLE_GRAVITY * frame_time;
+            }
+
+            ParticleKind::Explode2 => {
+                self.ramp += EXPLOSION_RAMP_2_SPEED * frame_time;
+                match EXPLOSION_RAMP_2.get(self.ramp as usize) {
+                    Some(c) => self.color = *c,
+                    None => return false,
+                }
+                self.velocity -= self.velocity * frame_time;
+                self.velocity.z -= PARTICLE_GRAVITY * frame_time;
+            }
+
+            ParticleKind::Fire => {
+                self.ramp += FIRE_RAMP_SPEED * frame_time;
+                match FIRE_RAMP.get(self.ramp as usize) {
+                    Some(c) => self.color = *c,
+                    None => return false,
+                }
+                self.velocity.z += PARTICLE_GRAVITY * frame_time;
+            }
+        }
+
+        true
+    }
+}
+
+/// A bounded pool of live particles.
+///
+/// Particles are stored in the order they were spawned. When the pool is full, spawning a new
+/// particle recycles the oldest one.
+///
+/// Effects draw from the pool's own random number generator, which is seeded from the system
+/// unless `seed` is called.
+pub struct Particles {
+    particles: VecDeque<Particle>,
+    limit: usize,
+    rng: XorShiftRng,
+
+    // alternates the color and direction of tracer particles
+    tracer_count: usize,
+}
+
+impl Particles {
+    /// Creates a new particle pool holding at most `limit` particles.
+    ///
+    /// `limit` is clamped to `MAX_PARTICLES`.
+    pub fn with_limit(limit: usize) -> Particles {
+        let limit = limit.min(MAX_PARTICLES);
+        Particles {
+            particles: VecDeque::with_capacity(limit),
+            limit,
+            rng: rand::weak_rng(),
+            tracer_count: 0,
+        }
+    }
+
+    /// Reseeds the effect random number generator so that the same sequence of effects produces
+    /// the same particles.
+    pub fn seed(&mut self, seed: u32) {
+        self.rng = seeded_rng(seed);
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Sets the maximum number of live particles, discarding the oldest particles if necessary.
+    ///
+    /// `limit` is clamped to `MAX_PARTICLES`.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit.min(MAX_PARTICLES);
+        while self.particles.len() > self.limit {
+            self.particles.pop_front();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.particles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.particles.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Particle> {
+        self.particles.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.particles.clear();
+    }
+
+    /// Adds a particle to the pool, recycling the oldest particle if the pool is full.
+    pub fn insert(&mut self, particle: Particle) {
+        if self.limit == 0 {
+            return;
+        }
+
+        if self.particles.len() >= self.limit {
+            self.particles.pop_front();
+        }
+
+        self.particles.push_back(particle);
+    }
+
+    /// Advances all particles by `frame_time` and removes those that have died by `time`.
+    pub fn update(&mut self, time: Duration, frame_time: Duration) {
+        let frame_time = engine::duration_to_f32(frame_time);
+        self.particles.retain(|p| p.die_time > time);
+        for p in self.particles.iter_mut() {
+            if !p.update(frame_time) {
+                p.die_time = time;
+            }
+        }
+    }
+
+    /// Spawns a burst of particles, as sent by the server in a particle message.
+    ///
+    /// A `count` of 255 produces an explosion instead.
+    pub fn create_effect(
+        &mut self,
+        time: Duration,
+        origin: Vector3<f32>,
+        direction: Vector3<f32>,
+        color: u8,
+        count: u8,
+    ) {
+        if count == EXPLOSION_PARTICLE_COUNT {
+            self.create_explosion(time, origin);
+            return;
+        }
+
+        for _ in 0..count {
+            let lifetime = Duration::milliseconds(self.rng.gen_range(0, 500));
+            let jitter = Vector3::new(
+                self.rng.gen_range(-8.0, 8.0),
+                self.rng.gen_range(-8.0, 8.0),
+                self.rng.gen_range(-8.0, 8.0),
+            );
+            let color = (color & !7) + self.rng.gen_range(0, 8);
+            self.insert(Particle::new(
+                ParticleKind::Grav,
+                origin + jitter,
+                direction * 15.0,
+                color,
+                time + lifetime,
+            ));
+        }
+    }
+
+    /// Spawns a rocket or grenade explosion at `origin`.
+    pub fn create_explosion(&mut self, time: Duration, origin: Vector3<f32>) {
+        for i in 0..1024 {
+            let kind = if i & 1 == 0 {
+                ParticleKind::Explode
+            } else {
+                ParticleKind::Explode2
+            };
+
+            let jitter = Vector3::new(
+                self.rng.gen_range(-16.0, 16.0),
+                self.rng.gen_range(-16.0, 16.0),
+                self.rng.gen_range(-16.0, 16.0),
+            );
+            let velocity = Vector3::new(
+                self.rng.gen_range(-256.0, 256.0),
+                self.rng.gen_range(-256.0, 256.0),
+                self.rng.gen_range(-256.0, 256.0),
+            );
+
+            let mut particle = Particle::new(
+                kind,
+                origin + jitter,
+                velocity,
+                EXPLOSION_RAMP_1[0],
+                time + Duration::seconds(5),
+            );
+            particle.ramp = self.rng.gen_range(0.0, 4.0);
+            self.insert(particle);
+        }
+    }
+
+    /// Spawns the fountain of lava sparks thrown up around `origin` when Chthon rises.
+    pub fn create_lava_splash(&mut self, time: Duration, origin: Vector3<f32>) {
+        for i in -16..16 {
+            for j in -16..16 {
+                let offset = Vector3::new(
+                    (j * 8 + self.rng.gen_range(0, 8)) as f32,
+                    (i * 8 + self.rng.gen_range(0, 8)) as f32,
+                    self.rng.gen_range(0, 64) as f32,
+                );
+                let direction = Vector3::new(offset.x, offset.y, 256.0).normalize();
+                let speed = self.rng.gen_range(50.0, 114.0);
+                let lifetime = Duration::milliseconds(2000 + self.rng.gen_range(0, 32) * 20);
+                let color = LAVA_COLOR + self.rng.gen_range(0, 8);
+
+                self.insert(Particle::new(
+                    ParticleKind::Grav,
+                    origin + offset,
+                    direction * speed,
+                    color,
+                    time + lifetime,
+                ));
+            }
+        }
+    }
+
+    /// Spawns the burst of sparks left where a player or monster teleported to `origin`.
+    pub fn create_teleport_splash(&mut self, time: Duration, origin: Vector3<f32>) {
+        for i in (-16..16).step_by(4) {
+            for j in (-16..16).step_by(4) {
+                for k in (-24..32).step_by(4) {
+                    let offset = Vector3::new(
+                        (i + self.rng.gen_range(0, 4)) as f32,
+                        (j + self.rng.gen_range(0, 4)) as f32,
+                        (k + self.rng.gen_range(0, 4)) as f32,
+                    );
+                    // the spark at the center has no direction to fly in
+                    let direction = Vector3::new(j as f32, i as f32, k as f32);
+                    let direction = if direction.magnitude2() > 0.0 {
+                        direction.normalize()
+                    } else {
+                        direction
+                    };
+                    let speed = self.rng.gen_range(50.0, 114.0);
+                    let lifetime = Duration::milliseconds(200 + self.rng.gen_range(0, 8) * 20);
+                    let color = TELEPORT_COLOR + self.rng.gen_range(0, 8);
+
+                    self.insert(Particle::new(
+                        ParticleKind::Grav,
+                        origin + offset,
+                        direction * speed,
+                        color,
+                        time + lifetime,
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Spawns a trail of particles along the path a model took from `start` to `end`.
+    pub fn create_trail(
+        &mut self,
+        time: Duration,
+        start: Vector3<f32>,
+        end: Vector3<f32>,
+        kind: TrailKind,
+    ) {
+        let path = end - start;
+        let length = path.magnitude();
+        if length == 0.0 {
+            return;
+        }
+        let direction = path / length;
+
+        // zombie gibs bleed less than other gibs
+        let spacing = match kind {
+            TrailKind::SlightBlood => 6.0,
+            _ => 3.0,
+        };
+
+        let mut dist = 0.0;
+        while dist < length {
+            let point = start + direction * dist;
+            let particle = match kind {
+                TrailKind::Rocket | TrailKind::Smoke => {
+                    // smoke starts further along the ramp, so it's darker
+                    let start_ramp = if kind == TrailKind::Smoke { 2 } else { 0 };
+                    let ramp = start_ramp + self.rng.gen_range(0, 4);
+                    let mut particle = Particle::new(
+                        ParticleKind::Fire,
+                        point + self.jitter(3),
+                        Vector3::new(0.0, 0.0, 0.0),
+                        FIRE_RAMP[ramp],
+                        time + Duration::seconds(2),
+                    );
+                    particle.ramp = ramp as f32;
+                    particle
+                }
+
+                TrailKind::Blood | TrailKind::SlightBlood => Particle::new(
+                    ParticleKind::Grav,
+                    point + self.jitter(3),
+                    Vector3::new(0.0, 0.0, 0.0),
+                    BLOOD_TRAIL_COLOR + self.rng.gen_range(0, 4),
+                    time + Duration::seconds(2),
+                ),
+
+                TrailKind::Tracer | TrailKind::Tracer2 => {
+                    let base_color = if kind == TrailKind::Tracer { 52 } else { 230 };
+                    let color = base_color + ((self.tracer_count & 4) << 1) as u8;
+
+                    // tracers spread out sideways, alternating left and right
+                    let side = if self.tracer_count & 1 == 1 { 30.0 } else { -30.0 };
+                    self.tracer_count = self.tracer_count.wrapping_add(1);
+
+                    Particle::new(
+                        ParticleKind::Static,
+                        point,
+                        Vector3::new(direction.y, -direction.x, 0.0) * side,
+                        color,
+                        time + Duration::milliseconds(500),
+                    )
+                }
+
+                TrailKind::Voor => Particle::new(
+                    ParticleKind::Static,
+                    point + self.jitter(8),
+                    Vector3::new(0.0, 0.0, 0.0),
+                    9 * 16 + 8 + self.rng.gen_range(0, 4),
+                    time + Duration::milliseconds(300),
+                ),
+            };
+
+            self.insert(particle);
+            dist += spacing;
+        }
+    }
+
+    // returns a random offset of up to `extent` units along each axis
+    fn jitter(&mut self, extent: i32) -> Vector3<f32> {
+        Vector3::new(
+            self.rng.gen_range(-extent, extent) as f32,
+            self.rng.gen_range(-extent, extent) as f32,
+            self.rng.gen_range(-extent, extent) as f32,
+        )
+    }
+}
+
+impl Default for Particles {
+    fn default() -> Particles {
+        Particles::with_limit(DEFAULT_PARTICLE_LIMIT)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cgmath::Zero;
+
+    fn particle_at(x: f32, die_time: Duration) -> Particle {
+        Particle::new(
+            ParticleKind::Static,
+            Vector3::new(x, 0.0, 0.0),
+            Vector3::zero(),
+            0,
+            die_time,
+        )
+    }
+
+    #[test]
+    fn test_particles_recycle_oldest() {
+        let mut particles = Particles::with_limit(3);
+        for i in 0..5 {
+            particles.insert(particle_at(i as f32, Duration::seconds(1)));
+        }
+
+        assert_eq!(particles.len(), 3);
+        let xs: Vec<f32> = particles.iter().map(|p| p.origin().x).collect();
+        assert_eq!(xs, vec![2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn test_particles_set_limit() {
+        let mut particles = Particles::with_limit(4);
+        for i in 0..4 {
+            particles.insert(particle_at(i as f32, Duration::seconds(1)));
+        }
+
+        particles.set_limit(2);
+        let xs: Vec<f32> = particles.iter().map(|p| p.origin().x).collect();
+        assert_eq!(xs, vec![2.0, 3.0]);
+
+        particles.set_limit(MAX_PARTICLES + 1);
+        assert_eq!(particles.limit(), MAX_PARTICLES);
+    }
+
+    #[test]
+    fn test_particles_update_removes_dead() {
+        let mut particles = Particles::with_limit(4);
+        particles.insert(particle_at(0.0, Duration::milliseconds(100)));
+        particles.insert(particle_at(1.0, Duration::milliseconds(300)));
+
+        particles.update(Duration::milliseconds(200), Duration::milliseconds(16));
+        assert_eq!(particles.len(), 1);
+        assert_eq!(particles.iter().next().unwrap().origin().x, 1.0);
+    }
+
+    #[test]
+    fn test_particles_many_explosions() {
+        let mut particles = Particles::default();
+        for i in 0..16 {
+            let origin = Vector3::new(i as f32 * 1000.0, 0.0, 0.0);
+            particles.create_explosion(Duration::zero(), origin);
+        }
+
+        // only the most recent explosions survive
+        assert_eq!(particles.len(), DEFAULT_PARTICLE_LIMIT);
+        assert!(particles.iter().all(|p| p.origin().x >= 12000.0 - 16.0));
+    }
+
+    #[test]
+    fn test_particles_seed_deterministic() {
+        let run = |seed| {
+            let mut particles = Particles::default();
+            particles.seed(seed);
+            particles.create_effect(
+                Duration::zero(),
+                Vector3::zero(),
+                Vector3::unit_z(),
+                73,
+                20,
+            );
+            particles.create_explosion(Duration::zero(), Vector3::zero());
+            particles.update(Duration::milliseconds(100), Duration::milliseconds(100));
+            particles
+                .iter()
+                .map(|p| (p.origin(), p.color()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(run(1234), run(1234));
+        assert_ne!(run(1234), run(4321));
+    }
+
+    #[test]
+    fn test_particles_trail_spacing() {
+        let mut particles = Particles::default();
+        particles.create_trail(
+            Duration::zero(),
+            Vector3::zero(),
+            Vector3::new(30.0, 0.0, 0.0),
+            TrailKind::Tracer,
+        );
+
+        // tracers start exactly on the path, one every 3 units
+        let xs: Vec<f32> = particles.iter().map(|p| p.origin().x).collect();
+        assert_eq!(xs.len(), 10);
+        assert_eq!(xs[1], 3.0);
+
+        // nothing moved, so there's no trail
+        particles.clear();
+        let origin = Vector3::new(10.0, 0.0, 0.0);
+        particles.create_trail(Duration::zero(), origin, origin, TrailKind::Rocket);
+        assert!(particles.is_empty());
+    }
+
+    #[test]
+    fn test_particles_fire_burns_out() {
+        let mut particles = Particles::default();
+        particles.create_trail(
+            Duration::zero(),
+            Vector3::zero(),
+            Vector3::new(3.0, 0.0, 0.0),
+            TrailKind::Rocket,
+        );
+        assert_eq!(particles.len(), 1);
+
+        // the fire ramp runs out after at most 6 / 5 seconds, long before the particle dies
+        particles.update(Duration::milliseconds(1200), Duration::milliseconds(1200));
+        particles.update(Duration::milliseconds(1216), Duration::milliseconds(16));
+        assert!(particles.is_empty());
+    }
+
+    #[test]
+    fn test_trail_kind_from_model_flags() {
+        assert_eq!(TrailKind::from_model_flags(ModelFlags::ROCKET), Some(TrailKind::Rocket));
+        assert_eq!(
+            TrailKind::from_model_flags(ModelFlags::GIB | ModelFlags::ROTATE),
+            Some(TrailKind::Blood)
+        );
+        assert_eq!(TrailKind::from_model_flags(ModelFlags::ROTATE), None);
+    }
+}
@@ -0,0 +1,638 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Client-side movement prediction.
+//!
+//! Server updates show the player where they were a round trip ago. To hide that latency, the
+//! client keeps the move commands the server hasn't processed yet and runs the player's physics
+//! on them itself, starting from the latest position the server sent.
+//!
+//! Only the world is collided with; doors, platforms and other entities are left to the server,
+//! whose corrections are blended in smoothly.
+
+use std::collections::VecDeque;
+
+use common::bsp::{HullKind, HullTrace};
+use common::engine;
+
+use cgmath::{Angle, Deg, InnerSpace, Vector3, Zero};
+use chrono::Duration;
+use failure::Error;
+
+// the original server's default movement settings
+const GRAVITY: f32 = 800.0;
+const FRICTION: f32 = 4.0;
+const EDGE_FRICTION: f32 = 2.0;
+const STOP_SPEED: f32 = 100.0;
+const MAX_SPEED: f32 = 320.0;
+const MAX_VELOCITY: f32 = 2000.0;
+const ACCELERATE: f32 = 10.0;
+const MAX_AIR_WISH_SPEED: f32 = 30.0;
+const JUMP_SPEED: f32 = 270.0;
+const STEP_SIZE: f32 = 18.0;
+
+// the bottom of the player's bounding box relative to its origin
+const PLAYER_MINS_Z: f32 = -24.0;
+
+const MAX_BUMPS: usize = 4;
+const MAX_CLIP_PLANES: usize = 5;
+
+// fly_move results
+const BLOCKED_FLOOR: u32 = 1;
+const BLOCKED_STEP: u32 = 2;
+
+// about a second of input at 60 frames per second
+const MAX_PENDING_CMDS: usize = 64;
+
+// errors larger than this (teleports, respawns) are corrected immediately
+const MAX_SMOOTH_ERROR: f32 = 64.0;
+
+// how quickly smaller errors are blended out, per second
+const ERROR_DECAY_RATE: f32 = 10.0;
+
+/// The part of a move command that affects the player's movement.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PredictCmd {
+    /// The latest server time the client had received when the command was sent.
+    pub send_time: Duration,
+
+    /// How long the command was held for.
+    pub duration: Duration,
+
+    pub angles: Vector3<Deg<f32>>,
+    pub fwd_move: f32,
+    pub side_move: f32,
+    pub jump: bool,
+}
+
+/// The state of the player that the movement physics works on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PlayerMove {
+    pub origin: Vector3<f32>,
+    pub velocity: Vector3<f32>,
+    pub on_ground: bool,
+
+    // the jump button has to be released between jumps
+    jump_released: bool,
+}
+
+impl PlayerMove {
+    pub fn new(origin: Vector3<f32>, velocity: Vector3<f32>, on_ground: bool) -> PlayerMove {
+        PlayerMove {
+            origin,
+            velocity,
+            on_ground,
+            jump_released: true,
+        }
+    }
+}
+
+/// Runs one move command through the player's physics.
+///
+/// This follows the original server's `SV_ClientThink` and `SV_Physics_Client` for a walking
+/// player out of the water. `trace` sweeps a hull through the world from a start to an end point;
+/// if it fails, the move is abandoned partway through and the error returned.
+pub fn player_move<T>(player: &mut PlayerMove, cmd: &PredictCmd, trace: &T) -> Result<(), Error>
+where
+    T: Fn(HullKind, Vector3<f32>, Vector3<f32>) -> Result<HullTrace, Error>,
+{
+    let time = engine::duration_to_f32(cmd.duration);
+    if time <= 0.0 {
+        return Ok(());
+    }
+
+    // the server pitches the player's model by a third of the view pitch
+    let pitch = -cmd.angles.x / 3.0;
+    let yaw = cmd.angles.y;
+    let forward = Vector3::new(pitch.cos() * yaw.cos(), pitch.cos() * yaw.sin(), 0.0);
+    let right = Vector3::new(yaw.sin(), -yaw.cos(), 0.0);
+
+    let mut wish_velocity = forward * cmd.fwd_move + right * cmd.side_move;
+    let mut wish_speed = wish_velocity.magnitude();
+    if wish_speed > MAX_SPEED {
+        wish_velocity *= MAX_SPEED / wish_speed;
+        wish_speed = MAX_SPEED;
+    }
+
+    if player.on_ground {
+        user_friction(player, time, trace)?;
+        let wish_dir = if wish_speed > 0.0 {
+            wish_velocity / wish_speed
+        } else {
+            Vector3::zero()
+        };
+        accelerate(player, wish_dir, wish_speed, time);
+    } else {
+        air_accelerate(player, wish_velocity, time);
+    }
+
+    // PlayerPreThink in the game code
+    if cmd.jump {
+        if player.on_ground && player.jump_released {
+            player.velocity.z += JUMP_SPEED;
+            player.on_ground = false;
+            player.jump_released = false;
+        }
+    } else {
+        player.jump_released = true;
+    }
+
+    player.velocity.z -= GRAVITY * time;
+    for i in 0..3 {
+        player.velocity[i] = player.velocity[i].max(-MAX_VELOCITY).min(MAX_VELOCITY);
+    }
+
+    walk_move(player, time, trace)
+}
+
+fn user_friction<T>(player: &mut PlayerMove, time: f32, trace: &T) -> Result<(), Error>
+where
+    T: Fn(HullKind, Vector3<f32>, Vector3<f32>) -> Result<HullTrace, Error>,
+{
+    let speed = player.velocity.x.hypot(player.velocity.y);
+    if speed == 0.0 {
+        return Ok(());
+    }
+
+    // slow down faster when about to walk off a ledge
+    let start = Vector3::new(
+        player.origin.x + player.velocity.x / speed * 16.0,
+        player.origin.y + player.velocity.y / speed * 16.0,
+        player.origin.z + PLAYER_MINS_Z,
+    );
+    let stop = start - Vector3::new(0.0, 0.0, 34.0);
+    let friction = if trace(HullKind::Point, start, stop)?.fraction == 1.0 {
+        FRICTION * EDGE_FRICTION
+    } else {
+        FRICTION
+    };
+
+    let control = speed.max(STOP_SPEED);
+    let new_speed = (speed - time * control * friction).max(0.0);
+    player.velocity *= new_speed / speed;
+
+    Ok(())
+}
+
+fn accelerate(player: &mut PlayerMove, wish_dir: Vector3<f32>, wish_speed: f32, time: f32) {
+    let add_speed = wish_speed - player.velocity.dot(wish_dir);
+    if add_speed <= 0.0 {
+        return;
+    }
+
+    let accel_speed = (ACCELERATE * time * wish_speed).min(add_speed);
+    player.velocity += accel_speed * wish_dir;
+}
+
+fn air_accelerate(player: &mut PlayerMove, wish_velocity: Vector3<f32>, time: f32) {
+    let wish_speed = wish_velocity.magnitude();
+    if wish_speed == 0.0 {
+        return;
+    }
+
+    let wish_dir = wish_velocity / wish_speed;
+    let add_speed = wish_speed.min(MAX_AIR_WISH_SPEED) - player.velocity.dot(wish_dir);
+    if add_speed <= 0.0 {
+        return;
+    }
+
+    let accel_speed = (ACCELERATE * wish_speed * time).min(add_speed);
+    player.velocity += accel_speed * wish_dir;
+}
+
+// removes the component of `velocity` going into the plane
+fn clip_velocity(velocity: Vector3<f32>, normal: Vector3<f32>) -> Vector3<f32> {
+    let mut clipped = velocity - normal * velocity.dot(normal);
+    for i in 0..3 {
+        if clipped[i].abs() < 0.1 {
+            clipped[i] = 0.0;
+        }
+    }
+
+    clipped
+}
+
+// moves the player along its velocity, sliding along anything it hits
+fn fly_move<T>(player: &mut PlayerMove, time: f32, trace: &T) -> Result<u32, Error>
+where
+    T: Fn(HullKind, Vector3<f32>, Vector3<f32>) -> Result<HullTrace, Error>,
+{
+    let primal_velocity = player.velocity;
+    let mut original_velocity = player.velocity;
+    let mut planes: Vec<Vector3<f32>> = Vec::with_capacity(MAX_CLIP_PLANES);
+    let mut time_left = time;
+    let mut blocked = 0;
+
+    for _ in 0..MAX_BUMPS {
+        if player.velocity == Vector3::zero() {
+            break;
+        }
+
+        let end = player.origin + time_left * player.velocity;
+        let tr = trace(HullKind::Player, player.origin, end)?;
+
+        if tr.all_solid {
+            // stuck in the world
+            player.velocity = Vector3::zero();
+            return Ok(BLOCKED_FLOOR | BLOCKED_STEP);
+        }
+
+        if tr.fraction > 0.0 {
+            player.origin = tr.end_point;
+            original_velocity = player.velocity;
+            planes.clear();
+        }
+
+        let normal = match tr.plane_normal {
+            Some(n) if tr.fraction < 1.0 => n,
+            _ => break,
+        };
+
+        if normal.z > 0.7 {
+            blocked |= BLOCKED_FLOOR;
+            player.on_ground = true;
+        }
+
+        if normal.z == 0.0 {
+            blocked |= BLOCKED_STEP;
+        }
+
+        time_left -= time_left * tr.fraction;
+
+        if planes.len() >= MAX_CLIP_PLANES {
+            player.velocity = Vector3::zero();
+            return Ok(BLOCKED_FLOOR | BLOCKED_STEP);
+        }
+        planes.push(normal);
+
+        // find a velocity that slides along all of the planes hit so far
+        let mut slide = None;
+        for (i, &plane) in planes.iter().enumerate() {
+            let velocity = clip_velocity(original_velocity, plane);
+            let clear = planes
+                .iter()
+                .enumerate()
+                .all(|(j, &other)| j == i || velocity.dot(other) >= 0.0);
+
+            if clear {
+                slide = Some(velocity);
+                break;
+            }
+        }
+
+        match slide {
+            Some(v) => player.velocity = v,
+
+            // caught in a crease, so slide along it
+            None if planes.len() == 2 => {
+                let dir = planes[0].cross(planes[1]);
+                player.velocity = dir * dir.dot(player.velocity);
+            }
+
+            None => {
+                player.velocity = Vector3::zero();
+                return Ok(blocked | BLOCKED_STEP);
+            }
+        }
+
+        // don't bounce back toward where the move started
+        if player.velocity.dot(primal_velocity) <= 0.0 {
+            player.velocity = Vector3::zero();
+            return Ok(blocked);
+        }
+    }
+
+    Ok(blocked)
+}
+
+// moves the player by `offset` or until it hits something, whichever comes first
+fn push<T>(player: &mut PlayerMove, offset: Vector3<f32>, trace: &T) -> Result<HullTrace, Error>
+where
+    T: Fn(HullKind, Vector3<f32>, Vector3<f32>) -> Result<HullTrace, Error>,
+{
+    let tr = trace(HullKind::Player, player.origin, player.origin + offset)?;
+    player.origin = tr.end_point;
+    Ok(tr)
+}
+
+// like fly_move, but walks up steps
+fn walk_move<T>(player: &mut PlayerMove, time: f32, trace: &T) -> Result<(), Error>
+where
+    T: Fn(HullKind, Vector3<f32>, Vector3<f32>) -> Result<HullTrace, Error>,
+{
+    let old_on_ground = player.on_ground;
+    player.on_ground = false;
+
+    let old_origin = player.origin;
+    let old_velocity = player.velocity;
+
+    let blocked = fly_move(player, time, trace)?;
+
+    // only walking into a wall can be a step
+    if blocked & BLOCKED_STEP == 0 || !old_on_ground {
+        return Ok(());
+    }
+
+    let no_step = *player;
+
+    // try the move again from a step higher up, then come back down
+    player.origin = old_origin;
+    push(player, Vector3::new(0.0, 0.0, STEP_SIZE), trace)?;
+    player.velocity = Vector3::new(old_velocity.x, old_velocity.y, 0.0);
+    fly_move(player, time, trace)?;
+
+    let down = push(
+        player,
+        Vector3::new(0.0, 0.0, -STEP_SIZE + old_velocity.z * time),
+        trace,
+    )?;
+
+    match down.plane_normal {
+        Some(n) if down.fraction < 1.0 && n.z > 0.7 => player.on_ground = true,
+
+        // not a step after all, e.g. a wall on top of a slope
+        _ => *player = no_step,
+    }
+
+    Ok(())
+}
+
+/// Predicts the player's position from the commands the server hasn't processed.
+#[derive(Debug)]
+pub struct Predictor {
+    // commands sent since the last one the server has processed, oldest first
+    pending: VecDeque<PredictCmd>,
+
+    // the server time of the last update reconciled against
+    server_time: Option<Duration>,
+
+    // whether the jump button was held in the last command the server processed
+    acked_jump: bool,
+
+    predicted: Option<PlayerMove>,
+
+    // the difference between where the player was shown and the corrected prediction
+    error: Vector3<f32>,
+}
+
+impl Predictor {
+    pub fn new() -> Predictor {
+        Predictor {
+            pending: VecDeque::new(),
+            server_time: None,
+            acked_jump: false,
+            predicted: None,
+            error: Vector3::zero(),
+        }
+    }
+
+    /// Discards the prediction, leaving the player where the server puts them.
+    pub fn reset(&mut self) {
+        *self = Predictor::new();
+    }
+
+    /// Returns where the player should be shown, if prediction is running.
+    pub fn origin(&self) -> Option<Vector3<f32>> {
+        self.predicted.map(|p| p.origin + self.error)
+    }
+
+    /// Starts over from the player's state in a server update sent at `server_time`.
+    ///
+    /// Commands sent at least `latency` before the update are assumed to have been processed by
+    /// the server, and the rest are replayed on top of its state. Small differences from the
+    /// previous prediction are blended out over the next few frames rather than snapped to.
+    ///
+    /// If a trace fails the prediction is left unfinished, and should be reset.
+    pub fn reconcile<T>(
+        &mut self,
+        server: PlayerMove,
+        server_time: Duration,
+        latency: Duration,
+        trace: &T,
+    ) -> Result<(), Error>
+    where
+        T: Fn(HullKind, Vector3<f32>, Vector3<f32>) -> Result<HullTrace, Error>,
+    {
+        if self.server_time == Some(server_time) {
+            return Ok(());
+        }
+        self.server_time = Some(server_time);
+
+        while self
+            .pending
+            .front()
+            .map_or(false, |cmd| cmd.send_time + latency <= server_time)
+        {
+            self.acked_jump = self.pending.pop_front().unwrap().jump;
+        }
+
+        let mut player = server;
+        player.jump_released = !self.acked_jump;
+        for cmd in self.pending.iter() {
+            player_move(&mut player, cmd, trace)?;
+        }
+
+        let shown = self.origin();
+        self.error = match shown {
+            Some(shown) if (shown - player.origin).magnitude() <= MAX_SMOOTH_ERROR => {
+                shown - player.origin
+            }
+            _ => Vector3::zero(),
+        };
+
+        self.predicted = Some(player);
+
+        Ok(())
+    }
+
+    /// Moves the predicted player according to a newly sent command.
+    ///
+    /// Nothing is predicted until the first call to `reconcile`. If a trace fails the prediction
+    /// is left unfinished, and should be reset.
+    pub fn predict<T>(&mut self, cmd: PredictCmd, trace: &T) -> Result<(), Error>
+    where
+        T: Fn(HullKind, Vector3<f32>, Vector3<f32>) -> Result<HullTrace, Error>,
+    {
+        match self.predicted {
+            Some(ref mut player) => player_move(player, &cmd, trace)?,
+            None => return Ok(()),
+        }
+
+        self.pending.push_back(cmd);
+        if self.pending.len() > MAX_PENDING_CMDS {
+            self.acked_jump = self.pending.pop_front().unwrap().jump;
+        }
+
+        let decay = (-ERROR_DECAY_RATE * engine::duration_to_f32(cmd.duration)).exp();
+        self.error *= decay;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // traces against a floor filling everything below z = 0
+    fn floor_trace(
+        hull: HullKind,
+        start: Vector3<f32>,
+        end: Vector3<f32>,
+    ) -> Result<HullTrace, Error> {
+        let floor = match hull {
+            HullKind::Point => 0.0,
+            _ => -PLAYER_MINS_Z,
+        };
+
+        let open = HullTrace {
+            fraction: 1.0,
+            end_point: end,
+            plane_normal: None,
+            start_solid: false,
+            all_solid: false,
+        };
+
+        if start.z < floor {
+            return Ok(HullTrace {
+                fraction: 0.0,
+                end_point: start,
+                start_solid: true,
+                all_solid: end.z < floor,
+                ..open
+            });
+        }
+
+        if end.z >= floor {
+            return Ok(open);
+        }
+
+        let fraction = (start.z - floor) / (start.z - end.z);
+        Ok(HullTrace {
+            fraction,
+            end_point: start + (end - start) * fraction,
+            plane_normal: Some(Vector3::unit_z()),
+            ..open
+        })
+    }
+
+    fn cmd(send_ms: i64, fwd_move: f32, jump: bool) -> PredictCmd {
+        PredictCmd {
+            send_time: Duration::milliseconds(send_ms),
+            duration: Duration::milliseconds(50),
+            angles: Vector3::new(Deg(0.0), Deg(0.0), Deg(0.0)),
+            fwd_move,
+            side_move: 0.0,
+            jump,
+        }
+    }
+
+    fn standing() -> PlayerMove {
+        PlayerMove::new(Vector3::new(0.0, 0.0, 24.0), Vector3::zero(), true)
+    }
+
+    #[test]
+    fn test_player_move_walk() {
+        let mut player = standing();
+        for _ in 0..20 {
+            player_move(&mut player, &cmd(0, 400.0, false), &floor_trace).unwrap();
+        }
+
+        // walking forward along +x reaches top speed without leaving the floor
+        assert!(player.origin.x > 0.0);
+        assert!((player.velocity.x - MAX_SPEED).abs() < 1.0, "{:?}", player.velocity);
+        assert!((player.origin.z - 24.0).abs() < 0.1, "{:?}", player.origin);
+        assert!(player.on_ground);
+    }
+
+    #[test]
+    fn test_player_move_jump() {
+        let mut player = standing();
+        player_move(&mut player, &cmd(0, 0.0, true), &floor_trace).unwrap();
+        assert!(!player.on_ground);
+        assert!(player.origin.z > 24.0);
+
+        // holding jump doesn't jump again after landing
+        for _ in 0..40 {
+            player_move(&mut player, &cmd(0, 0.0, true), &floor_trace).unwrap();
+        }
+        assert!(player.on_ground);
+        assert!((player.origin.z - 24.0).abs() < 0.1, "{:?}", player.origin);
+    }
+
+    #[test]
+    fn test_predictor_replays_unacked() {
+        let mut predictor = Predictor::new();
+        predictor.predict(cmd(0, 400.0, false), &floor_trace).unwrap();
+        assert_eq!(predictor.origin(), None);
+
+        let latency = Duration::milliseconds(100);
+        predictor.reconcile(standing(), Duration::zero(), latency, &floor_trace).unwrap();
+        assert_eq!(predictor.origin(), Some(standing().origin));
+
+        for t in 0..4 {
+            predictor.predict(cmd(t * 50, 400.0, false), &floor_trace).unwrap();
+        }
+        let predicted = predictor.origin().unwrap();
+        assert!(predicted.x > 0.0);
+
+        // the server has processed the first two commands and agrees with the prediction
+        let mut server = standing();
+        player_move(&mut server, &cmd(0, 400.0, false), &floor_trace).unwrap();
+        player_move(&mut server, &cmd(50, 400.0, false), &floor_trace).unwrap();
+        predictor.reconcile(server, Duration::milliseconds(150), latency, &floor_trace).unwrap();
+        assert!((predictor.origin().unwrap() - predicted).magnitude() < 1e-3);
+    }
+
+    #[test]
+    fn test_predictor_smooths_small_errors() {
+        let mut predictor = Predictor::new();
+        let latency = Duration::milliseconds(100);
+        predictor.reconcile(standing(), Duration::zero(), latency, &floor_trace).unwrap();
+
+        // the server puts the player slightly elsewhere
+        let mut server = standing();
+        server.origin.x = 8.0;
+        predictor.reconcile(server, Duration::milliseconds(50), latency, &floor_trace).unwrap();
+        assert_eq!(predictor.origin(), Some(standing().origin));
+
+        // the error fades out over the following frames
+        for t in 0..10 {
+            predictor.predict(cmd(50 + t * 50, 0.0, false), &floor_trace).unwrap();
+        }
+        let origin = predictor.origin().unwrap();
+        assert!(origin.x > 7.9 && origin.x <= 8.0, "{:?}", origin);
+
+        // a teleport snaps straight to the new position
+        let mut server = standing();
+        server.origin.x = 1000.0;
+        predictor.reconcile(server, Duration::milliseconds(600), latency, &floor_trace).unwrap();
+        assert_eq!(predictor.origin().unwrap().x, 1000.0);
+    }
+
+    #[test]
+    fn test_predictor_trace_error() {
+        let mut predictor = Predictor::new();
+        let latency = Duration::milliseconds(100);
+        predictor.reconcile(standing(), Duration::zero(), latency, &floor_trace).unwrap();
+
+        let no_hull = |_: HullKind, _: Vector3<f32>, _: Vector3<f32>| -> Result<HullTrace, Error> {
+            bail!("Invalid hull index")
+        };
+        assert!(predictor.predict(cmd(0, 400.0, false), &no_hull).is_err());
+    }
+}
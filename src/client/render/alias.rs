@@ -19,13 +19,16 @@ use client::render::Camera;
 use client::render::ColorFormat;
 use client::render::Palette;
 use client::render::Vertex;
+use client::render::VertexNormal;
 use client::render::pipe;
 use common::mdl::AliasModel;
 use common::mdl::Keyframe;
 use common::mdl::Texture;
 
+use cgmath::Angle;
 use cgmath::Deg;
 use cgmath::Euler;
+use cgmath::InnerSpace;
 use cgmath::Matrix4;
 use cgmath::Vector3;
 use chrono::Duration;
@@ -42,6 +45,125 @@ use gfx::pso::PipelineData;
 use gfx::pso::PipelineState;
 use gfx_device_gl::Resources;
 
+/// Find the index of the frame of an animation that should be displayed at `time`.
+///
+/// The animation loops every `total_duration`. If the animation has no length, returns the first
+/// frame.
+fn frame_for_time(durations: &[Duration], total_duration: Duration, time: Duration) -> usize {
+    let total_ms = total_duration.num_milliseconds();
+    if total_ms <= 0 {
+        return 0;
+    }
+
+    let mut time_ms = time.num_milliseconds() % total_ms;
+    for (frame_id, frame_duration) in durations.iter().enumerate() {
+        time_ms -= frame_duration.num_milliseconds();
+        if time_ms < 0 {
+            return frame_id;
+        }
+    }
+
+    // only reachable through rounding error, so hold the last frame
+    durations.len().saturating_sub(1)
+}
+
+/// Find the two frames of an animation to blend between at `time`, and how far to blend.
+///
+/// Returns the current frame, the frame after it (wrapping around to the first) and the fraction
+/// of the current frame's duration that has passed.
+fn frame_blend_for_time(
+    durations: &[Duration],
+    total_duration: Duration,
+    time: Duration,
+) -> (usize, usize, f32) {
+    let total_ms = total_duration.num_milliseconds();
+    if total_ms <= 0 || durations.is_empty() {
+        return (0, 0, 0.0);
+    }
+
+    let mut time_ms = time.num_milliseconds() % total_ms;
+    for (frame_id, frame_duration) in durations.iter().enumerate() {
+        let frame_ms = frame_duration.num_milliseconds();
+        if time_ms < frame_ms {
+            let next_id = (frame_id + 1) % durations.len();
+            return (frame_id, next_id, time_ms as f32 / frame_ms as f32);
+        }
+        time_ms -= frame_ms;
+    }
+
+    // only reachable through rounding error, so hold the last frame
+    let last_id = durations.len() - 1;
+    (last_id, last_id, 0.0)
+}
+
+/// Blends the vertex positions of two frames, keeping the texcoords of the first.
+///
+/// A `factor` of 0 gives the first frame and 1 the second.
+pub fn blend_vertices(from: &[Vertex], to: &[Vertex], factor: f32) -> Vec<Vertex> {
+    from.iter()
+        .zip(to.iter())
+        .map(|(a, b)| {
+            let lerp = |i: usize| a.pos[i] + (b.pos[i] - a.pos[i]) * factor;
+            Vertex {
+                pos: [lerp(0), lerp(1), lerp(2)],
+                texcoord: a.texcoord,
+            }
+        })
+        .collect()
+}
+
+/// Blends the normals of two frames. The result isn't renormalized, which only slightly dims
+/// vertices whose normals turn sharply between frames.
+pub fn blend_normals(from: &[VertexNormal], to: &[VertexNormal], factor: f32) -> Vec<VertexNormal> {
+    from.iter()
+        .zip(to.iter())
+        .map(|(a, b)| {
+            let lerp = |i: usize| a.normal[i] + (b.normal[i] - a.normal[i]) * factor;
+            VertexNormal {
+                normal: [lerp(0), lerp(1), lerp(2)],
+            }
+        })
+        .collect()
+}
+
+/// Returns the direction alias model vertex normals are shaded against, in model space.
+///
+/// As in the original engine, the light comes from above and from the model's front, so models
+/// look the same from every side as they turn.
+pub fn shade_vector(yaw: Deg<f32>) -> [f32; 3] {
+    let yaw = -yaw;
+    Vector3::new(yaw.cos(), yaw.sin(), 1.0).normalize().into()
+}
+
+/// Blending from an alias model's previous keyframe to its current one, set by `r_lerpmodels`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyframeLerp {
+    pub prev_keyframe_id: usize,
+
+    /// How far to blend, from 0 (the previous keyframe) to 1 (the current keyframe).
+    pub factor: f32,
+}
+
+// Returns the keyframe to blend from and how far to blend, or `None` if only `keyframe_id` should
+// be drawn. Models with a single keyframe never blend.
+fn keyframe_blend(
+    lerp: Option<KeyframeLerp>,
+    keyframe_id: usize,
+    keyframe_count: usize,
+) -> Option<(usize, f32)> {
+    let lerp = lerp?;
+    if lerp.prev_keyframe_id == keyframe_id || lerp.prev_keyframe_id >= keyframe_count {
+        return None;
+    }
+
+    // also rejects NaN
+    if lerp.factor >= 0.0 && lerp.factor < 1.0 {
+        Some((lerp.prev_keyframe_id, lerp.factor))
+    } else {
+        None
+    }
+}
+
 pub struct AliasRenderStaticTexture {
     view: ShaderResourceView<Resources, [f32; 4]>,
 }
@@ -72,10 +194,26 @@ pub enum AliasRenderKeyframe {
     Animated(AliasRenderAnimatedKeyframe),
 }
 
+// the vertex range of a surface within each frame, and the skins it has of its own
+pub struct AliasRenderSurface {
+    start: u32,
+    end: u32,
+    skins: Box<[Option<ShaderResourceView<Resources, [f32; 4]>>]>,
+}
+
 pub struct AliasRenderer {
     keyframes: Box<[AliasRenderKeyframe]>,
     textures: Box<[AliasRenderTexture]>,
+    surfaces: Box<[AliasRenderSurface]>,
     vertex_buffer: Buffer<Resources, Vertex>,
+    normal_buffer: Buffer<Resources, VertexNormal>,
+
+    // a copy of every frame's vertices and normals, blended into the blend buffers between frames
+    vertices: Box<[Vertex]>,
+    normals: Box<[VertexNormal]>,
+    blend_buffer: Buffer<Resources, Vertex>,
+    blend_normal_buffer: Buffer<Resources, VertexNormal>,
+    blend_slice: Slice<Resources>,
 }
 
 impl AliasRenderer {
@@ -87,6 +225,7 @@ impl AliasRenderer {
         let h = alias_model.texture_height();
 
         let mut vertices = Vec::new();
+        let mut normals = Vec::new();
         let mut keyframes = Vec::new();
 
         for keyframe in alias_model.keyframes() {
@@ -96,18 +235,12 @@ impl AliasRenderer {
                     for polygon in alias_model.polygons() {
                         for index in polygon.indices() {
                             let pos = static_keyframe.vertices()[*index as usize];
-                            let texcoord = &alias_model.texcoords()[*index as usize];
-
-                            let s = if !polygon.faces_front() && texcoord.is_on_seam() {
-                                (texcoord.s() + w / 2) as f32 + 0.5
-                            } else {
-                                texcoord.s() as f32 + 0.5
-                            } / w as f32;
-
-                            let t = (texcoord.t() as f32 + 0.5) / h as f32;
                             vertices.push(Vertex {
                                 pos: pos.into(),
-                                texcoord: [s, t],
+                                texcoord: alias_model.texcoord(polygon, *index),
+                            });
+                            normals.push(VertexNormal {
+                                normal: static_keyframe.normals()[*index as usize].into(),
                             });
                         }
                     }
@@ -135,18 +268,12 @@ impl AliasRenderer {
                         for polygon in alias_model.polygons() {
                             for index in polygon.indices() {
                                 let pos = frame.vertices()[*index as usize];
-                                let texcoord = &alias_model.texcoords()[*index as usize];
-
-                                let s = if !polygon.faces_front() && texcoord.is_on_seam() {
-                                    (texcoord.s() + w / 2) as f32 + 0.5
-                                } else {
-                                    texcoord.s() as f32 + 0.5
-                                } / w as f32;
-
-                                let t = (texcoord.t() as f32 + 0.5) / h as f32;
                                 vertices.push(Vertex {
                                     pos: pos.into(),
-                                    texcoord: [s, t],
+                                    texcoord: alias_model.texcoord(polygon, *index),
+                                });
+                                normals.push(VertexNormal {
+                                    normal: frame.normals()[*index as usize].into(),
                                 });
                             }
                         }
@@ -177,6 +304,29 @@ impl AliasRenderer {
 
         use gfx::traits::FactoryExt;
         let vertex_buffer = factory.create_vertex_buffer(&vertices);
+        let normal_buffer = factory.create_vertex_buffer(&normals);
+
+        // every frame has one vertex per polygon corner
+        let frame_vertex_count = alias_model.polygons().len() * 3;
+        let blend_buffer = factory.create_buffer(
+            frame_vertex_count.max(1),
+            gfx::buffer::Role::Vertex,
+            gfx::memory::Usage::Dynamic,
+            gfx::memory::Bind::empty(),
+        )?;
+        let blend_normal_buffer = factory.create_buffer(
+            frame_vertex_count.max(1),
+            gfx::buffer::Role::Vertex,
+            gfx::memory::Usage::Dynamic,
+            gfx::memory::Bind::empty(),
+        )?;
+        let blend_slice = Slice {
+            start: 0,
+            end: frame_vertex_count as u32,
+            base_vertex: 0,
+            instances: None,
+            buffer: IndexBuffer::Auto,
+        };
 
         let mut textures = Vec::new();
         for texture in alias_model.textures() {
@@ -227,10 +377,46 @@ impl AliasRenderer {
             }
         }
 
+        let mut surfaces = Vec::new();
+        for surface in alias_model.surfaces() {
+            let mut skins = Vec::new();
+            for skin in surface.skins() {
+                skins.push(match *skin {
+                    Some(ref skin) => {
+                        let (_, view) = factory.create_texture_immutable_u8::<ColorFormat>(
+                            gfx::texture::Kind::D2(
+                                skin.width() as u16,
+                                skin.height() as u16,
+                                gfx::texture::AaMode::Single,
+                            ),
+                            gfx::texture::Mipmap::Allocated,
+                            &[skin.rgba()],
+                        )?;
+                        Some(view)
+                    }
+
+                    None => None,
+                });
+            }
+
+            surfaces.push(AliasRenderSurface {
+                start: (surface.first_polygon() * 3) as u32,
+                end: ((surface.first_polygon() + surface.polygon_count()) * 3) as u32,
+                skins: skins.into_boxed_slice(),
+            });
+        }
+
         Ok(AliasRenderer {
             keyframes: keyframes.into_boxed_slice(),
             textures: textures.into_boxed_slice(),
+            surfaces: surfaces.into_boxed_slice(),
             vertex_buffer,
+            normal_buffer,
+            vertices: vertices.into_boxed_slice(),
+            normals: normals.into_boxed_slice(),
+            blend_buffer,
+            blend_normal_buffer,
+            blend_slice,
         })
     }
 
@@ -244,18 +430,67 @@ impl AliasRenderer {
         origin: Vector3<f32>,
         angles: Vector3<Deg<f32>>,
         keyframe_id: usize,
+        lerp: Option<KeyframeLerp>,
         texture_id: usize,
     ) -> Result<(), Error>
     where
         C: CommandBuffer<Resources>,
     {
-        ensure!(keyframe_id < self.keyframes.len(), "Keyframe ID out of range: {}", keyframe_id);
-        ensure!(texture_id < self.textures.len(), "Texture ID out of range: {}", texture_id);
-
         let model_transform = Matrix4::from_translation(Vector3::new(-origin.y, origin.z, -origin.x))
             * Matrix4::from(Euler::new(angles.x, angles.y, angles.z));
 
+        self.render_with_transform(
+            encoder,
+            pso,
+            user_data,
+            time,
+            camera,
+            model_transform,
+            keyframe_id,
+            lerp,
+            texture_id,
+        )
+    }
+
+    /// Renders the model with an arbitrary model transform.
+    ///
+    /// The transform is applied after vertices are converted to OpenGL coordinates. If `lerp` is
+    /// given, the model is blended from its previous keyframe when both keyframes are single
+    /// poses.
+    pub fn render_with_transform<C>(
+        &self,
+        encoder: &mut Encoder<Resources, C>,
+        pso: &PipelineState<Resources, <pipe::Data<Resources> as PipelineData<Resources>>::Meta>,
+        user_data: &mut pipe::Data<Resources>,
+        time: Duration,
+        camera: &Camera,
+        model_transform: Matrix4<f32>,
+        keyframe_id: usize,
+        lerp: Option<KeyframeLerp>,
+        texture_id: usize,
+    ) -> Result<(), Error>
+    where
+        C: CommandBuffer<Resources>,
+    {
+        ensure!(!self.keyframes.is_empty(), "Alias model has no keyframes");
+
+        // like skins, bad frames fall back to the first frame rather than stopping the render
+        let keyframe_id = if keyframe_id < self.keyframes.len() {
+            keyframe_id
+        } else {
+            debug!("No such keyframe: {}", keyframe_id);
+            0
+        };
+
+        // some entities specify skins their model doesn't have, so fall back to the first skin
+        let texture_id = if texture_id < self.textures.len() {
+            texture_id
+        } else {
+            0
+        };
+
         user_data.vertex_buffer = self.vertex_buffer.clone();
+        user_data.normal_buffer = self.normal_buffer.clone();
         user_data.transform = (camera.transform() * model_transform).into();
 
         match self.textures[texture_id] {
@@ -264,43 +499,263 @@ impl AliasRenderer {
             }
 
             AliasRenderTexture::Animated(ref animated_texture) => {
-                // pick a fallback texture
-                user_data.sampler.0 = animated_texture.views[0].clone();
-
-                let mut time_ms = time.num_milliseconds() % animated_texture.total_duration.num_milliseconds();
-
-                for (frame_id, frame_duration) in animated_texture.durations.iter().enumerate() {
-                    time_ms -= frame_duration.num_milliseconds();
-                    if time_ms <= 0 {
-                        user_data.sampler.0 = animated_texture.views[frame_id].clone();
-                        break;
-                    }
-                }
+                let frame_id = frame_for_time(
+                    &animated_texture.durations,
+                    animated_texture.total_duration,
+                    time,
+                );
+                user_data.sampler.0 = animated_texture.views[frame_id].clone();
             }
         }
 
         match self.keyframes[keyframe_id] {
             AliasRenderKeyframe::Static(ref static_keyframe) => {
-                encoder.draw(&static_keyframe.slice, pso, user_data);
+                let prev_slice = match keyframe_blend(lerp, keyframe_id, self.keyframes.len()) {
+                    Some((prev_id, factor)) => match self.keyframes[prev_id] {
+                        AliasRenderKeyframe::Static(ref prev) => Some((&prev.slice, factor)),
+                        AliasRenderKeyframe::Animated(_) => None,
+                    },
+                    None => None,
+                };
+
+                match prev_slice {
+                    Some((prev_slice, factor)) => self.draw_blended(
+                        encoder,
+                        pso,
+                        user_data,
+                        prev_slice,
+                        &static_keyframe.slice,
+                        factor,
+                        texture_id,
+                    )?,
+                    None => self.draw_surfaces(
+                        encoder,
+                        pso,
+                        user_data,
+                        &static_keyframe.slice,
+                        texture_id,
+                    ),
+                }
             }
 
             AliasRenderKeyframe::Animated(ref animated_keyframe) => {
-                // pick a fallback slice
-                let mut slice = &animated_keyframe.slices[0];
-
-                let mut time_ms = time.num_milliseconds() % animated_keyframe.total_duration.num_milliseconds();
-                for (frame_id, frame_duration) in animated_keyframe.durations.iter().enumerate() {
-                    time_ms -= frame_duration.num_milliseconds();
-                    if time_ms <= 0 {
-                        slice = &animated_keyframe.slices[frame_id];
-                        break;
-                    }
+                let (frame_id, next_id, factor) = frame_blend_for_time(
+                    &animated_keyframe.durations,
+                    animated_keyframe.total_duration,
+                    time,
+                );
+
+                if frame_id == next_id || factor <= 0.0 {
+                    self.draw_surfaces(
+                        encoder,
+                        pso,
+                        user_data,
+                        &animated_keyframe.slices[frame_id],
+                        texture_id,
+                    );
+                } else {
+                    self.draw_blended(
+                        encoder,
+                        pso,
+                        user_data,
+                        &animated_keyframe.slices[frame_id],
+                        &animated_keyframe.slices[next_id],
+                        factor,
+                        texture_id,
+                    )?;
                 }
-
-                encoder.draw(slice, pso, user_data);
             }
         }
 
         Ok(())
     }
+
+    // draws a blend of two frames through the blend buffer
+    fn draw_blended<C>(
+        &self,
+        encoder: &mut Encoder<Resources, C>,
+        pso: &PipelineState<Resources, <pipe::Data<Resources> as PipelineData<Resources>>::Meta>,
+        user_data: &mut pipe::Data<Resources>,
+        from: &Slice<Resources>,
+        to: &Slice<Resources>,
+        factor: f32,
+        texture_id: usize,
+    ) -> Result<(), Error>
+    where
+        C: CommandBuffer<Resources>,
+    {
+        let blended = blend_vertices(self.slice_vertices(from), self.slice_vertices(to), factor);
+        encoder
+            .update_buffer(&self.blend_buffer, &blended, 0)
+            .map_err(|e| format_err!("alias frame upload failed: {}", e))?;
+        let blended_normals =
+            blend_normals(self.slice_normals(from), self.slice_normals(to), factor);
+        encoder
+            .update_buffer(&self.blend_normal_buffer, &blended_normals, 0)
+            .map_err(|e| format_err!("alias frame upload failed: {}", e))?;
+
+        user_data.vertex_buffer = self.blend_buffer.clone();
+        user_data.normal_buffer = self.blend_normal_buffer.clone();
+        self.draw_surfaces(encoder, pso, user_data, &self.blend_slice, texture_id);
+        Ok(())
+    }
+
+    // draws each surface of a frame with its own skin, or with the model skin already bound if it
+    // has none for `texture_id`
+    fn draw_surfaces<C>(
+        &self,
+        encoder: &mut Encoder<Resources, C>,
+        pso: &PipelineState<Resources, <pipe::Data<Resources> as PipelineData<Resources>>::Meta>,
+        user_data: &mut pipe::Data<Resources>,
+        frame: &Slice<Resources>,
+        texture_id: usize,
+    ) where
+        C: CommandBuffer<Resources>,
+    {
+        let model_skin = user_data.sampler.0.clone();
+        for surface in self.surfaces.iter() {
+            let skin = surface.skins.get(texture_id).or(surface.skins.first());
+            user_data.sampler.0 = match skin {
+                Some(&Some(ref view)) => view.clone(),
+                _ => model_skin.clone(),
+            };
+
+            let slice = Slice {
+                start: surface.start,
+                end: surface.end,
+                ..frame.clone()
+            };
+            encoder.draw(&slice, pso, user_data);
+        }
+        user_data.sampler.0 = model_skin;
+    }
+
+    // returns the vertices of a single frame
+    fn slice_vertices(&self, slice: &Slice<Resources>) -> &[Vertex] {
+        let start = slice.base_vertex as usize;
+        &self.vertices[start..start + slice.end as usize]
+    }
+
+    // returns the normals of a single frame
+    fn slice_normals(&self, slice: &Slice<Resources>) -> &[VertexNormal] {
+        let start = slice.base_vertex as usize;
+        &self.normals[start..start + slice.end as usize]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_frame_for_time() {
+        let durations = [
+            Duration::milliseconds(100),
+            Duration::milliseconds(100),
+            Duration::milliseconds(200),
+        ];
+        let total = Duration::milliseconds(400);
+
+        assert_eq!(frame_for_time(&durations, total, Duration::milliseconds(0)), 0);
+        assert_eq!(frame_for_time(&durations, total, Duration::milliseconds(99)), 0);
+        assert_eq!(frame_for_time(&durations, total, Duration::milliseconds(100)), 1);
+        assert_eq!(frame_for_time(&durations, total, Duration::milliseconds(250)), 2);
+        assert_eq!(frame_for_time(&durations, total, Duration::milliseconds(450)), 0);
+    }
+
+    #[test]
+    fn test_frame_blend_for_time() {
+        let durations = [
+            Duration::milliseconds(100),
+            Duration::milliseconds(100),
+            Duration::milliseconds(200),
+        ];
+        let total = Duration::milliseconds(400);
+
+        let blend_at = |ms| frame_blend_for_time(&durations, total, Duration::milliseconds(ms));
+
+        assert_eq!(blend_at(0), (0, 1, 0.0));
+        assert_eq!(blend_at(150), (1, 2, 0.5));
+
+        // the last frame blends back into the first
+        assert_eq!(blend_at(350), (2, 0, 0.75));
+
+        let empty = frame_blend_for_time(&[], Duration::zero(), Duration::milliseconds(50));
+        assert_eq!(empty, (0, 0, 0.0));
+    }
+
+    #[test]
+    fn test_blend_vertices() {
+        let from = [Vertex {
+            pos: [0.0, 2.0, -4.0],
+            texcoord: [0.25, 0.5],
+        }];
+        let to = [Vertex {
+            pos: [4.0, 2.0, 4.0],
+            texcoord: [0.75, 0.5],
+        }];
+
+        let blended = blend_vertices(&from, &to, 0.25);
+        assert_eq!(blended[0].pos, [1.0, 2.0, -2.0]);
+        assert_eq!(blended[0].texcoord, [0.25, 0.5]);
+    }
+
+    #[test]
+    fn test_shade_vector() {
+        let shade = shade_vector(Deg(90.0));
+        let half = 0.5f32.sqrt();
+        let expected = [0.0, -half, half];
+        for i in 0..3 {
+            assert!((shade[i] - expected[i]).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_keyframe_blend() {
+        let lerp = |prev_keyframe_id, factor| Some(KeyframeLerp { prev_keyframe_id, factor });
+
+        assert_eq!(keyframe_blend(lerp(2, 0.25), 3, 8), Some((2, 0.25)));
+
+        // nothing to blend
+        assert_eq!(keyframe_blend(None, 3, 8), None);
+        assert_eq!(keyframe_blend(lerp(3, 0.25), 3, 8), None);
+        assert_eq!(keyframe_blend(lerp(2, 1.0), 3, 8), None);
+        assert_eq!(keyframe_blend(lerp(2, ::std::f32::NAN), 3, 8), None);
+
+        // single-frame models, and frames from a previous model
+        assert_eq!(keyframe_blend(lerp(0, 0.5), 0, 1), None);
+        assert_eq!(keyframe_blend(lerp(9, 0.5), 3, 8), None);
+    }
+
+    #[test]
+    fn test_frame_lerp() {
+        let frames = [
+            [Vertex {
+                pos: [-3.0, 1.0, 8.0],
+                texcoord: [0.0, 0.0],
+            }],
+            [Vertex {
+                pos: [5.0, -7.0, 8.0],
+                texcoord: [0.0, 0.0],
+            }],
+        ];
+
+        // an entity halfway between server messages after stepping from frame 0 to frame 1
+        let lerp = Some(KeyframeLerp {
+            prev_keyframe_id: 0,
+            factor: 0.5,
+        });
+        let (prev_id, factor) = keyframe_blend(lerp, 1, frames.len()).unwrap();
+        assert_eq!((prev_id, factor), (0, 0.5));
+
+        let blended = blend_vertices(&frames[prev_id], &frames[1], factor);
+        assert_eq!(blended[0].pos, [1.0, -3.0, 8.0]);
+
+        // once the next message arrives the current frame is drawn as is
+        let done = Some(KeyframeLerp {
+            prev_keyframe_id: 0,
+            factor: 1.0,
+        });
+        assert_eq!(keyframe_blend(done, 1, frames.len()), None);
+    }
 }
@@ -0,0 +1,321 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use client::render::{self, Camera, ColorFormat, SceneDepthFormat, Vertex, VERTEX_SHADER_GLSL};
+use common::mdl::Keyframe;
+use common::model::{Model, ModelFlags, ModelKind};
+
+use cgmath::{Matrix4, Vector3};
+use failure::Error;
+use gfx::{self, CommandBuffer, Encoder, Factory, Slice};
+use gfx::handle::{Buffer, DepthStencilView, RenderTargetView};
+use gfx::pso::{PipelineData, PipelineState};
+use gfx::traits::FactoryExt;
+use gfx_device_gl::Resources;
+
+// the collision box of a player, which doesn't depend on the player model
+const PLAYER_MINS: [f32; 3] = [-16.0, -16.0, -24.0];
+const PLAYER_MAXS: [f32; 3] = [16.0, 16.0, 32.0];
+
+const PLAYER_MODEL_NAME: &str = "progs/player.mdl";
+
+pub static BBOX_FRAGMENT_SHADER_GLSL: &[u8] = br#"
+#version 430
+
+in vec2 f_texcoord;
+in float f_fogDistance;
+
+uniform vec4 u_Color;
+
+out vec4 Target0;
+
+void main() {
+    Target0 = u_Color;
+}"#;
+
+gfx_defines! {
+    pipeline pipe_bbox {
+        vertex_buffer: gfx::VertexBuffer<Vertex> = (),
+        transform: gfx::Global<[[f32; 4]; 4]> = "u_Transform",
+        color: gfx::Global<[f32; 4]> = "u_Color",
+        out_color: gfx::RenderTarget<ColorFormat> = "Target0",
+        out_depth: gfx::DepthTarget<SceneDepthFormat> = gfx::preset::depth::LESS_EQUAL_TEST,
+    }
+}
+
+// the 12 edges of the unit cube as a line list
+static BBOX_VERTICES: [Vertex; 24] = [
+    // bottom
+    Vertex { pos: [0.0, 0.0, 0.0], texcoord: [0.0, 0.0] },
+    Vertex { pos: [1.0, 0.0, 0.0], texcoord: [0.0, 0.0] },
+    Vertex { pos: [1.0, 0.0, 0.0], texcoord: [0.0, 0.0] },
+    Vertex { pos: [1.0, 1.0, 0.0], texcoord: [0.0, 0.0] },
+    Vertex { pos: [1.0, 1.0, 0.0], texcoord: [0.0, 0.0] },
+    Vertex { pos: [0.0, 1.0, 0.0], texcoord: [0.0, 0.0] },
+    Vertex { pos: [0.0, 1.0, 0.0], texcoord: [0.0, 0.0] },
+    Vertex { pos: [0.0, 0.0, 0.0], texcoord: [0.0, 0.0] },
+    // top
+    Vertex { pos: [0.0, 0.0, 1.0], texcoord: [0.0, 0.0] },
+    Vertex { pos: [1.0, 0.0, 1.0], texcoord: [0.0, 0.0] },
+    Vertex { pos: [1.0, 0.0, 1.0], texcoord: [0.0, 0.0] },
+    Vertex { pos: [1.0, 1.0, 1.0], texcoord: [0.0, 0.0] },
+    Vertex { pos: [1.0, 1.0, 1.0], texcoord: [0.0, 0.0] },
+    Vertex { pos: [0.0, 1.0, 1.0], texcoord: [0.0, 0.0] },
+    Vertex { pos: [0.0, 1.0, 1.0], texcoord: [0.0, 0.0] },
+    Vertex { pos: [0.0, 0.0, 1.0], texcoord: [0.0, 0.0] },
+    // sides
+    Vertex { pos: [0.0, 0.0, 0.0], texcoord: [0.0, 0.0] },
+    Vertex { pos: [0.0, 0.0, 1.0], texcoord: [0.0, 0.0] },
+    Vertex { pos: [1.0, 0.0, 0.0], texcoord: [0.0, 0.0] },
+    Vertex { pos: [1.0, 0.0, 1.0], texcoord: [0.0, 0.0] },
+    Vertex { pos: [1.0, 1.0, 0.0], texcoord: [0.0, 0.0] },
+    Vertex { pos: [1.0, 1.0, 1.0], texcoord: [0.0, 0.0] },
+    Vertex { pos: [0.0, 1.0, 0.0], texcoord: [0.0, 0.0] },
+    Vertex { pos: [0.0, 1.0, 1.0], texcoord: [0.0, 0.0] },
+];
+
+/// The kind of entity a bounding box belongs to, which determines its color.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BboxKind {
+    Player,
+    Monster,
+    Item,
+    Brush,
+    Other,
+}
+
+impl BboxKind {
+    /// Guesses what kind of entity is using `model`.
+    ///
+    /// The client isn't told entity classnames, so items are recognized by their rotation flag
+    /// or, for ammo and health boxes, by their `maps/b_` brush model name. Any other alias model
+    /// is assumed to be a monster.
+    pub fn classify(model: &Model) -> BboxKind {
+        if model.name() == PLAYER_MODEL_NAME {
+            return BboxKind::Player;
+        }
+
+        if model.has_flag(ModelFlags::ROTATE) || model.name().starts_with("maps/b_") {
+            return BboxKind::Item;
+        }
+
+        match *model.kind() {
+            ModelKind::Alias(_) => BboxKind::Monster,
+            ModelKind::Brush(_) => BboxKind::Brush,
+            _ => BboxKind::Other,
+        }
+    }
+
+    pub fn color(&self) -> [f32; 4] {
+        match *self {
+            BboxKind::Player => [0.0, 1.0, 0.0, 1.0],
+            BboxKind::Monster => [1.0, 0.0, 0.0, 1.0],
+            BboxKind::Item => [1.0, 1.0, 0.0, 1.0],
+            BboxKind::Brush => [0.0, 0.5, 1.0, 1.0],
+            BboxKind::Other => [1.0, 1.0, 1.0, 1.0],
+        }
+    }
+}
+
+/// The bounding boxes of a model, relative to the origin of an entity using it.
+#[derive(Clone, Debug)]
+pub struct ModelBounds {
+    kind: BboxKind,
+
+    // one box per keyframe for alias models, or a single box for everything else
+    frames: Vec<(Vector3<f32>, Vector3<f32>)>,
+}
+
+impl ModelBounds {
+    /// Returns the bounds of `model`, or `None` for the null model.
+    ///
+    /// Players use the player collision hull and brush models use their own extents, which is
+    /// what they collide with. Monster collision sizes are set by the server and aren't sent to
+    /// the client, so other alias models use the extents of each keyframe instead.
+    pub fn from_model(model: &Model) -> Option<ModelBounds> {
+        let kind = BboxKind::classify(model);
+        let frames = match *model.kind() {
+            ModelKind::None => return None,
+            _ if kind == BboxKind::Player => {
+                vec![(PLAYER_MINS.into(), PLAYER_MAXS.into())]
+            }
+            ModelKind::Alias(ref amodel) => amodel
+                .keyframes()
+                .iter()
+                .map(|k| match *k {
+                    Keyframe::Static(ref s) => (s.min(), s.max()),
+                    Keyframe::Animated(ref a) => (a.min(), a.max()),
+                })
+                .collect(),
+            _ => vec![(model.min(), model.max())],
+        };
+
+        Some(ModelBounds { kind, frames })
+    }
+
+    pub fn kind(&self) -> BboxKind {
+        self.kind
+    }
+
+    /// Returns the bounds of the model in the given frame.
+    pub fn bounds(&self, frame_id: usize) -> Option<(Vector3<f32>, Vector3<f32>)> {
+        match self.frames.len() {
+            0 => None,
+            1 => Some(self.frames[0]),
+            _ => self.frames.get(frame_id).cloned(),
+        }
+    }
+}
+
+/// Returns the transform that maps the unit cube onto the box from `min` to `max`.
+///
+/// The result is in OpenGL coordinates, since the vertex shader converts the cube's vertices
+/// before applying it.
+pub fn bbox_transform(min: Vector3<f32>, max: Vector3<f32>) -> Matrix4<f32> {
+    let size = max - min;
+    Matrix4::from_translation(Vector3::new(-min.y, min.z, -min.x))
+        * Matrix4::from_nonuniform_scale(size.y, size.z, size.x)
+}
+
+/// Draws wireframe bounding boxes for `r_showbboxes`.
+pub struct BboxRenderer {
+    pipeline_state:
+        PipelineState<Resources, <pipe_bbox::Data<Resources> as PipelineData<Resources>>::Meta>,
+    vertex_buffer: Buffer<Resources, Vertex>,
+    color_target: RenderTargetView<Resources, ColorFormat>,
+    depth_target: DepthStencilView<Resources, SceneDepthFormat>,
+}
+
+impl BboxRenderer {
+    pub fn new<F>(
+        factory: &mut F,
+        color_target: RenderTargetView<Resources, ColorFormat>,
+        depth_target: DepthStencilView<Resources, SceneDepthFormat>,
+        reverse_z: bool,
+    ) -> Result<BboxRenderer, Error>
+    where
+        F: Factory<Resources>,
+    {
+        let shader_set = factory.create_shader_set(VERTEX_SHADER_GLSL, BBOX_FRAGMENT_SHADER_GLSL)?;
+        let pipeline_state = factory.create_pipeline_state(
+            &shader_set,
+            gfx::Primitive::LineList,
+            gfx::state::Rasterizer::new_fill(),
+            pipe_bbox::Init {
+                out_depth: gfx::state::Depth {
+                    write: false,
+                    ..render::depth_state(reverse_z)
+                },
+                ..pipe_bbox::new()
+            },
+        )?;
+
+        Ok(BboxRenderer {
+            pipeline_state,
+            vertex_buffer: factory.create_vertex_buffer(&BBOX_VERTICES),
+            color_target,
+            depth_target,
+        })
+    }
+
+    /// Draws the box from `min` to `max`, in world coordinates, in the color of `kind`.
+    pub fn render<C>(
+        &self,
+        encoder: &mut Encoder<Resources, C>,
+        camera: &Camera,
+        min: Vector3<f32>,
+        max: Vector3<f32>,
+        kind: BboxKind,
+    ) where
+        C: CommandBuffer<Resources>,
+    {
+        let data = pipe_bbox::Data {
+            vertex_buffer: self.vertex_buffer.clone(),
+            transform: (camera.transform() * bbox_transform(min, max)).into(),
+            color: kind.color(),
+            out_color: self.color_target.clone(),
+            out_depth: self.depth_target.clone(),
+        };
+
+        encoder.draw(
+            &Slice::new_match_vertex_buffer(&self.vertex_buffer),
+            &self.pipeline_state,
+            &data,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use cgmath::Vector4;
+
+    // the same conversion the vertex shader applies
+    fn to_gl(v: Vector3<f32>) -> Vector4<f32> {
+        Vector4::new(-v.y, v.z, -v.x, 1.0)
+    }
+
+    #[test]
+    fn test_bbox_transform_corners() {
+        let min = Vector3::new(-16.0, -8.0, -24.0);
+        let max = Vector3::new(16.0, 8.0, 32.0);
+        let transform = bbox_transform(min, max);
+
+        assert_eq!(transform * to_gl(Vector3::new(0.0, 0.0, 0.0)), to_gl(min));
+        assert_eq!(transform * to_gl(Vector3::new(1.0, 1.0, 1.0)), to_gl(max));
+        assert_eq!(
+            transform * to_gl(Vector3::new(1.0, 0.0, 1.0)),
+            to_gl(Vector3::new(16.0, -8.0, 32.0))
+        );
+    }
+
+    #[test]
+    fn test_model_bounds_single_frame() {
+        let bounds = ModelBounds {
+            kind: BboxKind::Brush,
+            frames: vec![(Vector3::new(-1.0, -1.0, -1.0), Vector3::new(1.0, 1.0, 1.0))],
+        };
+
+        // brush models have no frames, so every frame uses the same box
+        assert_eq!(bounds.bounds(0), bounds.bounds(5));
+        assert!(ModelBounds {
+            kind: BboxKind::Other,
+            frames: Vec::new(),
+        }
+        .bounds(0)
+        .is_none());
+    }
+
+    #[test]
+    fn test_model_bounds_per_frame() {
+        let frame = |z| (Vector3::new(-16.0, -16.0, -24.0), Vector3::new(16.0, 16.0, z));
+        let bounds = ModelBounds {
+            kind: BboxKind::Monster,
+            frames: vec![frame(40.0), frame(8.0)],
+        };
+
+        assert_eq!(bounds.bounds(1), Some(frame(8.0)));
+        assert_eq!(bounds.bounds(2), None);
+    }
+
+    #[test]
+    fn test_classify_null_model() {
+        assert_eq!(BboxKind::classify(&Model::none()), BboxKind::Other);
+        assert!(ModelBounds::from_model(&Model::none()).is_none());
+    }
+}
@@ -15,10 +15,21 @@
 // DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+use std::io::Write;
 use std::rc::Rc;
 
-use client::render::{self, Camera, ColorFormat, DepthFormat, Palette};
-use common::bsp::{BspData, BspFace, BspModel, BspTexInfo, BspTextureMipmap, MIPLEVELS};
+use client::render::lightmap::{LightmapSurface, Lightmaps};
+use client::render::{
+    self, Camera, ColorFormat, DummyTexturePattern, Frustum, Palette, SceneDepthFormat,
+};
+use client::Fog;
+use common::bsp::{
+    BspData, BspFace, BspLiquid, BspModel, BspTexInfo, BspTexture, BspTextureMipmap, MIPLEVELS,
+};
+use common::console::CvarRegistry;
+use common::engine;
+use common::png::{self, PngColor};
+use common::vfs::Vfs;
 
 use cgmath::{Deg, Euler, InnerSpace, Matrix4, SquareMatrix, Vector3};
 use chrono::Duration;
@@ -43,6 +54,7 @@ layout (location = 2) in vec2 a_LightmapTexcoord;
 
 out vec2 f_diffuseTexcoord;
 out vec2 f_lightmapTexcoord;
+out float f_fogDistance;
 
 uniform mat4 u_Transform;
 
@@ -50,6 +62,7 @@ void main() {
     f_diffuseTexcoord = a_DiffuseTexcoord;
     f_lightmapTexcoord = a_LightmapTexcoord;
     gl_Position = u_Transform * vec4(-a_Position.y, a_Position.z, -a_Position.x, 1.0);
+    f_fogDistance = gl_Position.w;
 }
 "#;
 
@@ -59,19 +72,34 @@ pub static BRUSH_FRAGMENT_SHADER_GLSL: &[u8] = br#"
 
 in vec2 f_diffuseTexcoord;
 in vec2 f_lightmapTexcoord;
+in float f_fogDistance;
 
 uniform vec4 u_LightstyleValue;
+uniform vec4 u_FogColor;
+uniform float u_FogDensity;
+uniform float u_Alpha;
+uniform float u_Time;
+uniform float u_Warp;
 uniform sampler2D u_Texture;
 uniform sampler2D u_Fullbright;
 uniform sampler2D u_Lightmap;
 
 out vec4 Target0;
 
+// the turbulence of liquid surfaces, as in the original engine's EmitWaterPolys: each texcoord is
+// displaced by up to 8 texels along a sine wave of the other, with a period of 16 pi texels
+vec2 turbulence(vec2 texcoord) {
+    vec2 size = vec2(textureSize(u_Texture, 0));
+    vec2 texel = texcoord * size;
+    return (texel + 8.0 * sin(texel.yx * 0.125 + u_Time)) / size;
+}
+
 void main() {
-    vec4 base_color = texture(u_Texture, f_diffuseTexcoord);
+    vec2 diffuse_texcoord = mix(f_diffuseTexcoord, turbulence(f_diffuseTexcoord), u_Warp);
+    vec4 base_color = texture(u_Texture, diffuse_texcoord);
     vec4 lightmap = texture(u_Lightmap, f_lightmapTexcoord);
 
-    vec4 lightmapped_color = vec4(base_color.rgb * lightmap.rrr, 1.0);
+    vec4 lightmapped_color = vec4(base_color.rgb * lightmap.rgb, 1.0);
 
     int lightstyle_count = 0;
     float light_factor = 0.0;
@@ -90,9 +118,14 @@ void main() {
         light_factor = 1.0;
     }
 
-    float fullbright_factor = texture(u_Fullbright, f_diffuseTexcoord).r;
+    float fullbright_factor = texture(u_Fullbright, diffuse_texcoord).r;
+
+    vec4 color = mix(lightmapped_color * light_factor, base_color, fullbright_factor);
 
-    Target0 = mix(lightmapped_color * light_factor, base_color, fullbright_factor);
+    // exponential squared fog
+    float fog_exponent = u_FogDensity * f_fogDistance;
+    float fog_factor = clamp(exp2(-1.442695 * fog_exponent * fog_exponent), 0.0, 1.0);
+    Target0 = vec4(mix(u_FogColor.rgb, color.rgb, fog_factor), u_Alpha);
 }"#;
 
 gfx_defines! {
@@ -106,11 +139,17 @@ gfx_defines! {
         vertex_buffer: gfx::VertexBuffer<BrushVertex> = (),
         transform: gfx::Global<[[f32; 4]; 4]> = "u_Transform",
         lightstyle_value: gfx::Global<[f32; 4]> = "u_LightstyleValue",
+        fog_color: gfx::Global<[f32; 4]> = "u_FogColor",
+        fog_density: gfx::Global<f32> = "u_FogDensity",
+        alpha: gfx::Global<f32> = "u_Alpha",
+        time: gfx::Global<f32> = "u_Time",
+        warp: gfx::Global<f32> = "u_Warp",
         diffuse_sampler: gfx::TextureSampler<[f32; 4]> = "u_Texture",
         fullbright_sampler: gfx::TextureSampler<f32> = "u_Fullbright",
-        lightmap_sampler: gfx::TextureSampler<f32> = "u_Lightmap",
-        out_color: gfx::RenderTarget<ColorFormat> = "Target0",
-        out_depth: gfx::DepthTarget<DepthFormat> = gfx::preset::depth::LESS_EQUAL_WRITE,
+        lightmap_sampler: gfx::TextureSampler<[f32; 4]> = "u_Lightmap",
+        out_color: gfx::BlendTarget<ColorFormat> =
+            ("Target0", gfx::state::ColorMask::all(), gfx::preset::blend::ALPHA),
+        out_depth: gfx::DepthTarget<SceneDepthFormat> = gfx::preset::depth::LESS_EQUAL_WRITE,
     }
 }
 
@@ -123,8 +162,218 @@ pub type BrushPipelineData = pipe_brush::Data<Resources>;
 pub struct BrushRenderFace {
     pub slice: Slice<Resources>,
     pub tex_id: usize,
+    pub liquid: Option<BspLiquid>,
+
+    /// The lightmap atlas page holding this face's lightmap.
     pub lightmap_id: Option<usize>,
+
+    /// The face's lightmap within its atlas page.
+    pub lightmap_rect: Option<LightmapAtlasRect>,
     pub light_styles: [u8; 4],
+
+    /// The bounding box of the face's vertices in model space, used for frustum culling.
+    pub mins: Vector3<f32>,
+    pub maxs: Vector3<f32>,
+}
+
+impl BrushRenderFace {
+    /// Returns the distance from `point` to the center of the face's bounding box.
+    pub fn distance(&self, point: Vector3<f32>) -> f32 {
+        ((self.mins + self.maxs) * 0.5 - point).magnitude()
+    }
+}
+
+/// Sorts face IDs, paired with their distances from the camera, from farthest to nearest.
+pub fn sort_faces_back_to_front(faces: &mut [(usize, f32)]) {
+    faces.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(::std::cmp::Ordering::Equal));
+}
+
+/// The width and height of a lightmap atlas page, in luxels.
+pub const LIGHTMAP_ATLAS_SIZE: usize = 512;
+
+/// The number of bytes in each luxel of a lightmap atlas page (red, green, blue and an unused
+/// alpha channel).
+pub const LUXEL_BYTES: usize = 4;
+
+/// The location of a face's lightmap in a `LightmapAtlas`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LightmapAtlasRect {
+    pub page: usize,
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl LightmapAtlasRect {
+    /// Maps lightmap texcoords for the face alone to texcoords in its atlas page.
+    pub fn atlas_texcoords(&self, texcoords: [f32; 2], page_size: usize) -> [f32; 2] {
+        [
+            (self.x as f32 + texcoords[0] * self.width as f32) / page_size as f32,
+            (self.y as f32 + texcoords[1] * self.height as f32) / page_size as f32,
+        ]
+    }
+}
+
+struct LightmapAtlasPage {
+    // the height of the filled area in each column
+    skyline: Vec<usize>,
+    luxels: Vec<u8>,
+}
+
+impl LightmapAtlasPage {
+    fn new(size: usize) -> LightmapAtlasPage {
+        LightmapAtlasPage {
+            skyline: vec![0; size],
+            luxels: vec![0; size * size * LUXEL_BYTES],
+        }
+    }
+
+    // finds the lowest spot that fits a `width` by `height` block, like `AllocBlock` in the
+    // original engine
+    fn allocate(&mut self, size: usize, width: usize, height: usize) -> Option<(usize, usize)> {
+        let mut best_y = size;
+        let mut best_x = None;
+        for x in 0..(size + 1).saturating_sub(width) {
+            let y = self.skyline[x..x + width].iter().cloned().max().unwrap_or(0);
+
+            if y < best_y {
+                best_y = y;
+                best_x = Some(x);
+            }
+        }
+
+        let x = best_x?;
+        if best_y + height > size {
+            return None;
+        }
+
+        for column in self.skyline[x..x + width].iter_mut() {
+            *column = best_y + height;
+        }
+
+        Some((x, best_y))
+    }
+}
+
+/// Packs the lightmaps of many faces into a few large textures.
+///
+/// Binding one texture per face means thousands of tiny textures and texture switches on large
+/// maps. Instead, lightmaps are packed into square pages with a skyline packer, and faces are
+/// drawn with texcoords adjusted to their region of the page.
+pub struct LightmapAtlas {
+    size: usize,
+    pages: Vec<LightmapAtlasPage>,
+}
+
+impl LightmapAtlas {
+    pub fn new(size: usize) -> LightmapAtlas {
+        LightmapAtlas {
+            size,
+            pages: Vec::new(),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Returns the RGBA luxels of a page, row by row.
+    pub fn page_luxels(&self, page: usize) -> &[u8] {
+        &self.pages[page].luxels
+    }
+
+    /// Copies a `width` by `height` lightmap of RGB luxels into the atlas and returns where it
+    /// was placed.
+    ///
+    /// A new page is started when the lightmap doesn't fit in the last one. Returns `None` if the
+    /// lightmap is larger than a page.
+    pub fn insert(&mut self, width: usize, height: usize, luxels: &[u8]) -> Option<LightmapAtlasRect> {
+        if width == 0 || height == 0 || width > self.size || height > self.size {
+            return None;
+        }
+
+        let size = self.size;
+        let placed = match self.pages.last_mut() {
+            Some(page) => page.allocate(size, width, height),
+            None => None,
+        };
+
+        let (x, y) = match placed {
+            Some(xy) => xy,
+            None => {
+                let mut page = LightmapAtlasPage::new(size);
+                let xy = page.allocate(size, width, height)?;
+                self.pages.push(page);
+                xy
+            }
+        };
+
+        let page_id = self.pages.len() - 1;
+        let page = &mut self.pages[page_id];
+        for row in 0..height {
+            for col in 0..width {
+                let src = 3 * (row * width + col);
+                let dst = LUXEL_BYTES * ((y + row) * size + x + col);
+                page.luxels[dst..dst + 3].copy_from_slice(&luxels[src..src + 3]);
+                page.luxels[dst + 3] = 255;
+            }
+        }
+
+        Some(LightmapAtlasRect {
+            page: page_id,
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+}
+
+/// The opacity of each kind of liquid surface.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LiquidAlpha {
+    pub water: f32,
+    pub slime: f32,
+    pub lava: f32,
+    pub teleport: f32,
+}
+
+impl LiquidAlpha {
+    /// Reads liquid opacities from `r_wateralpha`, `r_slimealpha`, `r_lavaalpha` and
+    /// `r_telealpha`, clamping each to `[0, 1]`.
+    pub fn from_cvars(cvars: &CvarRegistry) -> LiquidAlpha {
+        let get = |name| {
+            let alpha = cvars.get_value(name).unwrap_or(1.0);
+            if alpha.is_nan() {
+                1.0
+            } else {
+                alpha.max(0.0).min(1.0)
+            }
+        };
+
+        LiquidAlpha {
+            water: get("r_wateralpha"),
+            slime: get("r_slimealpha"),
+            lava: get("r_lavaalpha"),
+            teleport: get("r_telealpha"),
+        }
+    }
+
+    /// Returns the opacity of a surface with the given liquid, or 1 if it isn't a liquid.
+    pub fn alpha(&self, liquid: Option<BspLiquid>) -> f32 {
+        match liquid {
+            Some(BspLiquid::Water) => self.water,
+            Some(BspLiquid::Slime) => self.slime,
+            Some(BspLiquid::Lava) => self.lava,
+            Some(BspLiquid::Teleport) => self.teleport,
+            None => 1.0,
+        }
+    }
 }
 
 /// An object responsible for drawing brush models.
@@ -134,22 +383,140 @@ pub struct BrushRenderer {
     faces: Box<[BrushRenderFace]>,
     texture_views: Box<[ShaderResourceView<Resources, [f32; 4]>]>,
     fullbright_views: Box<[ShaderResourceView<Resources, f32>]>,
-    lightmap_views: Box<[ShaderResourceView<Resources, f32>]>,
+    lightmaps: Lightmaps,
 
     pipeline_state: BrushPipelineState,
+    translucent_pipeline_state: BrushPipelineState,
     vertex_buffer: Buffer<Resources, BrushVertex>,
     dummy_texture: ShaderResourceView<Resources, [f32; 4]>,
     dummy_fullbright: ShaderResourceView<Resources, f32>,
-    dummy_lightmap: ShaderResourceView<Resources, f32>,
+    dummy_lightmap: ShaderResourceView<Resources, [f32; 4]>,
 
     diffuse_sampler: Sampler<Resources>,
     fullbright_sampler: Sampler<Resources>,
     lightmap_sampler: Sampler<Resources>,
     color_target: RenderTargetView<Resources, ColorFormat>,
-    depth_target: DepthStencilView<Resources, DepthFormat>,
+    depth_target: DepthStencilView<Resources, SceneDepthFormat>,
 }
 
-pub fn create_pipeline_state<F>(factory: &mut F) -> Result<BrushPipelineState, Error>
+/// Creates the sampler used for lightmaps.
+///
+/// Bilinear filtering smooths out lightmaps; nearest-neighbor filtering gives the blocky look of
+/// the software renderer.
+pub fn create_lightmap_sampler<F>(factory: &mut F, bilinear: bool) -> Sampler<Resources>
+where
+    F: Factory<Resources>,
+{
+    let filter = if bilinear {
+        gfx::texture::FilterMethod::Bilinear
+    } else {
+        gfx::texture::FilterMethod::Scale
+    };
+
+    factory.create_sampler(gfx::texture::SamplerInfo::new(
+        filter,
+        gfx::texture::WrapMode::Tile,
+    ))
+}
+
+/// Filtering presets for world and brush model textures, chosen with `r_texturequality`.
+///
+/// - `low`: nearest-neighbor filtering without mipmaps, like the software renderer.
+/// - `medium`: nearest-neighbor filtering with mipmaps, which stops distant textures shimmering.
+/// - `high`: trilinear filtering.
+/// - `ultra`: trilinear filtering with 16x anisotropic filtering, which keeps textures sharp at
+///   glancing angles.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextureQuality {
+    Low,
+    Medium,
+    High,
+    Ultra,
+}
+
+impl TextureQuality {
+    /// Parses a preset name, or its number from 0 (`low`) to 3 (`ultra`).
+    pub fn parse<S>(value: S) -> Option<TextureQuality>
+    where
+        S: AsRef<str>,
+    {
+        match value.as_ref().trim().to_lowercase().as_str() {
+            "low" | "0" => Some(TextureQuality::Low),
+            "medium" | "1" => Some(TextureQuality::Medium),
+            "high" | "2" => Some(TextureQuality::High),
+            "ultra" | "3" => Some(TextureQuality::Ultra),
+            _ => None,
+        }
+    }
+
+    pub fn filter_method(&self) -> texture::FilterMethod {
+        match *self {
+            TextureQuality::Low => texture::FilterMethod::Scale,
+            TextureQuality::Medium => texture::FilterMethod::Mipmap,
+            TextureQuality::High => texture::FilterMethod::Trilinear,
+            TextureQuality::Ultra => texture::FilterMethod::Anisotropic(16),
+        }
+    }
+}
+
+/// Parses a `gl_texturemode` value into the filter used for world and brush model textures.
+///
+/// Mode names are matched case-insensitively, with or without the `GL_` prefix. gfx can't express
+/// every OpenGL filter combination, so the modes map onto the closest `FilterMethod`:
+///
+/// | Mode                        | `FilterMethod` |
+/// |-----------------------------|----------------|
+/// | `GL_NEAREST`                | `Scale`        |
+/// | `GL_LINEAR`                 | `Bilinear`     |
+/// | `GL_NEAREST_MIPMAP_NEAREST` | `Mipmap`       |
+/// | `GL_NEAREST_MIPMAP_LINEAR`  | `Mipmap`       |
+/// | `GL_LINEAR_MIPMAP_NEAREST`  | `Trilinear`    |
+/// | `GL_LINEAR_MIPMAP_LINEAR`   | `Trilinear`    |
+///
+/// Returns `None` for unrecognized modes.
+pub fn parse_texture_mode<S>(value: S) -> Option<texture::FilterMethod>
+where
+    S: AsRef<str>,
+{
+    let value = value.as_ref().trim().to_uppercase();
+    let mode = if value.starts_with("GL_") {
+        &value[3..]
+    } else {
+        &value[..]
+    };
+
+    match mode {
+        "NEAREST" => Some(texture::FilterMethod::Scale),
+        "LINEAR" => Some(texture::FilterMethod::Bilinear),
+        "NEAREST_MIPMAP_NEAREST" | "NEAREST_MIPMAP_LINEAR" => Some(texture::FilterMethod::Mipmap),
+        "LINEAR_MIPMAP_NEAREST" | "LINEAR_MIPMAP_LINEAR" => Some(texture::FilterMethod::Trilinear),
+        _ => None,
+    }
+}
+
+/// Creates the sampler used for diffuse textures.
+pub fn create_diffuse_sampler<F>(
+    factory: &mut F,
+    filter: texture::FilterMethod,
+) -> Sampler<Resources>
+where
+    F: Factory<Resources>,
+{
+    factory.create_sampler(gfx::texture::SamplerInfo::new(
+        filter,
+        gfx::texture::WrapMode::Tile,
+    ))
+}
+
+/// Creates the pipeline state for brush faces.
+///
+/// Translucent faces are blended over what has already been drawn, so their pipeline tests against
+/// the depth buffer without writing to it.
+pub fn create_pipeline_state<F>(
+    factory: &mut F,
+    reverse_z: bool,
+    translucent: bool,
+) -> Result<BrushPipelineState, Error>
 where
     F: Factory<Resources>
 {
@@ -168,108 +535,215 @@ where
             offset: None,
             samples: Some(gfx::state::MultiSample),
         },
-        pipe_brush::new(),
+        pipe_brush::Init {
+            out_depth: gfx::state::Depth {
+                write: !translucent,
+                ..super::depth_state(reverse_z)
+            },
+            ..pipe_brush::new()
+        },
     )?;
 
     Ok(pipeline)
 }
 
-// FIXME: this calculation is (very slightly) off. not sure why.
+// luxels are spaced this many texels apart on a surface
+pub(super) const LUXEL_SIZE: f32 = 16.0;
+
+// Lightmaps have `extents / 16 + 1` luxels along each axis, with luxel `i` sampling the surface at
+// `texture_mins + 16 * i`. The texcoord is offset by half a luxel so each sample lands at the
+// center of its texel rather than on the edge between two.
 fn calculate_lightmap_texcoords(
     position: Vector3<f32>,
     face: &BspFace,
     texinfo: &BspTexInfo,
 ) -> [f32; 2] {
+    let lightmap_size = |axis: usize| (face.extents[axis] / 16 + 1) as f32;
+
     let mut s = texinfo.s_vector.dot(position) + texinfo.s_offset;
     s -= face.texture_mins[0] as f32;
-    s /= face.extents[0] as f32;
+    s = (s / LUXEL_SIZE + 0.5) / lightmap_size(0);
 
     let mut t = texinfo.t_vector.dot(position) + texinfo.t_offset;
     t -= face.texture_mins[1] as f32;
-    t /= face.extents[1] as f32;
+    t = (t / LUXEL_SIZE + 0.5) / lightmap_size(1);
     [s, t]
 }
 
+// pieces of a subdivided polygon are never thinner than this along any axis
+const SUBDIVIDE_MARGIN: f32 = 8.0;
+
+/// Returns the liquid subdivision size for the given value of `gl_subdivide_size`.
+///
+/// Values of zero or less (or NaN) disable subdivision.
+pub fn subdivide_size(value: f32) -> Option<f32> {
+    if value > 0.0 {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+// splits a convex polygon in two along the plane where the given axis equals `dist`
+fn split_polygon(
+    points: &[Vector3<f32>],
+    axis: usize,
+    dist: f32,
+) -> (Vec<Vector3<f32>>, Vec<Vector3<f32>>) {
+    let mut front = Vec::new();
+    let mut back = Vec::new();
+
+    for (i, p) in points.iter().enumerate() {
+        let q = points[(i + 1) % points.len()];
+        let p_dist = p[axis] - dist;
+        let q_dist = q[axis] - dist;
+
+        if p_dist >= 0.0 {
+            front.push(*p);
+        }
+        if p_dist <= 0.0 {
+            back.push(*p);
+        }
+
+        // add a vertex where the edge crosses the plane
+        if p_dist != 0.0 && q_dist != 0.0 && (p_dist > 0.0) != (q_dist > 0.0) {
+            let mid = p + (q - p) * (p_dist / (p_dist - q_dist));
+            front.push(mid);
+            back.push(mid);
+        }
+    }
+
+    (front, back)
+}
+
+/// Recursively splits a convex polygon along axis-aligned planes spaced `size` units apart.
+///
+/// This is how the original engine tessellates liquid surfaces so that they can be warped per
+/// vertex. Each piece is clipped from the original polygon, so together the pieces cover exactly
+/// the same area.
+pub fn subdivide_polygon(points: &[Vector3<f32>], size: f32) -> Vec<Vec<Vector3<f32>>> {
+    let mut mins = Vector3::new(::std::f32::MAX, ::std::f32::MAX, ::std::f32::MAX);
+    let mut maxs = Vector3::new(::std::f32::MIN, ::std::f32::MIN, ::std::f32::MIN);
+    for p in points {
+        for axis in 0..3 {
+            mins[axis] = mins[axis].min(p[axis]);
+            maxs[axis] = maxs[axis].max(p[axis]);
+        }
+    }
+
+    for axis in 0..3 {
+        // split on the grid line closest to the middle of the polygon
+        let mid = (mins[axis] + maxs[axis]) / 2.0;
+        let dist = size * (mid / size + 0.5).floor();
+        if maxs[axis] - dist < SUBDIVIDE_MARGIN || dist - mins[axis] < SUBDIVIDE_MARGIN {
+            continue;
+        }
+
+        let (front, back) = split_polygon(points, axis, dist);
+        let mut polygons = subdivide_polygon(&front, size);
+        polygons.append(&mut subdivide_polygon(&back, size));
+        return polygons;
+    }
+
+    vec![points.to_vec()]
+}
+
 // TODO: remove collinear points in faces
 // Converts a brush model face from edge-based layout to triangle list layout.
 //
 // The newly created `BrushVertex` vertices will be stored in `vertices`. The index of this face's
 // first vertex in `vertices`, and the number of vertices pushed, will be stored in this face object
 // for rendering.
-pub(super) fn create_brush_render_face<F>(
-    factory: &mut F,
+//
+// Liquid faces are subdivided into a grid of `subdivide_size` units, if given, so that they have
+// enough vertices to warp smoothly.
+//
+// Faces with tool textures (clip, trigger, etc.) are never drawn and sky faces are drawn by the
+// `SkyRenderer`, so `None` is returned for them and nothing is added to `vertices`.
+pub(super) fn create_brush_render_face(
     bsp_data: &BspData,
     face_id: usize,
     vertices: &mut Vec<BrushVertex>,
-    lightmap_views: &mut Vec<ShaderResourceView<Resources, f32>>,
-) -> Result<BrushRenderFace, Error>
-where
-    F: Factory<Resources>,
+    lightmap_atlas: &mut LightmapAtlas,
+    subdivide_size: Option<f32>,
+) -> Result<Option<BrushRenderFace>, Error>
 {
     let face = &bsp_data.faces()[face_id];
     let face_vert_id = vertices.len();
     let texinfo = &bsp_data.texinfo()[face.texinfo_id];
     let tex = &bsp_data.textures()[texinfo.tex_id];
-    let face_edge_ids = &bsp_data.edgelist()[face.edge_id..face.edge_id + face.edge_count];
-    let base_vertex_id = bsp_data.edges()[face_edge_ids[0].index].vertex_ids[face_edge_ids[0].direction as usize];
-    let base_position = bsp_data.vertices()[base_vertex_id as usize];
-    let base_diffuse_texcoords = [
-        (base_position.dot(texinfo.s_vector) + texinfo.s_offset) / tex.width() as f32,
-        (base_position.dot(texinfo.t_vector) + texinfo.t_offset) / tex.height() as f32,
-    ];
-
-    // start at 1 since we've already calculated the base vertex
-    // stop at len - 1 because we use two vertices per iteration
-    for i in 1..face_edge_ids.len() - 1 {
-        // push the base vertex of the fan
-        vertices.push(BrushVertex {
-            position: base_position.into(),
-            diffuse_texcoord: base_diffuse_texcoords,
-            lightmap_texcoord: calculate_lightmap_texcoords(base_position, face, texinfo),
-        });
 
-        // push the ith and (i + 1)th vertices
-        for v in 0..2 {
-            let edge_id = &face_edge_ids[i + v];
-            let vertex_id = bsp_data.edges()[edge_id.index].vertex_ids[edge_id.direction as usize];
-            let position = bsp_data.vertices()[vertex_id as usize];
-            let diffuse_texcoords = [
-                (position.dot(texinfo.s_vector) + texinfo.s_offset) / tex.width() as f32,
-                (position.dot(texinfo.t_vector) + texinfo.t_offset) / tex.height() as f32,
-            ];
-            vertices.push(BrushVertex {
-                position: position.into(),
-                diffuse_texcoord: diffuse_texcoords,
-                lightmap_texcoord: calculate_lightmap_texcoords(position, face, texinfo),
-            })
-        }
+    if tex.is_tool() || tex.is_sky() {
+        return Ok(None);
     }
+    let face_edge_ids = &bsp_data.edgelist()[face.edge_id..face.edge_id + face.edge_count];
+    let polygon: Vec<Vector3<f32>> = face_edge_ids
+        .iter()
+        .map(|edge_id| {
+            let vertex_id = bsp_data.edges()[edge_id.index].vertex_ids[edge_id.direction as usize];
+            bsp_data.vertices()[vertex_id as usize]
+        })
+        .collect();
 
-    let lightmap_w = face.extents[0] / 16 + 1;
-    let lightmap_h = face.extents[1] / 16 + 1;
-    let lightmap_size = lightmap_w * lightmap_h;
-
-    let lightmap_id = if !texinfo.special {
-        if let Some(ofs) = face.lightmap_id {
-            let lightmap_data = &bsp_data.lightmaps()[ofs..ofs + lightmap_size as usize];
-            let (_lightmap_handle, lightmap_view) = factory.create_texture_immutable_u8::<(R8, Unorm)>(
-                texture::Kind::D2(lightmap_w as u16, lightmap_h as u16, texture::AaMode::Single),
-                texture::Mipmap::Allocated,
-                &[lightmap_data],
-            )?;
-            let l_id = lightmap_views.len();
-            lightmap_views.push(lightmap_view);
-            Some(l_id)
-        } else {
-            None
+    let polygons = match subdivide_size {
+        Some(size) if tex.liquid().is_some() => subdivide_polygon(&polygon, size),
+        _ => vec![polygon],
+    };
+
+    let lightmap_rect = if !texinfo.special {
+        match bsp_data.face_lightmap_rgb(face_id) {
+            Some((lightmap_w, lightmap_h, lightmap_data)) => {
+                let rect = lightmap_atlas.insert(lightmap_w, lightmap_h, &lightmap_data);
+                if rect.is_none() {
+                    warn!("Lightmap of face {} is too large for the atlas", face_id);
+                }
+                rect
+            }
+            None => None,
         }
     } else {
         None
     };
 
+    let atlas_size = lightmap_atlas.size();
+    let make_vertex = |position: Vector3<f32>| BrushVertex {
+        position: position.into(),
+        diffuse_texcoord: [
+            (position.dot(texinfo.s_vector) + texinfo.s_offset) / tex.width() as f32,
+            (position.dot(texinfo.t_vector) + texinfo.t_offset) / tex.height() as f32,
+        ],
+        lightmap_texcoord: {
+            let texcoords = calculate_lightmap_texcoords(position, face, texinfo);
+            match lightmap_rect {
+                Some(rect) => rect.atlas_texcoords(texcoords, atlas_size),
+                None => texcoords,
+            }
+        },
+    };
+
+    let mut mins = Vector3::new(::std::f32::MAX, ::std::f32::MAX, ::std::f32::MAX);
+    let mut maxs = Vector3::new(::std::f32::MIN, ::std::f32::MIN, ::std::f32::MIN);
+    for polygon in polygons.iter() {
+        for p in polygon.iter() {
+            for axis in 0..3 {
+                mins[axis] = mins[axis].min(p[axis]);
+                maxs[axis] = maxs[axis].max(p[axis]);
+            }
+        }
+    }
+
+    for polygon in polygons {
+        // convert the polygon's triangle fan to a triangle list
+        for i in 1..polygon.len().saturating_sub(1) {
+            vertices.push(make_vertex(polygon[0]));
+            vertices.push(make_vertex(polygon[i]));
+            vertices.push(make_vertex(polygon[i + 1]));
+        }
+    }
+
     let face_vert_count = vertices.len() - face_vert_id;
 
-    Ok(BrushRenderFace {
+    Ok(Some(BrushRenderFace {
         slice: Slice {
             start: 0,
             end: face_vert_count as u32,
@@ -278,9 +752,178 @@ where
             buffer: IndexBuffer::Auto,
         },
         tex_id: texinfo.tex_id,
-        lightmap_id,
+        liquid: tex.liquid(),
+        lightmap_id: lightmap_rect.map(|r| r.page),
+        lightmap_rect,
         light_styles: face.light_styles,
-    })
+        mins,
+        maxs,
+    }))
+}
+
+/// Merges faces that can be drawn with the same pipeline state into batches.
+///
+/// Faces are grouped by texture, lightmap page and light styles, and their vertices are copied into a
+/// new vertex list so that each batch covers a single contiguous range, with a bounding box
+/// covering all of its faces. The returned batches are
+/// sorted by texture so that consecutive draws share textures where possible.
+pub(super) fn batch_brush_render_faces<V>(
+    mut faces: Vec<BrushRenderFace>,
+    vertices: &[V],
+) -> (Vec<BrushRenderFace>, Vec<V>)
+where
+    V: Copy,
+{
+    faces.sort_by_key(|f| (f.tex_id, f.lightmap_id, f.light_styles));
+
+    let mut batches: Vec<BrushRenderFace> = Vec::new();
+    let mut batched_vertices = Vec::with_capacity(vertices.len());
+    for face in faces {
+        let start = face.slice.base_vertex as usize;
+        let count = (face.slice.end - face.slice.start) as usize;
+        let batch_vert_id = batched_vertices.len() as u32;
+        batched_vertices.extend_from_slice(&vertices[start..start + count]);
+
+        if let Some(batch) = batches.last_mut() {
+            if batch.tex_id == face.tex_id
+                && batch.lightmap_id == face.lightmap_id
+                && batch.light_styles == face.light_styles
+            {
+                batch.slice.end += count as u32;
+                for axis in 0..3 {
+                    batch.mins[axis] = batch.mins[axis].min(face.mins[axis]);
+                    batch.maxs[axis] = batch.maxs[axis].max(face.maxs[axis]);
+                }
+                continue;
+            }
+        }
+
+        batches.push(BrushRenderFace {
+            slice: Slice {
+                start: 0,
+                end: count as u32,
+                base_vertex: batch_vert_id,
+                instances: None,
+                buffer: IndexBuffer::Auto,
+            },
+            ..face
+        });
+    }
+
+    (batches, batched_vertices)
+}
+
+/// Uploads the diffuse and fullbright mipmaps of `tex` to the GPU.
+///
+/// Textures with non-power-of-two dimensions are resampled to the next power of two at each
+/// mip level so that mipmapping and wrapping behave consistently. Texture coordinates are
+/// normalized against the original dimensions, so they remain valid for the resampled texture.
+pub(super) fn create_brush_texture_views<F>(
+    factory: &mut F,
+    palette: &Palette,
+    tex: &BspTexture,
+) -> Result<
+    (
+        ShaderResourceView<Resources, [f32; 4]>,
+        ShaderResourceView<Resources, f32>,
+    ),
+    Error,
+>
+where
+    F: Factory<Resources>,
+{
+    let (width, height) = tex.dimensions();
+    let pot_w = width.next_power_of_two();
+    let pot_h = height.next_power_of_two();
+
+    let mut mipmaps = Vec::new();
+    let mut fullbrights = Vec::new();
+    for i in 0..MIPLEVELS {
+        let (mipmap, fullbright) =
+            palette.translate(tex.mipmap(BspTextureMipmap::from_usize(i).unwrap()));
+
+        if pot_w == width && pot_h == height {
+            mipmaps.push(mipmap);
+            fullbrights.push(fullbright);
+        } else {
+            let mip_w = (width >> i).max(1);
+            let mip_h = (height >> i).max(1);
+            mipmaps.push(render::resample_to_pot(mip_w, mip_h, 4, &mipmap).2);
+            fullbrights.push(render::resample_to_pot(mip_w, mip_h, 1, &fullbright).2);
+        }
+    }
+
+    if pot_w != width || pot_h != height {
+        debug!(
+            "Resampled NPOT texture {} from {}x{} to {}x{}",
+            tex.name(),
+            width,
+            height,
+            pot_w,
+            pot_h
+        );
+    }
+
+    let (_, texture_view) = factory.create_texture_immutable_u8::<ColorFormat>(
+        texture::Kind::D2(pot_w as u16, pot_h as u16, texture::AaMode::Single),
+        texture::Mipmap::Provided,
+        &[&mipmaps[0], &mipmaps[1], &mipmaps[2], &mipmaps[3]],
+    )?;
+
+    let (_, fullbright_view) = factory.create_texture_immutable_u8::<(R8, Unorm)>(
+        texture::Kind::D2(pot_w as u16, pot_h as u16, texture::AaMode::Single),
+        texture::Mipmap::Provided,
+        &[&fullbrights[0], &fullbrights[1], &fullbrights[2], &fullbrights[3]],
+    )?;
+
+    Ok((texture_view, fullbright_view))
+}
+
+/// Write the lightmap of each face in `bsp_data` to a PNG file under `dir` in the virtual
+/// filesystem, along with a manifest mapping each file to its face and texture.
+///
+/// Returns the number of lightmaps exported.
+pub fn export_lightmaps(bsp_data: &BspData, vfs: &Vfs, dir: &str) -> Result<usize, Error> {
+    let mut manifest = String::from("# file face texinfo texture width height\n");
+    let mut count = 0;
+
+    for (face_id, face) in bsp_data.faces().iter().enumerate() {
+        let texinfo = &bsp_data.texinfo()[face.texinfo_id];
+        if texinfo.special {
+            continue;
+        }
+
+        let (width, height, data) = match bsp_data.face_lightmap(face_id) {
+            Some(l) => l,
+            None => continue,
+        };
+
+        let file_name = format!("face{:05}.png", face_id);
+        let mut file = vfs.create(format!("{}/{}", dir, file_name))?;
+        png::write_png(
+            &mut file,
+            width as u32,
+            height as u32,
+            PngColor::Grayscale,
+            data,
+        )?;
+
+        manifest += &format!(
+            "{} {} {} {} {} {}\n",
+            file_name,
+            face_id,
+            face.texinfo_id,
+            bsp_data.textures()[texinfo.tex_id].name(),
+            width,
+            height
+        );
+        count += 1;
+    }
+
+    vfs.create(format!("{}/manifest.txt", dir))?
+        .write_all(manifest.as_bytes())?;
+
+    Ok(count)
 }
 
 impl BrushRenderer {
@@ -289,64 +932,65 @@ impl BrushRenderer {
         palette: &Palette,
         factory: &mut F,
         color_target: RenderTargetView<Resources, ColorFormat>,
-        depth_target: DepthStencilView<Resources, DepthFormat>,
+        depth_target: DepthStencilView<Resources, SceneDepthFormat>,
+        reverse_z: bool,
+        dummy_pattern: DummyTexturePattern,
+        subdivide_size: Option<f32>,
     ) -> Result<BrushRenderer, Error>
     where
         F: Factory<Resources>,
     {
         let mut faces = Vec::new();
         let mut vertices = Vec::new();
-        let mut lightmap_views = Vec::new();
+        let mut lightmap_atlas = LightmapAtlas::new(LIGHTMAP_ATLAS_SIZE);
+        let mut lightmap_surfaces = Vec::new();
 
-        let pipeline_state = create_pipeline_state(factory)?;
+        let pipeline_state = create_pipeline_state(factory, reverse_z, false)?;
+        let translucent_pipeline_state = create_pipeline_state(factory, reverse_z, true)?;
 
         let bsp_data = bsp_model.bsp_data().clone();
 
         for face_id in bsp_model.face_id..bsp_model.face_id + bsp_model.face_count {
-            faces.push(create_brush_render_face(
-                factory,
+            if let Some(face) = create_brush_render_face(
                 &bsp_data,
                 face_id,
                 &mut vertices,
-                &mut lightmap_views
-            )?);
+                &mut lightmap_atlas,
+                subdivide_size,
+            )? {
+                // faces are batched below, so their lightmaps have to be collected first
+                let surface = LightmapSurface::from_face(&bsp_data, face_id);
+                if let (Some(surface), Some(rect)) = (surface, face.lightmap_rect) {
+                    lightmap_surfaces.push((face_id, surface, rect));
+                }
+
+                faces.push(face);
+            }
         }
 
+        let lightmaps = Lightmaps::new(
+            factory,
+            &lightmap_atlas,
+            bsp_data.clone(),
+            bsp_model.render_node_id(),
+            lightmap_surfaces,
+        )?;
+
+        let (faces, vertices) = batch_brush_render_faces(faces, &vertices);
         let vertex_buffer = factory.create_vertex_buffer(&vertices);
 
         let mut texture_views = Vec::new();
         let mut fullbright_views = Vec::new();
         for tex in bsp_data.textures().iter() {
-            let mut mipmaps = Vec::new();
-            let mut fullbrights = Vec::new();
-            for i in 0..MIPLEVELS {
-                let (mipmap, fullbright) =
-                    palette.translate(tex.mipmap(BspTextureMipmap::from_usize(i).unwrap()));
-                mipmaps.push(mipmap);
-                fullbrights.push(fullbright);
-            }
-
-            let (width, height) = tex.dimensions();
-
-            let (_, texture_view) = factory
-                .create_texture_immutable_u8::<ColorFormat>(
-                    texture::Kind::D2(width as u16, height as u16, texture::AaMode::Single),
-                    texture::Mipmap::Provided,
-                    &[&mipmaps[0], &mipmaps[1], &mipmaps[2], &mipmaps[3]],
-                )?;
-
-            let (_, fullbright_view) = factory
-                .create_texture_immutable_u8::<(R8, Unorm)>(
-                    texture::Kind::D2(width as u16, height as u16, texture::AaMode::Single),
-                    texture::Mipmap::Provided,
-                    &[&fullbrights[0], &fullbrights[1], &fullbrights[2], &fullbrights[3]],
-                )?;
+            let (texture_view, fullbright_view) =
+                create_brush_texture_views(factory, palette, tex)?;
 
             texture_views.push(texture_view);
             fullbright_views.push(fullbright_view);
         }
 
-        let (_, dummy_texture) = render::create_dummy_texture(factory)?;
+        let (_, dummy_texture) =
+            render::create_dummy_texture_with_pattern(factory, dummy_pattern)?;
         let (_, dummy_fullbright) = render::create_dummy_fullbright(factory)?;
         let (_, dummy_lightmap) = render::create_dummy_lightmap(factory)?;
 
@@ -354,31 +998,66 @@ impl BrushRenderer {
             bsp_data,
             faces: faces.into_boxed_slice(),
             pipeline_state,
+            translucent_pipeline_state,
             vertex_buffer,
             texture_views: texture_views.into_boxed_slice(),
             fullbright_views: fullbright_views.into_boxed_slice(),
-            lightmap_views: lightmap_views.into_boxed_slice(),
+            lightmaps,
             dummy_texture,
             dummy_fullbright,
             dummy_lightmap,
-            diffuse_sampler: factory.create_sampler(gfx::texture::SamplerInfo::new(
-                gfx::texture::FilterMethod::Scale,
-                gfx::texture::WrapMode::Tile,
-            )),
+            diffuse_sampler: create_diffuse_sampler(factory, TextureQuality::Low.filter_method()),
             fullbright_sampler: factory.create_sampler(gfx::texture::SamplerInfo::new(
                 gfx::texture::FilterMethod::Scale,
                 gfx::texture::WrapMode::Tile,
             )),
-            lightmap_sampler: factory.create_sampler(gfx::texture::SamplerInfo::new(
-                gfx::texture::FilterMethod::Bilinear,
-                // gfx::texture::FilterMethod::Scale,
-                gfx::texture::WrapMode::Tile,
-            )),
+            lightmap_sampler: create_lightmap_sampler(factory, true),
             color_target,
             depth_target,
         })
     }
 
+    /// Rebuilds the lightmap sampler with bilinear or nearest-neighbor filtering.
+    pub fn set_lightmap_bilinear<F>(&mut self, factory: &mut F, bilinear: bool)
+    where
+        F: Factory<Resources>,
+    {
+        self.lightmap_sampler = create_lightmap_sampler(factory, bilinear);
+    }
+
+    /// Rebuilds the diffuse sampler with the texture filter `filter`.
+    pub fn set_texture_filter<F>(&mut self, factory: &mut F, filter: texture::FilterMethod)
+    where
+        F: Factory<Resources>,
+    {
+        self.diffuse_sampler = create_diffuse_sampler(factory, filter);
+    }
+
+    /// Queues a dynamic light for the next call to `update_lightmaps`.
+    ///
+    /// `origin` is relative to the model's origin. Lights are forgotten after each update.
+    pub fn add_dynamic_light(&mut self, origin: Vector3<f32>, radius: f32, intensity: f32) {
+        self.lightmaps.add_dynamic_light(origin, radius, intensity);
+    }
+
+    /// Brightens lightmaps with the queued dynamic lights and uploads up to `max_updates`
+    /// changed lightmap regions.
+    pub fn update_lightmaps<C>(
+        &mut self,
+        encoder: &mut Encoder<Resources, C>,
+        max_updates: Option<usize>,
+    ) -> Result<(), Error>
+    where
+        C: CommandBuffer<Resources>,
+    {
+        self.lightmaps.update(encoder, max_updates)
+    }
+
+    /// Returns the number of draw calls needed to render this model.
+    pub fn batch_count(&self) -> usize {
+        self.faces.len()
+    }
+
     fn create_pipeline_data(&self) -> Result<BrushPipelineData, Error>
     {
         let pipeline_data = pipe_brush::Data {
@@ -388,6 +1067,11 @@ impl BrushRenderer {
             fullbright_sampler: (self.dummy_fullbright.clone(), self.fullbright_sampler.clone()),
             lightmap_sampler: (self.dummy_lightmap.clone(), self.lightmap_sampler.clone()),
             lightstyle_value: [0.0; 4],
+            fog_color: [0.0; 4],
+            fog_density: 0.0,
+            alpha: 1.0,
+            time: 0.0,
+            warp: 0.0,
             out_color: self.color_target.clone(),
             out_depth: self.depth_target.clone(),
         };
@@ -395,6 +1079,10 @@ impl BrushRenderer {
         Ok(pipeline_data)
     }
 
+    /// Draws the model's opaque faces.
+    ///
+    /// Translucent liquids are left for `render_translucent`, which has to wait until everything
+    /// behind them has been drawn.
     pub fn render<C>(
         &self,
         encoder: &mut Encoder<Resources, C>,
@@ -403,27 +1091,120 @@ impl BrushRenderer {
         origin: Vector3<f32>,
         angles: Vector3<Deg<f32>>,
         lightstyle_values: &[f32],
+        fog: &Fog,
+        liquid_alpha: &LiquidAlpha,
     ) -> Result<(), Error>
     where
         C: CommandBuffer<Resources>,
     {
         let _guard = flame::start_guard("BrushRenderer::render");
+        let faces: Vec<(usize, f32)> = self
+            .faces
+            .iter()
+            .enumerate()
+            .filter(|&(_, face)| liquid_alpha.alpha(face.liquid) >= 1.0)
+            .map(|(face_id, _)| (face_id, 1.0))
+            .collect();
+
+        self.render_faces(encoder, time, camera, origin, angles, lightstyle_values, fog, &faces)
+    }
+
+    /// Returns whether any of the model's faces are translucent liquids.
+    pub fn has_translucent_faces(&self, liquid_alpha: &LiquidAlpha) -> bool {
+        self.faces.iter().any(|face| liquid_alpha.alpha(face.liquid) < 1.0)
+    }
+
+    /// Blends the model's translucent faces over the scene, from farthest to nearest.
+    ///
+    /// A model faded by `alpha` is translucent as a whole, so all of its faces are drawn.
+    pub fn render_translucent<C>(
+        &self,
+        encoder: &mut Encoder<Resources, C>,
+        time: Duration,
+        camera: &Camera,
+        origin: Vector3<f32>,
+        angles: Vector3<Deg<f32>>,
+        lightstyle_values: &[f32],
+        fog: &Fog,
+        liquid_alpha: &LiquidAlpha,
+        alpha: f32,
+    ) -> Result<(), Error>
+    where
+        C: CommandBuffer<Resources>,
+    {
+        let _guard = flame::start_guard("BrushRenderer::render_translucent");
+
+        // faces are sorted in the model's own space (rotation is ignored)
+        let eye = camera.origin() - origin;
+        let mut sorted: Vec<(usize, f32)> = self
+            .faces
+            .iter()
+            .enumerate()
+            .filter(|&(_, face)| alpha * liquid_alpha.alpha(face.liquid) < 1.0)
+            .map(|(face_id, face)| (face_id, face.distance(eye)))
+            .collect();
+        sort_faces_back_to_front(&mut sorted);
+
+        let faces: Vec<(usize, f32)> = sorted
+            .into_iter()
+            .map(|(face_id, _)| (face_id, alpha * liquid_alpha.alpha(self.faces[face_id].liquid)))
+            .collect();
+
+        self.render_faces(encoder, time, camera, origin, angles, lightstyle_values, fog, &faces)
+    }
+
+    // draws each face in `faces` with its alpha, in order
+    fn render_faces<C>(
+        &self,
+        encoder: &mut Encoder<Resources, C>,
+        time: Duration,
+        camera: &Camera,
+        origin: Vector3<f32>,
+        angles: Vector3<Deg<f32>>,
+        lightstyle_values: &[f32],
+        fog: &Fog,
+        faces: &[(usize, f32)],
+    ) -> Result<(), Error>
+    where
+        C: CommandBuffer<Resources>,
+    {
         let mut pipeline_data = self.create_pipeline_data()?;
+        pipeline_data.fog_color = fog.shader_color();
+        pipeline_data.fog_density = fog.shader_density();
+        pipeline_data.time = engine::duration_to_f32(time);
 
-        for face in self.faces.iter() {
-            let frame = self.bsp_data.texture_frame_for_time(face.tex_id, time);
+        let model_transform = Matrix4::from_translation(Vector3::new(-origin.y, origin.z, -origin.x))
+            * Matrix4::from(Euler::new(angles.x, angles.y, angles.z));
+        pipeline_data.transform = (camera.transform() * model_transform).into();
+        let frustum =
+            Frustum::from_transform(camera.transform() * model_transform * render::quake_to_gl());
 
-            let model_transform = Matrix4::from_translation(Vector3::new(-origin.y, origin.z, -origin.x))
-                * Matrix4::from(Euler::new(angles.x, angles.y, angles.z));
-            pipeline_data.vertex_buffer = self.vertex_buffer.clone();
-            pipeline_data.transform = (camera.transform() * model_transform).into();
+        // batches are sorted by texture, so only switch textures when it changes
+        let mut bound_frame = None;
+        let mut bound_lightmap = None;
 
-            pipeline_data.diffuse_sampler.0 = self.texture_views[frame].clone();
-            pipeline_data.fullbright_sampler.0 = self.fullbright_views[frame].clone();
-            pipeline_data.lightmap_sampler.0 = match face.lightmap_id {
-                Some(l_id) => self.lightmap_views[l_id].clone(),
-                None => self.dummy_lightmap.clone(),
-            };
+        for &(face_id, alpha) in faces {
+            let face = &self.faces[face_id];
+            if !frustum.intersects_box(face.mins, face.maxs) {
+                continue;
+            }
+
+            // every face in a batch shares a texture, so they all animate together
+            let frame = self.bsp_data.texture_frame_for_time(face.tex_id, time);
+            if bound_frame != Some(frame) {
+                pipeline_data.diffuse_sampler.0 = self.texture_views[frame].clone();
+                pipeline_data.fullbright_sampler.0 = self.fullbright_views[frame].clone();
+                bound_frame = Some(frame);
+            }
+
+            // faces share a few atlas pages, so the lightmap rarely changes either
+            if bound_lightmap != Some(face.lightmap_id) {
+                pipeline_data.lightmap_sampler.0 = match face.lightmap_id {
+                    Some(l_id) => self.lightmaps.view(l_id).clone(),
+                    None => self.dummy_lightmap.clone(),
+                };
+                bound_lightmap = Some(face.lightmap_id);
+            }
 
             let mut lightstyle_value = [-1.0; 4];
             for i in 0..4 {
@@ -433,10 +1214,290 @@ impl BrushRenderer {
             }
 
             pipeline_data.lightstyle_value = lightstyle_value;
+            pipeline_data.alpha = alpha;
+            pipeline_data.warp = if face.liquid.is_some() { 1.0 } else { 0.0 };
 
-            encoder.draw(&face.slice, &self.pipeline_state, &pipeline_data);
+            // translucent faces are blended over what's behind them without writing depth
+            let pipeline_state = if alpha < 1.0 {
+                &self.translucent_pipeline_state
+            } else {
+                &self.pipeline_state
+            };
+            encoder.draw(&face.slice, pipeline_state, &pipeline_data);
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn face(
+        tex_id: usize,
+        lightmap_id: Option<usize>,
+        vert_id: u32,
+        vert_count: u32,
+    ) -> BrushRenderFace {
+        BrushRenderFace {
+            slice: Slice {
+                start: 0,
+                end: vert_count,
+                base_vertex: vert_id,
+                instances: None,
+                buffer: IndexBuffer::Auto,
+            },
+            tex_id,
+            liquid: None,
+            lightmap_id,
+            lightmap_rect: None,
+            light_styles: [0, 255, 255, 255],
+            mins: Vector3::new(vert_id as f32, 0.0, 0.0),
+            maxs: Vector3::new(vert_id as f32, 1.0, 1.0),
+        }
+    }
+
+    #[test]
+    fn test_batch_brush_render_faces() {
+        // vertex values identify the face they came from
+        let vertices = [0, 0, 0, 1, 1, 1, 2, 2, 2, 2, 2, 2, 3, 3, 3, 4, 4, 4];
+        let faces = vec![
+            face(7, None, 0, 3),
+            face(2, Some(0), 3, 3),
+            face(7, None, 6, 6),
+            face(2, Some(0), 12, 3),
+            face(2, Some(1), 15, 3),
+        ];
+
+        let (batches, batched) = batch_brush_render_faces(faces, &vertices);
+        assert_eq!(batches.len(), 3);
+        assert_eq!(batched, vec![1, 1, 1, 3, 3, 3, 4, 4, 4, 0, 0, 0, 2, 2, 2, 2, 2, 2]);
+
+        let ranges: Vec<(usize, Option<usize>, u32, u32)> = batches
+            .iter()
+            .map(|b| (b.tex_id, b.lightmap_id, b.slice.base_vertex, b.slice.end))
+            .collect();
+        assert_eq!(
+            ranges,
+            vec![(2, Some(0), 0, 6), (2, Some(1), 6, 3), (7, None, 9, 9)]
+        );
+
+        // merged faces share a bounding box
+        assert_eq!(batches[0].mins, Vector3::new(3.0, 0.0, 0.0));
+        assert_eq!(batches[0].maxs, Vector3::new(12.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn test_sort_faces_back_to_front() {
+        let mut faces = vec![(0, 10.0), (1, 300.0), (2, 45.0)];
+        sort_faces_back_to_front(&mut faces);
+        assert_eq!(faces, vec![(1, 300.0), (2, 45.0), (0, 10.0)]);
+    }
+
+    #[test]
+    fn test_batch_keeps_light_styles_apart() {
+        let vertices = [0; 6];
+        let mut flicker = face(1, None, 3, 3);
+        flicker.light_styles = [1, 255, 255, 255];
+
+        let (batches, _) = batch_brush_render_faces(vec![face(1, None, 0, 3), flicker], &vertices);
+        assert_eq!(batches.len(), 2);
+    }
+
+    #[test]
+    fn test_calculate_lightmap_texcoords() {
+        use common::bsp::BspFaceSide;
+
+        // a 64x32 face with a 5x3 lightmap
+        let face = BspFace {
+            plane_id: 0,
+            side: BspFaceSide::Front,
+            edge_id: 0,
+            edge_count: 4,
+            texinfo_id: 0,
+            light_styles: [0, 255, 255, 255],
+            lightmap_id: Some(0),
+            texture_mins: [-16, 32],
+            extents: [64, 32],
+        };
+        let texinfo = BspTexInfo {
+            s_vector: Vector3::new(1.0, 0.0, 0.0),
+            s_offset: 8.0,
+            t_vector: Vector3::new(0.0, 1.0, 0.0),
+            t_offset: 0.0,
+            tex_id: 0,
+            special: false,
+        };
+
+        // the face's corners land on the centers of the corner luxels
+        let mins = calculate_lightmap_texcoords(Vector3::new(-24.0, 32.0, 0.0), &face, &texinfo);
+        assert_eq!(mins, [0.5 / 5.0, 0.5 / 3.0]);
+        let maxs = calculate_lightmap_texcoords(Vector3::new(40.0, 64.0, 0.0), &face, &texinfo);
+        assert_eq!(maxs, [4.5 / 5.0, 2.5 / 3.0]);
+
+        // so does every luxel in between
+        let mid = calculate_lightmap_texcoords(Vector3::new(8.0, 48.0, 0.0), &face, &texinfo);
+        assert_eq!(mid, [2.5 / 5.0, 1.5 / 3.0]);
+    }
+
+    #[test]
+    fn test_lightmap_atlas_samples_each_face() {
+        // two faces with distinct luxels, packed side by side in one page
+        let a: Vec<u8> = (0..5 * 3 * 3).map(|i| i as u8).collect();
+        let b: Vec<u8> = (0..4 * 6 * 3).map(|i| 100 + i as u8).collect();
+
+        let mut atlas = LightmapAtlas::new(16);
+        let rect_a = atlas.insert(5, 3, &a).unwrap();
+        let rect_b = atlas.insert(4, 6, &b).unwrap();
+        assert_eq!(atlas.page_count(), 1);
+        assert_eq!((rect_a.page, rect_a.x, rect_a.y), (0, 0, 0));
+        assert_eq!((rect_b.page, rect_b.x, rect_b.y), (0, 5, 0));
+
+        // the center of each luxel of each face maps to the matching luxel of the page
+        for &(rect, luxels) in [(rect_a, &a), (rect_b, &b)].iter() {
+            for y in 0..rect.height {
+                for x in 0..rect.width {
+                    let texcoords = [
+                        (x as f32 + 0.5) / rect.width as f32,
+                        (y as f32 + 0.5) / rect.height as f32,
+                    ];
+                    let [s, t] = rect.atlas_texcoords(texcoords, atlas.size());
+                    let (page_x, page_y) = ((s * 16.0) as usize, (t * 16.0) as usize);
+                    let page_luxel = LUXEL_BYTES * (page_y * 16 + page_x);
+                    let luxel = 3 * (y * rect.width + x);
+                    assert_eq!(
+                        &atlas.page_luxels(0)[page_luxel..page_luxel + 3],
+                        &luxels[luxel..luxel + 3]
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_lightmap_atlas_new_page() {
+        let mut atlas = LightmapAtlas::new(8);
+        assert_eq!(atlas.insert(8, 6, &[1; 8 * 6 * 3]).unwrap().page, 0);
+
+        // doesn't fit under the first lightmap, so it starts a new page
+        let rect = atlas.insert(4, 4, &[2; 4 * 4 * 3]).unwrap();
+        assert_eq!((rect.page, rect.x, rect.y), (1, 0, 0));
+        assert_eq!(atlas.page_count(), 2);
+
+        // larger than a page
+        assert_eq!(atlas.insert(9, 1, &[3; 9 * 3]), None);
+    }
+
+    #[test]
+    fn test_lightmap_atlas_colored_luxel() {
+        // a 2x2 lightmap from a .lit file with one orange luxel among gray ones
+        let mut luxels = vec![64; 2 * 2 * 3];
+        luxels[3..6].copy_from_slice(&[255, 128, 0]);
+
+        let mut atlas = LightmapAtlas::new(4);
+        let rect = atlas.insert(2, 2, &luxels).unwrap();
+        assert_eq!((rect.x, rect.y), (0, 0));
+
+        // the luxel keeps its color, so it tints whatever texture it lights
+        let page = atlas.page_luxels(0);
+        assert_eq!(&page[LUXEL_BYTES..2 * LUXEL_BYTES], &[255, 128, 0, 255]);
+        assert_eq!(&page[0..LUXEL_BYTES], &[64, 64, 64, 255]);
+    }
+
+    #[test]
+    fn test_texture_quality_parse() {
+        assert_eq!(TextureQuality::parse("low"), Some(TextureQuality::Low));
+        assert_eq!(TextureQuality::parse(" Medium "), Some(TextureQuality::Medium));
+        assert_eq!(TextureQuality::parse("2"), Some(TextureQuality::High));
+        assert_eq!(TextureQuality::parse("ULTRA"), Some(TextureQuality::Ultra));
+        assert_eq!(TextureQuality::parse("4"), None);
+        assert_eq!(TextureQuality::parse("best"), None);
+    }
+
+    #[test]
+    fn test_texture_quality_filter_method() {
+        use gfx::texture::FilterMethod;
+
+        assert_eq!(TextureQuality::Low.filter_method(), FilterMethod::Scale);
+        assert_eq!(TextureQuality::Medium.filter_method(), FilterMethod::Mipmap);
+        assert_eq!(TextureQuality::High.filter_method(), FilterMethod::Trilinear);
+        assert_eq!(TextureQuality::Ultra.filter_method(), FilterMethod::Anisotropic(16));
+    }
+
+    #[test]
+    fn test_parse_texture_mode() {
+        use gfx::texture::FilterMethod;
+
+        assert_eq!(parse_texture_mode("GL_NEAREST"), Some(FilterMethod::Scale));
+        assert_eq!(parse_texture_mode("gl_linear"), Some(FilterMethod::Bilinear));
+        assert_eq!(
+            parse_texture_mode("GL_NEAREST_MIPMAP_NEAREST"),
+            Some(FilterMethod::Mipmap)
+        );
+        assert_eq!(
+            parse_texture_mode(" linear_mipmap_linear "),
+            Some(FilterMethod::Trilinear)
+        );
+        assert_eq!(parse_texture_mode(""), None);
+        assert_eq!(parse_texture_mode("GL_ANISOTROPIC"), None);
+    }
+
+    // twice the area of a convex polygon lying in a plane of constant z
+    fn area2(points: &[Vector3<f32>]) -> f32 {
+        let mut sum = 0.0;
+        for (i, p) in points.iter().enumerate() {
+            let q = points[(i + 1) % points.len()];
+            sum += p.x * q.y - q.x * p.y;
+        }
+        sum.abs()
+    }
+
+    #[test]
+    fn test_subdivide_polygon() {
+        // a 256x256 square at the origin splits into 4 128x128 squares
+        let square = [
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(256.0, 0.0, 0.0),
+            Vector3::new(256.0, 256.0, 0.0),
+            Vector3::new(0.0, 256.0, 0.0),
+        ];
+        let pieces = subdivide_polygon(&square, 128.0);
+        assert_eq!(pieces.len(), 4);
+        for piece in pieces.iter() {
+            assert_eq!(area2(piece), 2.0 * 128.0 * 128.0);
+        }
+
+        // faces smaller than the grid are left alone
+        assert_eq!(subdivide_polygon(&square, 512.0), vec![square.to_vec()]);
+    }
+
+    #[test]
+    fn test_subdivide_polygon_clips_to_face() {
+        // a triangle isn't aligned to the grid, so its pieces have to be clipped to its edges
+        let triangle = [
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(300.0, 0.0, 0.0),
+            Vector3::new(0.0, 300.0, 0.0),
+        ];
+        let pieces = subdivide_polygon(&triangle, 64.0);
+        assert!(pieces.len() > 1);
+
+        let total: f32 = pieces.iter().map(|p| area2(p)).sum();
+        assert!((total - area2(&triangle)).abs() < 1.0, "total = {}", total);
+
+        for piece in pieces.iter() {
+            for p in piece.iter() {
+                assert!(p.x >= 0.0 && p.y >= 0.0 && p.x + p.y <= 300.0 + 0.01);
+            }
+        }
+    }
+
+    #[test]
+    fn test_subdivide_size() {
+        assert_eq!(subdivide_size(128.0), Some(128.0));
+        assert_eq!(subdivide_size(0.0), None);
+        assert_eq!(subdivide_size(-64.0), None);
+        assert_eq!(subdivide_size(::std::f32::NAN), None);
+    }
+}
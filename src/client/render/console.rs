@@ -18,6 +18,7 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 
+use client::input::chat::Chat;
 use client::render::{self, Palette, Vertex2d};
 use client::render::bitmap::BitmapTexture;
 use client::render::glyph::{GLYPH_HEIGHT, GlyphRenderer, GlyphRendererCommand, GLYPH_WIDTH};
@@ -26,6 +27,7 @@ use common::console::Console;
 use common::vfs::Vfs;
 use common::wad::QPic;
 
+use cgmath::{Matrix4, SquareMatrix};
 use failure::Error;
 use gfx::{CommandBuffer, Encoder, Factory, Slice};
 use gfx::handle::Buffer;
@@ -34,6 +36,11 @@ use gfx_device_gl::Resources;
 
 const PAD_LEFT: i32 = GLYPH_WIDTH as i32;
 
+const CONBACK_PATH: &str = "gfx/conback.lmp";
+
+// drawn in place of the background image if it can't be found
+const CONBACK_FALLBACK_RGBA: [u8; 4] = [0x1F, 0x1F, 0x1F, 0xFF];
+
 pub struct ConsoleRenderer {
     console: Rc<RefCell<Console>>,
     glyph_renderer: Rc<GlyphRenderer>,
@@ -53,7 +60,14 @@ impl ConsoleRenderer {
     ) -> Result<ConsoleRenderer, Error>
     where F: Factory<Resources> {
         let slice = Slice::new_match_vertex_buffer(&vertex_buffer);
-        let conback = BitmapTexture::from_qpic(factory, &QPic::load(vfs.open("gfx/conback.lmp").unwrap())?, palette)?;
+        // mods may provide their own background, which the Vfs will find first
+        let conback = match vfs.open(CONBACK_PATH) {
+            Ok(f) => BitmapTexture::from_qpic(factory, &QPic::load(f)?, palette)?,
+            Err(_) => {
+                warn!("{} not found, using a solid console background", CONBACK_PATH);
+                BitmapTexture::new(factory, 1, 1, CONBACK_FALLBACK_RGBA.to_vec().into_boxed_slice())?
+            }
+        };
         Ok(ConsoleRenderer {
             console,
             glyph_renderer,
@@ -97,7 +111,9 @@ impl ConsoleRenderer {
             y_min
         ).into();
         user_data.sampler.0 = self.conback.view();
+        user_data.alpha = alpha;
         encoder.draw(&self.slice, pso, user_data);
+        user_data.alpha = 1.0;
 
         let mut commands = Vec::new();
 
@@ -118,44 +134,100 @@ impl ConsoleRenderer {
             y_min + GLYPH_HEIGHT as i32,
         ));
 
+        for command in commands {
+            self.glyph_renderer.render_command(
+                encoder,
+                pso,
+                user_data,
+                display_width,
+                display_height,
+                Matrix4::identity(),
+                command,
+            )?;
+        }
+
         // draw output
         let console = self.console.borrow();
         let con_out = console.output();
-        for (line_id, line) in con_out.lines().enumerate() {
+        for (line_id, line) in con_out.colored_lines().enumerate() {
             // TODO: actually calculate the maximum extent of the console and stop rendering there
             // this will be needed for scrolling functionality
             if line_id > 100 {
                 break;
             }
 
-            for (chr_id, chr) in line.iter().enumerate() {
-                let mut c = *chr;
-
-                if c as u32 > ::std::u8::MAX as u32 {
-                    warn!("char \"{}\" (U+{:4}) cannot be displayed in the console", c, c as u32);
-                    continue;
+            let mut chr_id = 0;
+            for (color, chars) in line.runs() {
+                user_data.tint = color.rgb();
+
+                for chr in chars {
+                    let c = *chr;
+                    chr_id += 1;
+
+                    if c as u32 > ::std::u8::MAX as u32 {
+                        warn!(
+                            "char \"{}\" (U+{:4}) cannot be displayed in the console",
+                            c,
+                            c as u32
+                        );
+                        continue;
+                    }
+
+                    self.glyph_renderer.render_command(
+                        encoder,
+                        pso,
+                        user_data,
+                        display_width,
+                        display_height,
+                        Matrix4::identity(),
+                        GlyphRendererCommand::glyph(
+                            c as u8,
+                            PAD_LEFT as i32 + GLYPH_WIDTH as i32 * (chr_id - 1) as i32,
+                            // line_id + 2 is the row above the input line
+                            y_min + GLYPH_HEIGHT as i32 * (line_id + 2) as i32,
+                        ),
+                    )?;
                 }
-
-                commands.push(GlyphRendererCommand::glyph(
-                    c as u8,
-                    PAD_LEFT as i32 + GLYPH_WIDTH as i32 * chr_id as i32,
-                    // line_id + 2 is the row above the input line
-                    y_min + GLYPH_HEIGHT as i32 * (line_id + 2) as i32,
-                ));
             }
         }
-
-        for command in commands {
-            self.glyph_renderer.render_command(
-                encoder,
-                pso,
-                user_data,
-                display_width,
-                display_height,
-                command,
-            )?;
-        }
+        user_data.tint = [1.0; 3];
 
         Ok(())
     }
+
+    /// Draws the chat message being typed at the top of the screen, like the original engine.
+    pub fn render_chat<C>(
+        &self,
+        encoder: &mut Encoder<Resources, C>,
+        pso: &PipelineState<Resources, <pipeline2d::Data<Resources> as PipelineData<Resources>>::Meta>,
+        user_data: &mut pipeline2d::Data<Resources>,
+        display_width: u32,
+        display_height: u32,
+        chat: &Chat,
+    ) -> Result<(), Error>
+    where
+        C: CommandBuffer<Resources>
+    {
+        // TODO: replace with cvar scr_conscale
+        let display_width = display_width / 2;
+        let display_height = display_height / 2;
+
+        let prompt = if chat.is_team() { "say_team:" } else { "say:" };
+        let line = format!("{} {}", prompt, chat.text());
+
+        user_data.tint = [1.0; 3];
+        self.glyph_renderer.render_command(
+            encoder,
+            pso,
+            user_data,
+            display_width,
+            display_height,
+            Matrix4::identity(),
+            GlyphRendererCommand::text(
+                line,
+                PAD_LEFT,
+                display_height as i32 - GLYPH_HEIGHT as i32,
+            ),
+        )
+    }
 }
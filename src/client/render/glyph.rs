@@ -19,6 +19,7 @@ use client::render::{self, Palette, Vertex2d};
 use client::render::pipeline2d;
 use common::wad::QPic;
 
+use cgmath::Matrix4;
 use failure::Error;
 use gfx::{CommandBuffer, Encoder, Factory, IndexBuffer, Slice};
 use gfx::handle::{Buffer, ShaderResourceView};
@@ -131,6 +132,7 @@ impl GlyphRenderer {
         user_data: &mut pipeline2d::Data<Resources>,
         display_width: u32,
         display_height: u32,
+        viewport_transform: Matrix4<f32>,
         command: GlyphRendererCommand,
     ) -> Result<(), Error>
     where
@@ -141,14 +143,14 @@ impl GlyphRenderer {
 
         match command {
             GlyphRendererCommand::Glyph { glyph_id, x, y } => {
-                user_data.transform = render::screen_space_vertex_transform(
+                user_data.transform = (viewport_transform * render::screen_space_vertex_transform(
                     display_width,
                     display_height,
                     GLYPH_WIDTH as u32,
                     GLYPH_HEIGHT as u32,
                     x,
                     y,
-                ).into();
+                )).into();
                 let slice = self.slice_for_glyph(glyph_id);
                 encoder.draw(&slice, pso, user_data);
             }
@@ -162,14 +164,14 @@ impl GlyphRenderer {
                         break;
                     }
 
-                    user_data.transform = render::screen_space_vertex_transform(
+                    user_data.transform = (viewport_transform * render::screen_space_vertex_transform(
                         display_width,
                         display_height,
                         GLYPH_WIDTH as u32,
                         GLYPH_HEIGHT as u32,
                         abs_x,
                         y,
-                    ).into();
+                    )).into();
 
                     // TODO: check ASCII -> conchar mapping
                     let slice = self.slice_for_glyph(chr as u8);
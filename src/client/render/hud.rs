@@ -22,11 +22,14 @@ use std::cell::RefCell;
 use std::rc::Rc;
 
 use client::render::bitmap::BitmapTexture;
-use client::render::glyph::GlyphRendererCommand;
-use client::render::{self, GraphicsPackage, PipelineData2d, Vertex2d};
+use client::render::glyph::{GlyphRendererCommand, GLYPH_HEIGHT, GLYPH_WIDTH};
+use client::render::{self, GraphicsPackage, PipelineData2d, Vertex2d, Viewport};
 use client::Client;
+use common::console::CvarRegistry;
+use common::engine;
 use common::net::{ClientStat, ItemFlags};
 
+use cgmath::{Deg, Matrix4, SquareMatrix, Vector3};
 use chrono::Duration;
 use flame;
 use gfx::handle::Buffer;
@@ -52,6 +55,51 @@ enum AmmoSlots {
     Cells = 3,
 }
 
+// distance of the cl_showpos overlay from the edge of the screen
+const SHOWPOS_MARGIN: i32 = 8;
+
+// thresholds for the alternate stat colors when cl_hud_warn is off
+const DEFAULT_HEALTH_WARN: i32 = 25;
+const DEFAULT_AMMO_WARN: i32 = 10;
+
+// low stats flash between white and this color
+const HUD_WARN_COLOR: [f32; 3] = [1.0, 0.2, 0.2];
+
+// flashes per second
+const HUD_WARN_RATE: f32 = 2.0;
+
+/// Returns the tint of a HUD stat at `time`, flashing if `value` is at or below `threshold`.
+pub fn hud_warn_tint(value: i32, threshold: i32, time: Duration) -> [f32; 3] {
+    if value > threshold {
+        return [1.0; 3];
+    }
+
+    // 0 at the start of each flash, 1 halfway through
+    let phase = engine::duration_to_f32(time) * HUD_WARN_RATE;
+    let t = 0.5 - 0.5 * (phase * 2.0 * ::std::f32::consts::PI).cos();
+
+    let mut tint = [1.0; 3];
+    for (c, warn) in tint.iter_mut().zip(HUD_WARN_COLOR.iter()) {
+        *c += (warn - *c) * t;
+    }
+
+    tint
+}
+
+/// Formats the lines of the `cl_showpos` overlay.
+pub fn showpos_lines(
+    origin: Vector3<f32>,
+    angles: Vector3<Deg<f32>>,
+    velocity: Vector3<f32>,
+) -> [String; 4] {
+    [
+        format!("pos {:.1} {:.1} {:.1}", origin.x, origin.y, origin.z),
+        format!("ang {:.1} {:.1} {:.1}", angles.x.0, angles.y.0, angles.z.0),
+        format!("vel {:.1} {:.1} {:.1}", velocity.x, velocity.y, velocity.z),
+        format!("spd {:.1}", velocity.x.hypot(velocity.y)),
+    ]
+}
+
 pub struct HudRenderer {
     gfx_pkg: Rc<RefCell<GraphicsPackage>>,
 
@@ -79,6 +127,7 @@ pub struct HudRenderer {
     scorebar: BitmapTexture,
 
     vertex_buffer: Buffer<Resources, Vertex2d>,
+    viewport_transform: Matrix4<f32>,
 }
 
 impl HudRenderer {
@@ -202,6 +251,7 @@ impl HudRenderer {
             scorebar,
 
             vertex_buffer,
+            viewport_transform: Matrix4::identity(),
         })
     }
 
@@ -218,8 +268,8 @@ impl HudRenderer {
         C: CommandBuffer<Resources>,
     {
         user_data.vertex_buffer = self.vertex_buffer.clone();
-        user_data.transform = bitmap
-            .transform(display_width, display_height, position_x, position_y)
+        user_data.transform = (self.viewport_transform
+            * bitmap.transform(display_width, display_height, position_x, position_y))
             .into();
         user_data.sampler.0 = bitmap.view();
         encoder.draw(
@@ -288,6 +338,8 @@ impl HudRenderer {
         &mut self,
         encoder: &mut Encoder<Resources, C>,
         client: &Client,
+        cvars: &CvarRegistry,
+        viewport: &Viewport,
         display_width: u32,
         display_height: u32,
     ) -> Result<(), Error>
@@ -296,9 +348,12 @@ impl HudRenderer {
     {
         let mut user_data = self.gfx_pkg.borrow().gen_user_data_2d();
 
+        // lay the HUD out as if the viewport were the whole display, then squeeze it into place
+        self.viewport_transform = viewport.ndc_transform(display_width, display_height);
+
         // TODO: scale using a cvar (Quakespasm uses scr_{con,crosshair,menu,sbar}scale)
-        let display_width = display_width / 2;
-        let display_height = display_height / 2;
+        let display_width = viewport.width / 2;
+        let display_height = viewport.height / 2;
 
         let _guard = flame::start_guard("HudRenderer::render");
 
@@ -368,6 +423,7 @@ impl HudRenderer {
                         &mut user_data,
                         display_width,
                         display_height,
+                        self.viewport_transform,
                         GlyphRendererCommand::glyph(
                             18 + chr as u8 - '0' as u8,
                             ibar_x + (6 * i + chr_id) as i32 * 8 + 10,
@@ -470,12 +526,26 @@ impl HudRenderer {
             }
         }
 
+        // with cl_hud_warn set, low stats use configurable thresholds and flash
+        let hud_warn = cvars.get_value("cl_hud_warn").unwrap_or(0.0) != 0.0;
+        let (health_warn, ammo_warn) = if hud_warn {
+            (
+                cvars.get_value("cl_hud_warn_health").unwrap_or(0.0) as i32,
+                cvars.get_value("cl_hud_warn_ammo").unwrap_or(0.0) as i32,
+            )
+        } else {
+            (DEFAULT_HEALTH_WARN, DEFAULT_AMMO_WARN)
+        };
+
         // health
         let health = client.stats()[ClientStat::Health as usize];
+        if hud_warn {
+            user_data.tint = hud_warn_tint(health, health_warn, client.time());
+        }
         self.render_number(
             health,
             3,
-            health <= 25,
+            health <= health_warn,
             encoder,
             &mut user_data,
             display_width,
@@ -483,6 +553,7 @@ impl HudRenderer {
             sbar_x + 136,
             sbar_y,
         );
+        user_data.tint = [1.0; 3];
 
         let ammo_id = if client.items().contains(ItemFlags::SHELLS) {
             Some(0)
@@ -509,10 +580,13 @@ impl HudRenderer {
         }
 
         let ammo = client.stats()[ClientStat::Ammo as usize];
+        if hud_warn {
+            user_data.tint = hud_warn_tint(ammo, ammo_warn, client.time());
+        }
         self.render_number(
             ammo,
             3,
-            ammo <= 10,
+            ammo <= ammo_warn,
             encoder,
             &mut user_data,
             display_width,
@@ -520,7 +594,97 @@ impl HudRenderer {
             sbar_x + 248,
             sbar_y,
         );
+        user_data.tint = [1.0; 3];
+
+        // crosshair
+        let crosshair = cvars.get_value("crosshair").unwrap_or(0.0) != 0.0;
+        let hitmarker = cvars.get_value("cl_hitmarker").unwrap_or(0.0) != 0.0
+            && client.hitmarker_intensity() > 0.0;
+        if crosshair || hitmarker {
+            // the high half of conchars is the alternate (gold) character set
+            let glyph_id = if hitmarker { b'+' | 0x80 } else { b'+' };
+
+            // cl_crossy is measured down the screen, but our y axis points up
+            let cross_x = cvars.get_value("cl_crossx").unwrap_or(0.0) as i32;
+            let cross_y = cvars.get_value("cl_crossy").unwrap_or(0.0) as i32;
+
+            // the crosshair warns of low health too
+            if hud_warn {
+                user_data.tint = hud_warn_tint(health, health_warn, client.time());
+            }
+
+            self.gfx_pkg.borrow().glyph_renderer().render_command(
+                encoder,
+                self.gfx_pkg.borrow().pipeline_2d(),
+                &mut user_data,
+                display_width,
+                display_height,
+                self.viewport_transform,
+                GlyphRendererCommand::glyph(
+                    glyph_id,
+                    display_width as i32 / 2 - 4 + cross_x,
+                    display_height as i32 / 2 - 4 - cross_y,
+                ),
+            )?;
+            user_data.tint = [1.0; 3];
+        }
+
+        // position overlay in the top right corner
+        if cvars.get_value("cl_showpos").unwrap_or(0.0) != 0.0 {
+            let lines = showpos_lines(client.view_origin(), client.view_angles(), client.velocity());
+            for (line_id, line) in lines.iter().enumerate() {
+                let x = display_width as i32 - SHOWPOS_MARGIN - (GLYPH_WIDTH * line.len()) as i32;
+                let y = display_height as i32 - SHOWPOS_MARGIN
+                    - (GLYPH_HEIGHT * (line_id + 1)) as i32;
+
+                self.gfx_pkg.borrow().glyph_renderer().render_command(
+                    encoder,
+                    self.gfx_pkg.borrow().pipeline_2d(),
+                    &mut user_data,
+                    display_width,
+                    display_height,
+                    self.viewport_transform,
+                    GlyphRendererCommand::text(line.clone(), x, y),
+                )?;
+            }
+        }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hud_warn_tint() {
+        // stats above the threshold aren't tinted
+        assert_eq!(hud_warn_tint(26, 25, Duration::milliseconds(250)), [1.0; 3]);
+
+        // low stats flash from white to the warning color and back
+        assert_eq!(hud_warn_tint(25, 25, Duration::zero()), [1.0; 3]);
+        let peak = hud_warn_tint(10, 25, Duration::milliseconds(250));
+        for (c, warn) in peak.iter().zip(HUD_WARN_COLOR.iter()) {
+            assert!((c - warn).abs() < 1e-5);
+        }
+        let end = hud_warn_tint(10, 25, Duration::milliseconds(500));
+        for c in end.iter() {
+            assert!((c - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_showpos_lines() {
+        let lines = showpos_lines(
+            Vector3::new(544.0, -288.5, 24.0),
+            Vector3::new(Deg(-10.0), Deg(90.0), Deg(0.0)),
+            Vector3::new(300.0, 400.0, -20.0),
+        );
+
+        assert_eq!(lines[0], "pos 544.0 -288.5 24.0");
+        assert_eq!(lines[1], "ang -10.0 90.0 0.0");
+        assert_eq!(lines[2], "vel 300.0 400.0 -20.0");
+        assert_eq!(lines[3], "spd 500.0");
+    }
+}
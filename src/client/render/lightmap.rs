@@ -0,0 +1,581 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Dynamic lightmaps and batching of their texture updates.
+//!
+//! Dynamic lights change small regions of many lightmaps every frame. Uploading each region
+//! separately means many tiny texture updates, so regions are coalesced per lightmap first and,
+//! optionally, only a limited number of uploads are made per frame.
+
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+
+use client::render::brush::{LightmapAtlas, LightmapAtlasRect, LUXEL_BYTES, LUXEL_SIZE};
+use common::bsp::BspData;
+
+use cgmath::{InnerSpace, Vector3};
+use failure::Error;
+use gfx::format::{ChannelTyped, Swizzle, Unorm, R8_G8_B8_A8};
+use gfx::handle::{ShaderResourceView, Texture};
+use gfx::memory::{Bind, Typed, Usage};
+use gfx::texture::{self, ImageInfoCommon};
+use gfx::{CommandBuffer, Encoder, Factory};
+use gfx_device_gl::Resources;
+
+/// A rectangle of lightmap texels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LightmapRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl LightmapRect {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> LightmapRect {
+        LightmapRect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn right(&self) -> u32 {
+        self.x + self.width
+    }
+
+    pub fn bottom(&self) -> u32 {
+        self.y + self.height
+    }
+
+    /// Returns whether the two rectangles overlap or share an edge.
+    pub fn touches(&self, other: &LightmapRect) -> bool {
+        self.x <= other.right()
+            && other.x <= self.right()
+            && self.y <= other.bottom()
+            && other.y <= self.bottom()
+    }
+
+    /// Returns the smallest rectangle containing both rectangles.
+    pub fn union(&self, other: &LightmapRect) -> LightmapRect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        LightmapRect {
+            x,
+            y,
+            width: self.right().max(other.right()) - x,
+            height: self.bottom().max(other.bottom()) - y,
+        }
+    }
+}
+
+/// Returns the per-frame upload limit for the given value of `r_lightmap_maxupdates`.
+///
+/// Values of zero or less (or NaN) mean there is no limit.
+pub fn max_updates(value: f32) -> Option<usize> {
+    if value >= 1.0 {
+        Some(value as usize)
+    } else {
+        None
+    }
+}
+
+/// Collects changed lightmap regions and hands them out as batched uploads.
+///
+/// Touching regions of the same lightmap are merged into their bounding rectangle, trading a few
+/// redundant texels for fewer upload calls. Lightmaps are uploaded in the order they were first
+/// marked, so that updates deferred by the per-frame limit are never starved.
+#[derive(Debug, Default)]
+pub struct LightmapUpdates {
+    dirty: HashMap<usize, Vec<LightmapRect>>,
+    order: VecDeque<usize>,
+}
+
+impl LightmapUpdates {
+    pub fn new() -> LightmapUpdates {
+        LightmapUpdates::default()
+    }
+
+    /// Marks a region of the lightmap `lightmap_id` as needing to be uploaded.
+    pub fn mark_dirty(&mut self, lightmap_id: usize, rect: LightmapRect) {
+        if !self.dirty.contains_key(&lightmap_id) {
+            self.order.push_back(lightmap_id);
+        }
+
+        let rects = self.dirty.entry(lightmap_id).or_insert_with(Vec::new);
+
+        // a merged rectangle may now touch others, so keep merging until nothing changes
+        let mut merged = rect;
+        while let Some(i) = rects.iter().position(|r| r.touches(&merged)) {
+            merged = merged.union(&rects.swap_remove(i));
+        }
+        rects.push(merged);
+    }
+
+    /// Returns the number of uploads waiting.
+    pub fn pending(&self) -> usize {
+        self.dirty.values().map(|r| r.len()).sum()
+    }
+
+    /// Takes up to `max_updates` uploads, or all of them if there is no limit.
+    ///
+    /// Anything left over stays queued for the next call.
+    pub fn take(&mut self, max_updates: Option<usize>) -> Vec<(usize, LightmapRect)> {
+        let mut updates = Vec::new();
+
+        while let Some(lightmap_id) = self.order.pop_front() {
+            let mut rects = self.dirty.remove(&lightmap_id).unwrap_or_default();
+            while let Some(rect) = rects.pop() {
+                if max_updates.map_or(false, |max| updates.len() >= max) {
+                    rects.push(rect);
+                    break;
+                }
+
+                updates.push((lightmap_id, rect));
+            }
+
+            if !rects.is_empty() {
+                // out of budget: this lightmap goes first next time
+                self.dirty.insert(lightmap_id, rects);
+                self.order.push_front(lightmap_id);
+                break;
+            }
+        }
+
+        updates
+    }
+}
+
+/// The placement of a face's lightmap in world space.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LightmapSurface {
+    pub normal: Vector3<f32>,
+    pub dist: f32,
+    pub s_vector: Vector3<f32>,
+    pub s_offset: f32,
+    pub t_vector: Vector3<f32>,
+    pub t_offset: f32,
+    pub texture_mins: [f32; 2],
+    pub width: usize,
+    pub height: usize,
+}
+
+impl LightmapSurface {
+    /// Returns the lightmap placement of the face `face_id`, if it has a lightmap.
+    pub fn from_face(bsp_data: &BspData, face_id: usize) -> Option<LightmapSurface> {
+        let (width, height, _) = bsp_data.face_lightmap(face_id)?;
+        let face = &bsp_data.faces()[face_id];
+        let plane = &bsp_data.planes()[face.plane_id];
+        let texinfo = &bsp_data.texinfo()[face.texinfo_id];
+
+        Some(LightmapSurface {
+            normal: plane.get_normal(),
+            dist: -plane.point_dist(Vector3::new(0.0, 0.0, 0.0)),
+            s_vector: texinfo.s_vector,
+            s_offset: texinfo.s_offset,
+            t_vector: texinfo.t_vector,
+            t_offset: texinfo.t_offset,
+            texture_mins: [face.texture_mins[0] as f32, face.texture_mins[1] as f32],
+            width,
+            height,
+        })
+    }
+}
+
+/// Adds the light of a dynamic light at `origin` to the RGBA luxels of a lightmap.
+///
+/// Light falls off linearly with distance from the light, following the original engine's
+/// `R_AddDynamicLights`, and is scaled by `intensity`. Returns whether any luxel was reached.
+pub fn add_dynamic_light(
+    luxels: &mut [u8],
+    surface: &LightmapSurface,
+    origin: Vector3<f32>,
+    radius: f32,
+    intensity: f32,
+) -> bool {
+    let plane_dist = surface.normal.dot(origin) - surface.dist;
+    let rad = radius - plane_dist.abs();
+    if rad <= 0.0 {
+        return false;
+    }
+
+    // the point on the surface's plane closest to the light
+    let impact = origin - surface.normal * plane_dist;
+    let local_s = impact.dot(surface.s_vector) + surface.s_offset - surface.texture_mins[0];
+    let local_t = impact.dot(surface.t_vector) + surface.t_offset - surface.texture_mins[1];
+
+    let mut reached = false;
+    for t in 0..surface.height {
+        let td = (local_t - t as f32 * LUXEL_SIZE).abs();
+        for s in 0..surface.width {
+            let sd = (local_s - s as f32 * LUXEL_SIZE).abs();
+
+            // cheap approximation of the distance used by the original engine
+            let dist = if sd > td { sd + td / 2.0 } else { td + sd / 2.0 };
+            if dist < rad {
+                let luxel = LUXEL_BYTES * (t * surface.width + s);
+                for channel in luxels[luxel..luxel + 3].iter_mut() {
+                    *channel = (*channel as f32 + (rad - dist) * intensity).min(255.0) as u8;
+                }
+                reached = true;
+            }
+        }
+    }
+
+    reached
+}
+
+struct LightmapPage {
+    // the page as loaded, without any dynamic light
+    static_luxels: Vec<u8>,
+    luxels: Vec<u8>,
+    texture: Texture<Resources, R8_G8_B8_A8>,
+    view: ShaderResourceView<Resources, [f32; 4]>,
+}
+
+/// The lightmap atlas pages of a brush model, which dynamic lights can brighten.
+///
+/// Lights are queued with `add_dynamic_light` and applied by `update`, which then forgets them, so
+/// they have to be added again every frame they should be visible. Each light only visits the
+/// faces it can reach in the model's BSP tree.
+pub struct Lightmaps {
+    size: usize,
+    pages: Vec<LightmapPage>,
+    bsp_data: Rc<BspData>,
+    render_node_id: usize,
+    surfaces: Vec<(LightmapSurface, LightmapAtlasRect)>,
+    // maps BSP face IDs to their index in `surfaces`
+    face_surfaces: HashMap<usize, usize>,
+    lights: Vec<(Vector3<f32>, f32, f32)>,
+    // surfaces that hold dynamic light and must be restored once it's gone
+    lit: HashSet<usize>,
+    updates: LightmapUpdates,
+}
+
+impl Lightmaps {
+    /// Uploads the pages of `atlas` as textures that can be updated.
+    ///
+    /// `surfaces` lists the ID of each face with a lightmap in the atlas along with where it was
+    /// placed. Dynamic lights only reach the faces listed here, found by walking the tree of
+    /// `bsp_data` from `render_node_id`.
+    pub fn new<F>(
+        factory: &mut F,
+        atlas: &LightmapAtlas,
+        bsp_data: Rc<BspData>,
+        render_node_id: usize,
+        surfaces: Vec<(usize, LightmapSurface, LightmapAtlasRect)>,
+    ) -> Result<Lightmaps, Error>
+    where
+        F: Factory<Resources>,
+    {
+        let size = atlas.size();
+        let mut pages = Vec::with_capacity(atlas.page_count());
+        for page_id in 0..atlas.page_count() {
+            let luxels = atlas.page_luxels(page_id);
+            let info = texture::Info {
+                kind: texture::Kind::D2(size as u16, size as u16, texture::AaMode::Single),
+                levels: 1,
+                format: R8_G8_B8_A8::get_surface_type(),
+                bind: Bind::SHADER_RESOURCE | Bind::TRANSFER_DST,
+                usage: Usage::Dynamic,
+            };
+            let raw = factory.create_texture_raw(
+                info,
+                Some(Unorm::get_channel_type()),
+                Some((&[luxels], texture::Mipmap::Provided)),
+            )?;
+            let texture = Typed::new(raw);
+            let view = factory.view_texture_as_shader_resource::<(R8_G8_B8_A8, Unorm)>(
+                &texture,
+                (0, 0),
+                Swizzle::new(),
+            )?;
+
+            pages.push(LightmapPage {
+                static_luxels: luxels.to_vec(),
+                luxels: luxels.to_vec(),
+                texture,
+                view,
+            });
+        }
+
+        let face_surfaces = surfaces
+            .iter()
+            .enumerate()
+            .map(|(id, &(face_id, _, _))| (face_id, id))
+            .collect();
+        let surfaces = surfaces
+            .into_iter()
+            .map(|(_, surface, rect)| (surface, rect))
+            .collect();
+
+        Ok(Lightmaps {
+            size,
+            pages,
+            bsp_data,
+            render_node_id,
+            surfaces,
+            face_surfaces,
+            lights: Vec::new(),
+            lit: HashSet::new(),
+            updates: LightmapUpdates::new(),
+        })
+    }
+
+    /// Returns the texture of the atlas page `page_id`.
+    pub fn view(&self, page_id: usize) -> &ShaderResourceView<Resources, [f32; 4]> {
+        &self.pages[page_id].view
+    }
+
+    /// Queues a dynamic light at `origin`, in the model's own coordinates, for the next `update`.
+    pub fn add_dynamic_light(&mut self, origin: Vector3<f32>, radius: f32, intensity: f32) {
+        if radius > 0.0 && intensity > 0.0 {
+            self.lights.push((origin, radius, intensity));
+        }
+    }
+
+    /// Recomputes the lightmaps reached by the queued lights, or left lit by last frame's lights,
+    /// and uploads up to `max_updates` changed regions.
+    ///
+    /// The queued lights are cleared.
+    pub fn update<C>(
+        &mut self,
+        encoder: &mut Encoder<Resources, C>,
+        max_updates: Option<usize>,
+    ) -> Result<(), Error>
+    where
+        C: CommandBuffer<Resources>,
+    {
+        let size = self.size;
+
+        // find the lights that may reach each surface
+        let mut surface_lights: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut face_ids = Vec::new();
+        for (light_id, &(origin, radius, _)) in self.lights.iter().enumerate() {
+            face_ids.clear();
+            self.bsp_data.find_lit_faces(self.render_node_id, origin, radius, &mut face_ids);
+            for face_id in face_ids.iter() {
+                if let Some(&id) = self.face_surfaces.get(face_id) {
+                    surface_lights.entry(id).or_insert_with(Vec::new).push(light_id);
+                }
+            }
+        }
+
+        // surfaces lit last frame are visited too, so that they can be restored
+        let visit: BTreeSet<usize> = surface_lights
+            .keys()
+            .chain(self.lit.iter())
+            .cloned()
+            .collect();
+
+        let mut lit = HashSet::new();
+        for id in visit {
+            let (ref surface, rect) = self.surfaces[id];
+            let was_lit = self.lit.contains(&id);
+            let page = &mut self.pages[rect.page];
+            let row_bytes = LUXEL_BYTES * rect.width;
+            let mut luxels = Vec::with_capacity(row_bytes * rect.height);
+            for y in rect.y..rect.y + rect.height {
+                let start = LUXEL_BYTES * (y * size + rect.x);
+                luxels.extend_from_slice(&page.static_luxels[start..start + row_bytes]);
+            }
+
+            let mut reached = false;
+            for &light_id in surface_lights.get(&id).map_or(&[][..], |l| &l[..]) {
+                let (origin, radius, intensity) = self.lights[light_id];
+                reached |= add_dynamic_light(&mut luxels, surface, origin, radius, intensity);
+            }
+
+            if reached {
+                lit.insert(id);
+            }
+
+            if !reached && !was_lit {
+                continue;
+            }
+
+            let mut changed = false;
+            for (row_id, row) in luxels.chunks(row_bytes).enumerate() {
+                let start = LUXEL_BYTES * ((rect.y + row_id) * size + rect.x);
+                let dst = &mut page.luxels[start..start + row_bytes];
+                if dst != row {
+                    dst.copy_from_slice(row);
+                    changed = true;
+                }
+            }
+
+            if changed {
+                self.updates.mark_dirty(
+                    rect.page,
+                    LightmapRect::new(
+                        rect.x as u32,
+                        rect.y as u32,
+                        rect.width as u32,
+                        rect.height as u32,
+                    ),
+                );
+            }
+        }
+
+        self.lit = lit;
+        self.lights.clear();
+
+        for (page_id, rect) in self.updates.take(max_updates) {
+            let page = &self.pages[page_id];
+            let mut data = Vec::with_capacity((rect.width * rect.height) as usize);
+            for y in rect.y..rect.bottom() {
+                let row = y as usize * size;
+                let start = LUXEL_BYTES * (row + rect.x as usize);
+                let end = LUXEL_BYTES * (row + rect.right() as usize);
+                for luxel in page.luxels[start..end].chunks(LUXEL_BYTES) {
+                    data.push([luxel[0], luxel[1], luxel[2], luxel[3]]);
+                }
+            }
+
+            let info = ImageInfoCommon {
+                xoffset: rect.x as u16,
+                yoffset: rect.y as u16,
+                zoffset: 0,
+                width: rect.width as u16,
+                height: rect.height as u16,
+                depth: 1,
+                format: (),
+                mipmap: 0,
+            };
+            encoder
+                .update_texture::<R8_G8_B8_A8, (R8_G8_B8_A8, Unorm)>(
+                    &page.texture,
+                    None,
+                    info,
+                    &data,
+                )
+                .map_err(|e| format_err!("lightmap upload failed: {}", e))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rect_touches_and_union() {
+        let a = LightmapRect::new(0, 0, 4, 4);
+        let adjacent = LightmapRect::new(4, 0, 4, 4);
+        let apart = LightmapRect::new(5, 5, 2, 2);
+
+        assert!(a.touches(&adjacent));
+        assert!(!a.touches(&apart));
+        assert_eq!(a.union(&adjacent), LightmapRect::new(0, 0, 8, 4));
+        assert_eq!(a.union(&apart), LightmapRect::new(0, 0, 7, 7));
+    }
+
+    #[test]
+    fn test_add_dynamic_light() {
+        // a 3x1 lightmap on the floor, with its first luxel at the origin
+        let surface = LightmapSurface {
+            normal: Vector3::new(0.0, 0.0, 1.0),
+            dist: 0.0,
+            s_vector: Vector3::new(1.0, 0.0, 0.0),
+            s_offset: 0.0,
+            t_vector: Vector3::new(0.0, 1.0, 0.0),
+            t_offset: 0.0,
+            texture_mins: [0.0, 0.0],
+            width: 3,
+            height: 1,
+        };
+        let mut luxels = vec![10, 10, 10, 255, 10, 20, 30, 255, 250, 250, 250, 255];
+
+        // 40 units above the first luxel, so 60 units of radius are left at the surface
+        let origin = Vector3::new(0.0, 0.0, 40.0);
+        assert!(add_dynamic_light(&mut luxels, &surface, origin, 100.0, 1.0));
+        assert_eq!(luxels, vec![70, 70, 70, 255, 54, 64, 74, 255, 255, 255, 255, 255]);
+
+        // too far above the surface to reach it
+        let origin = Vector3::new(0.0, 0.0, 120.0);
+        assert!(!add_dynamic_light(&mut luxels, &surface, origin, 100.0, 1.0));
+        assert_eq!(luxels, vec![70, 70, 70, 255, 54, 64, 74, 255, 255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn test_merge_chain() {
+        let mut updates = LightmapUpdates::new();
+        updates.mark_dirty(0, LightmapRect::new(0, 0, 2, 2));
+        updates.mark_dirty(0, LightmapRect::new(6, 0, 2, 2));
+        assert_eq!(updates.pending(), 2);
+
+        // bridges the two existing rectangles
+        updates.mark_dirty(0, LightmapRect::new(2, 0, 4, 2));
+        assert_eq!(updates.take(None), vec![(0, LightmapRect::new(0, 0, 8, 2))]);
+        assert_eq!(updates.pending(), 0);
+    }
+
+    #[test]
+    fn test_rockets_upload_count() {
+        // three rockets flying past a wall made of 8 lightmapped faces, each lighting an area that
+        // overlaps the light from the frames before it
+        let mut updates = LightmapUpdates::new();
+        let mut naive_uploads = 0;
+        for rocket in 0..3 {
+            for step in 0..4 {
+                for face in 0..8 {
+                    updates.mark_dirty(face, LightmapRect::new(step * 2, rocket * 3, 4, 4));
+                    naive_uploads += 1;
+                }
+            }
+        }
+
+        let batched = updates.take(None);
+        assert_eq!(naive_uploads, 96);
+        assert_eq!(batched.len(), 8);
+    }
+
+    #[test]
+    fn test_max_updates_defers() {
+        let mut updates = LightmapUpdates::new();
+        for face in 0..5 {
+            updates.mark_dirty(face, LightmapRect::new(0, 0, 4, 4));
+        }
+        updates.mark_dirty(1, LightmapRect::new(10, 10, 2, 2));
+
+        let first: Vec<usize> = updates.take(Some(2)).iter().map(|u| u.0).collect();
+        assert_eq!(first, vec![0, 1]);
+        assert_eq!(updates.pending(), 4);
+
+        // the rest of lightmap 1 comes before lightmaps that were marked later
+        updates.mark_dirty(0, LightmapRect::new(0, 0, 4, 4));
+        let second: Vec<usize> = updates.take(Some(3)).iter().map(|u| u.0).collect();
+        assert_eq!(second, vec![1, 2, 3]);
+
+        let rest: Vec<usize> = updates.take(None).iter().map(|u| u.0).collect();
+        assert_eq!(rest, vec![4, 0]);
+    }
+
+    #[test]
+    fn test_max_updates_cvar() {
+        assert_eq!(max_updates(0.0), None);
+        assert_eq!(max_updates(-1.0), None);
+        assert_eq!(max_updates(::std::f32::NAN), None);
+        assert_eq!(max_updates(32.0), Some(32));
+    }
+}
@@ -0,0 +1,145 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! The plaque and signon progress shown while a level loads.
+
+use client::render::bitmap::BitmapTexture;
+use client::render::glyph::{GlyphRendererCommand, GLYPH_HEIGHT, GLYPH_WIDTH};
+use client::render::{GraphicsPackage, PipelineData2d, PipelineState2d};
+use common::net::SignOnStage;
+use common::vfs::Vfs;
+use common::wad::QPic;
+
+use cgmath::{Matrix4, SquareMatrix};
+use failure::Error;
+use gfx::{CommandBuffer, Encoder, Slice};
+use gfx_device_gl::Resources;
+
+const LOADING_PATH: &str = "gfx/loading.lmp";
+
+/// Returns a description of what the client is waiting for at the given signon stage.
+pub fn stage_text(stage: SignOnStage) -> &'static str {
+    match stage {
+        SignOnStage::Not => "Receiving server info",
+        SignOnStage::Prespawn => "Precaching models",
+        SignOnStage::ClientInfo => "Sending client info",
+        SignOnStage::Begin => "Spawning",
+        SignOnStage::Done => "Entering game",
+    }
+}
+
+// the position that centers something `size` wide in a display `display_size` wide
+fn centered(display_size: u32, size: u32) -> i32 {
+    (display_size as i32 - size as i32) / 2
+}
+
+pub struct LoadingRenderer {
+    // None if the game data doesn't have the plaque
+    plaque: Option<BitmapTexture>,
+    slice: Slice<Resources>,
+}
+
+impl LoadingRenderer {
+    pub fn new(vfs: &Vfs, gfx_pkg: &GraphicsPackage) -> Result<LoadingRenderer, Error> {
+        let plaque = match vfs.open(LOADING_PATH) {
+            Ok(f) => Some(BitmapTexture::from_qpic(
+                &mut *gfx_pkg.factory_mut(),
+                &QPic::load(f)?,
+                gfx_pkg.palette(),
+            )?),
+            Err(_) => {
+                warn!("{} not found, loading screen will only show progress", LOADING_PATH);
+                None
+            }
+        };
+
+        let slice = Slice::new_match_vertex_buffer(&gfx_pkg.quad_vertex_buffer());
+
+        Ok(LoadingRenderer { plaque, slice })
+    }
+
+    /// Draws the loading plaque in the center of the screen with the signon progress below it.
+    pub fn render<C>(
+        &self,
+        encoder: &mut Encoder<Resources, C>,
+        gfx_pkg: &GraphicsPackage,
+        user_data: &mut PipelineData2d,
+        display_width: u32,
+        display_height: u32,
+        stage: SignOnStage,
+    ) -> Result<(), Error>
+    where
+        C: CommandBuffer<Resources>,
+    {
+        let pso: &PipelineState2d = gfx_pkg.pipeline_2d();
+
+        // TODO: replace with cvar scr_conscale
+        let display_width = display_width / 2;
+        let display_height = display_height / 2;
+
+        // y is measured up from the bottom of the screen, so the text goes below the plaque
+        let mut text_y = display_height as i32 / 2;
+        if let Some(ref plaque) = self.plaque {
+            let y = centered(display_height, plaque.height());
+            user_data.vertex_buffer = gfx_pkg.quad_vertex_buffer();
+            user_data.transform = plaque
+                .transform(
+                    display_width,
+                    display_height,
+                    centered(display_width, plaque.width()),
+                    y,
+                )
+                .into();
+            user_data.sampler.0 = plaque.view();
+            encoder.draw(&self.slice, pso, user_data);
+
+            text_y = y - 2 * GLYPH_HEIGHT as i32;
+        }
+
+        let text = stage_text(stage);
+        gfx_pkg.glyph_renderer().render_command(
+            encoder,
+            pso,
+            user_data,
+            display_width,
+            display_height,
+            Matrix4::identity(),
+            GlyphRendererCommand::text(
+                text.to_owned(),
+                centered(display_width, (text.len() * GLYPH_WIDTH) as u32),
+                text_y,
+            ),
+        )?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_centered() {
+        // the 144x24 plaque on a 320x200 screen
+        assert_eq!(centered(320, 144), 88);
+        assert_eq!(centered(200, 24), 88);
+
+        // text wider than the screen hangs off both sides
+        assert_eq!(centered(16, 32), -8);
+    }
+}
@@ -19,28 +19,43 @@
 // SOFTWARE.
 
 pub mod alias;
+pub mod bbox;
 pub mod bitmap;
 pub mod brush;
 pub mod console;
 pub mod glyph;
 pub mod hud;
+pub mod lightmap;
+pub mod loading;
 pub mod menu;
+pub mod particle;
+pub mod postprocess;
+pub mod screenshot;
+pub mod sky;
 pub mod world;
 
-use std::cell::{Ref, RefCell, RefMut};
+use std::cell::{Cell, Ref, RefCell, RefMut};
 use std::collections::HashMap;
 use std::io::BufReader;
 use std::ops::DerefMut;
 use std::rc::Rc;
 
-use client::ClientEntity;
-use common::console::Console;
+use client::light::DynamicLight;
+use client::particle::Particles;
+use client::{ClientEntity, Fog};
+use common::bsp::BspLight;
+use common::console::{Console, CvarRegistry};
+use common::engine;
 use common::model::{Model, ModelKind};
+use common::net::EntityEffects;
 use common::vfs::Vfs;
-use common::wad::{QPic, Wad};
+use common::wad::{self, LumpKind, QPic, Wad};
 
 use byteorder::ReadBytesExt;
-use cgmath::{Deg, Euler, Matrix3, Matrix4, SquareMatrix, Vector3, Zero};
+use cgmath::{
+    Deg, Euler, InnerSpace, Matrix, Matrix3, Matrix4, Quaternion, SquareMatrix, Transform,
+    Vector3, Vector4, Zero,
+};
 use chrono::Duration;
 use failure::Error;
 use flame;
@@ -55,17 +70,80 @@ use gfx::{self, IndexBuffer, Slice};
 use gfx_device_gl::{Factory, Resources};
 
 pub use gfx::format::DepthStencil as DepthFormat;
+
+/// The depth format of the offscreen scene target.
+///
+/// Reverse-Z only improves depth precision with a floating-point depth buffer.
+pub type SceneDepthFormat = gfx::format::Depth32F;
 pub use gfx::format::Srgba8 as ColorFormat;
 
-use self::alias::AliasRenderer;
+use self::alias::{AliasRenderer, KeyframeLerp};
+use self::bbox::{BboxRenderer, ModelBounds};
 use self::bitmap::BitmapTexture;
-use self::brush::BrushRenderer;
+use self::brush::{BrushRenderer, LiquidAlpha, TextureQuality};
 use self::console::ConsoleRenderer;
 use self::glyph::GlyphRenderer;
+use self::particle::ParticleRenderer;
+use self::postprocess::{PostProcessPipelineState, ScreenEffect};
+use self::sky::SkyRenderer;
 use self::world::WorldRenderer;
 
 const PALETTE_SIZE: usize = 768;
 
+// minimum light level for the view model so it's never completely black
+const MIN_VIEWMODEL_LIGHT: f32 = 24.0 / 255.0;
+
+// how long a cached entity light sample stays valid, so animated lightstyles still show
+const LIGHT_CACHE_TIMEOUT_MS: i64 = 100;
+
+// position of the view model relative to the view origin (forward, left, up)
+const VIEWMODEL_OFFSET: [f32; 3] = [15.0, -10.0, 0.0];
+
+// position of each weapon's barrel relative to the view model origin (forward, left, up)
+const MUZZLE_OFFSETS: &[(&str, [f32; 3])] = &[
+    ("progs/v_shot.mdl", [26.0, 0.0, -4.0]),
+    ("progs/v_shot2.mdl", [26.0, 0.0, -4.0]),
+    ("progs/v_nail.mdl", [24.0, 0.0, -5.0]),
+    ("progs/v_nail2.mdl", [22.0, 0.0, -6.0]),
+    ("progs/v_rock.mdl", [20.0, 0.0, -6.0]),
+    ("progs/v_rock2.mdl", [22.0, 0.0, -4.0]),
+    ("progs/v_light.mdl", [24.0, 0.0, -6.0]),
+];
+
+// light added to the view model while its muzzle is flashing
+const MUZZLE_FLASH_LIGHT: f32 = 0.5;
+
+/// The smallest allowed value of `r_scale`.
+pub const MIN_SCENE_SCALE: f32 = 0.1;
+
+/// The distance to the near clipping plane.
+pub const NEAR_CLIP: f32 = 4.0;
+
+/// The distance to the far clipping plane if `r_farclip` is invalid.
+pub const DEFAULT_FAR_CLIP: f32 = 4096.0;
+
+// dimensions of the missing texture checkerboard and its squares, in texels
+const DUMMY_CHECKER_SIZE: usize = 8;
+const DUMMY_CHECKER_SQUARE: usize = 4;
+
+// radius of the shadow blob drawn under alias models
+const SHADOW_RADIUS: f32 = 16.0;
+
+// maximum distance to search for a floor under an entity when drawing its shadow
+const SHADOW_MAX_DIST: f32 = 256.0;
+
+// opacity of a shadow cast by an entity standing directly on the floor
+const SHADOW_ALPHA: f32 = 0.5;
+
+// distance to raise shadows above the floor to avoid z-fighting
+const SHADOW_LIFT: f32 = 0.5;
+
+// maximum length of a directional shadow relative to its width
+const SHADOW_MAX_STRETCH: f32 = 3.0;
+
+// fraction of r_maxdrawdist over which entities fade out
+const DRAW_DIST_FADE_FRACTION: f32 = 0.1;
+
 // TODO: per-API coordinate system conversions
 pub static VERTEX_SHADER_GLSL: &[u8] = br#"
 #version 430
@@ -74,12 +152,37 @@ layout (location = 0) in vec3 a_Pos;
 layout (location = 1) in vec2 a_Texcoord;
 
 out vec2 f_texcoord;
+out float f_fogDistance;
+
+uniform mat4 u_Transform;
+
+void main() {
+    f_texcoord = a_Texcoord;
+    gl_Position = u_Transform * vec4(-a_Pos.y, a_Pos.z, -a_Pos.x, 1.0);
+    f_fogDistance = gl_Position.w;
+}
+"#;
+
+// alias models are shaded by how far each vertex normal faces a fixed light direction
+pub static ALIAS_VERTEX_SHADER_GLSL: &[u8] = br#"
+#version 430
+
+layout (location = 0) in vec3 a_Pos;
+layout (location = 1) in vec2 a_Texcoord;
+layout (location = 2) in vec3 a_Normal;
+
+out vec2 f_texcoord;
+out float f_fogDistance;
+out float f_shade;
 
 uniform mat4 u_Transform;
+uniform vec3 u_ShadeVector;
 
 void main() {
     f_texcoord = a_Texcoord;
+    f_shade = 1.0 + 0.3 * dot(a_Normal, u_ShadeVector);
     gl_Position = u_Transform * vec4(-a_Pos.y, a_Pos.z, -a_Pos.x, 1.0);
+    f_fogDistance = gl_Position.w;
 }
 "#;
 
@@ -87,8 +190,14 @@ pub static FRAGMENT_SHADER_GLSL: &[u8] = br#"
 #version 430
 
 in vec2 f_texcoord;
+in float f_fogDistance;
+in float f_shade;
 
 uniform sampler2D u_Texture;
+uniform float u_Light;
+uniform float u_Alpha;
+uniform vec4 u_FogColor;
+uniform float u_FogDensity;
 
 out vec4 Target0;
 
@@ -97,7 +206,32 @@ void main() {
     if (color.a == 0) {
         discard;
     } else {
-        Target0 = color;
+        color.rgb *= u_Light * f_shade;
+
+        // exponential squared fog
+        float fog_exponent = u_FogDensity * f_fogDistance;
+        float fog_factor = clamp(exp2(-1.442695 * fog_exponent * fog_exponent), 0.0, 1.0);
+        Target0 = vec4(mix(u_FogColor.rgb, color.rgb, fog_factor), color.a * u_Alpha);
+    }
+}"#;
+
+pub static SHADOW_FRAGMENT_SHADER_GLSL: &[u8] = br#"
+#version 430
+
+in vec2 f_texcoord;
+in float f_fogDistance;
+
+uniform float u_Alpha;
+
+out vec4 Target0;
+
+void main() {
+    // fade out towards the edge of the blob
+    float dist = length(f_texcoord * 2.0 - 1.0);
+    if (dist > 1.0) {
+        discard;
+    } else {
+        Target0 = vec4(0.0, 0.0, 0.0, u_Alpha * (1.0 - dist * dist));
     }
 }"#;
 
@@ -124,6 +258,8 @@ pub static FRAGMENT_SHADER_2D_GLSL: &[u8] = br#"
 in vec2 f_texcoord;
 
 uniform sampler2D u_Texture;
+uniform float u_Alpha;
+uniform vec3 u_Tint;
 
 out vec4 Target0;
 
@@ -132,7 +268,7 @@ void main() {
     if (color.a == 0) {
         discard;
     } else {
-        Target0 = color;
+        Target0 = vec4(color.rgb * u_Tint, color.a * u_Alpha);
     }
 }
 "#;
@@ -161,7 +297,7 @@ impl GraphicsPackage {
         console: Rc<RefCell<Console>>,
     ) -> GraphicsPackage {
         let palette = Palette::load(&vfs, "gfx/palette.lmp");
-        let gfx_wad = Wad::load(vfs.open("gfx.wad").unwrap()).unwrap();
+        let gfx_wad = Wad::load(vfs, "gfx.wad").unwrap();
         let quad_vertex_buffer = factory.create_vertex_buffer(&QUAD_VERTICES);
 
         let glyph_renderer = Rc::new(
@@ -223,16 +359,23 @@ impl GraphicsPackage {
         }
     }
 
+    /// Loads the picture `name` as a texture.
+    ///
+    /// Pictures are read from `gfx.wad` if it has a picture lump named after the file (such as
+    /// `disc` for `gfx/disc.lmp`), and from the file itself otherwise.
     pub fn texture_from_qpic<S>(&self, vfs: &Vfs, name: S) -> BitmapTexture
     where
         S: AsRef<str>,
     {
-        BitmapTexture::from_qpic(
-            self.factory.borrow_mut().deref_mut(),
-            &QPic::load(vfs.open(name).unwrap()).unwrap(),
-            &self.palette,
-        )
-        .unwrap()
+        let name = name.as_ref();
+        let lump_name = wad::lump_name(name);
+        let qpic = match self.gfx_wad.entry_kind(lump_name) {
+            Some(LumpKind::QPic) => self.gfx_wad.open_qpic(lump_name).unwrap(),
+            _ => QPic::load(vfs.open(name).unwrap()).unwrap(),
+        };
+
+        BitmapTexture::from_qpic(self.factory.borrow_mut().deref_mut(), &qpic, &self.palette)
+            .unwrap()
     }
 
     pub fn palette(&self) -> &Palette {
@@ -288,6 +431,8 @@ impl GraphicsPackage {
             vertex_buffer: self.quad_vertex_buffer(),
             transform: Matrix4::identity().into(),
             sampler: (self.dummy_diffuse_texture(), self.sampler()),
+            alpha: 1.0,
+            tint: [1.0; 3],
             out_color: self.color_target(),
             out_depth: self.depth_stencil(),
         }
@@ -330,22 +475,99 @@ static QUAD_SLICE: Slice<Resources> = Slice {
     buffer: IndexBuffer::Auto,
 };
 
+// fullscreen quad for upscaling the scene target. Render targets are stored bottom-up, so unlike
+// QUAD_VERTICES the texture coordinates are not flipped.
+static BLIT_VERTICES: [Vertex2d; 6] = [
+    Vertex2d {
+        pos: [-1.0, -1.0],
+        texcoord: [0.0, 0.0],
+    }, // bottom left
+    Vertex2d {
+        pos: [-1.0, 1.0],
+        texcoord: [0.0, 1.0],
+    }, // top left
+    Vertex2d {
+        pos: [1.0, 1.0],
+        texcoord: [1.0, 1.0],
+    }, // top right
+    Vertex2d {
+        pos: [-1.0, -1.0],
+        texcoord: [0.0, 0.0],
+    }, // bottom left
+    Vertex2d {
+        pos: [1.0, 1.0],
+        texcoord: [1.0, 1.0],
+    }, // top right
+    Vertex2d {
+        pos: [1.0, -1.0],
+        texcoord: [1.0, 0.0],
+    }, // bottom right
+];
+
+// unit quad in the XY plane, used for shadow blobs
+static SHADOW_VERTICES: [Vertex; 6] = [
+    Vertex {
+        pos: [-1.0, -1.0, 0.0],
+        texcoord: [0.0, 0.0],
+    }, // bottom left
+    Vertex {
+        pos: [1.0, -1.0, 0.0],
+        texcoord: [1.0, 0.0],
+    }, // bottom right
+    Vertex {
+        pos: [1.0, 1.0, 0.0],
+        texcoord: [1.0, 1.0],
+    }, // top right
+    Vertex {
+        pos: [-1.0, -1.0, 0.0],
+        texcoord: [0.0, 0.0],
+    }, // bottom left
+    Vertex {
+        pos: [1.0, 1.0, 0.0],
+        texcoord: [1.0, 1.0],
+    }, // top right
+    Vertex {
+        pos: [-1.0, 1.0, 0.0],
+        texcoord: [0.0, 1.0],
+    }, // top left
+];
+
 gfx_defines! {
     vertex Vertex {
         pos: [f32; 3] = "a_Pos",
         texcoord: [f32; 2] = "a_Texcoord",
     }
 
+    vertex VertexNormal {
+        normal: [f32; 3] = "a_Normal",
+    }
+
     constant Locals {
         transform: [[f32; 4]; 4] = "u_Transform",
     }
 
     pipeline pipe {
         vertex_buffer: gfx::VertexBuffer<Vertex> = (),
+        normal_buffer: gfx::VertexBuffer<VertexNormal> = (),
         transform: gfx::Global<[[f32; 4]; 4]> = "u_Transform",
+        shade_vector: gfx::Global<[f32; 3]> = "u_ShadeVector",
         sampler: gfx::TextureSampler<[f32; 4]> = "u_Texture",
-        out_color: gfx::RenderTarget<ColorFormat> = "Target0",
-        out_depth: gfx::DepthTarget<DepthFormat> = gfx::preset::depth::LESS_EQUAL_WRITE,
+        light: gfx::Global<f32> = "u_Light",
+        alpha: gfx::Global<f32> = "u_Alpha",
+        fog_color: gfx::Global<[f32; 4]> = "u_FogColor",
+        fog_density: gfx::Global<f32> = "u_FogDensity",
+        out_color: gfx::BlendTarget<ColorFormat> =
+            ("Target0", gfx::state::ColorMask::all(), gfx::preset::blend::ALPHA),
+        out_depth: gfx::DepthTarget<SceneDepthFormat> = gfx::preset::depth::LESS_EQUAL_WRITE,
+    }
+
+    pipeline pipe_shadow {
+        vertex_buffer: gfx::VertexBuffer<Vertex> = (),
+        transform: gfx::Global<[[f32; 4]; 4]> = "u_Transform",
+        alpha: gfx::Global<f32> = "u_Alpha",
+        out_color: gfx::BlendTarget<ColorFormat> =
+            ("Target0", gfx::state::ColorMask::all(), gfx::preset::blend::ALPHA),
+        out_depth: gfx::DepthTarget<SceneDepthFormat> = gfx::preset::depth::LESS_EQUAL_TEST,
     }
 }
 
@@ -363,7 +585,10 @@ gfx_defines! {
         vertex_buffer: gfx::VertexBuffer<Vertex2d> = (),
         transform: gfx::Global<[[f32; 4]; 4]> = "u_Transform",
         sampler: gfx::TextureSampler<[f32; 4]> = "u_Texture",
-        out_color: gfx::RenderTarget<ColorFormat> = "Target0",
+        alpha: gfx::Global<f32> = "u_Alpha",
+        tint: gfx::Global<[f32; 3]> = "u_Tint",
+        out_color: gfx::BlendTarget<ColorFormat> =
+            ("Target0", gfx::state::ColorMask::all(), gfx::preset::blend::ALPHA),
         out_depth: gfx::DepthTarget<DepthFormat> = gfx::preset::depth::PASS_TEST,
     }
 }
@@ -377,6 +602,7 @@ pub struct Camera {
     angles: Vector3<Deg<f32>>,
     projection: Matrix4<f32>,
 
+    view: Matrix4<f32>,
     transform: Matrix4<f32>,
 }
 
@@ -387,16 +613,17 @@ impl Camera {
         projection: Matrix4<f32>,
     ) -> Camera {
         // negate the camera origin and angles
-        // TODO: the OpenGL coordinate conversion is hardcoded here! XXX
-        let converted_origin = Vector3::new(-origin.y, origin.z, -origin.x);
+        let converted_origin = quake_to_gl().transform_vector(origin);
         let translation = Matrix4::from_translation(-converted_origin);
         let rotation = Matrix4::from(Euler::new(angles.x, -angles.y, -angles.z));
+        let view = rotation * translation;
 
         Camera {
             origin,
             angles,
             projection,
-            transform: projection * rotation * translation,
+            view,
+            transform: projection * view,
         }
     }
 
@@ -404,17 +631,420 @@ impl Camera {
         self.origin
     }
 
+    /// Returns the projection matrix.
+    pub fn projection(&self) -> Matrix4<f32> {
+        self.projection
+    }
+
+    /// Returns the view matrix, which maps OpenGL world space to view space.
+    pub fn view(&self) -> Matrix4<f32> {
+        self.view
+    }
+
     pub fn transform(&self) -> Matrix4<f32> {
         self.transform
     }
 }
 
+/// Returns the matrix that converts Quake world space (X forward, Y left, Z up) to OpenGL world
+/// space, as the vertex shaders do.
+pub fn quake_to_gl() -> Matrix4<f32> {
+    Matrix4::from_cols(
+        Vector4::new(0.0, 0.0, -1.0, 0.0),
+        Vector4::new(-1.0, 0.0, 0.0, 0.0),
+        Vector4::new(0.0, 1.0, 0.0, 0.0),
+        Vector4::unit_w(),
+    )
+}
+
+/// The six clipping planes of a view, used to skip geometry that can't be on screen.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frustum {
+    // plane normals point inwards: a point p is inside if dot(plane.xyz, p) + plane.w >= 0
+    planes: [Vector4<f32>; 6],
+}
+
+impl Frustum {
+    /// Extracts the frustum planes from a matrix mapping some space to clip space.
+    ///
+    /// The planes are expressed in the space the matrix maps from, so the transform of a model
+    /// gives planes that can be tested against the model's own coordinates.
+    pub fn from_transform(transform: Matrix4<f32>) -> Frustum {
+        let w = transform.row(3);
+        let mut planes = [Vector4::zero(); 6];
+        for axis in 0..3 {
+            let row = transform.row(axis);
+            planes[2 * axis] = w + row;
+            planes[2 * axis + 1] = w - row;
+        }
+
+        Frustum { planes }
+    }
+
+    /// Returns whether any part of the axis-aligned box from `mins` to `maxs` may be inside.
+    pub fn intersects_box(&self, mins: Vector3<f32>, maxs: Vector3<f32>) -> bool {
+        self.planes.iter().all(|plane| {
+            // the corner furthest along the plane normal
+            let corner = Vector3::new(
+                if plane.x >= 0.0 { maxs.x } else { mins.x },
+                if plane.y >= 0.0 { maxs.y } else { mins.y },
+                if plane.z >= 0.0 { maxs.z } else { mins.z },
+            );
+
+            plane.truncate().dot(corner) + plane.w >= 0.0
+        })
+    }
+}
+
+/// Returns the opacity of an entity `dist` units from the camera.
+///
+/// Entities fade out linearly over the last `DRAW_DIST_FADE_FRACTION` of `max_dist` and are
+/// invisible beyond it. A `max_dist` of 0 or less disables culling.
+pub fn draw_distance_alpha(dist: f32, max_dist: f32) -> f32 {
+    if max_dist <= 0.0 {
+        return 1.0;
+    }
+
+    let fade_dist = max_dist * DRAW_DIST_FADE_FRACTION;
+    ((max_dist - dist) / fade_dist).max(0.0).min(1.0)
+}
+
+/// An alias or brush model entity that is drawn with blending after all opaque entities.
+///
+/// `light` only applies to alias models.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TranslucentModel {
+    pub ent_id: usize,
+    pub dist: f32,
+    pub alpha: f32,
+    pub light: f32,
+}
+
+/// Sorts translucent models from farthest to nearest, so that each blends over the ones behind it.
+pub fn sort_back_to_front(models: &mut [TranslucentModel]) {
+    models.sort_by(|a, b| {
+        b.dist
+            .partial_cmp(&a.dist)
+            .unwrap_or(::std::cmp::Ordering::Equal)
+    });
+}
+
+/// Returns the depth test state for the given depth convention.
+///
+/// With reverse-Z, the near plane is mapped to depth 1 and the far plane to depth 0, so nearer
+/// fragments have *greater* depth values and the depth buffer must be cleared to 0.
+pub fn depth_state(reverse_z: bool) -> gfx::state::Depth {
+    if reverse_z {
+        gfx::state::Depth {
+            fun: gfx::state::Comparison::GreaterEqual,
+            write: true,
+        }
+    } else {
+        gfx::preset::depth::LESS_EQUAL_WRITE
+    }
+}
+
+/// Remaps a projection matrix so that clip-space depth is reversed.
+///
+/// OpenGL maps clip-space Z from [-1, 1] onto the depth range [0, 1]; negating Z after
+/// projection sends the near plane to depth 1 and the far plane to depth 0. Floating-point
+/// depth values are densest near 0, so this distributes precision more evenly over distance.
+pub fn reverse_z_projection(projection: Matrix4<f32>) -> Matrix4<f32> {
+    Matrix4::from_nonuniform_scale(1.0, 1.0, -1.0) * projection
+}
+
+/// Returns the value the depth buffer should be cleared to.
+pub fn depth_clear_value(reverse_z: bool) -> f32 {
+    if reverse_z {
+        0.0
+    } else {
+        1.0
+    }
+}
+
+/// Returns the far clip distance for the given value of `r_farclip`.
+///
+/// Values that would place the far plane at or in front of the near plane fall back to
+/// `DEFAULT_FAR_CLIP`.
+pub fn far_clip(value: f32) -> f32 {
+    if value.is_nan() || value <= NEAR_CLIP {
+        DEFAULT_FAR_CLIP
+    } else {
+        value
+    }
+}
+
+/// Returns the position of the view model relative to the view origin, before rotation.
+///
+/// `offset` is added to the default position; in left-handed mode the result is mirrored to the
+/// other side of the view.
+pub fn viewmodel_offset(offset: Vector3<f32>, lefthand: bool) -> Vector3<f32> {
+    let mut position = Vector3::from(VIEWMODEL_OFFSET) + offset;
+    if lefthand {
+        position.y = -position.y;
+    }
+
+    position
+}
+
+/// Returns the position of the barrel of the weapon with view model `model_name` relative to the
+/// view model origin, or `None` if the weapon has no muzzle flash.
+pub fn muzzle_offset(model_name: &str) -> Option<Vector3<f32>> {
+    MUZZLE_OFFSETS
+        .iter()
+        .find(|&&(name, _)| name == model_name)
+        .map(|&(_, offset)| Vector3::from(offset))
+}
+
+/// Returns the world position of the muzzle of a view model.
+///
+/// `viewmodel_offset` is the view model position from `viewmodel_offset`. In left-handed mode the
+/// muzzle is mirrored along with the model.
+pub fn viewmodel_muzzle_origin(
+    view_origin: Vector3<f32>,
+    angles: Vector3<Deg<f32>>,
+    viewmodel_offset: Vector3<f32>,
+    muzzle_offset: Vector3<f32>,
+    lefthand: bool,
+) -> Vector3<f32> {
+    let mut muzzle_offset = muzzle_offset;
+    if lefthand {
+        muzzle_offset.y = -muzzle_offset.y;
+    }
+
+    let rotate: Matrix3<f32> = Euler::new(angles.x, angles.y, angles.z).into();
+    view_origin + rotate * (viewmodel_offset + muzzle_offset)
+}
+
+struct LightSample {
+    origin: Vector3<f32>,
+    time: Duration,
+    light: f32,
+}
+
+/// A cache of the light level sampled at each entity's position.
+///
+/// Sampling the lightmap under an entity means tracing through the BSP tree, so entities that
+/// haven't moved reuse their last sample until it times out.
+pub struct LightCache {
+    samples: HashMap<usize, LightSample>,
+}
+
+impl LightCache {
+    pub fn new() -> LightCache {
+        LightCache {
+            samples: HashMap::new(),
+        }
+    }
+
+    /// Returns the light level for entity `ent_id` at `origin`.
+    ///
+    /// The cached sample is used unless the entity has moved more than `threshold` units since it
+    /// was taken or it is older than `LIGHT_CACHE_TIMEOUT_MS`, in which case `sample` is called to
+    /// take a new one. A `threshold` of 0 or less disables the cache.
+    pub fn light<F>(
+        &mut self,
+        ent_id: usize,
+        origin: Vector3<f32>,
+        time: Duration,
+        threshold: f32,
+        sample: F,
+    ) -> f32
+    where
+        F: FnOnce() -> f32,
+    {
+        if !(threshold > 0.0) {
+            self.samples.remove(&ent_id);
+            return sample();
+        }
+
+        if let Some(cached) = self.samples.get(&ent_id) {
+            let age = time - cached.time;
+            if (origin - cached.origin).magnitude2() <= threshold * threshold
+                && age >= Duration::zero()
+                && age < Duration::milliseconds(LIGHT_CACHE_TIMEOUT_MS)
+            {
+                return cached.light;
+            }
+        }
+
+        let light = sample();
+        self.samples.insert(
+            ent_id,
+            LightSample {
+                origin,
+                time,
+                light,
+            },
+        );
+
+        light
+    }
+}
+
+/// Returns the size of the scene target for a `width` by `height` display at the given `r_scale`.
+///
+/// The scale is clamped to `[MIN_SCENE_SCALE, 1.0]`, and invalid values render at native
+/// resolution.
+pub fn scaled_size(width: u16, height: u16, scale: f32) -> (u16, u16) {
+    if scale.is_nan() || scale >= 1.0 {
+        return (width, height);
+    }
+
+    let scale = scale.max(MIN_SCENE_SCALE);
+    let scaled = |x: u16| ((x as f32 * scale).round() as u16).max(1);
+    (scaled(width), scaled(height))
+}
+
+/// A rectangular region of the display, in pixels, with the origin at the bottom left.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Viewport {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Viewport {
+    /// Returns a viewport covering the entire display.
+    pub fn full(display_w: u32, display_h: u32) -> Viewport {
+        Viewport {
+            x: 0,
+            y: 0,
+            width: display_w,
+            height: display_h,
+        }
+    }
+
+    /// Splits the display into `count` viewports of equal width, ordered left to right.
+    ///
+    /// Any leftover columns are given to the rightmost viewport.
+    pub fn side_by_side(display_w: u32, display_h: u32, count: u32) -> Vec<Viewport> {
+        if count == 0 {
+            return Vec::new();
+        }
+
+        let width = display_w / count;
+        (0..count)
+            .map(|i| Viewport {
+                x: i * width,
+                y: 0,
+                width: if i == count - 1 {
+                    display_w - i * width
+                } else {
+                    width
+                },
+                height: display_h,
+            })
+            .collect()
+    }
+
+    /// Returns a transform mapping normalized device coordinates onto this viewport.
+    ///
+    /// Anything drawn as if it filled the whole display is squeezed into this viewport instead.
+    pub fn ndc_transform(&self, display_w: u32, display_h: u32) -> Matrix4<f32> {
+        let scale_x = self.width as f32 / display_w as f32;
+        let scale_y = self.height as f32 / display_h as f32;
+        let center_x = (2 * self.x + self.width) as f32 / display_w as f32 - 1.0;
+        let center_y = (2 * self.y + self.height) as f32 / display_h as f32 - 1.0;
+
+        Matrix4::from_translation([center_x, center_y, 0.0].into())
+            * Matrix4::from_nonuniform_scale(scale_x, scale_y, 1.0)
+    }
+}
+
+/// An offscreen target the scene is rendered to before being copied to the display.
+struct SceneTarget {
+    color_texture: Texture<Resources, R8_G8_B8_A8>,
+    color_view: ShaderResourceView<Resources, [f32; 4]>,
+    color_target: RenderTargetView<Resources, ColorFormat>,
+    depth_target: DepthStencilView<Resources, SceneDepthFormat>,
+    blit_pipeline: PostProcessPipelineState,
+    blit_vertex_buffer: Buffer<Resources, Vertex2d>,
+    nearest_sampler: Sampler<Resources>,
+    linear_sampler: Sampler<Resources>,
+}
+
+impl SceneTarget {
+    fn new<F>(factory: &mut F, width: u16, height: u16) -> Result<SceneTarget, Error>
+    where
+        F: gfx::Factory<Resources>,
+    {
+        let (color_texture, color_view, color_target) =
+            factory.create_render_target::<ColorFormat>(width, height)?;
+        let depth_target =
+            factory.create_depth_stencil_view_only::<SceneDepthFormat>(width, height)?;
+
+        let blit_pipeline = postprocess::create_pipeline_state(factory)?;
+        let blit_vertex_buffer = factory.create_vertex_buffer(&BLIT_VERTICES);
+
+        let nearest_sampler = factory.create_sampler(SamplerInfo::new(
+            gfx::texture::FilterMethod::Scale,
+            gfx::texture::WrapMode::Clamp,
+        ));
+        let linear_sampler = factory.create_sampler(SamplerInfo::new(
+            gfx::texture::FilterMethod::Bilinear,
+            gfx::texture::WrapMode::Clamp,
+        ));
+
+        Ok(SceneTarget {
+            color_texture,
+            color_view,
+            color_target,
+            depth_target,
+            blit_pipeline,
+            blit_vertex_buffer,
+            nearest_sampler,
+            linear_sampler,
+        })
+    }
+}
+
 pub struct SceneRenderer {
     pipeline: PipelineState<Resources, <pipe::Data<Resources> as PipelineData<Resources>>::Meta>,
+
+    // light levels sampled for alias model entities
+    light_cache: RefCell<LightCache>,
+
+    // muzzle positions of weapon view models by model ID
+    muzzle_offsets: HashMap<usize, Vector3<f32>>,
+
+    // where the view model's muzzle flash was last drawn
+    muzzle_flash_origin: Cell<Option<Vector3<f32>>>,
+
+    // mirroring a model reverses its winding order, so it needs the opposite front face to cull
+    mirrored_pipeline:
+        PipelineState<Resources, <pipe::Data<Resources> as PipelineData<Resources>>::Meta>,
+    // translucent models write their depth first, then blend only their nearest surfaces, so
+    // that they don't show their own back faces
+    translucent_depth_pipeline:
+        PipelineState<Resources, <pipe::Data<Resources> as PipelineData<Resources>>::Meta>,
+    translucent_pipeline:
+        PipelineState<Resources, <pipe::Data<Resources> as PipelineData<Resources>>::Meta>,
+    shadow_pipeline:
+        PipelineState<Resources, <pipe_shadow::Data<Resources> as PipelineData<Resources>>::Meta>,
+    shadow_vertex_buffer: Buffer<Resources, Vertex>,
+    // light entities of the world model, used to cast directional shadows
+    lights: Vec<BspLight>,
+    bbox_renderer: BboxRenderer,
+    // bounding boxes drawn by r_showbboxes, by model ID
+    model_bounds: Vec<Option<ModelBounds>>,
+    color_target: RenderTargetView<Resources, ColorFormat>,
+    depth_target: DepthStencilView<Resources, SceneDepthFormat>,
     world_renderer: WorldRenderer,
+    sky_renderer: Option<SkyRenderer>,
     brush_renderers: HashMap<usize, BrushRenderer>,
     alias_renderers: HashMap<usize, AliasRenderer>,
+    particle_renderer: ParticleRenderer,
     // spr_renderers: ...,
+    lightmap_bilinear: bool,
+    texture_filter: gfx::texture::FilterMethod,
+    reverse_z: bool,
+
+    // the scene is rendered offscreen so that it can be scaled and have screen effects applied
+    scene_target: SceneTarget,
+    display_color_target: RenderTargetView<Resources, ColorFormat>,
+    display_depth_target: DepthStencilView<Resources, DepthFormat>,
 }
 
 impl SceneRenderer {
@@ -422,11 +1052,25 @@ impl SceneRenderer {
         models: &[Model],
         worldmodel_id: usize,
         gfx_pkg: &mut GraphicsPackage,
+        reverse_z: bool,
+        texture_gamma: f32,
+        dummy_pattern: DummyTexturePattern,
+        subdivide_size: Option<f32>,
+        scale: f32,
+        viewport: Viewport,
     ) -> Result<SceneRenderer, Error> {
         use gfx::traits::FactoryExt;
+
+        let display_color_target = gfx_pkg.color_target();
+        let display_depth_target = gfx_pkg.depth_stencil();
+        let (width, height) = scaled_size(viewport.width as u16, viewport.height as u16, scale);
+        let scene_target = SceneTarget::new(gfx_pkg.factory_mut().deref_mut(), width, height)?;
+        let color_target = scene_target.color_target.clone();
+        let depth_target = scene_target.depth_target.clone();
+
         let shader_set = gfx_pkg
             .factory_mut()
-            .create_shader_set(VERTEX_SHADER_GLSL, FRAGMENT_SHADER_GLSL)
+            .create_shader_set(ALIAS_VERTEX_SHADER_GLSL, FRAGMENT_SHADER_GLSL)
             .unwrap();
 
         let rasterizer = gfx::state::Rasterizer {
@@ -443,25 +1087,140 @@ impl SceneRenderer {
                 &shader_set,
                 gfx::Primitive::TriangleList,
                 rasterizer,
-                pipe::new(),
+                pipe::Init {
+                    out_depth: depth_state(reverse_z),
+                    ..pipe::new()
+                },
+            )
+            .unwrap();
+
+        let mirrored_pipeline = gfx_pkg
+            .factory_mut()
+            .create_pipeline_state(
+                &shader_set,
+                gfx::Primitive::TriangleList,
+                gfx::state::Rasterizer {
+                    front_face: gfx::state::FrontFace::CounterClockwise,
+                    ..rasterizer
+                },
+                pipe::Init {
+                    out_depth: depth_state(reverse_z),
+                    ..pipe::new()
+                },
+            )
+            .unwrap();
+
+        let translucent_depth_pipeline = gfx_pkg
+            .factory_mut()
+            .create_pipeline_state(
+                &shader_set,
+                gfx::Primitive::TriangleList,
+                rasterizer,
+                pipe::Init {
+                    out_color: (
+                        "Target0",
+                        gfx::state::ColorMask::empty(),
+                        gfx::preset::blend::ALPHA,
+                    ),
+                    out_depth: depth_state(reverse_z),
+                    ..pipe::new()
+                },
+            )
+            .unwrap();
+
+        let translucent_pipeline = gfx_pkg
+            .factory_mut()
+            .create_pipeline_state(
+                &shader_set,
+                gfx::Primitive::TriangleList,
+                rasterizer,
+                pipe::Init {
+                    out_depth: gfx::state::Depth {
+                        write: false,
+                        ..depth_state(reverse_z)
+                    },
+                    ..pipe::new()
+                },
+            )
+            .unwrap();
+
+        let shadow_shader_set = gfx_pkg
+            .factory_mut()
+            .create_shader_set(VERTEX_SHADER_GLSL, SHADOW_FRAGMENT_SHADER_GLSL)
+            .unwrap();
+
+        // shadows are drawn on top of the floor, so pull them slightly towards the camera
+        let shadow_rasterizer = gfx::state::Rasterizer {
+            cull_face: gfx::state::CullFace::Nothing,
+            offset: Some(if reverse_z {
+                gfx::state::Offset(1, 1)
+            } else {
+                gfx::state::Offset(-1, -1)
+            }),
+            ..rasterizer
+        };
+
+        let shadow_pipeline = gfx_pkg
+            .factory_mut()
+            .create_pipeline_state(
+                &shadow_shader_set,
+                gfx::Primitive::TriangleList,
+                shadow_rasterizer,
+                pipe_shadow::Init {
+                    out_depth: gfx::state::Depth {
+                        write: false,
+                        ..depth_state(reverse_z)
+                    },
+                    ..pipe_shadow::new()
+                },
             )
             .unwrap();
 
+        let shadow_vertex_buffer = gfx_pkg.factory_mut().create_vertex_buffer(&SHADOW_VERTICES);
+
+        let bbox_renderer = BboxRenderer::new(
+            gfx_pkg.factory_mut().deref_mut(),
+            color_target.clone(),
+            depth_target.clone(),
+            reverse_z,
+        )?;
+        let model_bounds = models.iter().map(ModelBounds::from_model).collect();
+
+        // texture gamma is baked into the palette at upload time
+        let palette = gfx_pkg.palette().with_gamma(texture_gamma);
+
         let mut maybe_world_renderer = None;
+        let mut sky_renderer = None;
         let mut brush_renderers = HashMap::new();
         let mut alias_renderers = HashMap::new();
+        let mut muzzle_offsets = HashMap::new();
         for (i, model) in models.iter().enumerate() {
+            if let Some(offset) = muzzle_offset(model.name()) {
+                muzzle_offsets.insert(i, offset);
+            }
+
             if i == worldmodel_id {
                 match *model.kind() {
                     ModelKind::Brush(ref bmodel) => {
                         debug!("model {}: world model", i);
                         maybe_world_renderer = Some(WorldRenderer::new(
                             &bmodel,
-                            gfx_pkg.palette(),
+                            &palette,
                             gfx_pkg.factory_mut().deref_mut(),
-                            gfx_pkg.color_target(),
-                            gfx_pkg.depth_stencil(),
+                            color_target.clone(),
+                            depth_target.clone(),
+                            reverse_z,
+                            dummy_pattern,
+                            subdivide_size,
                         )?);
+                        sky_renderer = SkyRenderer::new(
+                            &bmodel,
+                            &palette,
+                            gfx_pkg.factory_mut().deref_mut(),
+                            color_target.clone(),
+                            depth_target.clone(),
+                            reverse_z,
+                        )?;
                     }
 
                     _ => bail!("Invalid kind for worldmodel"),
@@ -474,10 +1233,13 @@ impl SceneRenderer {
                             i,
                             BrushRenderer::new(
                                 &bmodel,
-                                gfx_pkg.palette(),
+                                &palette,
                                 gfx_pkg.factory_mut().deref_mut(),
-                                gfx_pkg.color_target(),
-                                gfx_pkg.depth_stencil(),
+                                color_target.clone(),
+                                depth_target.clone(),
+                                reverse_z,
+                                dummy_pattern,
+                                subdivide_size,
                             )?,
                         );
                     }
@@ -488,7 +1250,7 @@ impl SceneRenderer {
                             i,
                             AliasRenderer::new(
                                 &amodel,
-                                gfx_pkg.palette(),
+                                &palette,
                                 gfx_pkg.factory_mut().deref_mut(),
                             )?,
                         );
@@ -505,38 +1267,312 @@ impl SceneRenderer {
             Some(w) => w,
             None => bail!("No worldmodel provided"),
         };
+        let lights = world_renderer.bsp_data().lights();
+
+        let particle_renderer = ParticleRenderer::new(
+            &palette,
+            gfx_pkg.factory_mut().deref_mut(),
+            color_target.clone(),
+            depth_target.clone(),
+            reverse_z,
+        )?;
 
         Ok(SceneRenderer {
             pipeline,
+            light_cache: RefCell::new(LightCache::new()),
+            muzzle_offsets,
+            muzzle_flash_origin: Cell::new(None),
+            mirrored_pipeline,
+            translucent_depth_pipeline,
+            translucent_pipeline,
+            shadow_pipeline,
+            shadow_vertex_buffer,
+            lights,
+            bbox_renderer,
+            model_bounds,
+            color_target,
+            depth_target,
             world_renderer,
+            sky_renderer,
             brush_renderers,
             alias_renderers,
+            particle_renderer,
+            lightmap_bilinear: true,
+            texture_filter: TextureQuality::Low.filter_method(),
+            reverse_z,
+            scene_target,
+            display_color_target,
+            display_depth_target,
         })
     }
 
-    pub fn render<C>(
+    /// Returns whether this renderer uses reverse-Z.
+    ///
+    /// `r_reversez` is latched: the depth convention is fixed when a level's renderer is built,
+    /// so projections must follow this rather than the cvar.
+    pub fn reverse_z(&self) -> bool {
+        self.reverse_z
+    }
+
+    /// Returns the size of the offscreen target the scene is rendered to.
+    pub fn scene_size(&self) -> (u32, u32) {
+        let (width, height, _, _) = self.scene_target.color_target.get_dimensions();
+        (width as u32, height as u32)
+    }
+
+    /// Queues a copy of the last rendered scene, before screen effects, into a new download
+    /// buffer.
+    ///
+    /// The buffer holds RGBA pixels with the bottom row first, and can only be read once the
+    /// encoder has been flushed.
+    pub fn download_scene<F, C>(
+        &self,
+        factory: &mut F,
+        encoder: &mut gfx::Encoder<Resources, C>,
+    ) -> Result<Buffer<Resources, [u8; 4]>, Error>
+    where
+        F: gfx::Factory<Resources>,
+        C: gfx::CommandBuffer<Resources>,
+    {
+        use gfx::format::Formatted;
+        use gfx::memory::Typed;
+
+        let (width, height, _, _) = self.scene_target.color_target.get_dimensions();
+        let download = factory
+            .create_download_buffer::<[u8; 4]>(width as usize * height as usize)
+            .map_err(|e| format_err!("scene download buffer creation failed: {}", e))?;
+
+        encoder
+            .copy_texture_to_buffer_raw(
+                self.scene_target.color_texture.raw(),
+                None,
+                texture::RawImageInfo {
+                    xoffset: 0,
+                    yoffset: 0,
+                    zoffset: 0,
+                    width,
+                    height,
+                    depth: 0,
+                    format: ColorFormat::get_format(),
+                    mipmap: 0,
+                },
+                download.raw(),
+                0,
+            )
+            .map_err(|e| format_err!("scene copy failed: {:?}", e))?;
+
+        Ok(download)
+    }
+
+    /// Returns the world position of the view model's muzzle flash, if it was flashing when the
+    /// scene was last rendered.
+    pub fn muzzle_flash_origin(&self) -> Option<Vector3<f32>> {
+        self.muzzle_flash_origin.get()
+    }
+
+    /// Switches lightmap filtering between bilinear and nearest-neighbor.
+    ///
+    /// The lightmap samplers are only rebuilt if the setting has changed.
+    pub fn set_lightmap_bilinear<F>(&mut self, factory: &mut F, bilinear: bool)
+    where
+        F: gfx::Factory<Resources>,
+    {
+        if bilinear == self.lightmap_bilinear {
+            return;
+        }
+
+        self.world_renderer.set_lightmap_bilinear(factory, bilinear);
+        for brush_renderer in self.brush_renderers.values_mut() {
+            brush_renderer.set_lightmap_bilinear(factory, bilinear);
+        }
+        self.lightmap_bilinear = bilinear;
+    }
+
+    /// Adds dynamic lights to the lightmaps of the world and of brush model entities.
+    ///
+    /// Lights are scaled by `intensity` (the value of `r_dynamic`), and none are drawn if it is
+    /// zero, though lightmaps lit on earlier frames are still restored. At most `max_updates`
+    /// lightmap regions are uploaded per model.
+    pub fn update_dynamic_lights<'a, C, I>(
+        &mut self,
+        encoder: &mut gfx::Encoder<Resources, C>,
+        lights: I,
+        entities: &[ClientEntity],
+        intensity: f32,
+        max_updates: Option<usize>,
+    ) -> Result<(), Error>
+    where
+        C: gfx::CommandBuffer<Resources>,
+        I: Iterator<Item = &'a DynamicLight>,
+    {
+        let _guard = flame::start_guard("SceneRenderer::update_dynamic_lights");
+        if intensity > 0.0 {
+            for light in lights {
+                self.world_renderer.add_dynamic_light(light.origin, light.radius, intensity);
+
+                // brush models are lit in their own space (rotation is ignored, as in the
+                // original engine)
+                for ent in entities.iter().skip(1) {
+                    if let Some(r) = self.brush_renderers.get_mut(&ent.get_model_id()) {
+                        let origin = light.origin - ent.get_origin();
+                        r.add_dynamic_light(origin, light.radius, intensity);
+                    }
+                }
+            }
+        }
+
+        self.world_renderer.update_lightmaps(encoder, max_updates)?;
+        for brush_renderer in self.brush_renderers.values_mut() {
+            brush_renderer.update_lightmaps(encoder, max_updates)?;
+        }
+
+        Ok(())
+    }
+
+    /// Switches the filter for world and brush model textures.
+    ///
+    /// Like `set_lightmap_bilinear`, the samplers are only rebuilt if the filter has changed, so
+    /// this can be called every frame.
+    pub fn set_texture_filter<F>(&mut self, factory: &mut F, filter: gfx::texture::FilterMethod)
+    where
+        F: gfx::Factory<Resources>,
+    {
+        if filter == self.texture_filter {
+            return;
+        }
+
+        self.world_renderer.set_texture_filter(factory, filter);
+        for brush_renderer in self.brush_renderers.values_mut() {
+            brush_renderer.set_texture_filter(factory, filter);
+        }
+        self.texture_filter = filter;
+    }
+
+    /// Renders the scene into `viewport`, upscaling it if `r_scale` is below 1.
+    ///
+    /// If the camera is inside water, slime or lava, the scene is tinted and warped as it is
+    /// copied to the display. The camera's aspect ratio should match that of the viewport.
+    pub fn render<C>(
         &self,
         encoder: &mut gfx::Encoder<Resources, C>,
         user_data: &mut pipe::Data<Resources>,
+        viewport: &Viewport,
         entities: &[ClientEntity],
+        particles: &Particles,
         view_ent_id: usize,
         view_model_id: usize,
+        view_model_frame: usize,
+        view_model_prev_frame: usize,
+        view_model_drop: f32,
         time: Duration,
         camera: &Camera,
         lightstyle_values: &[f32],
+        fog: &Fog,
+        cvars: &CvarRegistry,
     ) -> Result<(), Error>
     where
         C: gfx::CommandBuffer<Resources>,
     {
-        flame::start("render_world");
-        self.world_renderer.render(
+        let target = &self.scene_target;
+        encoder.clear(&target.color_target, [0.0, 0.0, 0.0, 1.0]);
+        encoder.clear_depth(&target.depth_target, depth_clear_value(self.reverse_z));
+
+        user_data.out_color = target.color_target.clone();
+        user_data.out_depth = target.depth_target.clone();
+        let result = self.render_scene(
             encoder,
+            user_data,
+            entities,
+            particles,
+            view_ent_id,
+            view_model_id,
+            view_model_frame,
+            view_model_prev_frame,
+            view_model_drop,
             time,
             camera,
-            Vector3::zero(),
-            Vector3::new(Deg(0.0), Deg(0.0), Deg(0.0)),
             lightstyle_values,
-        )?;
+            fog,
+            cvars,
+        );
+        user_data.out_color = self.display_color_target.clone();
+        result?;
+
+        let sampler = if cvars.get_value("r_scalefilter").unwrap_or(0.0) != 0.0 {
+            target.linear_sampler.clone()
+        } else {
+            target.nearest_sampler.clone()
+        };
+
+        let contents = self.world_renderer.bsp_data().leaf_contents_at(camera.origin());
+        let effect = ScreenEffect::for_contents(contents, cvars);
+
+        let (display_width, display_height, _, _) = self.display_color_target.get_dimensions();
+        let data = postprocess::pipe_postprocess::Data {
+            vertex_buffer: target.blit_vertex_buffer.clone(),
+            transform: viewport
+                .ndc_transform(display_width as u32, display_height as u32)
+                .into(),
+            sampler: (target.color_view.clone(), sampler),
+            tint: effect.tint,
+            tint_strength: effect.tint_strength,
+            warp: effect.warp,
+            time: engine::duration_to_f32(time),
+            out_color: self.display_color_target.clone(),
+            out_depth: self.display_depth_target.clone(),
+        };
+        encoder.draw(&QUAD_SLICE, &target.blit_pipeline, &data);
+
+        Ok(())
+    }
+
+    fn render_scene<C>(
+        &self,
+        encoder: &mut gfx::Encoder<Resources, C>,
+        user_data: &mut pipe::Data<Resources>,
+        entities: &[ClientEntity],
+        particles: &Particles,
+        view_ent_id: usize,
+        view_model_id: usize,
+        view_model_frame: usize,
+        view_model_prev_frame: usize,
+        view_model_drop: f32,
+        time: Duration,
+        camera: &Camera,
+        lightstyle_values: &[f32],
+        fog: &Fog,
+        cvars: &CvarRegistry,
+    ) -> Result<(), Error>
+    where
+        C: gfx::CommandBuffer<Resources>,
+    {
+        let shadow_mode = cvars.get_value("r_shadows").unwrap_or(0.0);
+        let max_draw_dist = cvars.get_value("r_maxdrawdist").unwrap_or(0.0);
+        let liquid_alpha = LiquidAlpha::from_cvars(cvars);
+        let light_cache_dist = cvars.get_value("r_lightcache_dist").unwrap_or(0.0);
+        let translucent_prepass = cvars.get_value("r_translucent_prepass").unwrap_or(1.0) != 0.0;
+        let draw_entities = cvars.get_value("r_drawentities").unwrap_or(1.0) != 0.0;
+        let lerp_models = cvars.get_value("r_lerpmodels").unwrap_or(0.0) != 0.0;
+        let keyframe_lerp = |ent: &ClientEntity| {
+            if lerp_models {
+                Some(KeyframeLerp {
+                    prev_keyframe_id: ent.get_prev_frame_id(),
+                    factor: ent.get_frame_lerp(),
+                })
+            } else {
+                None
+            }
+        };
+        let mut translucent_models = Vec::new();
+
+        user_data.fog_color = fog.shader_color();
+        user_data.fog_density = fog.shader_density();
+
+        flame::start("render_world");
+        self.world_renderer.render(encoder, time, camera, lightstyle_values, fog, &liquid_alpha)?;
+        if let Some(ref sky_renderer) = self.sky_renderer {
+            sky_renderer.render(encoder, time, camera);
+        }
         flame::end("render_world");
 
         flame::start("render_entities");
@@ -546,27 +1582,106 @@ impl SceneRenderer {
                 if let Some(ref alias_renderer) = self.alias_renderers.get(&view_model_id) {
                     let angles = ent.get_angles();
                     let rotate: Matrix3<f32> = Euler::new(angles.x, angles.y, angles.z).into();
-                    let offset = rotate * Vector3::new(15.0, -10.0, 0.0);
-                    let position = ent.get_origin() + offset;
-                    // TODO: need keyframe, texture ID
-                    // also need to disable depth testing to stop viewmodel clipping into walls
-                    alias_renderer.render(
+                    let lefthand = cvars.get_value("r_viewmodel_lefthand").unwrap_or(0.0) != 0.0;
+                    let offset = viewmodel_offset(
+                        Vector3::new(
+                            cvars.get_value("r_viewmodel_offset_x").unwrap_or(0.0),
+                            cvars.get_value("r_viewmodel_offset_y").unwrap_or(0.0),
+                            cvars.get_value("r_viewmodel_offset_z").unwrap_or(0.0)
+                                - view_model_drop,
+                        ),
+                        lefthand,
+                    );
+                    let position = ent.get_origin() + rotate * offset;
+
+                    // the flash follows the barrel wherever view bob and sway move the model
+                    let flashing = ent.get_effects().contains(EntityEffects::MUZZLE_FLASH);
+                    self.muzzle_flash_origin.set(match self.muzzle_offsets.get(&view_model_id) {
+                        Some(muzzle) if flashing => Some(viewmodel_muzzle_origin(
+                            ent.get_origin(),
+                            angles,
+                            offset,
+                            *muzzle,
+                            lefthand,
+                        )),
+                        _ => None,
+                    });
+
+                    let mut model_transform =
+                        Matrix4::from_translation(Vector3::new(-position.y, position.z, -position.x))
+                            * Matrix4::from(Euler::new(angles.x, angles.y, angles.z));
+                    // mirror across the model's left/right axis (X in OpenGL coordinates)
+                    let pipeline = if lefthand {
+                        model_transform =
+                            model_transform * Matrix4::from_nonuniform_scale(-1.0, 1.0, 1.0);
+                        &self.mirrored_pipeline
+                    } else {
+                        &self.pipeline
+                    };
+
+                    user_data.alpha = 1.0;
+                    user_data.shade_vector = alias::shade_vector(angles.y);
+
+                    // light the viewmodel from the lightmap at the player's position, brightened
+                    // while the muzzle is flashing
+                    let light = self
+                        .world_renderer
+                        .bsp_data()
+                        .light_at_point(ent.get_origin(), lightstyle_values)
+                        .unwrap_or(1.0)
+                        .max(MIN_VIEWMODEL_LIGHT);
+                    user_data.light = match self.muzzle_flash_origin.get() {
+                        Some(_) => (light + MUZZLE_FLASH_LIGHT).min(1.0),
+                        None => light,
+                    };
+
+                    // TODO: disable depth testing to stop viewmodel clipping into walls
+                    alias_renderer.render_with_transform(
                         encoder,
-                        &self.pipeline,
+                        pipeline,
                         user_data,
                         time,
                         camera,
-                        position,
-                        angles,
-                        0,
+                        model_transform,
+                        view_model_frame,
+                        keyframe_lerp(ent).map(|lerp| KeyframeLerp {
+                            prev_keyframe_id: view_model_prev_frame,
+                            ..lerp
+                        }),
                         0,
                     )?;
                 }
                 continue;
             }
 
+            if !draw_entities || ent.is_pruned() {
+                continue;
+            }
+
+            // cull distant entities, fading them out near the cutoff
+            let dist = (ent.get_origin() - camera.origin()).magnitude();
+            let alpha = draw_distance_alpha(dist, max_draw_dist);
+            if alpha <= 0.0 {
+                continue;
+            }
+
             let model_id = ent.get_model_id();
             if let Some(ref brush_renderer) = self.brush_renderers.get(&model_id) {
+                // fading brush models and liquids don't write depth, so they have to wait like
+                // translucent alias models
+                if alpha < 1.0 || brush_renderer.has_translucent_faces(&liquid_alpha) {
+                    translucent_models.push(TranslucentModel {
+                        ent_id,
+                        dist,
+                        alpha,
+                        light: 1.0,
+                    });
+                }
+
+                if alpha < 1.0 {
+                    continue;
+                }
+
                 brush_renderer.render(
                     encoder,
                     time,
@@ -574,9 +1689,49 @@ impl SceneRenderer {
                     ent.get_origin(),
                     ent.get_angles(),
                     lightstyle_values,
+                    fog,
+                    &liquid_alpha,
                 )?;
             } else if let Some(ref alias_renderer) = self.alias_renderers.get(&model_id) {
-                // TODO: pull keyframe and texture ID
+                user_data.shade_vector = alias::shade_vector(ent.get_angles().y);
+                let bsp_data = self.world_renderer.bsp_data();
+                user_data.light = self.light_cache.borrow_mut().light(
+                    ent_id,
+                    ent.get_origin(),
+                    time,
+                    light_cache_dist,
+                    || {
+                        bsp_data
+                            .light_at_point(ent.get_origin(), lightstyle_values)
+                            .unwrap_or(1.0)
+                    },
+                );
+
+                if shadow_mode >= 2.0 {
+                    let origin = ent.get_origin();
+                    let light = dominant_light(&self.lights, origin, |l| {
+                        bsp_data
+                            .trace_line(origin, l)
+                            .map_or(false, |t| !t.start_solid() && t.is_terminal())
+                    });
+                    self.render_shadow(encoder, camera, origin, light.map(|l| l.origin));
+                } else if shadow_mode != 0.0 {
+                    self.render_shadow(encoder, camera, ent.get_origin(), None);
+                }
+
+                // translucent models are blended over everything else once it's been drawn
+                if alpha < 1.0 {
+                    translucent_models.push(TranslucentModel {
+                        ent_id,
+                        dist,
+                        alpha,
+                        light: user_data.light,
+                    });
+                    continue;
+                }
+
+                user_data.alpha = alpha;
+
                 alias_renderer.render(
                     encoder,
                     &self.pipeline,
@@ -585,15 +1740,247 @@ impl SceneRenderer {
                     camera,
                     ent.get_origin(),
                     ent.get_angles(),
-                    0,
-                    0,
+                    ent.get_frame_id(),
+                    keyframe_lerp(ent),
+                    ent.get_skin_id(),
                 )?;
             }
         }
+
         flame::end("render_entities");
 
+        // translucent world liquids are blended in with translucent entities, farthest first
+        flame::start("render_translucent");
+        let world_faces = self.world_renderer.translucent_faces(camera, &liquid_alpha);
+        let mut world_faces = &world_faces[..];
+        sort_back_to_front(&mut translucent_models);
+        for model in translucent_models.iter() {
+            let behind = world_faces.iter().take_while(|&&(_, dist)| dist >= model.dist).count();
+            self.world_renderer.render_translucent(
+                encoder,
+                time,
+                camera,
+                lightstyle_values,
+                fog,
+                &liquid_alpha,
+                &world_faces[..behind],
+            )?;
+            world_faces = &world_faces[behind..];
+
+            let ent = &entities[model.ent_id];
+            if let Some(ref brush_renderer) = self.brush_renderers.get(&ent.get_model_id()) {
+                brush_renderer.render_translucent(
+                    encoder,
+                    time,
+                    camera,
+                    ent.get_origin(),
+                    ent.get_angles(),
+                    lightstyle_values,
+                    fog,
+                    &liquid_alpha,
+                    model.alpha,
+                )?;
+                continue;
+            }
+
+            let alias_renderer = &self.alias_renderers[&ent.get_model_id()];
+            user_data.light = model.light;
+            user_data.alpha = model.alpha;
+            user_data.shade_vector = alias::shade_vector(ent.get_angles().y);
+
+            // without the depth prepass, overlapping parts of the model are blended in whatever
+            // order its triangles happen to be drawn
+            let passes = if translucent_prepass {
+                vec![&self.translucent_depth_pipeline, &self.translucent_pipeline]
+            } else {
+                vec![&self.translucent_pipeline]
+            };
+
+            for pipeline in passes {
+                alias_renderer.render(
+                    encoder,
+                    pipeline,
+                    user_data,
+                    time,
+                    camera,
+                    ent.get_origin(),
+                    ent.get_angles(),
+                    ent.get_frame_id(),
+                    keyframe_lerp(ent),
+                    ent.get_skin_id(),
+                )?;
+            }
+        }
+        self.world_renderer.render_translucent(
+            encoder,
+            time,
+            camera,
+            lightstyle_values,
+            fog,
+            &liquid_alpha,
+            world_faces,
+        )?;
+        user_data.alpha = 1.0;
+        flame::end("render_translucent");
+
+        if cvars.get_value("r_showbboxes").unwrap_or(0.0) != 0.0 {
+            for (ent_id, ent) in entities.iter().enumerate() {
+                if ent_id == 0 || ent_id == view_ent_id || ent.is_pruned() {
+                    continue;
+                }
+
+                let bounds = match self.model_bounds.get(ent.get_model_id()) {
+                    Some(&Some(ref b)) => b,
+                    _ => continue,
+                };
+
+                if let Some((min, max)) = bounds.bounds(ent.get_frame_id()) {
+                    let origin = ent.get_origin();
+                    self.bbox_renderer
+                        .render(encoder, camera, origin + min, origin + max, bounds.kind());
+                }
+            }
+        }
+
+        if cvars.get_value("r_particles").unwrap_or(1.0) != 0.0 {
+            flame::start("render_particles");
+            self.particle_renderer.render(
+                encoder,
+                particles,
+                camera,
+                cvars.get_value("r_particle_size").unwrap_or(0.0).max(0.0),
+                fog,
+            )?;
+            flame::end("render_particles");
+        }
+
         Ok(())
     }
+
+    /// Draws a shadow blob on the floor beneath `origin`.
+    ///
+    /// The blob is aligned with the plane of the floor and fades out as the entity gets farther
+    /// from it. Nothing is drawn if there is no floor within `SHADOW_MAX_DIST` units.
+    ///
+    /// If `light` is given, the shadow is cast away from it and stretched along the floor instead
+    /// of being dropped straight down.
+    fn render_shadow<C>(
+        &self,
+        encoder: &mut gfx::Encoder<Resources, C>,
+        camera: &Camera,
+        origin: Vector3<f32>,
+        light: Option<Vector3<f32>>,
+    ) where
+        C: gfx::CommandBuffer<Resources>,
+    {
+        let (dir, stretch) = shadow_direction(origin, light);
+        let end = origin + dir * SHADOW_MAX_DIST * stretch;
+        let trace = match self.world_renderer.bsp_data().trace_line(origin, end) {
+            Ok(t) => t,
+            Err(_) => return,
+        };
+
+        if trace.start_solid() {
+            return;
+        }
+
+        let normal = match trace.end_plane() {
+            Some(p) => p.get_normal(),
+            None => return,
+        };
+
+        // don't draw shadows on walls or ceilings
+        if normal.z <= 0.0 {
+            return;
+        }
+
+        let height = origin.z - trace.end_point().z;
+        let alpha = SHADOW_ALPHA * (1.0 - height / SHADOW_MAX_DIST).max(0.0);
+
+        let to_gl = |v: Vector3<f32>| quake_to_gl().transform_vector(v);
+        let floor = to_gl(trace.end_point() + normal * SHADOW_LIFT);
+        let rotation = Quaternion::from_arc(Vector3::unit_y(), to_gl(normal), None);
+
+        // turn the blob to face along the shadow and stretch it by its length
+        let horizontal = Vector3::new(dir.x, dir.y, 0.0);
+        let heading = if horizontal.magnitude2() > 0.0 {
+            Quaternion::from_arc(
+                Vector3::unit_x(),
+                to_gl(horizontal.normalize()),
+                Some(Vector3::unit_y()),
+            )
+        } else {
+            Quaternion::from_arc(Vector3::unit_x(), Vector3::unit_x(), None)
+        };
+
+        let model_transform = Matrix4::from_translation(floor)
+            * Matrix4::from(rotation)
+            * Matrix4::from(heading)
+            * Matrix4::from_nonuniform_scale(
+                SHADOW_RADIUS * stretch,
+                SHADOW_RADIUS,
+                SHADOW_RADIUS,
+            );
+
+        let data = pipe_shadow::Data {
+            vertex_buffer: self.shadow_vertex_buffer.clone(),
+            transform: (camera.transform() * model_transform).into(),
+            alpha,
+            out_color: self.color_target.clone(),
+            out_depth: self.depth_target.clone(),
+        };
+
+        encoder.draw(
+            &Slice::new_match_vertex_buffer(&self.shadow_vertex_buffer),
+            &self.shadow_pipeline,
+            &data,
+        );
+    }
+}
+
+/// Returns the light that most strongly illuminates `point`, if any.
+///
+/// A light's contribution falls off linearly with distance, as it does in `light.exe`, so lights
+/// farther away than their intensity are ignored. `visible` is called to check whether a light at
+/// the given position can be seen from `point`; it's only called for lights in range, strongest
+/// first.
+pub fn dominant_light<F>(lights: &[BspLight], point: Vector3<f32>, visible: F) -> Option<&BspLight>
+where
+    F: Fn(Vector3<f32>) -> bool,
+{
+    let mut candidates: Vec<(f32, &BspLight)> = lights
+        .iter()
+        .map(|l| (l.intensity - (l.origin - point).magnitude(), l))
+        .filter(|&(strength, _)| strength > 0.0)
+        .collect();
+    candidates.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+
+    candidates
+        .into_iter()
+        .map(|(_, l)| l)
+        .find(|l| visible(l.origin))
+}
+
+/// Returns the direction to cast the shadow of an entity at `origin` and how far to stretch it.
+///
+/// Without a light, or if the light isn't above the entity, the shadow falls straight down.
+/// Otherwise it's cast directly away from the light and lengthened the lower the light is, up to
+/// `SHADOW_MAX_STRETCH` times its width.
+pub fn shadow_direction(origin: Vector3<f32>, light: Option<Vector3<f32>>) -> (Vector3<f32>, f32) {
+    let down = (-Vector3::unit_z(), 1.0);
+
+    let light = match light {
+        Some(l) => l,
+        None => return down,
+    };
+
+    let dir = origin - light;
+    if dir.z >= 0.0 || dir.magnitude2() == 0.0 {
+        return down;
+    }
+
+    let dir = dir.normalize();
+    (dir, (1.0 / -dir.z).min(SHADOW_MAX_STRETCH))
 }
 
 pub struct Palette {
@@ -618,6 +2005,63 @@ impl Palette {
         Palette { rgb }
     }
 
+    /// Returns the RGB value of the color at `index`.
+    pub fn rgb(&self, index: u8) -> [u8; 3] {
+        self.rgb[index as usize]
+    }
+
+    /// Returns the index of the color closest to `rgb`.
+    ///
+    /// Distance is measured as squared Euclidean distance in RGB space. If several colors are
+    /// equally close, the lowest index is returned.
+    pub fn index_of(&self, rgb: [u8; 3]) -> u8 {
+        let dist = |c: &[u8; 3]| -> u32 {
+            (0..3)
+                .map(|i| {
+                    let d = c[i] as i32 - rgb[i] as i32;
+                    (d * d) as u32
+                })
+                .sum()
+        };
+
+        let mut best = 0;
+        for (i, color) in self.rgb.iter().enumerate().skip(1) {
+            if dist(color) < dist(&self.rgb[best]) {
+                best = i;
+            }
+        }
+
+        best as u8
+    }
+
+    /// Returns a copy of this palette with texture gamma applied to every color.
+    ///
+    /// This follows the original GL renderer's `-gamma` option: values below 1.0 brighten the
+    /// palette and values above 1.0 darken it. Since the correction is baked into the palette,
+    /// textures must be re-uploaded for a new value to take effect. No gamma correction is applied
+    /// to the framebuffer, so this is the only brightness adjustment textures receive. Invalid
+    /// values (zero, negative or NaN) leave the palette unchanged.
+    pub fn with_gamma(&self, gamma: f32) -> Palette {
+        if !(gamma > 0.0) || gamma == 1.0 {
+            return Palette { rgb: self.rgb };
+        }
+
+        let mut table = [0u8; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let corrected = 255.0 * (i as f32 / 255.0).powf(gamma) + 0.5;
+            *entry = corrected.max(0.0).min(255.0) as u8;
+        }
+
+        let mut rgb = self.rgb;
+        for color in rgb.iter_mut() {
+            for component in color.iter_mut() {
+                *component = table[*component as usize];
+            }
+        }
+
+        Palette { rgb }
+    }
+
     // TODO: this will not render console characters correctly, as they use index 0 (black) to
     // indicate transparency.
     /// Translates a set of indices into a list of RGBA values and a list of fullbright values.
@@ -649,6 +2093,42 @@ impl Palette {
     }
 }
 
+/// Resamples a texture so that both of its dimensions are powers of two.
+///
+/// Each dimension is rounded up to the next power of two and the image is stretched to fit using
+/// nearest-neighbor sampling. Because the whole image is scaled rather than padded, normalized
+/// texture coordinates computed against the original dimensions still address the same texels,
+/// and the result tiles exactly as the original would under `WrapMode::Tile`.
+///
+/// Returns the new width, height and pixel data. If the texture is already power-of-two, the data
+/// is returned unchanged.
+pub fn resample_to_pot(
+    width: u32,
+    height: u32,
+    bytes_per_pixel: usize,
+    data: &[u8],
+) -> (u32, u32, Vec<u8>) {
+    let pot_w = width.next_power_of_two();
+    let pot_h = height.next_power_of_two();
+
+    if pot_w == width && pot_h == height {
+        return (width, height, data.to_owned());
+    }
+
+    let mut resampled = Vec::with_capacity((pot_w * pot_h) as usize * bytes_per_pixel);
+    for y in 0..pot_h {
+        // sample at texel centers so each destination texel takes the source texel it covers
+        let src_y = ((2 * y + 1) * height / (2 * pot_h)) as usize;
+        for x in 0..pot_w {
+            let src_x = ((2 * x + 1) * width / (2 * pot_w)) as usize;
+            let ofs = (src_y * width as usize + src_x) * bytes_per_pixel;
+            resampled.extend_from_slice(&data[ofs..ofs + bytes_per_pixel]);
+        }
+    }
+
+    (pot_w, pot_h, resampled)
+}
+
 pub fn create_texture<F>(
     factory: &mut F,
     width: u32,
@@ -677,6 +2157,56 @@ where
     Ok(ret)
 }
 
+/// The appearance of the placeholder used for missing textures.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DummyTexturePattern {
+    /// A magenta and black checkerboard that stands out from any real texture.
+    Checkerboard,
+
+    /// A flat gray that blends in with the rest of the level.
+    Neutral,
+}
+
+impl DummyTexturePattern {
+    /// Selects a pattern from the value of `r_missingtexture`.
+    pub fn from_cvar(value: f32) -> DummyTexturePattern {
+        if value != 0.0 {
+            DummyTexturePattern::Checkerboard
+        } else {
+            DummyTexturePattern::Neutral
+        }
+    }
+
+    /// Returns the width, height and RGBA data of the pattern.
+    pub fn rgba(&self) -> (u16, u16, Vec<u8>) {
+        match *self {
+            // the infamous Source engine "missing texture" texture
+            DummyTexturePattern::Checkerboard => {
+                let mut rgba = Vec::with_capacity(DUMMY_CHECKER_SIZE * DUMMY_CHECKER_SIZE * 4);
+                for y in 0..DUMMY_CHECKER_SIZE {
+                    for x in 0..DUMMY_CHECKER_SIZE {
+                        let square = x / DUMMY_CHECKER_SQUARE + y / DUMMY_CHECKER_SQUARE;
+                        if square % 2 == 0 {
+                            rgba.extend_from_slice(&[0xFF, 0x00, 0xFF, 0xFF]);
+                        } else {
+                            rgba.extend_from_slice(&[0x00, 0x00, 0x00, 0xFF]);
+                        }
+                    }
+                }
+
+                (
+                    DUMMY_CHECKER_SIZE as u16,
+                    DUMMY_CHECKER_SIZE as u16,
+                    rgba,
+                )
+            }
+
+            DummyTexturePattern::Neutral => (1, 1, vec![0x80, 0x80, 0x80, 0xFF]),
+        }
+    }
+}
+
+/// Creates a checkerboard placeholder texture.
 pub fn create_dummy_texture<F>(
     factory: &mut F,
 ) -> Result<
@@ -689,14 +2219,27 @@ pub fn create_dummy_texture<F>(
 where
     F: gfx::Factory<Resources>,
 {
-    // the infamous Source engine "missing texture" texture
-    let rgba = [
-        0xFF, 0x00, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0xFF, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0x00, 0xFF,
-        0xFF,
-    ];
+    create_dummy_texture_with_pattern(factory, DummyTexturePattern::Checkerboard)
+}
+
+/// Creates a placeholder texture with the given pattern.
+pub fn create_dummy_texture_with_pattern<F>(
+    factory: &mut F,
+    pattern: DummyTexturePattern,
+) -> Result<
+    (
+        Texture<Resources, R8_G8_B8_A8>,
+        ShaderResourceView<Resources, [f32; 4]>,
+    ),
+    Error,
+>
+where
+    F: gfx::Factory<Resources>,
+{
+    let (width, height, rgba) = pattern.rgba();
 
     let ret = factory.create_texture_immutable_u8::<ColorFormat>(
-        gfx::texture::Kind::D2(2, 2, gfx::texture::AaMode::Single),
+        gfx::texture::Kind::D2(width, height, gfx::texture::AaMode::Single),
         gfx::texture::Mipmap::Allocated,
         &[&rgba],
     )?;
@@ -721,14 +2264,20 @@ where
 
 pub fn create_dummy_lightmap<F>(
     factory: &mut F,
-) -> Result<(Texture<Resources, R8>, ShaderResourceView<Resources, f32>), Error>
+) -> Result<
+    (
+        Texture<Resources, R8_G8_B8_A8>,
+        ShaderResourceView<Resources, [f32; 4]>,
+    ),
+    Error,
+>
 where
     F: gfx::Factory<Resources>,
 {
-    let ret = factory.create_texture_immutable_u8::<(R8, Unorm)>(
+    let ret = factory.create_texture_immutable_u8::<(R8_G8_B8_A8, Unorm)>(
         texture::Kind::D2(1, 1, texture::AaMode::Single),
         texture::Mipmap::Allocated,
-        &[&[0xFF]],
+        &[&[0xFF, 0xFF, 0xFF, 0xFF]],
     )?;
 
     Ok(ret)
@@ -757,3 +2306,352 @@ pub fn screen_space_vertex_transform(
     Matrix4::from_translation([ndc_x, ndc_y, 0.0].into())
         * Matrix4::from_nonuniform_scale(scale_x, scale_y, 1.0)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // the first and last few entries of the standard Quake palette
+    fn test_palette() -> Palette {
+        let mut rgb = [[0u8; 3]; 256];
+        rgb[0] = [0x00, 0x00, 0x00];
+        rgb[1] = [0x0f, 0x0f, 0x0f];
+        rgb[2] = [0x1f, 0x1f, 0x1f];
+        rgb[15] = [0xeb, 0xeb, 0xeb];
+        rgb[254] = [0xff, 0xf3, 0x93];
+        rgb[255] = [0x9f, 0x5b, 0x53];
+        Palette { rgb }
+    }
+
+    #[test]
+    fn test_frustum_culls_box_behind_camera() {
+        let camera = Camera::new(
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(Deg(0.0), Deg(0.0), Deg(0.0)),
+            ::cgmath::perspective(Deg(90.0), 1.0, NEAR_CLIP, 4096.0),
+        );
+        let frustum = Frustum::from_transform(camera.transform() * quake_to_gl());
+        let size = Vector3::new(8.0, 8.0, 8.0);
+
+        // the camera looks down +X in Quake coordinates
+        let ahead = Vector3::new(100.0, 0.0, 0.0);
+        assert!(frustum.intersects_box(ahead - size, ahead + size));
+
+        let behind = Vector3::new(-100.0, 0.0, 0.0);
+        assert!(!frustum.intersects_box(behind - size, behind + size));
+
+        // off to the side, outside the 90 degree field of view
+        let beside = Vector3::new(50.0, 200.0, 0.0);
+        assert!(!frustum.intersects_box(beside - size, beside + size));
+
+        // straddling the near plane
+        assert!(frustum.intersects_box(-size, size));
+    }
+
+    #[test]
+    fn test_palette_rgb() {
+        let palette = test_palette();
+        assert_eq!(palette.rgb(0), [0x00, 0x00, 0x00]);
+        assert_eq!(palette.rgb(15), [0xeb, 0xeb, 0xeb]);
+        assert_eq!(palette.rgb(254), [0xff, 0xf3, 0x93]);
+        assert_eq!(palette.rgb(255), [0x9f, 0x5b, 0x53]);
+    }
+
+    #[test]
+    fn test_palette_index_of() {
+        let palette = test_palette();
+
+        // exact matches
+        assert_eq!(palette.index_of([0x0f, 0x0f, 0x0f]), 1);
+        assert_eq!(palette.index_of([0xff, 0xf3, 0x93]), 254);
+        assert_eq!(palette.index_of([0x9f, 0x5b, 0x53]), 255);
+
+        // nearest matches
+        assert_eq!(palette.index_of([0x1c, 0x20, 0x1e]), 2);
+        assert_eq!(palette.index_of([0xff, 0xff, 0xff]), 15);
+
+        // black appears many times in the test palette, the first one wins
+        assert_eq!(palette.index_of([0x01, 0x00, 0x02]), 0);
+    }
+
+    #[test]
+    fn test_viewport_side_by_side() {
+        let viewports = Viewport::side_by_side(1601, 900, 2);
+        assert_eq!(viewports.len(), 2);
+        assert_eq!(
+            viewports[0],
+            Viewport {
+                x: 0,
+                y: 0,
+                width: 800,
+                height: 900,
+            }
+        );
+        assert_eq!(
+            viewports[1],
+            Viewport {
+                x: 800,
+                y: 0,
+                width: 801,
+                height: 900,
+            }
+        );
+
+        assert_eq!(Viewport::side_by_side(1600, 900, 1), vec![Viewport::full(1600, 900)]);
+        assert!(Viewport::side_by_side(1600, 900, 0).is_empty());
+    }
+
+    #[test]
+    fn test_viewport_ndc_transform() {
+        use cgmath::Vector4;
+
+        // the full viewport leaves NDC unchanged
+        let full = Viewport::full(1600, 900).ndc_transform(1600, 900);
+        assert_eq!(full * Vector4::new(-1.0, 1.0, 0.0, 1.0), Vector4::new(-1.0, 1.0, 0.0, 1.0));
+
+        // the right half maps [-1, 1] to [0, 1] horizontally
+        let right = Viewport::side_by_side(1600, 900, 2)[1].ndc_transform(1600, 900);
+        assert_eq!(right * Vector4::new(-1.0, -1.0, 0.0, 1.0), Vector4::new(0.0, -1.0, 0.0, 1.0));
+        assert_eq!(right * Vector4::new(1.0, 1.0, 0.0, 1.0), Vector4::new(1.0, 1.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn test_muzzle_offset() {
+        assert!(muzzle_offset("progs/v_shot.mdl").is_some());
+        assert!(muzzle_offset("progs/v_axe.mdl").is_none());
+    }
+
+    #[test]
+    fn test_viewmodel_muzzle_origin() {
+        let origin = Vector3::new(100.0, 200.0, 50.0);
+        let offset = viewmodel_offset(Vector3::zero(), false);
+        let muzzle = Vector3::new(20.0, 2.0, -4.0);
+        let level = Vector3::new(Deg(0.0), Deg(0.0), Deg(0.0));
+
+        assert_eq!(
+            viewmodel_muzzle_origin(origin, level, offset, muzzle, false),
+            Vector3::new(135.0, 192.0, 46.0)
+        );
+
+        // left-handed mode mirrors the whole weapon, barrel included
+        let lefthand_offset = viewmodel_offset(Vector3::zero(), true);
+        assert_eq!(
+            viewmodel_muzzle_origin(origin, level, lefthand_offset, muzzle, true),
+            Vector3::new(135.0, 208.0, 46.0)
+        );
+
+        // the muzzle moves with the view model when the view turns
+        let turned = Vector3::new(Deg(0.0), Deg(0.0), Deg(90.0));
+        let turned_muzzle = viewmodel_muzzle_origin(origin, turned, offset, muzzle, false);
+        assert!((turned_muzzle - Vector3::new(135.0, 192.0, 46.0)).magnitude() > 1.0);
+        let dist = (turned_muzzle - origin).magnitude();
+        assert!((dist - (offset + muzzle).magnitude()).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_viewmodel_offset() {
+        let offset = Vector3::new(2.0, 4.0, -1.0);
+        assert_eq!(viewmodel_offset(Vector3::zero(), false), Vector3::new(15.0, -10.0, 0.0));
+        assert_eq!(viewmodel_offset(offset, false), Vector3::new(17.0, -6.0, -1.0));
+
+        // left-handed mode mirrors the sideways offset only
+        assert_eq!(viewmodel_offset(Vector3::zero(), true), Vector3::new(15.0, 10.0, 0.0));
+        assert_eq!(viewmodel_offset(offset, true), Vector3::new(17.0, 6.0, -1.0));
+    }
+
+    #[test]
+    fn test_light_cache() {
+        use std::cell::Cell;
+
+        let samples = Cell::new(0);
+        let sample = || {
+            samples.set(samples.get() + 1);
+            0.5
+        };
+        let origin = Vector3::new(64.0, 0.0, 0.0);
+        let ms = Duration::milliseconds;
+
+        let mut cache = LightCache::new();
+        assert_eq!(cache.light(1, origin, ms(0), 1.0, &sample), 0.5);
+        assert_eq!(samples.get(), 1);
+
+        // a stationary or barely moving entity reuses its sample
+        cache.light(1, origin, ms(10), 1.0, &sample);
+        cache.light(1, origin + Vector3::new(0.5, 0.0, 0.0), ms(20), 1.0, &sample);
+        assert_eq!(samples.get(), 1);
+
+        // other entities have their own samples
+        cache.light(2, origin, ms(20), 1.0, &sample);
+        assert_eq!(samples.get(), 2);
+
+        // moving past the threshold or waiting out the timeout resamples
+        let moved = origin + Vector3::new(2.0, 0.0, 0.0);
+        cache.light(1, moved, ms(30), 1.0, &sample);
+        assert_eq!(samples.get(), 3);
+        cache.light(1, moved, ms(30 + LIGHT_CACHE_TIMEOUT_MS), 1.0, &sample);
+        assert_eq!(samples.get(), 4);
+
+        // a threshold of 0 always resamples
+        cache.light(2, origin, ms(20), 0.0, &sample);
+        cache.light(2, origin, ms(20), 0.0, &sample);
+        assert_eq!(samples.get(), 6);
+    }
+
+    #[test]
+    fn test_far_clip() {
+        assert_eq!(far_clip(8192.0), 8192.0);
+        assert_eq!(far_clip(NEAR_CLIP), DEFAULT_FAR_CLIP);
+        assert_eq!(far_clip(-1.0), DEFAULT_FAR_CLIP);
+        assert_eq!(far_clip(::std::f32::NAN), DEFAULT_FAR_CLIP);
+    }
+
+    #[test]
+    fn test_scaled_size() {
+        assert_eq!(scaled_size(1600, 900, 1.0), (1600, 900));
+        assert_eq!(scaled_size(1600, 900, 0.5), (800, 450));
+        assert_eq!(scaled_size(1600, 900, 2.0), (1600, 900));
+        assert_eq!(scaled_size(1600, 900, ::std::f32::NAN), (1600, 900));
+
+        // the scale is clamped
+        assert_eq!(scaled_size(1600, 900, 0.0), (160, 90));
+        assert_eq!(scaled_size(4, 4, 0.1), (1, 1));
+    }
+
+    #[test]
+    fn test_dummy_texture_pattern() {
+        let (width, height, rgba) = DummyTexturePattern::Checkerboard.rgba();
+        assert_eq!(rgba.len(), width as usize * height as usize * 4);
+
+        let texel = |x: usize, y: usize| &rgba[(y * width as usize + x) * 4..][..4];
+        let magenta = [0xFF, 0x00, 0xFF, 0xFF];
+        let black = [0x00, 0x00, 0x00, 0xFF];
+        assert_eq!(texel(0, 0), magenta);
+        assert_eq!(texel(DUMMY_CHECKER_SQUARE, 0), black);
+        assert_eq!(texel(0, DUMMY_CHECKER_SQUARE), black);
+        assert_eq!(texel(DUMMY_CHECKER_SQUARE, DUMMY_CHECKER_SQUARE), magenta);
+
+        assert_eq!(
+            DummyTexturePattern::from_cvar(0.0),
+            DummyTexturePattern::Neutral
+        );
+        assert_eq!(
+            DummyTexturePattern::Neutral.rgba(),
+            (1, 1, vec![0x80, 0x80, 0x80, 0xFF])
+        );
+    }
+
+    #[test]
+    fn test_palette_with_gamma() {
+        let mut rgb = [[0u8; 3]; 256];
+        for (i, color) in rgb.iter_mut().enumerate() {
+            *color = [i as u8; 3];
+        }
+        let palette = Palette { rgb };
+
+        // a gamma of 1 is the identity
+        assert_eq!(&palette.with_gamma(1.0).rgb[..], &palette.rgb[..]);
+        assert_eq!(&palette.with_gamma(0.0).rgb[..], &palette.rgb[..]);
+
+        let bright = palette.with_gamma(0.7);
+        assert_eq!(bright.rgb[0], [0; 3]);
+        assert_eq!(bright.rgb[255], [255; 3]);
+        assert!(bright.rgb[128][0] > 128);
+
+        let dark = palette.with_gamma(1.5);
+        assert!(dark.rgb[128][0] < 128);
+    }
+
+    #[test]
+    fn test_sort_back_to_front() {
+        let model = |ent_id, dist| TranslucentModel {
+            ent_id,
+            dist,
+            alpha: 0.5,
+            light: 1.0,
+        };
+
+        let mut models = vec![model(1, 100.0), model(2, 500.0), model(3, 250.0)];
+        sort_back_to_front(&mut models);
+        let order: Vec<usize> = models.iter().map(|m| m.ent_id).collect();
+        assert_eq!(order, vec![2, 3, 1]);
+    }
+
+    #[test]
+    fn test_dominant_light() {
+        let light = |x, intensity| BspLight {
+            origin: Vector3::new(x, 0.0, 64.0),
+            intensity,
+        };
+        let lights = vec![light(-100.0, 150.0), light(50.0, 120.0), light(1000.0, 300.0)];
+        let point = Vector3::new(0.0, 0.0, 0.0);
+
+        // the nearer light wins even though it is dimmer
+        let nearest = dominant_light(&lights, point, |_| true).unwrap();
+        assert_eq!(nearest.origin.x, 50.0);
+
+        // occluded lights are skipped
+        let visible = dominant_light(&lights, point, |l| l.x < 0.0).unwrap();
+        assert_eq!(visible.origin.x, -100.0);
+
+        // lights out of range are never considered
+        assert!(dominant_light(&lights, point, |l| l.x > 500.0).is_none());
+        assert!(dominant_light(&[], point, |_| true).is_none());
+    }
+
+    #[test]
+    fn test_shadow_direction_points_away_from_light() {
+        let origin = Vector3::new(0.0, 0.0, 24.0);
+
+        assert_eq!(shadow_direction(origin, None), (-Vector3::unit_z(), 1.0));
+
+        // torch to the east and above: the shadow falls to the west
+        let (dir, stretch) = shadow_direction(origin, Some(Vector3::new(64.0, 0.0, 88.0)));
+        assert!(dir.x < 0.0 && dir.z < 0.0);
+        assert!(dir.y.abs() < 1e-6);
+        assert!((stretch - 2.0f32.sqrt()).abs() < 1e-5);
+
+        // a light almost level with the entity gives the longest shadow
+        let (_, stretch) = shadow_direction(origin, Some(Vector3::new(0.0, 1000.0, 25.0)));
+        assert_eq!(stretch, SHADOW_MAX_STRETCH);
+
+        // a light below the entity can't cast a shadow onto the floor
+        let below = shadow_direction(origin, Some(Vector3::new(64.0, 0.0, 0.0)));
+        assert_eq!(below, (-Vector3::unit_z(), 1.0));
+    }
+
+    #[test]
+    fn test_draw_distance_alpha() {
+        // disabled
+        assert_eq!(draw_distance_alpha(100000.0, 0.0), 1.0);
+
+        assert_eq!(draw_distance_alpha(500.0, 1000.0), 1.0);
+        assert_eq!(draw_distance_alpha(900.0, 1000.0), 1.0);
+        assert!((draw_distance_alpha(950.0, 1000.0) - 0.5).abs() < 1e-6);
+        assert_eq!(draw_distance_alpha(1000.0, 1000.0), 0.0);
+        assert_eq!(draw_distance_alpha(2000.0, 1000.0), 0.0);
+    }
+
+    #[test]
+    fn test_resample_to_pot_unchanged() {
+        let data = [0, 1, 2, 3];
+        assert_eq!(resample_to_pot(2, 2, 1, &data), (2, 2, data.to_vec()));
+    }
+
+    #[test]
+    fn test_resample_to_pot_tiles() {
+        // 3x1 texture stretched to 4x1
+        let data = [10, 20, 30];
+        let (w, h, resampled) = resample_to_pot(3, 1, 1, &data);
+        assert_eq!((w, h), (4, 1));
+
+        // the edges of the texture are preserved, so wrapping joins the same texels as before
+        assert_eq!(resampled[0], data[0]);
+        assert_eq!(resampled[3], data[2]);
+
+        // the center of each new texel falls within the original texel it was sampled from
+        for (x, texel) in resampled.iter().enumerate() {
+            let s = (x as f32 + 0.5) / 4.0;
+            assert_eq!(*texel, data[(s * 3.0) as usize]);
+        }
+    }
+}
@@ -0,0 +1,273 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use client::particle::{Particles, MAX_PARTICLES};
+use client::render::{self, Camera, ColorFormat, Palette, SceneDepthFormat};
+use client::Fog;
+
+use failure::Error;
+use gfx::handle::{Buffer, DepthStencilView, RenderTargetView, Sampler, ShaderResourceView};
+use gfx::pso::{PipelineData, PipelineState};
+use gfx::traits::FactoryExt;
+use gfx::{self, CommandBuffer, Encoder, Factory, IndexBuffer, Slice};
+use gfx_device_gl::Resources;
+
+// quads are drawn as two triangles
+const VERTICES_PER_QUAD: usize = 6;
+
+// corners of a particle quad in view space, scaled by the particle size
+const QUAD_CORNERS: [[f32; 2]; VERTICES_PER_QUAD] = [
+    [-1.0, -1.0],
+    [1.0, -1.0],
+    [1.0, 1.0],
+    [-1.0, -1.0],
+    [1.0, 1.0],
+    [-1.0, 1.0],
+];
+
+// TODO: per-API coordinate system conversions
+pub static PARTICLE_VERTEX_SHADER_GLSL: &[u8] = br#"
+#version 430
+
+layout (location = 0) in vec3 a_Position;
+layout (location = 1) in vec2 a_Corner;
+layout (location = 2) in float a_Color;
+
+out vec2 f_corner;
+flat out int f_color;
+out float f_fogDistance;
+
+uniform mat4 u_View;
+uniform mat4 u_Projection;
+uniform float u_Size;
+
+void main() {
+    f_corner = a_Corner;
+    f_color = int(a_Color);
+
+    // expand the quad in view space so it always faces the camera
+    vec4 view_pos = u_View * vec4(-a_Position.y, a_Position.z, -a_Position.x, 1.0);
+    view_pos.xy += a_Corner * u_Size;
+    gl_Position = u_Projection * view_pos;
+    f_fogDistance = gl_Position.w;
+}
+"#;
+
+pub static PARTICLE_FRAGMENT_SHADER_GLSL: &[u8] = br#"
+#version 430
+
+in vec2 f_corner;
+flat in int f_color;
+in float f_fogDistance;
+
+uniform sampler2D u_Palette;
+uniform vec4 u_FogColor;
+uniform float u_FogDensity;
+
+out vec4 Target0;
+
+void main() {
+    // round off the corners of the quad
+    if (length(f_corner) > 1.0) {
+        discard;
+    }
+
+    vec4 color = texelFetch(u_Palette, ivec2(f_color, 0), 0);
+
+    // exponential squared fog
+    float fog_exponent = u_FogDensity * f_fogDistance;
+    float fog_factor = clamp(exp2(-1.442695 * fog_exponent * fog_exponent), 0.0, 1.0);
+    Target0 = vec4(mix(u_FogColor.rgb, color.rgb, fog_factor), 1.0);
+}"#;
+
+gfx_defines! {
+    vertex ParticleVertex {
+        position: [f32; 3] = "a_Position",
+        corner: [f32; 2] = "a_Corner",
+        color: f32 = "a_Color",
+    }
+
+    pipeline pipe_particle {
+        vertex_buffer: gfx::VertexBuffer<ParticleVertex> = (),
+        view: gfx::Global<[[f32; 4]; 4]> = "u_View",
+        projection: gfx::Global<[[f32; 4]; 4]> = "u_Projection",
+        size: gfx::Global<f32> = "u_Size",
+        fog_color: gfx::Global<[f32; 4]> = "u_FogColor",
+        fog_density: gfx::Global<f32> = "u_FogDensity",
+        palette: gfx::TextureSampler<[f32; 4]> = "u_Palette",
+        out_color: gfx::RenderTarget<ColorFormat> = "Target0",
+        out_depth: gfx::DepthTarget<SceneDepthFormat> = gfx::preset::depth::LESS_EQUAL_WRITE,
+    }
+}
+
+pub type ParticlePipelineState =
+    PipelineState<Resources, <pipe_particle::Data<Resources> as PipelineData<Resources>>::Meta>;
+
+fn create_pipeline_state<F>(
+    factory: &mut F,
+    primitive: gfx::Primitive,
+    reverse_z: bool,
+) -> Result<ParticlePipelineState, Error>
+where
+    F: Factory<Resources>,
+{
+    let shader_set =
+        factory.create_shader_set(PARTICLE_VERTEX_SHADER_GLSL, PARTICLE_FRAGMENT_SHADER_GLSL)?;
+
+    let pipeline = factory.create_pipeline_state(
+        &shader_set,
+        primitive,
+        gfx::state::Rasterizer {
+            front_face: gfx::state::FrontFace::Clockwise,
+            cull_face: gfx::state::CullFace::Nothing,
+            method: gfx::state::RasterMethod::Fill,
+            offset: None,
+            samples: Some(gfx::state::MultiSample),
+        },
+        pipe_particle::Init {
+            out_depth: render::depth_state(reverse_z),
+            ..pipe_particle::new()
+        },
+    )?;
+
+    Ok(pipeline)
+}
+
+/// Renders the particles in a `Particles` pool.
+///
+/// Particles are drawn either as camera-facing quads `r_particle_size` units across or, if
+/// `r_particle_size` is 0, as single-pixel points.
+pub struct ParticleRenderer {
+    quad_pipeline: ParticlePipelineState,
+    point_pipeline: ParticlePipelineState,
+    vertex_buffer: Buffer<Resources, ParticleVertex>,
+    palette_view: ShaderResourceView<Resources, [f32; 4]>,
+    sampler: Sampler<Resources>,
+    color_target: RenderTargetView<Resources, ColorFormat>,
+    depth_target: DepthStencilView<Resources, SceneDepthFormat>,
+}
+
+impl ParticleRenderer {
+    pub fn new<F>(
+        palette: &Palette,
+        factory: &mut F,
+        color_target: RenderTargetView<Resources, ColorFormat>,
+        depth_target: DepthStencilView<Resources, SceneDepthFormat>,
+        reverse_z: bool,
+    ) -> Result<ParticleRenderer, Error>
+    where
+        F: Factory<Resources>,
+    {
+        let quad_pipeline =
+            create_pipeline_state(factory, gfx::Primitive::TriangleList, reverse_z)?;
+        let point_pipeline = create_pipeline_state(factory, gfx::Primitive::PointList, reverse_z)?;
+
+        // enough room for every particle to be drawn as a quad
+        let vertex_buffer = factory.create_buffer(
+            MAX_PARTICLES * VERTICES_PER_QUAD,
+            gfx::buffer::Role::Vertex,
+            gfx::memory::Usage::Dynamic,
+            gfx::memory::Bind::empty(),
+        )?;
+
+        // particle colors are looked up from a 256x1 palette texture
+        let mut rgba = Vec::with_capacity(256 * 4);
+        for i in 0..256 {
+            rgba.extend_from_slice(&palette.rgb(i as u8));
+            rgba.push(0xFF);
+        }
+        let (_, palette_view) = render::create_texture(factory, 256, 1, &rgba)?;
+
+        let sampler = factory.create_sampler(gfx::texture::SamplerInfo::new(
+            gfx::texture::FilterMethod::Scale,
+            gfx::texture::WrapMode::Clamp,
+        ));
+
+        Ok(ParticleRenderer {
+            quad_pipeline,
+            point_pipeline,
+            vertex_buffer,
+            palette_view,
+            sampler,
+            color_target,
+            depth_target,
+        })
+    }
+
+    pub fn render<C>(
+        &self,
+        encoder: &mut Encoder<Resources, C>,
+        particles: &Particles,
+        camera: &Camera,
+        size: f32,
+        fog: &Fog,
+    ) -> Result<(), Error>
+    where
+        C: CommandBuffer<Resources>,
+    {
+        if particles.is_empty() {
+            return Ok(());
+        }
+
+        let (pipeline, corners): (_, &[[f32; 2]]) = if size > 0.0 {
+            (&self.quad_pipeline, &QUAD_CORNERS)
+        } else {
+            (&self.point_pipeline, &[[0.0, 0.0]])
+        };
+
+        let mut vertices = Vec::with_capacity(particles.len() * corners.len());
+        for particle in particles.iter().take(MAX_PARTICLES) {
+            let origin = particle.origin();
+            for corner in corners.iter() {
+                vertices.push(ParticleVertex {
+                    position: origin.into(),
+                    corner: *corner,
+                    color: particle.color() as f32,
+                });
+            }
+        }
+
+        encoder.update_buffer(&self.vertex_buffer, &vertices, 0)?;
+
+        let slice = Slice {
+            start: 0,
+            end: vertices.len() as u32,
+            base_vertex: 0,
+            instances: None,
+            buffer: IndexBuffer::Auto,
+        };
+
+        let data = pipe_particle::Data {
+            vertex_buffer: self.vertex_buffer.clone(),
+            view: camera.view().into(),
+            projection: camera.projection().into(),
+            size: size * 0.5,
+            fog_color: fog.shader_color(),
+            fog_density: fog.shader_density(),
+            palette: (self.palette_view.clone(), self.sampler.clone()),
+            out_color: self.color_target.clone(),
+            out_depth: self.depth_target.clone(),
+        };
+
+        encoder.draw(&slice, pipeline, &data);
+
+        Ok(())
+    }
+}
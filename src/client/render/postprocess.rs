@@ -0,0 +1,220 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Full-screen effects applied while copying the rendered scene to the display.
+
+use client::render::{ColorFormat, DepthFormat, Vertex2d, VERTEX_SHADER_2D_GLSL};
+use common::bsp::BspLeafContents;
+use common::console::CvarRegistry;
+
+use failure::Error;
+use gfx::{self, Factory};
+use gfx::pso::{PipelineData, PipelineState};
+use gfx::traits::FactoryExt;
+use gfx_device_gl::Resources;
+
+pub static POSTPROCESS_FRAGMENT_SHADER_GLSL: &[u8] = br#"
+#version 430
+
+in vec2 f_texcoord;
+
+uniform sampler2D u_Texture;
+uniform vec3 u_Tint;
+uniform float u_TintStrength;
+uniform float u_Warp;
+uniform float u_Time;
+
+out vec4 Target0;
+
+const float TAU = 6.2831853;
+
+void main() {
+    // shrink the image by the warp amplitude on each side so the wobble never samples past the
+    // edge of the scene
+    vec2 wobble = vec2(
+        sin(TAU * (2.0 * f_texcoord.y + 0.5 * u_Time)),
+        cos(TAU * (2.0 * f_texcoord.x + 0.5 * u_Time))
+    );
+    vec2 texcoord = f_texcoord * (1.0 - 2.0 * u_Warp) + u_Warp * (1.0 + wobble);
+
+    vec4 color = texture(u_Texture, texcoord);
+    Target0 = vec4(color.rgb * mix(vec3(1.0), u_Tint, u_TintStrength), 1.0);
+}"#;
+
+gfx_defines! {
+    pipeline pipe_postprocess {
+        vertex_buffer: gfx::VertexBuffer<Vertex2d> = (),
+        transform: gfx::Global<[[f32; 4]; 4]> = "u_Transform",
+        sampler: gfx::TextureSampler<[f32; 4]> = "u_Texture",
+        tint: gfx::Global<[f32; 3]> = "u_Tint",
+        tint_strength: gfx::Global<f32> = "u_TintStrength",
+        warp: gfx::Global<f32> = "u_Warp",
+        time: gfx::Global<f32> = "u_Time",
+        out_color: gfx::RenderTarget<ColorFormat> = "Target0",
+        out_depth: gfx::DepthTarget<DepthFormat> = gfx::preset::depth::PASS_TEST,
+    }
+}
+
+pub type PostProcessPipelineState = PipelineState<
+    Resources,
+    <pipe_postprocess::Data<Resources> as PipelineData<Resources>>::Meta,
+>;
+
+// how far the view wobbles with r_waterwarp at 1, as a fraction of the screen
+const WARP_AMPLITUDE: f32 = 1.0 / 160.0;
+
+/// Parses a liquid tint cvar of the form `"r g b strength"`.
+///
+/// Each component is clamped to `[0, 1]`, and NaN becomes 0. Returns `None` if the value doesn't
+/// have exactly four numeric components.
+pub fn parse_tint<S>(value: S) -> Option<([f32; 3], f32)>
+where
+    S: AsRef<str>,
+{
+    let components: Vec<f32> = value
+        .as_ref()
+        .split_whitespace()
+        .map(|c| c.parse::<f32>().ok())
+        .collect::<Option<_>>()?;
+    if components.len() != 4 {
+        return None;
+    }
+
+    let clamp = |c: f32| c.max(0.0).min(1.0);
+    Some((
+        [clamp(components[0]), clamp(components[1]), clamp(components[2])],
+        clamp(components[3]),
+    ))
+}
+
+/// A color multiply and view warp applied to the whole scene.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScreenEffect {
+    /// The color the scene is multiplied by at full strength.
+    pub tint: [f32; 3],
+
+    /// How far to blend from no tint to `tint`, from 0 to 1.
+    pub tint_strength: f32,
+
+    /// The amplitude of the warp, as a fraction of the screen.
+    pub warp: f32,
+}
+
+impl ScreenEffect {
+    /// Returns an effect that leaves the scene untouched.
+    pub fn none() -> ScreenEffect {
+        ScreenEffect {
+            tint: [1.0; 3],
+            tint_strength: 0.0,
+            warp: 0.0,
+        }
+    }
+
+    /// Returns whether the effect changes the scene at all.
+    pub fn is_active(&self) -> bool {
+        self.tint_strength > 0.0 || self.warp > 0.0
+    }
+
+    /// Returns the effect for a camera inside a leaf with the given contents.
+    ///
+    /// Water (including currents), slime and lava are tinted by `r_watertint`, `r_slimetint` and
+    /// `r_lavatint` respectively, and warped by `r_waterwarp`. Anything else has no effect.
+    pub fn for_contents(contents: BspLeafContents, cvars: &CvarRegistry) -> ScreenEffect {
+        let tint_cvar = match contents {
+            BspLeafContents::Empty | BspLeafContents::Solid => return ScreenEffect::none(),
+            BspLeafContents::Slime => "r_slimetint",
+            BspLeafContents::Lava => "r_lavatint",
+            _ => "r_watertint",
+        };
+
+        let (tint, tint_strength) = cvars
+            .get(tint_cvar)
+            .ok()
+            .and_then(parse_tint)
+            .unwrap_or(([1.0; 3], 0.0));
+        let warp = cvars.get_value("r_waterwarp").unwrap_or(0.0);
+        let warp = if warp > 0.0 { warp.min(1.0) * WARP_AMPLITUDE } else { 0.0 };
+
+        ScreenEffect {
+            tint,
+            tint_strength,
+            warp,
+        }
+    }
+}
+
+/// Creates the pipeline that copies the scene to the display while applying a `ScreenEffect`.
+pub fn create_pipeline_state<F>(factory: &mut F) -> Result<PostProcessPipelineState, Error>
+where
+    F: Factory<Resources>,
+{
+    let shader_set =
+        factory.create_shader_set(VERTEX_SHADER_2D_GLSL, POSTPROCESS_FRAGMENT_SHADER_GLSL)?;
+
+    Ok(factory.create_pipeline_state(
+        &shader_set,
+        gfx::Primitive::TriangleList,
+        gfx::state::Rasterizer::new_fill(),
+        pipe_postprocess::new(),
+    )?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_tint() {
+        assert_eq!(parse_tint("0.4 0.6 1 0.5"), Some(([0.4, 0.6, 1.0], 0.5)));
+        assert_eq!(parse_tint(" 2 -1 0.5 3 "), Some(([1.0, 0.0, 0.5], 1.0)));
+        assert_eq!(parse_tint("0.4 0.6 1"), None);
+        assert_eq!(parse_tint("blue"), None);
+    }
+
+    #[test]
+    fn test_underwater_tint_switches_on() {
+        let cvars = CvarRegistry::new();
+        cvars.register("r_watertint", "0.4 0.6 1 0.5").unwrap();
+        cvars.register("r_slimetint", "0.3 1 0.3 0.6").unwrap();
+        cvars.register("r_lavatint", "1 0.3 0.1 0.6").unwrap();
+        cvars.register("r_waterwarp", "1").unwrap();
+
+        // above the surface
+        let effect = ScreenEffect::for_contents(BspLeafContents::Empty, &cvars);
+        assert_eq!(effect, ScreenEffect::none());
+        assert!(!effect.is_active());
+
+        // in the water
+        let effect = ScreenEffect::for_contents(BspLeafContents::Water, &cvars);
+        assert!(effect.is_active());
+        assert_eq!((effect.tint, effect.tint_strength), ([0.4, 0.6, 1.0], 0.5));
+        assert_eq!(effect.warp, WARP_AMPLITUDE);
+
+        // currents look like water
+        let current = ScreenEffect::for_contents(BspLeafContents::Current90, &cvars);
+        assert_eq!(current, effect);
+
+        let lava = ScreenEffect::for_contents(BspLeafContents::Lava, &cvars);
+        assert_eq!(lava.tint, [1.0, 0.3, 0.1]);
+
+        // warping can be turned off without losing the tint
+        cvars.set("r_waterwarp", "0").unwrap();
+        let slime = ScreenEffect::for_contents(BspLeafContents::Slime, &cvars);
+        assert_eq!((slime.tint, slime.warp), ([0.3, 1.0, 0.3], 0.0));
+        assert!(slime.is_active());
+    }
+}
@@ -0,0 +1,269 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! High-resolution screenshots assembled from tiles.
+//!
+//! A screenshot `multiplier` times the size of the display is captured by rendering the scene
+//! `multiplier * multiplier` times, once for each tile, with the projection narrowed to that
+//! tile's portion of the view. Because each tile's frustum is an exact subdivision of the full
+//! frustum, the tiles line up without seams when stitched back together.
+
+use std::io::Write;
+
+use common::png::{self, PngColor};
+use common::vfs::Vfs;
+
+use cgmath::Matrix4;
+use failure::Error;
+use gfx::handle::Buffer;
+use gfx::Factory;
+use gfx_device_gl::Resources;
+
+/// The largest supported screenshot multiplier.
+pub const MAX_MULTIPLIER: u32 = 8;
+
+/// Returns a projection that renders only the given tile of `projection`'s view.
+///
+/// The view is divided into `tiles` by `tiles` equal tiles. Tile `(0, 0)` is at the bottom left,
+/// matching OpenGL's framebuffer origin.
+pub fn tile_projection(
+    projection: Matrix4<f32>,
+    tiles: u32,
+    tile_x: u32,
+    tile_y: u32,
+) -> Matrix4<f32> {
+    let n = tiles as f32;
+
+    // map the tile's range of normalized device coordinates onto [-1, 1]. this is applied in clip
+    // space, where the translation is scaled by w, so it survives the perspective divide
+    let offset_x = 2.0 * tile_x as f32 + 1.0 - n;
+    let offset_y = 2.0 * tile_y as f32 + 1.0 - n;
+
+    Matrix4::from_translation([-offset_x, -offset_y, 0.0].into())
+        * Matrix4::from_nonuniform_scale(n, n, 1.0)
+        * projection
+}
+
+/// An image assembled from individually rendered tiles.
+pub struct HiresScreenshot {
+    tile_width: u32,
+    tile_height: u32,
+    multiplier: u32,
+
+    // RGBA, top row first
+    pixels: Vec<u8>,
+}
+
+impl HiresScreenshot {
+    /// Creates an empty screenshot made of `multiplier` by `multiplier` tiles of the given size.
+    pub fn new(
+        tile_width: u32,
+        tile_height: u32,
+        multiplier: u32,
+    ) -> Result<HiresScreenshot, Error> {
+        ensure!(
+            multiplier >= 1 && multiplier <= MAX_MULTIPLIER,
+            "Screenshot multiplier must be between 1 and {}",
+            MAX_MULTIPLIER
+        );
+
+        let width = (tile_width * multiplier) as usize;
+        let height = (tile_height * multiplier) as usize;
+
+        Ok(HiresScreenshot {
+            tile_width,
+            tile_height,
+            multiplier,
+            pixels: vec![0; width * height * 4],
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.tile_width * self.multiplier
+    }
+
+    pub fn height(&self) -> u32 {
+        self.tile_height * self.multiplier
+    }
+
+    pub fn multiplier(&self) -> u32 {
+        self.multiplier
+    }
+
+    /// Copies a rendered tile into place.
+    ///
+    /// `rgba` holds the tile as read back from the framebuffer, with the bottom row first.
+    pub fn add_tile(&mut self, tile_x: u32, tile_y: u32, rgba: &[u8]) -> Result<(), Error> {
+        ensure!(
+            tile_x < self.multiplier && tile_y < self.multiplier,
+            "Tile ({}, {}) is out of range",
+            tile_x,
+            tile_y
+        );
+
+        let tile_stride = self.tile_width as usize * 4;
+        ensure!(
+            rgba.len() == tile_stride * self.tile_height as usize,
+            "Tile data is {} bytes, expected {}",
+            rgba.len(),
+            tile_stride * self.tile_height as usize
+        );
+
+        let stride = self.width() as usize * 4;
+        let height = self.height() as usize;
+        for (row_id, row) in rgba.chunks(tile_stride).enumerate() {
+            // rows are counted from the bottom of the image, but stored from the top
+            let image_row = height - 1 - (tile_y * self.tile_height) as usize - row_id;
+            let start = image_row * stride + tile_x as usize * tile_stride;
+            self.pixels[start..start + tile_stride].copy_from_slice(row);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the assembled image as RGBA, with the top row first.
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    /// Writes the assembled image to `path` as a PNG.
+    pub fn save(&self, vfs: &Vfs, path: &str) -> Result<(), Error> {
+        let mut png_data = Vec::new();
+        png::write_png(
+            &mut png_data,
+            self.width(),
+            self.height(),
+            PngColor::Rgba,
+            &self.pixels,
+        )?;
+        vfs.create(path)?.write_all(&png_data)?;
+
+        Ok(())
+    }
+}
+
+/// Returns the first of `richter00.png` through `richter99.png` that doesn't exist yet.
+pub fn next_screenshot_path(vfs: &Vfs) -> Result<String, Error> {
+    for i in 0..100 {
+        let path = format!("richter{:02}.png", i);
+        if vfs.open(&path).is_err() {
+            return Ok(path);
+        }
+    }
+
+    bail!("Too many screenshots, couldn't find a free file name");
+}
+
+/// A screenshot whose tiles have been rendered but not yet read back.
+///
+/// Each tile is copied to its own download buffer as soon as it's rendered, so that the scene
+/// target can be reused for the next one. The buffers can only be read after the encoder has been
+/// flushed, so the screenshot is finished on the following frame.
+pub struct PendingScreenshot {
+    screenshot: HiresScreenshot,
+    tiles: Vec<(u32, u32, Buffer<Resources, [u8; 4]>)>,
+}
+
+impl PendingScreenshot {
+    pub fn new(screenshot: HiresScreenshot) -> PendingScreenshot {
+        PendingScreenshot {
+            screenshot,
+            tiles: Vec::new(),
+        }
+    }
+
+    /// Adds a rendered tile, as returned by `SceneRenderer::download_scene`.
+    pub fn add_tile(&mut self, tile_x: u32, tile_y: u32, download: Buffer<Resources, [u8; 4]>) {
+        self.tiles.push((tile_x, tile_y, download));
+    }
+
+    /// Reads back the tiles and writes the assembled screenshot to `path` as a PNG.
+    pub fn finish<F>(mut self, factory: &mut F, vfs: &Vfs, path: &str) -> Result<(), Error>
+    where
+        F: Factory<Resources>,
+    {
+        for (tile_x, tile_y, download) in self.tiles.drain(..) {
+            let mut rgba = Vec::new();
+            {
+                let reader = factory
+                    .read_mapping(&download)
+                    .map_err(|e| format_err!("screenshot readback failed: {}", e))?;
+                for pixel in reader.iter() {
+                    rgba.extend_from_slice(pixel);
+                }
+            }
+
+            self.screenshot.add_tile(tile_x, tile_y, &rgba)?;
+        }
+
+        self.screenshot.save(vfs, path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cgmath::{SquareMatrix, Vector4};
+
+    #[test]
+    fn test_tile_projection() {
+        let identity = Matrix4::identity();
+
+        // a single tile is the whole view
+        assert_eq!(tile_projection(identity, 1, 0, 0), identity);
+
+        // the corners of the top right tile of a 2x2 grid fill the screen
+        let top_right = tile_projection(identity, 2, 1, 1);
+        assert_eq!(
+            top_right * Vector4::new(0.0, 0.0, 0.0, 1.0),
+            Vector4::new(-1.0, -1.0, 0.0, 1.0)
+        );
+        assert_eq!(
+            top_right * Vector4::new(1.0, 1.0, 0.0, 1.0),
+            Vector4::new(1.0, 1.0, 0.0, 1.0)
+        );
+
+        // the offset scales with w so it's unaffected by the perspective divide
+        assert_eq!(
+            top_right * Vector4::new(2.0, 2.0, 0.0, 2.0),
+            Vector4::new(2.0, 2.0, 0.0, 2.0)
+        );
+    }
+
+    #[test]
+    fn test_add_tile() {
+        let mut shot = HiresScreenshot::new(1, 2, 2).unwrap();
+        assert_eq!((shot.width(), shot.height()), (2, 4));
+
+        // each tile is a bottom pixel followed by a top pixel
+        for tile_y in 0..2 {
+            for tile_x in 0..2 {
+                let id = (tile_y * 2 + tile_x) as u8 * 2;
+                shot.add_tile(tile_x, tile_y, &[id, 0, 0, 0, id + 1, 0, 0, 0])
+                    .unwrap();
+            }
+        }
+
+        let reds: Vec<u8> = shot.pixels().chunks(4).map(|p| p[0]).collect();
+        assert_eq!(reds, vec![5, 7, 4, 6, 1, 3, 0, 2]);
+
+        assert!(shot.add_tile(2, 0, &[0; 8]).is_err());
+        assert!(shot.add_tile(0, 0, &[0; 4]).is_err());
+        assert!(HiresScreenshot::new(1, 1, 0).is_err());
+        assert!(HiresScreenshot::new(1, 1, MAX_MULTIPLIER + 1).is_err());
+    }
+}
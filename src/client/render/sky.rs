@@ -0,0 +1,324 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use client::render::{self, Camera, ColorFormat, Palette, SceneDepthFormat};
+use common::bsp::{BspModel, BspTextureMipmap};
+
+use cgmath::Vector3;
+use chrono::Duration;
+use failure::Error;
+use gfx::{self, CommandBuffer, Encoder, Factory, IndexBuffer, Slice};
+use gfx::handle::{Buffer, DepthStencilView, RenderTargetView, Sampler, ShaderResourceView};
+use gfx::pso::{PipelineData, PipelineState};
+use gfx::texture;
+use gfx::traits::FactoryExt;
+use gfx_device_gl::Resources;
+
+// the cloud layer treats this palette index as transparent
+const SKY_TRANSPARENT_INDEX: u8 = 0;
+
+// scroll speeds of the solid and cloud layers in texels per second
+const SKY_SOLID_SPEED: f32 = 8.0;
+const SKY_ALPHA_SPEED: f32 = 16.0;
+
+// The sky layers are projected onto a flattened sphere around the camera as in GLQuake's
+// EmitSkyPolys. Everything is in Quake coordinates except gl_Position.
+pub static SKY_VERTEX_SHADER_GLSL: &[u8] = br#"
+#version 430
+
+layout (location = 0) in vec3 a_Position;
+
+out vec3 f_dir;
+
+uniform mat4 u_Transform;
+uniform vec3 u_ViewOrigin;
+
+void main() {
+    f_dir = a_Position - u_ViewOrigin;
+    gl_Position = u_Transform * vec4(-a_Position.y, a_Position.z, -a_Position.x, 1.0);
+}
+"#;
+
+pub static SKY_FRAGMENT_SHADER_GLSL: &[u8] = br#"
+#version 430
+
+in vec3 f_dir;
+
+uniform float u_SolidScroll;
+uniform float u_AlphaScroll;
+uniform sampler2D u_SolidSky;
+uniform sampler2D u_AlphaSky;
+
+out vec4 Target0;
+
+void main() {
+    // flatten the sphere so the clouds look like a ceiling rather than a dome
+    vec3 dir = vec3(f_dir.xy, f_dir.z * 3.0);
+    vec2 st = normalize(dir).xy * 6.0 * 63.0;
+
+    vec4 solid = texture(u_SolidSky, (st + u_SolidScroll) / 128.0);
+    vec4 clouds = texture(u_AlphaSky, (st + u_AlphaScroll) / 128.0);
+    Target0 = vec4(mix(solid.rgb, clouds.rgb, clouds.a), 1.0);
+}"#;
+
+gfx_defines! {
+    vertex SkyVertex {
+        position: [f32; 3] = "a_Position",
+    }
+
+    pipeline pipe_sky {
+        vertex_buffer: gfx::VertexBuffer<SkyVertex> = (),
+        transform: gfx::Global<[[f32; 4]; 4]> = "u_Transform",
+        view_origin: gfx::Global<[f32; 3]> = "u_ViewOrigin",
+        solid_scroll: gfx::Global<f32> = "u_SolidScroll",
+        alpha_scroll: gfx::Global<f32> = "u_AlphaScroll",
+        solid_sampler: gfx::TextureSampler<[f32; 4]> = "u_SolidSky",
+        alpha_sampler: gfx::TextureSampler<[f32; 4]> = "u_AlphaSky",
+        out_color: gfx::RenderTarget<ColorFormat> = "Target0",
+        out_depth: gfx::DepthTarget<SceneDepthFormat> = gfx::preset::depth::LESS_EQUAL_WRITE,
+    }
+}
+
+/// Splits a sky texture into its solid (back) and cloud (front) layers.
+///
+/// Sky textures hold the cloud layer in their left half and the solid layer in their right half,
+/// so the original 256x128 texture gives two 128x128 layers. Returns `None` if the texture can't
+/// be split evenly.
+pub fn split_sky_texture(
+    width: usize,
+    height: usize,
+    indices: &[u8],
+) -> Option<(Vec<u8>, Vec<u8>)> {
+    if width == 0 || width % 2 != 0 || indices.len() != width * height {
+        return None;
+    }
+
+    let half = width / 2;
+    let mut solid = Vec::with_capacity(half * height);
+    let mut clouds = Vec::with_capacity(half * height);
+    for row in indices.chunks(width) {
+        clouds.extend_from_slice(&row[..half]);
+        solid.extend_from_slice(&row[half..]);
+    }
+
+    Some((solid, clouds))
+}
+
+/// Returns how far a sky layer moving at `speed` texels per second has scrolled at `time`.
+///
+/// The result wraps every 128 texels, the size of one layer, so that it stays precise when
+/// uploaded as a 32-bit float however long the map has been running.
+pub fn sky_scroll(time: Duration, speed: f32) -> f32 {
+    let ms = time.num_milliseconds() as f64;
+    (ms * speed as f64 / 1000.0 % 128.0) as f32
+}
+
+/// An object responsible for drawing sky surfaces.
+///
+/// Faces with a sky texture are skipped by the world and brush renderers and drawn here as two
+/// scrolling layers: an opaque layer of distant sky, and a partially transparent layer of clouds
+/// moving twice as fast in front of it. They're drawn with the depth test like any other surface,
+/// so the sky only shows where the world leaves a gap.
+pub struct SkyRenderer {
+    pipeline_state:
+        PipelineState<Resources, <pipe_sky::Data<Resources> as PipelineData<Resources>>::Meta>,
+    vertex_buffer: Buffer<Resources, SkyVertex>,
+    slice: Slice<Resources>,
+    solid_view: ShaderResourceView<Resources, [f32; 4]>,
+    alpha_view: ShaderResourceView<Resources, [f32; 4]>,
+    sampler: Sampler<Resources>,
+    color_target: RenderTargetView<Resources, ColorFormat>,
+    depth_target: DepthStencilView<Resources, SceneDepthFormat>,
+}
+
+impl SkyRenderer {
+    /// Collects the sky faces of `bsp_model` and uploads the layers of its sky texture.
+    ///
+    /// Returns `None` if the model has no sky faces.
+    pub fn new<F>(
+        bsp_model: &BspModel,
+        palette: &Palette,
+        factory: &mut F,
+        color_target: RenderTargetView<Resources, ColorFormat>,
+        depth_target: DepthStencilView<Resources, SceneDepthFormat>,
+        reverse_z: bool,
+    ) -> Result<Option<SkyRenderer>, Error>
+    where
+        F: Factory<Resources>,
+    {
+        let bsp_data = bsp_model.bsp_data();
+
+        let mut sky_tex_id = None;
+        let mut vertices = Vec::new();
+        for face_id in bsp_model.face_id..bsp_model.face_id + bsp_model.face_count {
+            let face = &bsp_data.faces()[face_id];
+            let tex_id = bsp_data.texinfo()[face.texinfo_id].tex_id;
+            if !bsp_data.textures()[tex_id].is_sky() {
+                continue;
+            }
+
+            // maps only ever have one sky, so the first sky texture is used for every face
+            if sky_tex_id.is_none() {
+                sky_tex_id = Some(tex_id);
+            }
+
+            let face_edge_ids = &bsp_data.edgelist()[face.edge_id..face.edge_id + face.edge_count];
+            let polygon: Vec<Vector3<f32>> = face_edge_ids
+                .iter()
+                .map(|edge_id| {
+                    let vertex_id =
+                        bsp_data.edges()[edge_id.index].vertex_ids[edge_id.direction as usize];
+                    bsp_data.vertices()[vertex_id as usize]
+                })
+                .collect();
+
+            // convert the polygon's triangle fan to a triangle list
+            for i in 1..polygon.len().saturating_sub(1) {
+                for v in [polygon[0], polygon[i], polygon[i + 1]].iter() {
+                    vertices.push(SkyVertex {
+                        position: (*v).into(),
+                    });
+                }
+            }
+        }
+
+        let tex = match sky_tex_id {
+            Some(id) => &bsp_data.textures()[id],
+            None => return Ok(None),
+        };
+
+        let width = tex.width() as usize;
+        let height = tex.height() as usize;
+        let (solid, clouds) =
+            match split_sky_texture(width, height, tex.mipmap(BspTextureMipmap::Full)) {
+                Some(layers) => layers,
+                None => bail!("Sky texture {} is {}x{}", tex.name(), width, height),
+            };
+
+        let (solid_rgba, _) = palette.translate(&solid);
+        let (mut alpha_rgba, _) = palette.translate(&clouds);
+        for (i, index) in clouds.iter().enumerate() {
+            if *index == SKY_TRANSPARENT_INDEX {
+                alpha_rgba[i * 4 + 3] = 0;
+            }
+        }
+
+        let kind = texture::Kind::D2((width / 2) as u16, height as u16, texture::AaMode::Single);
+        let (_, solid_view) = factory.create_texture_immutable_u8::<ColorFormat>(
+            kind,
+            texture::Mipmap::Provided,
+            &[&solid_rgba],
+        )?;
+        let (_, alpha_view) = factory.create_texture_immutable_u8::<ColorFormat>(
+            kind,
+            texture::Mipmap::Provided,
+            &[&alpha_rgba],
+        )?;
+
+        let shader_set =
+            factory.create_shader_set(SKY_VERTEX_SHADER_GLSL, SKY_FRAGMENT_SHADER_GLSL)?;
+        let pipeline_state = factory.create_pipeline_state(
+            &shader_set,
+            gfx::Primitive::TriangleList,
+            gfx::state::Rasterizer {
+                front_face: gfx::state::FrontFace::Clockwise,
+                cull_face: gfx::state::CullFace::Back,
+                method: gfx::state::RasterMethod::Fill,
+                offset: None,
+                samples: Some(gfx::state::MultiSample),
+            },
+            pipe_sky::Init {
+                out_depth: render::depth_state(reverse_z),
+                ..pipe_sky::new()
+            },
+        )?;
+
+        let slice = Slice {
+            start: 0,
+            end: vertices.len() as u32,
+            base_vertex: 0,
+            instances: None,
+            buffer: IndexBuffer::Auto,
+        };
+
+        Ok(Some(SkyRenderer {
+            pipeline_state,
+            vertex_buffer: factory.create_vertex_buffer(&vertices),
+            slice,
+            solid_view,
+            alpha_view,
+            sampler: factory.create_sampler(texture::SamplerInfo::new(
+                texture::FilterMethod::Bilinear,
+                texture::WrapMode::Tile,
+            )),
+            color_target,
+            depth_target,
+        }))
+    }
+
+    pub fn render<C>(
+        &self,
+        encoder: &mut Encoder<Resources, C>,
+        time: Duration,
+        camera: &Camera,
+    ) where
+        C: CommandBuffer<Resources>,
+    {
+        let data = pipe_sky::Data {
+            vertex_buffer: self.vertex_buffer.clone(),
+            transform: camera.transform().into(),
+            view_origin: camera.origin().into(),
+            solid_scroll: sky_scroll(time, SKY_SOLID_SPEED),
+            alpha_scroll: sky_scroll(time, SKY_ALPHA_SPEED),
+            solid_sampler: (self.solid_view.clone(), self.sampler.clone()),
+            alpha_sampler: (self.alpha_view.clone(), self.sampler.clone()),
+            out_color: self.color_target.clone(),
+            out_depth: self.depth_target.clone(),
+        };
+
+        encoder.draw(&self.slice, &self.pipeline_state, &data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_sky_texture() {
+        // 4x2 texture: clouds on the left, solid sky on the right
+        let indices = [0, 1, 10, 11, 2, 0, 12, 13];
+        let (solid, clouds) = split_sky_texture(4, 2, &indices).unwrap();
+        assert_eq!(solid, vec![10, 11, 12, 13]);
+        assert_eq!(clouds, vec![0, 1, 2, 0]);
+
+        assert!(split_sky_texture(3, 2, &[0; 6]).is_none());
+        assert!(split_sky_texture(4, 2, &[0; 4]).is_none());
+    }
+
+    #[test]
+    fn test_sky_scroll_wraps() {
+        assert_eq!(sky_scroll(Duration::zero(), SKY_SOLID_SPEED), 0.0);
+        assert_eq!(sky_scroll(Duration::seconds(1), SKY_SOLID_SPEED), 8.0);
+        assert_eq!(sky_scroll(Duration::seconds(1), SKY_ALPHA_SPEED), 16.0);
+
+        // 17 seconds at 8 texels per second is 136 texels, one full layer and 8 more
+        assert_eq!(sky_scroll(Duration::seconds(17), SKY_SOLID_SPEED), 8.0);
+        let late = sky_scroll(Duration::hours(100), SKY_ALPHA_SPEED);
+        assert!(late >= 0.0 && late < 128.0);
+    }
+}
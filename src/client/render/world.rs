@@ -15,48 +15,55 @@
 // DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+use std::collections::HashMap;
 use std::rc::Rc;
 
-use client::render::{self, Camera, ColorFormat, DepthFormat, Palette};
+use client::render::{
+    self, Camera, ColorFormat, DummyTexturePattern, Frustum, Palette, SceneDepthFormat,
+};
+use client::Fog;
+use client::render::lightmap::{LightmapSurface, Lightmaps};
 use client::render::brush::{self, BrushPipelineData, BrushPipelineState, BrushRenderFace,
-    BrushVertex, pipe_brush};
-use common::bsp::{BspData, BspModel, BspTextureMipmap, MIPLEVELS};
+    BrushVertex, LightmapAtlas, LiquidAlpha, TextureQuality, LIGHTMAP_ATLAS_SIZE, pipe_brush};
+use common::bsp::{BspData, BspModel};
+use common::engine;
 
-use cgmath::{Deg, Euler, Vector3, Matrix4, SquareMatrix};
+use cgmath::{Vector3, Matrix4, SquareMatrix};
 use chrono::Duration;
 use failure::Error;
 use flame;
 use gfx::{self, CommandBuffer, Encoder, Factory};
-use gfx::format::{R8, Unorm};
 use gfx::handle::{Buffer, DepthStencilView, RenderTargetView, Sampler, ShaderResourceView};
-use gfx::texture;
 use gfx::traits::FactoryExt;
 use gfx_device_gl::Resources;
-use num::FromPrimitive;
 
 pub struct WorldRenderLeaf {
-    pub faces: Box<[BrushRenderFace]>,
+    /// The faces in this leaf, as indices into the world's faces.
+    pub face_ids: Box<[usize]>,
 }
 
 pub struct WorldRenderer {
     bsp_data: Rc<BspData>,
 
+    // a face may lie in several leaves, so leaves refer to faces rather than own them
+    faces: Box<[BrushRenderFace]>,
     leaves: Box<[WorldRenderLeaf]>,
     texture_views: Box<[ShaderResourceView<Resources, [f32; 4]>]>,
     fullbright_views: Box<[ShaderResourceView<Resources, f32>]>,
-    lightmap_views: Box<[ShaderResourceView<Resources, f32>]>,
+    lightmaps: Lightmaps,
 
     pipeline_state: BrushPipelineState,
+    translucent_pipeline_state: BrushPipelineState,
     vertex_buffer: Buffer<Resources, BrushVertex>,
     dummy_texture: ShaderResourceView<Resources, [f32; 4]>,
     dummy_fullbright: ShaderResourceView<Resources, f32>,
-    dummy_lightmap: ShaderResourceView<Resources, f32>,
+    dummy_lightmap: ShaderResourceView<Resources, [f32; 4]>,
 
     diffuse_sampler: Sampler<Resources>,
     fullbright_sampler: Sampler<Resources>,
     lightmap_sampler: Sampler<Resources>,
     color_target: RenderTargetView<Resources, ColorFormat>,
-    depth_target: DepthStencilView<Resources, DepthFormat>,
+    depth_target: DepthStencilView<Resources, SceneDepthFormat>,
 }
 
 impl WorldRenderer {
@@ -65,106 +72,157 @@ impl WorldRenderer {
         palette: &Palette,
         factory: &mut F,
         color_target: RenderTargetView<Resources, ColorFormat>,
-        depth_target: DepthStencilView<Resources, DepthFormat>,
+        depth_target: DepthStencilView<Resources, SceneDepthFormat>,
+        reverse_z: bool,
+        dummy_pattern: DummyTexturePattern,
+        subdivide_size: Option<f32>,
     ) -> Result<WorldRenderer, Error>
     where
         F: Factory<Resources>,
     {
+        let mut faces = Vec::new();
         let mut leaves = Vec::new();
         let mut vertices = Vec::new();
-        let mut lightmap_views = Vec::new();
+        let mut lightmap_atlas = LightmapAtlas::new(LIGHTMAP_ATLAS_SIZE);
+        let mut lightmap_surfaces = Vec::new();
 
-        let pipeline_state = brush::create_pipeline_state(factory)?;
+        let pipeline_state = brush::create_pipeline_state(factory, reverse_z, false)?;
+        let translucent_pipeline_state = brush::create_pipeline_state(factory, reverse_z, true)?;
 
         let bsp_data = bsp_model.bsp_data().clone();
 
+        // maps BSP face IDs to render faces, or to `None` if the face isn't drawn
+        let mut render_face_ids: HashMap<usize, Option<usize>> = HashMap::new();
+
         // BSP vertex data is stored in triangle fan layout so we have to convert to triangle list
         for leaf_id in bsp_model.leaf_id..bsp_model.leaf_id + bsp_model.leaf_count + 1 {
-            let mut faces = Vec::new();
+            let mut face_ids = Vec::new();
             let leaf = &bsp_data.leaves()[leaf_id];
             for facelist_id in leaf.facelist_id..leaf.facelist_id + leaf.facelist_count {
                 let face_id = bsp_data.facelist()[facelist_id];
-                faces.push(brush::create_brush_render_face(
-                    factory,
-                    &bsp_data,
-                    face_id,
-                    &mut vertices,
-                    &mut lightmap_views
-                )?);
+                let render_face_id = match render_face_ids.get(&face_id) {
+                    Some(id) => *id,
+                    None => {
+                        let id = brush::create_brush_render_face(
+                            &bsp_data,
+                            face_id,
+                            &mut vertices,
+                            &mut lightmap_atlas,
+                            subdivide_size,
+                        )?
+                        .map(|face| {
+                            let surface = LightmapSurface::from_face(&bsp_data, face_id);
+                            if let (Some(surface), Some(rect)) = (surface, face.lightmap_rect) {
+                                lightmap_surfaces.push((face_id, surface, rect));
+                            }
+
+                            faces.push(face);
+                            faces.len() - 1
+                        });
+                        render_face_ids.insert(face_id, id);
+                        id
+                    }
+                };
+
+                if let Some(id) = render_face_id {
+                    face_ids.push(id);
+                }
             }
 
             leaves.push(WorldRenderLeaf {
-                faces: faces.into_boxed_slice(),
+                face_ids: face_ids.into_boxed_slice(),
             });
         }
 
         let vertex_buffer = factory.create_vertex_buffer(&vertices);
+        let lightmaps = Lightmaps::new(
+            factory,
+            &lightmap_atlas,
+            bsp_data.clone(),
+            bsp_model.render_node_id(),
+            lightmap_surfaces,
+        )?;
 
         let mut texture_views = Vec::new();
         let mut fullbright_views = Vec::new();
         for tex in bsp_data.textures().iter() {
-            let mut mipmaps = Vec::new();
-            let mut fullbrights = Vec::new();
-            for i in 0..MIPLEVELS {
-                let (mipmap, fullbright) =
-                    palette.translate(tex.mipmap(BspTextureMipmap::from_usize(i).unwrap()));
-                mipmaps.push(mipmap);
-                fullbrights.push(fullbright);
-            }
-
-            let (width, height) = tex.dimensions();
-
-            let (_, texture_view) = factory
-                .create_texture_immutable_u8::<ColorFormat>(
-                    texture::Kind::D2(width as u16, height as u16, texture::AaMode::Single),
-                    texture::Mipmap::Provided,
-                    &[&mipmaps[0], &mipmaps[1], &mipmaps[2], &mipmaps[3]],
-                )?;
-
-            let (_, fullbright_view) = factory
-                .create_texture_immutable_u8::<(R8, Unorm)>(
-                    texture::Kind::D2(width as u16, height as u16, texture::AaMode::Single),
-                    texture::Mipmap::Provided,
-                    &[&fullbrights[0], &fullbrights[1], &fullbrights[2], &fullbrights[3]],
-                )?;
+            let (texture_view, fullbright_view) =
+                brush::create_brush_texture_views(factory, palette, tex)?;
 
             texture_views.push(texture_view);
             fullbright_views.push(fullbright_view);
         }
 
-        let (_, dummy_texture) = render::create_dummy_texture(factory)?;
+        let (_, dummy_texture) =
+            render::create_dummy_texture_with_pattern(factory, dummy_pattern)?;
         let (_, dummy_fullbright) = render::create_dummy_fullbright(factory)?;
         let (_, dummy_lightmap) = render::create_dummy_lightmap(factory)?;
 
         Ok(WorldRenderer {
             bsp_data: bsp_data,
+            faces: faces.into_boxed_slice(),
             leaves: leaves.into_boxed_slice(),
             pipeline_state,
+            translucent_pipeline_state,
             vertex_buffer,
             texture_views: texture_views.into_boxed_slice(),
             fullbright_views: fullbright_views.into_boxed_slice(),
-            lightmap_views: lightmap_views.into_boxed_slice(),
+            lightmaps,
             dummy_texture,
             dummy_fullbright,
             dummy_lightmap,
-            diffuse_sampler: factory.create_sampler(gfx::texture::SamplerInfo::new(
-                gfx::texture::FilterMethod::Scale,
-                gfx::texture::WrapMode::Tile,
-            )),
+            diffuse_sampler: brush::create_diffuse_sampler(
+                factory,
+                TextureQuality::Low.filter_method(),
+            ),
             fullbright_sampler: factory.create_sampler(gfx::texture::SamplerInfo::new(
                 gfx::texture::FilterMethod::Scale,
                 gfx::texture::WrapMode::Tile,
             )),
-            lightmap_sampler: factory.create_sampler(gfx::texture::SamplerInfo::new(
-                gfx::texture::FilterMethod::Bilinear,
-                // gfx::texture::FilterMethod::Scale,
-                gfx::texture::WrapMode::Tile,
-            )),
+            lightmap_sampler: brush::create_lightmap_sampler(factory, true),
             color_target,
             depth_target,
         })
     }
 
+    pub fn bsp_data(&self) -> &BspData {
+        &self.bsp_data
+    }
+
+    /// Queues a dynamic light for the next call to `update_lightmaps`.
+    pub fn add_dynamic_light(&mut self, origin: Vector3<f32>, radius: f32, intensity: f32) {
+        self.lightmaps.add_dynamic_light(origin, radius, intensity);
+    }
+
+    /// Brightens lightmaps with the queued dynamic lights and uploads up to `max_updates`
+    /// changed lightmap regions.
+    pub fn update_lightmaps<C>(
+        &mut self,
+        encoder: &mut Encoder<Resources, C>,
+        max_updates: Option<usize>,
+    ) -> Result<(), Error>
+    where
+        C: CommandBuffer<Resources>,
+    {
+        self.lightmaps.update(encoder, max_updates)
+    }
+
+    /// Rebuilds the lightmap sampler with bilinear or nearest-neighbor filtering.
+    pub fn set_lightmap_bilinear<F>(&mut self, factory: &mut F, bilinear: bool)
+    where
+        F: Factory<Resources>,
+    {
+        self.lightmap_sampler = brush::create_lightmap_sampler(factory, bilinear);
+    }
+
+    /// Rebuilds the diffuse sampler with the texture filter `filter`.
+    pub fn set_texture_filter<F>(&mut self, factory: &mut F, filter: gfx::texture::FilterMethod)
+    where
+        F: Factory<Resources>,
+    {
+        self.diffuse_sampler = brush::create_diffuse_sampler(factory, filter);
+    }
+
     fn create_pipeline_data(&self) -> Result<BrushPipelineData, Error>
     {
         let pipeline_data = pipe_brush::Data {
@@ -174,6 +232,11 @@ impl WorldRenderer {
             fullbright_sampler: (self.dummy_fullbright.clone(), self.fullbright_sampler.clone()),
             lightmap_sampler: (self.dummy_lightmap.clone(), self.lightmap_sampler.clone()),
             lightstyle_value: [0.0; 4],
+            fog_color: [0.0; 4],
+            fog_density: 0.0,
+            alpha: 1.0,
+            time: 0.0,
+            warp: 0.0,
             out_color: self.color_target.clone(),
             out_depth: self.depth_target.clone(),
         };
@@ -181,99 +244,199 @@ impl WorldRenderer {
         Ok(pipeline_data)
     }
 
-    pub fn render_leaf<C>(
+    /// Flags the faces in the given leaves, like the original engine's `R_MarkLeaves`.
+    ///
+    /// Returns one flag per face, so that faces shared by several visible leaves are only drawn
+    /// once.
+    pub fn mark_visible_faces(&self, leaf_ids: &[usize]) -> Vec<bool> {
+        let mut visible = vec![false; self.faces.len()];
+        for &leaf_id in leaf_ids {
+            let leaf = match self.leaves.get(leaf_id) {
+                Some(l) => l,
+                None => {
+                    error!(
+                        "leaf ID is out of bounds: the len is {} but the leaf ID is {}",
+                        self.leaves.len(),
+                        leaf_id
+                    );
+                    continue;
+                }
+            };
+
+            for &face_id in leaf.face_ids.iter() {
+                visible[face_id] = true;
+            }
+        }
+
+        visible
+    }
+
+    fn render_face<C>(
         &self,
         encoder: &mut Encoder<Resources, C>,
-        pipeline_state: &BrushPipelineState,
         pipeline_data: &mut BrushPipelineData,
+        face: &BrushRenderFace,
         time: Duration,
-        camera: &Camera,
-        origin: Vector3<f32>,
-        angles: Vector3<Deg<f32>>,
         lightstyle_values: &[f32],
-        leaf_id: usize,
+        alpha: f32,
     ) where
         C: CommandBuffer<Resources>,
     {
-        if leaf_id >= self.leaves.len() {
-            error!("leaf ID is out of bounds: the len is {} but the leaf ID is {}", self.leaves.len(), leaf_id);
-            return;
+        let frame = self.bsp_data.texture_frame_for_time(face.tex_id, time);
+
+        pipeline_data.diffuse_sampler.0 = self.texture_views[frame].clone();
+        pipeline_data.fullbright_sampler.0 = self.fullbright_views[frame].clone();
+        pipeline_data.lightmap_sampler.0 = match face.lightmap_id {
+            Some(l_id) => self.lightmaps.view(l_id).clone(),
+            None => self.dummy_lightmap.clone(),
+        };
+
+        let mut lightstyle_value = [-1.0; 4];
+        for i in 0..4 {
+            if let Some(l) = lightstyle_values.get(face.light_styles[i] as usize) {
+                lightstyle_value[i] = *l;
+            }
         }
+        pipeline_data.lightstyle_value = lightstyle_value;
+        pipeline_data.alpha = alpha;
+        pipeline_data.warp = if face.liquid.is_some() { 1.0 } else { 0.0 };
 
-        for face in self.leaves[leaf_id].faces.iter() {
-            let frame = self.bsp_data.texture_frame_for_time(face.tex_id, time);
+        let pipeline_state = if alpha < 1.0 {
+            &self.translucent_pipeline_state
+        } else {
+            &self.pipeline_state
+        };
+        encoder.draw(&face.slice, pipeline_state, pipeline_data);
+    }
 
-            let model_transform = Matrix4::from_translation(Vector3::new(-origin.y, origin.z, -origin.x))
-                * Matrix4::from(Euler::new(angles.x, angles.y, angles.z));
-            pipeline_data.vertex_buffer = self.vertex_buffer.clone();
-            pipeline_data.transform = (camera.transform() * model_transform).into();
+    // returns the faces in leaves visible from `camera` that are inside its frustum
+    fn visible_faces(&self, camera: &Camera) -> Vec<usize> {
+        let frustum = Frustum::from_transform(camera.transform() * render::quake_to_gl());
 
-            pipeline_data.diffuse_sampler.0 = self.texture_views[frame].clone();
-            pipeline_data.fullbright_sampler.0 = self.fullbright_views[frame].clone();
-            pipeline_data.lightmap_sampler.0 = match face.lightmap_id {
-                Some(l_id) => self.lightmap_views[l_id].clone(),
-                None => self.dummy_lightmap.clone(),
-            };
+        let containing_leaf_id = self.bsp_data.find_leaf(camera.origin());
+        let pvs = self.bsp_data.get_pvs(containing_leaf_id, self.leaves.len());
 
-            let mut lightstyle_value = [-1.0; 4];
-            for i in 0..4 {
-                if let Some(l) = lightstyle_values.get(face.light_styles[i] as usize) {
-                    lightstyle_value[i] = *l;
-                }
-            }
-            pipeline_data.lightstyle_value = lightstyle_value;
+        let leaf_ids: Vec<usize> = if pvs.is_empty() {
+            // No visibility data for this leaf, render all faces
+            (0..self.leaves.len()).collect()
+        } else {
+            pvs
+        };
 
-            encoder.draw(&face.slice, pipeline_state, pipeline_data);
-        }
+        self.mark_visible_faces(&leaf_ids)
+            .into_iter()
+            .enumerate()
+            .filter(|&(face_id, visible)| {
+                let face = &self.faces[face_id];
+                visible && frustum.intersects_box(face.mins, face.maxs)
+            })
+            .map(|(face_id, _)| face_id)
+            .collect()
     }
 
+    fn create_frame_pipeline_data(
+        &self,
+        time: Duration,
+        camera: &Camera,
+        fog: &Fog,
+    ) -> Result<BrushPipelineData, Error> {
+        let mut pipeline_data = self.create_pipeline_data()?;
+        pipeline_data.fog_color = fog.shader_color();
+        pipeline_data.fog_density = fog.shader_density();
+        pipeline_data.time = engine::duration_to_f32(time);
+        pipeline_data.transform = camera.transform().into();
+
+        Ok(pipeline_data)
+    }
+
+    /// Draws the opaque faces visible from `camera`.
+    ///
+    /// Translucent liquids are left for `render_translucent`, which has to wait until everything
+    /// behind them has been drawn.
     pub fn render<C>(
         &self,
         encoder: &mut Encoder<Resources, C>,
         time: Duration,
         camera: &Camera,
-        origin: Vector3<f32>,
-        angles: Vector3<Deg<f32>>,
         lightstyle_values: &[f32],
+        fog: &Fog,
+        liquid_alpha: &LiquidAlpha,
     ) -> Result<(), Error>
     where
         C: CommandBuffer<Resources>,
     {
         let _guard = flame::start_guard("WorldRenderer::render");
-        let mut pipeline_data = self.create_pipeline_data()?;
+        let mut pipeline_data = self.create_frame_pipeline_data(time, camera, fog)?;
 
-        let containing_leaf_id = self.bsp_data.find_leaf(camera.origin());
-        let pvs = self.bsp_data.get_pvs(containing_leaf_id, self.leaves.len());
-
-        if pvs.is_empty() {
-            // No visibility data for this leaf, render all faces
-            for leaf_id in 0..self.leaves.len() {
-                self.render_leaf(
-                    encoder,
-                    &self.pipeline_state,
-                    &mut pipeline_data,
-                    time,
-                    camera,
-                    origin,
-                    angles,
-                    lightstyle_values,
-                    leaf_id,
-                );
-            }
-        } else {
-            for leaf_id in pvs.iter() {
-                self.render_leaf(
-                    encoder,
-                    &self.pipeline_state,
-                    &mut pipeline_data,
-                    time,
-                    camera,
-                    origin,
-                    angles,
-                    lightstyle_values,
-                    *leaf_id,
-                );
+        for face_id in self.visible_faces(camera) {
+            let face = &self.faces[face_id];
+            if liquid_alpha.alpha(face.liquid) < 1.0 {
+                continue;
             }
+
+            self.render_face(
+                encoder,
+                &mut pipeline_data,
+                face,
+                time,
+                lightstyle_values,
+                1.0,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Returns the translucent faces visible from `camera` along with their distances from it,
+    /// sorted from farthest to nearest.
+    pub fn translucent_faces(
+        &self,
+        camera: &Camera,
+        liquid_alpha: &LiquidAlpha,
+    ) -> Vec<(usize, f32)> {
+        let mut faces: Vec<(usize, f32)> = self
+            .visible_faces(camera)
+            .into_iter()
+            .filter(|&face_id| liquid_alpha.alpha(self.faces[face_id].liquid) < 1.0)
+            .map(|face_id| (face_id, self.faces[face_id].distance(camera.origin())))
+            .collect();
+        brush::sort_faces_back_to_front(&mut faces);
+
+        faces
+    }
+
+    /// Blends the translucent faces listed in `faces`, as returned by `translucent_faces`, over
+    /// the scene in order.
+    pub fn render_translucent<C>(
+        &self,
+        encoder: &mut Encoder<Resources, C>,
+        time: Duration,
+        camera: &Camera,
+        lightstyle_values: &[f32],
+        fog: &Fog,
+        liquid_alpha: &LiquidAlpha,
+        faces: &[(usize, f32)],
+    ) -> Result<(), Error>
+    where
+        C: CommandBuffer<Resources>,
+    {
+        if faces.is_empty() {
+            return Ok(());
+        }
+
+        let _guard = flame::start_guard("WorldRenderer::render_translucent");
+        let mut pipeline_data = self.create_frame_pipeline_data(time, camera, fog)?;
+
+        for &(face_id, _) in faces {
+            let face = &self.faces[face_id];
+            self.render_face(
+                encoder,
+                &mut pipeline_data,
+                face,
+                time,
+                lightstyle_values,
+                liquid_alpha.alpha(face.liquid),
+            );
         }
 
         Ok(())
@@ -20,12 +20,18 @@
 
 use std::cell::RefCell;
 use std::io::{BufReader, Cursor, Read};
+use std::mem;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
 
+use common::engine;
 use common::vfs::Vfs;
 
-use cgmath::Vector3;
+use cgmath::{Angle, Deg, InnerSpace, Vector3};
+use chrono::Duration;
 use failure::Error;
+use rand::Rng;
 use rodio::source::{Buffered, SamplesConverter};
 use rodio::{Decoder, Endpoint, Sink, Source};
 
@@ -52,23 +58,30 @@ impl AudioSource {
 pub struct StaticSound {
     origin: Vector3<f32>,
     src: AudioSource,
-    sink: Sink,
+    sink: Option<Sink>,
     volume: u8,
     attenuation: u8,
+    gain: StereoGain,
 }
 
 impl StaticSound {
+    /// Starts looping `src` at `origin`.
+    ///
+    /// Without an `endpoint` the sound is tracked but never heard.
     pub fn new(
-        endpoint: &Endpoint,
+        endpoint: Option<&Endpoint>,
         origin: Vector3<f32>,
         src: AudioSource,
         volume: u8,
         attenuation: u8,
     ) -> StaticSound {
-        let sink = Sink::new(endpoint);
-        let infinite = src.0.clone().repeat_infinite();
-        sink.append(infinite);
-        // TODO: set volume, attenuation and spatialize
+        let gain = StereoGain::new(0.0, 0.0);
+        let sink = endpoint.map(|e| {
+            let sink = Sink::new(e);
+            let infinite = src.0.clone().repeat_infinite();
+            sink.append(Spatialized::new(infinite, gain.clone()));
+            sink
+        });
 
         StaticSound {
             origin,
@@ -76,19 +89,357 @@ impl StaticSound {
             sink,
             volume,
             attenuation,
+            gain,
         }
     }
+
+    /// Adjusts the sound's volume on each side for the listener's position.
+    pub fn update(&self, listener: &Listener, master_volume: f32) {
+        // static sound attenuation is sent in 64ths
+        let attenuation = self.attenuation as f32 / 64.0;
+        let (left, right) = spatialize(listener, self.origin, self.volume, attenuation);
+        self.gain.set(left * master_volume, right * master_volume);
+    }
+}
+
+/// Sounds at attenuation 1 fade out completely at this distance.
+pub const SOUND_NOMINAL_CLIP_DIST: f32 = 1000.0;
+
+/// Where sounds are heard from, and which way the listener is facing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Listener {
+    pub origin: Vector3<f32>,
+    pub forward: Vector3<f32>,
+    pub right: Vector3<f32>,
+    pub up: Vector3<f32>,
+}
+
+impl Listener {
+    /// Returns a listener at `origin` looking along `angles` (pitch, yaw and roll).
+    pub fn from_angles(origin: Vector3<f32>, angles: Vector3<Deg<f32>>) -> Listener {
+        let (sp, cp) = angles.x.sin_cos();
+        let (sy, cy) = angles.y.sin_cos();
+        let (sr, cr) = angles.z.sin_cos();
+
+        Listener {
+            origin,
+            forward: Vector3::new(cp * cy, cp * sy, -sp),
+            right: Vector3::new(-sr * sp * cy + cr * sy, -sr * sp * sy - cr * cy, -sr * cp),
+            up: Vector3::new(cr * sp * cy + sr * sy, cr * sp * sy - sr * cy, cr * cp),
+        }
+    }
+}
+
+impl Default for Listener {
+    fn default() -> Listener {
+        Listener::from_angles(
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(Deg(0.0), Deg(0.0), Deg(0.0)),
+        )
+    }
+}
+
+/// Returns the left and right gains of a sound at `origin`, as heard by `listener`.
+///
+/// As in the original engine, the volume falls off linearly until the sound is silent at
+/// `SOUND_NOMINAL_CLIP_DIST / attenuation`, so an attenuation of 0 can be heard everywhere. Sounds
+/// are panned toward the side they come from, up to twice as loud in one ear and silent in the
+/// other when directly to the side.
+pub fn spatialize(
+    listener: &Listener,
+    origin: Vector3<f32>,
+    volume: u8,
+    attenuation: f32,
+) -> (f32, f32) {
+    let master = volume as f32 / 255.0;
+
+    let offset = origin - listener.origin;
+    let dist = offset.magnitude();
+    let (dot, falloff) = if dist > 0.0 {
+        (
+            listener.right.dot(offset / dist),
+            dist * attenuation / SOUND_NOMINAL_CLIP_DIST,
+        )
+    } else {
+        (0.0, 0.0)
+    };
+
+    let scale = master * (1.0 - falloff);
+    ((scale * (1.0 - dot)).max(0.0), (scale * (1.0 + dot)).max(0.0))
+}
+
+/// A shared handle to the left and right gains of a sound, which can be changed while it plays.
+#[derive(Clone, Debug)]
+pub struct StereoGain(Arc<[AtomicU32; 2]>);
+
+impl StereoGain {
+    pub fn new(left: f32, right: f32) -> StereoGain {
+        StereoGain(Arc::new([
+            AtomicU32::new(left.to_bits()),
+            AtomicU32::new(right.to_bits()),
+        ]))
+    }
+
+    pub fn get(&self) -> (f32, f32) {
+        (
+            f32::from_bits(self.0[0].load(Ordering::Relaxed)),
+            f32::from_bits(self.0[1].load(Ordering::Relaxed)),
+        )
+    }
+
+    pub fn set(&self, left: f32, right: f32) {
+        self.0[0].store(left.to_bits(), Ordering::Relaxed);
+        self.0[1].store(right.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Mixes a source down to mono and plays it in stereo at the gains set by a `StereoGain`.
+pub struct Spatialized<I>
+where
+    I: Source<Item = f32>,
+{
+    input: I,
+    gain: StereoGain,
+
+    // the right sample of the current frame, once the left one has been returned
+    right: Option<f32>,
+}
+
+impl<I> Spatialized<I>
+where
+    I: Source<Item = f32>,
+{
+    pub fn new(input: I, gain: StereoGain) -> Spatialized<I> {
+        Spatialized {
+            input,
+            gain,
+            right: None,
+        }
+    }
+}
+
+impl<I> Iterator for Spatialized<I>
+where
+    I: Source<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if let Some(right) = self.right.take() {
+            return Some(right);
+        }
+
+        let channels = self.input.channels();
+        let frame = read_frame(&mut self.input, channels);
+        if frame.is_empty() || frame.len() < channels as usize {
+            return None;
+        }
+
+        let mono = frame.iter().sum::<f32>() / frame.len() as f32;
+        let (left, right) = self.gain.get();
+        self.right = Some(mono * right);
+        Some(mono * left)
+    }
+}
+
+impl<I> Source for Spatialized<I>
+where
+    I: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        2
+    }
+
+    fn samples_rate(&self) -> u32 {
+        self.input.samples_rate()
+    }
+
+    fn total_duration(&self) -> Option<::std::time::Duration> {
+        None
+    }
+}
+
+/// The speed of sound in world units per second, taking one unit to be an inch.
+pub const SPEED_OF_SOUND: f32 = 13500.0;
+
+// limits on the pitch change caused by the doppler effect
+const MIN_DOPPLER_FACTOR: f32 = 0.5;
+const MAX_DOPPLER_FACTOR: f32 = 2.0;
+
+/// Returns the factor by which the doppler effect shifts the pitch of a moving sound.
+///
+/// Velocities are multiplied by `scale` (`s_doppler`), so 0 disables the effect and larger
+/// values exaggerate it. Sounds approaching the listener are raised in pitch and those moving
+/// away are lowered.
+pub fn doppler_factor(
+    listener_origin: Vector3<f32>,
+    listener_velocity: Vector3<f32>,
+    source_origin: Vector3<f32>,
+    source_velocity: Vector3<f32>,
+    scale: f32,
+) -> f32 {
+    let offset = source_origin - listener_origin;
+    if scale <= 0.0 || offset.magnitude2() < 1.0 {
+        return 1.0;
+    }
+
+    // unit vector from the listener towards the source
+    let dir = offset.normalize();
+    let listener_speed = listener_velocity.dot(dir) * scale;
+    let source_speed = source_velocity.dot(dir) * scale;
+
+    let factor = (SPEED_OF_SOUND + listener_speed) / (SPEED_OF_SOUND + source_speed);
+    factor.max(MIN_DOPPLER_FACTOR).min(MAX_DOPPLER_FACTOR)
+}
+
+/// Returns a random pitch within `variation` of 1, e.g. between 0.95 and 1.05 for 0.05.
+pub fn detune<R>(rng: &mut R, variation: f32) -> f32
+where
+    R: Rng,
+{
+    if variation > 0.0 {
+        1.0 + rng.gen_range(-variation, variation)
+    } else {
+        1.0
+    }
+}
+
+/// A shared handle to the playback rate of a sound, which can be changed while it plays.
+#[derive(Clone, Debug)]
+pub struct PitchControl(Arc<AtomicU32>);
+
+impl PitchControl {
+    pub fn new(pitch: f32) -> PitchControl {
+        PitchControl(Arc::new(AtomicU32::new(pitch.to_bits())))
+    }
+
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, pitch: f32) {
+        self.0.store(pitch.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Plays a source at the rate set by a `PitchControl`, resampling with linear interpolation.
+///
+/// Unlike `Source::speed`, the rate can change at any point during playback.
+pub struct Pitched<I>
+where
+    I: Source<Item = f32>,
+{
+    input: I,
+    channels: u16,
+    samples_rate: u32,
+    pitch: PitchControl,
+
+    // the input frames the output currently lies between, and how far along it is
+    current: Vec<f32>,
+    next: Vec<f32>,
+    frac: f32,
+
+    // the channel of the next output sample
+    channel: usize,
+}
+
+impl<I> Pitched<I>
+where
+    I: Source<Item = f32>,
+{
+    pub fn new(mut input: I, pitch: PitchControl) -> Pitched<I> {
+        let channels = input.channels();
+        let samples_rate = input.samples_rate();
+        let current = read_frame(&mut input, channels);
+        let next = read_frame(&mut input, channels);
+
+        Pitched {
+            input,
+            channels,
+            samples_rate,
+            pitch,
+            current,
+            next,
+            frac: 0.0,
+            channel: 0,
+        }
+    }
+}
+
+// reads one sample for each channel, or fewer at the end of the input
+fn read_frame<I>(input: &mut I, channels: u16) -> Vec<f32>
+where
+    I: Iterator<Item = f32>,
+{
+    input.by_ref().take(channels as usize).collect()
+}
+
+impl<I> Iterator for Pitched<I>
+where
+    I: Source<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.current.len() < self.channels as usize {
+            return None;
+        }
+
+        let a = self.current[self.channel];
+        let b = self.next.get(self.channel).cloned().unwrap_or(a);
+        let sample = a + (b - a) * self.frac;
+
+        self.channel += 1;
+        if self.channel == self.channels as usize {
+            self.channel = 0;
+            self.frac += self.pitch.get().max(0.0);
+            while self.frac >= 1.0 && self.current.len() == self.channels as usize {
+                self.frac -= 1.0;
+                let next = read_frame(&mut self.input, self.channels);
+                self.current = mem::replace(&mut self.next, next);
+            }
+        }
+
+        Some(sample)
+    }
+}
+
+impl<I> Source for Pitched<I>
+where
+    I: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn samples_rate(&self) -> u32 {
+        self.samples_rate
+    }
+
+    fn total_duration(&self) -> Option<::std::time::Duration> {
+        None
+    }
 }
 
 /// Represents a single audio channel, capable of playing one sound at a time.
 pub struct Channel {
-    endpoint: Rc<Endpoint>,
+    endpoint: Option<Rc<Endpoint>>,
     sink: RefCell<Option<Sink>>,
 }
 
 impl Channel {
     /// Create a new `Channel` backed by the given `Endpoint`.
-    pub fn new(endpoint: Rc<Endpoint>) -> Channel {
+    ///
+    /// Without an `Endpoint`, sounds played on the channel are discarded.
+    pub fn new(endpoint: Option<Rc<Endpoint>>) -> Channel {
         Channel {
             endpoint,
             sink: RefCell::new(None),
@@ -96,13 +447,20 @@ impl Channel {
     }
 
     /// Play a new sound on this channel, cutting off any sound that was previously playing.
-    pub fn play(&self, src: AudioSource) {
+    ///
+    /// The sound's playback rate follows `pitch`, and its volume in each ear follows `gain`.
+    pub fn play(&self, src: AudioSource, pitch: PitchControl, gain: StereoGain) {
         // stop the old sound
         self.sink.replace(None);
 
+        let endpoint = match self.endpoint {
+            Some(ref e) => e,
+            None => return,
+        };
+
         // start the new sound
-        let mut new_sink = Sink::new(&self.endpoint);
-        new_sink.append(src.0);
+        let mut new_sink = Sink::new(endpoint);
+        new_sink.append(Spatialized::new(Pitched::new(src.0, pitch), gain));
         new_sink.set_volume(8.0);
 
         self.sink.replace(Some(new_sink));
@@ -130,3 +488,554 @@ impl Channel {
         }
     }
 }
+
+/// What to do with a new sound when every channel is busy, set by `s_channel_overflow`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelOverflow {
+    /// Cut off the sound that has been playing the longest.
+    StealOldest,
+
+    /// Cut off the quietest sound, unless the new sound is quieter still.
+    StealQuietest,
+
+    /// Don't play the new sound.
+    Drop,
+}
+
+impl ChannelOverflow {
+    /// Parses a policy from its name (`oldest`, `quietest` or `drop`) or its number (0-2).
+    pub fn parse<S>(value: S) -> Option<ChannelOverflow>
+    where
+        S: AsRef<str>,
+    {
+        match value.as_ref().trim().to_lowercase().as_str() {
+            "oldest" | "0" => Some(ChannelOverflow::StealOldest),
+            "quietest" | "1" => Some(ChannelOverflow::StealQuietest),
+            "drop" | "2" => Some(ChannelOverflow::Drop),
+            _ => None,
+        }
+    }
+}
+
+/// The state of a mixer channel, used to decide which channel a new sound should play on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelSlot {
+    pub in_use: bool,
+    pub ent_id: usize,
+    pub ent_channel: i8,
+    pub start_time: Duration,
+    pub volume: u8,
+}
+
+/// Chooses the channel to play a new sound on, or `None` if the sound should be dropped.
+///
+/// Empty and finished channels are used first. A sound on a nonzero entity channel replaces any
+/// sound on the same entity channel, and entity channel -1 replaces any sound on the entity.
+/// If every channel is busy, `overflow` decides which sound, if any, is cut off.
+pub fn choose_channel(
+    slots: &[Option<ChannelSlot>],
+    ent_id: usize,
+    ent_channel: i8,
+    volume: u8,
+    overflow: ChannelOverflow,
+) -> Option<usize> {
+    for (i, slot) in slots.iter().enumerate() {
+        let slot = match *slot {
+            Some(ref s) => s,
+            None => return Some(i),
+        };
+
+        if !slot.in_use {
+            return Some(i);
+        }
+
+        if ent_channel != 0
+            && slot.ent_id == ent_id
+            && (slot.ent_channel == ent_channel || ent_channel == -1)
+        {
+            return Some(i);
+        }
+    }
+
+    // every slot is occupied at this point
+    let busy = slots
+        .iter()
+        .enumerate()
+        .filter_map(|(i, s)| s.as_ref().map(|s| (i, s)));
+
+    match overflow {
+        ChannelOverflow::StealOldest => busy.min_by_key(|&(_, s)| s.start_time).map(|(i, _)| i),
+
+        ChannelOverflow::StealQuietest => busy
+            .min_by_key(|&(_, s)| (s.volume, s.start_time))
+            .and_then(|(i, s)| if s.volume <= volume { Some(i) } else { None }),
+
+        ChannelOverflow::Drop => None,
+    }
+}
+
+/// Returns the path of the music file replacing CD track `track`.
+pub fn music_track_path(track: u32) -> String {
+    format!("music/track{:02}.ogg", track)
+}
+
+// a music file's contents, shared between the decoders of each loop
+#[derive(Clone)]
+struct TrackData(Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for TrackData {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Decodes a music file from memory, starting over whenever it reaches the end.
+struct LoopingTrack {
+    data: TrackData,
+    decoder: Decoder<Cursor<TrackData>>,
+
+    // whether the last sample came from the current decoder, so an empty file doesn't spin
+    playing: bool,
+}
+
+impl LoopingTrack {
+    fn new(data: TrackData) -> Result<LoopingTrack, Error> {
+        let decoder = Decoder::new(Cursor::new(data.clone()))?;
+        Ok(LoopingTrack {
+            data,
+            decoder,
+            playing: false,
+        })
+    }
+}
+
+impl Iterator for LoopingTrack {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        if let Some(sample) = self.decoder.next() {
+            self.playing = true;
+            return Some(sample);
+        }
+
+        if !self.playing {
+            return None;
+        }
+
+        self.decoder = Decoder::new(Cursor::new(self.data.clone())).ok()?;
+        self.playing = false;
+        self.next()
+    }
+}
+
+impl Source for LoopingTrack {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.decoder.channels()
+    }
+
+    fn samples_rate(&self) -> u32 {
+        self.decoder.samples_rate()
+    }
+
+    fn total_duration(&self) -> Option<::std::time::Duration> {
+        None
+    }
+}
+
+/// Plays music files (`music/trackNN.ogg`) in place of CD audio tracks.
+pub struct MusicPlayer {
+    vfs: Rc<Vfs>,
+    endpoint: Option<Rc<Endpoint>>,
+    sink: Option<Sink>,
+    track: Option<u32>,
+    volume: f32,
+}
+
+impl MusicPlayer {
+    /// Creates a music player. Without an `endpoint`, music is never played.
+    pub fn new(vfs: Rc<Vfs>, endpoint: Option<Rc<Endpoint>>) -> MusicPlayer {
+        MusicPlayer {
+            vfs,
+            endpoint,
+            sink: None,
+            track: None,
+            volume: 1.0,
+        }
+    }
+
+    /// Starts playing CD track `track`, replacing any music already playing.
+    ///
+    /// Like the original engine without a CD in the drive, a missing track just leaves the music
+    /// off. Files that exist but can't be decoded are an error.
+    pub fn play(&mut self, track: u32, looping: bool) -> Result<(), Error> {
+        self.stop();
+
+        let endpoint = match self.endpoint {
+            Some(ref e) => e.clone(),
+            None => return Ok(()),
+        };
+
+        let path = music_track_path(track);
+        let mut file = match self.vfs.open(&path) {
+            Ok(f) => f,
+            Err(_) => {
+                debug!("No music for CD track {} ({})", track, path);
+                return Ok(());
+            }
+        };
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let data = TrackData(Arc::new(data));
+
+        let mut sink = Sink::new(&endpoint);
+        sink.set_volume(self.volume);
+        if looping {
+            sink.append(LoopingTrack::new(data)?);
+        } else {
+            sink.append(Decoder::new(Cursor::new(data))?);
+        }
+
+        self.sink = Some(sink);
+        self.track = Some(track);
+
+        Ok(())
+    }
+
+    /// Stops the music.
+    pub fn stop(&mut self) {
+        self.sink = None;
+        self.track = None;
+    }
+
+    pub fn pause(&self) {
+        if let Some(ref sink) = self.sink {
+            sink.pause();
+        }
+    }
+
+    pub fn resume(&self) {
+        if let Some(ref sink) = self.sink {
+            sink.play();
+        }
+    }
+
+    /// Returns the CD track being played, if any.
+    pub fn track(&self) -> Option<u32> {
+        self.track
+    }
+
+    /// Sets the music's volume, where 1 is the file's own level.
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume;
+        if let Some(ref mut sink) = self.sink {
+            sink.set_volume(volume);
+        }
+    }
+}
+
+/// Lowers the music while loud gameplay sounds play, like a sidechain compressor.
+///
+/// The music drops as soon as the gameplay level rises above the threshold and recovers linearly
+/// once it falls back below it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MusicDucking {
+    gain: f32,
+}
+
+impl MusicDucking {
+    pub fn new() -> MusicDucking {
+        MusicDucking { gain: 1.0 }
+    }
+
+    /// Returns the gain to apply to the music.
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    /// Updates the music gain for a frame lasting `frame_time`.
+    ///
+    /// `level` is the combined volume of the gameplay sounds playing and `threshold` the level
+    /// above which the music is ducked. `amount` (the value of `bgm_ducking`) is the fraction of
+    /// the music's volume taken away while ducked, so 0 disables ducking. The music takes
+    /// `release` seconds to recover fully.
+    pub fn update(
+        &mut self,
+        level: f32,
+        frame_time: Duration,
+        amount: f32,
+        threshold: f32,
+        release: f32,
+    ) {
+        let amount = amount.max(0.0).min(1.0);
+        let floor = 1.0 - amount;
+
+        if level > threshold {
+            self.gain = floor;
+        } else if release > 0.0 {
+            self.gain += amount * engine::duration_to_f32(frame_time) / release;
+        } else {
+            self.gain = 1.0;
+        }
+
+        self.gain = self.gain.max(floor).min(1.0);
+    }
+}
+
+impl Default for MusicDucking {
+    fn default() -> MusicDucking {
+        MusicDucking::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn busy(ent_id: usize, ent_channel: i8, start_ms: i64, volume: u8) -> Option<ChannelSlot> {
+        Some(ChannelSlot {
+            in_use: true,
+            ent_id,
+            ent_channel,
+            start_time: Duration::milliseconds(start_ms),
+            volume,
+        })
+    }
+
+    #[test]
+    fn test_choose_free_channel() {
+        let mut slots = vec![busy(1, 1, 0, 255), None, busy(2, 1, 0, 255)];
+        assert_eq!(choose_channel(&slots, 3, 1, 255, ChannelOverflow::Drop), Some(1));
+
+        slots[1] = busy(4, 1, 0, 255);
+        slots[2].as_mut().unwrap().in_use = false;
+        assert_eq!(choose_channel(&slots, 3, 1, 255, ChannelOverflow::Drop), Some(2));
+    }
+
+    #[test]
+    fn test_choose_same_entity_channel() {
+        let slots = vec![busy(1, 1, 0, 255), busy(1, 2, 0, 255)];
+        assert_eq!(choose_channel(&slots, 1, 2, 255, ChannelOverflow::Drop), Some(1));
+        assert_eq!(choose_channel(&slots, 1, -1, 255, ChannelOverflow::Drop), Some(0));
+
+        // entity channel 0 never replaces anything
+        assert_eq!(choose_channel(&slots, 1, 0, 255, ChannelOverflow::Drop), None);
+    }
+
+    #[test]
+    fn test_overflow_policies() {
+        let slots = vec![busy(1, 1, 300, 64), busy(2, 1, 100, 255), busy(3, 1, 200, 128)];
+
+        let choose = |volume, overflow| choose_channel(&slots, 4, 1, volume, overflow);
+        assert_eq!(choose(255, ChannelOverflow::StealOldest), Some(1));
+        assert_eq!(choose(255, ChannelOverflow::StealQuietest), Some(0));
+        assert_eq!(choose(255, ChannelOverflow::Drop), None);
+
+        // a new sound quieter than everything playing is the one that gets dropped
+        assert_eq!(choose(32, ChannelOverflow::StealQuietest), None);
+    }
+
+    fn assert_gains(actual: (f32, f32), expected: (f32, f32)) {
+        assert!(
+            (actual.0 - expected.0).abs() < 1e-4 && (actual.1 - expected.1).abs() < 1e-4,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_listener_from_angles() {
+        let origin = Vector3::new(0.0, 0.0, 0.0);
+
+        // facing along +x, +y is to the left
+        let listener = Listener::from_angles(origin, Vector3::new(Deg(0.0), Deg(0.0), Deg(0.0)));
+        assert_eq!(listener.forward, Vector3::new(1.0, 0.0, 0.0));
+        assert_eq!(listener.right, Vector3::new(0.0, -1.0, 0.0));
+        assert_eq!(listener.up, Vector3::new(0.0, 0.0, 1.0));
+
+        let listener = Listener::from_angles(origin, Vector3::new(Deg(0.0), Deg(90.0), Deg(0.0)));
+        assert!((listener.right - Vector3::new(1.0, 0.0, 0.0)).magnitude() < 1e-6);
+    }
+
+    #[test]
+    fn test_spatialize() {
+        let listener = Listener::default();
+
+        // straight ahead, halfway to the clip distance
+        let ahead = Vector3::new(SOUND_NOMINAL_CLIP_DIST / 2.0, 0.0, 0.0);
+        assert_gains(spatialize(&listener, ahead, 255, 1.0), (0.5, 0.5));
+
+        // twice the attenuation halves the range
+        assert_gains(spatialize(&listener, ahead, 255, 2.0), (0.0, 0.0));
+        assert_gains(spatialize(&listener, ahead * 4.0, 255, 0.0), (1.0, 1.0));
+
+        // off to the right, only the right ear hears it
+        let right = Vector3::new(0.0, -100.0, 0.0);
+        assert_gains(spatialize(&listener, right, 255, 1.0), (0.0, 1.8));
+
+        // the volume scales both sides, and a sound at the listener isn't panned
+        assert_gains(spatialize(&listener, listener.origin, 51, 1.0), (0.2, 0.2));
+    }
+
+    #[test]
+    fn test_spatialized_source() {
+        let gain = StereoGain::new(0.5, 2.0);
+        let samples: Vec<f32> = Spatialized::new(source(vec![0.5, -1.0]), gain.clone()).collect();
+        assert_eq!(samples, vec![0.25, 1.0, -0.5, -2.0]);
+
+        // stereo input is mixed down first
+        let stereo = ::rodio::buffer::SamplesBuffer::new(2, 1000, vec![1.0, 0.0]);
+        let samples: Vec<f32> = Spatialized::new(stereo, gain).collect();
+        assert_eq!(samples, vec![0.25, 1.0]);
+    }
+
+    // a mono 16-bit WAV file of the given samples
+    fn wav(samples: &[i16]) -> Vec<u8> {
+        use byteorder::{LittleEndian, WriteBytesExt};
+
+        let mut data = Vec::new();
+        data.extend_from_slice(b"RIFF");
+        data.write_u32::<LittleEndian>(36 + 2 * samples.len() as u32).unwrap();
+        data.extend_from_slice(b"WAVEfmt ");
+        data.write_u32::<LittleEndian>(16).unwrap();
+        data.write_u16::<LittleEndian>(1).unwrap(); // PCM
+        data.write_u16::<LittleEndian>(1).unwrap(); // channels
+        data.write_u32::<LittleEndian>(11025).unwrap(); // sample rate
+        data.write_u32::<LittleEndian>(22050).unwrap(); // byte rate
+        data.write_u16::<LittleEndian>(2).unwrap(); // block align
+        data.write_u16::<LittleEndian>(16).unwrap(); // bits per sample
+        data.extend_from_slice(b"data");
+        data.write_u32::<LittleEndian>(2 * samples.len() as u32).unwrap();
+        for s in samples {
+            data.write_i16::<LittleEndian>(*s).unwrap();
+        }
+
+        data
+    }
+
+    #[test]
+    fn test_music_track_path() {
+        assert_eq!(music_track_path(2), "music/track02.ogg");
+        assert_eq!(music_track_path(11), "music/track11.ogg");
+    }
+
+    #[test]
+    fn test_looping_track() {
+        let track = LoopingTrack::new(TrackData(Arc::new(wav(&[1, 2, 3])))).unwrap();
+        assert_eq!(track.channels(), 1);
+        assert_eq!(track.samples_rate(), 11025);
+        assert_eq!(track.take(7).collect::<Vec<_>>(), vec![1, 2, 3, 1, 2, 3, 1]);
+
+        // a track with no samples ends instead of looping forever
+        let mut empty = LoopingTrack::new(TrackData(Arc::new(wav(&[])))).unwrap();
+        assert_eq!(empty.next(), None);
+    }
+
+    // a mono source playing the given samples at 1000 samples per second
+    fn source(samples: Vec<f32>) -> ::rodio::buffer::SamplesBuffer<f32> {
+        ::rodio::buffer::SamplesBuffer::new(1, 1000, samples)
+    }
+
+    #[test]
+    fn test_pitched_resamples() {
+        let samples = vec![0.0, 1.0, 2.0, 3.0, 4.0];
+
+        let play = |pitch| -> Vec<f32> {
+            Pitched::new(source(samples.clone()), PitchControl::new(pitch)).collect()
+        };
+
+        assert_eq!(play(1.0), samples);
+        assert_eq!(play(2.0), vec![0.0, 2.0, 4.0]);
+        assert_eq!(play(0.5), vec![0.0, 0.5, 1.0, 1.5, 2.0, 2.5, 3.0, 3.5, 4.0, 4.0]);
+    }
+
+    #[test]
+    fn test_pitched_follows_control() {
+        let pitch = PitchControl::new(1.0);
+        let mut pitched = Pitched::new(source(vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0]), pitch.clone());
+        assert_eq!(pitched.next(), Some(0.0));
+        pitch.set(2.0);
+        assert_eq!(pitched.next(), Some(1.0));
+        assert_eq!(pitched.next(), Some(3.0));
+        assert_eq!(pitched.next(), Some(5.0));
+        assert_eq!(pitched.next(), None);
+    }
+
+    #[test]
+    fn test_doppler_passing_projectile() {
+        let listener = Vector3::new(0.0, 0.0, 0.0);
+        let still = Vector3::new(0.0, 0.0, 0.0);
+
+        // a rocket flying past along the X axis, 64 units to the side
+        let velocity = Vector3::new(1000.0, 0.0, 0.0);
+        let at = |x| doppler_factor(listener, still, Vector3::new(x, 64.0, 0.0), velocity, 1.0);
+
+        let approaching = at(-500.0);
+        let passing = at(0.0);
+        let receding = at(500.0);
+        assert!(approaching > 1.0);
+        assert!((passing - 1.0).abs() < 1e-6);
+        assert!(receding < 1.0);
+
+        // the shift is strongest far from the listener, where the rocket is heading straight
+        // towards or away from them
+        assert!(at(-2000.0) > approaching);
+        assert!(at(2000.0) < receding);
+
+        // disabled
+        let origin = Vector3::new(-500.0, 64.0, 0.0);
+        assert_eq!(doppler_factor(listener, still, origin, velocity, 0.0), 1.0);
+    }
+
+    #[test]
+    fn test_detune() {
+        let mut rng = ::rand::XorShiftRng::new_unseeded();
+        for _ in 0..100 {
+            let pitch = detune(&mut rng, 0.05);
+            assert!(pitch >= 0.95 && pitch <= 1.05);
+        }
+
+        assert_eq!(detune(&mut rng, 0.0), 1.0);
+    }
+
+    #[test]
+    fn test_channel_overflow_parse() {
+        assert_eq!(ChannelOverflow::parse("oldest"), Some(ChannelOverflow::StealOldest));
+        assert_eq!(ChannelOverflow::parse(" Quietest"), Some(ChannelOverflow::StealQuietest));
+        assert_eq!(ChannelOverflow::parse("2"), Some(ChannelOverflow::Drop));
+        assert_eq!(ChannelOverflow::parse("loudest"), None);
+    }
+
+    #[test]
+    fn test_music_ducking() {
+        let frame = Duration::milliseconds(250);
+        let mut ducking = MusicDucking::new();
+
+        // quiet sounds leave the music alone
+        ducking.update(0.5, frame, 0.5, 0.75, 1.0);
+        assert_eq!(ducking.gain(), 1.0);
+
+        // an explosion ducks the music at once
+        ducking.update(1.0, frame, 0.5, 0.75, 1.0);
+        assert_eq!(ducking.gain(), 0.5);
+
+        // and it recovers over the release time
+        ducking.update(0.0, frame, 0.5, 0.75, 1.0);
+        assert_eq!(ducking.gain(), 0.625);
+        for _ in 0..3 {
+            ducking.update(0.0, frame, 0.5, 0.75, 1.0);
+        }
+        assert_eq!(ducking.gain(), 1.0);
+        ducking.update(0.0, frame, 0.5, 0.75, 1.0);
+        assert_eq!(ducking.gain(), 1.0);
+
+        // no ducking at all
+        ducking.update(2.0, frame, 0.0, 0.75, 1.0);
+        assert_eq!(ducking.gain(), 1.0);
+    }
+}
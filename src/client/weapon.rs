@@ -0,0 +1,136 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use common::console::CvarRegistry;
+use common::engine;
+
+use chrono::Duration;
+
+/// How far below its resting position a newly selected weapon starts, in units.
+pub const WEAPON_SWITCH_DROP: f32 = 12.0;
+
+/// Returns how long a weapon switch takes according to `cl_weaponswitchtime` and
+/// `cl_instantswitch`.
+pub fn switch_duration(cvars: &CvarRegistry) -> Duration {
+    if cvars.get_value("cl_instantswitch").unwrap_or(0.0) != 0.0 {
+        return Duration::zero();
+    }
+
+    let seconds = cvars.get_value("cl_weaponswitchtime").unwrap_or(0.0);
+    if seconds > 0.0 {
+        engine::duration_from_f32(seconds)
+    } else {
+        Duration::zero()
+    }
+}
+
+/// Tracks the raise animation played when the player switches weapons.
+///
+/// The new weapon rises into view over the length of the switch, and it can't be fired until
+/// the switch is complete.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WeaponSwitch {
+    // (start time, duration) of the current switch
+    switch: Option<(Duration, Duration)>,
+}
+
+impl WeaponSwitch {
+    pub fn new() -> WeaponSwitch {
+        WeaponSwitch { switch: None }
+    }
+
+    /// Starts raising a new weapon at `time`.
+    ///
+    /// A zero `duration` switches instantly.
+    pub fn begin(&mut self, time: Duration, duration: Duration) {
+        self.switch = if duration > Duration::zero() {
+            Some((time, duration))
+        } else {
+            None
+        };
+    }
+
+    /// Returns how far the switch has progressed at `time`, from 0 to 1, or `None` if no switch
+    /// is in progress.
+    pub fn progress(&self, time: Duration) -> Option<f32> {
+        let (start, duration) = self.switch?;
+        let elapsed = time - start;
+        if elapsed >= duration {
+            return None;
+        }
+
+        let progress = engine::duration_to_f32(elapsed) / engine::duration_to_f32(duration);
+        Some(progress.max(0.0))
+    }
+
+    /// Returns whether firing is blocked at `time` because the weapon is still being raised.
+    pub fn blocks_fire(&self, time: Duration) -> bool {
+        self.progress(time).is_some()
+    }
+
+    /// Returns how far below its resting position the view model should be drawn at `time`.
+    pub fn view_model_drop(&self, time: Duration) -> f32 {
+        match self.progress(time) {
+            Some(p) => WEAPON_SWITCH_DROP * (1.0 - p),
+            None => 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fire_blocked_while_raising() {
+        let mut switch = WeaponSwitch::new();
+        assert!(!switch.blocks_fire(Duration::zero()));
+
+        switch.begin(Duration::seconds(1), Duration::milliseconds(400));
+        assert!(switch.blocks_fire(Duration::seconds(1)));
+        assert!(switch.blocks_fire(Duration::milliseconds(1399)));
+        assert!(!switch.blocks_fire(Duration::milliseconds(1400)));
+        assert!(!switch.blocks_fire(Duration::seconds(2)));
+    }
+
+    #[test]
+    fn test_view_model_rises() {
+        let mut switch = WeaponSwitch::new();
+        switch.begin(Duration::zero(), Duration::milliseconds(400));
+
+        assert_eq!(switch.view_model_drop(Duration::zero()), WEAPON_SWITCH_DROP);
+        let halfway = switch.view_model_drop(Duration::milliseconds(200));
+        assert!((halfway - WEAPON_SWITCH_DROP / 2.0).abs() < 1e-4);
+        assert_eq!(switch.view_model_drop(Duration::milliseconds(400)), 0.0);
+    }
+
+    #[test]
+    fn test_instant_switch() {
+        let cvars = CvarRegistry::new();
+        ::client::register_cvars(&cvars);
+        assert!(switch_duration(&cvars) > Duration::zero());
+
+        cvars.set("cl_instantswitch", "1").unwrap();
+        let mut switch = WeaponSwitch::new();
+        switch.begin(Duration::zero(), switch_duration(&cvars));
+        assert!(!switch.blocks_fire(Duration::zero()));
+        assert_eq!(switch.view_model_drop(Duration::zero()), 0.0);
+    }
+}
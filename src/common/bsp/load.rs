@@ -317,6 +317,22 @@ where
 /// Load a BSP file, returning the models it contains and a `String` describing the entities
 /// it contains.
 pub fn load<R>(data: R) -> Result<(Vec<Model>, String), Error>
+where
+    R: Read + Seek,
+{
+    load_with_entities(data, None, None)
+}
+
+/// Load a BSP file, replacing its entity lump with `ent_override` if given.
+///
+/// If the override can't be parsed, the embedded entity lump is used instead. `colored_lightmaps`
+/// holds the RGB samples of the map's `.lit` file, if any; they are ignored if they don't match
+/// the size of the lightmap lump.
+pub fn load_with_entities<R>(
+    data: R,
+    ent_override: Option<&str>,
+    colored_lightmaps: Option<&[u8]>,
+) -> Result<(Vec<Model>, String), Error>
 where
     R: Read + Seek,
 {
@@ -445,8 +461,21 @@ where
         ent_data.len() <= MAX_ENTSTRING,
         "Entity data exceeds MAX_ENTSTRING"
     );
-    let ent_string =
+    let mut ent_string =
         String::from_utf8(ent_data).context("Failed to create string from entity data")?;
+    let entities = match ent_override.map(|o| (o, super::parse_entities(o))) {
+        Some((o, ref entities)) if !entities.is_empty() => {
+            ent_string = o.to_owned();
+            entities.clone()
+        }
+
+        Some(_) => {
+            warn!("Ignoring entity override with no entities");
+            super::parse_entities(&ent_string)
+        }
+
+        None => super::parse_entities(&ent_string),
+    };
     check_alignment(&mut reader, ent_lump.offset + ent_lump.size as u64)?;
 
     // load planes
@@ -722,6 +751,19 @@ where
         lightmap_lump.offset + lightmap_lump.size as u64,
     )?;
 
+    let colored_lightmaps = match colored_lightmaps {
+        Some(samples) if samples.len() == 3 * lightmaps.len() => Some(samples.to_vec()),
+        Some(samples) => {
+            warn!(
+                "Colored lightmaps have {} samples, should have {}",
+                samples.len() / 3,
+                lightmaps.len()
+            );
+            None
+        }
+        None => None,
+    };
+
     reader.seek(SeekFrom::Start(collision_node_lump.offset))?;
 
     let mut collision_nodes = Vec::with_capacity(collision_node_count);
@@ -956,6 +998,7 @@ where
     };
 
     let bsp_data = Rc::new(BspData {
+        entities: entities.into_boxed_slice(),
         planes: planes_rc.clone(),
         textures: textures.into_boxed_slice(),
         vertices: vertices.into_boxed_slice(),
@@ -964,6 +1007,7 @@ where
         texinfo: texinfo.into_boxed_slice(),
         faces: faces.into_boxed_slice(),
         lightmaps: lightmaps.into_boxed_slice(),
+        colored_lightmaps: colored_lightmaps.map(|c| c.into_boxed_slice()),
         hulls: [hull_0, hull_1, hull_2],
         leaves: leaves.into_boxed_slice(),
         facelist: facelist.into_boxed_slice(),
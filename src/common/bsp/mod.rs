@@ -118,25 +118,32 @@
 
 mod load;
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt;
+use std::io::Read;
 use std::rc::Rc;
 
 use common::math::Hyperplane;
 use common::math::HyperplaneSide;
 use common::math::LinePlaneIntersect;
+use common::model::Model;
+use common::parse::map;
+use common::vfs::Vfs;
 
 // TODO: Either Trace should be moved into common or the functions requiring it should be moved into server
 use server::world::Trace;
 use server::world::TraceStart;
 use server::world::TraceEnd;
 
+use byteorder::{ByteOrder, LittleEndian};
 use chrono::Duration;
+use cgmath::InnerSpace;
 use cgmath::Vector3;
+use combine::Parser;
 use flame;
 
-pub use self::load::load;
+pub use self::load::{load, load_with_entities};
 
 // this is 4 in the original source, but the 4th hull is never used.
 const MAX_HULLS: usize = 3;
@@ -147,6 +154,9 @@ pub const MAX_SOUNDS: usize = 4;
 pub const MIPLEVELS: usize = 4;
 const DIST_EPSILON: f32 = 0.03125;
 
+// editor-only textures that mark brushes which should never be drawn
+const TOOL_TEXTURE_NAMES: &[&str] = &["clip", "hint", "hintskip", "nodraw", "skip", "trigger"];
+
 #[derive(Debug)]
 pub enum BspError {
     Io(::std::io::Error),
@@ -186,6 +196,206 @@ impl From<::std::io::Error> for BspError {
     }
 }
 
+/// Parses the entity lump into a list of key/value maps, one for each entity.
+///
+/// Malformed entities are skipped with a warning, resuming at the next `{`.
+pub fn parse_entities<S>(src: S) -> Vec<HashMap<String, String>>
+where
+    S: AsRef<str>,
+{
+    let mut src = src.as_ref().trim_end_matches('\0');
+    let mut entities = Vec::new();
+
+    loop {
+        src = match map::entity_space().parse(src) {
+            Ok((_, rest)) => rest,
+            Err(_) => src,
+        };
+
+        if src.is_empty() {
+            break;
+        }
+
+        match map::entity().easy_parse(src) {
+            Ok((entity, rest)) => {
+                entities.push(entity);
+                src = rest;
+            }
+
+            Err(e) => {
+                let e = e.map_position(|p| p.translate_position(src));
+                warn!("Skipping malformed entity: {}", e);
+                match src[1..].find('{') {
+                    Some(next) => src = &src[1 + next..],
+                    None => break,
+                }
+            }
+        }
+    }
+
+    entities
+}
+
+/// A static light source placed by the map author.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BspLight {
+    pub origin: Vector3<f32>,
+    pub intensity: f32,
+}
+
+// the brightness light.exe assumes for lights without a "light" key
+const DEFAULT_LIGHT_INTENSITY: f32 = 300.0;
+
+/// Collects the light sources (`light`, `light_torch_small_walltorch`, etc.) from a list of
+/// entities.
+///
+/// Lights without a valid origin are ignored.
+pub fn parse_lights(entities: &[HashMap<String, String>]) -> Vec<BspLight> {
+    entities
+        .iter()
+        .filter(|e| {
+            e.get("classname")
+                .map_or(false, |c| c.starts_with("light"))
+        })
+        .filter_map(|e| {
+            let coords: Vec<f32> = e
+                .get("origin")?
+                .split_whitespace()
+                .map(|c| c.parse().ok())
+                .collect::<Option<_>>()?;
+            if coords.len() != 3 {
+                return None;
+            }
+
+            let intensity = e
+                .get("light")
+                .and_then(|l| l.parse().ok())
+                .unwrap_or(DEFAULT_LIGHT_INTENSITY);
+
+            Some(BspLight {
+                origin: Vector3::new(coords[0], coords[1], coords[2]),
+                intensity,
+            })
+        })
+        .collect()
+}
+
+/// Returns the path of the external entity file that overrides the entities of the map at
+/// `bsp_path`, e.g. `maps/e1m1.ent` for `maps/e1m1.bsp`.
+pub fn ent_override_path(bsp_path: &str) -> String {
+    let stem = if bsp_path.ends_with(".bsp") {
+        &bsp_path[..bsp_path.len() - 4]
+    } else {
+        bsp_path
+    };
+
+    format!("{}.ent", stem)
+}
+
+/// Returns the path of the colored lightmap file for the map at `bsp_path`, e.g. `maps/e1m1.lit`
+/// for `maps/e1m1.bsp`.
+pub fn lit_path(bsp_path: &str) -> String {
+    let stem = if bsp_path.ends_with(".bsp") {
+        &bsp_path[..bsp_path.len() - 4]
+    } else {
+        bsp_path
+    };
+
+    format!("{}.lit", stem)
+}
+
+const LIT_MAGIC: &[u8; 4] = b"QLIT";
+const LIT_VERSION: i32 = 1;
+
+/// Parses the contents of a `.lit` file, returning its RGB lightmap samples.
+///
+/// A `.lit` file begins with the magic number `QLIT` and a little-endian 32-bit version number,
+/// which must be 1. The rest of the file is the BSP's lightmap lump with each sample expanded to a
+/// red, green and blue triple, so a face's colored lightmap begins at 3 times its lightmap offset.
+pub fn parse_lit(data: &[u8]) -> Result<&[u8], failure::Error> {
+    ensure!(data.len() >= 8, "Colored lightmap file is too short");
+    ensure!(&data[0..4] == LIT_MAGIC, "Bad colored lightmap magic number");
+
+    let version = LittleEndian::read_i32(&data[4..8]);
+    ensure!(
+        version == LIT_VERSION,
+        "Bad colored lightmap version (found {}, should be {})",
+        version,
+        LIT_VERSION
+    );
+
+    let samples = &data[8..];
+    ensure!(
+        samples.len() % 3 == 0,
+        "Colored lightmap size ({}) is not a multiple of 3",
+        samples.len()
+    );
+
+    Ok(samples)
+}
+
+/// Loads the map at `path`, using its external entity file in place of the embedded entity lump
+/// if one exists.
+///
+/// Colored lightmaps are loaded from the `.lit` file next to the map, if there is one.
+pub fn load_map(vfs: &Vfs, path: &str) -> Result<(Vec<Model>, String), failure::Error> {
+    let ent_path = ent_override_path(path);
+    let ent_override = match vfs.open(&ent_path) {
+        Ok(mut f) => {
+            let mut src = String::new();
+            match f.read_to_string(&mut src) {
+                Ok(_) => {
+                    debug!("Using entity override {}", ent_path);
+                    Some(src)
+                }
+
+                Err(e) => {
+                    warn!("Couldn't read entity override {}: {}", ent_path, e);
+                    None
+                }
+            }
+        }
+
+        Err(_) => None,
+    };
+
+    let lit_path = lit_path(path);
+    let lit = match vfs.open(&lit_path) {
+        Ok(mut f) => {
+            let mut data = Vec::new();
+            match f.read_to_end(&mut data) {
+                Ok(_) => Some(data),
+                Err(e) => {
+                    warn!("Couldn't read colored lightmaps {}: {}", lit_path, e);
+                    None
+                }
+            }
+        }
+
+        Err(_) => None,
+    };
+
+    let colored_lightmaps = match lit.as_ref().map(|data| parse_lit(data)) {
+        Some(Ok(samples)) => {
+            debug!("Using colored lightmaps {}", lit_path);
+            Some(samples)
+        }
+
+        Some(Err(e)) => {
+            warn!("Couldn't load colored lightmaps {}: {}", lit_path, e);
+            None
+        }
+
+        None => None,
+    };
+
+    load_with_entities(
+        vfs.open(path)?,
+        ent_override.as_ref().map(|s| s.as_str()),
+        colored_lightmaps,
+    )
+}
+
 #[derive(Copy, Clone, Debug, FromPrimitive)]
 pub enum BspTextureMipmap {
     Full = 0,
@@ -194,6 +404,15 @@ pub enum BspTextureMipmap {
     Eighth = 3,
 }
 
+/// The kind of liquid a texture represents.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BspLiquid {
+    Water,
+    Slime,
+    Lava,
+    Teleport,
+}
+
 #[derive(Debug)]
 pub struct BspTextureAnimation {
     pub sequence_duration: Duration,
@@ -234,6 +453,46 @@ impl BspTexture {
     pub fn mipmap(&self, mipmap: BspTextureMipmap) -> &[u8] {
         &self.mipmaps[mipmap as usize]
     }
+
+    /// Returns `true` if this is an editor tool texture (`clip`, `trigger`, `skip`, etc.).
+    ///
+    /// Faces with tool textures are left in the map by some compilers but should never be drawn.
+    pub fn is_tool(&self) -> bool {
+        TOOL_TEXTURE_NAMES
+            .iter()
+            .any(|n| self.name.eq_ignore_ascii_case(n))
+    }
+
+    /// Returns `true` if this is a sky texture.
+    ///
+    /// Sky textures are prefixed with `sky` and are drawn as scrolling cloud layers rather than
+    /// as ordinary surfaces.
+    pub fn is_sky(&self) -> bool {
+        self.name
+            .get(..3)
+            .map_or(false, |prefix| prefix.eq_ignore_ascii_case("sky"))
+    }
+
+    /// Returns the kind of liquid this texture represents, if any.
+    ///
+    /// Liquid textures are prefixed with `*`. By convention, `*slime`, `*lava` and `*tele`
+    /// textures are slime, lava and teleporters respectively, and any others are water.
+    pub fn liquid(&self) -> Option<BspLiquid> {
+        if !self.name.starts_with('*') {
+            return None;
+        }
+
+        let name = self.name[1..].to_ascii_lowercase();
+        Some(if name.starts_with("slime") {
+            BspLiquid::Slime
+        } else if name.starts_with("lava") {
+            BspLiquid::Lava
+        } else if name.starts_with("tele") {
+            BspLiquid::Teleport
+        } else {
+            BspLiquid::Water
+        })
+    }
 }
 
 #[derive(Debug)]
@@ -336,7 +595,7 @@ pub enum BspLeafContents {
     CurrentDown = 14,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum BspCollisionNodeChild {
     Node(usize),
     Contents(BspLeafContents),
@@ -348,6 +607,20 @@ pub struct BspCollisionNode {
     children: [BspCollisionNodeChild; 2],
 }
 
+impl BspCollisionNode {
+    /// Returns the index of the plane which splits this node.
+    pub fn plane_id(&self) -> usize {
+        self.plane_id
+    }
+
+    /// Returns the children of this node.
+    ///
+    /// The first child is in front of the splitting plane; the second is behind it.
+    pub fn children(&self) -> &[BspCollisionNodeChild; 2] {
+        &self.children
+    }
+}
+
 #[derive(Debug)]
 pub struct BspCollisionHull {
     planes: Rc<Box<[Hyperplane]>>,
@@ -358,6 +631,62 @@ pub struct BspCollisionHull {
     maxs: Vector3<f32>,
 }
 
+/// The collision hulls compiled into every BSP model.
+///
+/// A hull is the model's geometry expanded by the size of a box, so that tracing a single point
+/// through it is the same as sweeping the box through the original geometry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HullKind {
+    /// The unexpanded geometry, for points and projectiles.
+    Point = 0,
+
+    /// Geometry expanded by the player's bounding box, `(-16, -16, -24)` to `(16, 16, 32)`.
+    Player = 1,
+
+    /// Geometry expanded by the bounding box of large monsters such as shamblers, `(-32, -32,
+    /// -24)` to `(32, 32, 64)`.
+    Large = 2,
+}
+
+impl HullKind {
+    /// Chooses the hull used to move a box of the given size, as the original engine's
+    /// `SV_HullForEntity` does: boxes narrower than 3 units are treated as points, boxes up to 32
+    /// units wide use the player hull and anything larger uses the large hull.
+    pub fn for_size(size: Vector3<f32>) -> HullKind {
+        if size.x < 3.0 {
+            HullKind::Point
+        } else if size.x <= 32.0 {
+            HullKind::Player
+        } else {
+            HullKind::Large
+        }
+    }
+
+    /// Returns the index of this hull in `BspData::hulls()`.
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// The result of tracing a line segment through a collision hull.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HullTrace {
+    /// How far the trace got before it was stopped, from 0 (at the start) to 1 (at the end).
+    pub fraction: f32,
+
+    /// Where the trace was stopped, or the end of the segment if nothing was hit.
+    pub end_point: Vector3<f32>,
+
+    /// The normal of the plane that stopped the trace, pointing back toward the start.
+    pub plane_normal: Option<Vector3<f32>>,
+
+    /// Whether the trace started inside solid geometry.
+    pub start_solid: bool,
+
+    /// Whether the trace never left solid geometry.
+    pub all_solid: bool,
+}
+
 impl BspCollisionHull {
     // TODO: see if we can't make this a little less baffling
     /// Constructs a collision hull with the given minimum and maximum bounds.
@@ -458,6 +787,25 @@ impl BspCollisionHull {
         self.maxs
     }
 
+    /// Returns the planes referenced by this hull's nodes.
+    ///
+    /// Planes are given in Quake world space (X forward, Y left, Z up).
+    pub fn planes(&self) -> &[Hyperplane] {
+        &self.planes
+    }
+
+    /// Returns the collision nodes (clipnodes) of this hull.
+    ///
+    /// This slice may be shared with other hulls; traversal starts at `node_id()`.
+    pub fn nodes(&self) -> &[BspCollisionNode] {
+        &self.nodes
+    }
+
+    /// Returns the index of this hull's root node.
+    pub fn node_id(&self) -> usize {
+        self.node_id
+    }
+
     /// Returns the leaf contents at the given point in this hull.
     pub fn contents_at_point(&self, point: Vector3<f32>) -> Result<BspLeafContents, BspError> {
         self.contents_at_point_node(self.node_id, point)
@@ -484,6 +832,133 @@ impl BspCollisionHull {
         self.recursive_trace(self.node_id, start, end)
     }
 
+    /// Traces a point from `start` to `end` through this hull, stopping at the first solid
+    /// geometry it hits.
+    ///
+    /// This follows the original engine's `SV_RecursiveHullCheck`: changes of contents other than
+    /// into solid geometry (such as entering water) don't stop the trace, and the end point is
+    /// kept `DIST_EPSILON` units in front of the plane that was hit.
+    pub fn hull_trace(&self, start: Vector3<f32>, end: Vector3<f32>) -> HullTrace {
+        let mut trace = HullTrace {
+            fraction: 1.0,
+            end_point: end,
+            plane_normal: None,
+            start_solid: false,
+            all_solid: true,
+        };
+
+        self.recursive_hull_check(
+            BspCollisionNodeChild::Node(self.node_id),
+            0.0,
+            1.0,
+            start,
+            end,
+            &mut trace,
+        );
+
+        trace
+    }
+
+    fn child_contents(&self, child: BspCollisionNodeChild, point: Vector3<f32>) -> BspLeafContents {
+        match child {
+            BspCollisionNodeChild::Contents(c) => c,
+            BspCollisionNodeChild::Node(n) => self
+                .contents_at_point_node(n, point)
+                .unwrap_or(BspLeafContents::Solid),
+        }
+    }
+
+    // traces the part of the segment between fractions `p1f` and `p2f` (points `p1` and `p2`)
+    // through `child`. returns false once the trace has been stopped
+    fn recursive_hull_check(
+        &self,
+        child: BspCollisionNodeChild,
+        p1f: f32,
+        p2f: f32,
+        p1: Vector3<f32>,
+        p2: Vector3<f32>,
+        trace: &mut HullTrace,
+    ) -> bool {
+        let node = match child {
+            BspCollisionNodeChild::Contents(c) => {
+                if c == BspLeafContents::Solid {
+                    trace.start_solid = true;
+                } else {
+                    trace.all_solid = false;
+                }
+
+                return true;
+            }
+
+            BspCollisionNodeChild::Node(n) => &self.nodes[n],
+        };
+
+        let plane = &self.planes[node.plane_id];
+        let t1 = plane.point_dist(p1);
+        let t2 = plane.point_dist(p2);
+
+        if t1 >= 0.0 && t2 >= 0.0 {
+            return self.recursive_hull_check(node.children[0], p1f, p2f, p1, p2, trace);
+        }
+
+        if t1 < 0.0 && t2 < 0.0 {
+            return self.recursive_hull_check(node.children[1], p1f, p2f, p1, p2, trace);
+        }
+
+        // put the crossing point DIST_EPSILON units on the near side of the plane
+        let mut frac = if t1 < 0.0 {
+            (t1 + DIST_EPSILON) / (t1 - t2)
+        } else {
+            (t1 - DIST_EPSILON) / (t1 - t2)
+        };
+        frac = frac.max(0.0).min(1.0);
+
+        let mut midf = p1f + (p2f - p1f) * frac;
+        let mut mid = p1 + (p2 - p1) * frac;
+        let side = if t1 < 0.0 { 1 } else { 0 };
+
+        // move up to the plane
+        if !self.recursive_hull_check(node.children[side], p1f, midf, p1, mid, trace) {
+            return false;
+        }
+
+        // go past the plane if the other side isn't solid
+        let far = node.children[side ^ 1];
+        if self.child_contents(far, mid) != BspLeafContents::Solid {
+            return self.recursive_hull_check(far, midf, p2f, mid, p2, trace);
+        }
+
+        // never got out of the solid area
+        if trace.all_solid {
+            return false;
+        }
+
+        // the other side of the plane is solid, so this is the impact point
+        trace.plane_normal = Some(if side == 0 {
+            plane.get_normal()
+        } else {
+            -plane.get_normal()
+        });
+
+        let root = BspCollisionNodeChild::Node(self.node_id);
+        while self.child_contents(root, mid) == BspLeafContents::Solid {
+            // the backed-off point can still be in solid geometry if the plane meets another at a
+            // sharp angle, so keep backing off
+            frac -= 0.1;
+            if frac < 0.0 {
+                debug!("Hull trace backed up past the start");
+                break;
+            }
+
+            midf = p1f + (p2f - p1f) * frac;
+            mid = p1 + (p2 - p1) * frac;
+        }
+
+        trace.fraction = midf;
+        trace.end_point = mid;
+        false
+    }
+
     fn recursive_trace(
         &self,
         node: usize,
@@ -672,6 +1147,7 @@ pub struct BspEdgeIndex {
 
 #[derive(Debug)]
 pub struct BspData {
+    entities: Box<[HashMap<String, String>]>,
     planes: Rc<Box<[Hyperplane]>>,
     textures: Box<[BspTexture]>,
     vertices: Box<[Vector3<f32>]>,
@@ -680,6 +1156,7 @@ pub struct BspData {
     texinfo: Box<[BspTexInfo]>,
     faces: Box<[BspFace]>,
     lightmaps: Box<[u8]>,
+    colored_lightmaps: Option<Box<[u8]>>,
     leaves: Box<[BspLeaf]>,
     facelist: Box<[usize]>,
     edges: Box<[BspEdge]>,
@@ -688,6 +1165,28 @@ pub struct BspData {
 }
 
 impl BspData {
+    /// Returns the key/value pairs of each entity in the entity lump.
+    pub fn entities(&self) -> &[HashMap<String, String>] {
+        &self.entities
+    }
+
+    /// Returns the `worldspawn` entity, which holds map-wide settings such as the map title.
+    pub fn worldspawn(&self) -> Option<&HashMap<String, String>> {
+        self.entities
+            .iter()
+            .find(|e| e.get("classname").map(|c| c.as_str()) == Some("worldspawn"))
+    }
+
+    /// Returns the light sources placed in the map.
+    pub fn lights(&self) -> Vec<BspLight> {
+        parse_lights(&self.entities)
+    }
+
+    /// Returns the planes used to partition the map.
+    ///
+    /// Planes are stored in point-normal form in Quake world space, where X is the longitudinal
+    /// axis, Y is the lateral axis and Z is the vertical axis. A point `p` is in front of a plane
+    /// if `dot(normal, p) - dist` is positive.
     pub fn planes(&self) -> &[Hyperplane] {
         &self.planes
     }
@@ -696,14 +1195,30 @@ impl BspData {
         &self.textures
     }
 
+    /// Returns the vertex positions of the map in Quake world space.
     pub fn vertices(&self) -> &[Vector3<f32>] {
         &self.vertices
     }
 
+    /// Returns the raw run-length encoded visibility data.
+    ///
+    /// Each leaf's `vis_offset` indexes into this slice. Use `get_pvs()` to decode it.
+    pub fn visibility(&self) -> &[u8] {
+        &self.visibility
+    }
+
+    /// Returns the nodes of the rendering BSP tree (hull 0).
+    ///
+    /// Node bounds are axis-aligned boxes in Quake world space, rounded to integer units.
     pub fn render_nodes(&self) -> &[BspRenderNode] {
         &self.render_nodes
     }
 
+    /// Alias for `render_nodes()`.
+    pub fn nodes(&self) -> &[BspRenderNode] {
+        &self.render_nodes
+    }
+
     pub fn texinfo(&self) -> &[BspTexInfo] {
         &self.texinfo
     }
@@ -716,14 +1231,68 @@ impl BspData {
         &self.lightmaps
     }
 
+    /// Returns the dimensions and first light style's samples of the lightmap for face `face_id`.
+    ///
+    /// Returns `None` if the face has no lightmap. Lightmap samples are spaced 16 texels apart, so
+    /// a face with texture extents `(s, t)` has a lightmap of `(s / 16 + 1, t / 16 + 1)` samples.
+    pub fn face_lightmap(&self, face_id: usize) -> Option<(usize, usize, &[u8])> {
+        let face = &self.faces[face_id];
+        let ofs = face.lightmap_id?;
+
+        let width = (face.extents[0] / 16 + 1) as usize;
+        let height = (face.extents[1] / 16 + 1) as usize;
+
+        self.lightmaps
+            .get(ofs..ofs + width * height)
+            .map(|data| (width, height, data))
+    }
+
+    /// Returns whether RGB lightmaps were loaded from a `.lit` file.
+    pub fn has_colored_lightmaps(&self) -> bool {
+        self.colored_lightmaps.is_some()
+    }
+
+    /// Returns the dimensions and first light style's samples of the lightmap for face `face_id`
+    /// as red, green and blue triples.
+    ///
+    /// Colored lightmaps are used if they were loaded; otherwise each monochrome sample is
+    /// repeated in all three channels.
+    pub fn face_lightmap_rgb(&self, face_id: usize) -> Option<(usize, usize, Vec<u8>)> {
+        let (width, height, mono) = self.face_lightmap(face_id)?;
+
+        if let Some(ref colored) = self.colored_lightmaps {
+            let ofs = 3 * self.faces[face_id].lightmap_id?;
+            if let Some(data) = colored.get(ofs..ofs + 3 * width * height) {
+                return Some((width, height, data.to_vec()));
+            }
+        }
+
+        let mut data = Vec::with_capacity(3 * mono.len());
+        for &sample in mono {
+            data.extend_from_slice(&[sample, sample, sample]);
+        }
+
+        Some((width, height, data))
+    }
+
+    /// Returns the leaves of the rendering BSP tree.
+    ///
+    /// Leaf 0 is the shared solid leaf outside the map. Leaf bounds are axis-aligned boxes in
+    /// Quake world space, rounded to integer units.
     pub fn leaves(&self) -> &[BspLeaf] {
         &self.leaves
     }
 
+    /// Returns the face list (the "marksurfaces" lump), which maps leaf face ranges to face IDs.
     pub fn facelist(&self) -> &[usize] {
         &self.facelist
     }
 
+    /// Alias for `facelist()` using the name from the original Quake source.
+    pub fn marksurfaces(&self) -> &[usize] {
+        &self.facelist
+    }
+
     pub fn edges(&self) -> &[BspEdge] {
         &self.edges
     }
@@ -736,6 +1305,37 @@ impl BspData {
         &self.hulls
     }
 
+    /// Traces a line segment through the world's render hull (hull 0) from `start` to `end`.
+    ///
+    /// This is a point trace, so it collides with the visible geometry rather than the expanded
+    /// collision hulls used for player movement.
+    pub fn trace_line(&self, start: Vector3<f32>, end: Vector3<f32>) -> Result<Trace, BspError> {
+        self.hulls[0].trace(start, end)
+    }
+
+    /// Traces a point from `start` to `end` through the collision hull `hull_index` of the world.
+    ///
+    /// Traces through the player and large hulls (see `HullKind`) find where a box of that size
+    /// centered on the point would be stopped, which is how the server moves entities. Returns an
+    /// error if there is no such hull.
+    pub fn hull_trace(
+        &self,
+        hull_index: usize,
+        start: Vector3<f32>,
+        end: Vector3<f32>,
+    ) -> Result<HullTrace, BspError> {
+        let hull = self.hulls.get(hull_index).ok_or_else(|| {
+            BspError::with_msg(format!("Invalid hull index ({})", hull_index))
+        })?;
+
+        Ok(hull.hull_trace(start, end))
+    }
+
+    /// Returns the clipnodes shared by the player-sized (1) and large (2) collision hulls.
+    pub fn clipnodes(&self) -> &[BspCollisionNode] {
+        self.hulls[1].nodes()
+    }
+
     /// Find the index of the appropriate frame of the texture with index `first`.
     ///
     /// If the texture is not animated, immediately returns `first`.
@@ -800,6 +1400,201 @@ impl BspData {
         }
     }
 
+    /// Returns the contents of the leaf containing `point`.
+    ///
+    /// This is what the camera sees through: water, slime and lava leaves tint and warp the
+    /// view. A map without nodes is treated as empty.
+    pub fn leaf_contents_at<V>(&self, point: V) -> BspLeafContents
+    where
+        V: Into<Vector3<f32>>,
+    {
+        if self.render_nodes.is_empty() {
+            return BspLeafContents::Empty;
+        }
+
+        self.leaves[self.find_leaf(point)].contents
+    }
+
+    /// Samples the world lightmap directly beneath `point`.
+    ///
+    /// A line is traced from `point` down to 2048 units below it, and the lightmap of the first
+    /// face it crosses is sampled at the intersection. The result is scaled the same way as the
+    /// brush shader scales lightmaps: the first light style's sample (normalized to [0, 1]) times
+    /// the average of the face's active `lightstyle_values`.
+    ///
+    /// Returns `None` if the trace doesn't hit a lit face.
+    pub fn light_at_point(&self, point: Vector3<f32>, lightstyle_values: &[f32]) -> Option<f32> {
+        if self.render_nodes.is_empty() {
+            return None;
+        }
+
+        let end = point - Vector3::new(0.0, 0.0, 2048.0);
+        self.light_at_point_node(0, point, end, lightstyle_values)
+    }
+
+    fn light_at_point_node(
+        &self,
+        node_id: usize,
+        start: Vector3<f32>,
+        end: Vector3<f32>,
+        lightstyle_values: &[f32],
+    ) -> Option<f32> {
+        let node = &self.render_nodes[node_id];
+        let plane = &self.planes[node.plane_id];
+
+        let front = plane.point_dist(start);
+        let back = plane.point_dist(end);
+        let side = (front < 0.0) as usize;
+
+        // if the segment doesn't cross the plane, only check the side it's on
+        if (back < 0.0) as usize == side {
+            return match node.children[side] {
+                BspRenderNodeChild::Node(n) => {
+                    self.light_at_point_node(n, start, end, lightstyle_values)
+                }
+                BspRenderNodeChild::Leaf(_) => None,
+            };
+        }
+
+        let mid = start + (end - start) * (front / (front - back));
+
+        // check the near side first
+        if let BspRenderNodeChild::Node(n) = node.children[side] {
+            if let Some(l) = self.light_at_point_node(n, start, mid, lightstyle_values) {
+                return Some(l);
+            }
+        }
+
+        // check the faces on the splitting plane
+        for face_id in node.face_id..node.face_id + node.face_count {
+            let face = &self.faces[face_id];
+            let texinfo = &self.texinfo[face.texinfo_id];
+            if texinfo.special {
+                continue;
+            }
+
+            let s = mid.dot(texinfo.s_vector) + texinfo.s_offset - face.texture_mins[0] as f32;
+            let t = mid.dot(texinfo.t_vector) + texinfo.t_offset - face.texture_mins[1] as f32;
+            if s < 0.0 || t < 0.0 || s > face.extents[0] as f32 || t > face.extents[1] as f32 {
+                continue;
+            }
+
+            let (width, _, lightmap) = match self.face_lightmap(face_id) {
+                Some(l) => l,
+                None => return Some(0.0),
+            };
+
+            let sample = lightmap[(t as usize >> 4) * width + (s as usize >> 4)] as f32 / 255.0;
+
+            let mut style_count = 0;
+            let mut style_total = 0.0;
+            for style in face.light_styles.iter() {
+                match lightstyle_values.get(*style as usize) {
+                    Some(v) => {
+                        style_total += v;
+                        style_count += 1;
+                    }
+                    None => break,
+                }
+            }
+
+            let light_factor = if style_count == 0 {
+                1.0
+            } else {
+                style_total / style_count as f32
+            };
+
+            return Some(sample * light_factor);
+        }
+
+        // check the far side
+        match node.children[1 - side] {
+            BspRenderNodeChild::Node(n) => self.light_at_point_node(n, mid, end, lightstyle_values),
+            BspRenderNodeChild::Leaf(_) => None,
+        }
+    }
+
+    /// Collects the faces on the nodes below `node_id` that a light at `origin` may reach.
+    ///
+    /// Follows the original engine's `R_MarkLights`: only the side of a node's plane that the
+    /// light's sphere reaches is searched, and a node's faces are collected if the sphere
+    /// crosses its plane.
+    pub fn find_lit_faces(
+        &self,
+        node_id: usize,
+        origin: Vector3<f32>,
+        radius: f32,
+        face_ids: &mut Vec<usize>,
+    ) {
+        let node = &self.render_nodes[node_id];
+        let dist = self.planes[node.plane_id].point_dist(origin);
+
+        let sides: &[usize] = if dist > radius {
+            &[0]
+        } else if dist < -radius {
+            &[1]
+        } else {
+            face_ids.extend(node.face_id..node.face_id + node.face_count);
+            &[0, 1]
+        };
+
+        for &side in sides {
+            if let BspRenderNodeChild::Node(n) = node.children[side] {
+                self.find_lit_faces(n, origin, radius, face_ids);
+            }
+        }
+    }
+
+    /// Returns the IDs of the non-solid leaves touched by the box from `min` to `max`.
+    pub fn find_touched_leaves(&self, min: Vector3<f32>, max: Vector3<f32>) -> Vec<usize> {
+        let mut leaf_ids = Vec::new();
+        self.find_touched_leaves_node(&BspRenderNodeChild::Node(0), min, max, &mut leaf_ids);
+        leaf_ids
+    }
+
+    fn find_touched_leaves_node(
+        &self,
+        child: &BspRenderNodeChild,
+        min: Vector3<f32>,
+        max: Vector3<f32>,
+        leaf_ids: &mut Vec<usize>,
+    ) {
+        let node_id = match *child {
+            BspRenderNodeChild::Node(n) => n,
+            BspRenderNodeChild::Leaf(l) => {
+                if self.leaves[l].contents != BspLeafContents::Solid {
+                    leaf_ids.push(l);
+                }
+                return;
+            }
+        };
+
+        let node = &self.render_nodes[node_id];
+        let plane = &self.planes[node.plane_id];
+
+        // find the distances of the box corners nearest to and farthest from the plane
+        let normal = plane.get_normal();
+        let mut near = Vector3::new(0.0, 0.0, 0.0);
+        let mut far = Vector3::new(0.0, 0.0, 0.0);
+        for i in 0..3 {
+            if normal[i] >= 0.0 {
+                near[i] = min[i];
+                far[i] = max[i];
+            } else {
+                near[i] = max[i];
+                far[i] = min[i];
+            }
+        }
+
+        if plane.point_dist(far) >= 0.0 {
+            self.find_touched_leaves_node(&node.children[0], min, max, leaf_ids);
+        }
+
+        if plane.point_dist(near) < 0.0 {
+            self.find_touched_leaves_node(&node.children[1], min, max, leaf_ids);
+        }
+    }
+
     pub fn get_pvs(&self, leaf_id: usize, leaf_count: usize) -> Vec<usize> {
         let _guard = flame::start_guard("BspData::get_pvs");
         // leaf 0 is outside the map, everything is visible
@@ -808,33 +1603,7 @@ impl BspData {
         }
 
         match self.leaves[leaf_id].vis_offset {
-            Some(o) => {
-                let mut visleaf = 1;
-                let mut visleaf_list = Vec::new();
-                let mut it = (&self.visibility[o..]).iter();
-
-                while visleaf < leaf_count {
-                    let byte = it.next().unwrap();
-                    match *byte {
-                        // a zero byte signals the start of an RLE sequence
-                        0 => visleaf += 8 * *it.next().unwrap() as usize,
-
-                        bits => for shift in 0..8 {
-                            if bits & 1 << shift != 0 {
-                                if visleaf >= leaf_count {
-                                    error!("Impossible visleaf in PVS: the len is {} but the leaf ID is {}", leaf_count, visleaf);
-                                }
-                                visleaf_list.push(visleaf);
-                            }
-
-                            visleaf += 1;
-                        },
-                    }
-                }
-
-                visleaf_list
-            }
-
+            Some(o) => decompress_vis(&self.visibility[o..], leaf_count),
             None => Vec::new(),
         }
     }
@@ -948,12 +1717,18 @@ impl BspModel {
         self.origin
     }
 
+    /// Returns the ID of the render node at the root of this model's tree.
+    pub fn render_node_id(&self) -> usize {
+        // the first hull shares its tree with rendering
+        self.collision_node_ids[0]
+    }
+
     pub fn face_list(&self) -> &[usize] {
         &self.bsp_data.facelist[self.face_id..self.face_id + self.face_count]
     }
 
     pub fn hull(&self, index: usize) -> Result<BspCollisionHull, BspError> {
-        if index > MAX_HULLS {
+        if index >= MAX_HULLS {
             return Err(BspError::with_msg(format!(
                 "Invalid hull index ({})",
                 index
@@ -974,11 +1749,416 @@ impl BspModel {
 
 impl BspData {}
 
+/// Decodes a compressed visibility bitset into the IDs of the visible leaves.
+///
+/// Bit `n` of the set stands for leaf `n + 1`, since leaf 0 (outside the map) is never visible. A
+/// zero byte is followed by a count of zero bytes it stands for, so that long runs of invisible
+/// leaves take two bytes. Only leaves below `leaf_count` are returned, and decoding stops early if
+/// the data runs out.
+pub fn decompress_vis(data: &[u8], leaf_count: usize) -> Vec<usize> {
+    let mut visleaf = 1;
+    let mut visleaf_list = Vec::new();
+    let mut it = data.iter();
+
+    while visleaf < leaf_count {
+        let byte = match it.next() {
+            Some(b) => *b,
+            None => break,
+        };
+
+        match byte {
+            // a zero byte signals the start of an RLE sequence
+            0 => match it.next() {
+                Some(count) => visleaf += 8 * *count as usize,
+                None => break,
+            },
+
+            bits => {
+                for shift in 0..8 {
+                    if bits & 1 << shift != 0 && visleaf < leaf_count {
+                        visleaf_list.push(visleaf);
+                    }
+
+                    visleaf += 1;
+                }
+            }
+        }
+    }
+
+    visleaf_list
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use cgmath::Zero;
 
+    #[test]
+    fn test_decompress_vis() {
+        // leaves 1 and 3, then 16 invisible leaves, then leaf 26
+        let data = [0b0000_0101, 0, 2, 0b0000_0010, 0xff];
+        assert_eq!(decompress_vis(&data, 27), vec![1, 3, 26]);
+
+        // leaves past the end of the map are ignored
+        assert_eq!(decompress_vis(&[0xff], 5), vec![1, 2, 3, 4]);
+
+        // truncated data stops decoding instead of panicking
+        assert_eq!(decompress_vis(&[0b1000_0000, 0], 100), vec![8]);
+    }
+
+    #[test]
+    fn test_parse_lit() {
+        assert_eq!(lit_path("maps/e1m1.bsp"), "maps/e1m1.lit");
+
+        let mut lit = b"QLIT\x01\x00\x00\x00".to_vec();
+        lit.extend_from_slice(&[255, 128, 0, 10, 20, 30]);
+        assert_eq!(parse_lit(&lit).unwrap(), &[255, 128, 0, 10, 20, 30]);
+
+        // wrong version
+        lit[4] = 2;
+        assert!(parse_lit(&lit).is_err());
+        lit[4] = 1;
+
+        // incomplete sample
+        lit.push(0);
+        assert!(parse_lit(&lit).is_err());
+
+        assert!(parse_lit(b"IBSP\x01\x00\x00\x00").is_err());
+        assert!(parse_lit(b"QLIT").is_err());
+    }
+
+    #[test]
+    fn test_texture_is_tool() {
+        let tex = |name: &str| BspTexture {
+            name: name.to_owned(),
+            width: 0,
+            height: 0,
+            mipmaps: [Vec::new(), Vec::new(), Vec::new(), Vec::new()],
+            animation: None,
+        };
+
+        assert!(tex("clip").is_tool());
+        assert!(tex("TRIGGER").is_tool());
+        assert!(tex("skip").is_tool());
+        assert!(tex("hint").is_tool());
+        assert!(!tex("city4_6").is_tool());
+        assert!(!tex("*water0").is_tool());
+        assert!(!tex("triggerplate").is_tool());
+    }
+
+    #[test]
+    fn test_texture_is_sky() {
+        let tex = |name: &str| BspTexture {
+            name: name.to_owned(),
+            width: 0,
+            height: 0,
+            mipmaps: [Vec::new(), Vec::new(), Vec::new(), Vec::new()],
+            animation: None,
+        };
+
+        assert!(tex("sky1").is_sky());
+        assert!(tex("SKY4").is_sky());
+        assert!(!tex("sk").is_sky());
+        assert!(!tex("*water0").is_sky());
+        assert!(!tex("metal_sky").is_sky());
+    }
+
+    #[test]
+    fn test_texture_liquid() {
+        let tex = |name: &str| BspTexture {
+            name: name.to_owned(),
+            width: 0,
+            height: 0,
+            mipmaps: [Vec::new(), Vec::new(), Vec::new(), Vec::new()],
+            animation: None,
+        };
+
+        assert_eq!(tex("*water0").liquid(), Some(BspLiquid::Water));
+        assert_eq!(tex("*04water1").liquid(), Some(BspLiquid::Water));
+        assert_eq!(tex("*slime0").liquid(), Some(BspLiquid::Slime));
+        assert_eq!(tex("*LAVA1").liquid(), Some(BspLiquid::Lava));
+        assert_eq!(tex("*teleport").liquid(), Some(BspLiquid::Teleport));
+        assert_eq!(tex("lava_wall").liquid(), None);
+        assert_eq!(tex("city4_6").liquid(), None);
+    }
+
+    #[test]
+    fn test_parse_entities() {
+        let src = concat!(
+            "{\n",
+            "\"classname\" \"worldspawn\"\n",
+            "\"message\" \"The Slipgate Complex\"\n",
+            "\"wad\" \"gfx/base.wad\"\n",
+            "}\n",
+            // other compilers may use different whitespace
+            "{\r\n",
+            "\t\"classname\"  \"light\"\r\n",
+            "\"origin\" \"0 -128 64\"\n",
+            "// a comment\n",
+            "\"_color\" \"\"\n",
+            "}\n",
+            "\0",
+        );
+
+        let entities = parse_entities(src);
+        assert_eq!(entities.len(), 2);
+
+        assert_eq!(entities[0]["classname"], "worldspawn");
+        assert_eq!(entities[0]["message"], "The Slipgate Complex");
+        assert_eq!(entities[0]["wad"], "gfx/base.wad");
+
+        assert_eq!(entities[1]["classname"], "light");
+        assert_eq!(entities[1]["origin"], "0 -128 64");
+        assert_eq!(entities[1]["_color"], "");
+    }
+
+    #[test]
+    fn test_ent_override_path() {
+        assert_eq!(ent_override_path("maps/e1m1.bsp"), "maps/e1m1.ent");
+        assert_eq!(ent_override_path("maps/start"), "maps/start.ent");
+    }
+
+    #[test]
+    fn test_parse_entities_malformed() {
+        assert!(parse_entities("\"classname\" \"worldspawn\"").is_empty());
+        assert!(parse_entities("{\n\"classname\" \"worldspawn\"\n").is_empty());
+
+        // the bad entity is skipped, but the ones around it are kept
+        let src = concat!(
+            "{ \"classname\" \"worldspawn\" }\n",
+            "{\n\"classname\"\n}\n",
+            "{ \"classname\" \"light\" }\n",
+        );
+        let entities = parse_entities(src);
+        assert_eq!(entities.len(), 2);
+        assert_eq!(entities[0]["classname"], "worldspawn");
+        assert_eq!(entities[1]["classname"], "light");
+    }
+
+    #[test]
+    fn test_parse_lights() {
+        let src = concat!(
+            "{ \"classname\" \"worldspawn\" }\n",
+            "{ \"classname\" \"light\" \"origin\" \"0 -128 64\" }\n",
+            "{ \"classname\" \"light_torch_small_walltorch\" \"origin\" \"16 0 8\" ",
+            "\"light\" \"200\" }\n",
+            "{ \"classname\" \"light\" \"origin\" \"0 0\" }\n",
+            "{ \"classname\" \"info_player_start\" \"origin\" \"0 0 0\" }\n",
+        );
+
+        let lights = parse_lights(&parse_entities(src));
+        assert_eq!(
+            lights,
+            vec![
+                BspLight {
+                    origin: Vector3::new(0.0, -128.0, 64.0),
+                    intensity: 300.0,
+                },
+                BspLight {
+                    origin: Vector3::new(16.0, 0.0, 8.0),
+                    intensity: 200.0,
+                },
+            ]
+        );
+    }
+
+    // a map split by the plane z = 0, with water below it
+    fn pool_bsp_data() -> BspData {
+        let hull = || {
+            BspCollisionHull::for_bounds(Vector3::zero(), Vector3::new(1.0, 1.0, 1.0)).unwrap()
+        };
+        let leaf = |contents| BspLeaf {
+            contents,
+            vis_offset: None,
+            min: [0; 3],
+            max: [0; 3],
+            facelist_id: 0,
+            facelist_count: 0,
+            sounds: [0; MAX_SOUNDS],
+        };
+
+        BspData {
+            entities: Box::new([]),
+            planes: Rc::new(vec![Hyperplane::axis_z(0.0)].into_boxed_slice()),
+            textures: Box::new([]),
+            vertices: Box::new([]),
+            visibility: Box::new([]),
+            render_nodes: vec![BspRenderNode {
+                plane_id: 0,
+                children: [BspRenderNodeChild::Leaf(1), BspRenderNodeChild::Leaf(2)],
+                min: [0; 3],
+                max: [0; 3],
+                face_id: 0,
+                face_count: 0,
+            }].into_boxed_slice(),
+            texinfo: Box::new([]),
+            faces: Box::new([]),
+            lightmaps: Box::new([]),
+            colored_lightmaps: None,
+            leaves: vec![
+                leaf(BspLeafContents::Solid),
+                leaf(BspLeafContents::Empty),
+                leaf(BspLeafContents::Water),
+            ].into_boxed_slice(),
+            facelist: Box::new([]),
+            edges: Box::new([]),
+            edgelist: Box::new([]),
+            hulls: [hull(), hull(), hull()],
+        }
+    }
+
+    #[test]
+    fn test_leaf_contents_at() {
+        let bsp_data = pool_bsp_data();
+
+        // walk down from above the surface into the water
+        assert_eq!(
+            bsp_data.leaf_contents_at(Vector3::new(0.0, 0.0, 64.0)),
+            BspLeafContents::Empty
+        );
+        assert_eq!(
+            bsp_data.leaf_contents_at(Vector3::new(0.0, 0.0, -8.0)),
+            BspLeafContents::Water
+        );
+    }
+
+    #[test]
+    fn test_find_lit_faces() {
+        let mut bsp_data = pool_bsp_data();
+        bsp_data.render_nodes = vec![
+            BspRenderNode {
+                plane_id: 0,
+                children: [BspRenderNodeChild::Leaf(1), BspRenderNodeChild::Node(1)],
+                min: [0; 3],
+                max: [0; 3],
+                face_id: 0,
+                face_count: 2,
+            },
+            BspRenderNode {
+                plane_id: 0,
+                children: [BspRenderNodeChild::Leaf(2), BspRenderNodeChild::Leaf(2)],
+                min: [0; 3],
+                max: [0; 3],
+                face_id: 2,
+                face_count: 1,
+            },
+        ].into_boxed_slice();
+
+        let lit = |origin, radius| {
+            let mut face_ids = Vec::new();
+            bsp_data.find_lit_faces(0, origin, radius, &mut face_ids);
+            face_ids
+        };
+
+        // crosses the surface, so both nodes are reached
+        assert_eq!(lit(Vector3::new(0.0, 0.0, 16.0), 32.0), vec![0, 1, 2]);
+
+        // entirely above the surface, so the back side is never searched
+        assert!(lit(Vector3::new(0.0, 0.0, 64.0), 32.0).is_empty());
+    }
+
+    // a solid 64-unit cube at the origin, expanded for each hull
+    fn box_bsp_data() -> BspData {
+        let hull = |expand_min: [f32; 3], expand_max: [f32; 3]| {
+            BspCollisionHull::for_bounds(
+                Vector3::from(expand_min) * -1.0,
+                Vector3::new(64.0, 64.0, 64.0) + Vector3::from(expand_max),
+            ).unwrap()
+        };
+
+        // a box sweeps into the cube as soon as its far side touches it, so the cube grows by the
+        // box's maxs on its near side and by its mins on its far side
+        BspData {
+            hulls: [
+                hull([0.0; 3], [0.0; 3]),
+                hull([16.0, 16.0, 32.0], [16.0, 16.0, 24.0]),
+                hull([32.0, 32.0, 64.0], [32.0, 32.0, 24.0]),
+            ],
+            ..pool_bsp_data()
+        }
+    }
+
+    fn assert_approx(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_hull_trace_hits_box() {
+        let bsp_data = box_bsp_data();
+
+        let trace = bsp_data
+            .hull_trace(0, Vector3::new(-32.0, 32.0, 32.0), Vector3::new(96.0, 32.0, 32.0))
+            .unwrap();
+        assert_approx(trace.fraction, (32.0 - DIST_EPSILON) / 128.0);
+        assert_approx(trace.end_point.x, -DIST_EPSILON);
+        assert_eq!(trace.plane_normal, Some(Vector3::new(-1.0, 0.0, 0.0)));
+        assert!(!trace.start_solid);
+        assert!(!trace.all_solid);
+
+        // dropping onto the top of the cube
+        let trace = bsp_data
+            .hull_trace(0, Vector3::new(32.0, 32.0, 128.0), Vector3::new(32.0, 32.0, 0.0))
+            .unwrap();
+        assert_approx(trace.end_point.z, 64.0 + DIST_EPSILON);
+        assert_eq!(trace.plane_normal, Some(Vector3::new(0.0, 0.0, 1.0)));
+
+        // passing over the cube
+        let start = Vector3::new(-32.0, 32.0, 100.0);
+        let end = Vector3::new(96.0, 32.0, 100.0);
+        let trace = bsp_data.hull_trace(0, start, end).unwrap();
+        assert_eq!(trace.fraction, 1.0);
+        assert_eq!(trace.end_point, end);
+        assert_eq!(trace.plane_normal, None);
+    }
+
+    #[test]
+    fn test_hull_trace_hull_sizes() {
+        let bsp_data = box_bsp_data();
+        let start = Vector3::new(-64.0, 32.0, 32.0);
+        let end = Vector3::new(64.0, 32.0, 32.0);
+
+        // the player and large hulls stop the trace 16 and 32 units before the cube
+        let hulls = [
+            (HullKind::Point, 0.0),
+            (HullKind::Player, -16.0),
+            (HullKind::Large, -32.0),
+        ];
+        for &(hull, edge) in hulls.iter() {
+            let trace = bsp_data.hull_trace(hull.index(), start, end).unwrap();
+            assert_approx(trace.end_point.x, edge - DIST_EPSILON);
+            assert_approx(trace.fraction, (edge + 64.0 - DIST_EPSILON) / 128.0);
+        }
+
+        assert!(bsp_data.hull_trace(3, start, end).is_err());
+    }
+
+    #[test]
+    fn test_hull_trace_start_solid() {
+        let bsp_data = box_bsp_data();
+
+        // leaving the cube
+        let trace = bsp_data
+            .hull_trace(0, Vector3::new(32.0, 32.0, 32.0), Vector3::new(32.0, 32.0, 128.0))
+            .unwrap();
+        assert!(trace.start_solid);
+        assert!(!trace.all_solid);
+        assert_eq!(trace.fraction, 1.0);
+
+        // never leaving it
+        let trace = bsp_data
+            .hull_trace(0, Vector3::new(16.0, 32.0, 32.0), Vector3::new(48.0, 32.0, 32.0))
+            .unwrap();
+        assert!(trace.start_solid);
+        assert!(trace.all_solid);
+    }
+
+    #[test]
+    fn test_hull_kind_for_size() {
+        assert_eq!(HullKind::for_size(Vector3::zero()), HullKind::Point);
+        assert_eq!(HullKind::for_size(Vector3::new(32.0, 32.0, 56.0)), HullKind::Player);
+        assert_eq!(HullKind::for_size(Vector3::new(64.0, 64.0, 88.0)), HullKind::Large);
+    }
+
     #[test]
     fn test_hull_for_bounds() {
         let hull =
@@ -19,13 +19,15 @@
 
 // TODO: have console commands take an IntoIter<AsRef<str>> instead of a Vec<String>
 
-use std::cell::{Ref, RefCell};
+use std::cell::{Cell, Ref, RefCell};
 use std::collections::HashMap;
 use std::collections::VecDeque;
+use std::io::{Read, Write};
 use std::iter::FromIterator;
 use std::rc::Rc;
 
 use common::parse;
+use common::vfs::Vfs;
 
 use combine::Parser;
 use failure::Error;
@@ -71,6 +73,19 @@ impl CmdRegistry {
         Ok(())
     }
 
+    /// Removes the command with the given name.
+    ///
+    /// Returns an error if no command with the specified name exists.
+    pub fn remove<S>(&mut self, name: S) -> Result<(), ()>
+    where
+        S: AsRef<str>,
+    {
+        match self.cmds.remove(name.as_ref()) {
+            Some(_) => Ok(()),
+            None => Err(()),
+        }
+    }
+
     /// Executes a command.
     ///
     /// Returns an error if no command with the specified name exists.
@@ -96,6 +111,43 @@ impl CmdRegistry {
     }
 }
 
+/// Owns a set of commands, removing them from their registry when dropped.
+pub struct CmdGuard {
+    cmds: Rc<RefCell<CmdRegistry>>,
+    names: Vec<String>,
+}
+
+impl CmdGuard {
+    pub fn new(cmds: Rc<RefCell<CmdRegistry>>) -> CmdGuard {
+        CmdGuard {
+            cmds,
+            names: Vec::new(),
+        }
+    }
+
+    /// Registers a new command owned by this guard.
+    ///
+    /// Returns an error if a command with the specified name already exists.
+    pub fn insert<S>(&mut self, name: S, cmd: Box<Fn(&[&str])>) -> Result<(), ()>
+    where
+        S: AsRef<str>,
+    {
+        self.cmds.borrow_mut().insert(name.as_ref(), cmd)?;
+        self.names.push(name.as_ref().to_owned());
+        Ok(())
+    }
+}
+
+impl ::std::ops::Drop for CmdGuard {
+    fn drop(&mut self) {
+        let mut cmds = self.cmds.borrow_mut();
+        for name in self.names.iter() {
+            // nothing else should have removed them, but that's no reason to panic in a drop
+            let _ = cmds.remove(name);
+        }
+    }
+}
+
 /// A configuration variable.
 ///
 /// Cvars are the primary method of configuring the game.
@@ -113,10 +165,78 @@ struct Cvar {
 
     // The default value of this variable
     default: String,
+
+    // The kind of value this variable holds
+    kind: CvarKind,
+
+    // If true, commands stuffed by a server can't change this variable
+    user_only: bool,
+}
+
+/// The kind of value a `Cvar` holds, and the range it's kept within.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CvarKind {
+    /// Any text.
+    String,
+
+    /// A finite number, clamped to `[min, max]`.
+    Float { min: f32, max: f32 },
+
+    /// A whole number, clamped to `[min, max]`.
+    Int { min: i32, max: i32 },
+
+    /// 0 or 1. Any other number is stored as 1.
+    Bool,
+}
+
+impl CvarKind {
+    /// Returns the value to store when a `Cvar` of this kind is set to `value`, or `None` if the
+    /// value isn't valid for this kind.
+    fn sanitize(&self, value: &str) -> Option<String> {
+        match *self {
+            CvarKind::String => Some(value.to_owned()),
+
+            CvarKind::Float { min, max } => {
+                let f = value.trim().parse::<f32>().ok()?;
+                if !f.is_finite() {
+                    None
+                } else if f < min {
+                    Some(min.to_string())
+                } else if f > max {
+                    Some(max.to_string())
+                } else {
+                    Some(value.to_owned())
+                }
+            }
+
+            CvarKind::Int { min, max } => {
+                let i = value.trim().parse::<i32>().ok()?;
+                if i < min {
+                    Some(min.to_string())
+                } else if i > max {
+                    Some(max.to_string())
+                } else {
+                    Some(value.to_owned())
+                }
+            }
+
+            CvarKind::Bool => match value.trim().parse::<f32>() {
+                Ok(f) if f == 0.0 => Some("0".to_owned()),
+                Ok(f) if !f.is_nan() => Some("1".to_owned()),
+                _ => None,
+            },
+        }
+    }
 }
 
 pub struct CvarRegistry {
     cvars: RefCell<HashMap<String, Cvar>>,
+
+    // set while commands from a server run, so that they can't change user-only cvars
+    server_locked: Cell<bool>,
+
+    // invoked with the new value whenever the named cvar is set
+    callbacks: RefCell<HashMap<String, Box<FnMut(&str)>>>,
 }
 
 impl CvarRegistry {
@@ -124,16 +244,31 @@ impl CvarRegistry {
     pub fn new() -> CvarRegistry {
         CvarRegistry {
             cvars: RefCell::new(HashMap::new()),
+            server_locked: Cell::new(false),
+            callbacks: RefCell::new(HashMap::new()),
         }
     }
 
-    fn register_impl<S>(&self, name: S, default: S, archive: bool, notify: bool) -> Result<(), ()>
+    fn register_impl<S>(
+        &self,
+        name: S,
+        default: S,
+        kind: CvarKind,
+        archive: bool,
+        notify: bool,
+        user_only: bool,
+    ) -> Result<(), ()>
     where
         S: AsRef<str>,
     {
         let name = name.as_ref();
         let default = default.as_ref();
 
+        if kind.sanitize(default).as_ref().map(|d| d.as_str()) != Some(default) {
+            error!("Default value \"{}\" of {} is out of range", default, name);
+            return Err(());
+        }
+
         let mut cvars = self.cvars.borrow_mut();
         match cvars.get(name) {
             Some(_) => return Err(()),
@@ -145,6 +280,8 @@ impl CvarRegistry {
                         archive,
                         notify,
                         default: default.to_owned(),
+                        kind,
+                        user_only,
                     },
                 );
             }
@@ -158,7 +295,18 @@ impl CvarRegistry {
     where
         S: AsRef<str>,
     {
-        self.register_impl(name, default, false, false)
+        self.register_impl(name, default, CvarKind::String, false, false, false)
+    }
+
+    /// Register a new `Cvar` whose values are checked against `kind`.
+    ///
+    /// Numbers outside the range of `kind` are clamped to it, and values that aren't valid at all
+    /// are rejected. Returns an error if `default` itself isn't valid.
+    pub fn register_typed<S>(&self, name: S, default: S, kind: CvarKind) -> Result<(), ()>
+    where
+        S: AsRef<str>,
+    {
+        self.register_impl(name, default, kind, false, false, false)
     }
 
     /// Register a new archived `Cvar` with the given name.
@@ -169,7 +317,18 @@ impl CvarRegistry {
     where
         S: AsRef<str>,
     {
-        self.register_impl(name, default, true, false)
+        self.register_impl(name, default, CvarKind::String, true, false, false)
+    }
+
+    /// Register a new `Cvar` that only the user can set.
+    ///
+    /// Commands stuffed by a server can't change it, so it's safe to use for settings a server
+    /// shouldn't control, like where content is downloaded from.
+    pub fn register_user_only<S>(&self, name: S, default: S) -> Result<(), ()>
+    where
+        S: AsRef<str>,
+    {
+        self.register_impl(name, default, CvarKind::String, false, false, true)
     }
 
     /// Register a new notify `Cvar` with the given name.
@@ -181,7 +340,7 @@ impl CvarRegistry {
     where
         S: AsRef<str>,
     {
-        self.register_impl(name, default, false, true)
+        self.register_impl(name, default, CvarKind::String, false, true, false)
     }
 
     /// Register a new notify + archived `Cvar` with the given name.
@@ -196,7 +355,7 @@ impl CvarRegistry {
     where
         S: AsRef<str>,
     {
-        self.register_impl(name, default, true, true)
+        self.register_impl(name, default, CvarKind::String, true, true, false)
     }
 
     pub fn get<S>(&self, name: S) -> Result<String, ()>
@@ -222,22 +381,117 @@ impl CvarRegistry {
         }
     }
 
+    /// Sets the value of a `Cvar`.
+    ///
+    /// Out-of-range numbers are clamped. A value that isn't valid for the `Cvar`'s kind at all
+    /// resets it to its default, so a garbage value can't leave it in an unusable state, and
+    /// returns an error.
     pub fn set<S>(&self, name: S, value: S) -> Result<(), ()>
     where
         S: AsRef<str>,
     {
-        debug!("cvar assignment: {} {}", name.as_ref(), value.as_ref());
-        match self.cvars.borrow_mut().get_mut(name.as_ref()) {
+        let name = name.as_ref();
+        let value = value.as_ref();
+        debug!("cvar assignment: {} {}", name, value);
+
+        let (stored, valid) = match self.cvars.borrow_mut().get_mut(name) {
             Some(s) => {
-                s.val = value.as_ref().to_owned();
+                if s.user_only && self.server_locked.get() {
+                    warn!("{} can't be set by the server", name);
+                    return Err(());
+                }
+
+                let valid = match s.kind.sanitize(value) {
+                    Some(v) => {
+                        if v != value {
+                            warn!("{} must be in {:?}, clamped {} to {}", name, s.kind, value, v);
+                        }
+                        s.val = v;
+                        true
+                    }
+
+                    None => {
+                        warn!(
+                            "\"{}\" isn't a valid value for {}, restoring default \"{}\"",
+                            value, name, s.default
+                        );
+                        s.val = s.default.clone();
+                        false
+                    }
+                };
+
                 if s.notify {
                     // TODO: update userinfo/serverinfo
                     unimplemented!();
                 }
-                Ok(())
+
+                (s.val.clone(), valid)
             }
-            None => Err(()),
+
+            None => return Err(()),
+        };
+
+        // the callback is taken out while it runs so it can read and set cvars itself
+        let callback = self.callbacks.borrow_mut().remove(name);
+        if let Some(mut callback) = callback {
+            callback(&stored);
+
+            // keep a replacement registered by the callback
+            self.callbacks
+                .borrow_mut()
+                .entry(name.to_owned())
+                .or_insert(callback);
+        }
+
+        if valid {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    /// Registers a function to be called with the new value whenever a `Cvar` is set.
+    ///
+    /// This replaces any callback already registered for the `Cvar`, and returns an error if no
+    /// `Cvar` with the given name exists.
+    ///
+    /// The callback runs synchronously inside `set`, after the new value is stored, so it is
+    /// called once per assignment in the order the assignments are made, even if several happen
+    /// in the same frame or the value doesn't change. If the callback sets its own `Cvar`, the
+    /// value is stored but the callback isn't called again.
+    pub fn set_callback<S>(&self, name: S, callback: Box<FnMut(&str)>) -> Result<(), ()>
+    where
+        S: AsRef<str>,
+    {
+        if !self.contains(name.as_ref()) {
+            return Err(());
+        }
+
+        self.callbacks
+            .borrow_mut()
+            .insert(name.as_ref().to_owned(), callback);
+
+        Ok(())
+    }
+
+    /// Sets the value of a `Cvar` and marks it as archived, registering it if it doesn't exist.
+    ///
+    /// This is what `seta` lines in `config.cfg` do, so that cvars which aren't registered yet
+    /// keep their saved values.
+    pub fn set_archived<S>(&self, name: S, value: S) -> Result<(), ()>
+    where
+        S: AsRef<str>,
+    {
+        if !self.contains(name.as_ref()) {
+            return self.register_archive(name, value);
         }
+
+        self.set(name.as_ref(), value.as_ref())?;
+        if let Some(cvar) = self.cvars.borrow_mut().get_mut(name.as_ref()) {
+            cvar.archive = true;
+        }
+
+        Ok(())
     }
 
     pub fn contains<S>(&self, name: S) -> bool
@@ -246,6 +500,48 @@ impl CvarRegistry {
     {
         self.cvars.borrow().contains_key(name.as_ref())
     }
+
+    /// Returns whether the named `Cvar` can currently be set.
+    ///
+    /// User-only `Cvar`s can't be set while commands from a server are running.
+    pub fn is_settable<S>(&self, name: S) -> bool
+    where
+        S: AsRef<str>,
+    {
+        match self.cvars.borrow().get(name.as_ref()) {
+            Some(c) => !(c.user_only && self.server_locked.get()),
+            None => false,
+        }
+    }
+
+    /// Sets whether commands from a server are running, which keeps user-only `Cvar`s fixed.
+    pub fn set_server_locked(&self, locked: bool) {
+        self.server_locked.set(locked);
+    }
+
+    /// Writes a `seta` line for each archived `Cvar`, sorted by name.
+    ///
+    /// Values are always quoted so that ones containing spaces are read back whole. The console
+    /// has no way to escape a double-quote, so any in a value are dropped.
+    pub fn write_archived<W>(&self, writer: &mut W) -> Result<(), Error>
+    where
+        W: Write,
+    {
+        let cvars = self.cvars.borrow();
+        let mut names: Vec<&String> = cvars
+            .iter()
+            .filter(|&(_, cvar)| cvar.archive)
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+
+        for name in names {
+            let value = cvars[name].val.replace('"', "");
+            writeln!(writer, "seta {} \"{}\"", name, value)?;
+        }
+
+        Ok(())
+    }
 }
 
 /// The line of text currently being edited in the console.
@@ -381,8 +677,120 @@ impl History {
     }
 }
 
+/// Introduces a color code in console text. `^1` switches to red, `^7` back to white.
+pub const COLOR_ESCAPE: char = '^';
+
+/// Text colors selectable with color codes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConsoleColor {
+    Red,
+    Green,
+    Yellow,
+    White,
+    Gray,
+}
+
+impl ConsoleColor {
+    /// Returns the color selected by the code character following a `COLOR_ESCAPE`.
+    pub fn from_code(code: char) -> Option<ConsoleColor> {
+        match code {
+            '1' => Some(ConsoleColor::Red),
+            '2' => Some(ConsoleColor::Green),
+            '3' => Some(ConsoleColor::Yellow),
+            '7' => Some(ConsoleColor::White),
+            '8' => Some(ConsoleColor::Gray),
+            _ => None,
+        }
+    }
+
+    /// Returns the color code string for this color, e.g. `"^1"` for red.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            ConsoleColor::Red => "^1",
+            ConsoleColor::Green => "^2",
+            ConsoleColor::Yellow => "^3",
+            ConsoleColor::White => "^7",
+            ConsoleColor::Gray => "^8",
+        }
+    }
+
+    /// Returns the tint applied to glyphs drawn in this color.
+    pub fn rgb(&self) -> [f32; 3] {
+        match *self {
+            ConsoleColor::Red => [1.0, 0.3, 0.3],
+            ConsoleColor::Green => [0.4, 1.0, 0.4],
+            ConsoleColor::Yellow => [1.0, 1.0, 0.4],
+            ConsoleColor::White => [1.0, 1.0, 1.0],
+            ConsoleColor::Gray => [0.6, 0.6, 0.6],
+        }
+    }
+}
+
+/// Splits `text` into its printable characters and the color runs applied to them.
+///
+/// Each run is given as the index of its first character and its color. Text is white until the
+/// first color code. A `COLOR_ESCAPE` not followed by a known code is printed as-is.
+pub fn parse_color_codes(text: &str) -> (Vec<char>, Vec<(usize, ConsoleColor)>) {
+    let mut chars = Vec::new();
+    let mut runs = vec![(0, ConsoleColor::White)];
+
+    let mut iter = text.chars().peekable();
+    while let Some(c) = iter.next() {
+        if c == COLOR_ESCAPE {
+            if let Some(color) = iter.peek().and_then(|code| ConsoleColor::from_code(*code)) {
+                iter.next();
+
+                // a run with no characters in it is replaced by the new one
+                if runs.last().unwrap().0 == chars.len() {
+                    runs.pop();
+                }
+                runs.push((chars.len(), color));
+                continue;
+            }
+        }
+
+        chars.push(c);
+    }
+
+    (chars, runs)
+}
+
+/// Removes all color codes from `text`.
+pub fn strip_color_codes(text: &str) -> String {
+    String::from_iter(parse_color_codes(text).0)
+}
+
+/// A line of console output and its colors.
+pub struct ConsoleLine {
+    chars: Vec<char>,
+    runs: Vec<(usize, ConsoleColor)>,
+}
+
+impl ConsoleLine {
+    /// Returns the text of the line with color codes removed.
+    pub fn chars(&self) -> &[char] {
+        &self.chars
+    }
+
+    /// Returns the colored runs of text that make up this line.
+    pub fn runs(&self) -> Vec<(ConsoleColor, &[char])> {
+        self.runs
+            .iter()
+            .enumerate()
+            .map(|(i, &(start, color))| {
+                let end = match self.runs.get(i + 1) {
+                    Some(&(next, _)) => next,
+                    None => self.chars.len(),
+                };
+
+                (color, &self.chars[start..end])
+            })
+            .collect()
+    }
+}
+
 pub struct ConsoleOutput {
-    lines: VecDeque<Vec<char>>,
+    lines: VecDeque<ConsoleLine>,
 }
 
 impl ConsoleOutput {
@@ -392,16 +800,27 @@ impl ConsoleOutput {
         }
     }
 
+    /// Adds a line of output, interpreting any color codes it contains.
     pub fn push(&mut self, chars: Vec<char>) {
-        self.lines.push_front(chars);
+        let (chars, runs) = parse_color_codes(&String::from_iter(chars));
+        self.lines.push_front(ConsoleLine { chars, runs });
         // TODO: set maximum capacity and pop_back when we reach it
     }
 
+    /// Returns the text of each line, newest first, with color codes removed.
     pub fn lines(&self) -> impl Iterator<Item = &[char]> {
-        self.lines.iter().map(|v| v.as_slice())
+        self.lines.iter().map(|l| l.chars())
+    }
+
+    /// Returns each line with its colors, newest first.
+    pub fn colored_lines(&self) -> impl Iterator<Item = &ConsoleLine> {
+        self.lines.iter()
     }
 }
 
+// how deeply aliases and exec'd scripts may nest before they're assumed to be recursing
+const MAX_EXEC_DEPTH: usize = 64;
+
 pub struct Console {
     cmds: Rc<RefCell<CmdRegistry>>,
     cvars: Rc<RefCell<CvarRegistry>>,
@@ -410,6 +829,12 @@ pub struct Console {
     input: ConsoleInput,
     hist: History,
     buffer: RefCell<String>,
+
+    // text stuffed by a server, which can't change user-only cvars
+    server_buffer: RefCell<String>,
+
+    // text inserted by commands, executed ahead of the rest of the buffer
+    inserted: RefCell<String>,
     output: Rc<RefCell<ConsoleOutput>>,
 }
 
@@ -431,6 +856,20 @@ impl Console {
             )
             .unwrap();
 
+        let seta_cvars = cvars.clone();
+        cmds.borrow_mut()
+            .insert(
+                "seta",
+                Box::new(move |args| match args.len() {
+                    2 => {
+                        let _ = seta_cvars.borrow().set_archived(args[0], args[1]);
+                    }
+
+                    _ => println!("seta (cvar) (value): set and archive a cvar"),
+                }),
+            )
+            .unwrap();
+
         let aliases: Rc<RefCell<HashMap<String, String>>> = Rc::new(RefCell::new(HashMap::new()));
         let cmd_aliases = aliases.clone();
         cmds.borrow_mut()
@@ -444,13 +883,33 @@ impl Console {
                         println!("{} alias command(s)", cmd_aliases.borrow().len());
                     }
 
-                    2 => {
+                    1 => match cmd_aliases.borrow().get(args[0]) {
+                        Some(script) => println!("\"{}\" is \"{}\"", args[0], script),
+                        None => println!("\"{}\" is not an alias", args[0]),
+                    },
+
+                    // like the original, extra arguments are joined into the script
+                    _ => {
                         let name = args[0].to_string();
-                        let script = args[1].to_string();
+                        let script = args[1..].join(" ");
                         let _ = cmd_aliases.borrow_mut().insert(name, script);
                     }
+                }),
+            )
+            .unwrap();
+
+        let unalias_aliases = aliases.clone();
+        cmds.borrow_mut()
+            .insert(
+                "unalias",
+                Box::new(move |args| match args.len() {
+                    1 => {
+                        if unalias_aliases.borrow_mut().remove(args[0]).is_none() {
+                            println!("\"{}\" is not an alias", args[0]);
+                        }
+                    }
 
-                    _ => (),
+                    _ => println!("unalias (name): delete an alias"),
                 }),
             )
             .unwrap();
@@ -462,6 +921,8 @@ impl Console {
             input: ConsoleInput::new(),
             hist: History::new(),
             buffer: RefCell::new(String::new()),
+            server_buffer: RefCell::new(String::new()),
+            inserted: RefCell::new(String::new()),
             output: output.clone(),
         }
     }
@@ -524,60 +985,136 @@ impl Console {
     }
 
     /// Interprets the contents of the execution buffer.
+    ///
+    /// Text inserted while a command runs (by `exec` or an alias) is executed before the rest of
+    /// the buffer, so scripts run in the order they're written. Text stuffed by a server runs
+    /// last, and can't change user-only cvars.
     pub fn execute(&self) {
-        let text = self.buffer.borrow().to_owned();
-        self.buffer.borrow_mut().clear();
+        let text = self.inserted.replace(String::new()) + &self.buffer.replace(String::new());
+        self.execute_text(&text);
+
+        let server_text = self.server_buffer.replace(String::new());
+        if !server_text.is_empty() {
+            self.cvars.borrow().set_server_locked(true);
+            self.execute_text(&server_text);
+            self.cvars.borrow().set_server_locked(false);
+        }
+    }
+
+    fn execute_text(&self, text: &str) {
+        // each command is paired with how many aliases and scripts it's nested in
+        let mut commands: VecDeque<(usize, Vec<String>)> =
+            parse_commands(text).into_iter().map(|c| (0, c)).collect();
+
+        while let Some((depth, args)) = commands.pop_front() {
+            debug!("{:?}", args);
+            self.execute_command(&args);
 
-        println!("parsing: {:?}", text);
-        let (commands, _remaining) = parse::commands().easy_parse(text.as_str()).unwrap();
+            let inserted = self.inserted.replace(String::new());
+            if inserted.is_empty() {
+                continue;
+            }
+
+            // an alias that invokes itself would otherwise never finish
+            if depth >= MAX_EXEC_DEPTH {
+                self.println(format!(
+                    "{}\"{}\" is nested too deeply, ignoring",
+                    ConsoleColor::Red.code(),
+                    args[0]
+                ));
+                continue;
+            }
 
-        for command in commands.iter() {
-            debug!("{:?}", command);
+            for cmd in parse_commands(&inserted).into_iter().rev() {
+                commands.push_front((depth + 1, cmd));
+            }
         }
+    }
 
-        for args in commands {
-            if let Some(arg_0) = args.get(0) {
-                let maybe_alias = self.aliases.borrow().get(arg_0).map(|s| s.to_owned());
-                match maybe_alias {
-                    Some(a) => {
-                        self.stuff_text(a);
-                        self.execute();
-                    }
+    fn execute_command(&self, args: &[String]) {
+        let arg_0 = match args.get(0) {
+            Some(a) => a,
+            None => return,
+        };
 
-                    None => {
-                        let tail_args: Vec<&str> =
-                            (&args[1..]).iter().map(|s| s.as_str()).collect();
-
-                        if self.cmds.borrow().contains(arg_0) {
-                            self.cmds.borrow_mut().exec(arg_0, &tail_args).unwrap();
-                        } else if self.cvars.borrow().contains(arg_0) {
-                            // TODO error handling on cvar set
-                            match args.get(1) {
-                                Some(arg_1) => self.cvars.borrow_mut().set(arg_0, arg_1).unwrap(),
-                                None => {
-                                    let msg = format!(
-                                        "\"{}\" is \"{}\"",
-                                        arg_0,
-                                        self.cvars.borrow().get(arg_0).unwrap()
-                                    );
-                                    self.output
-                                        .borrow_mut()
-                                        .push(msg.as_str().chars().collect());
-                                }
-                            }
-                        } else {
-                            // TODO: try sending to server first
-                            self.output.borrow_mut().push(
-                                format!("Unrecognized command \"{}\"", arg_0)
-                                    .as_str()
-                                    .chars()
-                                    .collect(),
-                            );
-                        }
+        let maybe_alias = self.aliases.borrow().get(arg_0).map(|s| s.to_owned());
+        if let Some(a) = maybe_alias {
+            self.insert_text(expand_alias(&a, &args[1..]));
+            return;
+        }
+
+        let tail_args: Vec<&str> = (&args[1..]).iter().map(|s| s.as_str()).collect();
+
+        if self.cmds.borrow().contains(arg_0) {
+            self.cmds.borrow_mut().exec(arg_0, &tail_args).unwrap();
+        } else if self.cvars.borrow().contains(arg_0) {
+            match args.get(1) {
+                Some(_) if !self.cvars.borrow().is_settable(arg_0) => {
+                    self.println(format!(
+                        "{}\"{}\" can't be set by the server",
+                        ConsoleColor::Red.code(),
+                        arg_0
+                    ));
+                }
+                Some(arg_1) => {
+                    if self.cvars.borrow().set(arg_0, arg_1).is_err() {
+                        self.println(format!(
+                            "{}\"{}\" isn't a valid value for \"{}\"",
+                            ConsoleColor::Red.code(),
+                            arg_1,
+                            arg_0
+                        ));
                     }
                 }
+                None => {
+                    let msg = format!(
+                        "\"{}\" is \"{}\"",
+                        arg_0,
+                        self.cvars.borrow().get(arg_0).unwrap()
+                    );
+                    self.output.borrow_mut().push(msg.as_str().chars().collect());
+                }
             }
+        } else {
+            // TODO: try sending to server first
+            self.output.borrow_mut().push(
+                format!("{}Unrecognized command \"{}\"", ConsoleColor::Red.code(), arg_0)
+                    .as_str()
+                    .chars()
+                    .collect(),
+            );
+        }
+    }
+
+    /// Reads a script file and inserts it at the front of the execution buffer.
+    ///
+    /// When called from a command, the script runs before any commands that followed it.
+    pub fn exec<S>(&self, vfs: &Vfs, name: S) -> Result<(), Error>
+    where
+        S: AsRef<str>,
+    {
+        let mut script = String::new();
+        vfs.open(name.as_ref())?.read_to_string(&mut script)?;
+        self.insert_text(script);
+
+        Ok(())
+    }
+
+    /// Writes the console text, oldest line first and without color codes, to `path` in the
+    /// game directory.
+    pub fn dump<S>(&self, vfs: &Vfs, path: S) -> Result<(), Error>
+    where
+        S: AsRef<str>,
+    {
+        let mut text = String::new();
+        for line in self.output.borrow().lines().collect::<Vec<_>>().iter().rev() {
+            text.extend(line.iter());
+            text.push('\n');
         }
+
+        vfs.create(path.as_ref())?.write_all(text.as_bytes())?;
+
+        Ok(())
     }
 
     pub fn get_string(&self) -> String {
@@ -603,9 +1140,106 @@ impl Console {
         self.buffer.borrow_mut().push_str("\n");
     }
 
+    /// Appends text sent by a server to be executed after the execution buffer.
+    ///
+    /// Unlike `stuff_text`, this text can't change user-only cvars.
+    pub fn stuff_server_text<S>(&self, text: S)
+    where
+        S: AsRef<str>,
+    {
+        debug!("stuff_server_text:\n{:?}", text.as_ref());
+        let mut server_buffer = self.server_buffer.borrow_mut();
+        server_buffer.push_str(text.as_ref());
+        server_buffer.push_str("\n");
+    }
+
+    /// Inserts text at the front of the execution buffer, ahead of anything already stuffed.
+    pub fn insert_text<S>(&self, text: S)
+    where
+        S: AsRef<str>,
+    {
+        debug!("insert_text:\n{:?}", text.as_ref());
+        let mut inserted = self.inserted.borrow_mut();
+        inserted.push_str(text.as_ref());
+        inserted.push_str("\n");
+    }
+
     pub fn output(&self) -> Ref<ConsoleOutput> {
         self.output.borrow()
     }
+
+    /// Prints a line of text to the console output. The text may contain color codes.
+    pub fn println<S>(&self, text: S)
+    where
+        S: AsRef<str>,
+    {
+        self.output.borrow_mut().push(text.as_ref().chars().collect());
+    }
+}
+
+/// Substitutes the arguments an alias was invoked with into its script.
+///
+/// `$1` through `$9` are replaced with the corresponding argument, quoted if it contains spaces,
+/// and `$*` with all of the arguments. Missing arguments are replaced with nothing.
+pub fn expand_alias<S>(script: &str, args: &[S]) -> String
+where
+    S: AsRef<str>,
+{
+    let mut expanded = String::new();
+    let mut chars = script.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            expanded.push(c);
+            continue;
+        }
+
+        match chars.peek().cloned() {
+            Some('*') => {
+                chars.next();
+                let all: Vec<&str> = args.iter().map(|a| a.as_ref()).collect();
+                expanded.push_str(&all.join(" "));
+            }
+
+            Some(d @ '1'..='9') => {
+                chars.next();
+                let i = d as usize - '1' as usize;
+                if let Some(arg) = args.get(i) {
+                    let arg = arg.as_ref();
+                    if arg.contains(char::is_whitespace) {
+                        expanded.push_str(&format!("\"{}\"", arg));
+                    } else {
+                        expanded.push_str(arg);
+                    }
+                }
+            }
+
+            _ => expanded.push(c),
+        }
+    }
+
+    expanded
+}
+
+/// Splits console text into commands, reporting text that can't be parsed.
+fn parse_commands(text: &str) -> Vec<Vec<String>> {
+    if text.trim().is_empty() {
+        return Vec::new();
+    }
+
+    match parse::commands().easy_parse(text) {
+        Ok((commands, remaining)) => {
+            if !remaining.trim().is_empty() {
+                warn!("Couldn't parse console text: {:?}", remaining);
+            }
+            commands
+        }
+
+        Err(e) => {
+            warn!("Couldn't parse console text: {:?}", e);
+            Vec::new()
+        }
+    }
 }
 
 pub struct Tokenizer<'a> {
@@ -774,4 +1408,304 @@ mod tests {
         let mut tokenizer = Tokenizer::new("// this is a comment");
         assert_eq!(tokenizer.next(), None);
     }
+
+    #[test]
+    fn test_exec() {
+        let dir =
+            ::std::env::temp_dir().join(format!("richter-exec-test-{}", ::std::process::id()));
+        ::std::fs::create_dir_all(&dir).unwrap();
+        ::std::fs::write(
+            dir.join("autoexec.cfg"),
+            "exec nested.cfg\nsensitivity \"5\" // after nested.cfg\n",
+        )
+        .unwrap();
+        ::std::fs::write(
+            dir.join("nested.cfg"),
+            "sensitivity 3; alias greet \"echo hello\"\n",
+        )
+        .unwrap();
+
+        let mut vfs = Vfs::new();
+        vfs.add_directory(&dir).unwrap();
+        let vfs = Rc::new(vfs);
+
+        let cmds = Rc::new(RefCell::new(CmdRegistry::new()));
+        let cvars = Rc::new(RefCell::new(CvarRegistry::new()));
+        cvars.borrow().register_archive("sensitivity", "3").unwrap();
+        let console = Rc::new(RefCell::new(Console::new(cmds.clone(), cvars.clone())));
+
+        let exec_console = console.clone();
+        cmds.borrow_mut()
+            .insert(
+                "exec",
+                Box::new(move |args| exec_console.borrow().exec(&vfs, args[0]).unwrap()),
+            )
+            .unwrap();
+
+        console.borrow().stuff_text("exec autoexec.cfg");
+        console.borrow().execute();
+
+        // the nested script ran before the rest of the outer one
+        assert_eq!(cvars.borrow().get("sensitivity").unwrap(), "5");
+
+        console.borrow().stuff_text("greet");
+        console.borrow().execute();
+        let hello: Vec<char> = "hello".chars().collect();
+        assert_eq!(console.borrow().output().lines().next().unwrap(), &hello[..]);
+
+        ::std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cmd_registry_remove() {
+        let mut cmds = CmdRegistry::new();
+        cmds.insert("toggleconsole", Box::new(|_| ())).unwrap();
+        assert!(cmds.insert("toggleconsole", Box::new(|_| ())).is_err());
+
+        cmds.remove("toggleconsole").unwrap();
+        assert!(!cmds.contains("toggleconsole"));
+        assert!(cmds.remove("toggleconsole").is_err());
+        cmds.insert("toggleconsole", Box::new(|_| ())).unwrap();
+    }
+
+    #[test]
+    fn test_cmd_guard() {
+        let cmds = Rc::new(RefCell::new(CmdRegistry::new()));
+
+        // the second guard can only register the command if the first removed it
+        for _ in 0..2 {
+            let count = Rc::new(Cell::new(0));
+            let count_cmd = count.clone();
+
+            let mut guard = CmdGuard::new(cmds.clone());
+            guard
+                .insert("toggleconsole", Box::new(move |_| count_cmd.set(count_cmd.get() + 1)))
+                .unwrap();
+            assert!(guard.insert("toggleconsole", Box::new(|_| ())).is_err());
+
+            cmds.borrow_mut().exec("toggleconsole", &[]).unwrap();
+            assert_eq!(count.get(), 1);
+
+            drop(guard);
+            assert!(!cmds.borrow().contains("toggleconsole"));
+        }
+    }
+
+    #[test]
+    fn test_expand_alias() {
+        assert_eq!(expand_alias("echo $1", &["hello"]), "echo hello");
+        assert_eq!(expand_alias("say $2 $1", &["a", "b"]), "say b a");
+        assert_eq!(expand_alias("say $*", &["a", "b c"]), "say a b c");
+        assert_eq!(expand_alias("echo $1", &["two words"]), "echo \"two words\"");
+        assert_eq!(expand_alias("echo [$2]", &["a"]), "echo []");
+        assert_eq!(expand_alias("echo $$x $", &[] as &[&str]), "echo $$x $");
+    }
+
+    #[test]
+    fn test_alias() {
+        let cmds = Rc::new(RefCell::new(CmdRegistry::new()));
+        let cvars = Rc::new(RefCell::new(CvarRegistry::new()));
+        let console = Console::new(cmds, cvars);
+        let last_line =
+            |console: &Console| String::from_iter(console.output().lines().next().unwrap());
+
+        console.stuff_text("alias greet \"echo $1\"");
+        console.stuff_text("greet world");
+        console.execute();
+        assert_eq!(last_line(&console), "world");
+
+        console.stuff_text("unalias greet; greet world");
+        console.execute();
+        assert_eq!(last_line(&console), "Unrecognized command \"greet\"");
+
+        // aliases that recurse forever are cut off
+        console.stuff_text("alias loop \"echo again; loop\"; loop");
+        console.execute();
+        assert_eq!(last_line(&console), "\"loop\" is nested too deeply, ignoring");
+    }
+
+    #[test]
+    fn test_server_text_user_only() {
+        let cmds = Rc::new(RefCell::new(CmdRegistry::new()));
+        let cvars = Rc::new(RefCell::new(CvarRegistry::new()));
+        cvars.borrow().register("fov", "90").unwrap();
+        cvars.borrow().register_user_only("cl_downloadurl", "").unwrap();
+        let console = Console::new(cmds, cvars.clone());
+
+        // aliases run from server text are locked out too
+        console.stuff_server_text("fov 110; alias dl \"cl_downloadurl http://evil/\"; dl");
+        console.execute();
+        assert_eq!(cvars.borrow().get("fov").unwrap(), "110");
+        assert_eq!(cvars.borrow().get("cl_downloadurl").unwrap(), "");
+
+        // the lock is lifted once the server's text has run
+        console.stuff_text("dl");
+        console.execute();
+        assert_eq!(cvars.borrow().get("cl_downloadurl").unwrap(), "http://evil/");
+    }
+
+    #[test]
+    fn test_cvar_callback() {
+        let cvars = Rc::new(CvarRegistry::new());
+        cvars.register("fov", "90").unwrap();
+        cvars.register("r_dirty", "0").unwrap();
+        assert!(cvars.set_callback("nonexistent", Box::new(|_| ())).is_err());
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let cb_seen = seen.clone();
+        let cb_cvars = cvars.clone();
+        cvars
+            .set_callback(
+                "fov",
+                Box::new(move |value| {
+                    // the new value is already visible, and other cvars can be set
+                    assert_eq!(cb_cvars.get("fov").unwrap(), value);
+                    cb_cvars.set("r_dirty", "1").unwrap();
+                    cb_seen.borrow_mut().push(value.to_owned());
+                }),
+            )
+            .unwrap();
+
+        cvars.set("fov", "100").unwrap();
+        cvars.set("fov", "110").unwrap();
+        cvars.set("fov", "110").unwrap();
+        assert_eq!(*seen.borrow(), vec!["100", "110", "110"]);
+        assert_eq!(cvars.get("r_dirty").unwrap(), "1");
+
+        // a new callback replaces the old one
+        cvars.set_callback("fov", Box::new(|_| ())).unwrap();
+        cvars.set("fov", "90").unwrap();
+        assert_eq!(seen.borrow().len(), 3);
+    }
+
+    #[test]
+    fn test_cvar_kind_clamps() {
+        let cvars = CvarRegistry::new();
+        cvars
+            .register_typed("fov", "90", CvarKind::Float { min: 10.0, max: 170.0 })
+            .unwrap();
+        cvars
+            .register_typed("cl_maxfps", "72", CvarKind::Int { min: 1, max: 1000 })
+            .unwrap();
+        cvars.register_typed("cl_predict", "0", CvarKind::Bool).unwrap();
+
+        cvars.set("fov", "110.5").unwrap();
+        assert_eq!(cvars.get_value("fov").unwrap(), 110.5);
+        cvars.set("fov", "500").unwrap();
+        assert_eq!(cvars.get_value("fov").unwrap(), 170.0);
+        cvars.set("fov", "-1").unwrap();
+        assert_eq!(cvars.get_value("fov").unwrap(), 10.0);
+
+        cvars.set("cl_maxfps", "0").unwrap();
+        assert_eq!(cvars.get("cl_maxfps").unwrap(), "1");
+        cvars.set("cl_predict", "2").unwrap();
+        assert_eq!(cvars.get("cl_predict").unwrap(), "1");
+
+        // defaults must be valid themselves
+        assert!(cvars
+            .register_typed("r_bad", "9", CvarKind::Int { min: 0, max: 1 })
+            .is_err());
+    }
+
+    #[test]
+    fn test_cvar_kind_rejects() {
+        let cvars = CvarRegistry::new();
+        cvars
+            .register_typed("fov", "90", CvarKind::Float { min: 10.0, max: 170.0 })
+            .unwrap();
+        cvars
+            .register_typed("cl_maxfps", "72", CvarKind::Int { min: 1, max: 1000 })
+            .unwrap();
+        cvars.register("name", "player").unwrap();
+
+        // bad values restore the default instead of being stored
+        cvars.set("fov", "100").unwrap();
+        assert!(cvars.set("fov", "wide").is_err());
+        assert_eq!(cvars.get("fov").unwrap(), "90");
+        cvars.set("fov", "100").unwrap();
+        assert!(cvars.set("fov", "nan").is_err());
+        assert_eq!(cvars.get("fov").unwrap(), "90");
+        assert!(cvars.set("cl_maxfps", "60.5").is_err());
+        assert_eq!(cvars.get("cl_maxfps").unwrap(), "72");
+
+        // untyped cvars take anything
+        cvars.set("name", "wide").unwrap();
+        assert_eq!(cvars.get("name").unwrap(), "wide");
+    }
+
+    #[test]
+    fn test_write_archived() {
+        let cvars = CvarRegistry::new();
+        cvars.register_archive("sensitivity", "3").unwrap();
+        cvars.register_archive("_cl_name", "player").unwrap();
+        cvars.register("developer", "0").unwrap();
+        cvars.set("_cl_name", "big \"bad\" ranger").unwrap();
+
+        let mut cfg = Vec::new();
+        cvars.write_archived(&mut cfg).unwrap();
+        let cfg = String::from_utf8(cfg).unwrap();
+        assert_eq!(cfg, "seta _cl_name \"big bad ranger\"\nseta sensitivity \"3\"\n");
+
+        // the quoted value is read back as a single argument
+        let (commands, _) = parse::commands().easy_parse(cfg.as_str()).unwrap();
+        assert_eq!(commands[0], vec!["seta", "_cl_name", "big bad ranger"]);
+
+        // seta registers unknown cvars as archived so they're written out again
+        let cvars = CvarRegistry::new();
+        cvars.register("developer", "0").unwrap();
+        cvars.set_archived("developer", "1").unwrap();
+        cvars.set_archived("sv_custom", "2").unwrap();
+        let mut cfg = Vec::new();
+        cvars.write_archived(&mut cfg).unwrap();
+        assert_eq!(
+            String::from_utf8(cfg).unwrap(),
+            "seta developer \"1\"\nseta sv_custom \"2\"\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_color_codes() {
+        let (chars, runs) = parse_color_codes("a^1bc^8d^7");
+        assert_eq!(String::from_iter(chars), "abcd");
+        assert_eq!(
+            runs,
+            vec![
+                (0, ConsoleColor::White),
+                (1, ConsoleColor::Red),
+                (3, ConsoleColor::Gray),
+                (4, ConsoleColor::White),
+            ]
+        );
+
+        // leading code replaces the default color
+        let (_, runs) = parse_color_codes("^1warning");
+        assert_eq!(runs, vec![(0, ConsoleColor::Red)]);
+    }
+
+    #[test]
+    fn test_strip_color_codes() {
+        assert_eq!(strip_color_codes("^1red^7 white"), "red white");
+        assert_eq!(strip_color_codes("2^4 = 16"), "2^4 = 16");
+        assert_eq!(strip_color_codes("x^"), "x^");
+    }
+
+    #[test]
+    fn test_console_line_runs() {
+        let mut output = ConsoleOutput::new();
+        output.push("ok ^1bad".chars().collect());
+        let line = output.colored_lines().next().unwrap();
+        let runs: Vec<(ConsoleColor, String)> = line
+            .runs()
+            .into_iter()
+            .map(|(color, chars)| (color, String::from_iter(chars.iter())))
+            .collect();
+        assert_eq!(
+            runs,
+            vec![
+                (ConsoleColor::White, "ok ".to_owned()),
+                (ConsoleColor::Red, "bad".to_owned()),
+            ]
+        );
+        assert_eq!(String::from_iter(output.lines().next().unwrap().iter()), "ok bad");
+    }
 }
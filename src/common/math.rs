@@ -183,6 +183,18 @@ impl Hyperplane {
         }
     }
 
+    /// Returns the unit normal of this hyperplane.
+    pub fn get_normal(&self) -> Vector3<f32> {
+        match self.alignment {
+            Alignment::Axis(a) => {
+                let mut n = Vector3::zero();
+                n[a as usize] = 1.0;
+                n
+            }
+            Alignment::Normal(n) => n,
+        }
+    }
+
     /// Calculates the shortest distance between this hyperplane and the given point.
     pub fn point_dist(&self, point: Vector3<f32>) -> f32 {
         match self.alignment {
@@ -0,0 +1,509 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! MD3 model replacements.
+//!
+//! High-poly replacements for MDL models are commonly shipped as MD3 files next to the original
+//! (`progs/player.md3` for `progs/player.mdl`). An MD3 replaces the geometry and, where it can,
+//! the skins: its frames become static keyframes of the MDL's frame numbers, so it must have as
+//! many frames as the MDL has keyframes.
+//!
+//! Skin `n` of each surface is named in `<model>.md3_<n>.skin` files as in DarkPlaces, or by the
+//! surface's first shader if there are none. Skin images are loaded as TGA whatever extension
+//! they're given. Surfaces without a loadable skin are drawn with the MDL's skins. Skeletal
+//! formats like IQM aren't supported.
+
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+use common::mdl::{
+    AliasModel, AliasSurface, IndexedPolygon, Keyframe, StaticKeyframe, Texcoords, TrueColorSkin,
+};
+use common::tga;
+use common::vfs::Vfs;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use cgmath::Vector3;
+use failure::Error;
+
+pub const MAGIC: i32 =
+    ('I' as i32) << 0 | ('D' as i32) << 8 | ('P' as i32) << 16 | ('3' as i32) << 24;
+pub const VERSION: i32 = 15;
+
+// MD3 vertex coordinates are fixed-point with 6 fractional bits
+const XYZ_SCALE: f32 = 1.0 / 64.0;
+
+const NAME_SIZE: usize = 64;
+const FRAME_NAME_SIZE: usize = 16;
+
+fn read_name<R>(reader: &mut R, len: usize) -> Result<String, Error>
+where
+    R: Read,
+{
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes)?;
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(len);
+    Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+}
+
+fn read_vector<R>(reader: &mut R) -> Result<Vector3<f32>, Error>
+where
+    R: Read,
+{
+    Ok(Vector3::new(
+        reader.read_f32::<LittleEndian>()?,
+        reader.read_f32::<LittleEndian>()?,
+        reader.read_f32::<LittleEndian>()?,
+    ))
+}
+
+fn read_count<R>(reader: &mut R, what: &str) -> Result<usize, Error>
+where
+    R: Read,
+{
+    let count = reader.read_i32::<LittleEndian>()?;
+    ensure!(count >= 0, "Negative MD3 {} count ({})", what, count);
+    Ok(count as usize)
+}
+
+fn read_offset<R>(reader: &mut R) -> Result<u64, Error>
+where
+    R: Read,
+{
+    let ofs = reader.read_i32::<LittleEndian>()?;
+    ensure!(ofs >= 0, "Negative MD3 offset ({})", ofs);
+    Ok(ofs as u64)
+}
+
+// decodes an MD3 normal, which packs its latitude in the high byte and its longitude in the low
+// byte, each in 255ths of a full turn
+fn normal(packed: i16) -> Vector3<f32> {
+    let lat = ((packed >> 8) & 0xff) as f32 * 2.0 * PI / 255.0;
+    let lng = (packed & 0xff) as f32 * 2.0 * PI / 255.0;
+    Vector3::new(lat.cos() * lng.sin(), lat.sin() * lng.sin(), lng.cos())
+}
+
+// an MD3 surface, as a run of polygons of the merged mesh
+struct Md3Surface {
+    name: String,
+    shaders: Vec<String>,
+    first_polygon: usize,
+    polygon_count: usize,
+}
+
+// the frames and merged surfaces of an MD3
+struct Md3Geometry {
+    texcoords: Vec<[f32; 2]>,
+    polygons: Vec<IndexedPolygon>,
+    surfaces: Vec<Md3Surface>,
+    keyframes: Vec<Keyframe>,
+}
+
+// parses a skin file, which maps each surface name to an image on its own line:
+// `surface,path/to/image.tga`. Surface names are case-insensitive.
+fn parse_skin_file(text: &str) -> HashMap<String, String> {
+    let mut skins = HashMap::new();
+    for line in text.lines() {
+        let mut fields = line.splitn(2, ',');
+        let surface = fields.next().unwrap_or("").trim();
+        let image = fields.next().unwrap_or("").trim();
+        if !surface.is_empty() && !image.is_empty() {
+            skins.insert(surface.to_lowercase(), image.to_owned());
+        }
+    }
+
+    skins
+}
+
+// loads the skin files of the MD3 at `path`, one per skin number, stopping at the first gap
+fn load_skin_files(vfs: &Vfs, path: &str) -> Vec<HashMap<String, String>> {
+    let mut skin_files = Vec::new();
+    loop {
+        let skin_path = format!("{}_{}.skin", path, skin_files.len());
+        let mut text = String::new();
+        match vfs.open(&skin_path) {
+            Ok(mut file) => {
+                if let Err(e) = file.read_to_string(&mut text) {
+                    warn!("Couldn't read skin file {}: {}", skin_path, e);
+                    break;
+                }
+            }
+            Err(_) => break,
+        }
+
+        skin_files.push(parse_skin_file(&text));
+    }
+
+    skin_files
+}
+
+// loads a skin image as TGA, whatever extension it's named with
+fn load_skin(vfs: &Vfs, name: &str) -> Option<TrueColorSkin> {
+    let stem = match name.rfind('.') {
+        Some(dot) if !name[dot..].contains('/') => &name[..dot],
+        _ => name,
+    };
+    let path = format!("{}.tga", stem);
+
+    let image = match vfs.open(&path).and_then(tga::load) {
+        Ok(i) => i,
+        Err(e) => {
+            warn!("Couldn't load MD3 skin {}: {}", path, e);
+            return None;
+        }
+    };
+
+    let width = image.width();
+    let height = image.height();
+    Some(TrueColorSkin::new(width, height, image.into_rgba()))
+}
+
+/// Loads the MD3 file at `path` as a replacement for `base`.
+pub fn load(vfs: &Vfs, path: &str, base: &AliasModel) -> Result<AliasModel, Error> {
+    let geometry = load_geometry(vfs.open(path)?)?;
+    ensure!(
+        geometry.keyframes.len() == base.keyframes().len(),
+        "MD3 has {} frames, but the MDL has {}",
+        geometry.keyframes.len(),
+        base.keyframes().len()
+    );
+
+    let skin_files = load_skin_files(vfs, path);
+
+    let mut surfaces = Vec::with_capacity(geometry.surfaces.len());
+    for surface in geometry.surfaces.iter() {
+        let image_names: Vec<Option<String>> = if skin_files.is_empty() {
+            vec![surface.shaders.first().cloned()]
+        } else {
+            let name = surface.name.to_lowercase();
+            skin_files.iter().map(|f| f.get(&name).cloned()).collect()
+        };
+
+        let skins = image_names
+            .into_iter()
+            .map(|name| name.and_then(|n| load_skin(vfs, &n)))
+            .collect();
+        surfaces.push(AliasSurface::new(
+            surface.first_polygon,
+            surface.polygon_count,
+            skins,
+        ));
+    }
+
+    Ok(base.with_geometry(
+        Texcoords::Normalized(geometry.texcoords.into_boxed_slice()),
+        geometry.polygons,
+        surfaces,
+        geometry.keyframes,
+    ))
+}
+
+// reads the frames and merged surfaces of an MD3
+fn load_geometry<R>(data: R) -> Result<Md3Geometry, Error>
+where
+    R: Read + Seek,
+{
+    let mut reader = BufReader::new(data);
+
+    let magic = reader.read_i32::<LittleEndian>()?;
+    ensure!(
+        magic == MAGIC,
+        "Bad MD3 magic number (got {}, should be {})",
+        magic,
+        MAGIC
+    );
+
+    let version = reader.read_i32::<LittleEndian>()?;
+    ensure!(
+        version == VERSION,
+        "Bad MD3 version (got {}, should be {})",
+        version,
+        VERSION
+    );
+
+    let _name = read_name(&mut reader, NAME_SIZE)?;
+    let _flags = reader.read_i32::<LittleEndian>()?;
+    let frame_count = read_count(&mut reader, "frame")?;
+    let _tag_count = read_count(&mut reader, "tag")?;
+    let surface_count = read_count(&mut reader, "surface")?;
+    let _skin_count = read_count(&mut reader, "skin")?;
+    let frames_ofs = read_offset(&mut reader)?;
+    let _tags_ofs = read_offset(&mut reader)?;
+    let surfaces_ofs = read_offset(&mut reader)?;
+
+    ensure!(frame_count > 0, "MD3 has no frames");
+    ensure!(surface_count > 0, "MD3 has no surfaces");
+
+    reader.seek(SeekFrom::Start(frames_ofs))?;
+    let mut frames = Vec::with_capacity(frame_count);
+    for _ in 0..frame_count {
+        let min = read_vector(&mut reader)?;
+        let max = read_vector(&mut reader)?;
+        let _local_origin = read_vector(&mut reader)?;
+        let _radius = reader.read_f32::<LittleEndian>()?;
+        let name = read_name(&mut reader, FRAME_NAME_SIZE)?;
+        frames.push((name, min, max, Vec::new(), Vec::new()));
+    }
+
+    let mut texcoords = Vec::new();
+    let mut polygons = Vec::new();
+    let mut surfaces = Vec::with_capacity(surface_count);
+
+    // surfaces are merged into one mesh, so each surface's indices follow the vertices before it
+    let mut surface_ofs = surfaces_ofs;
+    for _ in 0..surface_count {
+        reader.seek(SeekFrom::Start(surface_ofs))?;
+
+        let magic = reader.read_i32::<LittleEndian>()?;
+        ensure!(magic == MAGIC, "Bad MD3 surface magic number ({})", magic);
+
+        let name = read_name(&mut reader, NAME_SIZE)?;
+        let _flags = reader.read_i32::<LittleEndian>()?;
+        let surface_frame_count = read_count(&mut reader, "surface frame")?;
+        let shader_count = read_count(&mut reader, "shader")?;
+        let vertex_count = read_count(&mut reader, "vertex")?;
+        let triangle_count = read_count(&mut reader, "triangle")?;
+        let triangles_ofs = read_offset(&mut reader)?;
+        let shaders_ofs = read_offset(&mut reader)?;
+        let st_ofs = read_offset(&mut reader)?;
+        let xyz_ofs = read_offset(&mut reader)?;
+        let end_ofs = read_offset(&mut reader)?;
+
+        ensure!(
+            surface_frame_count == frame_count,
+            "MD3 surface has {} frames, but the model has {}",
+            surface_frame_count,
+            frame_count
+        );
+
+        let first_vertex = texcoords.len() as u32;
+        let first_polygon = polygons.len();
+
+        reader.seek(SeekFrom::Start(surface_ofs + triangles_ofs))?;
+        for _ in 0..triangle_count {
+            let mut indices = [0; 3];
+            for i in 0..3 {
+                let index = reader.read_i32::<LittleEndian>()?;
+                ensure!(
+                    index >= 0 && (index as usize) < vertex_count,
+                    "MD3 vertex index out of range ({})",
+                    index
+                );
+                indices[i] = first_vertex + index as u32;
+            }
+
+            polygons.push(IndexedPolygon::new(true, indices));
+        }
+
+        reader.seek(SeekFrom::Start(surface_ofs + shaders_ofs))?;
+        let mut shaders = Vec::with_capacity(shader_count);
+        for _ in 0..shader_count {
+            shaders.push(read_name(&mut reader, NAME_SIZE)?);
+            let _shader_index = reader.read_i32::<LittleEndian>()?;
+        }
+
+        surfaces.push(Md3Surface {
+            name,
+            shaders,
+            first_polygon,
+            polygon_count: triangle_count,
+        });
+
+        reader.seek(SeekFrom::Start(surface_ofs + st_ofs))?;
+        for _ in 0..vertex_count {
+            let s = reader.read_f32::<LittleEndian>()?;
+            let t = reader.read_f32::<LittleEndian>()?;
+            texcoords.push([s, t]);
+        }
+
+        reader.seek(SeekFrom::Start(surface_ofs + xyz_ofs))?;
+        for frame in frames.iter_mut() {
+            for _ in 0..vertex_count {
+                let x = reader.read_i16::<LittleEndian>()?;
+                let y = reader.read_i16::<LittleEndian>()?;
+                let z = reader.read_i16::<LittleEndian>()?;
+                let normal_bits = reader.read_i16::<LittleEndian>()?;
+                frame.3.push(Vector3::new(x as f32, y as f32, z as f32) * XYZ_SCALE);
+                frame.4.push(normal(normal_bits));
+            }
+        }
+
+        surface_ofs += end_ofs;
+    }
+
+    let keyframes = frames
+        .into_iter()
+        .map(|(name, min, max, vertices, normals)| {
+            Keyframe::Static(StaticKeyframe::new(name, min, max, vertices, normals))
+        })
+        .collect();
+
+    Ok(Md3Geometry {
+        texcoords,
+        polygons,
+        surfaces,
+        keyframes,
+    })
+}
+
+/// Returns the path of the MD3 replacement for the MDL model `name`, if it is one.
+pub fn replacement_path<S>(name: S) -> Option<String>
+where
+    S: AsRef<str>,
+{
+    let name = name.as_ref();
+    if name.ends_with(".mdl") {
+        Some(format!("{}.md3", &name[..name.len() - 4]))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use byteorder::WriteBytesExt;
+    use cgmath::InnerSpace;
+
+    fn write_name(data: &mut Vec<u8>, name: &str, len: usize) {
+        let mut bytes = name.as_bytes().to_vec();
+        bytes.resize(len, 0);
+        data.extend_from_slice(&bytes);
+    }
+
+    // a single triangle with two frames, the second moved up by 1.5 units
+    fn md3_triangle() -> Vec<u8> {
+        let mut data = Vec::new();
+        data.write_i32::<LittleEndian>(MAGIC).unwrap();
+        data.write_i32::<LittleEndian>(VERSION).unwrap();
+        write_name(&mut data, "tri", NAME_SIZE);
+        for &v in [0, 2, 0, 1, 0, 108, 0, 220].iter() {
+            // flags, frames, tags, surfaces, skins, frames offset, tags offset, surfaces offset
+            data.write_i32::<LittleEndian>(v).unwrap();
+        }
+        data.write_i32::<LittleEndian>(0).unwrap(); // end offset, unused
+        assert_eq!(data.len(), 108);
+
+        for &(name, z) in [("stand1", 0.0), ("stand2", 1.5)].iter() {
+            for &v in [0.0, 0.0, z, 32.0, 16.0, z, 0.0, 0.0, 0.0, 32.0].iter() {
+                data.write_f32::<LittleEndian>(v).unwrap();
+            }
+            write_name(&mut data, name, FRAME_NAME_SIZE);
+        }
+
+        let surface_ofs = data.len() as i32;
+        assert_eq!(surface_ofs, 108 + 2 * 56);
+        data.write_i32::<LittleEndian>(MAGIC).unwrap();
+        write_name(&mut data, "body", NAME_SIZE);
+        // flags, frames, shaders, verts, triangles, then offsets of triangles, shaders, st, xyz
+        // and the end of the surface
+        for &v in [0, 2, 1, 3, 1, 108, 120, 188, 212, 260].iter() {
+            data.write_i32::<LittleEndian>(v).unwrap();
+        }
+
+        for &i in [0, 1, 2].iter() {
+            data.write_i32::<LittleEndian>(i).unwrap();
+        }
+
+        write_name(&mut data, "models/tri.tga", NAME_SIZE);
+        data.write_i32::<LittleEndian>(0).unwrap();
+
+        for &st in [0.0, 0.0, 0.5, 0.0, 1.25, -0.25].iter() {
+            data.write_f32::<LittleEndian>(st).unwrap();
+        }
+
+        for &z in [0, 96].iter() {
+            for &(x, y) in [(0, 0), (2048, 0), (0, 1024)].iter() {
+                data.write_i16::<LittleEndian>(x).unwrap();
+                data.write_i16::<LittleEndian>(y).unwrap();
+                data.write_i16::<LittleEndian>(z).unwrap();
+                data.write_i16::<LittleEndian>(0).unwrap();
+            }
+        }
+
+        data
+    }
+
+    #[test]
+    fn test_replacement_path() {
+        assert_eq!(
+            replacement_path("progs/player.mdl"),
+            Some(String::from("progs/player.md3"))
+        );
+        assert_eq!(replacement_path("progs/s_light.spr"), None);
+    }
+
+    #[test]
+    fn test_parse_skin_file() {
+        let text = "Body,models/body.tga\ntag_head,\n\n h_head , models/head.tga\n";
+        let skins = parse_skin_file(text);
+        assert_eq!(skins.len(), 2);
+        assert_eq!(skins["body"], "models/body.tga");
+        assert_eq!(skins["h_head"], "models/head.tga");
+    }
+
+    #[test]
+    fn test_normal() {
+        let up = normal(0);
+        assert!((up - Vector3::new(0.0, 0.0, 1.0)).magnitude() < 1e-6);
+
+        // a quarter turn of longitude at zero latitude points along +x
+        let x = normal(64);
+        assert!((x - Vector3::new(1.0, 0.0, 0.0)).magnitude() < 0.02);
+    }
+
+    #[test]
+    fn test_load_geometry() {
+        let geometry = load_geometry(Cursor::new(md3_triangle())).unwrap();
+
+        // texcoords outside [0, 1] are kept for the sampler to tile
+        assert_eq!(geometry.texcoords, vec![[0.0, 0.0], [0.5, 0.0], [1.25, -0.25]]);
+
+        assert_eq!(geometry.polygons.len(), 1);
+        assert_eq!(geometry.polygons[0].indices(), &[0, 1, 2]);
+
+        assert_eq!(geometry.surfaces.len(), 1);
+        assert_eq!(geometry.surfaces[0].name, "body");
+        assert_eq!(geometry.surfaces[0].shaders, vec![String::from("models/tri.tga")]);
+        assert_eq!(geometry.surfaces[0].first_polygon, 0);
+        assert_eq!(geometry.surfaces[0].polygon_count, 1);
+
+        assert_eq!(geometry.keyframes.len(), 2);
+        match geometry.keyframes[1] {
+            Keyframe::Static(ref k) => {
+                assert_eq!(k.name(), "stand2");
+                assert_eq!(k.max(), Vector3::new(32.0, 16.0, 1.5));
+                assert_eq!(
+                    k.vertices(),
+                    &[
+                        Vector3::new(0.0, 0.0, 1.5),
+                        Vector3::new(32.0, 0.0, 1.5),
+                        Vector3::new(0.0, 16.0, 1.5),
+                    ]
+                );
+            }
+            _ => panic!("MD3 frames should be static keyframes"),
+        }
+    }
+}
@@ -33,6 +33,188 @@ pub const VERSION: i32 = 6;
 
 const HEADER_SIZE: u64 = 84;
 
+/// The number of precalculated vertex normals MDL vertices can refer to.
+pub const VERTEX_NORMAL_COUNT: usize = 162;
+
+/// The precalculated vertex normals of the MDL format, indexed by each vertex's normal index.
+///
+/// These are the points of a subdivided icosahedron, in the order of `anorms.h` in the original
+/// engine (`r_avertexnormals`).
+pub static VERTEX_NORMALS: [[f32; 3]; VERTEX_NORMAL_COUNT] = [
+    [-0.525731, 0.000000, 0.850651],
+    [-0.442863, 0.238856, 0.864188],
+    [-0.295242, 0.000000, 0.955423],
+    [-0.309017, 0.500000, 0.809017],
+    [-0.162460, 0.262866, 0.951056],
+    [0.000000, 0.000000, 1.000000],
+    [0.000000, 0.850651, 0.525731],
+    [-0.147621, 0.716567, 0.681718],
+    [0.147621, 0.716567, 0.681718],
+    [0.000000, 0.525731, 0.850651],
+    [0.309017, 0.500000, 0.809017],
+    [0.525731, 0.000000, 0.850651],
+    [0.295242, 0.000000, 0.955423],
+    [0.442863, 0.238856, 0.864188],
+    [0.162460, 0.262866, 0.951056],
+    [-0.681718, 0.147621, 0.716567],
+    [-0.809017, 0.309017, 0.500000],
+    [-0.587785, 0.425325, 0.688191],
+    [-0.850651, 0.525731, 0.000000],
+    [-0.864188, 0.442863, 0.238856],
+    [-0.716567, 0.681718, 0.147621],
+    [-0.688191, 0.587785, 0.425325],
+    [-0.500000, 0.809017, 0.309017],
+    [-0.238856, 0.864188, 0.442863],
+    [-0.425325, 0.688191, 0.587785],
+    [-0.716567, 0.681718, -0.147621],
+    [-0.500000, 0.809017, -0.309017],
+    [-0.525731, 0.850651, 0.000000],
+    [0.000000, 0.850651, -0.525731],
+    [-0.238856, 0.864188, -0.442863],
+    [0.000000, 0.955423, -0.295242],
+    [-0.262866, 0.951056, -0.162460],
+    [0.000000, 1.000000, 0.000000],
+    [0.000000, 0.955423, 0.295242],
+    [-0.262866, 0.951056, 0.162460],
+    [0.238856, 0.864188, 0.442863],
+    [0.262866, 0.951056, 0.162460],
+    [0.500000, 0.809017, 0.309017],
+    [0.238856, 0.864188, -0.442863],
+    [0.262866, 0.951056, -0.162460],
+    [0.500000, 0.809017, -0.309017],
+    [0.850651, 0.525731, 0.000000],
+    [0.716567, 0.681718, 0.147621],
+    [0.716567, 0.681718, -0.147621],
+    [0.525731, 0.850651, 0.000000],
+    [0.425325, 0.688191, 0.587785],
+    [0.864188, 0.442863, 0.238856],
+    [0.688191, 0.587785, 0.425325],
+    [0.809017, 0.309017, 0.500000],
+    [0.681718, 0.147621, 0.716567],
+    [0.587785, 0.425325, 0.688191],
+    [0.955423, 0.295242, 0.000000],
+    [1.000000, 0.000000, 0.000000],
+    [0.951056, 0.162460, 0.262866],
+    [0.850651, -0.525731, 0.000000],
+    [0.955423, -0.295242, 0.000000],
+    [0.864188, -0.442863, 0.238856],
+    [0.951056, -0.162460, 0.262866],
+    [0.809017, -0.309017, 0.500000],
+    [0.681718, -0.147621, 0.716567],
+    [0.850651, 0.000000, 0.525731],
+    [0.864188, 0.442863, -0.238856],
+    [0.809017, 0.309017, -0.500000],
+    [0.951056, 0.162460, -0.262866],
+    [0.525731, 0.000000, -0.850651],
+    [0.681718, 0.147621, -0.716567],
+    [0.681718, -0.147621, -0.716567],
+    [0.850651, 0.000000, -0.525731],
+    [0.809017, -0.309017, -0.500000],
+    [0.864188, -0.442863, -0.238856],
+    [0.951056, -0.162460, -0.262866],
+    [0.147621, 0.716567, -0.681718],
+    [0.309017, 0.500000, -0.809017],
+    [0.425325, 0.688191, -0.587785],
+    [0.442863, 0.238856, -0.864188],
+    [0.587785, 0.425325, -0.688191],
+    [0.688191, 0.587785, -0.425325],
+    [-0.147621, 0.716567, -0.681718],
+    [-0.309017, 0.500000, -0.809017],
+    [0.000000, 0.525731, -0.850651],
+    [-0.525731, 0.000000, -0.850651],
+    [-0.442863, 0.238856, -0.864188],
+    [-0.295242, 0.000000, -0.955423],
+    [-0.162460, 0.262866, -0.951056],
+    [0.000000, 0.000000, -1.000000],
+    [0.295242, 0.000000, -0.955423],
+    [0.162460, 0.262866, -0.951056],
+    [-0.442863, -0.238856, -0.864188],
+    [-0.309017, -0.500000, -0.809017],
+    [-0.162460, -0.262866, -0.951056],
+    [0.000000, -0.850651, -0.525731],
+    [-0.147621, -0.716567, -0.681718],
+    [0.147621, -0.716567, -0.681718],
+    [0.000000, -0.525731, -0.850651],
+    [0.309017, -0.500000, -0.809017],
+    [0.442863, -0.238856, -0.864188],
+    [0.162460, -0.262866, -0.951056],
+    [0.238856, -0.864188, -0.442863],
+    [0.500000, -0.809017, -0.309017],
+    [0.425325, -0.688191, -0.587785],
+    [0.716567, -0.681718, -0.147621],
+    [0.688191, -0.587785, -0.425325],
+    [0.587785, -0.425325, -0.688191],
+    [0.000000, -0.955423, -0.295242],
+    [0.000000, -1.000000, 0.000000],
+    [0.262866, -0.951056, -0.162460],
+    [0.000000, -0.850651, 0.525731],
+    [0.000000, -0.955423, 0.295242],
+    [0.238856, -0.864188, 0.442863],
+    [0.262866, -0.951056, 0.162460],
+    [0.500000, -0.809017, 0.309017],
+    [0.716567, -0.681718, 0.147621],
+    [0.525731, -0.850651, 0.000000],
+    [-0.238856, -0.864188, -0.442863],
+    [-0.500000, -0.809017, -0.309017],
+    [-0.262866, -0.951056, -0.162460],
+    [-0.850651, -0.525731, 0.000000],
+    [-0.716567, -0.681718, -0.147621],
+    [-0.716567, -0.681718, 0.147621],
+    [-0.525731, -0.850651, 0.000000],
+    [-0.500000, -0.809017, 0.309017],
+    [-0.238856, -0.864188, 0.442863],
+    [-0.262866, -0.951056, 0.162460],
+    [-0.864188, -0.442863, 0.238856],
+    [-0.809017, -0.309017, 0.500000],
+    [-0.688191, -0.587785, 0.425325],
+    [-0.681718, -0.147621, 0.716567],
+    [-0.442863, -0.238856, 0.864188],
+    [-0.587785, -0.425325, 0.688191],
+    [-0.309017, -0.500000, 0.809017],
+    [-0.147621, -0.716567, 0.681718],
+    [-0.425325, -0.688191, 0.587785],
+    [-0.162460, -0.262866, 0.951056],
+    [0.442863, -0.238856, 0.864188],
+    [0.162460, -0.262866, 0.951056],
+    [0.309017, -0.500000, 0.809017],
+    [0.147621, -0.716567, 0.681718],
+    [0.000000, -0.525731, 0.850651],
+    [0.425325, -0.688191, 0.587785],
+    [0.587785, -0.425325, 0.688191],
+    [0.688191, -0.587785, 0.425325],
+    [-0.955423, 0.295242, 0.000000],
+    [-0.951056, 0.162460, 0.262866],
+    [-1.000000, 0.000000, 0.000000],
+    [-0.850651, 0.000000, 0.525731],
+    [-0.955423, -0.295242, 0.000000],
+    [-0.951056, -0.162460, 0.262866],
+    [-0.864188, 0.442863, -0.238856],
+    [-0.951056, 0.162460, -0.262866],
+    [-0.809017, 0.309017, -0.500000],
+    [-0.864188, -0.442863, -0.238856],
+    [-0.951056, -0.162460, -0.262866],
+    [-0.809017, -0.309017, -0.500000],
+    [-0.681718, 0.147621, -0.716567],
+    [-0.681718, -0.147621, -0.716567],
+    [-0.850651, 0.000000, -0.525731],
+    [-0.688191, 0.587785, -0.425325],
+    [-0.587785, 0.425325, -0.688191],
+    [-0.425325, 0.688191, -0.587785],
+    [-0.425325, -0.688191, -0.587785],
+    [-0.587785, -0.425325, -0.688191],
+    [-0.688191, -0.587785, -0.425325],
+];
+
+/// Returns the normal with the given index into `VERTEX_NORMALS`.
+///
+/// Out-of-range indices give a zero vector, which leaves the vertex unshaded.
+pub fn vertex_normal(index: u8) -> Vector3<f32> {
+    match VERTEX_NORMALS.get(index as usize) {
+        Some(n) => Vector3::new(n[0], n[1], n[2]),
+        None => Vector3::new(0.0, 0.0, 0.0),
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct StaticTexture {
     indices: Box<[u8]>,
@@ -88,6 +270,10 @@ pub struct Texcoord {
 }
 
 impl Texcoord {
+    pub fn new(is_on_seam: bool, s: u32, t: u32) -> Texcoord {
+        Texcoord { is_on_seam, s, t }
+    }
+
     pub fn is_on_seam(&self) -> bool {
         self.is_on_seam
     }
@@ -101,6 +287,85 @@ impl Texcoord {
     }
 }
 
+/// The texcoords of an alias model's vertices.
+#[derive(Clone, Debug)]
+pub enum Texcoords {
+    /// Texel coordinates on the model's skins, as stored in MDL files.
+    Texels(Box<[Texcoord]>),
+
+    /// Normalized coordinates, which may lie outside `[0, 1]` to tile, as stored in MD3 files.
+    Normalized(Box<[[f32; 2]]>),
+}
+
+/// A 32-bit color skin, used by model replacements in place of the indexed MDL skins.
+#[derive(Clone, Debug)]
+pub struct TrueColorSkin {
+    width: u32,
+    height: u32,
+    rgba: Box<[u8]>,
+}
+
+impl TrueColorSkin {
+    pub fn new(width: u32, height: u32, rgba: Vec<u8>) -> TrueColorSkin {
+        TrueColorSkin {
+            width,
+            height,
+            rgba: rgba.into_boxed_slice(),
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Returns the pixels of this skin in RGBA order, from the top row down.
+    pub fn rgba(&self) -> &[u8] {
+        &self.rgba
+    }
+}
+
+/// A run of an alias model's polygons that are drawn with the same skins.
+#[derive(Clone, Debug)]
+pub struct AliasSurface {
+    first_polygon: usize,
+    polygon_count: usize,
+    skins: Box<[Option<TrueColorSkin>]>,
+}
+
+impl AliasSurface {
+    pub fn new(
+        first_polygon: usize,
+        polygon_count: usize,
+        skins: Vec<Option<TrueColorSkin>>,
+    ) -> AliasSurface {
+        AliasSurface {
+            first_polygon,
+            polygon_count,
+            skins: skins.into_boxed_slice(),
+        }
+    }
+
+    pub fn first_polygon(&self) -> usize {
+        self.first_polygon
+    }
+
+    pub fn polygon_count(&self) -> usize {
+        self.polygon_count
+    }
+
+    /// Returns this surface's own skin for each skin number.
+    ///
+    /// Skin numbers without their own skin, and all skins of MDL models, use the model's indexed
+    /// skins.
+    pub fn skins(&self) -> &[Option<TrueColorSkin>] {
+        &self.skins
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct IndexedPolygon {
     faces_front: bool,
@@ -108,6 +373,13 @@ pub struct IndexedPolygon {
 }
 
 impl IndexedPolygon {
+    pub fn new(faces_front: bool, indices: [u32; 3]) -> IndexedPolygon {
+        IndexedPolygon {
+            faces_front,
+            indices,
+        }
+    }
+
     pub fn faces_front(&self) -> bool {
         self.faces_front
     }
@@ -123,9 +395,29 @@ pub struct StaticKeyframe {
     min: Vector3<f32>,
     max: Vector3<f32>,
     vertices: Box<[Vector3<f32>]>,
+    normals: Box<[Vector3<f32>]>,
 }
 
 impl StaticKeyframe {
+    pub fn new<S>(
+        name: S,
+        min: Vector3<f32>,
+        max: Vector3<f32>,
+        vertices: Vec<Vector3<f32>>,
+        normals: Vec<Vector3<f32>>,
+    ) -> StaticKeyframe
+    where
+        S: AsRef<str>,
+    {
+        StaticKeyframe {
+            name: name.as_ref().to_owned(),
+            min,
+            max,
+            vertices: vertices.into_boxed_slice(),
+            normals: normals.into_boxed_slice(),
+        }
+    }
+
     /// Returns the name of this keyframe.
     pub fn name(&self) -> &str {
         &self.name
@@ -145,6 +437,11 @@ impl StaticKeyframe {
     pub fn vertices(&self) -> &[Vector3<f32>] {
         &self.vertices
     }
+
+    /// Returns the normal of each vertex of this keyframe.
+    pub fn normals(&self) -> &[Vector3<f32>] {
+        &self.normals
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -154,6 +451,7 @@ pub struct AnimatedKeyframeFrame {
     max: Vector3<f32>,
     duration: Duration,
     vertices: Box<[Vector3<f32>]>,
+    normals: Box<[Vector3<f32>]>,
 }
 
 impl AnimatedKeyframeFrame {
@@ -181,6 +479,11 @@ impl AnimatedKeyframeFrame {
     pub fn vertices(&self) -> &[Vector3<f32>] {
         &self.vertices
     }
+
+    /// Returns the normal of each vertex of this subframe.
+    pub fn normals(&self) -> &[Vector3<f32>] {
+        &self.normals
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -213,6 +516,18 @@ pub enum Keyframe {
     Animated(AnimatedKeyframe),
 }
 
+impl Keyframe {
+    /// Returns the name of this keyframe.
+    ///
+    /// Animated keyframes are named after their first subframe.
+    pub fn name(&self) -> &str {
+        match *self {
+            Keyframe::Static(ref k) => k.name(),
+            Keyframe::Animated(ref k) => k.frames.first().map(|f| f.name()).unwrap_or(""),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct AliasModel {
     origin: Vector3<f32>,
@@ -220,8 +535,9 @@ pub struct AliasModel {
     texture_width: u32,
     texture_height: u32,
     textures: Box<[Texture]>,
-    texcoords: Box<[Texcoord]>,
+    texcoords: Texcoords,
     polygons: Box<[IndexedPolygon]>,
+    surfaces: Box<[AliasSurface]>,
     keyframes: Box<[Keyframe]>,
     flags: ModelFlags,
 }
@@ -247,14 +563,44 @@ impl AliasModel {
         &self.textures
     }
 
-    pub fn texcoords(&self) -> &[Texcoord] {
+    pub fn texcoords(&self) -> &Texcoords {
         &self.texcoords
     }
 
+    /// Returns the normalized texcoord of the vertex `index` as a corner of `polygon`.
+    ///
+    /// Texel coordinates are moved to the texel center, and seam vertices of back-facing polygons
+    /// are moved to the back half of the skin.
+    pub fn texcoord(&self, polygon: &IndexedPolygon, index: u32) -> [f32; 2] {
+        match self.texcoords {
+            Texcoords::Texels(ref texels) => {
+                let w = self.texture_width;
+                let h = self.texture_height;
+                let texcoord = &texels[index as usize];
+
+                let s = if !polygon.faces_front() && texcoord.is_on_seam() {
+                    (texcoord.s() + w / 2) as f32 + 0.5
+                } else {
+                    texcoord.s() as f32 + 0.5
+                } / w as f32;
+
+                let t = (texcoord.t() as f32 + 0.5) / h as f32;
+                [s, t]
+            }
+
+            Texcoords::Normalized(ref coords) => coords[index as usize],
+        }
+    }
+
     pub fn polygons(&self) -> &[IndexedPolygon] {
         &self.polygons
     }
 
+    /// Returns the surfaces of this model, which together cover all of its polygons in order.
+    pub fn surfaces(&self) -> &[AliasSurface] {
+        &self.surfaces
+    }
+
     pub fn keyframes(&self) -> &[Keyframe] {
         &self.keyframes
     }
@@ -262,6 +608,47 @@ impl AliasModel {
     pub fn flags(&self) -> ModelFlags {
         self.flags
     }
+
+    /// Returns a copy of this model with its indexed skins and flags but different geometry.
+    ///
+    /// Surfaces without skins of their own are drawn with this model's skins.
+    pub fn with_geometry(
+        &self,
+        texcoords: Texcoords,
+        polygons: Vec<IndexedPolygon>,
+        surfaces: Vec<AliasSurface>,
+        keyframes: Vec<Keyframe>,
+    ) -> AliasModel {
+        AliasModel {
+            origin: self.origin,
+            radius: self.radius,
+            texture_width: self.texture_width,
+            texture_height: self.texture_height,
+            textures: self.textures.clone(),
+            texcoords,
+            polygons: polygons.into_boxed_slice(),
+            surfaces: surfaces.into_boxed_slice(),
+            keyframes: keyframes.into_boxed_slice(),
+            flags: self.flags,
+        }
+    }
+}
+
+// reads the intervals of a skin or frame group. These are stored as the cumulative time at which
+// each frame ends, so they're converted to per-frame durations.
+fn read_durations<R>(reader: &mut R, count: usize) -> Result<Vec<Duration>, Error>
+where
+    R: Read,
+{
+    let mut durations = Vec::with_capacity(count);
+    let mut prev_interval = 0.0;
+    for _ in 0..count {
+        let interval = reader.read_f32::<LittleEndian>()?;
+        durations.push(engine::duration_from_f32(interval - prev_interval));
+        prev_interval = interval;
+    }
+
+    Ok(durations)
 }
 
 pub fn load<R>(data: R) -> Result<AliasModel, Error>
@@ -385,12 +772,7 @@ where
                 // TODO: sanity check this value
                 let texture_frame_count = reader.read_i32::<LittleEndian>()? as usize;
 
-                let mut durations = Vec::with_capacity(texture_frame_count);
-                for _ in 0..texture_frame_count {
-                    durations.push(engine::duration_from_f32(
-                        reader.read_f32::<LittleEndian>()?,
-                    ));
-                }
+                let durations = read_durations(&mut reader, texture_frame_count)?;
 
                 let mut frames = Vec::with_capacity(texture_frame_count);
                 for frame_id in 0..texture_frame_count {
@@ -502,13 +884,14 @@ where
                 debug!("Keyframe name: {}", name);
 
                 let mut vertices: Vec<Vector3<f32>> = Vec::with_capacity(vertex_count as usize);
+                let mut normals: Vec<Vector3<f32>> = Vec::with_capacity(vertex_count as usize);
                 for _ in 0..vertex_count {
                     vertices.push(Vector3::new(
                         reader.read_u8()? as f32 * scale[0] + origin[0],
                         reader.read_u8()? as f32 * scale[1] + origin[1],
                         reader.read_u8()? as f32 * scale[2] + origin[2],
                     ));
-                    reader.read_u8()?; // discard vertex normal
+                    normals.push(vertex_normal(reader.read_u8()?));
                 }
 
                 Keyframe::Static(StaticKeyframe {
@@ -516,6 +899,7 @@ where
                     min,
                     max,
                     vertices: vertices.into_boxed_slice(),
+                    normals: normals.into_boxed_slice(),
                 })
             }
 
@@ -541,12 +925,7 @@ where
 
                 reader.read_u8()?; // discard vertex normal
 
-                let mut durations = Vec::new();
-                for _ in 0..subframe_count {
-                    durations.push(engine::duration_from_f32(
-                        reader.read_f32::<LittleEndian>()?,
-                    ));
-                }
+                let durations = read_durations(&mut reader, subframe_count as usize)?;
 
                 let mut subframes = Vec::new();
                 for subframe_id in 0..subframe_count {
@@ -584,13 +963,14 @@ where
                     debug!("Frame name: {}", name);
 
                     let mut vertices: Vec<Vector3<f32>> = Vec::with_capacity(vertex_count as usize);
+                    let mut normals: Vec<Vector3<f32>> = Vec::with_capacity(vertex_count as usize);
                     for _ in 0..vertex_count {
                         vertices.push(Vector3::new(
                             reader.read_u8()? as f32 * scale[0] + origin[0],
                             reader.read_u8()? as f32 * scale[1] + origin[1],
                             reader.read_u8()? as f32 * scale[2] + origin[2],
                         ));
-                        reader.read_u8()?; // discard vertex normal
+                        normals.push(vertex_normal(reader.read_u8()?));
                     }
 
                     subframes.push(AnimatedKeyframeFrame {
@@ -599,6 +979,7 @@ where
                         name,
                         duration: durations[subframe_id as usize],
                         vertices: vertices.into_boxed_slice(),
+                        normals: normals.into_boxed_slice(),
                     })
                 }
 
@@ -623,9 +1004,31 @@ where
         texture_width: texture_width as u32,
         texture_height: texture_height as u32,
         textures: textures.into_boxed_slice(),
-        texcoords: texcoords.into_boxed_slice(),
+        texcoords: Texcoords::Texels(texcoords.into_boxed_slice()),
+        surfaces: vec![AliasSurface::new(0, polygons.len(), Vec::new())].into_boxed_slice(),
         polygons: polygons.into_boxed_slice(),
         keyframes: keyframes.into_boxed_slice(),
         flags,
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use byteorder::WriteBytesExt;
+
+    #[test]
+    fn test_read_durations() {
+        let mut data = Vec::new();
+        for &interval in [0.125, 0.375, 0.75].iter() {
+            data.write_f32::<LittleEndian>(interval).unwrap();
+        }
+
+        let durations = read_durations(&mut Cursor::new(data), 3).unwrap();
+        let ms: Vec<i64> = durations.iter().map(|d| d.num_milliseconds()).collect();
+        assert_eq!(ms, vec![125, 250, 375]);
+    }
+}
@@ -23,12 +23,16 @@ pub mod console;
 pub mod engine;
 pub mod host;
 pub mod math;
+pub mod md3;
 pub mod mdl;
 pub mod model;
 pub mod net;
 pub mod pak;
 pub mod parse;
+pub mod pk3;
+pub mod png;
 pub mod sprite;
+pub mod tga;
 pub mod util;
 pub mod vfs;
 pub mod wad;
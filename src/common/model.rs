@@ -16,6 +16,7 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 use common::bsp::BspModel;
+use common::md3;
 use common::mdl;
 use common::mdl::AliasModel;
 use common::sprite;
@@ -82,10 +83,22 @@ impl Model {
         if name.ends_with(".bsp") {
             panic!("BSP files may contain multiple models, use bsp::load for this");
         } else if name.ends_with(".mdl") {
-            Ok(Model::from_alias_model(
-                name.to_owned(),
-                mdl::load(vfs.open(name)?)?,
-            ))
+            let mut alias_model = mdl::load(vfs.open(name)?)?;
+
+            // an MD3 next to the model replaces it
+            if let Some(path) = md3::replacement_path(name) {
+                if vfs.open(&path).is_ok() {
+                    match md3::load(vfs, &path, &alias_model) {
+                        Ok(replacement) => {
+                            debug!("Replacing {} with {}", name, path);
+                            alias_model = replacement;
+                        }
+                        Err(e) => warn!("Couldn't load replacement model {}: {}", path, e),
+                    }
+                }
+            }
+
+            Ok(Model::from_alias_model(name.to_owned(), alias_model))
         } else if name.ends_with(".spr") {
             Ok(Model::from_sprite_model(
                 name.to_owned(),
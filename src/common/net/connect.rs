@@ -546,83 +546,116 @@ impl ConnectListener {
         Ok(ConnectListener { socket })
     }
 
+    /// Returns the local address this listener is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr, NetError> {
+        Ok(self.socket.local_addr()?)
+    }
+
     /// Receives a request and returns it along with its remote address.
     pub fn recv_request(&self) -> Result<(Request, SocketAddr), NetError> {
+        self.socket.set_nonblocking(false)?;
+
         // Original engine receives connection requests in `net_message`,
         // allocated at https://github.com/id-Software/Quake/blob/master/WinQuake/net_main.c#L851
         let mut recv_buf = [0u8; MAX_MESSAGE];
         let (len, remote) = self.socket.recv_from(&mut recv_buf)?;
-        let mut reader = BufReader::new(&recv_buf[..len]);
-
-        let control = reader.read_i32::<NetworkEndian>()?;
-
-        // TODO: figure out what a control value of -1 means
-        if control == -1 {
-            return Err(NetError::with_msg("Control value is -1"));
-        }
+        let request = read_request(&recv_buf[..len])?;
 
-        // high 4 bits must be 0x8000 (CONNECT_CONTROL)
-        if control & !CONNECT_LENGTH_MASK != CONNECT_CONTROL {
-            return Err(NetError::InvalidData(format!(
-                "control value {:X}",
-                control & !CONNECT_LENGTH_MASK
-            )));
-        }
+        Ok((request, remote))
+    }
 
-        // low 4 bits must be total length of packet
-        let control_len = (control & CONNECT_LENGTH_MASK) as usize;
-        if control_len != len {
-            return Err(NetError::InvalidData(format!(
-                "Actual packet length ({}) differs from header value ({})",
-                len, control_len,
-            )));
-        }
+    /// Receives a request if one is waiting, without blocking.
+    ///
+    /// Returns `None` if there are no pending requests.
+    pub fn try_recv_request(&self) -> Result<Option<(Request, SocketAddr)>, NetError> {
+        self.socket.set_nonblocking(true)?;
 
-        // validate request code
-        let request_byte = reader.read_u8()?;
-        let request_code = match RequestCode::from_u8(request_byte) {
-            Some(r) => r,
-            None => {
-                return Err(NetError::InvalidData(format!(
-                    "request code {}",
-                    request_byte
-                )))
-            }
+        let mut recv_buf = [0u8; MAX_MESSAGE];
+        let (len, remote) = match self.socket.recv_from(&mut recv_buf) {
+            Err(e) => match e.kind() {
+                ErrorKind::WouldBlock => return Ok(None),
+                _ => return Err(NetError::from(e)),
+            },
+            Ok(ret) => ret,
         };
+        let request = read_request(&recv_buf[..len])?;
 
-        let request = match request_code {
-            RequestCode::Connect => {
-                let game_name = util::read_cstring(&mut reader).unwrap();
-                let proto_ver = reader.read_u8()?;
-                Request::Connect(RequestConnect {
-                    game_name,
-                    proto_ver,
-                })
-            }
+        Ok(Some((request, remote)))
+    }
 
-            RequestCode::ServerInfo => {
-                let game_name = util::read_cstring(&mut reader).unwrap();
-                Request::ServerInfo(RequestServerInfo { game_name })
-            }
+    pub fn send_response(&self, response: Response, remote: SocketAddr) -> Result<(), NetError> {
+        self.socket.send_to(&response.to_bytes()?, remote)?;
+        Ok(())
+    }
+}
 
-            RequestCode::PlayerInfo => {
-                let player_id = reader.read_u8()?;
-                Request::PlayerInfo(RequestPlayerInfo { player_id })
-            }
+fn read_request(packet: &[u8]) -> Result<Request, NetError> {
+    let len = packet.len();
+    let mut reader = BufReader::new(packet);
 
-            RequestCode::RuleInfo => {
-                let prev_cvar = util::read_cstring(&mut reader).unwrap();
-                Request::RuleInfo(RequestRuleInfo { prev_cvar })
-            }
-        };
+    let control = reader.read_i32::<NetworkEndian>()?;
 
-        Ok((request, remote))
+    // TODO: figure out what a control value of -1 means
+    if control == -1 {
+        return Err(NetError::with_msg("Control value is -1"));
     }
 
-    pub fn send_response(&self, response: Response, remote: SocketAddr) -> Result<(), NetError> {
-        self.socket.send_to(&response.to_bytes()?, remote)?;
-        Ok(())
+    // high 4 bits must be 0x8000 (CONNECT_CONTROL)
+    if control & !CONNECT_LENGTH_MASK != CONNECT_CONTROL {
+        return Err(NetError::InvalidData(format!(
+            "control value {:X}",
+            control & !CONNECT_LENGTH_MASK
+        )));
     }
+
+    // low 4 bits must be total length of packet
+    let control_len = (control & CONNECT_LENGTH_MASK) as usize;
+    if control_len != len {
+        return Err(NetError::InvalidData(format!(
+            "Actual packet length ({}) differs from header value ({})",
+            len, control_len,
+        )));
+    }
+
+    // validate request code
+    let request_byte = reader.read_u8()?;
+    let request_code = match RequestCode::from_u8(request_byte) {
+        Some(r) => r,
+        None => {
+            return Err(NetError::InvalidData(format!(
+                "request code {}",
+                request_byte
+            )))
+        }
+    };
+
+    let request = match request_code {
+        RequestCode::Connect => {
+            let game_name = util::read_cstring(&mut reader).unwrap();
+            let proto_ver = reader.read_u8()?;
+            Request::Connect(RequestConnect {
+                game_name,
+                proto_ver,
+            })
+        }
+
+        RequestCode::ServerInfo => {
+            let game_name = util::read_cstring(&mut reader).unwrap();
+            Request::ServerInfo(RequestServerInfo { game_name })
+        }
+
+        RequestCode::PlayerInfo => {
+            let player_id = reader.read_u8()?;
+            Request::PlayerInfo(RequestPlayerInfo { player_id })
+        }
+
+        RequestCode::RuleInfo => {
+            let prev_cvar = util::read_cstring(&mut reader).unwrap();
+            Request::RuleInfo(RequestRuleInfo { prev_cvar })
+        }
+    };
+
+    Ok(request)
 }
 
 pub struct ConnectSocket {
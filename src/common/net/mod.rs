@@ -32,6 +32,7 @@ use std::io::Read;
 use std::io::Write;
 use std::net::SocketAddr;
 use std::net::UdpSocket;
+use std::time::Instant;
 
 use common::engine;
 use common::util;
@@ -67,9 +68,26 @@ const SOUND_ATTENUATION_WRITE_FACTOR: u8 = 64;
 const SOUND_ATTENUATION_READ_FACTOR: f32 = 1.0 / SOUND_ATTENUATION_WRITE_FACTOR as f32;
 
 pub static GAME_NAME: &'static str = "QUAKE";
+pub const DEFAULT_PORT: u16 = 26000;
 pub const MAX_CLIENTS: usize = 16;
 pub const MAX_ITEMS: usize = 32;
 
+/// The largest number of entities the protocol can address. Sound messages pack the entity ID
+/// into 13 bits.
+pub const MAX_EDICTS: usize = 8192;
+
+/// The default value of `max_edicts`.
+pub const DEFAULT_MAX_EDICTS: usize = 600;
+
+/// Converts a `max_edicts` value to an entity capacity between 1 and `MAX_EDICTS`.
+pub fn max_edicts(value: f32) -> usize {
+    if value.is_nan() || value < 1.0 {
+        return 1;
+    }
+
+    (value as usize).min(MAX_EDICTS)
+}
+
 pub const DEFAULT_VIEWHEIGHT: f32 = 22.0;
 
 #[derive(Debug)]
@@ -313,6 +331,10 @@ pub struct TempEntityPoint {
 }
 
 impl TempEntityPoint {
+    pub fn origin(&self) -> Vector3<f32> {
+        self.origin
+    }
+
     fn deserialize<R>(reader: &mut R) -> Result<TempEntityPoint, NetError>
     where
         R: BufRead + ReadBytesExt,
@@ -559,7 +581,7 @@ bitflags! {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct EntityState {
     pub origin: Vector3<f32>,
     pub angles: Vector3<Deg<f32>>,
@@ -584,6 +606,83 @@ impl EntityState {
             effects: EntityEffects::empty(),
         }
     }
+
+    /// Produces a fast update for entity `ent_id` containing only the fields of this state that
+    /// differ from `baseline`.
+    ///
+    /// Fields are compared at network precision, so changes too small to be transmitted are
+    /// not sent.
+    pub fn delta_update(&self, ent_id: u16, baseline: &EntityState, no_lerp: bool) -> ServerCmd {
+        fn changed<T: PartialEq>(new: T, old: T) -> Option<T> {
+            if new != old {
+                Some(new)
+            } else {
+                None
+            }
+        }
+
+        let coord = |new: f32, old: f32| changed(coord_bits(new), coord_bits(old)).map(|_| new);
+        let angle = |new: Deg<f32>, old: Deg<f32>| {
+            changed(angle_bits(new), angle_bits(old)).map(|_| new)
+        };
+
+        ServerCmd::FastUpdate {
+            ent_id,
+            model_id: changed(self.model_id as u8, baseline.model_id as u8),
+            frame_id: changed(self.frame_id as u8, baseline.frame_id as u8),
+            colormap: changed(self.colormap, baseline.colormap),
+            skin_id: changed(self.skin_id as u8, baseline.skin_id as u8),
+            effects: changed(self.effects, baseline.effects),
+            origin_x: coord(self.origin.x, baseline.origin.x),
+            pitch: angle(self.angles.x, baseline.angles.x),
+            origin_y: coord(self.origin.y, baseline.origin.y),
+            yaw: angle(self.angles.y, baseline.angles.y),
+            origin_z: coord(self.origin.z, baseline.origin.z),
+            roll: angle(self.angles.z, baseline.angles.z),
+            no_lerp,
+        }
+    }
+
+    /// Applies a fast update to this baseline, returning the entity's new state.
+    ///
+    /// As in the original protocol, fields missing from the update take their baseline values.
+    /// Returns `None` if `update` is not a `ServerCmd::FastUpdate`.
+    pub fn apply_update(&self, update: &ServerCmd) -> Option<EntityState> {
+        match *update {
+            ServerCmd::FastUpdate {
+                model_id,
+                frame_id,
+                colormap,
+                skin_id,
+                effects,
+                origin_x,
+                pitch,
+                origin_y,
+                yaw,
+                origin_z,
+                roll,
+                ..
+            } => Some(EntityState {
+                origin: Vector3::new(
+                    origin_x.unwrap_or(self.origin.x),
+                    origin_y.unwrap_or(self.origin.y),
+                    origin_z.unwrap_or(self.origin.z),
+                ),
+                angles: Vector3::new(
+                    pitch.unwrap_or(self.angles.x),
+                    yaw.unwrap_or(self.angles.y),
+                    roll.unwrap_or(self.angles.z),
+                ),
+                model_id: model_id.map(|m| m as usize).unwrap_or(self.model_id),
+                frame_id: frame_id.map(|f| f as usize).unwrap_or(self.frame_id),
+                colormap: colormap.unwrap_or(self.colormap),
+                skin_id: skin_id.map(|s| s as usize).unwrap_or(self.skin_id),
+                effects: effects.unwrap_or(self.effects),
+            }),
+
+            _ => None,
+        }
+    }
 }
 
 /// A trait for in-game server and client network commands.
@@ -1487,6 +1586,11 @@ impl ServerCmd {
     where
         W: WriteBytesExt,
     {
+        // fast updates have no command code, their flags are written in its place
+        if let ServerCmd::FastUpdate { .. } = *self {
+            return self.serialize_fast_update(writer);
+        }
+
         writer.write_u8(self.code())?;
 
         match *self {
@@ -1859,8 +1963,105 @@ impl ServerCmd {
                 writer.write_u8(0)?;
             }
 
-            // TODO
-            ServerCmd::FastUpdate { .. } => unimplemented!(),
+            ServerCmd::FastUpdate { .. } => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    fn serialize_fast_update<W>(&self, writer: &mut W) -> Result<(), NetError>
+    where
+        W: WriteBytesExt,
+    {
+        let (
+            ent_id,
+            model_id,
+            frame_id,
+            colormap,
+            skin_id,
+            effects,
+            origin_x,
+            pitch,
+            origin_y,
+            yaw,
+            origin_z,
+            roll,
+            no_lerp,
+        ) = match *self {
+            ServerCmd::FastUpdate {
+                ent_id,
+                model_id,
+                frame_id,
+                colormap,
+                skin_id,
+                effects,
+                origin_x,
+                pitch,
+                origin_y,
+                yaw,
+                origin_z,
+                roll,
+                no_lerp,
+            } => (
+                ent_id, model_id, frame_id, colormap, skin_id, effects, origin_x, pitch, origin_y,
+                yaw, origin_z, roll, no_lerp,
+            ),
+            _ => unreachable!(),
+        };
+
+        let mut flags = UpdateFlags::empty();
+        flags.set(UpdateFlags::LONG_ENTITY, ent_id > 0xFF);
+        flags.set(UpdateFlags::MODEL, model_id.is_some());
+        flags.set(UpdateFlags::FRAME, frame_id.is_some());
+        flags.set(UpdateFlags::COLORMAP, colormap.is_some());
+        flags.set(UpdateFlags::SKIN, skin_id.is_some());
+        flags.set(UpdateFlags::EFFECTS, effects.is_some());
+        flags.set(UpdateFlags::ORIGIN_X, origin_x.is_some());
+        flags.set(UpdateFlags::PITCH, pitch.is_some());
+        flags.set(UpdateFlags::ORIGIN_Y, origin_y.is_some());
+        flags.set(UpdateFlags::YAW, yaw.is_some());
+        flags.set(UpdateFlags::ORIGIN_Z, origin_z.is_some());
+        flags.set(UpdateFlags::ROLL, roll.is_some());
+        flags.set(UpdateFlags::NO_LERP, no_lerp);
+        flags.set(UpdateFlags::MORE_BITS, flags.bits() & 0xFF00 != 0);
+
+        writer.write_u8(FAST_UPDATE_FLAG | flags.bits() as u8)?;
+        if flags.contains(UpdateFlags::MORE_BITS) {
+            writer.write_u8((flags.bits() >> 8) as u8)?;
+        }
+
+        if flags.contains(UpdateFlags::LONG_ENTITY) {
+            writer.write_u16::<LittleEndian>(ent_id)?;
+        } else {
+            writer.write_u8(ent_id as u8)?;
+        }
+
+        for byte in [model_id, frame_id, colormap, skin_id].iter().filter_map(|b| *b) {
+            writer.write_u8(byte)?;
+        }
+
+        if let Some(e) = effects {
+            writer.write_u8(e.bits())?;
+        }
+
+        // origin and angles are interleaved
+        if let Some(x) = origin_x {
+            write_coord(writer, x)?;
+        }
+        if let Some(p) = pitch {
+            write_angle(writer, p)?;
+        }
+        if let Some(y) = origin_y {
+            write_coord(writer, y)?;
+        }
+        if let Some(y) = yaw {
+            write_angle(writer, y)?;
+        }
+        if let Some(z) = origin_z {
+            write_coord(writer, z)?;
+        }
+        if let Some(r) = roll {
+            write_angle(writer, r)?;
         }
 
         Ok(())
@@ -2025,6 +2226,12 @@ pub struct QSocket {
     send_count: usize,
     resend_count: usize,
 
+    // when the packet in the send cache was sent, if it hasn't been resent since
+    send_instant: Option<Instant>,
+
+    // smoothed round trip time of reliable packets
+    rtt: Option<Duration>,
+
     recv_sequence: u32,
     recv_buf: [u8; MAX_MESSAGE],
 }
@@ -2046,12 +2253,24 @@ impl QSocket {
             send_count: 0,
             send_next: false,
             resend_count: 0,
+            send_instant: None,
+            rtt: None,
 
             recv_sequence: 0,
             recv_buf: [0; MAX_MESSAGE],
         }
     }
 
+    /// Returns the address of the other end of the connection.
+    pub fn remote(&self) -> SocketAddr {
+        self.remote
+    }
+
+    /// Returns the smoothed round trip time of acknowledged reliable packets, if any have been.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
     pub fn can_send(&self) -> bool {
         self.send_queue.is_empty() && self.send_cache.is_empty()
     }
@@ -2095,6 +2314,9 @@ impl QSocket {
             self.socket.send_to(&self.send_cache, self.remote)?;
             self.resend_count += 1;
 
+            // an ACK could be for either packet, so it can't be timed
+            self.send_instant = None;
+
             Ok(())
         }
     }
@@ -2128,7 +2350,8 @@ impl QSocket {
         // send the composed packet
         self.socket.send_to(&self.send_cache, self.remote)?;
 
-        // TODO: update send time
+        self.send_instant = Some(Instant::now());
+
         // bump send count
         self.send_count += 1;
 
@@ -2286,6 +2509,12 @@ impl QSocket {
                             return Err(NetError::with_msg("ACK sequencing error"));
                         }
 
+                        if let Some(sent) = self.send_instant.take() {
+                            let sample = Duration::from_std(sent.elapsed())
+                                .unwrap_or_else(|_| Duration::zero());
+                            self.rtt = Some(smooth_rtt(self.rtt, sample));
+                        }
+
                         // our last reliable message has been acked
                         if self.send_queue.is_empty() {
                             // the whole message is through, clear the send cache
@@ -2333,6 +2562,14 @@ impl QSocket {
     }
 }
 
+// blends a new round trip time sample into the running estimate
+fn smooth_rtt(rtt: Option<Duration>, sample: Duration) -> Duration {
+    match rtt {
+        Some(r) => r + (sample - r) / 8,
+        None => sample,
+    }
+}
+
 fn read_coord<R>(reader: &mut R) -> Result<f32, NetError>
 where
     R: BufRead + ReadBytesExt,
@@ -2351,11 +2588,16 @@ where
     ))
 }
 
+// the network representation of a coordinate
+fn coord_bits(coord: f32) -> i16 {
+    (coord * 8.0) as i16
+}
+
 fn write_coord<W>(writer: &mut W, coord: f32) -> Result<(), NetError>
 where
     W: WriteBytesExt,
 {
-    writer.write_i16::<LittleEndian>((coord * 8.0) as i16)?;
+    writer.write_i16::<LittleEndian>(coord_bits(coord))?;
     Ok(())
 }
 
@@ -2388,11 +2630,16 @@ where
     ))
 }
 
+// the network representation of an angle
+fn angle_bits(angle: Deg<f32>) -> u8 {
+    ((angle.0 as i32 * 256 / 360) & 0xFF) as u8
+}
+
 fn write_angle<W>(writer: &mut W, angle: Deg<f32>) -> Result<(), NetError>
 where
     W: WriteBytesExt,
 {
-    writer.write_u8(((angle.0 as i32 * 256 / 360) & 0xFF) as u8)?;
+    writer.write_u8(angle_bits(angle))?;
     Ok(())
 }
 
@@ -2413,6 +2660,25 @@ mod test {
 
     use std::io::BufReader;
 
+    #[test]
+    fn test_max_edicts() {
+        assert_eq!(max_edicts(600.0), 600);
+        assert_eq!(max_edicts(2048.5), 2048);
+        assert_eq!(max_edicts(100000.0), MAX_EDICTS);
+        assert_eq!(max_edicts(0.0), 1);
+        assert_eq!(max_edicts(-5.0), 1);
+    }
+
+    #[test]
+    fn test_smooth_rtt() {
+        let first = smooth_rtt(None, Duration::milliseconds(80));
+        assert_eq!(first, Duration::milliseconds(80));
+
+        // a single slow packet only nudges the estimate
+        let second = smooth_rtt(Some(first), Duration::milliseconds(160));
+        assert_eq!(second, Duration::milliseconds(90));
+    }
+
     #[test]
     fn test_server_cmd_update_stat_read_write_eq() {
         let src = ServerCmd::UpdateStat {
@@ -2428,6 +2694,61 @@ mod test {
         assert_eq!(src, dst);
     }
 
+    #[test]
+    fn test_entity_delta_round_trip() {
+        let mut baseline = EntityState::uninitialized();
+        baseline.origin = Vector3::new(64.0, -32.0, 16.0);
+        baseline.model_id = 5;
+
+        let mut state = baseline.clone();
+        state.origin.y = 100.5;
+        state.angles.y = Deg(45.0);
+        state.frame_id = 3;
+        state.effects = EntityEffects::MUZZLE_FLASH;
+
+        let src = state.delta_update(300, &baseline, false);
+        match src {
+            ServerCmd::FastUpdate {
+                model_id,
+                origin_x,
+                origin_y,
+                ..
+            } => {
+                // unchanged fields are left out
+                assert_eq!(model_id, None);
+                assert_eq!(origin_x, None);
+                assert_eq!(origin_y, Some(100.5));
+            }
+            _ => panic!("delta_update did not produce a fast update"),
+        }
+
+        let mut packet = Vec::new();
+        src.serialize(&mut packet).unwrap();
+        let mut reader = BufReader::new(packet.as_slice());
+        let dst = ServerCmd::deserialize(&mut reader).unwrap().unwrap();
+        assert_eq!(src, dst);
+
+        assert_eq!(baseline.apply_update(&dst).unwrap(), state);
+    }
+
+    #[test]
+    fn test_entity_delta_unchanged_is_minimal() {
+        let baseline = EntityState::uninitialized();
+        let mut state = baseline.clone();
+
+        // too small to survive quantization
+        state.origin.x = 0.01;
+
+        let mut packet = Vec::new();
+        state
+            .delta_update(1, &baseline, false)
+            .serialize(&mut packet)
+            .unwrap();
+
+        // flags byte and entity id only
+        assert_eq!(packet.len(), 2);
+    }
+
     #[test]
     fn test_server_cmd_version_read_write_eq() {
         let src = ServerCmd::Version { version: 42 };
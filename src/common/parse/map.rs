@@ -19,16 +19,28 @@ use std::collections::HashMap;
 
 use common::parse::quoted;
 
-use combine::char::string;
-use combine::{between, many, token, ParseError, Parser, Stream};
+use combine::char::{space, string};
+use combine::{
+    attempt, between, choice, many, satisfy, skip_many, token, ParseError, Parser, Stream,
+};
 
-// "name" "value"\n
+// whitespace and `// comments` between tokens
+pub fn entity_space<I>() -> impl Parser<Input = I, Output = ()>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    let comment = (attempt(string("//")), skip_many(satisfy(|c| c != '\n'))).map(|_| ());
+    skip_many(choice((space().map(|_| ()), comment)))
+}
+
+// "name" "value"
 pub fn entity_attribute<I>() -> impl Parser<Input = I, Output = (String, String)>
 where
     I: Stream<Item = char>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    (quoted(), token(' '), quoted(), token('\n')).map(|(k, _, v, _)| (k, v))
+    (quoted(), entity_space(), quoted(), entity_space()).map(|(k, _, v, _)| (k, v))
 }
 
 // {
@@ -36,12 +48,20 @@ where
 // "name2" "value2"
 // "name3" "value3"
 // }
+//
+// Tokens may be separated by any whitespace or comments, as entity strings written by compilers
+// other than the original `qbsp` aren't laid out one attribute per line. If an entity specifies
+// the same key more than once, the last value wins.
 pub fn entity<I>() -> impl Parser<Input = I, Output = HashMap<String, String>>
 where
     I: Stream<Item = char>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    between(string("{\n"), string("}\n"), many(entity_attribute()))
+    between(
+        (token('{'), entity_space()),
+        (token('}'), entity_space()),
+        many(entity_attribute()),
+    )
 }
 
 pub fn entities<I>() -> impl Parser<Input = I, Output = Vec<HashMap<String, String>>>
@@ -49,5 +69,5 @@ where
     I: Stream<Item = char>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    many(entity())
+    entity_space().with(many(entity()))
 }
@@ -0,0 +1,409 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! PK3 (zip) archive reading.
+//!
+//! The archive is read into memory when it is mounted, but entries are only decompressed when
+//! they are opened. Each entry is checked against the CRC-32 in the central directory.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use failure::Error;
+use flate2::read::DeflateDecoder;
+use flate2::Crc;
+
+const LOCAL_HEADER_MAGIC: u32 = 0x04034b50;
+const CENTRAL_HEADER_MAGIC: u32 = 0x02014b50;
+const END_OF_DIRECTORY_MAGIC: u32 = 0x06054b50;
+
+const LOCAL_HEADER_SIZE: usize = 30;
+const END_OF_DIRECTORY_SIZE: usize = 22;
+const MAX_COMMENT_SIZE: usize = 0xFFFF;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+const FLAG_ENCRYPTED: u16 = 1;
+
+struct Pk3Entry {
+    method: u16,
+    flags: u16,
+    crc: u32,
+    compressed_size: usize,
+    size: usize,
+    local_header_offset: usize,
+}
+
+pub struct Pk3 {
+    data: Box<[u8]>,
+    entries: HashMap<String, Pk3Entry>,
+}
+
+impl Pk3 {
+    pub fn new<P>(path: P) -> Result<Pk3, Error>
+    where
+        P: AsRef<Path>,
+    {
+        debug!("Opening {}", path.as_ref().display());
+
+        let mut data = Vec::new();
+        fs::File::open(path)?.read_to_end(&mut data)?;
+        Pk3::from_data(data.into_boxed_slice())
+    }
+
+    /// Reads the central directory of the zip archive in `data`.
+    pub fn from_data(data: Box<[u8]>) -> Result<Pk3, Error> {
+        // the end of central directory record is followed only by the archive comment
+        ensure!(data.len() >= END_OF_DIRECTORY_SIZE, "Archive too small");
+        let search_start = data
+            .len()
+            .saturating_sub(END_OF_DIRECTORY_SIZE + MAX_COMMENT_SIZE);
+        let eocd_offset = match (search_start..data.len() - END_OF_DIRECTORY_SIZE + 1)
+            .rev()
+            .find(|&i| {
+                (&data[i..i + 4]).read_u32::<LittleEndian>().unwrap() == END_OF_DIRECTORY_MAGIC
+            }) {
+            Some(o) => o,
+            None => bail!("No end of central directory record"),
+        };
+
+        let mut eocd = Cursor::new(&data[eocd_offset + 4..]);
+        let disk = eocd.read_u16::<LittleEndian>()?;
+        let directory_disk = eocd.read_u16::<LittleEndian>()?;
+        ensure!(
+            disk == 0 && directory_disk == 0,
+            "Multi-disk archives are not supported"
+        );
+        let _disk_entry_count = eocd.read_u16::<LittleEndian>()?;
+        let entry_count = eocd.read_u16::<LittleEndian>()?;
+        let _directory_size = eocd.read_u32::<LittleEndian>()?;
+        let directory_offset = eocd.read_u32::<LittleEndian>()? as u64;
+
+        let mut reader = Cursor::new(&data[..]);
+        reader.seek(SeekFrom::Start(directory_offset))?;
+
+        let mut entries = HashMap::new();
+        for _ in 0..entry_count {
+            ensure!(
+                reader.read_u32::<LittleEndian>()? == CENTRAL_HEADER_MAGIC,
+                "Bad central directory entry"
+            );
+            let _version_made_by = reader.read_u16::<LittleEndian>()?;
+            let _version_needed = reader.read_u16::<LittleEndian>()?;
+            let flags = reader.read_u16::<LittleEndian>()?;
+            let method = reader.read_u16::<LittleEndian>()?;
+            let _mod_time = reader.read_u16::<LittleEndian>()?;
+            let _mod_date = reader.read_u16::<LittleEndian>()?;
+            let crc = reader.read_u32::<LittleEndian>()?;
+            let compressed_size = reader.read_u32::<LittleEndian>()? as usize;
+            let size = reader.read_u32::<LittleEndian>()? as usize;
+            let name_len = reader.read_u16::<LittleEndian>()? as usize;
+            let extra_len = reader.read_u16::<LittleEndian>()? as i64;
+            let comment_len = reader.read_u16::<LittleEndian>()? as i64;
+            let _disk_start = reader.read_u16::<LittleEndian>()?;
+            let _internal_attributes = reader.read_u16::<LittleEndian>()?;
+            let _external_attributes = reader.read_u32::<LittleEndian>()?;
+            let local_header_offset = reader.read_u32::<LittleEndian>()? as usize;
+
+            let mut name_bytes = vec![0; name_len];
+            reader.read_exact(&mut name_bytes)?;
+            reader.seek(SeekFrom::Current(extra_len + comment_len))?;
+
+            // directories have no contents of their own
+            let name = String::from_utf8(name_bytes)?;
+            if name.ends_with('/') {
+                continue;
+            }
+
+            entries.insert(
+                name,
+                Pk3Entry {
+                    method,
+                    flags,
+                    crc,
+                    compressed_size,
+                    size,
+                    local_header_offset,
+                },
+            );
+        }
+
+        Ok(Pk3 { data, entries })
+    }
+
+    /// Decompresses and returns the contents of the file at `path`.
+    pub fn open<S>(&self, path: S) -> Result<Vec<u8>, Error>
+    where
+        S: AsRef<str>,
+    {
+        let entry = match self.entries.get(path.as_ref()) {
+            Some(e) => e,
+            None => bail!("No \"{}\" in pk3 file", path.as_ref()),
+        };
+
+        ensure!(
+            entry.flags & FLAG_ENCRYPTED == 0,
+            "\"{}\" is encrypted",
+            path.as_ref()
+        );
+
+        // the local header may have different extra data than the central directory
+        let header_offset = entry.local_header_offset;
+        ensure!(
+            header_offset + LOCAL_HEADER_SIZE <= self.data.len(),
+            "Local header out of bounds"
+        );
+        let mut header = Cursor::new(&self.data[header_offset..]);
+        ensure!(
+            header.read_u32::<LittleEndian>()? == LOCAL_HEADER_MAGIC,
+            "Bad local header for \"{}\"",
+            path.as_ref()
+        );
+        header.seek(SeekFrom::Start(26))?;
+        let name_len = header.read_u16::<LittleEndian>()? as usize;
+        let extra_len = header.read_u16::<LittleEndian>()? as usize;
+
+        let data_start = header_offset + LOCAL_HEADER_SIZE + name_len + extra_len;
+        let data_end = data_start + entry.compressed_size;
+        ensure!(data_end <= self.data.len(), "Entry data out of bounds");
+        let compressed = &self.data[data_start..data_end];
+
+        let contents = match entry.method {
+            METHOD_STORED => compressed.to_vec(),
+            METHOD_DEFLATED => {
+                // read one byte past the expected size so that oversized entries are caught below
+                let mut contents = Vec::with_capacity(entry.size);
+                DeflateDecoder::new(compressed)
+                    .take(entry.size as u64 + 1)
+                    .read_to_end(&mut contents)?;
+                contents
+            }
+            m => bail!(
+                "Unsupported compression method {} for \"{}\"",
+                m,
+                path.as_ref()
+            ),
+        };
+
+        ensure!(
+            contents.len() == entry.size,
+            "Size mismatch for \"{}\" (expected {}, got {})",
+            path.as_ref(),
+            entry.size,
+            contents.len()
+        );
+
+        let mut crc = Crc::new();
+        crc.update(&contents);
+        ensure!(
+            crc.sum() == entry.crc,
+            "CRC mismatch for \"{}\" (expected {:08x}, got {:08x})",
+            path.as_ref(),
+            entry.crc,
+            crc.sum()
+        );
+
+        Ok(contents)
+    }
+
+    pub fn contains<S>(&self, path: S) -> bool
+    where
+        S: AsRef<str>,
+    {
+        self.entries.contains_key(path.as_ref())
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(|k| k.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use common::vfs::Vfs;
+
+    use byteorder::WriteBytesExt;
+
+    // "hello, hello, hello world" compressed with fixed Huffman codes
+    static FIXED_DEFLATE: [u8; 17] = [
+        0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0xd7, 0x51, 0xc8, 0x40, 0xa2, 0x14, 0xca, 0xf3, 0x8b, 0x72,
+        0x52, 0x00,
+    ];
+
+    // the output of `map_list` compressed with dynamic Huffman codes
+    static DYNAMIC_DEFLATE: [u8; 86] = [
+        0x55, 0xcd, 0x3b, 0x0a, 0x80, 0x30, 0x14, 0x44, 0xd1, 0xde, 0xc5, 0x28, 0x99, 0x19, 0x3f,
+        0xdb, 0x51, 0xb0, 0x7c, 0x10, 0xc8, 0xfe, 0xc1, 0x26, 0x85, 0xb7, 0x3c, 0xd5, 0xa9, 0xbb,
+        0x8f, 0xed, 0x6d, 0xd5, 0xd6, 0x67, 0xf4, 0xa5, 0xa6, 0x04, 0x19, 0x0a, 0xb4, 0x43, 0x07,
+        0x74, 0x42, 0xd7, 0x4f, 0xc2, 0x27, 0x7c, 0xc2, 0x27, 0x7c, 0xc2, 0x27, 0x7c, 0xc2, 0x27,
+        0x7c, 0xc6, 0x67, 0x7c, 0xc6, 0x67, 0x7c, 0xc6, 0x67, 0x7c, 0xc6, 0x67, 0x7c, 0xc1, 0x17,
+        0x7c, 0xc1, 0x17, 0x7c, 0xc1, 0x17, 0x7c, 0xc1, 0x97, 0xf9, 0x7d,
+    ];
+
+    fn map_list() -> Vec<u8> {
+        let mut list = String::new();
+        for e in 1..5 {
+            for m in 1..9 {
+                list.push_str(&format!("maps/e{}m{}.bsp\n", e, m));
+            }
+        }
+
+        list.into_bytes()
+    }
+
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = Crc::new();
+        crc.update(data);
+        crc.sum()
+    }
+
+    // builds a zip archive from (name, method, uncompressed size, CRC-32, stored data) entries
+    fn build_zip(entries: &[(&str, u16, usize, u32, &[u8])]) -> Vec<u8> {
+        let mut zip = Vec::new();
+        let mut directory = Vec::new();
+        for &(name, method, size, crc, data) in entries {
+            let offset = zip.len() as u32;
+
+            zip.write_u32::<LittleEndian>(LOCAL_HEADER_MAGIC).unwrap();
+            zip.write_u16::<LittleEndian>(20).unwrap();
+            zip.write_u16::<LittleEndian>(0).unwrap();
+            zip.write_u16::<LittleEndian>(method).unwrap();
+            zip.write_u32::<LittleEndian>(0).unwrap(); // time and date
+            zip.write_u32::<LittleEndian>(crc).unwrap();
+            zip.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+            zip.write_u32::<LittleEndian>(size as u32).unwrap();
+            zip.write_u16::<LittleEndian>(name.len() as u16).unwrap();
+            zip.write_u16::<LittleEndian>(4).unwrap();
+            zip.extend_from_slice(name.as_bytes());
+            zip.extend_from_slice(&[0xAA; 4]); // extra data only in the local header
+            zip.extend_from_slice(data);
+
+            directory.write_u32::<LittleEndian>(CENTRAL_HEADER_MAGIC).unwrap();
+            directory.write_u16::<LittleEndian>(20).unwrap();
+            directory.write_u16::<LittleEndian>(20).unwrap();
+            directory.write_u16::<LittleEndian>(0).unwrap();
+            directory.write_u16::<LittleEndian>(method).unwrap();
+            directory.write_u32::<LittleEndian>(0).unwrap();
+            directory.write_u32::<LittleEndian>(crc).unwrap();
+            directory.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+            directory.write_u32::<LittleEndian>(size as u32).unwrap();
+            directory.write_u16::<LittleEndian>(name.len() as u16).unwrap();
+            directory.write_u16::<LittleEndian>(0).unwrap();
+            directory.write_u16::<LittleEndian>(0).unwrap();
+            directory.write_u16::<LittleEndian>(0).unwrap();
+            directory.write_u16::<LittleEndian>(0).unwrap();
+            directory.write_u32::<LittleEndian>(0).unwrap();
+            directory.write_u32::<LittleEndian>(offset).unwrap();
+            directory.extend_from_slice(name.as_bytes());
+        }
+
+        let directory_offset = zip.len() as u32;
+        zip.extend_from_slice(&directory);
+        zip.write_u32::<LittleEndian>(END_OF_DIRECTORY_MAGIC).unwrap();
+        zip.write_u16::<LittleEndian>(0).unwrap();
+        zip.write_u16::<LittleEndian>(0).unwrap();
+        zip.write_u16::<LittleEndian>(entries.len() as u16).unwrap();
+        zip.write_u16::<LittleEndian>(entries.len() as u16).unwrap();
+        zip.write_u32::<LittleEndian>(directory.len() as u32).unwrap();
+        zip.write_u32::<LittleEndian>(directory_offset).unwrap();
+        zip.write_u16::<LittleEndian>(7).unwrap();
+        zip.extend_from_slice(b"comment");
+
+        zip
+    }
+
+    #[test]
+    fn test_pk3_open() {
+        let maps = map_list();
+        let hello = b"hello, hello, hello world";
+        let zip = build_zip(&[
+            ("maps/", METHOD_STORED, 0, 0, &[]),
+            ("maps/list.txt", METHOD_DEFLATED, maps.len(), crc32(&maps), &DYNAMIC_DEFLATE),
+            ("gfx/stored.lmp", METHOD_STORED, 4, crc32(&[1, 2, 3, 4]), &[1, 2, 3, 4]),
+            ("hello.txt", METHOD_DEFLATED, 25, crc32(hello), &FIXED_DEFLATE),
+        ]);
+        let pk3 = Pk3::from_data(zip.into_boxed_slice()).unwrap();
+
+        assert!(!pk3.contains("maps/"));
+        assert_eq!(pk3.open("maps/list.txt").unwrap(), maps);
+        assert_eq!(pk3.open("gfx/stored.lmp").unwrap(), vec![1, 2, 3, 4]);
+        assert_eq!(pk3.open("hello.txt").unwrap(), b"hello, hello, hello world".to_vec());
+        assert!(pk3.open("progs.dat").is_err());
+    }
+
+    #[test]
+    fn test_vfs_pk3_matches_loose_files() {
+        let dir = ::std::env::temp_dir().join(format!("richter-pk3-test-{}", ::std::process::id()));
+        fs::create_dir_all(dir.join("loose/maps")).unwrap();
+        fs::write(dir.join("loose/maps/list.txt"), map_list()).unwrap();
+        let crc = crc32(&map_list());
+        let zip = build_zip(&[("maps/list.txt", METHOD_DEFLATED, 448, crc, &DYNAMIC_DEFLATE)]);
+        fs::write(dir.join("pak0.pk3"), zip).unwrap();
+
+        let read_all = |vfs: &Vfs| {
+            let mut contents = Vec::new();
+            vfs.open("maps/list.txt")
+                .unwrap()
+                .read_to_end(&mut contents)
+                .unwrap();
+            contents
+        };
+
+        let mut loose = Vfs::new();
+        loose.add_directory(dir.join("loose")).unwrap();
+        let mut packed = Vfs::new();
+        packed.add_pk3file(dir.join("pak0.pk3")).unwrap();
+        assert_eq!(read_all(&packed), read_all(&loose));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pk3_size_mismatch() {
+        let crc = crc32(b"hello, hello, hello world");
+        let zip = build_zip(&[("hello.txt", METHOD_DEFLATED, 30, crc, &FIXED_DEFLATE)]);
+        let pk3 = Pk3::from_data(zip.into_boxed_slice()).unwrap();
+        assert!(pk3.open("hello.txt").is_err());
+    }
+
+    #[test]
+    fn test_pk3_truncated() {
+        let maps = map_list();
+        let zip = build_zip(&[(
+            "maps/list.txt",
+            METHOD_DEFLATED,
+            maps.len(),
+            crc32(&maps),
+            &DYNAMIC_DEFLATE[..40],
+        )]);
+        let pk3 = Pk3::from_data(zip.into_boxed_slice()).unwrap();
+        assert!(pk3.open("maps/list.txt").is_err());
+    }
+
+    #[test]
+    fn test_pk3_crc_mismatch() {
+        let zip = build_zip(&[("gfx/stored.lmp", METHOD_STORED, 4, 0x1234_5678, &[1, 2, 3, 4])]);
+        let pk3 = Pk3::from_data(zip.into_boxed_slice()).unwrap();
+        assert!(pk3.open("gfx/stored.lmp").is_err());
+    }
+}
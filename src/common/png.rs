@@ -0,0 +1,171 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Minimal PNG encoding for debug output.
+//!
+//! Only 8-bit images without scanline filtering are written. This is intended for developer
+//! tooling such as lightmap dumps and screenshots, not for general asset output.
+
+use std::io::Write;
+
+use byteorder::{BigEndian, WriteBytesExt};
+use failure::Error;
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+/// The pixel layout of image data passed to `write_png`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PngColor {
+    /// One byte per pixel.
+    Grayscale,
+
+    /// Three bytes per pixel.
+    Rgb,
+
+    /// Four bytes per pixel.
+    Rgba,
+}
+
+impl PngColor {
+    fn bytes_per_pixel(&self) -> usize {
+        match *self {
+            PngColor::Grayscale => 1,
+            PngColor::Rgb => 3,
+            PngColor::Rgba => 4,
+        }
+    }
+
+    fn color_type(&self) -> u8 {
+        match *self {
+            PngColor::Grayscale => 0,
+            PngColor::Rgb => 2,
+            PngColor::Rgba => 6,
+        }
+    }
+}
+
+fn write_chunk<W>(writer: &mut W, kind: &[u8; 4], data: &[u8]) -> Result<(), Error>
+where
+    W: Write,
+{
+    writer.write_u32::<BigEndian>(data.len() as u32)?;
+
+    let mut crc_data = Vec::with_capacity(kind.len() + data.len());
+    crc_data.extend_from_slice(kind);
+    crc_data.extend_from_slice(data);
+
+    let mut crc = Crc::new();
+    crc.update(&crc_data);
+
+    writer.write_all(&crc_data)?;
+    writer.write_u32::<BigEndian>(crc.sum())?;
+
+    Ok(())
+}
+
+/// Encode `data` as a PNG image of the given dimensions and write it to `writer`.
+///
+/// `data` must be tightly packed in row-major order, starting with the top row.
+pub fn write_png<W>(
+    writer: &mut W,
+    width: u32,
+    height: u32,
+    color: PngColor,
+    data: &[u8],
+) -> Result<(), Error>
+where
+    W: Write,
+{
+    let stride = width as usize * color.bytes_per_pixel();
+    ensure!(
+        data.len() == stride * height as usize,
+        "Image data is {} bytes, expected {}",
+        data.len(),
+        stride * height as usize
+    );
+
+    writer.write_all(&PNG_SIGNATURE)?;
+
+    let mut header = Vec::with_capacity(13);
+    header.write_u32::<BigEndian>(width)?;
+    header.write_u32::<BigEndian>(height)?;
+    header.write_u8(8)?; // bit depth
+    header.write_u8(color.color_type())?;
+    header.write_u8(0)?; // compression method
+    header.write_u8(0)?; // filter method
+    header.write_u8(0)?; // interlace method
+    write_chunk(writer, b"IHDR", &header)?;
+
+    // prefix each scanline with filter type 0 (none)
+    let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+    for row in data.chunks(stride.max(1)).take(height as usize) {
+        zlib.write_all(&[0])?;
+        zlib.write_all(row)?;
+    }
+
+    write_chunk(writer, b"IDAT", &zlib.finish()?)?;
+    write_chunk(writer, b"IEND", &[])?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_png_layout() {
+        let mut out = Vec::new();
+        write_png(&mut out, 2, 2, PngColor::Grayscale, &[0, 64, 128, 255]).unwrap();
+
+        assert_eq!(&out[..8], &PNG_SIGNATURE);
+        assert_eq!(&out[12..16], b"IHDR");
+        assert_eq!(
+            &out[out.len() - 12..],
+            &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]
+        );
+    }
+
+    #[test]
+    fn test_write_png_image_data() {
+        use byteorder::ReadBytesExt;
+        use flate2::read::ZlibDecoder;
+        use std::io::Read;
+
+        let mut out = Vec::new();
+        write_png(&mut out, 2, 2, PngColor::Grayscale, &[0, 64, 128, 255]).unwrap();
+
+        // IDAT follows the signature and the 25-byte IHDR chunk
+        let idat = &out[33..];
+        assert_eq!(&idat[4..8], b"IDAT");
+        let len = (&idat[..4]).read_u32::<BigEndian>().unwrap() as usize;
+
+        let mut raw = Vec::new();
+        ZlibDecoder::new(&idat[8..8 + len])
+            .read_to_end(&mut raw)
+            .unwrap();
+        assert_eq!(raw, vec![0, 0, 64, 0, 128, 255]);
+    }
+
+    #[test]
+    fn test_write_png_bad_size() {
+        let mut out = Vec::new();
+        assert!(write_png(&mut out, 2, 2, PngColor::Rgba, &[0; 4]).is_err());
+    }
+}
@@ -0,0 +1,212 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Truevision TGA image loading.
+//!
+//! Only truecolor and grayscale images are supported, either uncompressed or run-length encoded.
+//! These cover the skins shipped with MD3 model replacements.
+
+use std::io::Read;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use failure::Error;
+
+const TYPE_TRUECOLOR: u8 = 2;
+const TYPE_GRAYSCALE: u8 = 3;
+const TYPE_RLE_TRUECOLOR: u8 = 10;
+const TYPE_RLE_GRAYSCALE: u8 = 11;
+
+// set in the image descriptor if the first row is the top of the image
+const DESCRIPTOR_TOP_LEFT: u8 = 0x20;
+
+/// A decoded TGA image.
+#[derive(Clone, Debug)]
+pub struct TgaImage {
+    width: u32,
+    height: u32,
+    rgba: Vec<u8>,
+}
+
+impl TgaImage {
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Returns the pixels of the image in RGBA order, from the top row down.
+    pub fn into_rgba(self) -> Vec<u8> {
+        self.rgba
+    }
+}
+
+// reads one pixel in the file's BGR(A) or grayscale layout as RGBA
+fn read_pixel<R>(reader: &mut R, bytes_per_pixel: usize) -> Result<[u8; 4], Error>
+where
+    R: Read,
+{
+    let mut raw = [0; 4];
+    reader.read_exact(&mut raw[..bytes_per_pixel])?;
+    Ok(match bytes_per_pixel {
+        1 => [raw[0], raw[0], raw[0], 0xFF],
+        3 => [raw[2], raw[1], raw[0], 0xFF],
+        _ => [raw[2], raw[1], raw[0], raw[3]],
+    })
+}
+
+/// Loads a TGA image.
+pub fn load<R>(mut reader: R) -> Result<TgaImage, Error>
+where
+    R: Read,
+{
+    let id_len = reader.read_u8()?;
+    let color_map_type = reader.read_u8()?;
+    let image_type = reader.read_u8()?;
+    let _color_map_first = reader.read_u16::<LittleEndian>()?;
+    let color_map_len = reader.read_u16::<LittleEndian>()?;
+    let color_map_entry_bits = reader.read_u8()?;
+    let _x_origin = reader.read_u16::<LittleEndian>()?;
+    let _y_origin = reader.read_u16::<LittleEndian>()?;
+    let width = reader.read_u16::<LittleEndian>()? as usize;
+    let height = reader.read_u16::<LittleEndian>()? as usize;
+    let bits_per_pixel = reader.read_u8()?;
+    let descriptor = reader.read_u8()?;
+
+    let (grayscale, rle) = match image_type {
+        TYPE_TRUECOLOR => (false, false),
+        TYPE_GRAYSCALE => (true, false),
+        TYPE_RLE_TRUECOLOR => (false, true),
+        TYPE_RLE_GRAYSCALE => (true, true),
+        t => bail!("Unsupported TGA image type {}", t),
+    };
+
+    let bytes_per_pixel = match (grayscale, bits_per_pixel) {
+        (true, 8) => 1,
+        (false, 24) => 3,
+        (false, 32) => 4,
+        (_, b) => bail!("Unsupported TGA pixel depth {} for image type {}", b, image_type),
+    };
+
+    // skip the image ID and any color map, which truecolor images don't use
+    let mut skip = id_len as u64;
+    if color_map_type != 0 {
+        skip += color_map_len as u64 * ((color_map_entry_bits as u64 + 7) / 8);
+    }
+    ::std::io::copy(&mut (&mut reader).take(skip), &mut ::std::io::sink())?;
+
+    let pixel_count = width * height;
+    let mut pixels = Vec::with_capacity(pixel_count);
+    if rle {
+        while pixels.len() < pixel_count {
+            let packet = reader.read_u8()?;
+            let count = (packet & 0x7F) as usize + 1;
+            ensure!(
+                pixels.len() + count <= pixel_count,
+                "TGA run-length packet overflows the image"
+            );
+
+            if packet & 0x80 != 0 {
+                let pixel = read_pixel(&mut reader, bytes_per_pixel)?;
+                for _ in 0..count {
+                    pixels.push(pixel);
+                }
+            } else {
+                for _ in 0..count {
+                    pixels.push(read_pixel(&mut reader, bytes_per_pixel)?);
+                }
+            }
+        }
+    } else {
+        for _ in 0..pixel_count {
+            pixels.push(read_pixel(&mut reader, bytes_per_pixel)?);
+        }
+    }
+
+    // rows are stored bottom-up unless the descriptor says otherwise
+    let mut rgba = Vec::with_capacity(pixel_count * 4);
+    for row in 0..height {
+        let src_row = if descriptor & DESCRIPTOR_TOP_LEFT != 0 {
+            row
+        } else {
+            height - 1 - row
+        };
+
+        for pixel in &pixels[src_row * width..(src_row + 1) * width] {
+            rgba.extend_from_slice(pixel);
+        }
+    }
+
+    Ok(TgaImage {
+        width: width as u32,
+        height: height as u32,
+        rgba,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use byteorder::WriteBytesExt;
+
+    fn header(image_type: u8, width: u16, height: u16, bits: u8, descriptor: u8) -> Vec<u8> {
+        let mut data = vec![0, 0, image_type, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        data.write_u16::<LittleEndian>(width).unwrap();
+        data.write_u16::<LittleEndian>(height).unwrap();
+        data.push(bits);
+        data.push(descriptor);
+        data
+    }
+
+    #[test]
+    fn test_load_bottom_up() {
+        let mut data = header(TYPE_TRUECOLOR, 1, 2, 24, 0);
+        // bottom row (blue), then top row (red), in BGR order
+        data.extend_from_slice(&[0xFF, 0, 0, 0, 0, 0xFF]);
+
+        let image = load(Cursor::new(data)).unwrap();
+        assert_eq!((image.width(), image.height()), (1, 2));
+        assert_eq!(image.into_rgba(), vec![0xFF, 0, 0, 0xFF, 0, 0, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn test_load_rle() {
+        let mut data = header(TYPE_RLE_TRUECOLOR, 3, 1, 32, DESCRIPTOR_TOP_LEFT);
+        // a run of two green pixels, then one raw half-transparent white pixel
+        data.extend_from_slice(&[0x81, 0, 0xFF, 0, 0xFF, 0x00, 0xFF, 0xFF, 0xFF, 0x80]);
+
+        let image = load(Cursor::new(data)).unwrap();
+        assert_eq!(
+            image.into_rgba(),
+            vec![0, 0xFF, 0, 0xFF, 0, 0xFF, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0x80]
+        );
+    }
+
+    #[test]
+    fn test_load_rle_overflow() {
+        let mut data = header(TYPE_RLE_GRAYSCALE, 2, 1, 8, 0);
+        data.extend_from_slice(&[0x82, 0x40]);
+        assert!(load(Cursor::new(data)).is_err());
+    }
+}
@@ -15,16 +15,18 @@
 // DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{Cursor, Read, Seek, SeekFrom};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
 use common::pak::Pak;
+use common::pk3::Pk3;
 
 use failure::Error;
 
 enum VfsComponent {
     Pak(Pak),
+    Pk3(Pk3),
     Directory(PathBuf),
 }
 
@@ -48,6 +50,16 @@ impl Vfs {
         Ok(())
     }
 
+    /// Mounts a PK3 (zip) archive. Like PAK files, later archives take precedence.
+    pub fn add_pk3file<P>(&mut self, path: P) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+    {
+        self.components.push(VfsComponent::Pk3(Pk3::new(path)?));
+
+        Ok(())
+    }
+
     pub fn add_directory<P>(&mut self, path: P) -> Result<(), Error>
     where
         P: AsRef<Path>,
@@ -72,6 +84,12 @@ impl Vfs {
                     }
                 }
 
+                VfsComponent::Pk3(pk3) => {
+                    if pk3.contains(vp) {
+                        return Ok(VirtualFile::Pk3Backed(Cursor::new(pk3.open(vp)?)));
+                    }
+                }
+
                 VfsComponent::Directory(path) => {
                     let mut full_path = path.to_owned();
                     full_path.push(vp);
@@ -85,10 +103,57 @@ impl Vfs {
 
         bail!("File not found.");
     }
+
+    /// Create a file for writing at `virtual_path`.
+    ///
+    /// Files are always written to the most recently added directory component, creating any
+    /// intermediate directories as needed. Archives are never written to.
+    ///
+    /// Absolute paths and paths containing `..` are rejected, so that a path can never escape the
+    /// game directory.
+    pub fn create<S>(&self, virtual_path: S) -> Result<File, Error>
+    where
+        S: AsRef<str>,
+    {
+        check_writable_path(virtual_path.as_ref())?;
+
+        for c in self.components.iter().rev() {
+            if let VfsComponent::Directory(path) = c {
+                let mut full_path = path.to_owned();
+                full_path.push(virtual_path.as_ref());
+
+                if let Some(parent) = full_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+
+                return Ok(File::create(full_path)?);
+            }
+        }
+
+        bail!("No writable directory in search path.");
+    }
+}
+
+// Ensures that `virtual_path` names a file inside the directory it is joined to.
+fn check_writable_path(virtual_path: &str) -> Result<(), Error> {
+    ensure!(!virtual_path.is_empty(), "Empty path");
+
+    for component in Path::new(virtual_path).components() {
+        match component {
+            Component::Normal(_) | Component::CurDir => (),
+            _ => bail!(
+                "Invalid path \"{}\": must be relative and inside the game directory",
+                virtual_path
+            ),
+        }
+    }
+
+    Ok(())
 }
 
 pub enum VirtualFile<'a> {
     PakBacked(Cursor<&'a [u8]>),
+    Pk3Backed(Cursor<Vec<u8>>),
     FileBacked(File),
 }
 
@@ -96,6 +161,7 @@ impl<'a> Read for VirtualFile<'a> {
     fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
         match self {
             VirtualFile::PakBacked(data) => data.read(buf),
+            VirtualFile::Pk3Backed(data) => data.read(buf),
             VirtualFile::FileBacked(file) => file.read(buf),
         }
     }
@@ -105,7 +171,33 @@ impl<'a> Seek for VirtualFile<'a> {
     fn seek(&mut self, pos: SeekFrom) -> ::std::io::Result<u64> {
         match self {
             VirtualFile::PakBacked(data) => data.seek(pos),
+            VirtualFile::Pk3Backed(data) => data.seek(pos),
             VirtualFile::FileBacked(file) => file.seek(pos),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_writable_path() {
+        assert!(check_writable_path("config.cfg").is_ok());
+        assert!(check_writable_path("demos/demo1.dem").is_ok());
+        assert!(check_writable_path("./condump.txt").is_ok());
+
+        assert!(check_writable_path("").is_err());
+        assert!(check_writable_path("/etc/passwd").is_err());
+        assert!(check_writable_path("../config.cfg").is_err());
+        assert!(check_writable_path("maps/../../config.cfg").is_err());
+    }
+
+    #[test]
+    fn test_create_rejects_escaping_paths() {
+        let mut vfs = Vfs::new();
+        vfs.add_directory(::std::env::temp_dir()).unwrap();
+        assert!(vfs.create("../richter_vfs_escape.txt").is_err());
+        assert!(vfs.create("/tmp/richter_vfs_escape.txt").is_err());
+    }
+}
@@ -26,6 +26,7 @@ use std::io::Seek;
 use std::io::SeekFrom;
 
 use common::util;
+use common::vfs::Vfs;
 
 use byteorder::LittleEndian;
 use byteorder::ReadBytesExt;
@@ -36,6 +37,50 @@ use failure::Error;
 const LUMPINFO_SIZE: usize = 32;
 const MAGIC: u32 = 'W' as u32 | ('A' as u32) << 8 | ('D' as u32) << 16 | ('2' as u32) << 24;
 
+/// The type of a lump in a WAD2 file.
+///
+/// See the `TYP_*` definitions in
+/// https://github.com/id-Software/Quake/blob/master/WinQuake/wad.h#L27-L35
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LumpKind {
+    Palette,
+    QTex,
+    /// A picture with a width and height, as read by `QPic::load`.
+    QPic,
+    Sound,
+    MipTex,
+    Other(u8),
+}
+
+impl LumpKind {
+    pub fn from_u8(value: u8) -> LumpKind {
+        match value {
+            0x40 => LumpKind::Palette,
+            0x41 => LumpKind::QTex,
+            0x42 => LumpKind::QPic,
+            0x43 => LumpKind::Sound,
+            0x44 => LumpKind::MipTex,
+            v => LumpKind::Other(v),
+        }
+    }
+}
+
+/// Returns the name of the `gfx.wad` lump that may hold the picture file at `path`, e.g. `disc`
+/// for `gfx/disc.lmp`.
+pub fn lump_name(path: &str) -> &str {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    if file_name.ends_with(".lmp") {
+        &file_name[..file_name.len() - 4]
+    } else {
+        file_name
+    }
+}
+
+// lump names are matched without regard to case, like the original engine's `W_CleanupName`
+fn cleanup_name<S>(name: S) -> String where S: AsRef<str> {
+    name.as_ref().to_uppercase()
+}
+
 pub struct QPic {
     width: u32,
     height: u32,
@@ -43,14 +88,24 @@ pub struct QPic {
 }
 
 impl QPic {
+    /// Reads a picture: its width and height as 32-bit integers, followed by one palette index
+    /// per pixel, row by row.
     pub fn load<R>(data: R) -> Result<QPic, Error> where R: Read + Seek {
         let mut reader = BufReader::new(data);
 
         let width = reader.read_u32::<LittleEndian>()?;
         let height = reader.read_u32::<LittleEndian>()?;
 
+        let size = width as u64 * height as u64;
         let mut indices = Vec::new();
-        (&mut reader).take((width * height) as u64).read_to_end(&mut indices)?;
+        (&mut reader).take(size).read_to_end(&mut indices)?;
+        ensure!(
+            indices.len() as u64 == size,
+            "Picture data is too short: got {} pixels, should be {}x{}",
+            indices.len(),
+            width,
+            height
+        );
 
         Ok(QPic {
             width,
@@ -75,15 +130,24 @@ impl QPic {
 struct LumpInfo {
     offset: u32,
     size: u32,
+    kind: LumpKind,
     name: String,
 }
 
+/// A WAD2 archive, such as `gfx.wad`, which holds the pictures of the status bar and console.
 pub struct Wad {
-    files: HashMap<String, Box<[u8]>>,
+    files: HashMap<String, (LumpKind, Box<[u8]>)>,
 }
 
 impl Wad {
-    pub fn load<R>(data: R) -> Result <Wad, Error> where R: Read + Seek {
+    /// Loads the WAD2 archive at `path` in the virtual filesystem.
+    pub fn load<S>(vfs: &Vfs, path: S) -> Result<Wad, Error> where S: AsRef<str> {
+        Wad::read(vfs.open(path.as_ref())?)
+    }
+
+    /// Reads a WAD2 archive: a header giving the number and location of the lumps, followed by
+    /// the lump data and a directory describing each lump.
+    pub fn read<R>(data: R) -> Result <Wad, Error> where R: Read + Seek {
         let mut reader = BufReader::new(data);
 
         let magic = reader.read_u32::<LittleEndian>()?;
@@ -101,7 +165,7 @@ impl Wad {
             let offset = reader.read_u32::<LittleEndian>()?;
             let _size_on_disk = reader.read_u32::<LittleEndian>()?;
             let size = reader.read_u32::<LittleEndian>()?;
-            let _type = reader.read_u8()?;
+            let kind = LumpKind::from_u8(reader.read_u8()?);
             let _compression = reader.read_u8()?;
             let _pad = reader.read_u16::<LittleEndian>()?;
             let mut name_bytes = [0u8; 16];
@@ -113,7 +177,8 @@ impl Wad {
             lump_infos.push(LumpInfo {
                 offset,
                 size,
-                name
+                kind,
+                name: cleanup_name(name),
             });
         }
 
@@ -123,15 +188,30 @@ impl Wad {
             let mut data = Vec::with_capacity(lump_info.size as usize);
             reader.seek(SeekFrom::Start(lump_info.offset as u64))?;
             (&mut reader).take(lump_info.size as u64).read_to_end(&mut data)?;
-            files.insert(lump_info.name.to_owned(), data.into_boxed_slice());
+            ensure!(
+                data.len() == lump_info.size as usize,
+                "Lump {} extends past the end of the WAD",
+                lump_info.name
+            );
+            files.insert(lump_info.name, (lump_info.kind, data.into_boxed_slice()));
         }
 
         Ok(Wad { files })
     }
 
+    /// Returns the data of the lump `name`, if it exists.
+    pub fn entry<S>(&self, name: S) -> Option<&[u8]> where S: AsRef<str> {
+        self.files.get(&cleanup_name(name)).map(|&(_, ref data)| &data[..])
+    }
+
+    /// Returns the type of the lump `name`, if it exists.
+    pub fn entry_kind<S>(&self, name: S) -> Option<LumpKind> where S: AsRef<str> {
+        self.files.get(&cleanup_name(name)).map(|&(kind, _)| kind)
+    }
+
     pub fn open_conchars(&self) -> Result<QPic, Error> {
-        match self.files.get("CONCHARS") {
-            Some(ref data) => {
+        match self.entry("CONCHARS") {
+            Some(data) => {
                 let width = 128;
                 let height = 128;
                 let indices = Vec::from(&data[..(width * height) as usize]);
@@ -147,15 +227,83 @@ impl Wad {
         }
     }
 
+    /// Reads the picture in the lump `name`.
+    ///
+    /// Returns an error if there is no such lump or it isn't a picture.
     pub fn open_qpic<S>(&self, name: S) -> Result<QPic, Error> where S: AsRef<str> {
-        if name.as_ref() == "CONCHARS" {
+        let name = cleanup_name(name);
+        if name == "CONCHARS" {
             bail!("conchars must be opened with open_conchars()");
         }
 
-        match self.files.get(name.as_ref()) {
-            Some(ref data) => QPic::load(Cursor::new(data)),
-            None => bail!("File not found in WAD: {}", name.as_ref()),
+        match self.files.get(&name) {
+            Some(&(LumpKind::QPic, ref data)) => QPic::load(Cursor::new(data)),
+            Some(&(kind, _)) => bail!("{} is not a picture (type {:?})", name, kind),
+            None => bail!("File not found in WAD: {}", name),
         }
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use byteorder::WriteBytesExt;
+
+    // builds a WAD2 with a single lump
+    fn wad_bytes(name: &str, kind: u8, lump: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.write_u32::<LittleEndian>(MAGIC).unwrap();
+        data.write_u32::<LittleEndian>(1).unwrap();
+        data.write_u32::<LittleEndian>(12 + lump.len() as u32).unwrap();
+        data.extend_from_slice(lump);
+
+        data.write_u32::<LittleEndian>(12).unwrap();
+        data.write_u32::<LittleEndian>(lump.len() as u32).unwrap();
+        data.write_u32::<LittleEndian>(lump.len() as u32).unwrap();
+        data.push(kind);
+        data.push(0);
+        data.write_u16::<LittleEndian>(0).unwrap();
+        let mut name_bytes = [0u8; 16];
+        name_bytes[..name.len()].copy_from_slice(name.as_bytes());
+        data.extend_from_slice(&name_bytes);
+
+        assert_eq!(data.len(), 12 + lump.len() + LUMPINFO_SIZE);
+        data
+    }
+
+    #[test]
+    fn test_wad_qpic() {
+        // a 3x2 picture
+        let lump = [3, 0, 0, 0, 2, 0, 0, 0, 10, 11, 12, 20, 21, 22];
+        let wad = Wad::read(Cursor::new(wad_bytes("sb_test", 0x42, &lump))).unwrap();
+
+        assert_eq!(wad.entry("SB_TEST"), Some(&lump[..]));
+        assert_eq!(wad.entry_kind("sb_test"), Some(LumpKind::QPic));
+        assert_eq!(wad.entry("missing"), None);
+
+        let qpic = wad.open_qpic("sb_test").unwrap();
+        assert_eq!((qpic.width(), qpic.height()), (3, 2));
+        assert_eq!(qpic.indices(), &[10, 11, 12, 20, 21, 22]);
+    }
+
+    #[test]
+    fn test_lump_name() {
+        assert_eq!(lump_name("gfx/disc.lmp"), "disc");
+        assert_eq!(lump_name("sb_health"), "sb_health");
+    }
+
+    #[test]
+    fn test_wad_rejects_non_qpic() {
+        let wad = Wad::read(Cursor::new(wad_bytes("pal", 0x40, &[0; 8]))).unwrap();
+        assert!(wad.open_qpic("pal").is_err());
+
+        // truncated picture data
+        let wad = Wad::read(Cursor::new(wad_bytes("short", 0x42, &[4, 0, 0, 0, 4, 0, 0, 0, 1])))
+            .unwrap();
+        assert!(wad.open_qpic("short").is_err());
+
+        assert!(Wad::read(Cursor::new(b"WAD3".to_vec())).is_err());
+    }
+}
@@ -27,6 +27,7 @@ extern crate env_logger;
 #[macro_use]
 extern crate failure;
 extern crate flame;
+extern crate flate2;
 #[macro_use]
 extern crate gfx;
 extern crate gfx_device_gl;
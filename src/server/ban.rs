@@ -0,0 +1,144 @@
+// Copyright © 2018 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+use std::net::IpAddr;
+
+use common::vfs::Vfs;
+
+use failure::Error;
+
+/// The file the ban list is stored in.
+pub const BAN_LIST_FILE: &str = "banlist.txt";
+
+/// A set of addresses that are refused when they try to connect.
+#[derive(Clone, Debug, Default)]
+pub struct BanList {
+    addrs: BTreeSet<IpAddr>,
+}
+
+impl BanList {
+    pub fn new() -> BanList {
+        BanList::default()
+    }
+
+    /// Parses a ban list with one address per line.
+    ///
+    /// Blank lines and lines starting with `//` are ignored.
+    pub fn parse<S>(src: S) -> Result<BanList, Error>
+    where
+        S: AsRef<str>,
+    {
+        let mut addrs = BTreeSet::new();
+        for line in src.as_ref().lines().map(|l| l.trim()) {
+            if line.is_empty() || line.starts_with("//") {
+                continue;
+            }
+
+            match line.parse() {
+                Ok(addr) => {
+                    addrs.insert(addr);
+                }
+                Err(_) => bail!("Invalid address in ban list: {}", line),
+            }
+        }
+
+        Ok(BanList { addrs })
+    }
+
+    /// Loads the ban list from `BAN_LIST_FILE`, or returns an empty list if there isn't one.
+    pub fn load(vfs: &Vfs) -> Result<BanList, Error> {
+        let mut file = match vfs.open(BAN_LIST_FILE) {
+            Ok(f) => f,
+            Err(_) => return Ok(BanList::new()),
+        };
+
+        let mut src = String::new();
+        file.read_to_string(&mut src)?;
+        BanList::parse(src)
+    }
+
+    /// Writes the ban list to `BAN_LIST_FILE`.
+    pub fn save(&self, vfs: &Vfs) -> Result<(), Error> {
+        let mut file = vfs.create(BAN_LIST_FILE)?;
+        file.write_all(self.to_string().as_bytes())?;
+        Ok(())
+    }
+
+    /// Adds `addr` to the ban list. Returns `false` if it was already banned.
+    pub fn ban(&mut self, addr: IpAddr) -> bool {
+        self.addrs.insert(addr)
+    }
+
+    /// Removes `addr` from the ban list. Returns `false` if it wasn't banned.
+    pub fn unban(&mut self, addr: IpAddr) -> bool {
+        self.addrs.remove(&addr)
+    }
+
+    pub fn is_banned(&self, addr: IpAddr) -> bool {
+        self.addrs.contains(&addr)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &IpAddr> {
+        self.addrs.iter()
+    }
+}
+
+impl ::std::fmt::Display for BanList {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        for addr in self.addrs.iter() {
+            writeln!(f, "{}", addr)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ban_list_round_trip() {
+        let mut bans = BanList::new();
+        assert!(bans.ban("10.0.0.2".parse().unwrap()));
+        assert!(bans.ban("::1".parse().unwrap()));
+        assert!(!bans.ban("10.0.0.2".parse().unwrap()));
+
+        let parsed = BanList::parse(bans.to_string()).unwrap();
+        assert!(parsed.is_banned("10.0.0.2".parse().unwrap()));
+        assert!(parsed.is_banned("::1".parse().unwrap()));
+        assert!(!parsed.is_banned("10.0.0.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_ban_list_parse() {
+        let bans = BanList::parse("// banned for spamming\n192.168.1.1\n\n").unwrap();
+        assert_eq!(bans.iter().count(), 1);
+        assert!(BanList::parse("not an address").is_err());
+    }
+
+    #[test]
+    fn test_ban_list_unban() {
+        let mut bans = BanList::new();
+        bans.ban("192.168.1.1".parse().unwrap());
+        assert!(bans.unban("192.168.1.1".parse().unwrap()));
+        assert!(!bans.unban("192.168.1.1".parse().unwrap()));
+        assert!(!bans.is_banned("192.168.1.1".parse().unwrap()));
+    }
+}
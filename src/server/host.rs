@@ -0,0 +1,640 @@
+// Copyright © 2018 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! The network side of a running server: accepting clients and exchanging messages with them.
+
+use std::cell::RefCell;
+use std::io::Cursor;
+use std::net::{SocketAddr, UdpSocket};
+use std::rc::Rc;
+
+use common::console::{CmdRegistry, CvarRegistry};
+use common::engine;
+use common::net::connect::{
+    ConnectListener, Request, RequestConnect, Response, ResponseAccept, ResponseReject,
+    CONNECT_PROTOCOL_VERSION,
+};
+use common::net::{self, BlockingMode, ClientCmd, NetError, QSocket, ServerCmd};
+use common::parse;
+use common::vfs::Vfs;
+
+use server::ban::BanList;
+use server::limits::{MatchLimits, MatchState};
+use server::maplist::{map_exists, register_rotation_cmds, MapRotation};
+use server::progs::EntityId;
+use server::world::{FieldAddrFloat, FieldAddrVector, World};
+use server::{
+    bind_listener, client_pvs, encode_entity_updates, entity_in_pvs, register_admin_cmds,
+    screen_connection, ClientInGame, ClientSlot, ServerStatics,
+};
+
+use cgmath::Vector3;
+use chrono::Duration;
+use combine::Parser;
+use failure::Error;
+
+/// Accepts new clients and reads their messages once per server frame.
+pub struct ServerHost {
+    listener: ConnectListener,
+    statics: Rc<RefCell<ServerStatics>>,
+    bans: Rc<RefCell<BanList>>,
+    rotation: Rc<RefCell<MapRotation>>,
+    match_state: MatchState,
+    vfs: Rc<Vfs>,
+    time: Duration,
+}
+
+impl ServerHost {
+    /// Starts accepting clients on the address given by `sv_address` and `sv_port`.
+    ///
+    /// The number of client slots is taken from `maxplayers`. The ban list and map rotation are
+    /// loaded from the `Vfs`, and the commands that manage them are added to `cmds`.
+    pub fn new(
+        vfs: Rc<Vfs>,
+        cvars: &CvarRegistry,
+        cmds: &mut CmdRegistry,
+    ) -> Result<ServerHost, Error> {
+        let listener = bind_listener(cvars)?;
+        let statics = Rc::new(RefCell::new(ServerStatics::from_cvars(cvars)));
+        let slot_statics = statics.clone();
+        cvars
+            .set_callback(
+                "maxplayers",
+                Box::new(move |value| {
+                    slot_statics.borrow().check_slot_limit(value);
+                }),
+            )
+            .map_err(|_| format_err!("maxplayers is not registered"))?;
+
+        let bans = Rc::new(RefCell::new(BanList::load(&vfs)?));
+        register_admin_cmds(cmds, statics.clone(), bans.clone(), vfs.clone());
+
+        let rotation = Rc::new(RefCell::new(MapRotation::load(&vfs, cvars)?));
+        register_rotation_cmds(cmds, rotation.clone(), vfs.clone());
+
+        Ok(ServerHost {
+            listener,
+            statics,
+            bans,
+            rotation,
+            match_state: MatchState::new(MatchLimits::from_cvars(cvars)),
+            vfs,
+            time: Duration::zero(),
+        })
+    }
+
+    /// Moves the server to `map` once its level has been loaded.
+    ///
+    /// The client slots are resized to `maxplayers`, dropping anyone past the new limit, and
+    /// every client has to sign on again. The match limits are reset from `fraglimit` and
+    /// `timelimit`.
+    pub fn change_map(&mut self, cvars: &CvarRegistry, map: &str) {
+        println!("Changing to {}", map);
+        self.rotation.borrow_mut().set_current(map);
+        self.match_state = MatchState::new(MatchLimits::from_cvars(cvars));
+        self.time = Duration::zero();
+
+        let mut statics = self.statics.borrow_mut();
+        let limit = ServerStatics::from_cvars(cvars).client_slot_limit();
+        for mut c in statics.resize(limit) {
+            println!("Dropped {} ({}): server is now full", c.name(), c.addr());
+            let _ = c.send_disconnect();
+        }
+
+        for slot in statics.client_slots.iter_mut() {
+            if let ClientSlot::InGame(ref mut c) = *slot {
+                c.spawned = false;
+            }
+        }
+    }
+
+    /// Returns the address clients connect to.
+    pub fn local_addr(&self) -> Result<SocketAddr, NetError> {
+        self.listener.local_addr()
+    }
+
+    /// Runs a server frame.
+    ///
+    /// The match limits are only checked and entity updates only sent if a level is loaded into
+    /// `world`. If `nextmap` or `map_restart` was used, or the intermission after a match is
+    /// over, returns the map to load and pass to `change_map`.
+    pub fn frame(
+        &mut self,
+        frame_time: Duration,
+        world: Option<&World>,
+    ) -> Result<Option<String>, Error> {
+        self.time = self.time + frame_time;
+        self.check_new_clients()?;
+        self.read_client_messages();
+
+        if let Some(world) = world {
+            self.check_match_limits(frame_time, world)?;
+            self.send_client_messages(frame_time, world)?;
+        }
+
+        Ok(self.rotation.borrow_mut().take_pending_change())
+    }
+
+    /// Answers the connection requests received since the last frame.
+    fn check_new_clients(&mut self) -> Result<(), Error> {
+        loop {
+            let (request, remote) = match self.listener.try_recv_request() {
+                Ok(Some(r)) => r,
+                Ok(None) => return Ok(()),
+
+                // anyone can send us garbage, so this shouldn't stop the server. anything else
+                // waiting will be picked up next frame.
+                Err(e) => {
+                    warn!("Invalid connection request: {}", e);
+                    return Ok(());
+                }
+            };
+
+            match request {
+                Request::Connect(connect) => self.accept(connect, remote)?,
+
+                // TODO: answer server, player and rule info queries
+                _ => debug!("Ignoring query from {}", remote),
+            }
+        }
+    }
+
+    /// Handles a connection request from `remote`.
+    ///
+    /// If the client is allowed in, it's given a slot and its own socket to talk to the server
+    /// on, and the port of that socket is sent back in the accept response.
+    fn accept(&mut self, connect: RequestConnect, remote: SocketAddr) -> Result<(), Error> {
+        if connect.game_name != net::GAME_NAME || connect.proto_ver != CONNECT_PROTOCOL_VERSION {
+            return self.reject(remote, "Incompatible version.\n");
+        }
+
+        let refusal = screen_connection(&self.bans.borrow(), &self.statics.borrow(), remote);
+        if let Some(response) = refusal {
+            println!("Refused connection from {}", remote);
+            self.listener.send_response(response, remote)?;
+            return Ok(());
+        }
+
+        let mut local = self.listener.local_addr()?;
+        local.set_port(0);
+        let socket = UdpSocket::bind(local)?;
+        let port = socket.local_addr()?.port();
+
+        let slot_id = match self
+            .statics
+            .borrow_mut()
+            .connect(QSocket::new(socket, remote))
+        {
+            Some(s) => s,
+            None => return self.reject(remote, "Server is full.\n"),
+        };
+
+        // TODO: send the signon messages for the current level
+        println!("Client {} connected from {}", slot_id, remote);
+        self.listener.send_response(
+            Response::Accept(ResponseAccept { port: port as i32 }),
+            remote,
+        )?;
+
+        Ok(())
+    }
+
+    fn reject(&self, remote: SocketAddr, message: &str) -> Result<(), Error> {
+        self.listener.send_response(
+            Response::Reject(ResponseReject {
+                message: message.to_owned(),
+            }),
+            remote,
+        )?;
+
+        Ok(())
+    }
+
+    /// Handles the messages each client has sent since the last frame.
+    ///
+    /// A client that sends something unreadable is dropped, and one that disconnects has its slot
+    /// freed.
+    fn read_client_messages(&mut self) {
+        let mut statics = self.statics.borrow_mut();
+        for slot_id in 0..statics.client_slots.len() {
+            let (addr, result) = match statics.client_slots[slot_id] {
+                ClientSlot::InGame(ref mut c) => (c.addr(), read_client(c)),
+                ClientSlot::Disconnected => continue,
+            };
+
+            match result {
+                Ok(true) => (),
+                Ok(false) => {
+                    if let Some(c) = statics.disconnect(addr) {
+                        println!("{} disconnected", c.name());
+                    }
+                }
+                Err(e) => drop_client(&mut statics, slot_id, e),
+            }
+        }
+    }
+
+    /// Ends the match once a player reaches `fraglimit` or `timelimit` runs out, and moves on to
+    /// the next map in the rotation after the intermission.
+    fn check_match_limits(&mut self, frame_time: Duration, world: &World) -> Result<(), Error> {
+        let mut statics = self.statics.borrow_mut();
+
+        let mut frags = Vec::new();
+        for slot in statics.client_slots.iter() {
+            if let ClientSlot::InGame(ref c) = *slot {
+                let entity = world.try_get_entity(c.entity_id)?;
+                let f = entity.get_float(FieldAddrFloat::Frags as i16)?;
+                frags.push((c.name(), f as i32));
+            }
+        }
+
+        let cmds = self.match_state.update(frame_time, frags);
+        for slot in statics.client_slots.iter_mut() {
+            if let ClientSlot::InGame(ref mut c) = *slot {
+                for cmd in cmds.iter() {
+                    c.queue_reliable(cmd)?;
+                }
+            }
+        }
+
+        let vfs = &self.vfs;
+        self.match_state
+            .finish_intermission(&mut self.rotation.borrow_mut(), |m| map_exists(vfs, m));
+
+        Ok(())
+    }
+
+    /// Sends each spawned client its datagram for this frame.
+    ///
+    /// Entity updates are packed in entity order until the client's rate runs out. Whatever
+    /// doesn't fit is sent with the entity's newer state in a later frame.
+    fn send_client_messages(&mut self, frame_time: Duration, world: &World) -> Result<(), Error> {
+        let mut header = Vec::new();
+        ServerCmd::Time {
+            time: engine::duration_to_f32(self.time),
+        }
+        .serialize(&mut header)?;
+
+        let mut statics = self.statics.borrow_mut();
+        for slot_id in 0..statics.client_slots.len() {
+            let client = match statics.client_slots[slot_id] {
+                ClientSlot::InGame(ref mut c) if c.spawned => c,
+                _ => continue,
+            };
+
+            let updates = entity_updates(world, client.entity_id)?;
+            let packed = client.pack_updates(frame_time, updates.iter().map(|u| u.as_slice()));
+            let mut msg = header.clone();
+            msg.extend(packed);
+
+            let result = client
+                .flush_reliable()
+                .and_then(|_| client.qsock.send_msg_unreliable(&msg));
+            if let Err(e) = result {
+                drop_client(&mut statics, slot_id, e);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn drop_client<E>(statics: &mut ServerStatics, slot_id: usize, reason: E)
+where
+    E: ::std::fmt::Display,
+{
+    if let Some(mut c) = statics.kick(slot_id) {
+        println!("Dropped {} ({}): {}", c.name(), c.addr(), reason);
+        let _ = c.send_disconnect();
+    }
+}
+
+/// Encodes an update for each entity the client controlling `viewer` might be able to see, as a
+/// delta against its baseline.
+fn entity_updates(world: &World, viewer: EntityId) -> Result<Vec<Vec<u8>>, Error> {
+    let viewer_entity = world.try_get_entity(viewer)?;
+    let view_offset: Vector3<f32> = viewer_entity
+        .get_vector(FieldAddrVector::ViewOffset as i16)?
+        .into();
+    let view_origin = viewer_entity.origin()? + view_offset;
+    let pvs = world
+        .world_bsp()
+        .and_then(|bsp_data| client_pvs(&bsp_data, view_origin));
+
+    let mut states = Vec::new();
+
+    // the world itself is sent with the level, not as an update
+    for (entity_id, entity) in world.entities().skip(1) {
+        // clients always know where they are
+        if entity_id != viewer && !entity_in_pvs(pvs.as_ref(), entity.touched_leaves()) {
+            continue;
+        }
+
+        let state = entity.state()?;
+        if state.model_id != 0 {
+            states.push((entity_id.0 as u16, &entity.baseline, state));
+        }
+    }
+
+    let updates = encode_entity_updates(
+        states
+            .iter()
+            .map(|&(ent_id, baseline, ref state)| (ent_id, baseline, state)),
+    )?;
+
+    Ok(updates)
+}
+
+/// Reads the messages `client` has sent since the last frame.
+///
+/// Returns whether the client is still connected.
+fn read_client(client: &mut ClientInGame) -> Result<bool, Error> {
+    loop {
+        let msg = client.qsock.recv_msg(BlockingMode::NonBlocking)?;
+        if msg.is_empty() {
+            return Ok(true);
+        }
+
+        let mut reader = Cursor::new(msg.as_slice());
+        while (reader.position() as usize) < msg.len() {
+            match ClientCmd::deserialize(&mut reader)? {
+                ClientCmd::StringCmd { cmd } => {
+                    for args in parse_string_cmd(&cmd) {
+                        exec_string_cmd(client, &args);
+                    }
+                }
+
+                // anything sent after this is ignored
+                ClientCmd::Disconnect => return Ok(false),
+
+                // TODO: run player movement
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Splits a string command from a client into the arguments of each command it contains.
+fn parse_string_cmd(cmd: &str) -> Vec<Vec<String>> {
+    // the client doesn't always terminate its commands
+    let text = format!("{}\n", cmd.trim_end());
+    match parse::commands().easy_parse(text.as_str()) {
+        Ok((commands, _)) => commands,
+        Err(e) => {
+            warn!("Couldn't parse string command {:?}: {:?}", cmd, e);
+            Vec::new()
+        }
+    }
+}
+
+fn exec_string_cmd(client: &mut ClientInGame, args: &[String]) {
+    match args[0].as_str() {
+        "name" => match args.get(1) {
+            Some(name) => client.set_name(name),
+            None => debug!("{} sent name without an argument", client.name()),
+        },
+
+        "rate" => match args.get(1).and_then(|r| r.parse::<u32>().ok()) {
+            Some(rate) => client.set_rate(rate),
+            None => debug!("{} sent an invalid rate: {:?}", client.name(), args),
+        },
+
+        "begin" => client.spawned = true,
+
+        _ => debug!("Unhandled string command from {}: {:?}", client.name(), args),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::net::connect::ConnectSocket;
+    use server::{rate, register_cvars};
+
+    fn host_with_cvars(cvars: &CvarRegistry, cmds: &mut CmdRegistry) -> ServerHost {
+        // let the system pick the port so tests can run side by side
+        cvars.set("sv_address", "127.0.0.1").unwrap();
+        cvars.set("sv_port", "0").unwrap();
+        ServerHost::new(Rc::new(Vfs::new()), cvars, cmds).unwrap()
+    }
+
+    fn host(cmds: &mut CmdRegistry) -> ServerHost {
+        let cvars = CvarRegistry::new();
+        register_cvars(&cvars);
+        cvars.set("maxplayers", "4").unwrap();
+        host_with_cvars(&cvars, cmds)
+    }
+
+    fn request_connect(host: &mut ServerHost, sock: &mut ConnectSocket) -> Response {
+        sock.send_request(
+            Request::connect(net::GAME_NAME, CONNECT_PROTOCOL_VERSION),
+            host.local_addr().unwrap(),
+        )
+        .unwrap();
+        host.frame(Duration::milliseconds(10), None).unwrap();
+
+        let (response, _) = sock
+            .recv_response(Some(Duration::seconds(1)))
+            .unwrap()
+            .expect("no response from server");
+        response
+    }
+
+    #[test]
+    fn test_ban_refuses_connection() {
+        let mut cmds = CmdRegistry::new();
+        let mut host = host(&mut cmds);
+        let mut sock = ConnectSocket::bind("127.0.0.1:0").unwrap();
+
+        match request_connect(&mut host, &mut sock) {
+            Response::Accept(accept) => assert!(accept.port > 0),
+            r => panic!("connection was not accepted: {:?}", r),
+        }
+        assert_eq!(host.statics.borrow().client_slot_count, 1);
+
+        // banning the address drops the client that's already connected
+        cmds.exec("ban", &["127.0.0.1"]).unwrap();
+        assert_eq!(host.statics.borrow().client_slot_count, 0);
+
+        // the first socket was sent the disconnect, so come back from a new one
+        let mut sock = ConnectSocket::bind("127.0.0.1:0").unwrap();
+        match request_connect(&mut host, &mut sock) {
+            Response::Reject(reject) => assert_eq!(reject.message, "You have been banned.\n"),
+            r => panic!("banned connection was not rejected: {:?}", r),
+        }
+
+        cmds.exec("unban", &["127.0.0.1"]).unwrap();
+        match request_connect(&mut host, &mut sock) {
+            Response::Accept(_) => (),
+            r => panic!("connection was not accepted after unban: {:?}", r),
+        }
+    }
+
+    /// Connects a client and returns the socket it talks to the server on.
+    fn connect_client(host: &mut ServerHost) -> QSocket {
+        let mut sock = ConnectSocket::bind("127.0.0.1:0").unwrap();
+        let port = match request_connect(host, &mut sock) {
+            Response::Accept(accept) => accept.port as u16,
+            r => panic!("connection was not accepted: {:?}", r),
+        };
+
+        let mut server_addr = host.local_addr().unwrap();
+        server_addr.set_port(port);
+        sock.into_qsocket(server_addr)
+    }
+
+    fn send_string_cmd(host: &mut ServerHost, qsock: &mut QSocket, cmd: &str) {
+        let mut msg = Vec::new();
+        ClientCmd::StringCmd {
+            cmd: cmd.to_owned(),
+        }
+        .serialize(&mut msg)
+        .unwrap();
+        qsock.send_msg_unreliable(&msg).unwrap();
+        host.frame(Duration::milliseconds(10), None).unwrap();
+    }
+
+    #[test]
+    fn test_kick_by_name() {
+        let mut cmds = CmdRegistry::new();
+        let mut host = host(&mut cmds);
+        let mut qsock = connect_client(&mut host);
+
+        // name ourselves the way the client does during signon
+        send_string_cmd(&mut host, &mut qsock, "name \"ranger\"\n");
+
+        cmds.exec("kick", &["ranger"]).unwrap();
+        assert_eq!(host.statics.borrow().client_slot_count, 0);
+
+        // the client is told it was dropped
+        let msg = qsock
+            .recv_msg(BlockingMode::Timeout(Duration::seconds(1)))
+            .unwrap();
+        assert_eq!(msg, vec![net::ServerCmdCode::Disconnect as u8]);
+    }
+
+    #[test]
+    fn test_server_full_refuses_connection() {
+        let cvars = CvarRegistry::new();
+        register_cvars(&cvars);
+        cvars.set("maxplayers", "2").unwrap();
+        let mut cmds = CmdRegistry::new();
+        let mut host = host_with_cvars(&cvars, &mut cmds);
+
+        for _ in 0..2 {
+            let mut sock = ConnectSocket::bind("127.0.0.1:0").unwrap();
+            match request_connect(&mut host, &mut sock) {
+                Response::Accept(_) => (),
+                r => panic!("connection was not accepted: {:?}", r),
+            }
+        }
+
+        let mut sock = ConnectSocket::bind("127.0.0.1:0").unwrap();
+        match request_connect(&mut host, &mut sock) {
+            Response::Reject(reject) => assert_eq!(reject.message, "Server is full.\n"),
+            r => panic!("connection to a full server was not rejected: {:?}", r),
+        }
+
+        // raising maxplayers doesn't resize the slots until the next map
+        cvars.set("maxplayers", "3").unwrap();
+        match request_connect(&mut host, &mut sock) {
+            Response::Reject(_) => (),
+            r => panic!("slots were resized mid-map: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_rate_string_cmd() {
+        let mut cmds = CmdRegistry::new();
+        let mut host = host(&mut cmds);
+        let mut qsock = connect_client(&mut host);
+
+        let rate = |host: &ServerHost| match host.statics.borrow().client_slots[0] {
+            ClientSlot::InGame(ref c) => c.rate(),
+            ClientSlot::Disconnected => panic!("client was dropped"),
+        };
+        assert_eq!(rate(&host), rate::DEFAULT_RATE);
+
+        send_string_cmd(&mut host, &mut qsock, "rate 10000");
+        assert_eq!(rate(&host), 10000);
+
+        // requests outside the supported range are clamped
+        send_string_cmd(&mut host, &mut qsock, "rate 1000000");
+        assert_eq!(rate(&host), rate::MAX_RATE);
+    }
+
+    #[test]
+    fn test_disconnect_frees_slot() {
+        let cvars = CvarRegistry::new();
+        register_cvars(&cvars);
+        cvars.set("maxplayers", "1").unwrap();
+        let mut cmds = CmdRegistry::new();
+        let mut host = host_with_cvars(&cvars, &mut cmds);
+        let mut qsock = connect_client(&mut host);
+
+        let mut msg = Vec::new();
+        ClientCmd::Disconnect.serialize(&mut msg).unwrap();
+        qsock.send_msg_unreliable(&msg).unwrap();
+        host.frame(Duration::milliseconds(10), None).unwrap();
+        assert_eq!(host.statics.borrow().client_slot_count, 0);
+
+        // the only slot is free again without waiting for a timeout
+        connect_client(&mut host);
+        assert_eq!(host.statics.borrow().client_slot_count, 1);
+    }
+
+    #[test]
+    fn test_map_restart() {
+        let cvars = CvarRegistry::new();
+        register_cvars(&cvars);
+        cvars.set("sv_maplist", "dm1 dm2").unwrap();
+        let mut cmds = CmdRegistry::new();
+        let mut host = host_with_cvars(&cvars, &mut cmds);
+
+        host.change_map(&cvars, "dm2");
+        cmds.exec("map_restart", &[]).unwrap();
+        let change = host.frame(Duration::milliseconds(10), None).unwrap();
+        assert_eq!(change, Some("dm2".to_owned()));
+
+        // the change is only reported once
+        assert_eq!(host.frame(Duration::milliseconds(10), None).unwrap(), None);
+    }
+
+    #[test]
+    fn test_change_map_resizes_slots() {
+        let cvars = CvarRegistry::new();
+        register_cvars(&cvars);
+        cvars.set("maxplayers", "2").unwrap();
+        let mut cmds = CmdRegistry::new();
+        let mut host = host_with_cvars(&cvars, &mut cmds);
+        connect_client(&mut host);
+        let mut second = connect_client(&mut host);
+
+        cvars.set("maxplayers", "1").unwrap();
+        assert_eq!(host.statics.borrow().client_slot_limit(), 2);
+
+        host.change_map(&cvars, "dm1");
+        assert_eq!(host.statics.borrow().client_slot_limit(), 1);
+        assert_eq!(host.statics.borrow().client_slot_count, 1);
+
+        // the client that no longer fits is told it was dropped
+        let msg = second
+            .recv_msg(BlockingMode::Timeout(Duration::seconds(1)))
+            .unwrap();
+        assert_eq!(msg, vec![net::ServerCmdCode::Disconnect as u8]);
+    }
+}
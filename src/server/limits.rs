@@ -0,0 +1,342 @@
+// Copyright © 2018 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Deathmatch frag and time limits.
+
+use common::console::CvarRegistry;
+use common::net::ServerCmd;
+
+use server::maplist::MapRotation;
+use server::GameRules;
+
+use chrono::Duration;
+
+/// How long the intermission lasts before the next map in the rotation is loaded.
+pub const INTERMISSION_SECONDS: i64 = 10;
+
+/// Why a match ended.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MatchEnd {
+    /// The named player reached the frag limit.
+    FragLimit(String),
+    TimeLimit,
+}
+
+/// The limits set by the `fraglimit` and `timelimit` cvars.
+///
+/// A limit of zero means there is no limit. Limits only apply in deathmatch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MatchLimits {
+    pub fraglimit: i32,
+    pub timelimit: Duration,
+}
+
+impl MatchLimits {
+    pub fn unlimited() -> MatchLimits {
+        MatchLimits {
+            fraglimit: 0,
+            timelimit: Duration::zero(),
+        }
+    }
+
+    /// Reads the limits from the cvars. `timelimit` is in minutes.
+    pub fn from_cvars(cvars: &CvarRegistry) -> MatchLimits {
+        if !GameRules::from_cvars(cvars).is_deathmatch() {
+            return MatchLimits::unlimited();
+        }
+
+        let fraglimit = cvars.get_value("fraglimit").unwrap_or(0.0).max(0.0);
+        let timelimit = cvars.get_value("timelimit").unwrap_or(0.0).max(0.0);
+
+        MatchLimits {
+            fraglimit: fraglimit as i32,
+            timelimit: Duration::milliseconds((timelimit * 60_000.0) as i64),
+        }
+    }
+}
+
+/// Tracks the progress of a match towards its limits.
+#[derive(Clone, Debug)]
+pub struct MatchState {
+    limits: MatchLimits,
+    elapsed: Duration,
+
+    // the last remaining time and frags announced, so each change is only broadcast once
+    minutes_left: Option<i64>,
+    frags_left: Option<i32>,
+
+    ended: Option<MatchEnd>,
+    intermission_start: Duration,
+    map_changed: bool,
+}
+
+impl MatchState {
+    pub fn new(limits: MatchLimits) -> MatchState {
+        MatchState {
+            limits,
+            elapsed: Duration::zero(),
+            minutes_left: None,
+            frags_left: None,
+            ended: None,
+            intermission_start: Duration::zero(),
+            map_changed: false,
+        }
+    }
+
+    pub fn ended(&self) -> Option<&MatchEnd> {
+        self.ended.as_ref()
+    }
+
+    /// Returns the time left in the match, or `None` if there is no time limit.
+    pub fn time_remaining(&self) -> Option<Duration> {
+        if self.limits.timelimit <= Duration::zero() {
+            return None;
+        }
+
+        Some((self.limits.timelimit - self.elapsed).max(Duration::zero()))
+    }
+
+    /// Returns how many more frags the leader needs to win, or `None` if there is no frag limit.
+    pub fn frags_remaining<I>(&self, frags: I) -> Option<i32>
+    where
+        I: IntoIterator<Item = i32>,
+    {
+        if self.limits.fraglimit <= 0 {
+            return None;
+        }
+
+        let leader = frags.into_iter().max().unwrap_or(0);
+        Some((self.limits.fraglimit - leader).max(0))
+    }
+
+    /// Advances the match clock by `frame_time` and checks the limits against each player's
+    /// frags.
+    ///
+    /// Returns the messages to broadcast to all clients: the remaining time and frags as they
+    /// change and, when a limit is reached, the reason followed by the start of the intermission.
+    pub fn update<'a, I>(&mut self, frame_time: Duration, frags: I) -> Vec<ServerCmd>
+    where
+        I: IntoIterator<Item = (&'a str, i32)>,
+    {
+        let mut cmds = Vec::new();
+        if self.ended.is_some() {
+            self.elapsed = self.elapsed + frame_time;
+            return cmds;
+        }
+
+        self.elapsed = self.elapsed + frame_time;
+        let frags: Vec<(&str, i32)> = frags.into_iter().collect();
+
+        // the frag limit is checked first, so a frag on the last frame still wins the match
+        let fraglimit = self.limits.fraglimit;
+        if fraglimit > 0 {
+            if let Some(&(name, _)) = frags.iter().find(|&&(_, f)| f >= fraglimit) {
+                return self.end(MatchEnd::FragLimit(name.to_owned()));
+            }
+        }
+
+        if let Some(remaining) = self.time_remaining() {
+            if remaining <= Duration::zero() {
+                return self.end(MatchEnd::TimeLimit);
+            }
+
+            // announce each whole minute, rounding up so a fresh 5 minute match says "5"
+            let minutes = (remaining.num_milliseconds() + 59_999) / 60_000;
+            if self.minutes_left != Some(minutes) {
+                self.minutes_left = Some(minutes);
+                cmds.push(ServerCmd::Print {
+                    text: format!("{} minute{} remaining\n", minutes, plural(minutes)),
+                });
+            }
+        }
+
+        if let Some(remaining) = self.frags_remaining(frags.iter().map(|&(_, f)| f)) {
+            if self.frags_left != Some(remaining) {
+                self.frags_left = Some(remaining);
+                cmds.push(ServerCmd::Print {
+                    text: format!("{} frag{} remaining\n", remaining, plural(remaining as i64)),
+                });
+            }
+        }
+
+        cmds
+    }
+
+    fn end(&mut self, end: MatchEnd) -> Vec<ServerCmd> {
+        let text = match end {
+            MatchEnd::FragLimit(ref name) => format!("{} hit the frag limit\n", name),
+            MatchEnd::TimeLimit => "Time limit reached\n".to_owned(),
+        };
+
+        self.ended = Some(end);
+        self.intermission_start = self.elapsed;
+        vec![ServerCmd::Print { text }, ServerCmd::Intermission]
+    }
+
+    /// Once the intermission has run its course, moves to the next map in the rotation.
+    ///
+    /// Returns the map that was requested, at most once per match.
+    pub fn finish_intermission<F>(
+        &mut self,
+        rotation: &mut MapRotation,
+        exists: F,
+    ) -> Option<String>
+    where
+        F: Fn(&str) -> bool,
+    {
+        if self.ended.is_none() || self.map_changed {
+            return None;
+        }
+
+        if self.elapsed - self.intermission_start < Duration::seconds(INTERMISSION_SECONDS) {
+            return None;
+        }
+
+        self.map_changed = true;
+        let map = rotation.advance(exists)?;
+        rotation.request_change(map.clone());
+        Some(map)
+    }
+}
+
+fn plural(count: i64) -> &'static str {
+    if count == 1 {
+        ""
+    } else {
+        "s"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limits(fraglimit: i32, minutes: i64) -> MatchLimits {
+        MatchLimits {
+            fraglimit,
+            timelimit: Duration::minutes(minutes),
+        }
+    }
+
+    #[test]
+    fn test_limits_from_cvars() {
+        let cvars = CvarRegistry::new();
+        ::server::register_cvars(&cvars);
+        cvars.set("fraglimit", "20").unwrap();
+        cvars.set("timelimit", "1.5").unwrap();
+
+        // no limits outside deathmatch
+        assert_eq!(MatchLimits::from_cvars(&cvars), MatchLimits::unlimited());
+
+        cvars.set("deathmatch", "1").unwrap();
+        let limits = MatchLimits::from_cvars(&cvars);
+        assert_eq!(limits.fraglimit, 20);
+        assert_eq!(limits.timelimit, Duration::seconds(90));
+    }
+
+    #[test]
+    fn test_fraglimit_ends_match() {
+        // the time limit is far off, so only the frag limit can end the match
+        let mut state = MatchState::new(limits(10, 60));
+        state.update(Duration::seconds(1), vec![("a", 3), ("b", 9)]);
+        assert_eq!(state.ended(), None);
+
+        let cmds = state.update(Duration::seconds(1), vec![("a", 3), ("b", 10)]);
+        assert_eq!(state.ended(), Some(&MatchEnd::FragLimit("b".to_owned())));
+        assert!(cmds.contains(&ServerCmd::Intermission));
+
+        // the match only ends once
+        let cmds = state.update(Duration::seconds(1), vec![("a", 11), ("b", 10)]);
+        assert!(cmds.is_empty());
+        assert_eq!(state.ended(), Some(&MatchEnd::FragLimit("b".to_owned())));
+    }
+
+    #[test]
+    fn test_timelimit_ends_match() {
+        // no frag limit, so only the time limit can end the match
+        let mut state = MatchState::new(limits(0, 1));
+        state.update(Duration::seconds(59), vec![("a", 100)]);
+        assert_eq!(state.ended(), None);
+        assert_eq!(state.time_remaining(), Some(Duration::seconds(1)));
+
+        let cmds = state.update(Duration::seconds(1), vec![("a", 100)]);
+        assert_eq!(state.ended(), Some(&MatchEnd::TimeLimit));
+        assert!(cmds.contains(&ServerCmd::Intermission));
+        assert_eq!(state.time_remaining(), Some(Duration::zero()));
+    }
+
+    #[test]
+    fn test_unlimited_never_ends() {
+        let mut state = MatchState::new(MatchLimits::unlimited());
+        let cmds = state.update(Duration::hours(10), vec![("a", 1000)]);
+        assert!(cmds.is_empty());
+        assert_eq!(state.ended(), None);
+        assert_eq!(state.time_remaining(), None);
+        assert_eq!(state.frags_remaining(vec![1000]), None);
+    }
+
+    #[test]
+    fn test_broadcast_remaining() {
+        let mut state = MatchState::new(limits(5, 2));
+        let cmds = state.update(Duration::milliseconds(100), vec![("a", 1)]);
+        assert_eq!(
+            cmds,
+            vec![
+                ServerCmd::Print {
+                    text: "2 minutes remaining\n".to_owned(),
+                },
+                ServerCmd::Print {
+                    text: "4 frags remaining\n".to_owned(),
+                },
+            ]
+        );
+
+        // nothing changed
+        assert!(state.update(Duration::seconds(1), vec![("a", 1)]).is_empty());
+
+        let cmds = state.update(Duration::seconds(60), vec![("a", 4)]);
+        assert_eq!(
+            cmds,
+            vec![
+                ServerCmd::Print {
+                    text: "1 minute remaining\n".to_owned(),
+                },
+                ServerCmd::Print {
+                    text: "1 frag remaining\n".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_intermission_advances_rotation() {
+        let mut rotation = MapRotation::parse("dm1 dm2");
+        rotation.set_current("dm1");
+
+        let mut state = MatchState::new(limits(1, 0));
+        state.update(Duration::seconds(1), vec![("a", 1)]);
+        assert_eq!(state.finish_intermission(&mut rotation, |_| true), None);
+
+        state.update(Duration::seconds(INTERMISSION_SECONDS), vec![("a", 1)]);
+        assert_eq!(
+            state.finish_intermission(&mut rotation, |_| true),
+            Some("dm2".to_owned())
+        );
+        assert_eq!(rotation.take_pending_change(), Some("dm2".to_owned()));
+        assert_eq!(state.finish_intermission(&mut rotation, |_| true), None);
+    }
+}
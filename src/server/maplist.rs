@@ -0,0 +1,246 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use std::cell::RefCell;
+use std::io::Read;
+use std::rc::Rc;
+
+use common::console::{CmdRegistry, CvarRegistry};
+use common::vfs::Vfs;
+
+use failure::Error;
+
+/// The file the map rotation is read from if `sv_maplist` is empty.
+pub const MAP_LIST_FILE: &str = "maplist.txt";
+
+/// Returns whether the map `name` (e.g. `"e1m1"`) can be loaded.
+pub fn map_exists(vfs: &Vfs, name: &str) -> bool {
+    vfs.open(format!("maps/{}.bsp", name)).is_ok()
+}
+
+/// The list of maps a server cycles through at the end of each match.
+#[derive(Clone, Debug, Default)]
+pub struct MapRotation {
+    maps: Vec<String>,
+    current: Option<usize>,
+
+    // a map change requested by a command, to be carried out by the server
+    pending: Option<String>,
+}
+
+impl MapRotation {
+    pub fn new(maps: Vec<String>) -> MapRotation {
+        MapRotation {
+            maps,
+            current: None,
+            pending: None,
+        }
+    }
+
+    /// Parses a map list with map names separated by whitespace.
+    ///
+    /// Lines starting with `//` are ignored.
+    pub fn parse<S>(src: S) -> MapRotation
+    where
+        S: AsRef<str>,
+    {
+        let maps = src
+            .as_ref()
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.starts_with("//"))
+            .flat_map(|l| l.split_whitespace())
+            .map(|m| m.to_owned())
+            .collect();
+
+        MapRotation::new(maps)
+    }
+
+    /// Loads the rotation from `sv_maplist`, or from `MAP_LIST_FILE` if the cvar is empty.
+    ///
+    /// If neither exists, the rotation is empty.
+    pub fn load(vfs: &Vfs, cvars: &CvarRegistry) -> Result<MapRotation, Error> {
+        let maplist = cvars.get("sv_maplist").unwrap_or_default();
+        if !maplist.trim().is_empty() {
+            return Ok(MapRotation::parse(maplist));
+        }
+
+        let mut file = match vfs.open(MAP_LIST_FILE) {
+            Ok(f) => f,
+            Err(_) => return Ok(MapRotation::default()),
+        };
+
+        let mut src = String::new();
+        file.read_to_string(&mut src)?;
+        Ok(MapRotation::parse(src))
+    }
+
+    pub fn maps(&self) -> &[String] {
+        &self.maps
+    }
+
+    pub fn current(&self) -> Option<&str> {
+        self.current.map(|i| self.maps[i].as_str())
+    }
+
+    /// Records that `map` is being played, so that the rotation continues after it.
+    ///
+    /// Maps that aren't in the rotation leave its position unchanged.
+    pub fn set_current(&mut self, map: &str) {
+        if let Some(i) = self.maps.iter().position(|m| m == map) {
+            self.current = Some(i);
+        }
+    }
+
+    /// Moves to the next map in the rotation, wrapping at the end of the list.
+    ///
+    /// Maps for which `exists` returns false are skipped with a warning. Returns `None` if no map
+    /// in the rotation exists.
+    pub fn advance<F>(&mut self, exists: F) -> Option<String>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let count = self.maps.len();
+        let start = self.current.map(|i| i + 1).unwrap_or(0);
+        for offset in 0..count {
+            let i = (start + offset) % count;
+            if exists(&self.maps[i]) {
+                self.current = Some(i);
+                return Some(self.maps[i].clone());
+            }
+
+            warn!("Skipping missing map {} in rotation", self.maps[i]);
+        }
+
+        None
+    }
+
+    /// Requests a change to `map` at the next opportunity.
+    pub fn request_change(&mut self, map: String) {
+        self.pending = Some(map);
+    }
+
+    /// Returns the requested map change, if any, and clears it.
+    pub fn take_pending_change(&mut self) -> Option<String> {
+        self.pending.take()
+    }
+}
+
+/// Registers the map rotation commands (`nextmap` and `map_restart`).
+///
+/// These only request a map change; the server picks it up with
+/// `MapRotation::take_pending_change`.
+pub fn register_rotation_cmds(
+    cmds: &mut CmdRegistry,
+    rotation: Rc<RefCell<MapRotation>>,
+    vfs: Rc<Vfs>,
+) {
+    let nextmap_rotation = rotation.clone();
+    cmds.insert_or_replace(
+        "nextmap",
+        Box::new(move |_| {
+            let mut rotation = nextmap_rotation.borrow_mut();
+            match rotation.advance(|m| map_exists(&vfs, m)) {
+                Some(map) => {
+                    println!("Changing to {}", map);
+                    rotation.request_change(map);
+                }
+
+                None => println!("No maps in rotation"),
+            }
+        }),
+    )
+    .unwrap();
+
+    cmds.insert_or_replace(
+        "map_restart",
+        Box::new(move |_| {
+            let mut rotation = rotation.borrow_mut();
+            match rotation.current().map(|m| m.to_owned()) {
+                Some(map) => rotation.request_change(map),
+                None => println!("No map in rotation is running"),
+            }
+        }),
+    )
+    .unwrap();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let rotation = MapRotation::parse("dm1 dm2\n// dm3\n  dm4\n\ndm5\tdm6\n");
+        assert_eq!(rotation.maps(), &["dm1", "dm2", "dm4", "dm5", "dm6"]);
+        assert_eq!(rotation.current(), None);
+    }
+
+    #[test]
+    fn test_advance_wraps() {
+        let mut rotation = MapRotation::parse("dm1 dm2 dm3");
+        assert_eq!(rotation.advance(|_| true), Some("dm1".to_owned()));
+        assert_eq!(rotation.advance(|_| true), Some("dm2".to_owned()));
+        assert_eq!(rotation.advance(|_| true), Some("dm3".to_owned()));
+        assert_eq!(rotation.advance(|_| true), Some("dm1".to_owned()));
+    }
+
+    #[test]
+    fn test_advance_skips_missing() {
+        let mut rotation = MapRotation::parse("dm1 dm2 dm3");
+        rotation.set_current("dm1");
+        assert_eq!(rotation.advance(|m| m != "dm2"), Some("dm3".to_owned()));
+        assert_eq!(rotation.advance(|m| m != "dm2"), Some("dm1".to_owned()));
+        assert_eq!(rotation.advance(|_| false), None);
+
+        assert_eq!(MapRotation::default().advance(|_| true), None);
+    }
+
+    #[test]
+    fn test_set_current_unknown_map() {
+        let mut rotation = MapRotation::parse("dm1 dm2 dm3");
+        rotation.set_current("dm2");
+        rotation.set_current("e1m1");
+        assert_eq!(rotation.current(), Some("dm2"));
+        assert_eq!(rotation.advance(|_| true), Some("dm3".to_owned()));
+    }
+
+    #[test]
+    fn test_load_from_cvar() {
+        let cvars = CvarRegistry::new();
+        ::server::register_cvars(&cvars);
+        cvars.set("sv_maplist", "dm4 dm6").unwrap();
+        let rotation = MapRotation::load(&Vfs::new(), &cvars).unwrap();
+        assert_eq!(rotation.maps(), &["dm4", "dm6"]);
+    }
+
+    #[test]
+    fn test_rotation_cmds() {
+        let mut cmds = CmdRegistry::new();
+        let rotation = Rc::new(RefCell::new(MapRotation::parse("dm1 dm2")));
+        rotation.borrow_mut().set_current("dm1");
+        register_rotation_cmds(&mut cmds, rotation.clone(), Rc::new(Vfs::new()));
+
+        // nothing exists in an empty Vfs
+        cmds.exec("nextmap", &[]).unwrap();
+        assert_eq!(rotation.borrow_mut().take_pending_change(), None);
+
+        cmds.exec("map_restart", &[]).unwrap();
+        assert_eq!(rotation.borrow_mut().take_pending_change(), Some("dm1".to_owned()));
+        assert_eq!(rotation.borrow_mut().take_pending_change(), None);
+    }
+}
@@ -15,23 +15,110 @@
 // DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+pub mod ban;
+pub mod host;
+pub mod limits;
+pub mod maplist;
 pub mod progs;
+pub mod rate;
 pub mod world;
 
+mod cvars;
+pub use self::cvars::register_cvars;
+
+use std::cell::RefCell;
+use std::collections::HashSet;
 use std::io::Cursor;
 use std::io::Seek;
 use std::io::SeekFrom;
+use std::net::{IpAddr, SocketAddr};
 use std::rc::Rc;
 
+use common::bsp::BspData;
+use common::console::{CmdRegistry, CvarRegistry};
+use common::net;
+use common::net::connect::{ConnectListener, Response, ResponseReject};
+use common::net::{EntityState, NetError, QSocket, ServerCmd};
+use common::vfs::Vfs;
+
+use self::ban::BanList;
+use self::rate::RateLimiter;
+
 use self::progs::EntityId;
+use self::progs::GlobalAddrFloat;
+use self::progs::Globals;
+use self::progs::GlobalsError;
 use self::progs::StringId;
 use self::progs::StringTable;
 
 use byteorder::WriteBytesExt;
+use cgmath::Vector3;
+use chrono::Duration;
 
 const MAX_DATAGRAM: usize = 1024;
 const MAX_LIGHTSTYLES: usize = 64;
 
+/// The highest skill level (Nightmare).
+pub const MAX_SKILL: u32 = 3;
+
+/// Returns the current skill level from the `skill` cvar, rounded to the nearest level and clamped
+/// to the range 0 (Easy) to 3 (Nightmare).
+pub fn skill_level(cvars: &CvarRegistry) -> u32 {
+    let skill = cvars.get_value("skill").unwrap_or(1.0);
+    if skill.is_nan() {
+        return 1;
+    }
+
+    (skill + 0.5).max(0.0).min(MAX_SKILL as f32) as u32
+}
+
+/// The game mode chosen by the `coop`, `deathmatch` and `teamplay` cvars.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GameRules {
+    pub coop: f32,
+    pub deathmatch: f32,
+    pub teamplay: f32,
+}
+
+impl GameRules {
+    /// Reads the game rules from the cvars.
+    ///
+    /// As in the original engine, cooperative mode takes precedence over deathmatch.
+    pub fn from_cvars(cvars: &CvarRegistry) -> GameRules {
+        let coop = cvars.get_value("coop").unwrap_or(0.0);
+        let deathmatch = if coop != 0.0 {
+            0.0
+        } else {
+            cvars.get_value("deathmatch").unwrap_or(0.0)
+        };
+
+        GameRules {
+            coop,
+            deathmatch,
+            teamplay: cvars.get_value("teamplay").unwrap_or(0.0),
+        }
+    }
+
+    pub fn is_coop(&self) -> bool {
+        self.coop != 0.0
+    }
+
+    pub fn is_deathmatch(&self) -> bool {
+        self.deathmatch != 0.0
+    }
+
+    /// Copies the game rules into the QuakeC globals.
+    ///
+    /// The game logic reads these to decide whether to spawn monsters, respawn items and allow
+    /// friendly fire, so this must be done before a map's entities are spawned.
+    pub fn write_globals(&self, globals: &mut Globals) -> Result<(), GlobalsError> {
+        globals.put_float(self.coop, GlobalAddrFloat::Coop as i16)?;
+        globals.put_float(self.deathmatch, GlobalAddrFloat::Deathmatch as i16)?;
+        globals.put_float(self.teamplay, GlobalAddrFloat::TeamPlay as i16)?;
+        Ok(())
+    }
+}
+
 pub enum ClientSlot {
     Disconnected,
     InGame(ClientInGame),
@@ -39,7 +126,89 @@ pub enum ClientSlot {
 
 pub struct ClientInGame {
     privileged: bool,
+    spawned: bool,
     entity_id: EntityId,
+    name: String,
+    qsock: QSocket,
+    rate: RateLimiter,
+
+    // reliable commands waiting to be sent
+    message: Vec<u8>,
+}
+
+impl ClientInGame {
+    /// Creates a newly connected client.
+    ///
+    /// The client is named once it sends a `name` command during signon.
+    pub fn new(entity_id: EntityId, qsock: QSocket) -> ClientInGame {
+        ClientInGame {
+            privileged: false,
+            spawned: false,
+            entity_id,
+            name: String::from("unconnected"),
+            qsock,
+            rate: RateLimiter::new(rate::DEFAULT_RATE, MAX_DATAGRAM),
+            message: Vec::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn set_name<S>(&mut self, name: S)
+    where
+        S: AsRef<str>,
+    {
+        self.name = name.as_ref().to_owned();
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.qsock.remote()
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate.rate()
+    }
+
+    /// Sets the bandwidth limit reported by the client's `rate` command.
+    pub fn set_rate(&mut self, rate: u32) {
+        self.rate.set_rate(rate);
+    }
+
+    /// Packs this tick's entity updates for the client, in priority order, without exceeding its
+    /// rate.
+    pub fn pack_updates<'a, I>(&mut self, elapsed: Duration, updates: I) -> Vec<u8>
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        self.rate.tick(elapsed);
+        self.rate.pack(updates)
+    }
+
+    /// Queues a command to be sent to the client reliably.
+    pub fn queue_reliable(&mut self, cmd: &ServerCmd) -> Result<(), NetError> {
+        cmd.serialize(&mut self.message)
+    }
+
+    /// Sends the queued reliable commands once the previous reliable message has been
+    /// acknowledged.
+    pub fn flush_reliable(&mut self) -> Result<(), NetError> {
+        if self.message.is_empty() || !self.qsock.can_send() {
+            return Ok(());
+        }
+
+        self.qsock.begin_send_msg(&self.message)?;
+        self.message.clear();
+        Ok(())
+    }
+
+    /// Tells the client that the server has dropped it.
+    pub fn send_disconnect(&mut self) -> Result<(), NetError> {
+        let mut msg = Vec::new();
+        ServerCmd::Disconnect.serialize(&mut msg)?;
+        self.qsock.send_msg_unreliable(&msg)
+    }
 }
 
 bitflags! {
@@ -61,6 +230,369 @@ pub struct ServerStatics {
     client_slots: Vec<ClientSlot>,
 }
 
+impl ServerStatics {
+    pub fn new(client_slot_limit: usize) -> ServerStatics {
+        let client_slot_limit = client_slot_limit.max(1).min(net::MAX_CLIENTS);
+        ServerStatics {
+            client_slot_limit,
+            client_slot_count: 0,
+            client_slots: (0..client_slot_limit)
+                .map(|_| ClientSlot::Disconnected)
+                .collect(),
+        }
+    }
+
+    /// Creates the client slots for a new server, sized by the `maxplayers` cvar.
+    pub fn from_cvars(cvars: &CvarRegistry) -> ServerStatics {
+        ServerStatics::new(cvars.get_value("maxplayers").unwrap_or(1.0).max(0.0) as usize)
+    }
+
+    pub fn client_slot_limit(&self) -> usize {
+        self.client_slot_limit
+    }
+
+    pub fn client_slot_count(&self) -> usize {
+        self.client_slot_count
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.client_slot_count >= self.client_slot_limit
+    }
+
+    /// Prints a notice if `maxplayers` has been set to something other than the number of client
+    /// slots.
+    ///
+    /// The slots are only resized when a new map is started, so the new value won't apply until
+    /// then. Returns whether the value differs.
+    pub fn check_slot_limit(&self, maxplayers: &str) -> bool {
+        let requested = match maxplayers.trim().parse::<f32>() {
+            Ok(m) => m.max(1.0).min(net::MAX_CLIENTS as f32) as usize,
+            Err(_) => return false,
+        };
+
+        if requested != self.client_slot_limit {
+            println!(
+                "maxplayers will be changed to {} on the next map (currently {})",
+                requested, self.client_slot_limit
+            );
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Changes the number of client slots when a new map starts.
+    ///
+    /// Clients keep their slots, except those past the new limit, which are dropped and returned.
+    pub fn resize(&mut self, client_slot_limit: usize) -> Vec<ClientInGame> {
+        let client_slot_limit = client_slot_limit.max(1).min(net::MAX_CLIENTS);
+
+        let mut dropped = Vec::new();
+        for slot_id in client_slot_limit..self.client_slots.len() {
+            dropped.extend(self.kick(slot_id));
+        }
+
+        self.client_slots.truncate(client_slot_limit);
+        while self.client_slots.len() < client_slot_limit {
+            self.client_slots.push(ClientSlot::Disconnected);
+        }
+
+        self.client_slot_limit = client_slot_limit;
+        dropped
+    }
+
+    /// Places a newly connected client in the first free slot and returns the slot number, or
+    /// `None` if every slot is taken.
+    pub fn connect(&mut self, qsock: QSocket) -> Option<usize> {
+        let slot_id = self.client_slots.iter().position(|slot| match *slot {
+            ClientSlot::Disconnected => true,
+            ClientSlot::InGame(_) => false,
+        })?;
+
+        // entity 0 is the world, so each client's entity follows its slot
+        let entity_id = EntityId(slot_id + 1);
+        self.client_slots[slot_id] = ClientSlot::InGame(ClientInGame::new(entity_id, qsock));
+        self.client_slot_count += 1;
+        Some(slot_id)
+    }
+
+    /// Finds the slot of a connected client by name or by slot number, as given to `kick`.
+    ///
+    /// Names take precedence, so a player named "2" can still be kicked by name.
+    pub fn find_client<S>(&self, target: S) -> Option<usize>
+    where
+        S: AsRef<str>,
+    {
+        let target = target.as_ref();
+        let by_name = self.client_slots.iter().position(|slot| match *slot {
+            ClientSlot::InGame(ref c) => c.name == target,
+            ClientSlot::Disconnected => false,
+        });
+
+        by_name.or_else(|| match target.parse::<usize>() {
+            Ok(id) => match self.client_slots.get(id) {
+                Some(&ClientSlot::InGame(_)) => Some(id),
+                _ => None,
+            },
+            Err(_) => None,
+        })
+    }
+
+    /// Frees the slot of a connected client and returns its state so the caller can notify it.
+    pub fn kick(&mut self, slot_id: usize) -> Option<ClientInGame> {
+        let slot = match self.client_slots.get_mut(slot_id) {
+            Some(s) => s,
+            None => return None,
+        };
+
+        match ::std::mem::replace(slot, ClientSlot::Disconnected) {
+            ClientSlot::InGame(c) => {
+                self.client_slot_count -= 1;
+                Some(c)
+            }
+            ClientSlot::Disconnected => None,
+        }
+    }
+
+    /// Frees the slot of the client at `addr` after it sends a disconnect message.
+    ///
+    /// The slot is available again immediately rather than after the client times out.
+    pub fn disconnect(&mut self, addr: SocketAddr) -> Option<ClientInGame> {
+        let slot_id = self.client_slots.iter().position(|slot| match *slot {
+            ClientSlot::InGame(ref c) => c.addr() == addr,
+            ClientSlot::Disconnected => false,
+        })?;
+
+        self.kick(slot_id)
+    }
+}
+
+/// Returns the address the server listens on for new connections.
+///
+/// The port is taken from `sv_port` and the interface from `sv_address`. An empty `sv_address`
+/// listens on all interfaces.
+pub fn listen_addr(cvars: &CvarRegistry) -> Result<SocketAddr, NetError> {
+    let port = match cvars.get("sv_port") {
+        Ok(p) => p
+            .trim()
+            .parse::<u16>()
+            .map_err(|_| NetError::with_msg(format!("Invalid sv_port \"{}\"", p)))?,
+        Err(_) => net::DEFAULT_PORT,
+    };
+
+    let address = cvars.get("sv_address").unwrap_or_default();
+    let ip = match address.trim() {
+        "" => IpAddr::from([0, 0, 0, 0]),
+        a => a
+            .parse::<IpAddr>()
+            .map_err(|_| NetError::with_msg(format!("Invalid sv_address \"{}\"", a)))?,
+    };
+
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Binds the connection listener to the address given by `sv_address` and `sv_port`.
+pub fn bind_listener(cvars: &CvarRegistry) -> Result<ConnectListener, NetError> {
+    let addr = listen_addr(cvars)?;
+    let listener = ConnectListener::bind(addr)?;
+    println!("Listening on {}", listener.local_addr()?);
+    Ok(listener)
+}
+
+/// Computes the set of leaves potentially visible from `view_origin`.
+///
+/// Returns `None` if everything is visible, i.e. the viewer is outside the map or the map has no
+/// visibility data for the viewer's leaf.
+pub fn client_pvs(bsp_data: &BspData, view_origin: Vector3<f32>) -> Option<HashSet<usize>> {
+    let leaf_id = bsp_data.find_leaf(view_origin);
+    if leaf_id == 0 || bsp_data.leaves()[leaf_id].vis_offset.is_none() {
+        return None;
+    }
+
+    let mut pvs: HashSet<usize> = bsp_data
+        .get_pvs(leaf_id, bsp_data.leaves().len())
+        .into_iter()
+        .collect();
+    pvs.insert(leaf_id);
+    Some(pvs)
+}
+
+/// Returns whether an entity touching `leaf_ids` should be sent to a client with the given PVS.
+///
+/// Entities that touch too many leaves to track (`leaf_ids` is `None`) are always sent, as is
+/// everything when `pvs` is `None`.
+pub fn entity_in_pvs(pvs: Option<&HashSet<usize>>, leaf_ids: Option<&[usize]>) -> bool {
+    match (pvs, leaf_ids) {
+        (Some(pvs), Some(leaf_ids)) => leaf_ids.iter().any(|l| pvs.contains(l)),
+        _ => true,
+    }
+}
+
+/// Encodes a fast update for each entity as a delta against its baseline.
+///
+/// `entities` yields each entity's ID, baseline and current state. The updates are encoded
+/// separately so they can be prioritized and packed with `ClientInGame::pack_updates`.
+pub fn encode_entity_updates<'a, I>(entities: I) -> Result<Vec<Vec<u8>>, NetError>
+where
+    I: IntoIterator<Item = (u16, &'a EntityState, &'a EntityState)>,
+{
+    let mut updates = Vec::new();
+    for (ent_id, baseline, state) in entities {
+        let mut update = Vec::new();
+        state
+            .delta_update(ent_id, baseline, false)
+            .serialize(&mut update)?;
+        updates.push(update);
+    }
+
+    Ok(updates)
+}
+
+/// Checks a connection request from `remote` against the ban list and the free client slots.
+///
+/// Returns the rejection to send if `remote` is banned or the server is full.
+pub fn screen_connection(
+    bans: &BanList,
+    statics: &ServerStatics,
+    remote: SocketAddr,
+) -> Option<Response> {
+    let message = if bans.is_banned(remote.ip()) {
+        "You have been banned.\n"
+    } else if statics.is_full() {
+        "Server is full.\n"
+    } else {
+        return None;
+    };
+
+    Some(Response::Reject(ResponseReject {
+        message: message.to_owned(),
+    }))
+}
+
+/// Registers the server administration commands (`kick`, `ban`, `banlist` and `unban`).
+///
+/// Changes to the ban list are written back to `ban::BAN_LIST_FILE` immediately.
+pub fn register_admin_cmds(
+    cmds: &mut CmdRegistry,
+    statics: Rc<RefCell<ServerStatics>>,
+    bans: Rc<RefCell<BanList>>,
+    vfs: Rc<Vfs>,
+) {
+    let kick_statics = statics.clone();
+    cmds.insert_or_replace(
+        "kick",
+        Box::new(move |args| {
+            if args.len() != 1 {
+                println!("kick <name|id>: disconnect a player");
+                return;
+            }
+
+            let mut statics = kick_statics.borrow_mut();
+            match statics.find_client(args[0]).and_then(|id| statics.kick(id)) {
+                Some(c) => drop_kicked(c),
+                None => println!("No player {}", args[0]),
+            }
+        }),
+    )
+    .unwrap();
+
+    let ban_bans = bans.clone();
+    let ban_vfs = vfs.clone();
+    cmds.insert_or_replace(
+        "ban",
+        Box::new(move |args| {
+            if args.len() != 1 {
+                println!("ban <address>: refuse connections from an address");
+                return;
+            }
+
+            let addr: IpAddr = match args[0].parse() {
+                Ok(a) => a,
+                Err(_) => {
+                    println!("Invalid address: {}", args[0]);
+                    return;
+                }
+            };
+
+            let mut bans = ban_bans.borrow_mut();
+            if !bans.ban(addr) {
+                println!("{} is already banned", addr);
+                return;
+            }
+
+            // disconnect anyone already playing from that address
+            let mut statics = statics.borrow_mut();
+            for id in 0..statics.client_slots.len() {
+                let banned = match statics.client_slots[id] {
+                    ClientSlot::InGame(ref c) => c.addr().ip() == addr,
+                    ClientSlot::Disconnected => false,
+                };
+
+                if banned {
+                    if let Some(c) = statics.kick(id) {
+                        drop_kicked(c);
+                    }
+                }
+            }
+
+            if let Err(e) = bans.save(&ban_vfs) {
+                println!("Couldn't save ban list: {}", e);
+            }
+        }),
+    )
+    .unwrap();
+
+    let banlist_bans = bans.clone();
+    cmds.insert_or_replace(
+        "banlist",
+        Box::new(move |_| {
+            let bans = banlist_bans.borrow();
+            println!("Banned addresses:");
+            for addr in bans.iter() {
+                println!("  {}", addr);
+            }
+        }),
+    )
+    .unwrap();
+
+    cmds.insert_or_replace(
+        "unban",
+        Box::new(move |args| {
+            if args.len() != 1 {
+                println!("unban <address>: remove an address from the ban list");
+                return;
+            }
+
+            let addr: IpAddr = match args[0].parse() {
+                Ok(a) => a,
+                Err(_) => {
+                    println!("Invalid address: {}", args[0]);
+                    return;
+                }
+            };
+
+            let mut bans = bans.borrow_mut();
+            if !bans.unban(addr) {
+                println!("{} is not banned", addr);
+                return;
+            }
+
+            if let Err(e) = bans.save(&vfs) {
+                println!("Couldn't save ban list: {}", e);
+            }
+        }),
+    )
+    .unwrap();
+}
+
+fn drop_kicked(mut client: ClientInGame) {
+    if let Err(e) = client.send_disconnect() {
+        warn!("Couldn't notify {} of disconnect: {}", client.name(), e);
+    }
+
+    println!("Kicked {} ({})", client.name(), client.addr());
+}
+
 pub struct Server {
     string_table: Rc<StringTable>,
     sound_precache: Vec<String>,
@@ -150,3 +682,271 @@ impl Server {
         self.lightstyles[lightstyle_index] = lightstyle_val_id;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use server::world::SpawnFlags;
+    use std::net::UdpSocket;
+
+    fn qsocket(id: usize) -> QSocket {
+        QSocket::new(
+            UdpSocket::bind("127.0.0.1:0").unwrap(),
+            format!("10.0.0.{}:26000", id + 1).parse().unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_listen_addr() {
+        let cvars = CvarRegistry::new();
+        register_cvars(&cvars);
+        assert_eq!(
+            listen_addr(&cvars).unwrap(),
+            "0.0.0.0:26000".parse::<SocketAddr>().unwrap()
+        );
+
+        cvars.set("sv_address", "192.168.1.10").unwrap();
+        cvars.set("sv_port", "27500").unwrap();
+        assert_eq!(
+            listen_addr(&cvars).unwrap(),
+            "192.168.1.10:27500".parse::<SocketAddr>().unwrap()
+        );
+
+        cvars.set("sv_port", "70000").unwrap();
+        assert!(listen_addr(&cvars).is_err());
+    }
+
+    #[test]
+    fn test_bind_listener_port() {
+        // find a free port to bind to
+        let port = ::std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let cvars = CvarRegistry::new();
+        register_cvars(&cvars);
+        cvars.set("sv_address", "127.0.0.1").unwrap();
+        cvars.set("sv_port", &port.to_string()).unwrap();
+
+        let listener = bind_listener(&cvars).unwrap();
+        assert_eq!(listener.local_addr().unwrap().port(), port);
+    }
+
+    #[test]
+    fn test_encode_entity_updates() {
+        use cgmath::Vector3;
+        use std::io::BufReader;
+
+        let baseline = EntityState::uninitialized();
+        let mut moved = baseline.clone();
+        moved.origin = Vector3::new(8.0, 16.0, 24.0);
+
+        let updates =
+            encode_entity_updates(vec![(1, &baseline, &baseline), (2, &baseline, &moved)])
+                .unwrap();
+
+        // an entity at its baseline needs only its flags and ID
+        assert_eq!(updates[0].len(), 2);
+
+        let mut reader = BufReader::new(updates[1].as_slice());
+        let cmd = ServerCmd::deserialize(&mut reader).unwrap().unwrap();
+        assert_eq!(baseline.apply_update(&cmd).unwrap(), moved);
+    }
+
+    #[test]
+    fn test_entity_in_pvs() {
+        // the client can see leaves 1-3, leaf 7 is a sealed room elsewhere
+        let pvs: HashSet<usize> = [1, 2, 3].iter().cloned().collect();
+
+        assert!(entity_in_pvs(Some(&pvs), Some(&[2])));
+        assert!(!entity_in_pvs(Some(&pvs), Some(&[7])));
+
+        // an entity straddling a visible leaf is sent
+        assert!(entity_in_pvs(Some(&pvs), Some(&[7, 3])));
+
+        // an entity not in any leaf can't be seen
+        assert!(!entity_in_pvs(Some(&pvs), Some(&[])));
+
+        // untracked entities and clients without a PVS get everything
+        assert!(entity_in_pvs(Some(&pvs), None));
+        assert!(entity_in_pvs(None, Some(&[7])));
+    }
+
+    fn statics_with_clients(names: &[&str]) -> ServerStatics {
+        let mut statics = ServerStatics::new(4);
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(statics.connect(qsocket(i)), Some(i));
+            if let ClientSlot::InGame(ref mut c) = statics.client_slots[i] {
+                c.set_name(name);
+            }
+        }
+
+        statics
+    }
+
+    #[test]
+    fn test_connect() {
+        let mut statics = statics_with_clients(&["ranger"]);
+        assert_eq!(statics.client_slot_count, 1);
+
+        // the second client gets the entity after the first one's
+        assert_eq!(statics.connect(qsocket(1)), Some(1));
+        match statics.client_slots[1] {
+            ClientSlot::InGame(ref c) => {
+                assert_eq!(c.entity_id, EntityId(2));
+                assert_eq!(c.name(), "unconnected");
+            }
+            ClientSlot::Disconnected => panic!("client was not placed in slot 1"),
+        }
+    }
+
+    #[test]
+    fn test_find_client() {
+        let statics = statics_with_clients(&["ranger", "1"]);
+        assert_eq!(statics.find_client("ranger"), Some(0));
+
+        // names take precedence over slot numbers
+        assert_eq!(statics.find_client("1"), Some(1));
+        assert_eq!(statics.find_client("0"), Some(0));
+        assert_eq!(statics.find_client("3"), None);
+        assert_eq!(statics.find_client("shambler"), None);
+    }
+
+    #[test]
+    fn test_kick() {
+        let mut statics = statics_with_clients(&["ranger"]);
+        let kicked = statics.kick(0).unwrap();
+        assert_eq!(kicked.name(), "ranger");
+        assert_eq!(statics.client_slot_count, 0);
+        assert!(statics.kick(0).is_none());
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut statics = statics_with_clients(&["ranger", "grunt", "knight"]);
+
+        let dropped = statics.resize(2);
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].name(), "knight");
+        assert_eq!(statics.client_slot_count(), 2);
+        assert!(statics.is_full());
+
+        statics.resize(8);
+        assert_eq!(statics.client_slot_limit(), 8);
+        assert_eq!(statics.connect(qsocket(2)), Some(2));
+        assert_eq!(statics.find_client("grunt"), Some(1));
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let mut statics = statics_with_clients(&["ranger", "grunt"]);
+        let grunt_addr = "10.0.0.2:26000".parse().unwrap();
+
+        assert_eq!(statics.disconnect(grunt_addr).unwrap().name(), "grunt");
+        assert_eq!(statics.client_slot_count, 1);
+        assert!(statics.disconnect(grunt_addr).is_none());
+
+        // the freed slot is reused by the next connection
+        assert_eq!(statics.connect(qsocket(1)), Some(1));
+    }
+
+    #[test]
+    fn test_screen_connection() {
+        let statics = ServerStatics::new(4);
+        let mut bans = BanList::new();
+        bans.ban("10.0.0.1".parse().unwrap());
+
+        assert!(screen_connection(&bans, &statics, "10.0.0.1:26000".parse().unwrap()).is_some());
+        assert!(screen_connection(&bans, &statics, "10.0.0.2:26000".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_server_full() {
+        let cvars = CvarRegistry::new();
+        register_cvars(&cvars);
+        cvars.set("maxplayers", "2").unwrap();
+
+        let bans = BanList::new();
+        let mut statics = ServerStatics::from_cvars(&cvars);
+        let remote = "10.0.0.3:26000".parse().unwrap();
+        for i in 0..2 {
+            assert!(screen_connection(&bans, &statics, remote).is_none());
+            assert_eq!(statics.connect(qsocket(i)), Some(i));
+        }
+
+        // the third connection is refused
+        match screen_connection(&bans, &statics, remote) {
+            Some(Response::Reject(r)) => assert_eq!(r.message, "Server is full.\n"),
+            _ => panic!("third connection was not rejected"),
+        }
+        assert_eq!(statics.connect(qsocket(2)), None);
+
+        // kicking someone frees up a slot
+        statics.kick(0);
+        assert!(screen_connection(&bans, &statics, remote).is_none());
+    }
+
+    #[test]
+    fn test_check_slot_limit() {
+        let statics = ServerStatics::new(1);
+        assert!(!statics.check_slot_limit("1"));
+        assert!(statics.check_slot_limit("8"));
+        assert_eq!(statics.client_slot_limit(), 1);
+
+        // values out of range are clamped the same way the slots are
+        assert!(!statics.check_slot_limit("0"));
+    }
+
+    #[test]
+    fn test_skill_level() {
+        let cvars = CvarRegistry::new();
+        register_cvars(&cvars);
+        assert_eq!(skill_level(&cvars), 1);
+
+        cvars.set("skill", "2.6").unwrap();
+        assert_eq!(skill_level(&cvars), 3);
+
+        cvars.set("skill", "-1").unwrap();
+        assert_eq!(skill_level(&cvars), 0);
+
+        cvars.set("skill", "7").unwrap();
+        assert_eq!(skill_level(&cvars), MAX_SKILL);
+    }
+
+    #[test]
+    fn test_game_rules_from_cvars() {
+        let cvars = CvarRegistry::new();
+        register_cvars(&cvars);
+
+        // single player by default
+        let rules = GameRules::from_cvars(&cvars);
+        assert!(!rules.is_coop());
+        assert!(!rules.is_deathmatch());
+
+        cvars.set("deathmatch", "2").unwrap();
+        cvars.set("teamplay", "1").unwrap();
+        let rules = GameRules::from_cvars(&cvars);
+        assert_eq!(rules.deathmatch, 2.0);
+        assert_eq!(rules.teamplay, 1.0);
+
+        // coop overrides deathmatch
+        cvars.set("coop", "1").unwrap();
+        let rules = GameRules::from_cvars(&cvars);
+        assert!(rules.is_coop());
+        assert!(!rules.is_deathmatch());
+    }
+
+    #[test]
+    fn test_spawn_flags_inhibit_skill() {
+        let not_easy = SpawnFlags::NOT_EASY;
+        assert!(not_easy.inhibits_skill(0));
+        assert!(!not_easy.inhibits_skill(1));
+
+        let not_hard = SpawnFlags::NOT_HARD;
+        assert!(!not_hard.inhibits_skill(1));
+        assert!(not_hard.inhibits_skill(2));
+        assert!(not_hard.inhibits_skill(3));
+    }
+}
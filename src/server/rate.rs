@@ -0,0 +1,145 @@
+// Copyright © 2018 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use common::engine;
+
+use chrono::Duration;
+
+/// The rate assumed for clients that haven't reported one, in bytes per second.
+pub const DEFAULT_RATE: u32 = 2500;
+
+/// The lowest rate a client may request.
+pub const MIN_RATE: u32 = 500;
+
+/// The highest rate a client may request.
+pub const MAX_RATE: u32 = 25000;
+
+/// Clamps a rate requested by a client to the supported range.
+pub fn clamp_rate(rate: u32) -> u32 {
+    rate.max(MIN_RATE).min(MAX_RATE)
+}
+
+/// Limits the bandwidth used by unreliable updates sent to a single client.
+///
+/// The limiter accrues `rate` bytes per second of server time, up to a burst of one datagram.
+/// Each tick, updates are packed in priority order until the accrued budget runs out.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    rate: u32,
+    max_burst: usize,
+    budget: f32,
+}
+
+impl RateLimiter {
+    /// Creates a limiter for `rate` bytes per second that never sends more than `max_burst`
+    /// bytes in a single tick.
+    pub fn new(rate: u32, max_burst: usize) -> RateLimiter {
+        RateLimiter {
+            rate: clamp_rate(rate),
+            max_burst,
+            budget: 0.0,
+        }
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Sets the rate, as reported by the client's `rate` cvar.
+    pub fn set_rate(&mut self, rate: u32) {
+        self.rate = clamp_rate(rate);
+    }
+
+    /// Returns the number of bytes that may be sent this tick.
+    pub fn budget(&self) -> usize {
+        self.budget as usize
+    }
+
+    /// Accrues budget for `elapsed` seconds of server time.
+    pub fn tick(&mut self, elapsed: Duration) {
+        let accrued = self.rate as f32 * engine::duration_to_f32(elapsed);
+        self.budget = (self.budget + accrued).min(self.max_burst as f32);
+    }
+
+    /// Packs as many `updates` as the budget allows into a single message.
+    ///
+    /// `updates` must be given in priority order. Updates that don't fit in the remaining budget
+    /// are dropped, but smaller updates after them may still be sent.
+    pub fn pack<'a, I>(&mut self, updates: I) -> Vec<u8>
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let mut msg = Vec::new();
+        for update in updates {
+            if msg.len() + update.len() <= self.budget() {
+                msg.extend_from_slice(update);
+            }
+        }
+
+        self.budget -= msg.len() as f32;
+        msg
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bytes_sent(rate: u32) -> usize {
+        let updates: Vec<Vec<u8>> = (0..32).map(|i| vec![i as u8; 24]).collect();
+        let mut limiter = RateLimiter::new(rate, 1024);
+
+        let mut total = 0;
+        for _ in 0..20 {
+            limiter.tick(Duration::milliseconds(50));
+            total += limiter.pack(updates.iter().map(|u| u.as_slice())).len();
+        }
+
+        total
+    }
+
+    #[test]
+    fn test_low_rate_sends_less() {
+        let low = bytes_sent(MIN_RATE);
+        let high = bytes_sent(MAX_RATE);
+
+        // one second of ticks at the minimum rate
+        assert!(low <= MIN_RATE as usize);
+        assert!(low < high);
+    }
+
+    #[test]
+    fn test_pack_skips_oversized() {
+        let mut limiter = RateLimiter::new(1000, 1024);
+        limiter.tick(Duration::milliseconds(100));
+        assert_eq!(limiter.budget(), 100);
+
+        let big = [0u8; 80];
+        let bigger = [1u8; 40];
+        let small = [2u8; 16];
+        let msg = limiter.pack(vec![&big[..], &bigger[..], &small[..]]);
+        assert_eq!(msg.len(), 96);
+        assert_eq!(limiter.budget(), 4);
+    }
+
+    #[test]
+    fn test_clamp_rate() {
+        assert_eq!(clamp_rate(0), MIN_RATE);
+        assert_eq!(clamp_rate(DEFAULT_RATE), DEFAULT_RATE);
+        assert_eq!(clamp_rate(1_000_000), MAX_RATE);
+    }
+}
@@ -20,7 +20,7 @@ use std::error::Error;
 use std::fmt;
 use std::rc::Rc;
 
-use common::net::EntityState;
+use common::net::{EntityEffects, EntityState};
 use server::progs::EntityId;
 use server::progs::FieldDef;
 use server::progs::FunctionId;
@@ -33,7 +33,7 @@ use server::world::phys::MoveKind;
 use byteorder::LittleEndian;
 use byteorder::ReadBytesExt;
 use byteorder::WriteBytesExt;
-use cgmath::Vector3;
+use cgmath::{Deg, Vector3};
 use num::FromPrimitive;
 
 pub const MAX_ENT_LEAVES: usize = 16;
@@ -242,6 +242,29 @@ bitflags! {
     }
 }
 
+bitflags! {
+    pub struct SpawnFlags: u32 {
+        const NOT_EASY       = 0b0000100000000;
+        const NOT_MEDIUM     = 0b0001000000000;
+        const NOT_HARD       = 0b0010000000000;
+        const NOT_DEATHMATCH = 0b0100000000000;
+    }
+}
+
+impl SpawnFlags {
+    /// Returns whether an entity with these flags should be left out of a map played at the given
+    /// skill level.
+    ///
+    /// Nightmare (skill 3) uses the same entities as Hard.
+    pub fn inhibits_skill(&self, skill: u32) -> bool {
+        match skill {
+            0 => self.contains(SpawnFlags::NOT_EASY),
+            1 => self.contains(SpawnFlags::NOT_MEDIUM),
+            _ => self.contains(SpawnFlags::NOT_HARD),
+        }
+    }
+}
+
 // TODO: if this never gets used, remove it
 #[allow(dead_code)]
 fn float_addr(addr: usize) -> Result<FieldAddrFloat, ProgsError> {
@@ -312,6 +335,9 @@ pub struct Entity {
     type_def: Rc<EntityTypeDef>,
     addrs: Box<[[u8; 4]]>,
 
+    /// The number of BSP leaves this entity touches.
+    ///
+    /// Only the first `MAX_ENT_LEAVES` are stored in `leaf_ids`.
     pub leaf_count: usize,
     pub leaf_ids: [usize; MAX_ENT_LEAVES],
     pub baseline: EntityState,
@@ -334,6 +360,16 @@ impl Entity {
         }
     }
 
+    /// Returns the BSP leaves this entity touches, or `None` if it touches more leaves than can
+    /// be stored.
+    pub fn touched_leaves(&self) -> Option<&[usize]> {
+        if self.leaf_count > MAX_ENT_LEAVES {
+            None
+        } else {
+            Some(&self.leaf_ids[..self.leaf_count])
+        }
+    }
+
     pub fn type_check(&self, addr: usize, type_: Type) -> Result<(), EntityError> {
         match self.type_def
             .field_defs
@@ -574,6 +610,22 @@ impl Entity {
         Ok(self.get_vector(FieldAddrVector::Origin as i16)?.into())
     }
 
+    /// Returns the part of this entity's state that is sent to clients.
+    pub fn state(&self) -> Result<EntityState, EntityError> {
+        let angles = self.get_vector(FieldAddrVector::Angles as i16)?;
+        let effects = self.get_float(FieldAddrFloat::Effects as i16)? as u8;
+
+        Ok(EntityState {
+            origin: self.origin()?,
+            angles: Vector3::new(Deg(angles[0]), Deg(angles[1]), Deg(angles[2])),
+            model_id: self.model_index()?,
+            frame_id: self.get_float(FieldAddrFloat::FrameId as i16)? as usize,
+            colormap: self.get_float(FieldAddrFloat::Colormap as i16)? as u8,
+            skin_id: self.get_float(FieldAddrFloat::SkinId as i16)? as usize,
+            effects: EntityEffects::from_bits_truncate(effects),
+        })
+    }
+
     pub fn min(&self) -> Result<Vector3<f32>, EntityError> {
         Ok(self.get_vector(FieldAddrVector::Mins as i16)?.into())
     }
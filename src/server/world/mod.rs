@@ -38,15 +38,18 @@ pub use self::entity::FieldAddrFloat;
 pub use self::entity::FieldAddrFunctionId;
 pub use self::entity::FieldAddrStringId;
 pub use self::entity::FieldAddrVector;
+pub use self::entity::SpawnFlags;
 
 use common::bsp;
 use common::bsp::BspCollisionHull;
 use common::bsp::BspLeafContents;
+use common::bsp::HullKind;
 use common::console::CvarRegistry;
 use common::engine;
 use common::mdl;
 use common::model::Model;
 use common::model::ModelKind;
+use common::net;
 use common::vfs::Vfs;
 use common::parse;
 use common::sprite;
@@ -63,7 +66,7 @@ use server::progs::ProgsError;
 use server::progs::StringId;
 use server::progs::StringTable;
 use server::progs::Type;
-use server::Server;
+use server::{self, GameRules, Server};
 
 use cgmath::InnerSpace;
 use cgmath::Vector3;
@@ -71,7 +74,6 @@ use cgmath::Zero;
 use chrono::Duration;
 
 const AREA_DEPTH: usize = 4;
-const MAX_ENTITIES: usize = 600;
 
 enum AreaNodeKind {
     Branch(AreaBranch),
@@ -236,16 +238,32 @@ pub struct World {
     type_def: Rc<EntityTypeDef>,
 
     area_nodes: Box<[AreaNode]>,
-    slots: Box<[AreaEntitySlot]>,
+    // grows as entities are spawned, up to max_edicts
+    slots: Vec<AreaEntitySlot>,
+    max_edicts: usize,
     models: Vec<Model>,
 }
 
 impl World {
+    /// Creates a world from the given brush models with room for `max_edicts` entities.
+    ///
+    /// `max_edicts` should come from `net::max_edicts`, which keeps it within protocol limits.
+    /// Entity slots are added as they're needed, and spawning more than `max_edicts` entities is
+    /// an error.
     pub fn create(
         mut brush_models: Vec<Model>,
         type_def: Rc<EntityTypeDef>,
         string_table: Rc<StringTable>,
+        max_edicts: usize,
     ) -> Result<World, ProgsError> {
+        if max_edicts == 0 || max_edicts > net::MAX_EDICTS {
+            return Err(ProgsError::with_msg(format!(
+                "max_edicts must be between 1 and {} (got {})",
+                net::MAX_EDICTS,
+                max_edicts
+            )));
+        }
+
         // generate area tree for world model
         let area_nodes = AreaNode::generate(brush_models[0].min(), brush_models[0].max());
 
@@ -270,20 +288,17 @@ impl World {
             FieldAddrFloat::MoveKind as i16,
         )?;
 
-        let mut slots = Vec::with_capacity(MAX_ENTITIES);
-        slots.push(AreaEntitySlot::Occupied(AreaEntity {
+        let slots = vec![AreaEntitySlot::Occupied(AreaEntity {
             entity: world_entity,
             area_id: None,
-        }));
-        for _ in 0..MAX_ENTITIES - 1 {
-            slots.push(AreaEntitySlot::Vacant);
-        }
+        })];
 
         Ok(World {
             string_table,
             area_nodes: area_nodes.into_boxed_slice(),
             type_def,
-            slots: slots.into_boxed_slice(),
+            slots,
+            max_edicts,
             models,
         })
     }
@@ -368,18 +383,26 @@ impl World {
         }
     }
 
-    fn find_vacant_slot(&self) -> Result<usize, ()> {
+    fn find_vacant_slot(&mut self) -> Result<usize, ProgsError> {
         for (i, slot) in self.slots.iter().enumerate() {
             if let &AreaEntitySlot::Vacant = slot {
                 return Ok(i);
             }
         }
 
-        panic!("no vacant slots");
+        if self.slots.len() >= self.max_edicts {
+            return Err(ProgsError::with_msg(format!(
+                "No free entity slots (all {} in use), raise max_edicts to load this map",
+                self.slots.len()
+            )));
+        }
+
+        self.slots.push(AreaEntitySlot::Vacant);
+        Ok(self.slots.len() - 1)
     }
 
     pub fn alloc_uninitialized(&mut self) -> Result<EntityId, ProgsError> {
-        let slot_id = self.find_vacant_slot().unwrap();
+        let slot_id = self.find_vacant_slot()?;
 
         self.slots[slot_id] = AreaEntitySlot::Occupied(AreaEntity {
             entity: Entity::new(self.string_table.clone(), self.type_def.clone()),
@@ -448,7 +471,7 @@ impl World {
                         Type::QEntity => {
                             let id: usize = val.parse().unwrap();
 
-                            if id > MAX_ENTITIES {
+                            if id >= self.slots.len() {
                                 panic!("out-of-bounds entity access");
                             }
 
@@ -468,7 +491,7 @@ impl World {
             }
         }
 
-        let entry_id = self.find_vacant_slot().unwrap();
+        let entry_id = self.find_vacant_slot()?;
 
         self.slots[entry_id] = AreaEntitySlot::Occupied(AreaEntity {
             entity: ent,
@@ -496,6 +519,39 @@ impl World {
         Ok(())
     }
 
+    /// Returns the BSP data of the level's world model.
+    pub fn world_bsp(&self) -> Option<Rc<bsp::BspData>> {
+        match *self.models[1].kind() {
+            ModelKind::Brush(ref bmodel) => Some(bmodel.bsp_data()),
+            _ => None,
+        }
+    }
+
+    /// Returns each entity in the world along with its ID, in ID order.
+    pub fn entities(&self) -> impl Iterator<Item = (EntityId, &Entity)> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(i, slot)| match *slot {
+                AreaEntitySlot::Occupied(ref e) => Some((EntityId(i), &e.entity)),
+                AreaEntitySlot::Vacant => None,
+            })
+    }
+
+    /// Records each entity's current state as its baseline.
+    ///
+    /// This is done once a level's entities are spawned. Updates sent to clients afterward only
+    /// carry what differs from the baseline.
+    pub fn create_baselines(&mut self) -> Result<(), ProgsError> {
+        for slot in self.slots.iter_mut() {
+            if let AreaEntitySlot::Occupied(ref mut e) = *slot {
+                e.entity.baseline = e.entity.state()?;
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn try_get_entity(&self, entity_id: EntityId) -> Result<&Entity, ProgsError> {
         if entity_id.0 as usize > self.slots.len() {
             return Err(ProgsError::with_msg(format!(
@@ -581,16 +637,38 @@ impl World {
         server: &mut Server,
         map: HashMap<&str, &str>,
         vfs: &Vfs,
-    ) -> Result<EntityId, ProgsError> {
+    ) -> Result<Option<EntityId>, ProgsError> {
         let classname = match map.get("classname") {
             Some(c) => c.to_owned(),
             None => return Err(ProgsError::with_msg("No classname for entity")),
         };
 
+        // leave out entities that don't belong in the current game mode or skill level
+        let spawnflags = map
+            .get("spawnflags")
+            .and_then(|f| f.parse::<f32>().ok())
+            .unwrap_or(0.0) as u32;
+        let spawnflags = SpawnFlags::from_bits_truncate(spawnflags);
+        let rules = GameRules::from_cvars(cvars);
+        let inhibited = if rules.is_deathmatch() {
+            spawnflags.contains(SpawnFlags::NOT_DEATHMATCH)
+        } else {
+            spawnflags.inhibits_skill(server::skill_level(cvars))
+        };
+
+        if inhibited {
+            debug!("Inhibiting {} in this game mode", classname);
+            return Ok(None);
+        }
+
         let e_id = self.alloc_from_map(map)?;
 
         // TODO: set origin, mins and maxs here if needed
 
+        // the spawn function decides whether to spawn monsters and how items respawn based on
+        // the game mode
+        rules.write_globals(globals)?;
+
         // set `self` before calling spawn function
         globals.put_entity_id(e_id, GlobalAddrEntity::Self_ as i16)?;
 
@@ -599,7 +677,7 @@ impl World {
         // TODO: should touch triggers?
         self.link_entity(e_id, false)?;
 
-        Ok(e_id)
+        Ok(Some(e_id))
     }
 
     fn unlink_entity(&mut self, e_id: EntityId) -> Result<(), ProgsError> {
@@ -639,6 +717,11 @@ impl World {
 
         self.unlink_entity(e_id)?;
 
+        let world_bsp = match *self.models[1].kind() {
+            ModelKind::Brush(ref bmodel) => Some(bmodel.bsp_data()),
+            _ => None,
+        };
+
         let mut abs_min;
         let mut abs_max;
         let solid;
@@ -674,7 +757,13 @@ impl World {
             ent.leaf_count = 0;
             let model_index = ent.get_float(FieldAddrFloat::ModelIndex as i16)?;
             if model_index != 0.0 {
-                // TODO: SV_FindTouchedLeafs
+                if let Some(ref bsp_data) = world_bsp {
+                    let leaf_ids = bsp_data.find_touched_leaves(abs_min, abs_max);
+                    for (slot, leaf_id) in ent.leaf_ids.iter_mut().zip(leaf_ids.iter()) {
+                        *slot = *leaf_id;
+                    }
+                    ent.leaf_count = leaf_ids.len();
+                }
             }
 
             solid = ent.solid()?;
@@ -851,21 +940,10 @@ impl World {
                 let size = max - min;
                 match self.models[self.try_get_entity(e_id)?.model_index()?].kind() {
                     &ModelKind::Brush(ref bmodel) => {
-                        let hull_index;
-
-                        // TODO: replace these magic constants
-                        if size[0] < 3.0 {
-                            debug!("Using hull 0");
-                            hull_index = 0;
-                        } else if size[0] <= 32.0 {
-                            debug!("Using hull 1");
-                            hull_index = 1;
-                        } else {
-                            debug!("Using hull 2");
-                            hull_index = 2;
-                        }
+                        let hull_kind = HullKind::for_size(size);
+                        debug!("Using hull {:?}", hull_kind);
 
-                        let hull = bmodel.hull(hull_index).unwrap();
+                        let hull = bmodel.hull(hull_kind.index()).unwrap();
 
                         let offset = hull.min() - min + self.try_get_entity(e_id)?.origin()?;
 
@@ -218,6 +218,16 @@ impl Trace {
         self.end.point
     }
 
+    /// Returns the plane this trace ended on, if it ended on a leaf boundary.
+    ///
+    /// The plane's normal points back toward the start of the trace.
+    pub fn end_plane(&self) -> Option<&Hyperplane> {
+        match self.end.kind {
+            TraceEndKind::Terminal => None,
+            TraceEndKind::Boundary(ref b) => Some(&b.plane),
+        }
+    }
+
     pub fn all_solid(&self) -> bool {
         self.contents == BspLeafContents::Solid
     }